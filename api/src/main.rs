@@ -21,6 +21,11 @@ struct SubSystem {
     description: Option<String>,
     syscalls: Vec<SysCall>,
     constants: Vec<(String, String, i128)>,
+
+    // Error codes, as (name, code, description) triples
+    // Syscalls that fail return the negated error code
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<(String, i64, String)>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -113,6 +118,9 @@ fn main()
 {
     let mut unique_names: HashSet<String> = HashSet::new();
 
+    // Map from error code to name
+    let mut unique_errors: HashMap<i64, String> = HashMap::new();
+
     // Map from constant index to name
     let mut idx_to_name: Vec<Option<String>> = Vec::default();
 
@@ -130,6 +138,19 @@ fn main()
             subsystem.description = Some(normalize_description(&text));
         }
 
+        // Error codes must be positive and unique
+        for (name, code, text) in &mut subsystem.errors {
+            if *code <= 0 {
+                panic!("error code {} must be positive", name);
+            }
+
+            if unique_errors.insert(*code, name.clone()).is_some() {
+                panic!("two errors have the code {}", code);
+            }
+
+            *text = normalize_description(text);
+        }
+
         // For each syscall for this subsystem
         for syscall in &mut subsystem.syscalls {
             // Make sure that syscall names are valid
@@ -232,6 +253,7 @@ fn gen_rust_bindings(out_file: &str, subsystems: &Vec<SubSystem>, idx_to_name: &
         "    pub const_idx: u16,\n",
         "    pub argc: usize,\n",
         "    pub has_ret: bool,\n",
+        "    pub permission: &'static str,\n",
         "}",
     )).unwrap();
     writeln!(&mut file).unwrap();
@@ -242,11 +264,12 @@ fn gen_rust_bindings(out_file: &str, subsystems: &Vec<SubSystem>, idx_to_name: &
             let has_ret = syscall.returns.0 != "void";
             writeln!(
                 &mut file,
-                "    Some(SysCallDesc {{ name: \"{}\", const_idx: {}, argc: {}, has_ret: {} }}),",
+                "    Some(SysCallDesc {{ name: \"{}\", const_idx: {}, argc: {}, has_ret: {}, permission: \"{}\" }}),",
                 syscall.name,
                 syscall.const_idx.unwrap(),
                 syscall.args.len(),
                 has_ret,
+                syscall.permission,
             ).unwrap();
         }
         else
@@ -269,6 +292,14 @@ fn gen_rust_bindings(out_file: &str, subsystems: &Vec<SubSystem>, idx_to_name: &
             ).unwrap();
         }
     }
+
+    // Write out the error codes
+    writeln!(&mut file).unwrap();
+    for subsystem in subsystems {
+        for (name, code, _) in &subsystem.errors {
+            writeln!(&mut file, "pub const {}: i64 = {};", name, code).unwrap();
+        }
+    }
 }
 
 fn gen_c_bindings(out_file: &str, subsystems: &Vec<SubSystem>)
@@ -333,6 +364,14 @@ fn gen_c_bindings(out_file: &str, subsystems: &Vec<SubSystem>)
         }
     }
 
+    // Write out the syscall indices, to be used with sys_has_syscall
+    for subsystem in subsystems {
+        for syscall in &subsystem.syscalls {
+            writeln!(&mut file, "#define SYS_{} {}", syscall.name, syscall.const_idx.unwrap()).unwrap();
+        }
+    }
+    writeln!(&mut file).unwrap();
+
     // Write out the constants for each subsystem
     for subsystem in subsystems {
         for (name, type_name, value) in &subsystem.constants {
@@ -346,6 +385,14 @@ fn gen_c_bindings(out_file: &str, subsystems: &Vec<SubSystem>)
     }
     writeln!(&mut file).unwrap();
 
+    // Write out the error codes
+    for subsystem in subsystems {
+        for (name, code, text) in &subsystem.errors {
+            writeln!(&mut file, "#define {} {} // {}", name, code, text).unwrap();
+        }
+    }
+    writeln!(&mut file).unwrap();
+
     writeln!(&mut file, "#endif").unwrap();
 }
 
@@ -366,6 +413,11 @@ fn gen_markdown(out_file: &str, subsystems: &Vec<SubSystem>)
     writeln!(&mut file, "and can output either 0 or 1 value on the stack.").unwrap();
     writeln!(&mut file, "The syscalls with a `void` return type do not output anything.").unwrap();
     writeln!(&mut file).unwrap();
+    writeln!(&mut file, "Syscalls that can fail return a signed value, which is negative on failure.").unwrap();
+    writeln!(&mut file, "The negated value is one of the error codes listed under the vm subsystem,").unwrap();
+    writeln!(&mut file, "for instance a syscall returns `-EBADF` when given an invalid handle.").unwrap();
+    writeln!(&mut file, "Invalid memory accesses are not reported this way, they stop the program.").unwrap();
+    writeln!(&mut file).unwrap();
 
     for subsystem in subsystems {
         writeln!(&mut file, "# {}", subsystem.subsystem).unwrap();
@@ -421,5 +473,18 @@ fn gen_markdown(out_file: &str, subsystems: &Vec<SubSystem>)
 
             writeln!(&mut file).unwrap();
         }
+
+        // Write out the error codes
+        if !subsystem.errors.is_empty() {
+            writeln!(&mut file, "## Error codes").unwrap();
+            writeln!(&mut file, "Syscalls that fail return one of these codes, negated:").unwrap();
+            writeln!(&mut file).unwrap();
+
+            for (name, code, text) in &subsystem.errors {
+                writeln!(&mut file, "- `{} = {}`: {}", name, code, text).unwrap();
+            }
+
+            writeln!(&mut file).unwrap();
+        }
     }
 }
@@ -13,6 +13,7 @@
 
 // RGBA pixels
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Cube vertices in [-1, 1]
 float verts[8][3] = {
@@ -108,7 +109,7 @@ void anim_callback()
     trans_line3d(m_cube, verts[2], verts[6]);
     trans_line3d(m_cube, verts[3], verts[7]);
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     u64 end_time = time_current_ms();
     printf("render time: %dms\n", end_time - start_time);
@@ -127,8 +128,8 @@ void keydown(u64 window_id, u16 keycode)
 
 int main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Rotating 3D Cube Example", 0);
-    window_on_keydown(0, keydown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Rotating 3D Cube Example", 0);
+    window_on_keydown(window, keydown);
     time_delay_cb(0, anim_callback);
     enable_event_loop();
 
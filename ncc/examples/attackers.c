@@ -23,6 +23,7 @@ char* ENEMY_DOTS[4];
 
 // RGBA pixels: 800 * 600
 u32 frame_buffer[480_000];
+u64 window;
 
 // Left/right arrow currently pressed
 bool left_down = false;
@@ -272,7 +273,7 @@ void anim_callback()
         );
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     // Schedule a fixed rate update for the next frame (40fps)
     fixed_rate_update(start_time, 1000 / 40, anim_callback);
@@ -346,9 +347,9 @@ void main()
 {
     init();
 
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Galactic Attackers", 0);
-    window_on_keydown(0, keydown);
-    window_on_keyup(0, keyup);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Galactic Attackers", 0);
+    window_on_keydown(window, keydown);
+    window_on_keyup(window, keyup);
 
     time_delay_cb(0, anim_callback);
     time_delay_cb(1500, enemy_callback);
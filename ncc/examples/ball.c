@@ -1,5 +1,6 @@
 #include <uvm/syscalls.h>
 #include <uvm/utils.h>
+#include <uvm/loop.h>
 #include <stdlib.h>
 #include <stdint.h>
 
@@ -10,6 +11,7 @@
 
 // RGBA pixels: 800 * 600
 u32 frame_buffer[600][800];
+u64 window;
 
 // Current ball position
 int px = 200;
@@ -54,15 +56,9 @@ void draw_ball()
     }
 }
 
-void anim_callback()
+// Advance the ball by one time step
+void update()
 {
-    u64 start_time = time_current_ms();
-
-    // Clear the frame buffer, set all pixels to black
-    memset32(frame_buffer, 0, 800 * 600);
-
-    draw_ball();
-
     px = px + vx;
     py = py + vy;
 
@@ -87,11 +83,16 @@ void anim_callback()
         vy = -vy;
         audio_pos = 0;
     }
+}
 
-    window_draw_frame(0, frame_buffer);
+void render(float alpha)
+{
+    // Clear the frame buffer, set all pixels to black
+    memset32(frame_buffer, 0, 800 * 600);
 
-    // Schedule a fixed rate update for the next frame (60fps)
-    fixed_rate_update(start_time, 1000 / 60, anim_callback);
+    draw_ball();
+
+    window_draw_frame(window, frame_buffer);
 }
 
 u16* audio_cb(u16 num_channels, u32 num_samples)
@@ -132,12 +133,11 @@ void keydown(u64 window_id, u16 keycode)
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Bouncing Ball Example", 0);
-    window_on_keydown(0, keydown);
-
-    time_delay_cb(0, anim_callback);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Bouncing Ball Example", 0);
+    window_on_keydown(window, keydown);
 
     audio_open_output(44100, 1, AUDIO_FORMAT_I16, audio_cb);
 
-    enable_event_loop();
+    // Update and render at 60fps
+    loop_start(1000 / 60, 1000 / 60, update, render);
 }
@@ -46,6 +46,7 @@ size_t margin = 5;
 size_t char_width = 18;
 size_t char_height = 32;
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 char text[NUM_ROWS][NUM_COLS];
 
 // Position of the cursor
@@ -145,7 +146,7 @@ void main()
     console_width = FRAME_WIDTH/3+75;
     vm_init();
     vm_command_text_buffer_clear();
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "UVM Basic", 0);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "UVM Basic", 0);
 
     canvas_fill(white);
     console_redraw_all_text();
@@ -159,8 +160,8 @@ void main()
 
     console_redraw_commit();
 
-    window_on_keydown(0, keydown);
-    window_on_textinput(0, textinput);
+    window_on_keydown(window, keydown);
+    window_on_textinput(window, textinput);
 
     time_delay_cb(0, anim_callback);
 
@@ -719,7 +720,7 @@ void console_redraw_commit()
         console_draw_char('_', col_idx, line_idx);
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 }
 
 size_t console_get_line_pos(size_t line_num)
@@ -1131,7 +1132,7 @@ void resize_interned_sym_buffer()
     for(size_t cursor = 0; cursor < old_capacity; ++cursor)
     {
         u64* sym_meta = old_buffer[cursor];
-        char* str = sym_meta[SYM_META_STR];
+        char* str = (char*)sym_meta[SYM_META_STR];
         u32 str_hash = hash(str);
         u64 idx = (vm_intern_capacity - 1) & str_hash;
 
@@ -1145,7 +1146,7 @@ void resize_interned_sym_buffer()
             DEBUGI(idx);
             DEBUG("");
             ++idx;
-            if(idx >= vm_intern_buffer) idx = 0;
+            if(idx >= vm_intern_capacity) idx = 0;
         }
         DEBUG("Found a spot at index: ");
         DEBUGI(idx);
@@ -1186,10 +1187,10 @@ u64* vm_intern(u8* sym, u64 len, u32 hash)
             NULLGAURD(sym_meta);
             sym_meta[SYM_META_STR] = (u64)sym_str;
 
-            vm_intern_buffer[cursor] = (u64)sym_meta;
+            vm_intern_buffer[cursor] = sym_meta;
             return sym_meta;
         }
-        else if (strncmp(sym_meta[SYM_META_STR], sym, len) == 0)
+        else if (strncmp((char*)sym_meta[SYM_META_STR], sym, len) == 0)
         {
             DEBUG("Symbol have been found");
             return sym_meta;
@@ -1236,7 +1237,7 @@ i64 vm_symbol_get_var(u64* sym)
         console_puts("Trying to access ");
         console_newline();
         console_puts("an uninitialized variable");
-        console_puts(sym[SYM_META_STR]);
+        console_puts((char*)sym[SYM_META_STR]);
         return -1;
     }
     return sym[SYM_META_LOC]-1;
@@ -1331,7 +1332,7 @@ void vm_command_double_size()
     memset(new_buff, 0, new_size);
     memcpy(new_buff, old_buff, old_size);
     vm_commands_selected[VM_COMMANDS_INSTS_BUFFER] = new_buff;
-    vm_commands_selected[VM_COMMANDS_CAPACITY] = new_capacity;
+    vm_commands_selected[VM_COMMANDS_CAPACITY] = (u64*)new_capacity;
     free((void*)old_buff);
 }
 
@@ -2044,9 +2045,9 @@ void vm_exec()
         else if (op == OP_RAND) vm_push(rand());
         else if (op == OP_PRINT_STR)
         {
-            u64* str = (u64)vm_vars[arg];
+            u64* str = (u64*)vm_vars[arg];
             console_newline();
-            console_puts(str[SYM_META_STR]);
+            console_puts((char*)str[SYM_META_STR]);
             break;
         }
         else if (op == OP_SLEEP)
@@ -28,6 +28,7 @@ char* CURSOR_DOTS[1];
 
 // RGBA pixels: 800 * 600
 u32 frame_buffer[480_000];
+u64 window;
 
 // Left/right arrow currently pressed
 bool left_key = false;
@@ -260,7 +261,7 @@ int X(int w, int c, int h, int e, int S, int s)
                         else
                             g = I + S;
                         if (!r & (q | A < 3 || g) || (r + 1 & z ^ y) > 9 && q | A > 2){
-                            m=!(r-2&7);
+                            m=(int*)!(r-2&7);
                             if (m) {
                                 y = y ^ 8;
                                 return K;
@@ -565,7 +566,7 @@ void anim_callback()
         }
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
     time_delay_cb(33, anim_callback);
 }
 
@@ -621,9 +622,9 @@ void main()
 {
     init();
 
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Toledo Nanochess for UVM", 0);
-    window_on_keydown(0, keydown);
-    window_on_keyup(0, keyup);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Toledo Nanochess for UVM", 0);
+    window_on_keydown(window, keydown);
+    window_on_keyup(window, keyup);
 
     time_delay_cb(0, anim_callback);
 
@@ -9,6 +9,7 @@
 
 // RGBA pixels: 800 * 600
 u32 frame_buffer[480_000];
+u64 window;
 
 // Strings mapping the dots for each character
 char* CHAR_DOTS[256];
@@ -227,7 +228,7 @@ void anim_callback()
 
     draw_number(500, 200, 10, (int)seconds);
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
     time_delay_cb(25, anim_callback);
 }
 
@@ -237,7 +238,7 @@ void main()
 
     start_time = time_current_ms();
 
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Counter", 0);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Counter", 0);
 
     time_delay_cb(0, anim_callback);
     enable_event_loop();
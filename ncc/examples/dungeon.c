@@ -0,0 +1,101 @@
+#include <uvm/syscalls.h>
+#include <stdio.h>
+
+// Walk around a room in the terminal with the arrow keys,
+// collecting gold, without opening a window. Press q to quit.
+
+#define WIDTH 40
+#define HEIGHT 12
+#define NUM_GOLD 5
+
+int player_x = 2;
+int player_y = 2;
+
+int gold_xs[NUM_GOLD] = { 10, 25, 33, 6, 18 };
+int gold_ys[NUM_GOLD] = { 3, 8, 2, 9, 6 };
+int gold_left = NUM_GOLD;
+
+void draw()
+{
+    console_set_style(CONSOLE_DEFAULT, CONSOLE_DEFAULT, 0);
+    console_clear();
+
+    for (int y = 0; y < HEIGHT; ++y)
+    {
+        for (int x = 0; x < WIDTH; ++x)
+        {
+            if (x == 0 || y == 0 || x == WIDTH - 1 || y == HEIGHT - 1)
+                putchar('#');
+            else
+                putchar('.');
+        }
+        putchar('\n');
+    }
+
+    console_set_style(CONSOLE_YELLOW + CONSOLE_BRIGHT, CONSOLE_DEFAULT, CONSOLE_BOLD);
+    for (int i = 0; i < NUM_GOLD; ++i)
+    {
+        if (gold_xs[i] >= 0)
+        {
+            console_move_cursor(gold_ys[i], gold_xs[i]);
+            putchar('$');
+        }
+    }
+
+    console_set_style(CONSOLE_GREEN + CONSOLE_BRIGHT, CONSOLE_DEFAULT, CONSOLE_BOLD);
+    console_move_cursor(player_y, player_x);
+    putchar('@');
+
+    console_set_style(CONSOLE_DEFAULT, CONSOLE_DEFAULT, 0);
+    console_move_cursor(HEIGHT, 0);
+    printf("Gold left: %d\n", gold_left);
+}
+
+void move(int dx, int dy)
+{
+    int x = player_x + dx;
+    int y = player_y + dy;
+
+    if (x <= 0 || y <= 0 || x >= WIDTH - 1 || y >= HEIGHT - 1)
+        return;
+
+    player_x = x;
+    player_y = y;
+
+    for (int i = 0; i < NUM_GOLD; ++i)
+    {
+        if (gold_xs[i] == x && gold_ys[i] == y)
+        {
+            gold_xs[i] = -1;
+            --gold_left;
+        }
+    }
+}
+
+void main()
+{
+    console_set_raw(true);
+    console_show_cursor(false);
+
+    while (gold_left > 0)
+    {
+        draw();
+
+        int key = console_read_key(true);
+
+        if (key == -1 || key == 'q')
+            break;
+        else if (key == KEY_LEFT)
+            move(-1, 0);
+        else if (key == KEY_RIGHT)
+            move(1, 0);
+        else if (key == KEY_UP)
+            move(0, -1);
+        else if (key == KEY_DOWN)
+            move(0, 1);
+    }
+
+    draw();
+    console_show_cursor(true);
+    console_set_raw(false);
+}
@@ -14,6 +14,7 @@
 
 // RGBA pixels
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Palette of RGB colors
 u32 palette[256];
@@ -106,7 +107,7 @@ void anim_callback()
         }
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     // Schedule a fixed rate update for the next frame (40fps)
     fixed_rate_update(frame_start_time, 1000 / 40, anim_callback);
@@ -134,8 +135,8 @@ void main()
         palette[i] = hsl_to_rgb(x * 0.33f, 1.0f, l);
     }
 
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Demoscene Fire Effect", 0);
-    window_on_keydown(0, keydown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Demoscene Fire Effect", 0);
+    window_on_keydown(window, keydown);
 
     time_delay_cb(0, anim_callback);
 
@@ -11,6 +11,7 @@
 
 // RGBA pixels: 600 * 600
 uint32_t frame_buffer[360_000];
+uint64_t window;
 
 // Current and next board
 bool board[2][NUM_ROWS][NUM_COLS];
@@ -100,7 +101,7 @@ void update()
         }
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 }
 
 void anim_callback()
@@ -115,7 +116,7 @@ void anim_callback()
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Game of Life", 0);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Game of Life", 0);
 
     // Randomly initialize the board
     srand(time_current_ms());
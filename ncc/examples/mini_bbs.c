@@ -422,7 +422,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
         char* response = "Goodbye!\n";
         net_write(socket_id, response, strlen(response));
         net_close(socket_id);
-        free_user(socket_id);
+        free_user(p_user);
         return;
     }
 
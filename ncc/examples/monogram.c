@@ -584,6 +584,7 @@ u32 FRAME_WIDTH = 400;
 u32 FRAME_HEIGHT = 400;
 
 u32 frame_buffer[161600];
+u64 window;
 
 void anim_callback()
 {
@@ -619,13 +620,13 @@ void anim_callback()
         );
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
     time_delay_cb(10, anim_callback);
 }
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Monogram Font Example", 0);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Monogram Font Example", 0);
 
     time_delay_cb(0, anim_callback);
 
@@ -11,6 +11,7 @@
 
 // 2D RGBA pixel array
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Current mouse pointer position
 size_t pos_x = 200;
@@ -145,7 +146,7 @@ void mousemove(u64 window_id, int new_x, int new_y)
     pos_x = new_x;
     pos_y = new_y;
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 }
 
 void mousedown(u64 window_id, u8 btn_id)
@@ -164,7 +165,7 @@ void mousedown(u64 window_id, u8 btn_id)
         }
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 }
 
 void mouseup(u64 window_id, u8 btn_id)
@@ -177,7 +178,7 @@ void mouseup(u64 window_id, u8 btn_id)
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "UVM Paint Program Example", 0);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "UVM Paint Program Example", 0);
 
     // Initially fill the canvas with white
     fill_rect(
@@ -194,11 +195,11 @@ void main()
     draw_palette();
 
     // Register mouse event callbacks
-    window_on_mousemove(0, mousemove);
-    window_on_mousedown(0, mousedown);
-    window_on_mouseup(0, mouseup);
+    window_on_mousemove(window, mousemove);
+    window_on_mousedown(window, mousedown);
+    window_on_mouseup(window, mouseup);
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     enable_event_loop();
 }
@@ -16,6 +16,7 @@ u64 prog_start_time;
 
 // RGBA pixels
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Palette of RGB colors
 u32 palette[256];
@@ -23,45 +24,8 @@ u32 palette[256];
 // Greyscale plasma values
 int plasma[FRAME_HEIGHT][FRAME_WIDTH];
 
-// Convert a color from HSV format to RGB format
-u32 hsv_to_rgb(float h, float s, float v)
-{
-	if (s < 0.01f)
-	{
-		// simple gray conversion
-        int c = (int)(v * 255.0f);
-        return rgb32(c, c, c);
-	}
-
-    // convert hue from [0, 360( to range [0,6)
-    h = h / 60.0f;
-    if (h >= 6.0f)
-        h = h - 6.0f;
-
-    // break "h" down into integer and fractional parts.
-    int i = (int)h;
-    float f = h - (float)i;
-
-    // Compute the permuted RGB values
-    int vi = (int)(f * 255.0f);
-    int p = (int)((v * (1.0f - s)) * 255.0f);
-    int q = (int)((v * (1.0f - (s * f))) * 255.0f);
-    int t = (int)((v * (1.0f - (s * (1.0f - f)))) * 255.0f);
-
-    // map v, p, q, and t into red, green, and blue values
-    if (i == 0)
-        return rgb32(vi, t, p);
-    if (i == 1)
-        return rgb32(q, vi, p);
-    if (i == 2)
-        return rgb32(p, vi, t);
-    if (i == 3)
-        return rgb32(p, q, vi);
-    if (i == 4)
-        return rgb32(t, p, vi);
-
-    return rgb32(vi, p, q);
-}
+// HSV colors used to generate the palette
+float palette_hsv[256][3];
 
 void anim_callback()
 {
@@ -81,7 +45,7 @@ void anim_callback()
         }
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     // Schedule a fixed rate update for the next frame (40fps)
     fixed_rate_update(frame_start_time, 1000 / 40, anim_callback);
@@ -103,8 +67,11 @@ void main()
     for (int i = 0; i < 256; ++i)
     {
         // Vary the hue through the palette
-        palette[i] = hsv_to_rgb(360.0f / 256.0f * (float)i, 1.0f, 1.0f);
+        palette_hsv[i][0] = 360.0f / 256.0f * (float)i;
+        palette_hsv[i][1] = 1.0f;
+        palette_hsv[i][2] = 1.0f;
     }
+    hsv_to_rgb(palette, palette_hsv, 256);
 
     // Generate the greyscale plasma values
     for (int y = 0; y < FRAME_HEIGHT; ++y)
@@ -129,8 +96,8 @@ void main()
         }
     }
 
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Demoscene Plasma Effect", 0);
-    window_on_keydown(0, keydown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Demoscene Plasma Effect", 0);
+    window_on_keydown(window, keydown);
 
     time_delay_cb(0, anim_callback);
 
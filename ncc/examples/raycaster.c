@@ -36,6 +36,7 @@ u8 map[MAP_HEIGHT][MAP_WIDTH] = {
 
 // RGBA pixels
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Player position and camera direction
 float pos_x = 1.5f;
@@ -339,7 +340,7 @@ void anim_callback()
     u64 frame_end_time = time_current_ms();
     printf("render time %d ms\n", frame_end_time - frame_start_time);
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     // Schedule a fixed rate update for the next frame (30fps)
     fixed_rate_update(frame_start_time, 1000 / 30, anim_callback);
@@ -387,8 +388,8 @@ void keydown(u64 window_id, u16 keycode)
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Ray-Casting Example", 0);
-    window_on_keydown(0, keydown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Ray-Casting Example", 0);
+    window_on_keydown(window, keydown);
 
     time_delay_cb(0, anim_callback);
 
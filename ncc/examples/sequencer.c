@@ -16,6 +16,7 @@
 
 // Frame buffer to draw into
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Sequencer grid
 bool grid[NUM_ROWS][NUM_STEPS];
@@ -69,7 +70,7 @@ void redraw()
         }
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 }
 
 u16* audio_cb(u16 num_channels, u32 num_samples)
@@ -164,9 +165,9 @@ void keydown(u64 window_id, u16 keycode)
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Pentatonic Sequencer", 0);
-    window_on_keydown(0, keydown);
-    window_on_mousedown(0, mousedown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Pentatonic Sequencer", 0);
+    window_on_keydown(window, keydown);
+    window_on_mousedown(window, mousedown);
 
     enable_event_loop();
 
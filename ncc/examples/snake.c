@@ -13,6 +13,7 @@
 
 // RGBA pixels: 640 * 640
 u32 frame_buffer[409_600];
+u64 window;
 
 // Apple position
 int apple_x = 10;
@@ -173,7 +174,7 @@ void anim_callback()
         0xFF00FF
     );
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     // Schedule a fixed rate update for the next frame
     fixed_rate_update(start_time, 100, anim_callback);
@@ -213,8 +214,8 @@ void keydown(u64 window_id, u16 keycode)
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Snake Game Example", 0);
-    window_on_keydown(0, keydown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Snake Game Example", 0);
+    window_on_keydown(window, keydown);
 
     for (int i = 0; i < snake_len; ++i)
     {
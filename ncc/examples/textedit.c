@@ -10,6 +10,7 @@
 #define NUM_ROWS 18
 
 u32 frame_buffer[600][800];
+u64 window;
 
 char text[NUM_ROWS][NUM_COLS];
 
@@ -128,7 +129,7 @@ void redraw()
         );
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 }
 
 void anim_callback()
@@ -140,12 +141,12 @@ void anim_callback()
 
 void main()
 {
-    window_create(800, 600, "Text Editor Demo", 0);
+    window = window_create(800, 600, "Text Editor Demo", 0);
 
     redraw();
 
-    window_on_keydown(0, keydown);
-    window_on_textinput(0, textinput);
+    window_on_keydown(window, keydown);
+    window_on_textinput(window, textinput);
 
     time_delay_cb(0, anim_callback);
 
@@ -13,6 +13,7 @@
 
 // RGBA pixels
 u32 frame_buffer[FRAME_HEIGHT][FRAME_WIDTH];
+u64 window;
 
 // Perspective projection matrix
 mat44 persp;
@@ -148,7 +149,7 @@ void anim_callback()
         draw_line3d(v0, v1, COLOR_PURPLE);
     }
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     u64 end_time = time_current_ms();
     printf("render time: %dms\n", end_time - start_time);
@@ -168,8 +169,8 @@ void keydown(u64 window_id, u16 keycode)
 
 int main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "The Grid", 0);
-    window_on_keydown(0, keydown);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "The Grid", 0);
+    window_on_keydown(window, keydown);
     time_delay_cb(0, anim_callback);
     enable_event_loop();
 
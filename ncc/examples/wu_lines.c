@@ -8,6 +8,7 @@ size_t FRAME_WIDTH = 400;
 size_t FRAME_HEIGHT = 400;
 
 u32 frame_buffer[160000];
+u64 window;
 
 // Current mouse pointer position
 size_t pos_x = 200;
@@ -319,16 +320,16 @@ void anim_callback()
     draw_wu_line(frame_buffer, FRAME_WIDTH, FRAME_HEIGHT, h - pos_y, pos_x, 0, h, COLOR_BLUE);
     draw_wu_line(frame_buffer, FRAME_WIDTH, FRAME_HEIGHT, h - pos_y, pos_x, w, 0, COLOR_YELLOW );
 
-    window_draw_frame(0, frame_buffer);
+    window_draw_frame(window, frame_buffer);
 
     time_delay_cb(10, anim_callback);
 }
 
 void main()
 {
-    window_create(FRAME_WIDTH, FRAME_HEIGHT, "Wu Anti-Aliased Line Example", 0);
+    window = window_create(FRAME_WIDTH, FRAME_HEIGHT, "Wu Anti-Aliased Line Example", 0);
 
-    window_on_mousemove(0, mousemove);
+    window_on_mousemove(window, mousemove);
 
     time_delay_cb(0, anim_callback);
 
@@ -11,7 +11,7 @@
     asm () -> void { syscall print_endl; };\
     asm ("assert(" #test_expr ")") -> void { syscall print_str; };\
     asm () -> void { syscall print_endl; };\
-    asm () -> void { push -1; exit; };\
+    __builtin_trap();\
 }
 #else
 #define assert(test_val) {}
@@ -24,11 +24,11 @@
     asm ("not yet implemented") -> void { syscall 6; };\
 }
 
-// TODO: add line numbers
 // panic(msg) macro
 #define panic(msg)\
 {\
-    asm (msg) -> void { syscall 6; panic; };\
+    asm (msg) -> void { syscall 6; };\
+    __builtin_trap();\
 }
 
 #endif
@@ -10,6 +10,11 @@ int isalnum(int c)
     );
 }
 
+int isdigit(int c)
+{
+    return (c >= '0' && c <= '9');
+}
+
 // Check if c is a printable character
 // Note that this includes spaces, but excludes \t, \r and \n
 int isprint(int c)
@@ -0,0 +1,74 @@
+#ifndef __ERRNO_H__
+#define __ERRNO_H__
+
+// The error codes are generated from api/syscalls.json
+#include <uvm/syscalls.h>
+
+// Syscalls that can fail return a negated error code, e.g. -EBADF.
+// Library functions report failures by setting errno instead.
+_Thread_local int errno = 0;
+
+// Convert the result of a syscall into the errno convention.
+// On failure, sets errno and returns -1, otherwise returns the result.
+long __syscall_ret(long result)
+{
+    if (result < 0)
+    {
+        errno = (int)-result;
+        return -1;
+    }
+
+    return result;
+}
+
+// Get a description of an error code
+char* strerror(int errnum)
+{
+    if (errnum == EPERM)
+        return "Operation not permitted";
+    if (errnum == ENOENT)
+        return "No such file or directory";
+    if (errnum == EIO)
+        return "Input/output error";
+    if (errnum == EBADF)
+        return "Invalid handle";
+    if (errnum == EAGAIN)
+        return "Resource temporarily unavailable, try again";
+    if (errnum == ENOMEM)
+        return "Out of memory";
+    if (errnum == EACCES)
+        return "Permission denied";
+    if (errnum == EBUSY)
+        return "Device or resource busy";
+    if (errnum == EEXIST)
+        return "File exists";
+    if (errnum == ENOTDIR)
+        return "Not a directory";
+    if (errnum == EISDIR)
+        return "Is a directory";
+    if (errnum == EINVAL)
+        return "Invalid argument";
+    if (errnum == EMFILE)
+        return "Too many open files";
+    if (errnum == ENOSPC)
+        return "No space left on device";
+    if (errnum == ENOSYS)
+        return "Function not implemented";
+    if (errnum == ENOTEMPTY)
+        return "Directory not empty";
+    if (errnum == EADDRINUSE)
+        return "Address already in use";
+    if (errnum == ECONNRESET)
+        return "Connection reset by peer";
+    if (errnum == ENOTCONN)
+        return "Not connected";
+    if (errnum == ETIMEDOUT)
+        return "Connection timed out";
+    if (errnum == ECONNREFUSED)
+        return "Connection refused";
+    if (errnum == EDQUOT)
+        return "Quota exceeded";
+    return "Unknown error";
+}
+
+#endif
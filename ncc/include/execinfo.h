@@ -0,0 +1,18 @@
+#ifndef __EXECINFO_H__
+#define __EXECINFO_H__
+
+#include <uvm/syscalls.h>
+
+// Store the return addresses of the active function calls in buffer,
+// starting with the address backtrace() itself returns to.
+// Returns the number of addresses stored, which is at most size.
+int backtrace(void** buffer, int size)
+{
+    if (size <= 0)
+        return 0;
+
+    u64 num_frames = vm_backtrace(buffer, size);
+    return (num_frames < size)? (int)num_frames:size;
+}
+
+#endif
@@ -0,0 +1,114 @@
+#ifndef __GETOPT_H__
+#define __GETOPT_H__
+
+#include <stdio.h>
+#include <string.h>
+
+// The command-line arguments of the program can be obtained by declaring
+// main as int main(int argc, char** argv), or with the proc_argc() and
+// proc_argv() syscalls.
+
+// Argument of the last option parsed, if it takes one
+char* optarg = NULL;
+
+// Index of the next argument to parse. Set it back to 1 to parse
+// another argument list. When getopt() returns -1, this is the index
+// of the first argument that isn't an option.
+int optind = 1;
+
+// If nonzero, getopt() prints a message on invalid options
+int opterr = 1;
+
+// Option character that caused the last error
+int optopt = 0;
+
+// Position of the next option character in a group like -abc
+int __optpos = 1;
+
+// Move on to the next argument
+void __getopt_next()
+{
+    ++optind;
+    __optpos = 1;
+}
+
+// Parse the next option of an argument list, POSIX-style.
+// The options string lists the valid option characters, and a character
+// followed by ':' takes an argument, either in the same argument (-ofile)
+// or in the next one (-o file). Parsing stops at the first argument that
+// isn't an option, or after "--". Returns the option character, '?' for an
+// invalid option, or -1 once all options were parsed. A missing argument
+// is reported as '?', or as ':' if the options string starts with ':'.
+int getopt(int argc, char** argv, char* options)
+{
+    optarg = NULL;
+
+    if (optind >= argc || argv[optind] == NULL)
+        return -1;
+
+    char* arg = argv[optind];
+
+    // Start of a new group of options
+    if (__optpos == 1)
+    {
+        if (arg[0] != '-' || arg[1] == '\0')
+            return -1;
+
+        if (strcmp(arg, "--") == 0)
+        {
+            ++optind;
+            return -1;
+        }
+    }
+
+    int ch = arg[__optpos];
+    ++__optpos;
+    int group_done = arg[__optpos] == '\0';
+    int silent = options[0] == ':';
+
+    char* spec = NULL;
+    if (ch != ':')
+        spec = strchr(options, ch);
+
+    if (spec == NULL)
+    {
+        optopt = ch;
+        if (opterr && !silent)
+            printf("%s: invalid option -- '%c'\n", argv[0], ch);
+        if (group_done)
+            __getopt_next();
+        return '?';
+    }
+
+    if (spec[1] != ':')
+    {
+        if (group_done)
+            __getopt_next();
+        return ch;
+    }
+
+    // The option takes an argument, which is either
+    // the rest of this argument or the next one
+    if (!group_done)
+    {
+        optarg = arg + __optpos;
+    }
+    else if (optind + 1 < argc)
+    {
+        ++optind;
+        optarg = argv[optind];
+    }
+    else
+    {
+        optopt = ch;
+        __getopt_next();
+        if (opterr && !silent)
+            printf("%s: option requires an argument -- '%c'\n", argv[0], ch);
+        return silent? ':':'?';
+    }
+
+    __getopt_next();
+    return ch;
+}
+
+#endif
@@ -15,25 +15,38 @@ int puts(char* str)
 #ifndef putchar
 int putchar(char ch)
 {
-    asm (ch) -> int { syscall putchar; };
+    return asm (ch) -> int { syscall putchar; };
 }
 #endif
 
 #ifndef getchar
 int getchar()
 {
-    asm () -> int { syscall getchar; };
+    return asm () -> int { syscall getchar; };
 }
 #endif
 
 // Internal buffer used by printf
 char* __buffer[32];
 
+// Write a run of characters which isn't null-terminated
+void __write_chars__(char* str, unsigned int len)
+{
+    if (len > 0)
+    {
+        asm (str, len) -> i64 { syscall io_write; };
+    }
+}
+
 int printf(char* format, ...)
 {
     unsigned int ch_written = 0;
     unsigned int var_arg_idx = 1;
 
+    // Start of the characters to print as they are, which
+    // are written in one go rather than one at a time
+    unsigned int run_start = 0;
+
     // For each character of the format string
     for (unsigned int i = 0;; ++i)
     {
@@ -41,17 +54,21 @@ int printf(char* format, ...)
 
         if (c == 0)
         {
+            __write_chars__(format + run_start, i - run_start);
             break;
         }
 
         // Format specifier
         if (c == '%')
         {
-            // Percent character
+            __write_chars__(format + run_start, i - run_start);
+            run_start = i;
+
+            // Percent character, the second one starts the next run
             if (format[i+1] == '%')
             {
-                putchar('%');
                 ++i;
+                run_start = i;
                 ++ch_written;
                 continue;
             }
@@ -60,6 +77,7 @@ int printf(char* format, ...)
             if (format[i+1] == 's')
             {
                 ++i;
+                run_start = i + 1;
 
                 // Get the integer argument and print it
                 asm (var_arg_idx) -> void {
@@ -75,6 +93,7 @@ int printf(char* format, ...)
             if (format[i+1] == 'd' || format[i+1] == 'i')
             {
                 ++i;
+                run_start = i + 1;
 
                 // Get the integer argument and print it
                 asm (var_arg_idx) -> void {
@@ -92,6 +111,7 @@ int printf(char* format, ...)
             if (format[i+1] == 'u')
             {
                 ++i;
+                run_start = i + 1;
 
                 // Get the integer argument and print it
                 asm (var_arg_idx) -> void {
@@ -112,6 +132,7 @@ int printf(char* format, ...)
             if (format[i+1] == 'f')
             {
                 ++i;
+                run_start = i + 1;
 
                 // Get the float argument and print it
                 asm (var_arg_idx) -> void {
@@ -128,10 +149,7 @@ int printf(char* format, ...)
             // That makes it easier to debug the problem than a panic.
         }
 
-        // Print this character
-        // NOTE: may want to be careful about printing
-        // in the middle of a UTF-8 unicode character?
-        putchar(c);
+        // This character is printed with the rest of the run
         ++ch_written;
     }
 
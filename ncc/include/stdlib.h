@@ -16,10 +16,18 @@ void exit(int status)
     asm (status) -> void { exit; };
 }
 
+// Register a function to be called when the program exits
+int atexit(void* func)
+{
+    asm (func) -> void { syscall proc_atexit; };
+    return 0;
+}
+
 // Convert long int to string
 char* ltoa(long value, char* str, int base)
 {
     assert(base > 0 && base <= 16);
+    char* start = str;
 
     // If negative, write a minus sign
     if (value < 0)
@@ -59,6 +67,8 @@ char* ltoa(long value, char* str, int base)
 
     // Write the null terminator
     str[num_digits] = '\0';
+
+    return start;
 }
 
 // Convert int to string
@@ -97,55 +107,22 @@ void srand(unsigned int seed)
     __cur_rand__ = (seed << 1) + 1;
 }
 
-#define align_ptr(ptr, n_bytes) (((u64)(ptr) + ((n_bytes) - 1)) & ~((n_bytes) - 1))
-
-u64 __heap_size__ = 0;
-u8* __next_alloc__ = 0;
-
+// The allocator is provided by the VM, which keeps its bookkeeping outside
+// of guest memory and reports invalid frees. When running with --asan or
+// --detect-leaks, the VM also tracks the blocks allocated.
 void* malloc(size_t size)
 {
-    // If this is the first allocation
-    if (__next_alloc__ == 0)
-    {
-        __heap_size__ = asm () -> u64 { syscall vm_heap_size; };
-        __next_alloc__ = (u8*)__heap_size__;
-    }
-
-    // Bump the allocation pointer
-    u8* header_ptr = __next_alloc__;
-    u8* block_ptr = header_ptr + 8;
-    __next_alloc__ = align_ptr(block_ptr + size, 8);
-
-    // Resize the heap if needed
-    if (__next_alloc__ > __heap_size__)
-    {
-        __heap_size__ = asm (__next_alloc__) -> u64 { syscall vm_resize_heap; };
-    }
-
-    // Write a magic word at the beginning of the block for safety checks
-    u32* magic_ptr = (u32*)header_ptr;
-    *magic_ptr = 0x1337BAB3;
+    return asm (size) -> void* { syscall mem_alloc; };
+}
 
-    return (void*)block_ptr;
+void* realloc(void* ptr, size_t size)
+{
+    return asm (ptr, size) -> void* { syscall mem_resize; };
 }
 
 void free(void* ptr)
 {
-    // Verify and clear the magic word
-    // This will help detect double-free errors
-    u8* header_ptr = ((u8*)ptr) - 8;
-    u32* magic_ptr = (u32*)header_ptr;
-
-    if (*magic_ptr != 0x1337BAB3)
-    {
-        asm ("magic word does not match in free()\n") -> void {
-            syscall print_str;
-            panic;
-        };
-    }
-
-    // Corrupt the magic word to detect double-free errors
-    *magic_ptr = 0x1111_1111;
+    asm (ptr) -> void { syscall mem_free; };
 }
 
 #endif
@@ -0,0 +1,25 @@
+#ifndef __UVM_EVENT_H__
+#define __UVM_EVENT_H__
+
+#include <uvm/syscalls.h>
+
+// Event dequeued with event_poll(), for the events which have no callback
+//
+// The source is the window, timer or socket the event comes from, and the
+// arguments are the remaining arguments the callback would have received,
+// for instance the button and the coordinates for EVENT_MOUSEDOWN, or the
+// number of bytes received for EVENT_NET_DATA. Its layout is described by
+// the EVENT_SIZE and EVENT_OFS_* constants.
+typedef struct
+{
+    // EVENT_* kind
+    u64 kind;
+
+    u64 source;
+
+    i64 arg0;
+    i64 arg1;
+    i64 arg2;
+} event_t;
+
+#endif
@@ -2,6 +2,7 @@
 #define __UVM_GRAPHICS__
 
 #include <assert.h>
+#include <math.h>
 #include <uvm/syscalls.h>
 #include <uvm/utils.h>
 
@@ -21,6 +22,66 @@
 #define rgb32(r, g, b) ((u32)0xFF_00_00_00 | ((u32)r << 16) | ((u32)g << 8) | (u32)b)
 #define rgba32(r, g, b, a) (((u32)a << 24) | ((u32)r << 16) | ((u32)g << 8) | (u32)b)
 
+// Bitmap in memory, used by the graphics syscalls
+typedef struct
+{
+    u32* pixels;
+    u32 width;
+    u32 height;
+} bitmap_t;
+
+// Rectangle within a bitmap
+typedef struct
+{
+    u32 x;
+    u32 y;
+    u32 width;
+    u32 height;
+} rect_t;
+
+// Sprite to draw with blit_sprites()
+typedef struct
+{
+    // Position in the destination bitmap
+    i32 x;
+    i32 y;
+
+    // Rectangle to copy from the sprite sheet
+    u32 src_x;
+    u32 src_y;
+    u32 width;
+    u32 height;
+
+    // SPRITE_FLIP_X, SPRITE_FLIP_Y, SPRITE_OPAQUE
+    u32 flags;
+} sprite_t;
+
+// Tile map to draw with draw_tilemap()
+typedef struct
+{
+    bitmap_t* tileset;
+
+    // Tile indices in row-major order, TILE_EMPTY for no tile
+    u16* tiles;
+    u32 map_width;
+    u32 map_height;
+
+    u32 tile_width;
+    u32 tile_height;
+} tilemap_t;
+
+// Fill a 256-entry lookup table for gamma correction with apply_lut()
+void gamma_lut(u8* lut, float gamma)
+{
+    assert(gamma > 0.0f);
+
+    for (int i = 0; i < 256; ++i)
+    {
+        float f = powf((float)i / 255.0f, 1.0f / gamma);
+        lut[i] = (u8)(int)(f * 255.0f + 0.5f);
+    }
+}
+
 // Fill a rectangle area with a given color
 void fill_rect(
     u32* fb,
@@ -0,0 +1,498 @@
+#ifndef __UVM_JSON_H__
+#define __UVM_JSON_H__
+
+#include <stddef.h>
+#include <stdlib.h>
+#include <string.h>
+
+// Minimal JSON parser and writer
+//
+// The parser doesn't allocate memory. It splits the input text into
+// tokens that point back into it, which is simple and fast,
+// and leaves it to you to decide how to convert values.
+// Tokens are stored in document order, a container token
+// is directly followed by its children, and an object key
+// is a string token whose child is the associated value.
+
+// Token types
+#define JSON_OBJECT 1
+#define JSON_ARRAY 2
+#define JSON_STRING 3
+#define JSON_PRIMITIVE 4
+
+// Error codes returned by json_parse
+#define JSON_ERROR_NOMEM -1
+#define JSON_ERROR_INVAL -2
+#define JSON_ERROR_PART -3
+
+typedef struct
+{
+    // Token type
+    int type;
+
+    // Start and end positions in the JSON text,
+    // strings exclude the quotes
+    int start;
+    int end;
+
+    // Number of children, object keys count as one child
+    int size;
+
+    // Index of the parent token, or -1
+    int parent;
+} json_tok_t;
+
+// Allocate a token and link it to its parent
+int __json_new_tok(json_tok_t* toks, int num_toks, int max_toks, int parent, int type, int start, int end)
+{
+    if (num_toks >= max_toks)
+        return JSON_ERROR_NOMEM;
+
+    toks[num_toks].type = type;
+    toks[num_toks].start = start;
+    toks[num_toks].end = end;
+    toks[num_toks].size = 0;
+    toks[num_toks].parent = parent;
+
+    if (parent != -1)
+        ++toks[parent].size;
+
+    return num_toks;
+}
+
+// Check that a new value may appear under a given parent
+bool __json_value_ok(json_tok_t* toks, int parent)
+{
+    if (parent == -1)
+        return true;
+
+    // Values in objects must come after a key
+    if (toks[parent].type == JSON_OBJECT)
+        return false;
+
+    // A key or an array element may only have one value
+    if (toks[parent].type == JSON_STRING)
+        return toks[parent].size == 0;
+
+    return true;
+}
+
+// Parse a JSON string of a given length into an array of tokens
+// Returns the number of tokens, or a negative error code
+int json_parse(char* js, size_t len, json_tok_t* toks, int max_toks)
+{
+    int num_toks = 0;
+
+    // Container or key that new values are added to
+    int parent = -1;
+
+    for (size_t pos = 0; pos < len && js[pos] != '\0'; ++pos)
+    {
+        char c = js[pos];
+
+        if (c == ' ' || c == '\t' || c == '\n' || c == '\r')
+            continue;
+
+        if (c == '{' || c == '[')
+        {
+            // Objects can be keys in non-strict parsers, not here
+            if (parent != -1 && toks[parent].type == JSON_OBJECT)
+                return JSON_ERROR_INVAL;
+            if (!__json_value_ok(toks, parent))
+                return JSON_ERROR_INVAL;
+
+            int type = (c == '{')? JSON_OBJECT:JSON_ARRAY;
+            int tok = __json_new_tok(toks, num_toks, max_toks, parent, type, (int)pos, -1);
+            if (tok < 0)
+                return tok;
+            ++num_toks;
+            parent = tok;
+            continue;
+        }
+
+        if (c == '}' || c == ']')
+        {
+            if (parent == -1)
+                return JSON_ERROR_INVAL;
+
+            // Finish the value of the last key
+            if (toks[parent].type == JSON_STRING)
+                parent = toks[parent].parent;
+
+            int type = (c == '}')? JSON_OBJECT:JSON_ARRAY;
+            if (toks[parent].type != type)
+                return JSON_ERROR_INVAL;
+
+            toks[parent].end = (int)pos + 1;
+            parent = toks[parent].parent;
+            continue;
+        }
+
+        if (c == '"')
+        {
+            size_t start = pos + 1;
+
+            // Find the closing quote, skipping over escape sequences
+            for (pos = start; pos < len && js[pos] != '"'; ++pos)
+            {
+                if (js[pos] == '\0')
+                    return JSON_ERROR_PART;
+                if (js[pos] == '\\')
+                    ++pos;
+            }
+
+            if (pos >= len)
+                return JSON_ERROR_PART;
+
+            // Strings directly inside objects are keys
+            if (parent != -1 && toks[parent].type != JSON_OBJECT && !__json_value_ok(toks, parent))
+                return JSON_ERROR_INVAL;
+
+            int tok = __json_new_tok(toks, num_toks, max_toks, parent, JSON_STRING, (int)start, (int)pos);
+            if (tok < 0)
+                return tok;
+            ++num_toks;
+            continue;
+        }
+
+        if (c == ':')
+        {
+            // The last token must be a key in the current object
+            if (num_toks == 0 || parent == -1 || toks[parent].type != JSON_OBJECT)
+                return JSON_ERROR_INVAL;
+            if (toks[num_toks - 1].type != JSON_STRING || toks[num_toks - 1].parent != parent)
+                return JSON_ERROR_INVAL;
+
+            parent = num_toks - 1;
+            continue;
+        }
+
+        if (c == ',')
+        {
+            // Go back up from a key to its object
+            if (parent != -1 && toks[parent].type == JSON_STRING)
+                parent = toks[parent].parent;
+            continue;
+        }
+
+        // Primitive value, that is a number, true, false or null
+        if (c != '-' && !isdigit(c) && c != 't' && c != 'f' && c != 'n')
+            return JSON_ERROR_INVAL;
+        if (!__json_value_ok(toks, parent))
+            return JSON_ERROR_INVAL;
+
+        size_t start = pos;
+        while (pos < len)
+        {
+            c = js[pos];
+            if (c == '\0' || c == ' ' || c == '\t' || c == '\n' || c == '\r' || c == ',' || c == ']' || c == '}' || c == ':')
+                break;
+            ++pos;
+        }
+
+        int tok = __json_new_tok(toks, num_toks, max_toks, parent, JSON_PRIMITIVE, (int)start, (int)pos);
+        if (tok < 0)
+            return tok;
+        ++num_toks;
+
+        // The loop increment moves past the primitive
+        --pos;
+    }
+
+    // Check that all containers were closed
+    for (int i = 0; i < num_toks; ++i)
+    {
+        if (toks[i].end == -1)
+            return JSON_ERROR_PART;
+    }
+
+    return num_toks;
+}
+
+// Get the index of the token following a token and all of its children
+int json_skip(json_tok_t* toks, int idx)
+{
+    int next = idx + 1;
+
+    for (int i = 0; i < toks[idx].size; ++i)
+        next = json_skip(toks, next);
+
+    return next;
+}
+
+// Check if a token matches a given string
+bool json_eq(char* js, json_tok_t* tok, char* str)
+{
+    int len = tok->end - tok->start;
+
+    if (strlen(str) != (size_t)len)
+        return false;
+
+    return strncmp(js + tok->start, str, (size_t)len) == 0;
+}
+
+// Get the index of the value associated with a key in an object
+// Returns -1 if the key is not found
+int json_obj_get(char* js, json_tok_t* toks, int obj_idx, char* key)
+{
+    if (toks[obj_idx].type != JSON_OBJECT)
+        return -1;
+
+    int idx = obj_idx + 1;
+
+    for (int i = 0; i < toks[obj_idx].size; ++i)
+    {
+        if (json_eq(js, toks + idx, key))
+            return idx + 1;
+
+        idx = json_skip(toks, idx);
+    }
+
+    return -1;
+}
+
+// Get the index of the nth element of an array
+// Returns -1 if the index is out of bounds
+int json_arr_get(json_tok_t* toks, int arr_idx, int n)
+{
+    if (toks[arr_idx].type != JSON_ARRAY || n < 0 || n >= toks[arr_idx].size)
+        return -1;
+
+    int idx = arr_idx + 1;
+
+    for (int i = 0; i < n; ++i)
+        idx = json_skip(toks, idx);
+
+    return idx;
+}
+
+// Get the integer value of a primitive token
+long json_get_int(char* js, json_tok_t* tok)
+{
+    long val = 0;
+    int pos = tok->start;
+    bool neg = false;
+
+    if (js[pos] == '-')
+    {
+        neg = true;
+        ++pos;
+    }
+
+    for (; pos < tok->end && isdigit(js[pos]); ++pos)
+        val = val * 10 + (js[pos] - '0');
+
+    return neg? -val:val;
+}
+
+// Get the boolean value of a primitive token
+bool json_get_bool(char* js, json_tok_t* tok)
+{
+    return js[tok->start] == 't';
+}
+
+// Check if a primitive token is null
+bool json_is_null(char* js, json_tok_t* tok)
+{
+    return tok->type == JSON_PRIMITIVE && js[tok->start] == 'n';
+}
+
+// Copy the value of a string token into a buffer, decoding escape sequences
+// The output is always null-terminated, and the length is returned
+size_t json_get_str(char* js, json_tok_t* tok, char* buf, size_t buf_len)
+{
+    size_t len = 0;
+
+    for (int pos = tok->start; pos < tok->end && len + 1 < buf_len; ++pos)
+    {
+        char c = js[pos];
+
+        if (c == '\\')
+        {
+            ++pos;
+            c = js[pos];
+
+            if (c == 'n')
+                c = '\n';
+            else if (c == 't')
+                c = '\t';
+            else if (c == 'r')
+                c = '\r';
+            else if (c == 'b')
+                c = (char)8;
+            else if (c == 'f')
+                c = (char)12;
+            else if (c == 'u')
+            {
+                // Only code points below 128 are decoded
+                int code = 0;
+                for (int i = 0; i < 4 && pos + 1 < tok->end; ++i)
+                {
+                    ++pos;
+                    int h = tolower(js[pos]);
+                    if (isdigit(h))
+                        code = code * 16 + (h - '0');
+                    else
+                        code = code * 16 + (h - 'a' + 10);
+                }
+                c = (code < 128)? (char)code:(char)'?';
+            }
+        }
+
+        buf[len] = c;
+        ++len;
+    }
+
+    buf[len] = '\0';
+    return len;
+}
+
+typedef struct
+{
+    // Output buffer
+    char* buf;
+    size_t cap;
+    size_t len;
+
+    // Set if the output didn't fit in the buffer
+    int overflow;
+
+    // Set if a comma is needed before the next value
+    int need_comma;
+} json_writer_t;
+
+// Initialize a writer to output into a given buffer
+void json_writer_init(json_writer_t* w, char* buf, size_t cap)
+{
+    w->buf = buf;
+    w->cap = cap;
+    w->len = 0;
+    w->overflow = 0;
+    w->need_comma = 0;
+
+    if (cap > 0)
+        buf[0] = '\0';
+}
+
+// Append a character, keeping the output null-terminated
+void __json_put(json_writer_t* w, char c)
+{
+    if (w->len + 1 >= w->cap)
+    {
+        w->overflow = 1;
+        return;
+    }
+
+    w->buf[w->len] = c;
+    ++w->len;
+    w->buf[w->len] = '\0';
+}
+
+void __json_puts(json_writer_t* w, char* s)
+{
+    for (; *s != '\0'; ++s)
+        __json_put(w, *s);
+}
+
+// Write a comma if this isn't the first value in a container
+void __json_sep(json_writer_t* w)
+{
+    if (w->need_comma)
+        __json_put(w, ',');
+
+    w->need_comma = 1;
+}
+
+void json_begin_object(json_writer_t* w)
+{
+    __json_sep(w);
+    __json_put(w, '{');
+    w->need_comma = 0;
+}
+
+void json_end_object(json_writer_t* w)
+{
+    __json_put(w, '}');
+    w->need_comma = 1;
+}
+
+void json_begin_array(json_writer_t* w)
+{
+    __json_sep(w);
+    __json_put(w, '[');
+    w->need_comma = 0;
+}
+
+void json_end_array(json_writer_t* w)
+{
+    __json_put(w, ']');
+    w->need_comma = 1;
+}
+
+// Write a string, escaping special characters
+void json_write_str(json_writer_t* w, char* s)
+{
+    __json_sep(w);
+    __json_put(w, '"');
+
+    for (; *s != '\0'; ++s)
+    {
+        char c = *s;
+
+        if (c == '"' || c == '\\')
+        {
+            __json_put(w, '\\');
+            __json_put(w, c);
+        }
+        else if (c == '\n')
+            __json_puts(w, "\\n");
+        else if (c == '\t')
+            __json_puts(w, "\\t");
+        else if (c == '\r')
+            __json_puts(w, "\\r");
+        else if ((u8)c < 32)
+        {
+            __json_puts(w, "\\u00");
+            __json_put(w, "0123456789abcdef"[(u8)c / 16]);
+            __json_put(w, "0123456789abcdef"[(u8)c % 16]);
+        }
+        else
+            __json_put(w, c);
+    }
+
+    __json_put(w, '"');
+}
+
+// Write an object key, the value must be written next
+void json_key(json_writer_t* w, char* key)
+{
+    json_write_str(w, key);
+    __json_put(w, ':');
+    w->need_comma = 0;
+}
+
+char __json_num_buf[24];
+
+void json_write_int(json_writer_t* w, long val)
+{
+    __json_sep(w);
+    ltoa(val, __json_num_buf, 10);
+    __json_puts(w, __json_num_buf);
+}
+
+void json_write_bool(json_writer_t* w, bool val)
+{
+    __json_sep(w);
+    if (val)
+        __json_puts(w, "true");
+    else
+        __json_puts(w, "false");
+}
+
+void json_write_null(json_writer_t* w)
+{
+    __json_sep(w);
+    __json_puts(w, "null");
+}
+
+#endif
@@ -0,0 +1,96 @@
+#ifndef __UVM_LOOP_H__
+#define __UVM_LOOP_H__
+
+#include <assert.h>
+#include <uvm/syscalls.h>
+#include <uvm/utils.h>
+
+// Fixed-timestep update/render loop
+//
+// The update callback advances the program state by a fixed time step,
+// so that animations and physics behave the same regardless of the frame
+// rate. The render callback is called once per frame, and receives the
+// fraction of a time step that has elapsed since the last update, in
+// [0, 1), which can be used to interpolate between the last two states:
+//
+//   void update();
+//   void render(float alpha);
+//
+// When frames are slow, multiple updates are run before the next render.
+// The accumulated time is capped to LOOP_MAX_STEPS steps so that the
+// program can't fall further and further behind.
+
+#ifndef LOOP_MAX_STEPS
+#define LOOP_MAX_STEPS 8
+#endif
+
+u64 __loop_step_ms__ = 0;
+u64 __loop_frame_ms__ = 0;
+void* __loop_update__ = 0;
+void* __loop_render__ = 0;
+bool __loop_running__ = false;
+
+// Time of the last frame, and time not yet consumed by updates
+u64 __loop_last_time__ = 0;
+u64 __loop_accum__ = 0;
+
+void __loop_frame__()
+{
+    // The loop may have been stopped since this frame was scheduled
+    if (!__loop_running__)
+        return;
+
+    u64 start_time = time_current_ms();
+    __loop_accum__ = __loop_accum__ + (start_time - __loop_last_time__);
+    __loop_last_time__ = start_time;
+
+    u64 max_accum = LOOP_MAX_STEPS * __loop_step_ms__;
+    if (__loop_accum__ > max_accum)
+        __loop_accum__ = max_accum;
+
+    while (__loop_accum__ >= __loop_step_ms__)
+    {
+        asm (__loop_update__) -> u64 { call_fp 0; };
+        __loop_accum__ = __loop_accum__ - __loop_step_ms__;
+
+        // The update callback can stop the loop
+        if (!__loop_running__)
+            return;
+    }
+
+    // The accumulator is less than one step here, so it fits in an int
+    float alpha = (float)(int)__loop_accum__ / (float)(int)__loop_step_ms__;
+    asm (alpha, __loop_render__) -> u64 { call_fp 1; };
+
+    if (__loop_running__)
+        fixed_rate_update(start_time, __loop_frame_ms__, __loop_frame__);
+}
+
+// Start calling update every step_ms milliseconds, and render
+// at most once every frame_ms milliseconds
+void loop_start(u64 step_ms, u64 frame_ms, void* update, void* render)
+{
+    assert(step_ms > 0);
+    assert(!__loop_running__);
+
+    __loop_step_ms__ = step_ms;
+    __loop_frame_ms__ = frame_ms;
+    __loop_update__ = update;
+    __loop_render__ = render;
+    __loop_running__ = true;
+
+    // Render the initial state before the first update
+    __loop_last_time__ = time_current_ms();
+    __loop_accum__ = 0;
+    time_delay_cb(0, __loop_frame__);
+
+    enable_event_loop();
+}
+
+// Stop the loop, no more updates or frames are run after this call
+void loop_stop()
+{
+    __loop_running__ = false;
+}
+
+#endif
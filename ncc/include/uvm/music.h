@@ -46,6 +46,54 @@ char* PC_NOTE_NAME[12] = {
 };
 */
 
+// Oscillator to fill buffers with audio_osc()
+typedef struct
+{
+    u32 sample_rate;
+    float freq;
+
+    // Amplitude in [0, 1]
+    float amplitude;
+
+    // Position in the period of the wave, in [0, 1)
+    float phase;
+} osc_t;
+
+// ADSR envelope to apply with audio_envelope()
+// Durations and positions are counted in samples
+typedef struct
+{
+    u32 attack;
+    u32 decay;
+
+    // Sustain level in [0, 1]
+    float sustain;
+    u32 release;
+
+    // Samples since the note started
+    u64 pos;
+
+    // Position at which the note is released, UINT64_MAX while held
+    u64 release_pos;
+} envelope_t;
+
+// Biquad filter set up with audio_biquad_init() and run with audio_biquad()
+typedef struct
+{
+    // Coefficients
+    float b0;
+    float b1;
+    float b2;
+    float a1;
+    float a2;
+
+    // Previous inputs and outputs
+    float x1;
+    float x2;
+    float y1;
+    float y2;
+} biquad_t;
+
 // Get the frequency for a note
 // offset is detuning offset in cents
 float pc_to_freq(unsigned int note_no, float offset)
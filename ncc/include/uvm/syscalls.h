@@ -26,9 +26,73 @@
 #define vm_heap_size() asm () -> u64 { syscall vm_heap_size; }
 
 // u64 vm_resize_heap(u64 num_bytes)
-// Resize the heap to a new size given in bytes. This is similar to the `brk()` system call on POSIX systems. Note that the heap may be resized to a size larger than requested. The heap size is guaranteed to be a multiple of 8 bytes. Returns the new heap size in bytes if successful, or `UINT64_MAX` on failure.
+// Resize the heap to a new size given in bytes. This is similar to the `brk()` system call on POSIX systems. Note that the heap may be resized to a size larger than requested. The heap size is guaranteed to be a multiple of 8 bytes. Returns the new heap size in bytes if successful, or `UINT64_MAX` on failure, such as when the heap would grow past the maximum heap size set by the host.
 #define vm_resize_heap(__num_bytes) asm (__num_bytes) -> u64 { syscall vm_resize_heap; }
 
+// i64 vm_grow_heap(u64 num_bytes)
+// Grow the heap by at least `num_bytes` bytes, similar to the `sbrk()` system call on POSIX systems. The new memory is zero-initialized. Returns the previous heap size, which is the address of the new memory, or `-ENOMEM` if the heap would grow past the maximum heap size set by the host, such as with the `--max-heap` option of the `uvm` command.
+#define vm_grow_heap(__num_bytes) asm (__num_bytes) -> i64 { syscall vm_grow_heap; }
+
+// bool vm_track_alloc(void* ptr, u64 num_bytes)
+// Inform the VM that a block of memory was allocated by the guest allocator. Returns false unless the VM is running with `--asan` or `--detect-leaks`. With `--asan`, the 8 bytes before the block and at least 8 bytes after it are treated as redzones, and guest accesses outside of allocated blocks are reported. With `--detect-leaks`, blocks that are never freed are reported when the program exits.
+#define vm_track_alloc(__ptr, __num_bytes) asm (__ptr, __num_bytes) -> bool { syscall vm_track_alloc; }
+
+// bool vm_track_free(void* ptr)
+// Inform the VM that a block of memory allocated with `vm_track_alloc` was freed. Returns false unless the VM is running with `--asan` or `--detect-leaks`. In those modes, invalid and double frees are reported. With `--asan`, accesses to the freed block are also detected as use-after-free errors.
+#define vm_track_free(__ptr) asm (__ptr) -> bool { syscall vm_track_free; }
+
+// u64 vm_heap_walk(u64* entries, u64 max_entries)
+// List the live blocks reported with `vm_track_alloc`, in increasing address order. Up to `max_entries` entries are written to the `entries` buffer, each made of three `u64` values: the address of the block, its size in bytes, and the pc of the call to the allocator which allocated it. Returns the total number of live blocks, which can be larger than `max_entries`, or `UINT64_MAX` unless the VM is running with `--asan` or `--detect-leaks`.
+#define vm_heap_walk(__entries, __max_entries) asm (__entries, __max_entries) -> u64 { syscall vm_heap_walk; }
+
+// u64 vm_backtrace(u64* ret_addrs, u64 max_frames)
+// Walk the call stack of the running program. The return addresses of the active function calls are written to the `ret_addrs` buffer, innermost call first, up to `max_frames` of them. The first address is the one the current function returns to. The call made by the VM to start the program or to run a callback has no return address and is not listed. Returns the total number of return addresses, which can be larger than `max_frames`.
+#define vm_backtrace(__ret_addrs, __max_frames) asm (__ret_addrs, __max_frames) -> u64 { syscall vm_backtrace; }
+
+// void vm_yield()
+// Give control back to the host, which decides when execution continues. The `uvm` command-line tool continues running the program right away. Hosts embedding the VM can use this to run programs cooperatively.
+#define vm_yield() asm () -> void { syscall vm_yield; }
+
+// u32 sys_version()
+// Report the version of the running VM, encoded as `(major << 16) | (minor << 8) | patch`. Programs can use this together with `sys_has_syscall` to degrade gracefully when running on an older VM.
+#define sys_version() asm () -> u32 { syscall sys_version; }
+
+// bool sys_has_syscall(u16 const_idx)
+// Check if the running VM implements the syscall with a given index. The index of each syscall is available in C as `SYS_<name>`, for instance `SYS_net_read`. Returns false for indices that are out of range.
+#define sys_has_syscall(__const_idx) asm (__const_idx) -> bool { syscall sys_has_syscall; }
+
+// u64 sys_batch(u64* entries, u64 num_entries)
+// Run a batch of syscalls in one call, which saves the dispatch overhead of each one for programs making many small calls, such as drawing primitives or writing file chunks. Each entry of the array is `SYS_BATCH_ENTRY_SIZE` bytes long, and holds six u64 values: the syscall index, up to four arguments, and the result, which is written back once the syscall returns, or set to 0 if it returns nothing. The syscalls run in order, and the batch stops at the first entry whose syscall doesn't exist, or makes the thread wait or yield, such as `thread_join`, `mutex_lock`, `cond_wait`, `vm_yield` and `sys_batch` itself, in which case the result of that entry is set to `-ENOSYS`. Returns the number of entries that ran.
+#define sys_batch(__entries, __num_entries) asm (__entries, __num_entries) -> u64 { syscall sys_batch; }
+
+// void vm_set_trap_handler(void* handler)
+// Register a function to call when the program causes an error, such as a division by zero, an out-of-bounds or unaligned memory access, an unknown syscall or a call stack overflow, instead of the VM stopping with an error message. The handler is called on top of the call stack at the point of the error, with the `TRAP_*` code of the error, the address of the faulting instruction, and the faulting memory address or syscall number. It should report the error and exit, because if it returns, the VM stops as if there was no handler. Registering a handler also enables memory access checks. Pass `NULL` to remove the handler.
+#define vm_set_trap_handler(__handler) asm (__handler) -> void { syscall vm_set_trap_handler; }
+
+// void vm_mem_layout(u64* regions)
+// Write the start address and size in bytes of each memory region to an array of 8 values, with the start of region `MEM_REGION_*` at index `2 * MEM_REGION_*` and its size right after it. The code region is in the separate code address space. The data region holds the initialized data and string constants of the program, and the heap region is the rest of the heap after it, which grows with `vm_resize_heap`. The stack region is the value stack, which is not addressable, and only its size is meaningful.
+#define vm_mem_layout(__regions) asm (__regions) -> void { syscall vm_mem_layout; }
+
+// i64 vm_mem_protect(void* addr, u64 num_bytes, u32 prot)
+// Change the protection of a range of heap pages to a combination of `MEM_PROT_READ` and `MEM_PROT_WRITE`. The address must be a multiple of `MEM_PAGE_SIZE`, and the size is rounded up to a multiple of it. Load and store instructions which access a page without the matching permission cause a `TRAP_PROTECTION` trap, for instance to place guard pages around allocator metadata. Syscalls which access memory are not affected. Protecting memory enables memory access checks. Returns 0 on success, or `-EINVAL` if the range is not aligned or not inside the heap.
+#define vm_mem_protect(__addr, __num_bytes, __prot) asm (__addr, __num_bytes, __prot) -> i64 { syscall vm_mem_protect; }
+
+// i64 vm_mem_query(void* addr)
+// Get the `MEM_PROT_*` protection flags of the heap page containing an address, or `-EINVAL` if the address is not inside the heap.
+#define vm_mem_query(__addr) asm (__addr) -> i64 { syscall vm_mem_query; }
+
+// void* mem_alloc(u64 num_bytes)
+// Allocate a block of memory of at least `num_bytes` bytes, aligned to 8 bytes, at the end of the heap, which grows as needed. The contents of the block are undefined. Memory below the heap size at the time of the call is never allocated, so programs can keep using it. Returns a null pointer if the size is too large. With `--asan` or `--detect-leaks`, the block is tracked as with `vm_track_alloc`.
+#define mem_alloc(__num_bytes) asm (__num_bytes) -> void* { syscall mem_alloc; }
+
+// void mem_free(void* ptr)
+// Free a block allocated with `mem_alloc` or `mem_resize`, so that its memory can be reused. Freeing a null pointer does nothing. Freeing any other address which isn't the start of an allocated block stops the program with an error.
+#define mem_free(__ptr) asm (__ptr) -> void { syscall mem_free; }
+
+// void* mem_resize(void* ptr, u64 num_bytes)
+// Change the size of a block allocated with `mem_alloc` or `mem_resize`, like `realloc()` in C. The block is resized in place if possible, otherwise it is moved to a new address, with its contents up to the smaller of the two sizes. The old address must not be used after the call. Resizing a null pointer allocates a new block. Returns a null pointer if the size is too large, in which case the block is left unchanged.
+#define mem_resize(__ptr, __num_bytes) asm (__ptr, __num_bytes) -> void* { syscall mem_resize; }
+
 // void print_i64(i64 val)
 // Print an i64 value to standard output.
 #define print_i64(__val) asm (__val) -> void { syscall print_i64; }
@@ -37,6 +101,10 @@
 // Print an f32 value to standard output.
 #define print_f32(__val) asm (__val) -> void { syscall print_f32; }
 
+// void print_f64(f64 val)
+// Print an f64 value to standard output.
+#define print_f64(__val) asm (__val) -> void { syscall print_f64; }
+
 // void print_str(const char* str)
 // Print a string to standard output.
 #define print_str(__str) asm (__str) -> void { syscall print_str; }
@@ -46,13 +114,133 @@
 #define print_endl() asm () -> void { syscall print_endl; }
 
 // i32 putchar(i32 char)
-// Write one byte to standard output. This is a blocking function. The value -1 is returned on end of file or error. Otherwise the byte written is returned.
+// Write one byte to standard output. This is a blocking function. The value -1 is returned on end of file or error, and `-EDQUOT` if the bytes written quota is used up. Otherwise the byte written is returned.
 #define putchar(__char) asm (__char) -> i32 { syscall putchar; }
 
+// i64 io_write(const void* buf, u64 num_bytes)
+// Write a buffer of `num_bytes` bytes to standard output, which doesn't need to be null-terminated, and can hold any bytes. This is a blocking function. Returns the number of bytes written, `-EDQUOT` if the bytes written quota doesn't allow writing all of them, in which case nothing is written, or another negated error code on failure.
+#define io_write(__buf, __num_bytes) asm (__buf, __num_bytes) -> i64 { syscall io_write; }
+
 // i32 getchar()
 // Read one byte from standard input. This is a blocking function. The value -1 is returned on end of file or error.
 #define getchar() asm () -> i32 { syscall getchar; }
 
+// i64 read_line(char* buf, u64 buf_len)
+// Read a line from standard input into a buffer of `buf_len` bytes, as a null-terminated string which includes the newline, if there is one. A line which doesn't fit in the buffer is returned in multiple parts, and only the last part ends with a newline. This is a blocking function. Returns the number of bytes read, not counting the null terminator, which is 0 only once the end of the input is reached, or a negated error code on failure.
+#define read_line(__buf, __buf_len) asm (__buf, __buf_len) -> i64 { syscall read_line; }
+
+// void console_clear()
+// Clear the terminal and move the cursor to the top-left corner.
+#define console_clear() asm () -> void { syscall console_clear; }
+
+// void console_move_cursor(u32 row, u32 col)
+// Move the cursor to a given row and column, counted from 0 at the top-left corner of the terminal. Text printed afterwards starts at that position.
+#define console_move_cursor(__row, __col) asm (__row, __col) -> void { syscall console_move_cursor; }
+
+// void console_set_style(u8 fg, u8 bg, u32 attrs)
+// Set the foreground and background colors and the attributes of text printed afterwards. Colors are one of the 8 `CONSOLE_*` colors, optionally plus `CONSOLE_BRIGHT`, or `CONSOLE_DEFAULT` for the default color of the terminal. The attributes are a combination of the `CONSOLE_BOLD`, `CONSOLE_UNDERLINE` and `CONSOLE_REVERSE` flags. Use `console_set_style(CONSOLE_DEFAULT, CONSOLE_DEFAULT, 0)` to restore the default style.
+#define console_set_style(__fg, __bg, __attrs) asm (__fg, __bg, __attrs) -> void { syscall console_set_style; }
+
+// void console_show_cursor(bool visible)
+// Show or hide the cursor.
+#define console_show_cursor(__visible) asm (__visible) -> void { syscall console_show_cursor; }
+
+// u64 console_size()
+// Get the size of the terminal, encoded as `(rows << 32) | cols`. Returns 0 if the output isn't a terminal.
+#define console_size() asm () -> u64 { syscall console_size; }
+
+// bool console_set_raw(bool enabled)
+// Enable or disable raw input mode. In raw mode, keys are available to `console_read_key` as soon as they are pressed, instead of once a full line was typed, and they aren't echoed. Ctrl-C still stops the program. The terminal is restored when the program exits. Returns false if the input isn't a terminal.
+#define console_set_raw(__enabled) asm (__enabled) -> bool { syscall console_set_raw; }
+
+// i64 console_read_key(bool blocking)
+// Read a key from the terminal. Printable characters are returned as their byte value, and arrow keys, return, backspace and escape as the corresponding `KEY_*` constants of the window subsystem. If `blocking` is false and no key was pressed, returns -1 immediately. Also returns -1 at the end of the input. Reads the input directly, so it shouldn't be mixed with `getchar`.
+#define console_read_key(__blocking) asm (__blocking) -> i64 { syscall console_read_key; }
+
+// void proc_atexit(void* callback)
+// Register a callback to be called when the program terminates, either by executing `exit` or by being interrupted with ctrl-C. Callbacks are called in reverse order of registration.
+#define proc_atexit(__callback) asm (__callback) -> void { syscall proc_atexit; }
+
+// u64 proc_spawn(const char* cmd, u8* out_buf, u64 out_buf_len, void* on_exit)
+// Run a shell command on the host in the background. When the command terminates, its standard output is copied into the output buffer, truncated to the buffer length, and the on_exit callback is called with the process id, exit code and number of output bytes. The exit code is -1 if the process was killed by a signal. Returns 0 if spawning processes is not allowed, which requires running the VM with `--allow-exec`.
+#define proc_spawn(__cmd, __out_buf, __out_buf_len, __on_exit) asm (__cmd, __out_buf, __out_buf_len, __on_exit) -> u64 { syscall proc_spawn; }
+
+// u64 proc_argc()
+// Get the number of command-line arguments of the program, including the path of the program itself. Arguments that follow the program path on the VM command line are passed to the program.
+#define proc_argc() asm () -> u64 { syscall proc_argc; }
+
+// char** proc_argv()
+// Get the command-line arguments of the program as an array of `argc` null-terminated strings, followed by a null pointer. The first argument is the path of the program. The first call copies the arguments at the end of the heap, which grows the heap, and later calls return the same array.
+#define proc_argv() asm () -> char** { syscall proc_argv; }
+
+// u64 module_load(const char* path)
+// Load a module from an assembly file or a binary image file. The module's code and data sections are placed after those of the running program, and its exports become available to modules loaded later. Returns the address of the module's entry point, which is the start of its code section, or 0 if the module could not be loaded.
+#define module_load(__path) asm (__path) -> u64 { syscall module_load; }
+
+// u64 module_symbol(const char* name)
+// Get the address of a label exported by the main program or by a loaded module. Returns 0 if no such label is exported.
+#define module_symbol(__name) asm (__name) -> u64 { syscall module_symbol; }
+
+// u64 tls_alloc(const void* init, u64 num_bytes)
+// Reserve space in the TLS block of every thread. The new space is 8-byte aligned and initialized with a copy of `num_bytes` bytes starting at `init`, or zeroed if `init` is null. The existing TLS block is moved when it grows, so addresses obtained from `tls_base` before this call become invalid. This can't be called while other threads are running. Returns the offset of the new space in the TLS block.
+#define tls_alloc(__init, __num_bytes) asm (__init, __num_bytes) -> u64 { syscall tls_alloc; }
+
+// void* tls_base()
+// Get the address of the TLS block of the current thread, or null if no TLS space was allocated.
+#define tls_base() asm () -> void* { syscall tls_base; }
+
+// u64 thread_spawn(void* fun, void* arg)
+// Start a thread which calls the function at address `fun` with `arg` as its only argument, and runs until that function returns. The thread runs after the threads which are already waiting to run. Returns the id of the new thread, or 0 if its TLS block could not be allocated.
+#define thread_spawn(__fun, __arg) asm (__fun, __arg) -> u64 { syscall thread_spawn; }
+
+// u64 thread_join(u64 tid)
+// Wait for a thread to finish and get the value its function returned. Each thread can only be joined once, by a single thread, and the main thread can't be joined.
+#define thread_join(__tid) asm (__tid) -> u64 { syscall thread_join; }
+
+// u64 thread_id()
+// Get the id of the current thread, which is 0 for the main thread.
+#define thread_id() asm () -> u64 { syscall thread_id; }
+
+// u64 mutex_create()
+// Create a mutex, which is unlocked, and get its id.
+#define mutex_create() asm () -> u64 { syscall mutex_create; }
+
+// void mutex_lock(u64 mutex)
+// Lock a mutex. If another thread holds the mutex, the current thread waits until it is unlocked. Locking a mutex which the current thread already holds stops the program with an error.
+#define mutex_lock(__mutex) asm (__mutex) -> void { syscall mutex_lock; }
+
+// void mutex_unlock(u64 mutex)
+// Unlock a mutex held by the current thread. Threads waiting for the mutex get it in the order in which they started waiting.
+#define mutex_unlock(__mutex) asm (__mutex) -> void { syscall mutex_unlock; }
+
+// u64 cond_create()
+// Create a condition variable and get its id.
+#define cond_create() asm () -> u64 { syscall cond_create; }
+
+// void cond_wait(u64 cond, u64 mutex)
+// Unlock a mutex held by the current thread, and wait until the condition variable is signaled. The mutex is locked again before this returns.
+#define cond_wait(__cond, __mutex) asm (__cond, __mutex) -> void { syscall cond_wait; }
+
+// void cond_signal(u64 cond)
+// Wake up the thread which has waited the longest on a condition variable. This does nothing if no thread is waiting.
+#define cond_signal(__cond) asm (__cond) -> void { syscall cond_signal; }
+
+// i64 pipe_create(u8* buf, u64 capacity)
+// Create an empty pipe which queues up to capacity bytes in the buffer buf, and get its handle. The buffer must stay valid until the pipe is closed. Returns -EINVAL if the capacity is zero.
+#define pipe_create(__buf, __capacity) asm (__buf, __capacity) -> i64 { syscall pipe_create; }
+
+// i64 pipe_write(u64 pipe, const u8* src, u64 num_bytes)
+// Write bytes to a pipe, and get the number of bytes written. If the pipe doesn't have room for all of them, the current thread waits until it does, so that the bytes of a write are never interleaved with those of other writes. Returns -EBADF if the pipe isn't open or is closed while waiting, and -EINVAL if num_bytes is larger than the capacity of the pipe.
+#define pipe_write(__pipe, __src, __num_bytes) asm (__pipe, __src, __num_bytes) -> i64 { syscall pipe_write; }
+
+// i64 pipe_read(u64 pipe, u8* dst, u64 num_bytes)
+// Read up to num_bytes bytes from a pipe, in the order in which they were written, and get the number of bytes read. If the pipe is empty, the current thread waits until another thread writes to it. Reading zero bytes returns immediately. Returns -EBADF if the pipe isn't open or is closed while waiting.
+#define pipe_read(__pipe, __dst, __num_bytes) asm (__pipe, __dst, __num_bytes) -> i64 { syscall pipe_read; }
+
+// i64 pipe_close(u64 pipe)
+// Close a pipe. The threads waiting to write to or read from it get -EBADF. Bytes still queued are discarded, and the buffer of the pipe can then be reused. Returns -EBADF if the pipe isn't open.
+#define pipe_close(__pipe) asm (__pipe) -> i64 { syscall pipe_close; }
+
 // u64 time_current_ms()
 // Get the UNIX time stamp in milliseconds.
 #define time_current_ms() asm () -> u64 { syscall time_current_ms; }
@@ -61,62 +249,404 @@
 // Schedule a callback to be called once after a given delay.
 #define time_delay_cb(__delay_ms, __callback) asm (__delay_ms, __callback) -> void { syscall time_delay_cb; }
 
-// u32 window_create(u32 width, u32 height, const char* title, u64 flags)
-// Create a new window with a frame buffer to draw into. The window is initially hidden when created, and will appear as soon as the first frame of image data is drawn.
-#define window_create(__width, __height, __title, __flags) asm (__width, __height, __title, __flags) -> u32 { syscall window_create; }
+// u64 time_interval_cb(u64 interval_ms, void* callback)
+// Schedule a callback to be called repeatedly, every `interval_ms` milliseconds, until the timer is cancelled with `time_cancel_cb`. The interval must be at least 1ms. If the program falls behind, the runs which were missed are skipped rather than run late.
+#define time_interval_cb(__interval_ms, __callback) asm (__interval_ms, __callback) -> u64 { syscall time_interval_cb; }
 
-// void window_draw_frame(u32 window_id, const u8* pixel_data)
-// Copy a frame of pixels to be displayed into the window. The frame must have the same width and height as the window. The pixel format is 32 bits per pixel in BGRA byte order, with 8 bits for each component and the B byte at the lowest address.
-#define window_draw_frame(__window_id, __pixel_data) asm (__window_id, __pixel_data) -> void { syscall window_draw_frame; }
+// i64 time_cancel_cb(u64 timer_id)
+// Stop a repeating timer started with `time_interval_cb`, which can be called from the callback of the timer. Returns 0, or `-EBADF` if the timer id is invalid or the timer was already cancelled.
+#define time_cancel_cb(__timer_id) asm (__timer_id) -> i64 { syscall time_cancel_cb; }
 
-// void window_on_mousemove(u32 window_id, void* callback)
-// Register a callback for mouse movement. Mouse x/y coordinates are relative to the top-left corner of the window and may be negative if outside of the window.
-#define window_on_mousemove(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_mousemove; }
+// u64 event_poll(event_t* event)
+// Remove the oldest queued event and write it into `event`. Returns 1 if an event was written, or 0 if the queue is empty, in which case `event` is left unchanged.
+#define event_poll(__event) asm (__event) -> u64 { syscall event_poll; }
 
-// void window_on_mousedown(u32 window_id, void* callback)
-// Register a callback for mouse button press events.
-#define window_on_mousedown(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_mousedown; }
+// void trace_begin(const char* name)
+// Start a span with a given name. Spans can be nested, and each one is closed by a matching `trace_end` call.
+#define trace_begin(__name) asm (__name) -> void { syscall trace_begin; }
 
-// void window_on_mouseup(u32 window_id, void* callback)
-// Register a callback for mouse button release events.
-#define window_on_mouseup(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_mouseup; }
+// void trace_end()
+// End the most recently started span. Calls with no open span are ignored. Spans still open when the program exits are ended at that point.
+#define trace_end() asm () -> void { syscall trace_end; }
 
-// void window_on_keydown(u32 window_id, void* callback)
-// Register a callback for key press event.
-#define window_on_keydown(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_keydown; }
+// i64 window_create(u32 width, u32 height, const char* title, u64 flags)
+// Create a new window with a frame buffer to draw into. The window is initially hidden when created, and will appear as soon as the first frame of image data is drawn. The low 8 bits of `flags` select the pixel format of the frame buffer, one of the `PIXEL_FORMAT_*` constants. Passing 0 selects `PIXEL_FORMAT_BGRA32`. Returns the id of the window, which the other window syscalls take, or `-EINVAL` if the pixel format is unknown.
+#define window_create(__width, __height, __title, __flags) asm (__width, __height, __title, __flags) -> i64 { syscall window_create; }
 
-// void window_on_keyup(u32 window_id, void* callback)
-// Register a callback for key release event.
-#define window_on_keyup(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_keyup; }
+// i64 window_draw_frame(u64 window_id, const u8* pixel_data)
+// Copy a frame of pixels to be displayed into the window. The frame must have the same width and height as the window. The pixel data must use the pixel format selected when the window was created. The default format is 32 bits per pixel in BGRA byte order, with 8 bits for each component and the B byte at the lowest address. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_draw_frame(__window_id, __pixel_data) asm (__window_id, __pixel_data) -> i64 { syscall window_draw_frame; }
 
-// void window_on_textinput(u32 window_id, void* callback)
-// Register a callback to receive text input. The text is encoded as UTF-8 and the callback is called for each byte input.
-#define window_on_textinput(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_textinput; }
+// i64 window_draw_surface(u64 window_id, u64 surface_id)
+// Display the contents of a surface created with `surface_create` in a window, like `window_draw_frame`. The surface must have the same width and height as the window. Returns 0 on success, `-EBADF` if the window or surface id is invalid, or `-EINVAL` if the surface size doesn't match the window.
+#define window_draw_surface(__window_id, __surface_id) asm (__window_id, __surface_id) -> i64 { syscall window_draw_surface; }
 
-// u32 audio_open_output(u32 sample_rate, u16 num_channels, u16 format, void* callback)
-// Open an audio output device.
-#define audio_open_output(__sample_rate, __num_channels, __format, __callback) asm (__sample_rate, __num_channels, __format, __callback) -> u32 { syscall audio_open_output; }
+// i64 window_on_mousemove(u64 window_id, void* callback)
+// Register a callback for mouse movement. Mouse x/y coordinates are relative to the top-left corner of the window and may be negative if outside of the window. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_on_mousemove(__window_id, __callback) asm (__window_id, __callback) -> i64 { syscall window_on_mousemove; }
 
-// u64 net_listen(const char* listen_addr, void* on_new_conn)
-// Open a listening TCP socket to accept incoming connections. A callback function is called when a new connection request is received.
-#define net_listen(__listen_addr, __on_new_conn) asm (__listen_addr, __on_new_conn) -> u64 { syscall net_listen; }
+// i64 window_on_mousedown(u64 window_id, void* callback)
+// Register a callback for mouse button press events. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_on_mousedown(__window_id, __callback) asm (__window_id, __callback) -> i64 { syscall window_on_mousedown; }
 
-// u64 net_accept(u64 socket_id, char* client_addr_buf, u64 addr_buf_len, void* on_incoming_data)
-// Accept an incoming connection and creates a new socket. A callback function is called when incoming data is received on the new socket.
-#define net_accept(__socket_id, __client_addr_buf, __addr_buf_len, __on_incoming_data) asm (__socket_id, __client_addr_buf, __addr_buf_len, __on_incoming_data) -> u64 { syscall net_accept; }
+// i64 window_on_mouseup(u64 window_id, void* callback)
+// Register a callback for mouse button release events. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_on_mouseup(__window_id, __callback) asm (__window_id, __callback) -> i64 { syscall window_on_mouseup; }
 
-// u64 net_read(u64 socket_id, u8* buf_ptr, u64 buf_len)
-// Read data from a socket into a buffer with specified capacity. Data can only be read if available.
-#define net_read(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> u64 { syscall net_read; }
+// i64 window_on_keydown(u64 window_id, void* callback)
+// Register a callback for key press event. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_on_keydown(__window_id, __callback) asm (__window_id, __callback) -> i64 { syscall window_on_keydown; }
 
-// u64 net_write(u64 socket_id, const u8* buf_ptr, u64 buf_len)
-// Write data to an open socket. This function will attempt to write the entire buffer and may block if the output buffer is full.
-#define net_write(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> u64 { syscall net_write; }
+// i64 window_on_keyup(u64 window_id, void* callback)
+// Register a callback for key release event. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_on_keyup(__window_id, __callback) asm (__window_id, __callback) -> i64 { syscall window_on_keyup; }
 
-// void net_close(u64 socket_id)
-// Close an open socket.
-#define net_close(__socket_id) asm (__socket_id) -> void { syscall net_close; }
+// i64 window_on_textinput(u64 window_id, void* callback)
+// Register a callback to receive text input. The text is encoded as UTF-8 and the callback is called for each byte input. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_on_textinput(__window_id, __callback) asm (__window_id, __callback) -> i64 { syscall window_on_textinput; }
 
+// i64 window_frame_stats(u64 window_id, u64* stats)
+// Write frame timing statistics for a window into an array of 4 u64 values. `stats[0]` is the time between the last two drawn frames in microseconds, `stats[1]` is the average frame rate over the last 60 frames in frames per second, `stats[2]` is the number of display refreshes that were missed between frames, and `stats[3]` is the total number of frames drawn. Returns 0, or `-EBADF` if the window id is invalid.
+#define window_frame_stats(__window_id, __stats) asm (__window_id, __stats) -> i64 { syscall window_frame_stats; }
+
+// i64 window_on_dropfile(u64 window_id, char* path_buf, u64 buf_len, void* callback)
+// Register a callback to be called when a file is dragged and dropped onto the window. The path of the file is copied into `path_buf` as a null-terminated string, truncated to fit in `buf_len` bytes. The callback receives the window id and the length of the path in bytes, not including the null terminator. Since this reveals paths on the host, it requires running the VM with `--allow-dropfile`. Returns 0, `-EPERM` if dropped files aren't allowed, or `-EBADF` if the window id is invalid.
+#define window_on_dropfile(__window_id, __path_buf, __buf_len, __callback) asm (__window_id, __path_buf, __buf_len, __callback) -> i64 { syscall window_on_dropfile; }
+
+// void blit_sprites(const bitmap_t* dst, const bitmap_t* sheet, const sprite_t* sprites, u64 num_sprites)
+// Draw a batch of sprites from a sprite sheet into a bitmap. Each `sprite_t { i32 x; i32 y; u32 src_x; u32 src_y; u32 width; u32 height; u32 flags; }` entry copies a rectangle of the sheet to position (x, y) of the destination, clipping it to the destination bounds. Pixels with an alpha value of 0 are transparent unless the `SPRITE_OPAQUE` flag is set, and the `SPRITE_FLIP_X` and `SPRITE_FLIP_Y` flags mirror the sprite. Source rectangles outside of the sheet are an error.
+#define blit_sprites(__dst, __sheet, __sprites, __num_sprites) asm (__dst, __sheet, __sprites, __num_sprites) -> void { syscall blit_sprites; }
+
+// void draw_tilemap(const bitmap_t* dst, const tilemap_t* map, i32 scroll_x, i32 scroll_y)
+// Draw a tile map into a bitmap. `tilemap_t { bitmap_t* tileset; u16* tiles; u32 map_width; u32 map_height; u32 tile_width; u32 tile_height; }` describes a grid of `map_width * map_height` tile indices stored in row-major order. Tiles are numbered in row-major order in the tileset, and the index `TILE_EMPTY` leaves the destination unchanged. The map is drawn so that the pixel at (scroll_x, scroll_y) of the map is the top-left corner of the destination, and pixels with an alpha value of 0 are transparent so that layers can be stacked.
+#define draw_tilemap(__dst, __map, __scroll_x, __scroll_y) asm (__dst, __map, __scroll_x, __scroll_y) -> void { syscall draw_tilemap; }
+
+// i64 surface_create(const bitmap_t* bitmap, u32 flags)
+// Register a bitmap in guest memory as an offscreen surface. Surfaces can be composited into each other with `surface_blit` and displayed with `window_draw_surface`, which makes it possible to implement double buffering, UI layers and post-processing passes. If `flags` includes `SURFACE_TRANSPARENT`, pixels of this surface with an alpha value of 0 are skipped when it is blitted into another surface. The surface refers to the pixel memory of the bitmap, which must stay valid until the surface is destroyed. Returns `-EINVAL` if the bitmap is empty.
+#define surface_create(__bitmap, __flags) asm (__bitmap, __flags) -> i64 { syscall surface_create; }
+
+// i64 surface_destroy(u64 surface_id)
+// Unregister a surface. The pixel memory is owned by the guest and is not freed. Returns 0 on success, or `-EBADF` if the surface id is invalid.
+#define surface_destroy(__surface_id) asm (__surface_id) -> i64 { syscall surface_destroy; }
+
+// i64 surface_blit(u64 dst_id, u64 src_id, i32 x, i32 y)
+// Copy the pixels of the source surface to position (x, y) of the destination surface, clipped to the destination bounds. The source and destination may be the same surface. Returns 0 on success, or `-EBADF` if a surface id is invalid.
+#define surface_blit(__dst_id, __src_id, __x, __y) asm (__dst_id, __src_id, __x, __y) -> i64 { syscall surface_blit; }
+
+// void blend_over(u32* dst, const u32* src, u64 num_pixels)
+// Alpha blend a buffer of pixels over another. Each pixel of `src` is composited over the corresponding pixel of `dst` using its alpha channel, and the result is written into `dst`. Colors are not premultiplied by alpha.
+#define blend_over(__dst, __src, __num_pixels) asm (__dst, __src, __num_pixels) -> void { syscall blend_over; }
+
+// void hsv_to_rgb(u32* dst, const f32* hsv, u64 num_colors)
+// Convert an array of colors from HSV to RGB. Each color is a triple of f32 values, where the hue is in degrees and the saturation and value are in [0, 1]. The output pixels are opaque.
+#define hsv_to_rgb(__dst, __hsv, __num_colors) asm (__dst, __hsv, __num_colors) -> void { syscall hsv_to_rgb; }
+
+// void apply_lut(const bitmap_t* bitmap, const rect_t* rect, const u8* lut)
+// Apply a lookup table of 256 bytes to the red, green and blue channels of the pixels in a rectangle `rect_t { u32 x; u32 y; u32 width; u32 height; }` of a bitmap, leaving the alpha channel unchanged. This can be used for gamma correction or color grading. If `rect` is null, the whole bitmap is modified. The rectangle is clipped to the bitmap bounds.
+#define apply_lut(__bitmap, __rect, __lut) asm (__bitmap, __rect, __lut) -> void { syscall apply_lut; }
+
+// bool pad_rumble(u32 pad_id, u16 low_freq, u16 high_freq, u32 duration_ms)
+// Make a gamepad vibrate for a given duration in milliseconds. The `low_freq` and `high_freq` arguments set the intensity of the low and high frequency rumble motors, from 0 to 65535. Calling this again replaces any rumble effect currently playing, and an intensity of zero stops the motors. Returns false if the gamepad doesn't exist or doesn't support rumble.
+#define pad_rumble(__pad_id, __low_freq, __high_freq, __duration_ms) asm (__pad_id, __low_freq, __high_freq, __duration_ms) -> bool { syscall pad_rumble; }
+
+// i64 audio_open_output(u32 sample_rate, u16 num_channels, u16 format, void* callback)
+// Open an audio output device, and get its id. Returns `-EINVAL` if the sample rate, number of channels or format is not supported, or `-EIO` if the device could not be opened.
+#define audio_open_output(__sample_rate, __num_channels, __format, __callback) asm (__sample_rate, __num_channels, __format, __callback) -> i64 { syscall audio_open_output; }
+
+// void audio_mix(i16* dst, const i16* src, u64 num_samples, f32 gain)
+// Mix a buffer of 16-bit signed samples into another. Each sample of `src` is multiplied by `gain` and added to the corresponding sample of `dst`, saturating to the range of i16. This can be used to layer sound effects without looping over samples in bytecode.
+#define audio_mix(__dst, __src, __num_samples, __gain) asm (__dst, __src, __num_samples, __gain) -> void { syscall audio_mix; }
+
+// void audio_resample(i16* dst, u64 dst_len, const i16* src, u64 src_len)
+// Convert a buffer of `src_len` 16-bit signed samples into `dst_len` samples using linear interpolation. This can be used to convert between sample rates or to change the pitch of a sound.
+#define audio_resample(__dst, __dst_len, __src, __src_len) asm (__dst, __dst_len, __src, __src_len) -> void { syscall audio_resample; }
+
+// i64 audio_osc(i16* dst, u64 num_samples, u16 waveform, osc_t* osc)
+// Fill a buffer of 16-bit signed samples with one of the `AUDIO_WAVE_*` waveforms. The oscillator is described by the C struct `osc_t { u32 sample_rate; float freq; float amplitude; float phase; }` defined in `uvm/music.h`, where the amplitude is in [0, 1] and the phase in [0, 1). The phase is updated so that the next buffer filled continues the wave. Returns 0 on success, or `-EINVAL` if the waveform is unknown or the sample rate is zero.
+#define audio_osc(__dst, __num_samples, __waveform, __osc) asm (__dst, __num_samples, __waveform, __osc) -> i64 { syscall audio_osc; }
+
+// void audio_envelope(i16* buf, u64 num_samples, envelope_t* env)
+// Apply an ADSR envelope to a buffer of 16-bit signed samples. The envelope is described by the C struct `envelope_t { u32 attack; u32 decay; float sustain; u32 release; u64 pos; u64 release_pos; }` defined in `uvm/music.h`. Durations and positions are counted in samples, `pos` is the number of samples since the note started, which is updated, and `release_pos` is the position at which the note is released, or `UINT64_MAX` while it is held. The sustain level is in [0, 1].
+#define audio_envelope(__buf, __num_samples, __env) asm (__buf, __num_samples, __env) -> void { syscall audio_envelope; }
+
+// i64 audio_biquad_init(biquad_t* filter, u16 filter_type, f32 cutoff, f32 q)
+// Compute the coefficients of an `AUDIO_FILTER_*` biquad filter and clear its state. The filter is described by the C struct `biquad_t { float b0, b1, b2, a1, a2; float x1, x2, y1, y2; }` defined in `uvm/music.h`. The cutoff or center frequency is given as a fraction of the sample rate. Returns 0 on success, or `-EINVAL` if the filter type is unknown, the cutoff is not between 0 and 0.5, or `q` is not positive.
+#define audio_biquad_init(__filter, __filter_type, __cutoff, __q) asm (__filter, __filter_type, __cutoff, __q) -> i64 { syscall audio_biquad_init; }
+
+// void audio_biquad(i16* buf, u64 num_samples, biquad_t* filter)
+// Run a biquad filter over a buffer of 16-bit signed samples, in place. The state of the filter is updated so that the next buffer filtered continues from this one.
+#define audio_biquad(__buf, __num_samples, __filter) asm (__buf, __num_samples, __filter) -> void { syscall audio_biquad; }
+
+// i64 net_listen(const char* listen_addr, void* on_new_conn)
+// Open a listening TCP socket to accept incoming connections. A callback function is called when a new connection request is received. Returns `-EACCES` if the address is not allowed by the network policy, `-EMFILE` if the network connections quota is used up, or a negative error code if the socket could not be opened.
+#define net_listen(__listen_addr, __on_new_conn) asm (__listen_addr, __on_new_conn) -> i64 { syscall net_listen; }
+
+// i64 net_accept(u64 socket_id, char* client_addr_buf, u64 addr_buf_len, void* on_incoming_data)
+// Accept an incoming connection and creates a new socket. A callback function is called with the socket id and number of bytes when incoming data is received on the new socket, and with 0 bytes when the connection is closed by the client. Returns `-EAGAIN` if there is no pending connection, `-EMFILE` if the network connections quota is used up, in which case the connection stays pending, or `-EBADF` if the socket is not a listening socket.
+#define net_accept(__socket_id, __client_addr_buf, __addr_buf_len, __on_incoming_data) asm (__socket_id, __client_addr_buf, __addr_buf_len, __on_incoming_data) -> i64 { syscall net_accept; }
+
+// i64 net_tcp_connect(const char* addr, void* on_connect, void* on_incoming_data)
+// Open a TCP connection to a `host:port` address on another thread. Once the connection is opened, the on_connect callback is called with the socket id and 0, or with a negative error code if the connection failed, in which case the socket is closed. The on_incoming_data callback is called with the socket id and number of bytes when incoming data is received, which can then be read with `net_read`, and with 0 bytes when the connection is closed by the server. Reading or writing before the connection is opened returns `-ENOTCONN`. Returns `-EACCES` if the address is not allowed by the network policy, or `-EMFILE` if the network connections quota is used up.
+#define net_tcp_connect(__addr, __on_connect, __on_incoming_data) asm (__addr, __on_connect, __on_incoming_data) -> i64 { syscall net_tcp_connect; }
+
+// i64 net_read(u64 socket_id, u8* buf_ptr, u64 buf_len)
+// Read data from a socket into a buffer with specified capacity. Data can only be read if available. Returns `-EBADF` if the socket id is invalid.
+#define net_read(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> i64 { syscall net_read; }
+
+// i64 net_write(u64 socket_id, const u8* buf_ptr, u64 buf_len)
+// Write data to an open socket. This function will attempt to write the entire buffer and may block if the output buffer is full. Returns `-EBADF` if the socket id is invalid, `-EDQUOT` if writing the buffer would exceed the bytes written quota, or a negative error code if the connection has been lost.
+#define net_write(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> i64 { syscall net_write; }
+
+// i64 net_close(u64 socket_id)
+// Close an open socket. Returns 0 on success, or `-EBADF` if the socket id is invalid.
+#define net_close(__socket_id) asm (__socket_id) -> i64 { syscall net_close; }
+
+// i64 ws_connect(const char* url, void* on_connect, void* on_message)
+// Open a WebSocket connection to a `ws://` URL on another thread. Once the connection is opened and the handshake is done, the on_connect callback is called with the socket id and 0, or with a negative error code if the connection or handshake failed, in which case the socket is closed. The server has 10 seconds to answer the handshake, after which it fails with `-ETIMEDOUT`. The on_message callback is called with the socket id and message length whenever a complete message has been received, and the message can then be read with `net_read`. Sending before the connection is opened returns `-ENOTCONN`. Returns `-EINVAL` if the URL is invalid, `-EACCES` if the address is not allowed by the network policy, or `-EMFILE` if the network connections quota is used up.
+#define ws_connect(__url, __on_connect, __on_message) asm (__url, __on_connect, __on_message) -> i64 { syscall ws_connect; }
+
+// i64 ws_send(u64 socket_id, const void* buf_ptr, u64 buf_len, u8 msg_type)
+// Send a message on a WebSocket connection. The message type must be `WS_MSG_TEXT` or `WS_MSG_BINARY`. Returns 0 on success, `-EINVAL` if the message type is invalid, `-EBADF` if the socket is not a WebSocket, `-EDQUOT` if sending the message would exceed the bytes written quota, or a negative error code if the connection has been lost.
+#define ws_send(__socket_id, __buf_ptr, __buf_len, __msg_type) asm (__socket_id, __buf_ptr, __buf_len, __msg_type) -> i64 { syscall ws_send; }
+
+// i64 net_fetch(const char* url, void* on_done)
+// Make an HTTP GET request to an `http://` URL on another thread. Once the response has been received, the on_done callback is called with the request id, the HTTP status code or a negative error code if the request failed, and the length of the body, which can then be read with `net_read`. Redirects are not followed. The request must be closed with `net_close`, which can also cancel it. Returns `-EINVAL` if the URL is invalid, `-EACCES` if the address is not allowed by the network policy, or `-EMFILE` if the network connections quota is used up.
+#define net_fetch(__url, __on_done) asm (__url, __on_done) -> i64 { syscall net_fetch; }
+
+// i64 fs_open(const char* path, u32 flags)
+// Open a file, with a combination of the `FS_OPEN_*` flags, which must include `FS_OPEN_READ` or `FS_OPEN_WRITE`. Paths are absolute paths in the virtual filesystem of the guest, which only contains the directories mapped by the host, such as with the `--map` and `--mount` options of the `uvm` command. Returns a file handle, or a negated error code such as `-ENOENT` if the file doesn't exist, `-EROFS` if it is in a read-only directory and opened for writing, or `-EMFILE` if too many files are open.
+#define fs_open(__path, __flags) asm (__path, __flags) -> i64 { syscall fs_open; }
+
+// i64 fs_read(u64 file, void* buf_ptr, u64 buf_len)
+// Read up to `buf_len` bytes from a file. Returns the number of bytes read, which is 0 at the end of the file, or a negated error code. Fails with `-EBADF` if the file wasn't opened with `FS_OPEN_READ`.
+#define fs_read(__file, __buf_ptr, __buf_len) asm (__file, __buf_ptr, __buf_len) -> i64 { syscall fs_read; }
+
+// i64 fs_write(u64 file, const void* buf_ptr, u64 buf_len)
+// Write bytes to a file. Returns the number of bytes written, or a negated error code. Fails with `-EBADF` if the file wasn't opened with `FS_OPEN_WRITE`, or `-ENOSPC` if the file would grow past the size limit of its filesystem.
+#define fs_write(__file, __buf_ptr, __buf_len) asm (__file, __buf_ptr, __buf_len) -> i64 { syscall fs_write; }
+
+// i64 fs_seek(u64 file, i64 offset, u8 whence)
+// Move the position that the next read or write of a file happens at, to `offset` bytes from the start of the file with `FS_SEEK_SET`, from the current position with `FS_SEEK_CUR`, or from the end of the file with `FS_SEEK_END`. The position can be past the end of the file, and writing there fills the gap with zeros. Returns the new position from the start of the file, or a negated error code such as `-EINVAL` if the position would be negative, or `-EBADF` if the handle isn't an open file.
+#define fs_seek(__file, __offset, __whence) asm (__file, __offset, __whence) -> i64 { syscall fs_seek; }
+
+// i64 fs_close(u64 file)
+// Close a file. Returns 0 on success, or `-EBADF` if the handle isn't an open file.
+#define fs_close(__file) asm (__file) -> i64 { syscall fs_close; }
+
+// i64 fs_list_dir(const char* path, char* buf_ptr, u64 buf_len)
+// List the entries of a directory, writing their names to a buffer in sorted order, each followed by a null terminator. Directories containing mount points can be listed, and they include the mount points among their entries. Returns the number of bytes the names take up. If this is more than `buf_len`, nothing is written, so that the directory can be listed again with a larger buffer. Fails with `-ENOENT` if the directory doesn't exist, or `-ENOTDIR` if the path is a file.
+#define fs_list_dir(__path, __buf_ptr, __buf_len) asm (__path, __buf_ptr, __buf_len) -> i64 { syscall fs_list_dir; }
+
+// i64 fs_stat(const char* path, u64* stat_ptr)
+// Get information about a file or directory, which is written to an array of two u64 values: its type, either `FS_TYPE_FILE` or `FS_TYPE_DIR`, and its size in bytes, which is 0 for directories. Returns 0 on success, or a negated error code such as `-ENOENT` if the path doesn't exist.
+#define fs_stat(__path, __stat_ptr) asm (__path, __stat_ptr) -> i64 { syscall fs_stat; }
+
+// i64 fs_mkdir(const char* path)
+// Create a directory, whose parent directory must exist. Returns 0 on success, or a negated error code such as `-EEXIST` if the path already exists, `-ENOENT` if the parent directory doesn't exist, or `-EROFS` if it is in a read-only directory.
+#define fs_mkdir(__path) asm (__path) -> i64 { syscall fs_mkdir; }
+
+// i64 fs_remove(const char* path)
+// Remove a file or an empty directory. Files which are open can still be used until they are closed. Returns 0 on success, or a negated error code such as `-ENOENT` if the path doesn't exist, `-ENOTEMPTY` if the directory isn't empty, or `-EBUSY` if it is a mount point or contains one.
+#define fs_remove(__path) asm (__path) -> i64 { syscall fs_remove; }
+
+// i64 compress(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+// Compress data into raw DEFLATE format (RFC 1951). Returns the number of bytes written to the destination buffer, or `-ENOSPC` if the compressed data doesn't fit.
+#define compress(__dst, __dst_len, __src, __src_len) asm (__dst, __dst_len, __src, __src_len) -> i64 { syscall compress; }
+
+// i64 decompress(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+// Decompress data in raw DEFLATE format (RFC 1951). Returns the number of bytes written to the destination buffer, `-EINVAL` if the input is invalid, or `-ENOSPC` if the decompressed data doesn't fit.
+#define decompress(__dst, __dst_len, __src, __src_len) asm (__dst, __dst_len, __src, __src_len) -> i64 { syscall decompress; }
+
+// void crypto_sha256(const u8* src, u64 num_bytes, u8* digest)
+// Compute the SHA-256 hash of a buffer. The 32-byte digest is written to the digest buffer.
+#define crypto_sha256(__src, __num_bytes, __digest) asm (__src, __num_bytes, __digest) -> void { syscall crypto_sha256; }
+
+// u32 crypto_crc32(const u8* src, u64 num_bytes, u32 crc)
+// Compute the CRC-32 checksum of a buffer, as used by zlib, gzip and PNG. Pass 0 as the initial crc value, or the result of a previous call to checksum data in multiple parts.
+#define crypto_crc32(__src, __num_bytes, __crc) asm (__src, __num_bytes, __crc) -> u32 { syscall crypto_crc32; }
+
+// bool str_validate_utf8(const u8* src, u64 num_bytes)
+// Check if a buffer contains valid UTF-8 text.
+#define str_validate_utf8(__src, __num_bytes) asm (__src, __num_bytes) -> bool { syscall str_validate_utf8; }
+
+// u64 str_char_count(const u8* src, u64 num_bytes)
+// Count the number of Unicode code points in a UTF-8 buffer. Each invalid byte sequence counts as one character.
+#define str_char_count(__src, __num_bytes) asm (__src, __num_bytes) -> u64 { syscall str_char_count; }
+
+// i64 str_to_upper(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+// Convert UTF-8 text to uppercase. The output may be longer than the input. Returns the number of bytes written, `-EINVAL` if the input is not valid UTF-8, or `-ENOSPC` if the output doesn't fit. No null terminator is written.
+#define str_to_upper(__dst, __dst_len, __src, __src_len) asm (__dst, __dst_len, __src, __src_len) -> i64 { syscall str_to_upper; }
+
+// i64 str_to_lower(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+// Convert UTF-8 text to lowercase. The output may be longer than the input. Returns the number of bytes written, `-EINVAL` if the input is not valid UTF-8, or `-ENOSPC` if the output doesn't fit. No null terminator is written.
+#define str_to_lower(__dst, __dst_len, __src, __src_len) asm (__dst, __dst_len, __src, __src_len) -> i64 { syscall str_to_lower; }
+
+#define SYS_memset 4
+#define SYS_memset32 16
+#define SYS_memcpy 3
+#define SYS_memcmp 27
+#define SYS_vm_heap_size 14
+#define SYS_vm_resize_heap 17
+#define SYS_vm_grow_heap 87
+#define SYS_vm_track_alloc 45
+#define SYS_vm_track_free 46
+#define SYS_vm_heap_walk 62
+#define SYS_vm_backtrace 63
+#define SYS_vm_yield 66
+#define SYS_sys_version 51
+#define SYS_sys_has_syscall 52
+#define SYS_sys_batch 101
+#define SYS_vm_set_trap_handler 76
+#define SYS_vm_mem_layout 77
+#define SYS_vm_mem_protect 78
+#define SYS_vm_mem_query 79
+#define SYS_mem_alloc 84
+#define SYS_mem_free 85
+#define SYS_mem_resize 86
+#define SYS_print_i64 5
+#define SYS_print_f32 20
+#define SYS_print_f64 113
+#define SYS_print_str 6
+#define SYS_print_endl 7
+#define SYS_putchar 26
+#define SYS_io_write 114
+#define SYS_getchar 8
+#define SYS_read_line 88
+#define SYS_console_clear 67
+#define SYS_console_move_cursor 68
+#define SYS_console_set_style 69
+#define SYS_console_show_cursor 70
+#define SYS_console_size 71
+#define SYS_console_set_raw 72
+#define SYS_console_read_key 73
+#define SYS_proc_atexit 28
+#define SYS_proc_spawn 44
+#define SYS_proc_argc 64
+#define SYS_proc_argv 65
+#define SYS_module_load 47
+#define SYS_module_symbol 48
+#define SYS_tls_alloc 49
+#define SYS_tls_base 50
+#define SYS_thread_spawn 89
+#define SYS_thread_join 90
+#define SYS_thread_id 91
+#define SYS_mutex_create 92
+#define SYS_mutex_lock 93
+#define SYS_mutex_unlock 94
+#define SYS_cond_create 95
+#define SYS_cond_wait 96
+#define SYS_cond_signal 97
+#define SYS_pipe_create 115
+#define SYS_pipe_write 116
+#define SYS_pipe_read 117
+#define SYS_pipe_close 118
+#define SYS_time_current_ms 0
+#define SYS_time_delay_cb 2
+#define SYS_time_interval_cb 98
+#define SYS_time_cancel_cb 99
+#define SYS_event_poll 112
+#define SYS_trace_begin 74
+#define SYS_trace_end 75
+#define SYS_window_create 1
+#define SYS_window_draw_frame 10
+#define SYS_window_draw_surface 58
+#define SYS_window_on_mousemove 11
+#define SYS_window_on_mousedown 12
+#define SYS_window_on_mouseup 13
+#define SYS_window_on_keydown 9
+#define SYS_window_on_keyup 15
+#define SYS_window_on_textinput 19
+#define SYS_window_frame_stats 31
+#define SYS_window_on_dropfile 32
+#define SYS_blit_sprites 53
+#define SYS_draw_tilemap 54
+#define SYS_surface_create 55
+#define SYS_surface_destroy 56
+#define SYS_surface_blit 57
+#define SYS_blend_over 59
+#define SYS_hsv_to_rgb 60
+#define SYS_apply_lut 61
+#define SYS_pad_rumble 33
+#define SYS_audio_open_output 18
+#define SYS_audio_mix 29
+#define SYS_audio_resample 30
+#define SYS_audio_osc 107
+#define SYS_audio_envelope 108
+#define SYS_audio_biquad_init 109
+#define SYS_audio_biquad 110
+#define SYS_net_listen 21
+#define SYS_net_accept 22
+#define SYS_net_tcp_connect 106
+#define SYS_net_read 23
+#define SYS_net_write 24
+#define SYS_net_close 25
+#define SYS_ws_connect 34
+#define SYS_ws_send 35
+#define SYS_net_fetch 111
+#define SYS_fs_open 80
+#define SYS_fs_read 81
+#define SYS_fs_write 82
+#define SYS_fs_seek 100
+#define SYS_fs_close 83
+#define SYS_fs_list_dir 102
+#define SYS_fs_stat 103
+#define SYS_fs_mkdir 104
+#define SYS_fs_remove 105
+#define SYS_compress 36
+#define SYS_decompress 37
+#define SYS_crypto_sha256 38
+#define SYS_crypto_crc32 39
+#define SYS_str_validate_utf8 40
+#define SYS_str_char_count 41
+#define SYS_str_to_upper 42
+#define SYS_str_to_lower 43
+
+#define TRAP_DIV_ZERO 1
+#define TRAP_OUT_OF_BOUNDS 2
+#define TRAP_UNALIGNED 3
+#define TRAP_INVALID_SYSCALL 4
+#define TRAP_STACK_OVERFLOW 5
+#define TRAP_PROTECTION 6
+#define TRAP_UNVERIFIED_CODE 7
+#define MEM_REGION_CODE 0
+#define MEM_REGION_DATA 1
+#define MEM_REGION_HEAP 2
+#define MEM_REGION_STACK 3
+#define MEM_PROT_READ 1
+#define MEM_PROT_WRITE 2
+#define MEM_PAGE_SIZE 4096
+#define SYS_BATCH_ENTRY_SIZE 48
+#define CONSOLE_BLACK 0
+#define CONSOLE_RED 1
+#define CONSOLE_GREEN 2
+#define CONSOLE_YELLOW 3
+#define CONSOLE_BLUE 4
+#define CONSOLE_MAGENTA 5
+#define CONSOLE_CYAN 6
+#define CONSOLE_WHITE 7
+#define CONSOLE_BRIGHT 8
+#define CONSOLE_DEFAULT 255
+#define CONSOLE_BOLD 1
+#define CONSOLE_UNDERLINE 2
+#define CONSOLE_REVERSE 4
+#define EVENT_KEYDOWN 1
+#define EVENT_KEYUP 2
+#define EVENT_MOUSEMOVE 3
+#define EVENT_MOUSEDOWN 4
+#define EVENT_MOUSEUP 5
+#define EVENT_TEXTINPUT 6
+#define EVENT_TIMER 7
+#define EVENT_NET_CONN 8
+#define EVENT_NET_DATA 9
+#define EVENT_WS_MESSAGE 10
+#define EVENT_NET_FETCH 11
+#define EVENT_NET_CONNECT 12
+#define EVENT_SIZE 40
+#define EVENT_OFS_KIND 0
+#define EVENT_OFS_SOURCE 8
+#define EVENT_OFS_ARG0 16
+#define EVENT_OFS_ARG1 24
+#define EVENT_OFS_ARG2 32
 #define KEY_BACKSPACE 8
 #define KEY_TAB 9
 #define KEY_RETURN 10
@@ -170,6 +700,57 @@
 #define KEY_UP 16003
 #define KEY_DOWN 16004
 #define KEY_SHIFT 16005
+#define PIXEL_FORMAT_BGRA32 0
+#define PIXEL_FORMAT_RGBA32 1
+#define PIXEL_FORMAT_RGB565 2
+#define SPRITE_FLIP_X 1
+#define SPRITE_FLIP_Y 2
+#define SPRITE_OPAQUE 4
+#define TILE_EMPTY 65535
+#define SURFACE_TRANSPARENT 1
 #define AUDIO_FORMAT_I16 0
+#define AUDIO_WAVE_SINE 0
+#define AUDIO_WAVE_SQUARE 1
+#define AUDIO_WAVE_SAW 2
+#define AUDIO_WAVE_TRIANGLE 3
+#define AUDIO_FILTER_LOWPASS 0
+#define AUDIO_FILTER_HIGHPASS 1
+#define AUDIO_FILTER_BANDPASS 2
+#define WS_MSG_TEXT 1
+#define WS_MSG_BINARY 2
+#define FS_OPEN_READ 1
+#define FS_OPEN_WRITE 2
+#define FS_OPEN_CREATE 4
+#define FS_OPEN_TRUNCATE 8
+#define FS_OPEN_APPEND 16
+#define FS_SEEK_SET 0
+#define FS_SEEK_CUR 1
+#define FS_SEEK_END 2
+#define FS_TYPE_FILE 1
+#define FS_TYPE_DIR 2
+
+#define EPERM 1 // Operation not permitted.
+#define ENOENT 2 // No such file or directory.
+#define EIO 5 // Input/output error.
+#define EBADF 9 // Invalid handle.
+#define EAGAIN 11 // Resource temporarily unavailable, try again.
+#define ENOMEM 12 // Out of memory.
+#define EACCES 13 // Permission denied.
+#define EBUSY 16 // Device or resource busy.
+#define EEXIST 17 // File exists.
+#define ENOTDIR 20 // Not a directory.
+#define EISDIR 21 // Is a directory.
+#define EINVAL 22 // Invalid argument.
+#define EMFILE 24 // Too many open files.
+#define ENOSPC 28 // No space left on device.
+#define EROFS 30 // Read-only file system.
+#define ENOSYS 38 // Function not implemented.
+#define ENOTEMPTY 39 // Directory not empty.
+#define EADDRINUSE 98 // Address already in use.
+#define ECONNRESET 104 // Connection reset by peer.
+#define ENOTCONN 107 // Not connected.
+#define ETIMEDOUT 110 // Connection timed out.
+#define ECONNREFUSED 111 // Connection refused.
+#define EDQUOT 122 // Quota exceeded.
 
 #endif
@@ -208,6 +208,11 @@ impl Type
             _ => panic!("is_signed {:?}", self)
         }
     }
+
+    pub fn is_void(&self) -> bool
+    {
+        matches!(self, Type::Void)
+    }
 }
 
 impl fmt::Display for Type {
@@ -349,6 +354,14 @@ pub enum Expr
         rhs: Box<Expr>,
     },
 
+    // Compound assignment (a op= b), which evaluates the target once
+    // This is transformed into a plain assignment by resolve_syms
+    CompoundAssign {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+
     Ternary {
         test_expr: Box<Expr>,
         then_expr: Box<Expr>,
@@ -405,12 +418,28 @@ pub enum Stmt
         body_stmt: Box<Stmt>,
     },
 
+    /// Switch statement, whose body holds the case labels
+    Switch {
+        test_expr: Expr,
+        body_stmt: Box<Stmt>,
+    },
+
+    /// Case label with its value, and default label of a switch
+    Case(i128),
+    Default,
+
     /// Local variable declaration
     VarDecl {
         var_type: Type,
         var_name: String,
         init_expr: Option<Expr>,
-    }
+    },
+
+    /// Source line of the statements that follow, with -g
+    Line {
+        src_name: String,
+        line_no: u32,
+    },
 }
 
 impl Stmt
@@ -423,6 +452,8 @@ impl Stmt
         match self {
             Stmt::Expr(expr) => {}
             Stmt::Break | Stmt::Continue => {}
+            Stmt::Case(_) | Stmt::Default => {}
+            Stmt::Line { .. } => {}
             Stmt::ReturnVoid => {}
             Stmt::ReturnExpr(expr) => {}
             Stmt::VarDecl { .. } => {}
@@ -443,6 +474,10 @@ impl Stmt
                 body_stmt.each_stmt(func);
             }
 
+            Stmt::Switch { test_expr, body_stmt } => {
+                body_stmt.each_stmt(func);
+            }
+
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 if init_stmt.is_some() {
                     init_stmt.as_ref().unwrap().each_stmt(func);
@@ -520,6 +555,13 @@ pub struct Global
 
     // Initialization expression
     pub init_expr: Option<Expr>,
+
+    // String constant stored in the assembler's string table,
+    // where it can share storage with identical strings
+    pub interned: bool,
+
+    // Variable with a separate instance for each thread
+    pub thread_local: bool,
 }
 
 pub type TypeDef = Rc<Box<RefCell<Type>>>;
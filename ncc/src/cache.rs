@@ -0,0 +1,183 @@
+//! On-disk compilation cache
+//!
+//! With --cache-dir, the output of each compilation is stored in the cache
+//! directory under a key computed from the preprocessed source, the options
+//! which affect code generation and the identity of the ncc binary. When
+//! a file is compiled again with the same key, the cached assembly is
+//! reused and the rest of the pipeline is skipped.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 64-bit FNV-1a hash, which is stable across Rust versions,
+/// unlike std's DefaultHasher
+#[derive(Clone, Copy)]
+struct Hasher(u64);
+
+impl Hasher
+{
+    fn new() -> Self
+    {
+        Hasher(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8])
+    {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Hash a field, followed by a separator so that
+    /// consecutive fields can't run into each other
+    fn field(&mut self, bytes: &[u8])
+    {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+/// Cached compiler output
+pub struct Entry
+{
+    pub asm: String,
+    pub warnings: Vec<String>,
+}
+
+pub struct Cache
+{
+    dir: PathBuf,
+}
+
+impl Cache
+{
+    pub fn new(dir: &str) -> Self
+    {
+        Cache { dir: PathBuf::from(dir) }
+    }
+
+    /// Compute the cache key for a compilation
+    /// Each option which affects the generated code must be passed in
+    pub fn key(src: &str, options: &[&[u8]]) -> String
+    {
+        let mut hasher = Hasher::new();
+        hasher.field(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.field(&compiler_id());
+        hasher.field(src.as_bytes());
+
+        for option in options {
+            hasher.field(option);
+        }
+
+        format!("{:016x}", hasher.0)
+    }
+
+    fn asm_path(&self, key: &str) -> PathBuf
+    {
+        self.dir.join(format!("{}.asm", key))
+    }
+
+    fn warnings_path(&self, key: &str) -> PathBuf
+    {
+        self.dir.join(format!("{}.warnings", key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Entry>
+    {
+        let asm = fs::read_to_string(self.asm_path(key)).ok()?;
+
+        let warnings = match fs::read_to_string(self.warnings_path(key)) {
+            Ok(text) => text.lines().map(|s| s.to_string()).collect(),
+            Err(_) => Vec::default(),
+        };
+
+        Some(Entry { asm, warnings })
+    }
+
+    /// Store an entry in the cache. Failing to write the cache doesn't
+    /// prevent compilation, so errors are ignored.
+    pub fn put(&self, key: &str, entry: &Entry)
+    {
+        if fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+
+        // The warnings are written first because the presence
+        // of the asm file marks the entry as complete
+        if !entry.warnings.is_empty() {
+            let mut text = entry.warnings.join("\n");
+            text.push('\n');
+            if write_atomic(&self.warnings_path(key), &text).is_err() {
+                return;
+            }
+        }
+
+        let _ = write_atomic(&self.asm_path(key), &entry.asm);
+    }
+}
+
+/// Write a file through a temporary file, so that concurrent
+/// compilations never read a partially written entry
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()>
+{
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Identify the ncc binary, so that rebuilding the compiler
+/// invalidates the entries it produced
+fn compiler_id() -> Vec<u8>
+{
+    let meta = std::env::current_exe().and_then(fs::metadata);
+
+    match meta {
+        Ok(meta) => {
+            let mtime = meta.modified().ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .unwrap_or(0);
+            format!("{}:{}", meta.len(), mtime).into_bytes()
+        }
+        Err(_) => Vec::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn cache_keys()
+    {
+        let key = Cache::key("int main() {}", &[b"O0"]);
+        assert_eq!(key.len(), 16);
+        assert_eq!(key, Cache::key("int main() {}", &[b"O0"]));
+        assert_ne!(key, Cache::key("int main() { }", &[b"O0"]));
+        assert_ne!(key, Cache::key("int main() {}", &[b"O1"]));
+
+        // Fields are delimited
+        assert_ne!(Cache::key("", &[b"ab", b""]), Cache::key("", &[b"a", b"b"]));
+    }
+
+    #[test]
+    fn cache_entries()
+    {
+        let dir = std::env::temp_dir().join(format!("ncc_cache_test_{}", std::process::id()));
+        let cache = Cache::new(dir.to_str().unwrap());
+
+        let key = Cache::key("src", &[]);
+        assert!(cache.get(&key).is_none());
+
+        let warnings = vec!["unreachable code".to_string()];
+        cache.put(&key, &Entry { asm: "exit;\n".to_string(), warnings: warnings.clone() });
+
+        let entry = cache.get(&key).unwrap();
+        assert_eq!(entry.asm, "exit;\n");
+        assert_eq!(entry.warnings, warnings);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -35,6 +35,10 @@ impl Stmt
 
             Stmt::Break | Stmt::Continue => {}
 
+            Stmt::Case(_) | Stmt::Default => {}
+
+            Stmt::Line { .. } => {}
+
             Stmt::ReturnVoid => {}
 
             Stmt::ReturnExpr(expr) => {
@@ -68,6 +72,11 @@ impl Stmt
                 body_stmt.insert_casts(ret_type)?;
             }
 
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.insert_casts()?;
+                body_stmt.insert_casts(ret_type)?;
+            }
+
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 if init_stmt.is_some() {
                     init_stmt.as_mut().unwrap().insert_casts(ret_type)?;
@@ -112,6 +121,7 @@ impl Expr
             }
 
             Expr::Ident(name) => panic!(),
+            Expr::CompoundAssign { .. } => panic!(),
 
             Expr::Ref(_) => {},
 
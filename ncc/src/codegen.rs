@@ -1,11 +1,14 @@
 use std::cmp::max;
-use crate::ast::*;
-use crate::parsing::{ParseError};
-use crate::types::*;
+use crate::ast::{Type, BinOp};
+use crate::ir::*;
 use Type::*;
 
 const ALLOC_STACK_SIZE: u64 = 1024 * 1024;
 
+/// Largest ratio between the size of the jump table
+/// of a switch and its number of cases
+const MAX_JUMP_TABLE_SPREAD: i128 = 4;
+
 #[derive(Default)]
 struct SymGen
 {
@@ -22,91 +25,31 @@ impl SymGen
     }
 }
 
-fn gen_global_init(t: &Type, init_expr: &Option<Expr>, out: &mut String) -> Result<(), ParseError>
+fn gen_data(data: &Data, out: &mut String)
 {
-    match (t, init_expr) {
-        (_, None) => {
-            out.push_str(&format!(".zero {};\n", t.sizeof()));
-        }
-
-        (Type::UInt(n), Some(Expr::Int(v))) => {
-            out.push_str(&format!(".u{} {};\n", n, v))
-        }
-
-        (Type::Int(n), Some(Expr::Int(v))) => {
-            out.push_str(&format!(".i{} {};\n", n, v))
-        }
-
-        (Type::Float(32), Some(Expr::Float32(v))) => {
-            out.push_str(&format!(".f32 {};\n", v))
-        }
-
-        (Type::Pointer(_), Some(Expr::Int(v))) => {
-            out.push_str(&format!(".u64 {};\n", v))
+    match data {
+        Data::Zero(num_bytes) => out.push_str(&format!(".zero {};\n", num_bytes)),
+        Data::UInt { num_bits, val } => out.push_str(&format!(".u{} {};\n", num_bits, val)),
+        Data::Int { num_bits, val } => out.push_str(&format!(".i{} {};\n", num_bits, val)),
+        Data::Float32(val) => out.push_str(&format!(".f32 {};\n", val)),
+        Data::Addr64(name) => out.push_str(&format!(".addr64 {};\n", name)),
+
+        Data::Stringz(s) => {
+            let escaped_str = s.as_bytes().escape_ascii();
+            out.push_str(&format!(".stringz \"{}\";\n", escaped_str))
         }
 
-        // Pointer to a global array
-        (Type::Pointer(_), Some(Expr::Ref(Decl::Global { name, t: Array { .. } } ))) => {
-            out.push_str(&format!(".addr64 {};\n", name))
-        }
-
-        // Global string constant
-        (Type::Array { elem_type, size_expr }, Some(Expr::String(s))) => {
-            match (elem_type.as_ref(), size_expr.as_ref()) {
-                (Type::UInt(8), Expr::Int(n)) => {
-                    let bytes = s.as_bytes();
-                    assert!(*n as usize == bytes.len() + 1);
-                    let escaped_str = bytes.escape_ascii();
-                    out.push_str(&format!(".stringz \"{}\";\n", escaped_str))
-                }
-                _ => panic!()
+        Data::Seq(elems) => {
+            for elem in elems {
+                gen_data(elem, out);
             }
         }
-
-        // Global array with initializer expression
-        (Type::Array {..}, Some(init_expr)) => {
-            gen_array_init(t, init_expr, out)?;
-        }
-
-        _ => todo!("{:?} {:?}", t, init_expr)
     }
-
-    Ok(())
-}
-
-// FIXME: ideally, all error checking should be done before we get to the
-// codegen, so that codegen can't return an error?
-fn gen_array_init(array_type: &Type, init_expr: &Expr, out: &mut String) -> Result<(), ParseError>
-{
-    // Get the type of the initializer expression
-    let init_expr_type = init_expr.eval_type()?;
-
-    let (array_elem_t, array_size_expr) = match array_type {
-        Type::Array { elem_type, size_expr } => (elem_type.as_ref().clone(), size_expr),
-        _ => panic!()
-    };
-
-    let (init_elem_t, init_size_expr) = match init_expr_type {
-        Type::Array { elem_type, size_expr } => (elem_type.as_ref().clone(), size_expr),
-        _ => panic!()
-    };
-
-    let elem_exprs = match init_expr {
-        Expr::Array(elem_exprs) => elem_exprs,
-        _ => return ParseError::msg_only("invalid initializer for global array variable")
-    };
-
-    // Generate initialization data for each element expression
-    for expr in elem_exprs {
-        gen_global_init(&array_elem_t, &Some(expr.clone()), out)?;
-    }
-
-    Ok(())
 }
 
 impl Unit
 {
-    pub fn gen_code(&self) -> Result<String, ParseError>
+    pub fn gen_code(&self) -> String
     {
         let mut sym = SymGen::default();
         let mut out: String = "".to_string();
@@ -128,20 +71,47 @@ impl Unit
         out.push_str("\n");
 
         // Global variable initialization
-        for global in &self.global_vars {
+        for global in self.global_vars.iter().filter(|g| !g.thread_local) {
+            // String constants go into the string table
+            if let (true, Data::Stringz(s)) = (global.interned, &global.init) {
+                let escaped_str = s.as_bytes().escape_ascii();
+                out.push_str(&format!(".intern {}, \"{}\";\n", global.name, escaped_str));
+                continue;
+            }
+
             // Align the data
-            let align_bytes = global.var_type.align_bytes();
-            out.push_str(&format!(".align {};\n", align_bytes));
+            out.push_str(&format!(".align {};\n", global.align));
 
             // Write a label
             out.push_str(&format!("{}:\n", global.name));
 
             // Generate initialization data for the global
-            gen_global_init(&global.var_type, &global.init_expr, &mut out)?;
+            gen_data(&global.init, &mut out);
 
             out.push_str("\n");
         }
 
+        // Thread-local variables are laid out in a template,
+        // which gets copied into the TLS block of each thread
+        let has_tls = self.global_vars.iter().any(|g| g.thread_local);
+        if has_tls {
+            out.push_str("# Thread-local storage template\n");
+            out.push_str(".align 8;\n");
+            out.push_str("__TLS_TEMPLATE__:\n");
+
+            for global in self.global_vars.iter().filter(|g| g.thread_local) {
+                out.push_str(&format!(".align {};\n", global.align));
+                out.push_str(&format!("{}:\n", global.name));
+                gen_data(&global.init, &mut out);
+            }
+
+            out.push_str("__TLS_TEMPLATE_END__:\n");
+            out.push_str(".align 8;\n");
+            out.push_str("__TLS_DELTA__:\n");
+            out.push_str(".u64 0;\n");
+            out.push_str("\n");
+        }
+
         // If any function in this unit uses stack allocation,
         // The allocation stack grows upwards
         if self.stack_alloc {
@@ -163,12 +133,32 @@ impl Unit
         // If there is a main function
         let main_fn: Vec<&Function> = self.fun_decls.iter().filter(|f| f.name == "main").collect();
         if let [main_fn] = main_fn[..] {
-            //
-            // TODO: support calling main with argc, argv as well
-            //
+            if has_tls {
+                out.push_str("# allocate thread-local storage\n");
+                out.push_str("push __TLS_DELTA__;\n");
+                out.push_str("push __TLS_TEMPLATE__;\n");
+                out.push_str("push __TLS_TEMPLATE_END__;\n");
+                out.push_str("push __TLS_TEMPLATE__;\n");
+                out.push_str("sub_u64;\n");
+                out.push_str("syscall tls_alloc;\n");
+                out.push_str("push __TLS_TEMPLATE__;\n");
+                out.push_str("sub_u64;\n");
+                out.push_str("store_u64;\n");
+                out.push_str("\n");
+            }
+
+            // main can take the argc and argv parameters
+            let num_params = main_fn.params.len().min(2);
+            if num_params > 0 {
+                out.push_str("# get the command-line arguments\n");
+                out.push_str("syscall proc_argc;\n");
+            }
+            if num_params > 1 {
+                out.push_str("syscall proc_argv;\n");
+            }
 
             out.push_str("# call the main function and then exit\n");
-            out.push_str("call main, 0;\n");
+            out.push_str(&format!("call main, {};\n", num_params));
             out.push_str("push __EVENT_LOOP_ENABLED__;\n");
             out.push_str("load_u8;\n");
             out.push_str("jnz __ret_to_event_loop__;\n");
@@ -187,35 +177,27 @@ impl Unit
 
         // Generate code for all the functions
         for fun in &self.fun_decls {
-            fun.gen_code(&mut sym, &mut out)?;
+            fun.gen_code(&mut sym, &mut out);
         }
 
-        Ok((out))
+        out
     }
 }
 
 impl Function
 {
-    fn needs_final_return(&self) -> bool
+    /// Restore the alloc stack sp before returning,
+    /// if this function uses stack allocation
+    fn gen_stack_alloc_restore(&self, out: &mut String)
     {
-        if let Stmt::Block(stmts) = &self.body {
-            if stmts.len() > 0 {
-                let last_stmt = &stmts[stmts.len() - 1];
-
-                if let Stmt::ReturnVoid = last_stmt {
-                    return false;
-                }
-
-                if let Stmt::ReturnExpr(_) = last_stmt {
-                    return false;
-                }
-            }
+        if let Some(bp_idx) = self.stack_alloc_bp {
+            out.push_str(&format!("push __stack_alloc_sp__;\n"));
+            out.push_str(&format!("get_local {};\n", bp_idx));
+            out.push_str(&format!("store_u64;\n"));
         }
-
-        return true;
     }
 
-    fn gen_code(&self, sym: &mut SymGen, out: &mut String) -> Result<(), ParseError>
+    fn gen_code(&self, sym: &mut SymGen, out: &mut String)
     {
         // Print the function signature in comments
         out.push_str(&format!("#\n"));
@@ -232,9 +214,18 @@ impl Function
         // Emit label for function
         out.push_str(&format!("{}:\n", self.name));
 
-        // Allocate stack slots for the local variables
-        if self.num_locals > 1 && self.num_locals <= 255 {
-            out.push_str(&format!("push_0n {};\n", self.num_locals));
+        // The prologue gets the source line of the first statement
+        if let Stmt::Block(stmts) = &self.body {
+            if let Some(line @ Stmt::Line { .. }) = stmts.first() {
+                line.gen_code(self, &None, &None, sym, out);
+            }
+        }
+
+        // Allocate stack slots for the local variables, recording their
+        // count in the stack frame so the VM can validate local accesses.
+        // The enter instruction can only allocate up to 255 locals.
+        if self.num_locals <= 255 {
+            out.push_str(&format!("enter {};\n", self.num_locals));
         } else {
             for i in 0..self.num_locals {
                 out.push_str("push 0;\n");
@@ -270,24 +261,16 @@ impl Function
             out.push_str(&format!("{}:\n", no_overflow));
         }
 
-        self.body.gen_code(self, &None, &None, sym, out)?;
+        self.body.gen_code(self, &None, &None, sym, out);
 
         // If the body needs a final return
-        if self.needs_final_return() {
-            // If this function uses stack allocation, restore the alloc stack sp
-            if let Some(bp_idx) = self.stack_alloc_bp {
-                out.push_str(&format!("push __stack_alloc_sp__;\n"));
-                out.push_str(&format!("get_local {};\n", bp_idx));
-                out.push_str(&format!("store_u64;\n"));
-            }
-
+        if self.needs_final_return {
+            self.gen_stack_alloc_restore(out);
             out.push_str("push 0;\n");
             out.push_str("ret;\n");
         }
 
         out.push_str("\n");
-
-        Ok(())
     }
 }
 
@@ -300,80 +283,61 @@ impl Stmt
         cont_label: &Option<String>,
         sym: &mut SymGen,
         out: &mut String
-    ) -> Result<(), ParseError>
+    )
     {
         match self {
             Stmt::Expr(expr) => {
-                match expr {
+                match &expr.kind {
                     // For assignment expressions as statements,
                     // avoid generating output that we would then need to pop
-                    Expr::Binary { op: BinOp::Assign, lhs, rhs } => {
-                        gen_assign(lhs, rhs, sym, out, false)?;
+                    ExprKind::Assign { dst, value } => {
+                        gen_assign(dst, value, sym, out, false);
                     }
 
                     // For asm expressions with void output type, don't pop
                     // the output because no output is produced
-                    Expr::Asm { out_type: Type::Void, .. } => {
-                        expr.gen_code(sym, out)?;
+                    ExprKind::Asm { .. } if matches!(expr.t, Void) => {
+                        expr.gen_code(sym, out);
                     }
 
                     _ => {
-                        expr.gen_code(sym, out)?;
+                        expr.gen_code(sym, out);
                         out.push_str("pop;\n");
                     }
                 }
             }
 
+            // Loop labels are always set, since lowering rejects
+            // break and continue statements outside of loops
             Stmt::Break => {
-                match break_label {
-                    Some(label) => out.push_str(&format!("jmp {};\n", label)),
-                    None => return ParseError::msg_only("break outside of loop context")
-                }
+                out.push_str(&format!("jmp {};\n", break_label.as_ref().unwrap()));
             }
 
             Stmt::Continue => {
-                match cont_label {
-                    Some(label) => out.push_str(&format!("jmp {};\n", label)),
-                    None => return ParseError::msg_only("continue outside of loop context")
-                }
+                out.push_str(&format!("jmp {};\n", cont_label.as_ref().unwrap()));
             }
 
             // Return void
-            Stmt::ReturnVoid => {
-                // If this function uses stack allocation, restore the alloc stack sp
-                if let Some(bp_idx) = fun.stack_alloc_bp {
-                    out.push_str(&format!("push __stack_alloc_sp__;\n"));
-                    out.push_str(&format!("get_local {};\n", bp_idx));
-                    out.push_str(&format!("store_u64;\n"));
-                }
-
+            Stmt::Return(None) => {
+                fun.gen_stack_alloc_restore(out);
                 out.push_str("push 0;\n");
                 out.push_str("ret;\n");
             }
 
-            Stmt::ReturnExpr(expr) => {
-                // If this function uses stack allocation, restore the alloc stack sp
-                if let Some(bp_idx) = fun.stack_alloc_bp {
-                    out.push_str(&format!("push __stack_alloc_sp__;\n"));
-                    out.push_str(&format!("get_local {};\n", bp_idx));
-                    out.push_str(&format!("store_u64;\n"));
-                }
+            Stmt::Return(Some(expr)) => {
+                fun.gen_stack_alloc_restore(out);
+                expr.gen_code(sym, out);
 
                 // If we're returning an asm expression with type void
-                if let Expr::Asm { out_type: Type::Void, .. } = expr.as_ref() {
-                    expr.gen_code(sym, out)?;
+                if let (ExprKind::Asm { .. }, Void) = (&expr.kind, &expr.t) {
                     out.push_str("push 0;\n");
-                    out.push_str("ret;\n");
-                }
-                else
-                {
-                    expr.gen_code(sym, out)?;
-                    out.push_str("ret;\n");
                 }
+
+                out.push_str("ret;\n");
             }
 
             Stmt::If { test_expr, then_stmt, else_stmt } => {
-                test_expr.gen_code(sym, out)?;
+                test_expr.gen_code(sym, out);
 
                 let false_label = sym.gen_sym("if_false");
 
@@ -383,26 +347,50 @@ impl Stmt
                 if else_stmt.is_some() {
                     let join_label = sym.gen_sym("if_join");
 
-                    then_stmt.gen_code(fun, break_label, cont_label, sym, out)?;
+                    then_stmt.gen_code(fun, break_label, cont_label, sym, out);
                     out.push_str(&format!("jmp {};\n", join_label));
 
                     out.push_str(&format!("{}:\n", false_label));
-                    else_stmt.as_ref().unwrap().gen_code(fun, break_label, cont_label, sym, out)?;
+                    else_stmt.as_ref().unwrap().gen_code(fun, break_label, cont_label, sym, out);
                     out.push_str(&format!("{}:\n", join_label));
                 }
                 else
                 {
-                    then_stmt.gen_code(fun, break_label, cont_label, sym, out)?;
+                    then_stmt.gen_code(fun, break_label, cont_label, sym, out);
                     out.push_str(&format!("{}:\n", false_label));
                 }
             }
 
+            // In hot functions, loops are rotated so that the test is at the
+            // bottom, which saves executing a jump on every iteration
+            Stmt::While { test_expr, body_stmt } if fun.hot => {
+                let loop_label = sym.gen_sym("while_loop");
+                let test_label = sym.gen_sym("while_test");
+                let break_label = sym.gen_sym("while_break");
+
+                out.push_str(&format!("jmp {};\n", test_label));
+                out.push_str(&format!("{}:\n", loop_label));
+
+                body_stmt.gen_code(
+                    fun,
+                    &Some(break_label.clone()),
+                    &Some(test_label.clone()),
+                    sym,
+                    out
+                );
+
+                out.push_str(&format!("{}:\n", test_label));
+                test_expr.gen_code(sym, out);
+                out.push_str(&format!("jnz {};\n", loop_label));
+                out.push_str(&format!("{}:\n", break_label));
+            }
+
             Stmt::While { test_expr, body_stmt } => {
                 let loop_label = sym.gen_sym("while_loop");
                 let break_label = sym.gen_sym("while_break");
 
                 out.push_str(&format!("{}:\n", loop_label));
-                test_expr.gen_code(sym, out)?;
+                test_expr.gen_code(sym, out);
                 out.push_str(&format!("jz {};\n", break_label));
 
                 body_stmt.gen_code(
@@ -411,7 +399,7 @@ impl Stmt
                     &Some(loop_label.clone()),
                     sym,
                     out
-                )?;
+                );
 
                 out.push_str(&format!("jmp {};\n", loop_label));
                 out.push_str(&format!("{}:\n", break_label));
@@ -429,27 +417,56 @@ impl Stmt
                     &Some(cont_label.clone()),
                     sym,
                     out
-                )?;
+                );
 
                 out.push_str(&format!("{}:\n", cont_label));
-                test_expr.gen_code(sym, out)?;
-                out.push_str(&format!("jz {};\n", break_label));
-                out.push_str(&format!("jmp {};\n", loop_label));
+                test_expr.gen_code(sym, out);
+
+                if fun.hot {
+                    out.push_str(&format!("jnz {};\n", loop_label));
+                } else {
+                    out.push_str(&format!("jz {};\n", break_label));
+                    out.push_str(&format!("jmp {};\n", loop_label));
+                }
 
                 out.push_str(&format!("{}:\n", break_label));
             }
 
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 if init_stmt.is_some() {
-                    init_stmt.as_ref().unwrap().gen_code(fun, break_label, cont_label, sym, out)?;
+                    init_stmt.as_ref().unwrap().gen_code(fun, break_label, cont_label, sym, out);
                 }
 
                 let loop_label = sym.gen_sym("for_loop");
                 let cont_label = sym.gen_sym("for_cont");
                 let break_label = sym.gen_sym("for_break");
 
+                if fun.hot {
+                    let test_label = sym.gen_sym("for_test");
+                    out.push_str(&format!("jmp {};\n", test_label));
+                    out.push_str(&format!("{}:\n", loop_label));
+
+                    body_stmt.gen_code(
+                        fun,
+                        &Some(break_label.clone()),
+                        &Some(cont_label.clone()),
+                        sym,
+                        out
+                    );
+
+                    out.push_str(&format!("{}:\n", cont_label));
+                    incr_expr.gen_code(sym, out);
+                    out.push_str("pop;\n");
+
+                    out.push_str(&format!("{}:\n", test_label));
+                    test_expr.gen_code(sym, out);
+                    out.push_str(&format!("jnz {};\n", loop_label));
+                    out.push_str(&format!("{}:\n", break_label));
+                    return;
+                }
+
                 out.push_str(&format!("{}:\n", loop_label));
-                test_expr.gen_code(sym, out)?;
+                test_expr.gen_code(sym, out);
                 out.push_str(&format!("jz {};\n", break_label));
 
                 body_stmt.gen_code(
@@ -458,10 +475,10 @@ impl Stmt
                     &Some(cont_label.clone()),
                     sym,
                     out
-                )?;
+                );
 
                 out.push_str(&format!("{}:\n", cont_label));
-                incr_expr.gen_code(sym, out)?;
+                incr_expr.gen_code(sym, out);
                 out.push_str("pop;\n");
                 out.push_str(&format!("jmp {};\n", loop_label));
 
@@ -470,74 +487,96 @@ impl Stmt
 
             Stmt::Block(stmts) => {
                 for stmt in stmts {
-                    stmt.gen_code(fun, break_label, cont_label, sym, out)?;
+                    stmt.gen_code(fun, break_label, cont_label, sym, out);
                 }
             }
 
-            _ => todo!()
-        }
+            // Continue statements in the body go to the enclosing loop
+            Stmt::Switch { test_expr, cases, default, body } => {
+                let break_label = sym.gen_sym("switch_break");
+                let case_labels: Vec<(i128, String)> = cases.iter().map(|(val, _)| (*val, sym.gen_sym("switch_case"))).collect();
+                let default_label = match default {
+                    Some(_) => sym.gen_sym("switch_default"),
+                    None => break_label.clone(),
+                };
+
+                test_expr.gen_code(sym, out);
+                gen_switch_jump(&test_expr.t, &case_labels, &default_label, sym, out);
+
+                for idx in 0..=body.len() {
+                    for ((_, stmt_idx), (_, label)) in cases.iter().zip(&case_labels) {
+                        if *stmt_idx == idx {
+                            out.push_str(&format!("{}:\n", label));
+                        }
+                    }
+
+                    if *default == Some(idx) {
+                        out.push_str(&format!("{}:\n", default_label));
+                    }
 
-        Ok(())
+                    if let Some(stmt) = body.get(idx) {
+                        stmt.gen_code(fun, &Some(break_label.clone()), cont_label, sym, out);
+                    }
+                }
+
+                out.push_str(&format!("{}:\n", break_label));
+            }
+
+            Stmt::Line { src_name, line_no } => {
+                out.push_str(&format!(".loc \"{}\", {};\n", src_name.as_bytes().escape_ascii(), line_no));
+            }
+        }
     }
 }
 
 impl Expr
 {
-    fn gen_code(&self, sym: &mut SymGen, out: &mut String) -> Result<(), ParseError>
+    fn gen_code(&self, sym: &mut SymGen, out: &mut String)
     {
-        match self {
-            Expr::Int(v) => {
+        match &self.kind {
+            ExprKind::Int(v) => {
                 out.push_str(&format!("push {};\n", v));
             }
 
-            Expr::Float32(v) => {
+            ExprKind::Float32(v) => {
                 out.push_str(&format!("push_f32 {};\n", v));
             }
 
-            Expr::Ref(decl) => {
-                match decl {
-                    Decl::Arg { idx, .. } => {
-                        out.push_str(&format!("get_arg {};\n", idx));
-                    }
-                    Decl::Local { idx, .. } => {
-                        out.push_str(&format!("get_local {};\n", idx));
+            ExprKind::Arg(idx) => {
+                out.push_str(&format!("get_arg {};\n", idx));
+            }
+
+            ExprKind::Local(idx) => {
+                out.push_str(&format!("get_local {};\n", idx));
+            }
+
+            ExprKind::Global(name) => {
+                out.push_str(&format!("push {};\n", name));
+                match &self.t {
+                    UInt(n) => out.push_str(&format!("load_u{};\n", n)),
+                    Int(64) => out.push_str("load_u64;\n"),
+                    Int(32) => {
+                        out.push_str("load_u32;\n");
+                        out.push_str("sx_i32_i64;\n");
                     }
-                    Decl::Global { name, t } => {
-                        out.push_str(&format!("push {};\n", name));
-                        match t {
-                            Type::UInt(n) => out.push_str(&format!("load_u{};\n", n)),
-                            Type::Int(64) => out.push_str("load_u64;\n"),
-                            Type::Int(32) => {
-                                out.push_str("load_u32;\n");
-                                out.push_str("sx_i32_i64;\n");
-                            }
-                            Type::Float(32) => {
-                                out.push_str("load_u32;\n");
-                            }
-                            Type::Pointer(t) => {
-                                out.push_str("load_u64;\n");
-                            }
-                            Type::Fun { .. } => {}
-                            Type::Array { .. } => {}
-                            Type::Struct { .. } => {}
-                            _ => todo!()
-                        }
+                    Float(32) => {
+                        out.push_str("load_u32;\n");
                     }
-                    Decl::Fun { name, t } => {
-                        out.push_str(&format!("push {};\n", name));
+                    Pointer(_) => {
+                        out.push_str("load_u64;\n");
                     }
-
-                    _ => panic!()
+                    _ => unreachable!()
                 }
             }
 
-            Expr::Cast { new_type, child } => {
-                use Type::*;
+            ExprKind::Addr(name) => {
+                out.push_str(&format!("push {};\n", name));
+            }
 
-                let src_type = child.eval_type()?;
-                child.gen_code(sym, out)?;
+            ExprKind::Cast(child) => {
+                child.gen_code(sym, out);
 
-                match (&new_type, &src_type) {
+                match (&self.t, &child.t) {
                     // These int casts are no-ops
                     (UInt(m), Int(n)) if m >= n => {},
                     (Int(m), UInt(n)) if m >= n => {},
@@ -583,6 +622,7 @@ impl Expr
                     // Pointer cast, these are no-ops
                     (Pointer(_), Pointer(_)) => {},
                     (Pointer(_), Array{..}) => {},
+                    (Pointer(_), Struct{..}) => {},
                     (UInt(64), Pointer(_)) => {},
                     (Pointer(_), UInt(_)) => {},
                     (Pointer(_), Fun {..}) => {},
@@ -594,185 +634,115 @@ impl Expr
                         }
                     },
 
-                    _ => panic!("cannot cast to {} from {}", new_type, src_type)
+                    // Lowering only produces supported casts
+                    _ => unreachable!("cannot cast to {} from {}", self.t, child.t)
                 }
             }
 
-            Expr::SizeofExpr { child } => {
-                let t = child.eval_type()?;
-                out.push_str(&format!("push {};\n", t.sizeof()));
-            }
-
-            Expr::SizeofType { t } => {
-                out.push_str(&format!("push {};\n", t.sizeof()));
+            ExprKind::Load { addr, num_bits } => {
+                addr.gen_code(sym, out);
+                out.push_str(&format!("load_u{};\n", num_bits));
             }
 
-            Expr::Arrow { base, field } => {
-                base.gen_code(sym, out)?;
-                let base_type = base.eval_type()?;
+            ExprKind::Field { base, offset, num_bits } => {
+                base.gen_code(sym, out);
+                out.push_str(&format!("push {};\n", offset));
+                out.push_str("add_u64;");
 
-                if let Pointer(s) = base_type {
-                    let (offset, size_bytes) = s.get_field(field).unwrap();
-                    let num_bits = size_bytes * 8;
-
-                    if num_bits <= 64 {
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;");
-                        out.push_str(&format!("load_u{};\n", num_bits));
-                    }
-                    else
-                    {
-                        // Don't load the field, compute its address instead
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;");
-                    }
-                }
-                else
-                {
-                    panic!();
+                // Array and struct fields evaluate to their address
+                if !matches!(self.t, Array { .. } | Struct { .. }) {
+                    out.push_str(&format!("load_u{};\n", num_bits));
                 }
             }
 
-            Expr::Unary { op, child } => {
-                // If we're computing the address of a dereference,
-                // which happens for addresses of array elements
-                if *op == UnOp::AddressOf {
-                    if let Expr::Unary { op: UnOp::Deref, child } = child.as_ref() {
-                        child.gen_code(sym, out)?;
-                        return Ok(());
-                    }
-                }
-
-                child.gen_code(sym, out)?;
-
-                match op {
-                    UnOp::Deref => {
-                        let child_type = child.eval_type()?;
-
-                        // If this is a pointer to an array, this is a no-op
-                        // because a pointer to an array is the array itself
-                        if let Pointer(t) = child_type {
-                            if let Array { .. } = t.as_ref() {
-                                return Ok(())
-                            }
-                        }
+            ExprKind::Neg(child) => {
+                child.gen_code(sym, out);
 
-                        let ptr_type = child.eval_type()?;
-                        let elem_bits = ptr_type.elem_type().num_bits();
-                        out.push_str(&format!("load_u{};\n", elem_bits));
+                match child.t {
+                    Float(32) => {
+                        out.push_str(&format!("push_f32 -1;\n"));
+                        out.push_str(&format!("mul_f32;\n"));
                     }
 
-                    // Address of (&a) operator
-                    UnOp::AddressOf => {
-                        let child_type = child.eval_type()?;
-
-                        // For structs, this is currently a no-op
-                        if let Struct {..} = child_type {
-                            return Ok(())
-                        }
-
-                        todo!();
-                    }
-
-                    UnOp::Minus => {
-                        let child_type = child.eval_type()?;
-
-                        match child_type {
-                            Float(32) => {
-                                out.push_str(&format!("push_f32 -1;\n"));
-                                out.push_str(&format!("mul_f32;\n"));
-                            }
-
-                            Int(n) | UInt(n) => {
-                                if n <= 32 {
-                                    if child_type.is_signed() && n < 32 {
-                                        out.push_str(&format!("sx_i{}_i32;\n", n));
-                                    }
-                                    out.push_str(&format!("push -1;\n"));
-                                    out.push_str(&format!("mul_u32;\n"));
-                                } else {
-                                    out.push_str(&format!("push -1;\n"));
-                                    out.push_str(&format!("mul_u64;\n"));
-                                }
+                    Int(n) | UInt(n) => {
+                        if n <= 32 {
+                            if child.t.is_signed() && n < 32 {
+                                out.push_str(&format!("sx_i{}_i32;\n", n));
                             }
-
-                            _ => panic!()
+                            out.push_str(&format!("push -1;\n"));
+                            out.push_str(&format!("mul_u32;\n"));
+                        } else {
+                            out.push_str(&format!("push -1;\n"));
+                            out.push_str(&format!("mul_u64;\n"));
                         }
                     }
 
-                    UnOp::BitNot => {
-                        let child_type = child.eval_type()?;
-                        let num_bits = child_type.num_bits();
-                        let op_bits = if num_bits <= 32 { 32 } else { 64 };
-                        out.push_str(&format!("not_u{};\n", op_bits));
+                    _ => unreachable!()
+                }
+            }
 
-                        if num_bits < 32 {
-                            out.push_str(&format!("trunc_u{};\n", num_bits));
-                        }
-                    }
+            ExprKind::BitNot(child) => {
+                child.gen_code(sym, out);
 
-                    // Logical negation
-                    UnOp::Not => {
-                        out.push_str("push 0;\n");
-                        out.push_str("eq_u64;\n");
-                    }
+                let num_bits = child.t.num_bits();
+                let op_bits = if num_bits <= 32 { 32 } else { 64 };
+                out.push_str(&format!("not_u{};\n", op_bits));
 
-                    //_ => todo!()
+                if num_bits < 32 {
+                    out.push_str(&format!("trunc_u{};\n", num_bits));
                 }
-            },
+            }
+
+            // Logical negation
+            ExprKind::Not(child) => {
+                child.gen_code(sym, out);
+                out.push_str("push 0;\n");
+                out.push_str("eq_u64;\n");
+            }
 
-            Expr::Binary { op, lhs, rhs } => {
-                let out_type = self.eval_type()?;
-                gen_bin_op(op, lhs, rhs, &out_type, sym, out)?;
+            ExprKind::Binary { op, lhs, rhs } => {
+                gen_bin_op(op, lhs, rhs, &self.t, sym, out);
             }
 
-            Expr::Ternary { test_expr, then_expr, else_expr } => {
+            ExprKind::Assign { dst, value } => {
+                gen_assign(dst, value, sym, out, true);
+            }
+
+            ExprKind::Ternary { test_expr, then_expr, else_expr } => {
                 let false_label = sym.gen_sym("and_false");
                 let done_label = sym.gen_sym("and_done");
 
-                test_expr.gen_code(sym, out)?;
+                test_expr.gen_code(sym, out);
                 out.push_str(&format!("jz {};\n", false_label));
 
                 // Evaluate the then expression
-                then_expr.gen_code(sym, out)?;
+                then_expr.gen_code(sym, out);
                 out.push_str(&format!("jmp {};\n", done_label));
 
                 // Evaluate the else expression
                 out.push_str(&format!("{}:\n", false_label));
-                else_expr.gen_code(sym, out)?;
+                else_expr.gen_code(sym, out);
 
                 out.push_str(&format!("{}:\n", done_label));
             }
 
-            Expr::Call { callee, args } => {
-                //callee.gen_code(out)?;
-
-                match callee.as_ref() {
-                    Expr::Ref(Decl::Fun { name, .. }) =>
-                    {
-                        for arg in args {
-                            arg.gen_code(sym, out)?;
-                        }
-
-                        out.push_str(&format!("call {}, {};\n", name, args.len()));
-                    }
-                    _ => todo!()
+            ExprKind::Call { name, args } => {
+                for arg in args {
+                    arg.gen_code(sym, out);
                 }
+
+                out.push_str(&format!("call {}, {};\n", name, args.len()));
             }
 
-            Expr::Asm { text, args, out_type } => {
+            ExprKind::Asm { text, args } => {
                 for arg in args {
-                    arg.gen_code(sym, out)?;
+                    arg.gen_code(sym, out);
                 }
 
                 out.push_str(&text);
                 out.push_str("\n");
             }
-
-            _ => todo!("{:?}", self)
         }
-
-        Ok(())
     }
 }
 
@@ -801,6 +771,134 @@ fn emit_arith_op(out_type: &Type, signed_op: &str, unsigned_op: &str, fp_op: &st
     }
 }
 
+
+/// Get the value of an integer constant, looking through casts
+/// that don't change its value
+fn const_int_val(expr: &Expr) -> Option<i128>
+{
+    match (&expr.kind, &expr.t) {
+        (ExprKind::Int(val), _) => Some(*val),
+
+        (ExprKind::Cast(child), Int(n)) => {
+            const_int_val(child).filter(|&v| v >= -(1 << (n - 1)) && v < (1 << (n - 1)))
+        }
+
+        (ExprKind::Cast(child), UInt(n)) => {
+            const_int_val(child).filter(|&v| v >= 0 && v < (1 << n))
+        }
+
+        _ => None
+    }
+}
+
+/// Emit code for a multiplication, division or remainder
+/// by a power of two using shifts and masks.
+/// Returns false if the operation can't be strength-reduced.
+fn gen_pow2_op(
+    op: &BinOp,
+    lhs: &Expr,
+    rhs: &Expr,
+    out_type: &Type,
+    sym: &mut SymGen,
+    out: &mut String
+) -> bool
+{
+    let num_bits = match out_type {
+        Int(n) | UInt(n) => *n,
+        _ => return false
+    };
+
+    // Multiplication is commutative, so the constant can be on either side
+    let (expr, val) = match (op, const_int_val(lhs), const_int_val(rhs)) {
+        (_, _, Some(val)) => (lhs, val),
+        (BinOp::Mul, Some(val), _) => (rhs, val),
+        _ => return false
+    };
+
+    if val <= 0 || (val & (val - 1)) != 0 {
+        return false;
+    }
+
+    let shift = val.trailing_zeros() as usize;
+    let is_signed = out_type.is_signed();
+
+    // Signed division by one doesn't need to be special-cased, and
+    // signed values narrower than 32 bits aren't sign-extended
+    if shift >= num_bits || (is_signed && (num_bits < 32 || (*op != BinOp::Mul && shift == 0))) {
+        return false;
+    }
+
+    let op_bits = if num_bits == 64 { 64 } else { 32 };
+    expr.gen_code(sym, out);
+
+    match op {
+        BinOp::Mul => {
+            out.push_str(&format!("push {};\n", shift));
+            out.push_str(&format!("lshift_u{};\n", op_bits));
+        }
+
+        BinOp::Div if !is_signed => {
+            out.push_str(&format!("push {};\n", shift));
+            out.push_str(&format!("rshift_u{};\n", op_bits));
+        }
+
+        BinOp::Mod if !is_signed => {
+            out.push_str(&format!("push {};\n", val - 1));
+            out.push_str(&format!("and_u{};\n", op_bits));
+        }
+
+        // Signed division rounds toward zero, so negative values
+        // are biased by 2^shift - 1 before shifting
+        BinOp::Div => {
+            out.push_str("dup;\n");
+            emit_pow2_bias(shift, op_bits, out);
+            out.push_str(&format!("push {};\n", shift));
+            out.push_str(&format!("rshift_i{};\n", op_bits));
+        }
+
+        // x % 2^k is x - ((x + bias) & -2^k)
+        BinOp::Mod => {
+            out.push_str("dup;\n");
+            out.push_str("dup;\n");
+            emit_pow2_bias(shift, op_bits, out);
+            out.push_str(&format!("push {};\n", -val));
+            out.push_str(&format!("and_u{};\n", op_bits));
+            out.push_str(&format!("sub_u{};\n", op_bits));
+        }
+
+        _ => unreachable!()
+    }
+
+    if num_bits < 32 {
+        out.push_str(&format!("trunc_u{};\n", num_bits));
+    }
+
+    true
+}
+
+/// Add 2^shift - 1 to the signed value on top of the stack if it is
+/// negative, given a copy of that value right below it
+fn emit_pow2_bias(shift: usize, op_bits: usize, out: &mut String)
+{
+    out.push_str(&format!("push {};\n", op_bits - 1));
+    out.push_str(&format!("rshift_i{};\n", op_bits));
+    out.push_str(&format!("push {};\n", op_bits - shift));
+    out.push_str(&format!("rshift_u{};\n", op_bits));
+    out.push_str(&format!("add_u{};\n", op_bits));
+}
+
+/// Emit code to multiply a 64-bit index by an element size
+fn emit_scale(elem_sizeof: usize, out: &mut String)
+{
+    if elem_sizeof.is_power_of_two() {
+        out.push_str(&format!("push {};\n", elem_sizeof.trailing_zeros()));
+        out.push_str("lshift_u64;\n");
+    } else {
+        out.push_str(&format!("push {};\n", elem_sizeof));
+        out.push_str("mul_u64;\n");
+    }
+}
+
 /// Emit code for a comparison operation
 fn emit_cmp_op(lhs_type: &Type, rhs_type: &Type, signed_op: &str, unsigned_op: &str, fp_op: &str, out: &mut String)
 {
@@ -841,24 +939,21 @@ fn gen_bin_op(
     out_type: &Type,
     sym: &mut SymGen,
     out: &mut String
-) -> Result<(), ParseError>
+)
 {
     use BinOp::*;
-    use Type::*;
-
-    // Assignments are different from other kinds of expressions
-    // because we don't evaluate the lhs the same way
-    if *op == Assign {
-        gen_assign(lhs, rhs, sym, out, true)?;
-        return Ok(());
-    }
 
     // Comma sequencing operator: (a, b)
     if *op == Comma {
-        lhs.gen_code(sym, out)?;
-        out.push_str("pop;\n");
-        rhs.gen_code(sym, out)?;
-        return Ok(());
+        // The value of an assignment on the left is not needed
+        if let ExprKind::Assign { dst, value } = &lhs.kind {
+            gen_assign(dst, value, sym, out, false);
+        } else {
+            lhs.gen_code(sym, out);
+            out.push_str("pop;\n");
+        }
+        rhs.gen_code(sym, out);
+        return;
     }
 
     // Logical AND (a && b)
@@ -867,11 +962,11 @@ fn gen_bin_op(
         let done_label = sym.gen_sym("and_done");
 
         // If a is false, the expression evaluates to false
-        lhs.gen_code(sym, out)?;
+        lhs.gen_code(sym, out);
         out.push_str(&format!("jz {};\n", false_label));
 
         // Evaluate the rhs
-        rhs.gen_code(sym, out)?;
+        rhs.gen_code(sym, out);
         out.push_str(&format!("jz {};\n", false_label));
 
         // Both subexpressions are true
@@ -883,7 +978,7 @@ fn gen_bin_op(
 
         out.push_str(&format!("{}:\n", done_label));
 
-        return Ok(());
+        return;
     }
 
     // Logical OR (a || b)
@@ -892,11 +987,11 @@ fn gen_bin_op(
         let done_label = sym.gen_sym("or_done");
 
         // If a is true, the expression evaluates to true
-        lhs.gen_code(sym, out)?;
+        lhs.gen_code(sym, out);
         out.push_str(&format!("jnz {};\n", true_label));
 
         // Evaluate the rhs
-        rhs.gen_code(sym, out)?;
+        rhs.gen_code(sym, out);
         out.push_str(&format!("jnz {};\n", true_label));
 
         // Both subexpressions are false
@@ -908,14 +1003,20 @@ fn gen_bin_op(
 
         out.push_str(&format!("{}:\n", done_label));
 
-        return Ok(());
+        return;
     }
 
-    lhs.gen_code(sym, out)?;
-    rhs.gen_code(sym, out)?;
+    // Multiplications and divisions by powers of two are strength-reduced
+    // into shifts and masks, since mul/div are slow in the VM
+    if matches!(op, Mul | Div | Mod) && gen_pow2_op(op, lhs, rhs, out_type, sym, out) {
+        return;
+    }
+
+    lhs.gen_code(sym, out);
+    rhs.gen_code(sym, out);
 
-    let lhs_type = lhs.eval_type()?;
-    let rhs_type = rhs.eval_type()?;
+    let lhs_type = &lhs.t;
+    let rhs_type = &rhs.t;
 
     match op {
         BitAnd => {
@@ -942,34 +1043,26 @@ fn gen_bin_op(
         Add => {
             match (lhs_type, rhs_type) {
                 // Small signed indices need to be sign-extended
-                (Pointer(b), Int(n)) if n <= 32 => {
+                (Pointer(b), Int(n)) if *n <= 32 => {
                     out.push_str(&format!("sx_i{}_i64;\n", n));
-                    let elem_sizeof = b.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
+                    emit_scale(b.sizeof(), out);
                     out.push_str("add_u64;\n");
                 }
 
                 (Pointer(b), UInt(n)) | (Pointer(b), Int(n)) => {
-                    let elem_sizeof = b.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
+                    emit_scale(b.sizeof(), out);
                     out.push_str("add_u64;\n");
                 }
 
                 // Small signed indices need to be sign-extended
-                (Array{ elem_type , ..}, Int(n)) if n <= 32 => {
+                (Array{ elem_type , ..}, Int(n)) if *n <= 32 => {
                     out.push_str(&format!("sx_i{}_i64;\n", n));
-                    let elem_sizeof = elem_type.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
+                    emit_scale(elem_type.sizeof(), out);
                     out.push_str("add_u64;\n");
                 }
 
                 (Array{ elem_type , ..}, UInt(n)) | (Array{ elem_type , ..}, Int(n)) => {
-                    let elem_sizeof = elem_type.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
+                    emit_scale(elem_type.sizeof(), out);
                     out.push_str("add_u64;\n");
                 }
 
@@ -982,18 +1075,14 @@ fn gen_bin_op(
         Sub => {
             match (lhs_type, rhs_type) {
                 // Small signed indices need to be sign-extended
-                (Pointer(b), Int(n)) if n <= 32 => {
+                (Pointer(b), Int(n)) if *n <= 32 => {
                     out.push_str(&format!("sx_i{}_i64;\n", n));
-                    let elem_sizeof = b.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
+                    emit_scale(b.sizeof(), out);
                     out.push_str("sub_u64;\n");
                 }
 
                 (Pointer(b), UInt(n)) | (Pointer(b), Int(n)) => {
-                    let elem_sizeof = b.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
+                    emit_scale(b.sizeof(), out);
                     out.push_str("sub_u64;\n");
                 }
 
@@ -1017,173 +1106,189 @@ fn gen_bin_op(
         }
 
         Eq => {
-            emit_cmp_op(&lhs_type, &rhs_type, "eq_u", "eq_u", "eq_f", out);
+            emit_cmp_op(lhs_type, rhs_type, "eq_u", "eq_u", "eq_f", out);
         }
 
         Ne => {
-            emit_cmp_op(&lhs_type, &rhs_type, "ne_u", "ne_u", "ne_f", out);
+            emit_cmp_op(lhs_type, rhs_type, "ne_u", "ne_u", "ne_f", out);
         }
 
         Lt => {
-            emit_cmp_op(&lhs_type, &rhs_type, "lt_i", "lt_u", "lt_f", out);
+            emit_cmp_op(lhs_type, rhs_type, "lt_i", "lt_u", "lt_f", out);
         }
 
         Le => {
-            emit_cmp_op(&lhs_type, &rhs_type, "le_i", "le_u", "le_f", out);
+            emit_cmp_op(lhs_type, rhs_type, "le_i", "le_u", "le_f", out);
         }
 
         Gt => {
-            emit_cmp_op(&lhs_type, &rhs_type, "gt_i", "gt_u", "gt_f", out);
+            emit_cmp_op(lhs_type, rhs_type, "gt_i", "gt_u", "gt_f", out);
         }
 
         Ge => {
-            emit_cmp_op(&lhs_type, &rhs_type, "ge_i", "ge_u", "ge_f", out);
+            emit_cmp_op(lhs_type, rhs_type, "ge_i", "ge_u", "ge_f", out);
         }
 
-        _ => todo!("{:?}", op),
+        // Assignments are lowered separately
+        // Handled above, or lowered into LValue assignments
+        And | Or | Comma | Assign => unreachable!(),
     }
+}
 
-    Ok(())
+/// Jump from the value of a switch test expression, which is on
+/// the stack, to the label of the matching case or the default label
+fn gen_switch_jump(t: &Type, cases: &[(i128, String)], default_label: &str, sym: &mut SymGen, out: &mut String)
+{
+    // Values of 32 bits or less are compared on their low 32 bits
+    let (num_bits, suffix) = if t.num_bits() <= 32 { (32, "u32") } else { (64, "u64") };
+    let bits = |val: i128| (val as u64) & (u64::MAX >> (64 - num_bits));
+
+    let min_val = cases.iter().map(|(val, _)| *val).min().unwrap_or(0);
+    let max_val = cases.iter().map(|(val, _)| *val).max().unwrap_or(0);
+
+    // Dense cases index a jump table with the offset from the smallest value,
+    // which wraps around to a large index for values below it
+    if !cases.is_empty() && max_val - min_val < MAX_JUMP_TABLE_SPREAD * cases.len() as i128 {
+        if min_val != 0 {
+            out.push_str(&format!("push {};\n", bits(min_val)));
+            out.push_str(&format!("sub_{};\n", suffix));
+        } else if num_bits == 32 {
+            out.push_str("trunc_u32;\n");
+        }
+
+        let mut table = vec![default_label; (max_val - min_val + 1) as usize];
+        for (val, label) in cases {
+            table[(val - min_val) as usize] = label;
+        }
+
+        out.push_str(&format!("jmp_table [{}], {};\n", table.join(", "), default_label));
+        return;
+    }
+
+    // Sparse cases are compared with the value one by one
+    for (val, label) in cases {
+        let next_label = sym.gen_sym("switch_next");
+        out.push_str("dup;\n");
+        out.push_str(&format!("push {};\n", bits(*val)));
+        out.push_str(&format!("eq_{};\n", suffix));
+        out.push_str(&format!("jz {};\n", next_label));
+        out.push_str("pop;\n");
+        out.push_str(&format!("jmp {};\n", label));
+        out.push_str(&format!("{}:\n", next_label));
+    }
+
+    out.push_str("pop;\n");
+    out.push_str(&format!("jmp {};\n", default_label));
 }
 
 fn gen_assign(
-    lhs: &Expr,
-    rhs: &Expr,
+    dst: &LValue,
+    value: &Expr,
     sym: &mut SymGen,
     out: &mut String,
     need_value: bool,
-) -> Result<(), ParseError>
+)
 {
-    //dbg!(lhs);
-    //dbg!(rhs);
-
-    match lhs {
-        Expr::Arrow { base, field } => {
-            let base_type = base.eval_type()?;
-
-            if let Pointer(s) = base_type {
-                let (offset, size_bytes) = s.get_field(field).unwrap();
-                let num_bits = size_bytes * 8;
-
-                if num_bits <= 64 {
-                    // If the output value is needed
-                    if need_value {
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
-
-                        // Evaluate the base address
-                        base.gen_code(sym, out)?;
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;\n");
-
-                        out.push_str("getn 1;\n");
-                        out.push_str(&format!("store_u{};\n", num_bits));
-                    }
-                    else
-                    {
-                        // Evaluate the base address
-                        base.gen_code(sym, out)?;
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;\n");
+    match dst {
+        LValue::Arg(idx) => {
+            value.gen_code(sym, out);
+            if need_value { out.push_str("dup;\n"); }
+            out.push_str(&format!("set_arg {};\n", idx));
+        }
 
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+        LValue::Local(idx) => {
+            value.gen_code(sym, out);
+            if need_value { out.push_str("dup;\n"); }
+            out.push_str(&format!("set_local {};\n", idx));
+        }
 
-                        out.push_str(&format!("store_u{};\n", num_bits));
-                    }
-                }
-                else
-                {
-                    panic!();
-                }
+        LValue::Global { name, num_bits } => {
+            // If the output value is needed
+            if need_value {
+                // Evaluate the value expression
+                value.gen_code(sym, out);
+
+                // Push the address
+                out.push_str(&format!("push {};\n", name));
+
+                out.push_str("getn 1;\n");
             }
             else
             {
-                panic!();
-            }
-        }
+                // Push the address
+                out.push_str(&format!("push {};\n", name));
 
-        Expr::Unary { op, child } => {
-            match op {
-                UnOp::Deref => {
-                    let ptr_type = child.eval_type()?;
-                    let elem_bits = ptr_type.elem_type().num_bits();
+                // Evaluate the value expression
+                value.gen_code(sym, out);
+            }
 
-                    // If the output value is needed
-                    if need_value {
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+            out.push_str(&format!("store_u{};\n", num_bits));
+        }
 
-                        // Evaluate the address expression
-                        child.gen_code(sym, out)?;
+        LValue::Mem { addr, num_bits } => {
+            // If the output value is needed
+            if need_value {
+                // Evaluate the value expression
+                value.gen_code(sym, out);
 
-                        out.push_str("getn 1;\n");
-                    }
-                    else
-                    {
-                        // Evaluate the address expression
-                        child.gen_code(sym, out)?;
+                // Evaluate the address expression
+                addr.gen_code(sym, out);
 
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
-                    }
-
-                    // store (addr) (value)
-                    out.push_str(&format!("store_u{};\n", elem_bits));
-                }
-                _ => todo!()
+                out.push_str("getn 1;\n");
             }
-        },
-
-        Expr::Ref(decl) => {
-            match decl {
-                Decl::Arg { idx, .. } => {
-                    rhs.gen_code(sym, out)?;
-                    if need_value { out.push_str("dup;\n"); }
-                    out.push_str(&format!("set_arg {};\n", idx));
-                }
-                Decl::Local { idx, .. } => {
-                    rhs.gen_code(sym, out)?;
-                    if need_value { out.push_str("dup;\n"); }
-                    out.push_str(&format!("set_local {};\n", idx));
-                }
+            else
+            {
+                // Evaluate the address expression
+                addr.gen_code(sym, out);
 
-                Decl::Global { name, t } => {
-                    // If the output value is needed
-                    if need_value {
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+                // Evaluate the value expression
+                value.gen_code(sym, out);
+            }
 
-                        // Push the address
-                        out.push_str(&format!("push {};\n", name));
+            // store (addr) (value)
+            out.push_str(&format!("store_u{};\n", num_bits));
+        }
 
-                        out.push_str("getn 1;\n");
-                    }
-                    else
-                    {
-                        // Push the address
-                        out.push_str(&format!("push {};\n", name));
+        LValue::Field { base, offset, num_bits } => {
+            // If the output value is needed
+            if need_value {
+                // Evaluate the value expression
+                value.gen_code(sym, out);
 
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
-                    }
+                // Evaluate the base address
+                base.gen_code(sym, out);
+                out.push_str(&format!("push {};\n", offset));
+                out.push_str("add_u64;\n");
 
-                    match t {
-                        Type::UInt(n) | Type::Int(n) => out.push_str(&format!("store_u{};\n", n)),
-                        Type::Pointer(_) => out.push_str(&format!("store_u64;\n")),
-                        Type::Float(32) => out.push_str("store_u32;\n"),
+                out.push_str("getn 1;\n");
+                out.push_str(&format!("store_u{};\n", num_bits));
+            }
+            else
+            {
+                // Evaluate the base address
+                base.gen_code(sym, out);
+                out.push_str(&format!("push {};\n", offset));
+                out.push_str("add_u64;\n");
 
-                        _ => todo!()
-                    }
-                }
+                // Evaluate the value expression
+                value.gen_code(sym, out);
 
-                _ => todo!()
+                out.push_str(&format!("store_u{};\n", num_bits));
             }
         }
-        _ => todo!()
-    }
 
-    Ok(())
+        LValue::Copy { addr, num_bytes } => {
+            // Like other struct values, the value of
+            // the assignment is the address of the struct
+            addr.gen_code(sym, out);
+            if need_value { out.push_str("dup;\n"); }
+
+            // memcpy (dst) (src) (num_bytes)
+            value.gen_code(sym, out);
+            out.push_str(&format!("push {};\n", num_bytes));
+            out.push_str("syscall memcpy;\n");
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1204,7 +1309,7 @@ mod tests
         unit.check_types().unwrap();
         unit.insert_casts().unwrap();
         dbg!(&unit.fun_decls[0]);
-        unit.gen_code().unwrap()
+        unit.lower().unwrap().gen_code()
     }
 
     fn compile_file(file_name: &str)
@@ -1224,7 +1329,7 @@ mod tests
         unit.resolve_syms().unwrap();
         unit.check_types().unwrap();
         unit.insert_casts().unwrap();
-        unit.gen_code().unwrap();
+        unit.lower().unwrap().gen_code();
     }
 
     #[test]
@@ -1298,6 +1403,29 @@ mod tests
         gen_ok("int foo() { int g = 3; return (g); }");
     }
 
+    #[test]
+    fn interned_strings()
+    {
+        let out = gen_ok("char* a = \"foo\"; char* f() { return \"foo\"; } char* g() { return \"bar\"; }");
+        assert_eq!(out.matches(".intern").count(), 2);
+        assert!(!out.contains(".stringz"));
+    }
+
+    #[test]
+    fn strength_reduction()
+    {
+        let out = gen_ok("u64 f(int x, u64 y) { return x * 8 + x / 16 + x % 4 + 2 * y; }");
+        assert!(!out.contains("mul_") && !out.contains("div_") && !out.contains("mod_"));
+
+        // Pointer arithmetic scales indices with shifts
+        let out = gen_ok("u64 f(u64* p, u64 i) { return p[i]; }");
+        assert!(!out.contains("mul_"));
+
+        // Other constants still use mul/div
+        let out = gen_ok("int f(int x) { return x * 3 + x / 1; }");
+        assert!(out.contains("mul_u32") && out.contains("div_i32"));
+    }
+
     #[test]
     fn pointers()
     {
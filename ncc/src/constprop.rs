@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::*;
+use Type::*;
+
+impl Unit
+{
+    /// Replace uses of scalar global variables that are never written
+    /// with their initial value, so that configuration constants such as
+    /// WIDTH and HEIGHT don't generate a load at every use site.
+    /// A whole program is compiled as a single unit, so every write to
+    /// a global, including through its address, is visible here.
+    pub fn propagate_consts(&mut self)
+    {
+        // Globals that may be written
+        let mut written = HashSet::default();
+
+        for fun in &self.fun_decls {
+            fun.body.collect_writes(&mut written);
+        }
+
+        for global in &self.global_vars {
+            if let Some(init_expr) = &global.init_expr {
+                init_expr.collect_writes(&mut written);
+            }
+        }
+
+        let mut consts = HashMap::default();
+
+        for global in &self.global_vars {
+            if global.thread_local || global.interned || written.contains(&global.name) {
+                continue;
+            }
+
+            if let Some(val) = const_value(&global.var_type, &global.init_expr) {
+                consts.insert(global.name.clone(), val);
+            }
+        }
+
+        if consts.is_empty() {
+            return;
+        }
+
+        for fun in &mut self.fun_decls {
+            fun.body.subst_consts(&consts);
+        }
+    }
+}
+
+/// Get an expression for the initial value of a scalar global variable
+fn const_value(t: &Type, init_expr: &Option<Expr>) -> Option<Expr>
+{
+    let val = match (t, init_expr) {
+        (Float(32), None) => return Some(Expr::Float32(0.0)),
+        (Float(32), Some(Expr::Float32(v))) => return Some(Expr::Float32(*v)),
+
+        (UInt(_) | Int(_), None) => 0,
+        (UInt(_) | Int(_), Some(Expr::Int(v))) => *v,
+        _ => return None
+    };
+
+    // Wrap the value as it would be when stored in memory
+    let val = match t {
+        UInt(n) if *n < 64 => val & ((1 << n) - 1),
+        UInt(_) => val as u64 as i128,
+        Int(n) => {
+            let shift = 128 - *n as u32;
+            (val << shift) >> shift
+        }
+        _ => unreachable!()
+    };
+
+    // Integer literals that fit in 32 bits have int type
+    if let Int(32) = t {
+        return Some(Expr::Int(val));
+    }
+
+    Some(Expr::Cast {
+        new_type: t.clone(),
+        child: Box::new(Expr::Int(val))
+    })
+}
+
+impl Stmt
+{
+    /// Collect the names of the global variables a statement may write
+    fn collect_writes(&self, names: &mut HashSet<String>)
+    {
+        match self {
+            Stmt::Expr(expr) => expr.collect_writes(names),
+            Stmt::ReturnExpr(expr) => expr.collect_writes(names),
+            Stmt::ReturnVoid | Stmt::Break | Stmt::Continue => {}
+            Stmt::Case(_) | Stmt::Default => {}
+            Stmt::Line { .. } => {}
+
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    stmt.collect_writes(names);
+                }
+            }
+
+            Stmt::If { test_expr, then_stmt, else_stmt } => {
+                test_expr.collect_writes(names);
+                then_stmt.collect_writes(names);
+                if let Some(else_stmt) = else_stmt {
+                    else_stmt.collect_writes(names);
+                }
+            }
+
+            Stmt::While { test_expr, body_stmt } |
+            Stmt::DoWhile { body_stmt, test_expr } |
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.collect_writes(names);
+                body_stmt.collect_writes(names);
+            }
+
+            Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
+                if let Some(init_stmt) = init_stmt {
+                    init_stmt.collect_writes(names);
+                }
+                test_expr.collect_writes(names);
+                incr_expr.collect_writes(names);
+                body_stmt.collect_writes(names);
+            }
+
+            Stmt::VarDecl { init_expr, .. } => {
+                if let Some(init_expr) = init_expr {
+                    init_expr.collect_writes(names);
+                }
+            }
+        }
+    }
+
+    /// Substitute constant values for references to global variables
+    fn subst_consts(&mut self, consts: &HashMap<String, Expr>)
+    {
+        match self {
+            Stmt::Expr(expr) => expr.subst_consts(consts),
+            Stmt::ReturnExpr(expr) => expr.subst_consts(consts),
+            Stmt::ReturnVoid | Stmt::Break | Stmt::Continue => {}
+            Stmt::Case(_) | Stmt::Default => {}
+            Stmt::Line { .. } => {}
+
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    stmt.subst_consts(consts);
+                }
+            }
+
+            Stmt::If { test_expr, then_stmt, else_stmt } => {
+                test_expr.subst_consts(consts);
+                then_stmt.subst_consts(consts);
+                if let Some(else_stmt) = else_stmt {
+                    else_stmt.subst_consts(consts);
+                }
+            }
+
+            Stmt::While { test_expr, body_stmt } |
+            Stmt::DoWhile { body_stmt, test_expr } |
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.subst_consts(consts);
+                body_stmt.subst_consts(consts);
+            }
+
+            Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
+                if let Some(init_stmt) = init_stmt {
+                    init_stmt.subst_consts(consts);
+                }
+                test_expr.subst_consts(consts);
+                incr_expr.subst_consts(consts);
+                body_stmt.subst_consts(consts);
+            }
+
+            Stmt::VarDecl { init_expr, .. } => {
+                if let Some(init_expr) = init_expr {
+                    init_expr.subst_consts(consts);
+                }
+            }
+        }
+    }
+}
+
+impl Expr
+{
+    /// Collect the names of the global variables an expression may write,
+    /// either directly or by taking their address
+    fn collect_writes(&self, names: &mut HashSet<String>)
+    {
+        match self {
+            Expr::Int(_) | Expr::String(_) | Expr::Float32(_) => {}
+            Expr::SizeofType { .. } => {}
+            Expr::Ident(_) | Expr::Ref(_) => {}
+
+            Expr::Array(exprs) => {
+                for expr in exprs {
+                    expr.collect_writes(names);
+                }
+            }
+
+            Expr::Unary { op: UnOp::AddressOf, child } => {
+                if let Expr::Ref(Decl::Global { name, .. }) = child.as_ref() {
+                    names.insert(name.clone());
+                }
+                child.collect_writes(names);
+            }
+
+            Expr::Cast { child, .. } |
+            Expr::SizeofExpr { child } |
+            Expr::Unary { child, .. } => child.collect_writes(names),
+
+            Expr::Arrow { base, .. } => base.collect_writes(names),
+
+            Expr::Binary { op, lhs, rhs } => {
+                if let (BinOp::Assign, Expr::Ref(Decl::Global { name, .. })) = (op, lhs.as_ref()) {
+                    names.insert(name.clone());
+                }
+                lhs.collect_writes(names);
+                rhs.collect_writes(names);
+            }
+
+            Expr::CompoundAssign { lhs, rhs, .. } => {
+                if let Expr::Ref(Decl::Global { name, .. }) = lhs.as_ref() {
+                    names.insert(name.clone());
+                }
+                lhs.collect_writes(names);
+                rhs.collect_writes(names);
+            }
+
+            Expr::Ternary { test_expr, then_expr, else_expr } => {
+                test_expr.collect_writes(names);
+                then_expr.collect_writes(names);
+                else_expr.collect_writes(names);
+            }
+
+            Expr::Call { callee, args } => {
+                callee.collect_writes(names);
+                for arg in args {
+                    arg.collect_writes(names);
+                }
+            }
+
+            Expr::Asm { text, args, .. } => {
+                // Inline assembly may store to any label it mentions
+                let idents = text.split(|c: char| !c.is_alphanumeric() && c != '_');
+                names.extend(idents.filter(|s| !s.is_empty()).map(|s| s.to_string()));
+
+                for arg in args {
+                    arg.collect_writes(names);
+                }
+            }
+        }
+    }
+
+    /// Substitute constant values for references to global variables
+    fn subst_consts(&mut self, consts: &HashMap<String, Expr>)
+    {
+        match self {
+            Expr::Int(_) | Expr::String(_) | Expr::Float32(_) => {}
+            Expr::SizeofType { .. } | Expr::Ident(_) => {}
+
+            // The operand of sizeof is not evaluated, and its type must be kept
+            Expr::SizeofExpr { .. } => {}
+
+            Expr::Ref(Decl::Global { name, .. }) => {
+                if let Some(val) = consts.get(name) {
+                    *self = val.clone();
+                }
+            }
+            Expr::Ref(_) => {}
+
+            Expr::Array(exprs) => {
+                for expr in exprs {
+                    expr.subst_consts(consts);
+                }
+            }
+
+            Expr::Cast { child, .. } |
+            Expr::Unary { child, .. } => child.subst_consts(consts),
+
+            Expr::Arrow { base, .. } => base.subst_consts(consts),
+
+            Expr::Binary { lhs, rhs, .. } |
+            Expr::CompoundAssign { lhs, rhs, .. } => {
+                lhs.subst_consts(consts);
+                rhs.subst_consts(consts);
+            }
+
+            Expr::Ternary { test_expr, then_expr, else_expr } => {
+                test_expr.subst_consts(consts);
+                then_expr.subst_consts(consts);
+                else_expr.subst_consts(consts);
+            }
+
+            Expr::Call { callee, args } => {
+                callee.subst_consts(consts);
+                for arg in args {
+                    arg.subst_consts(consts);
+                }
+            }
+
+            Expr::Asm { args, .. } => {
+                for arg in args {
+                    arg.subst_consts(consts);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::parsing::Input;
+    use crate::parser::parse_unit;
+
+    /// Compile a unit and return the names of the globals that are still loaded
+    fn loaded_globals(src: &str) -> Vec<String>
+    {
+        let mut input = Input::new(src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        unit.resolve_types().unwrap();
+        unit.resolve_syms().unwrap();
+        unit.check_types().unwrap();
+        unit.propagate_consts();
+        unit.insert_casts().unwrap();
+        unit.strip_unused();
+        unit.global_vars.iter().map(|g| g.name.clone()).collect()
+    }
+
+    #[test]
+    fn const_globals()
+    {
+        assert_eq!(loaded_globals("int W = 320; int main() { return W * 2; }").len(), 0);
+        assert_eq!(loaded_globals("unsigned char C = 300; float F = 1.5f; int main() { return C + (int)F; }").len(), 0);
+        assert_eq!(loaded_globals("int Z; int main() { return Z; }").len(), 0);
+
+        // Globals which are written or have their address taken are kept
+        assert_eq!(loaded_globals("int X = 1; void f() { X = 2; } int main() { f(); return X; }"), ["X"]);
+        assert_eq!(loaded_globals("int X = 1; void f() { ++X; } int main() { f(); return X; }"), ["X"]);
+        assert_eq!(loaded_globals("int X = 1; int main() { int* p = &X; *p = 2; return X; }"), ["X"]);
+        assert_eq!(loaded_globals("int X = 1; int main() { asm () -> void { push 2; push X; store_u32; }; return X; }"), ["X"]);
+    }
+}
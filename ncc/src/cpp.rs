@@ -39,7 +39,7 @@ impl Input
         loop
         {
             if self.eof() {
-                return self.parse_error("end of input inside string");
+                return self.error_code("E0002", "end of input inside string");
             }
 
             let ch = self.eat_ch();
@@ -87,7 +87,7 @@ fn parse_def(input: &mut Input) -> Result<Def, ParseError>
             }
 
             if input.eof() {
-                return input.parse_error("eof inside #define macro parameters");
+                return input.error_code("E0002", "eof inside #define macro parameters");
             }
 
             param_vec.push(input.parse_ident()?);
@@ -116,10 +116,12 @@ fn parse_def(input: &mut Input) -> Result<Def, ParseError>
             break;
         }
 
-        // Eat single-line comments
+        // Eat single-line comments, but leave the newline
+        // so that it ends the definition
         if input.match_chars(&['/', '/']) {
-            input.eat_comment();
-            text += " ";
+            while !input.eof() && input.peek_ch() != '\n' {
+                input.eat_ch();
+            }
             continue;
         }
 
@@ -150,7 +152,7 @@ fn parse_def(input: &mut Input) -> Result<Def, ParseError>
                     '\n' => break,
                     '\r' => {},
                     ' ' => {},
-                    _ => return input.parse_error("expected newline")
+                    _ => return input.error_code("E0002", "expected newline")
                 }
             }
         }
@@ -236,7 +238,7 @@ fn process_branches(
             )?;
 
             if end_keyword != "endif" {
-                return input.parse_error("expected #endif");
+                return input.error_code("E0002", "expected #endif");
             }
         }
 
@@ -262,7 +264,7 @@ fn process_branches(
             )?;
 
             if end_keyword != "endif" {
-                return input.parse_error("expected #endif");
+                return input.error_code("E0002", "expected #endif");
             }
 
             output += &sub_output;
@@ -280,7 +282,7 @@ fn read_macro_arg(input: &mut Input, depth: usize) -> Result<String, ParseError>
     loop
     {
         if input.eof() {
-            return input.parse_error("end of input inside macro argument");
+            return input.error_code("E0002", "end of input inside macro argument");
         }
 
         let ch = input.peek_ch();
@@ -345,7 +347,7 @@ fn expand_macro(
         loop
         {
             if input.eof() {
-                return input.parse_error("unexpected end of input");
+                return input.error_code("E0002", "unexpected end of input");
             }
 
             if input.match_token(")")? {
@@ -363,7 +365,7 @@ fn expand_macro(
 
         // If the argument count doesn't match
         if args.len() != params.len() {
-            return input.parse_error(&format!(
+            return input.error_code("E0002", &format!(
                 "macro {} expected {} arguments",
                 def.name,
                 params.len()
@@ -433,7 +435,7 @@ fn expand_macro(
                     let escaped_str = bytes.escape_ascii();
                     output += &format!("\"{}\"", escaped_str);
                 } else {
-                    return input.parse_error("stray '#' character in macro text");
+                    return input.error_code("E0002", "stray '#' character in macro text");
                 }
 
                 continue;
@@ -457,7 +459,7 @@ fn expand_macro(
     )?;
 
     if end_keyword != "" {
-        return input.parse_error(&format!("unexpected #{}", end_keyword));
+        return input.error_code("E0002", &format!("unexpected #{}", end_keyword));
     }
 
     return Ok(sub_input);
@@ -500,7 +502,7 @@ pub fn process_input_with_defs(
     )?;
 
     if end_keyword != "" {
-        return input.parse_error(&format!("unexpected #{}", end_keyword));
+        return input.error_code("E0002", &format!("unexpected #{}", end_keyword));
     }
 
     Ok(output)
@@ -573,7 +575,7 @@ fn process_input_rec(
                 )?;
 
                 if end_keyword != "" {
-                    return include_input.parse_error(&format!("unexpected #{}", end_keyword));
+                    return include_input.error_code("E0002", &format!("unexpected #{}", end_keyword));
                 }
 
                 output += &include_output;
@@ -600,7 +602,7 @@ fn process_input_rec(
             }
 
             if gen_output {
-                return input.parse_error(&format!(
+                return input.error_code("E0002", &format!(
                     "unknown preprocessor directive {}", directive
                 ));
             }
@@ -737,6 +739,13 @@ mod tests
         assert_eq!(line_count("#define FOO 2\n"), 2);
         assert_eq!(line_count("#define FOO 2\nFOO"), 2);
         assert_eq!(line_count("#define FOO 2\nFOO\n"), 3);
+        assert_eq!(line_count("#define FOO 2 // foo\n#define BAR 3 // bar\nFOO BAR\n"), 4);
+    }
+
+    #[test]
+    fn comments()
+    {
+        assert_eq!(process("#define FOO 2 // foo\n#define BAR 3\nFOO BAR").trim(), "2 3");
     }
 
     #[test]
@@ -0,0 +1,587 @@
+//! Stable codes for the errors reported by ncc
+//!
+//! Each error carries a code which doesn't change when the wording of
+//! its message does, so that it can be looked up. `ncc --explain E0012`
+//! prints the long-form description of a code, with an example of code
+//! which produces the error and a way to fix it. Syntax errors found
+//! while parsing use E0001 unless they have a more specific code.
+//! Codes are never reused: when an error goes away, its code stays
+//! reserved.
+
+pub struct ErrorCode
+{
+    pub code: &'static str,
+
+    /// One-line summary of the error
+    pub title: &'static str,
+
+    /// Description of the error, with an erroneous example and a fix
+    pub explanation: &'static str,
+}
+
+/// Code for errors found by the parser without a more specific code
+pub const SYNTAX_ERROR: &str = "E0001";
+
+pub const ERROR_CODES: &[ErrorCode] = &[
+    ErrorCode {
+        code: "E0001",
+        title: "syntax error",
+        explanation: r#"The source code doesn't follow the grammar that ncc accepts, for
+example because a token is missing, or because input ends inside a
+comment, string or block.
+
+Erroneous example:
+
+    int x = 3
+
+Fix, add the missing semicolon:
+
+    int x = 3;
+"#,
+    },
+    ErrorCode {
+        code: "E0002",
+        title: "preprocessor error",
+        explanation: r#"A preprocessor directive is malformed or unknown, a conditional block
+is missing its #endif, or a macro is used with the wrong number of
+arguments.
+
+Erroneous example:
+
+    #define MAX(a, b) ((a) > (b)? (a):(b))
+    int x = MAX(1, 2, 3);
+
+Fix, pass as many arguments as the macro has parameters:
+
+    int x = MAX(1, 2);
+"#,
+    },
+    ErrorCode {
+        code: "E0003",
+        title: "could not read file",
+        explanation: r#"An input file given on the command line, or a profile given with
+--profile-use, doesn't exist or can't be read.
+
+Erroneous example:
+
+    ncc exmaples/fib.c
+
+Fix, check the path and the permissions of the file:
+
+    ncc examples/fib.c
+"#,
+    },
+    ErrorCode {
+        code: "E0004",
+        title: "invalid profile",
+        explanation: r#"The profile passed with --profile-use has a line which isn't in the
+format written by `uvm --profile`. Profiles have one line per function,
+with the function name, the number of calls and the number of loop
+iterations.
+
+Fix, regenerate the profile by running the program again with
+`uvm --profile <file>`, without editing it by hand.
+"#,
+    },
+    ErrorCode {
+        code: "E0005",
+        title: "double literal",
+        explanation: r#"The double type isn't supported, so floating-point literals need the
+f suffix, which makes them float constants.
+
+Erroneous example:
+
+    float x = 3.5;
+
+Fix:
+
+    float x = 3.5f;
+"#,
+    },
+    ErrorCode {
+        code: "E0006",
+        title: "unknown type",
+        explanation: r#"A type name is used which isn't a built-in type or a typedef declared
+in the source.
+
+Erroneous example:
+
+    Vec3 pos;
+
+Fix, declare the typedef, or include the header which declares it:
+
+    typedef struct { float x; float y; float z; } Vec3;
+    Vec3 pos;
+"#,
+    },
+    ErrorCode {
+        code: "E0007",
+        title: "recursive typedef",
+        explanation: r#"A typedef contains an instance of itself, which would make the type
+infinitely large. A struct can only refer to its own type through a
+pointer.
+
+Erroneous example:
+
+    typedef struct { int val; node_t next; } node_t;
+
+Fix:
+
+    typedef struct { int val; node_t* next; } node_t;
+"#,
+    },
+    ErrorCode {
+        code: "E0008",
+        title: "undeclared identifier",
+        explanation: r#"A name is used which isn't a local variable, a function parameter, a
+global variable or a function declared earlier in the source.
+
+Erroneous example:
+
+    int main() { x = 1; return 0; }
+
+Fix, declare the variable first:
+
+    int main() { int x = 1; return 0; }
+"#,
+    },
+    ErrorCode {
+        code: "E0009",
+        title: "incompatible types in assignment",
+        explanation: r#"The value assigned to a variable has a type which doesn't convert
+implicitly to the type of the variable. Integers other than the null
+pointer constants don't convert implicitly to pointers, and pointers
+only convert implicitly to and from void*.
+
+Erroneous example:
+
+    u8* p = 0x1000;
+
+Fix, cast the value to the type of the variable:
+
+    u8* p = (u8*)0x1000;
+"#,
+    },
+    ErrorCode {
+        code: "E0010",
+        title: "incompatible argument type",
+        explanation: r#"An argument passed to a function has a type which doesn't convert
+implicitly to the type of the corresponding parameter. The same
+conversions as for assignments are allowed.
+
+Erroneous example:
+
+    void fill(u8* dst, u64 n);
+    fill(4096, 10);
+
+Fix, pass a value of the parameter type, or cast it:
+
+    fill((u8*)4096, 10);
+"#,
+    },
+    ErrorCode {
+        code: "E0011",
+        title: "wrong number of arguments",
+        explanation: r#"A function is called with fewer or more arguments than it has
+parameters, or with fewer arguments than the fixed parameters of a
+variadic function.
+
+Erroneous example:
+
+    int add(int a, int b);
+    int x = add(1);
+
+Fix:
+
+    int x = add(1, 2);
+"#,
+    },
+    ErrorCode {
+        code: "E0012",
+        title: "invalid cast",
+        explanation: r#"A value is cast to a type it can't be converted to. Integers, floats
+and pointers can be cast to each other, but structs and arrays can't
+be cast, and neither can values be cast to them.
+
+Erroneous example:
+
+    typedef struct { int x; int y; } Point;
+    Point p;
+    u64 v = (u64)p;
+
+Fix, cast the address of the struct, or convert one of its fields:
+
+    u64 v = (u64)&p;
+    u64 x = (u64)p.x;
+"#,
+    },
+    ErrorCode {
+        code: "E0013",
+        title: "comparison needs a cast",
+        explanation: r#"Two values are compared which don't have compatible types, such as a
+pointer and an integer other than a null pointer constant, or pointers
+to different types where neither is void*.
+
+Erroneous example:
+
+    int* p = malloc(8);
+    if (p == 0x1000) {}
+
+Fix, cast one side to the type of the other, or compare with null:
+
+    if (p == (int*)0x1000) {}
+    if (p == null) {}
+"#,
+    },
+    ErrorCode {
+        code: "E0014",
+        title: "incompatible operand types",
+        explanation: r#"The operands of an arithmetic, bitwise or ternary operator have types
+which the operator can't be applied to together. For example, pointers
+can be offset by an integer but not added together, bitwise operators
+don't apply to floats, and the two branches of a ternary expression
+need the same type.
+
+Erroneous example:
+
+    float f = 1.5f;
+    int x = 3 | f;
+
+Fix, convert the operands to a common type:
+
+    int x = 3 | (int)f;
+"#,
+    },
+    ErrorCode {
+        code: "E0015",
+        title: "incompatible return type",
+        explanation: r#"A return statement returns a value which doesn't convert to the return
+type of the function, or returns nothing from a function which isn't
+void.
+
+Erroneous example:
+
+    int* get() { return 5; }
+
+Fix, return a value of the right type:
+
+    int* get() { return (int*)5; }
+"#,
+    },
+    ErrorCode {
+        code: "E0016",
+        title: "missing return",
+        explanation: r#"A function which doesn't return void can reach the end of its body
+without a return statement, in which case its return value would be
+undefined. Only main is allowed to reach the end of its body.
+
+Erroneous example:
+
+    int sign(int x) { if (x < 0) return -1; if (x > 0) return 1; }
+
+Fix, return a value on every path through the function:
+
+    int sign(int x) { if (x < 0) return -1; if (x > 0) return 1; return 0; }
+"#,
+    },
+    ErrorCode {
+        code: "E0017",
+        title: "unknown struct field",
+        explanation: r#"A field is accessed which isn't in the struct type of the value.
+
+Erroneous example:
+
+    typedef struct { int x; int y; } Point;
+    Point p;
+    p.z = 1;
+
+Fix, use one of the fields of the struct, or add the field to it:
+
+    p.y = 1;
+"#,
+    },
+    ErrorCode {
+        code: "E0018",
+        title: "arrow operator on a value which isn't a struct pointer",
+        explanation: r#"The arrow operator a->b accesses a field through a pointer to a struct.
+It can't be used on a struct itself, or on other types.
+
+Erroneous example:
+
+    Point p;
+    p->x = 1;
+
+Fix, use the dot operator on structs:
+
+    p.x = 1;
+"#,
+    },
+    ErrorCode {
+        code: "E0019",
+        title: "invalid global initializer",
+        explanation: r#"Global variables are initialized when the program is assembled, so
+their initializer must be a constant. Pointers can only be initialized
+with a null pointer constant, and global arrays with a string or a
+list of constants of the same type. Initializer lists aren't supported
+for global structs yet.
+
+Erroneous example:
+
+    int* p = 4;
+    int a[2] = { 1, x };
+
+Fix, use constants, or assign the variable in main:
+
+    int* p = null;
+    int a[2] = { 1, 0 };
+"#,
+    },
+    ErrorCode {
+        code: "E0020",
+        title: "invalid local initializer",
+        explanation: r#"A local variable has an initializer which doesn't match its type.
+Local arrays can only be initialized with an initializer list, scalar
+values can't be initialized with a list, and a list can't have more
+elements than the array or more values than the struct has fields.
+Array literals outside of initializers aren't supported.
+
+Erroneous example:
+
+    int a[2] = { 1, 2, 3 };
+
+Fix, make the array large enough, or remove the extra values:
+
+    int a[3] = { 1, 2, 3 };
+"#,
+    },
+    ErrorCode {
+        code: "E0021",
+        title: "invalid switch value",
+        explanation: r#"The value tested by a switch statement must be an integer.
+
+Erroneous example:
+
+    float f = 1.0f;
+    switch (f) { case 1: break; }
+
+Fix, convert the value to an integer, or use if statements:
+
+    switch ((int)f) { case 1: break; }
+"#,
+    },
+    ErrorCode {
+        code: "E0022",
+        title: "duplicate case label",
+        explanation: r#"A switch statement has two case labels with the same value, or more
+than one default label, so it isn't known where the switch jumps to.
+
+Erroneous example:
+
+    switch (x) { case 1: return 1; case 1: return 2; }
+
+Fix, remove one of the labels, or merge the code of both cases:
+
+    switch (x) { case 1: return 1; case 2: return 2; }
+"#,
+    },
+    ErrorCode {
+        code: "E0023",
+        title: "invalid case label",
+        explanation: r#"Case labels must be integer constants, and must be directly in the body
+of their switch statement, not inside a nested block or loop.
+
+Erroneous example:
+
+    switch (x) { case 0: { case 1: break; } }
+
+Fix, move the label to the body of the switch:
+
+    switch (x) { case 0: case 1: break; }
+"#,
+    },
+    ErrorCode {
+        code: "E0024",
+        title: "break or continue outside of a loop",
+        explanation: r#"A break statement can only be used in a loop or a switch statement,
+and a continue statement only in a loop.
+
+Erroneous example:
+
+    int main() { continue; }
+
+Fix, remove the statement, or move it into a loop:
+
+    int main() { for (;;) { continue; } }
+"#,
+    },
+    ErrorCode {
+        code: "E0025",
+        title: "unsupported address-of",
+        explanation: r#"Pointers to variables which aren't structs or arrays aren't supported,
+so the address-of operator can only be applied to structs and array elements.
+
+Erroneous example:
+
+    int x = 1;
+    int* p = &x;
+
+Fix, use an array with a single element:
+
+    int x[1] = { 1 };
+    int* p = &x[0];
+"#,
+    },
+    ErrorCode {
+        code: "E0026",
+        title: "called value is not a function",
+        explanation: r#"Only functions can be called by name. Calls through function pointers,
+such as a void* holding a callback, aren't supported by the call syntax
+yet. The call_fp instruction can be used in an asm expression instead,
+with the function pointer after the arguments.
+
+Erroneous example:
+
+    typedef struct { int x; void* cb; } Button;
+    Button b;
+    b.cb(5);
+
+Fix:
+
+    asm (5, b.cb) -> void { call_fp 1; };
+"#,
+    },
+    ErrorCode {
+        code: "E0027",
+        title: "operation not supported for this type",
+        explanation: r#"A value is loaded, stored, negated or complemented, but its type can't
+be used with that operation, for example because it is larger than 64
+bits and not a struct or array.
+
+Erroneous example:
+
+    float f = 1.0f;
+    float g = ~f;
+
+Fix, use an operation which applies to the type:
+
+    float g = -f;
+"#,
+    },
+    ErrorCode {
+        code: "E0028",
+        title: "invalid assignment target",
+        explanation: r#"The left-hand side of an assignment isn't a variable, a dereferenced
+pointer, an array element or a struct field.
+
+Erroneous example:
+
+    int x;
+    x + 1 = 2;
+
+Fix:
+
+    x = 2 - 1;
+"#,
+    },
+    ErrorCode {
+        code: "E0029",
+        title: "thread-local function",
+        explanation: r#"Only variables can be declared _Thread_local, since each thread gets
+its own copy of a thread-local variable. Functions are shared by all
+threads.
+
+Erroneous example:
+
+    _Thread_local int count() { return 1; }
+
+Fix, remove the qualifier from the function:
+
+    int count() { return 1; }
+"#,
+    },
+];
+
+/// Find the code of an error, ignoring case
+pub fn find_code(code: &str) -> Option<&'static ErrorCode>
+{
+    ERROR_CODES.iter().find(|e| e.code.eq_ignore_ascii_case(code))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::collections::HashSet;
+    use crate::parsing::{Input, ParseError};
+    use crate::parser::parse_unit;
+
+    /// Run the passes of the compiler up to lowering and
+    /// get the code of the error, if there is one
+    fn error_code(src: &str) -> Option<&'static str>
+    {
+        let compile = || -> Result<(), ParseError> {
+            let mut input = Input::new(src, "src");
+            let mut unit = parse_unit(&mut input)?;
+            unit.resolve_types()?;
+            unit.resolve_syms()?;
+            unit.check_types()?;
+            unit.check_returns()?;
+            unit.insert_casts()?;
+            unit.lower()?;
+            Ok(())
+        };
+
+        compile().err().map(|err| err.code)
+    }
+
+    #[test]
+    fn codes_unique()
+    {
+        let mut codes = HashSet::new();
+
+        for (idx, e) in ERROR_CODES.iter().enumerate() {
+            assert!(codes.insert(e.code), "duplicate code {}", e.code);
+
+            // Codes are numbered in order, without gaps
+            assert_eq!(e.code, format!("E{:04}", idx + 1));
+            assert!(!e.title.is_empty() && e.explanation.ends_with('\n'));
+        }
+
+        assert!(find_code("e0012").unwrap().title == "invalid cast");
+        assert!(find_code("E9999").is_none());
+    }
+
+    #[test]
+    fn error_codes()
+    {
+        assert_eq!(error_code("int main() { return 0; }"), None);
+        assert_eq!(error_code("int x = 3"), Some(SYNTAX_ERROR));
+        assert_eq!(error_code("float x = 3.5;"), Some("E0005"));
+        assert_eq!(error_code("Vec3 pos;"), Some("E0006"));
+        assert_eq!(error_code("int main() { x = 1; return 0; }"), Some("E0008"));
+        assert_eq!(error_code("void main() { u8* p = 0x1000; }"), Some("E0009"));
+        assert_eq!(error_code("int main() { int* p = null; return p == 0x1000; }"), Some("E0013"));
+        assert_eq!(error_code("int f(int x) { if (x) return 1; }"), Some("E0016"));
+        assert_eq!(error_code("void f(int x) { switch (x) { case 1: case 1: break; } }"), Some("E0022"));
+        assert_eq!(error_code("void f() { int x = 1; int* p = &x; }"), Some("E0025"));
+        assert_eq!(error_code("_Thread_local int f() { return 1; }"), Some("E0029"));
+    }
+
+    /// Every code used in the sources of the compiler has an explanation
+    #[test]
+    fn codes_explained()
+    {
+        for entry in std::fs::read_dir("src").unwrap() {
+            let path = entry.unwrap().path();
+            if path.ends_with("errors.rs") {
+                continue;
+            }
+
+            let src = std::fs::read_to_string(&path).unwrap();
+            for (idx, _) in src.match_indices("\"E0") {
+                let code = &src[idx + 1..idx + 6];
+                assert!(find_code(code).is_some(), "unknown error code {} in {}", code, path.display());
+            }
+        }
+    }
+}
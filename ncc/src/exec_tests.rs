@@ -5,7 +5,7 @@ use std::io::{self, Write};
 use std::process::Command;
 use std::collections::HashSet;
 
-fn compile_and_run(file_path: &str, run_example: bool)
+fn compile_and_run(file_path: &str, run_example: bool, optimize: bool)
 {
     if run_example {
         io::stdout().write(format!("compiling and running: {}\n", file_path).as_bytes()).unwrap();
@@ -18,6 +18,8 @@ fn compile_and_run(file_path: &str, run_example: bool)
     let mut command = Command::new("target/debug/ncc");
     command.current_dir(".");
     command.arg("-DTEST");
+    command.args(["--cache-dir", "target/ncc_cache"]);
+    if optimize { command.arg("-O"); }
     command.arg(file_path);
     println!("{:?}", command);
     let output = command.output().unwrap();
@@ -74,15 +76,50 @@ fn exec_tests()
         if file_name.ends_with(".c") {
             let run_example = run_examples.get(file_name).is_some();
             let file_path = file_path.display().to_string();
-            compile_and_run(&file_path, run_example);
+            compile_and_run(&file_path, run_example, false);
         }
     }
 
-    // Compile all the tests and run them
+    // Compile all the tests and run them, with and without optimizations
     for file in fs::read_dir("./tests").unwrap() {
         let file_path = file.unwrap().path().display().to_string();
         if file_path.ends_with(".c") {
-            compile_and_run(&file_path, true);
+            compile_and_run(&file_path, true, false);
+            compile_and_run(&file_path, true, true);
         }
     }
+
+    // Profile a program, then recompile it using the profile
+    let mut command = Command::new("target/debug/ncc");
+    command.args(["-DTEST", "examples/crc32.c"]);
+    assert!(command.output().unwrap().status.success(), "compilation failed");
+
+    let mut command = Command::new("target/debug/uvm");
+    command.current_dir("../vm");
+    command.args(["--profile", "../ncc/out.profile", "../ncc/out.asm"]);
+    assert!(command.output().unwrap().status.success(), "execution failed");
+
+    let mut command = Command::new("target/debug/ncc");
+    command.args(["-DTEST", "--profile-use", "out.profile", "examples/crc32.c"]);
+    assert!(command.output().unwrap().status.success(), "compilation failed");
+    fs::remove_file("out.profile").unwrap();
+
+    let mut command = Command::new("target/debug/uvm");
+    command.current_dir("../vm");
+    command.arg("../ncc/out.asm");
+    assert!(command.output().unwrap().status.success(), "execution failed");
+
+    // Measure the coverage of a program compiled with source lines
+    let mut command = Command::new("target/debug/ncc");
+    command.args(["-DTEST", "-g", "examples/crc32.c"]);
+    assert!(command.output().unwrap().status.success(), "compilation failed");
+
+    let mut command = Command::new("target/debug/uvm");
+    command.current_dir("../vm");
+    command.args(["--coverage", "../ncc/out.info", "../ncc/out.asm"]);
+    assert!(command.output().unwrap().status.success(), "execution failed");
+
+    let report = fs::read_to_string("out.info").unwrap();
+    assert!(report.contains("SF:examples/crc32.c\n"), "{}", report);
+    fs::remove_file("out.info").unwrap();
 }
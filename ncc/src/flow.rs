@@ -0,0 +1,218 @@
+use crate::ast::*;
+use crate::parsing::ParseError;
+
+impl Unit
+{
+    /// Check that non-void functions return a value on every path,
+    /// instead of silently returning 0 when they reach the end.
+    /// The main function is exempt, since it implicitly returns 0.
+    /// Returns warnings about unreachable statements.
+    pub fn check_returns(&self) -> Result<Vec<String>, ParseError>
+    {
+        let mut warnings = Vec::default();
+
+        for fun in &self.fun_decls {
+            fun.body.find_unreachable(&fun.name, &mut warnings);
+
+            let is_void = matches!(fun.ret_type, Type::Void);
+            if !is_void && fun.name != "main" && fun.body.falls_through() {
+                return ParseError::msg_only("E0016", &format!(
+                    "non-void function {} can reach the end without returning a value",
+                    fun.name
+                ));
+            }
+        }
+
+        Ok(warnings)
+    }
+}
+
+impl Stmt
+{
+    /// Check if execution can continue past the end of this statement
+    pub fn falls_through(&self) -> bool
+    {
+        match self {
+            Stmt::Expr(expr) => !expr.is_trap(),
+            Stmt::VarDecl { .. } | Stmt::Line { .. } => true,
+            Stmt::Case(_) | Stmt::Default => true,
+
+            Stmt::ReturnExpr(_) | Stmt::ReturnVoid => false,
+            Stmt::Break | Stmt::Continue => false,
+
+            Stmt::Block(stmts) => end_reachable(stmts),
+
+            Stmt::If { then_stmt, else_stmt, .. } => {
+                match else_stmt {
+                    Some(else_stmt) => then_stmt.falls_through() || else_stmt.falls_through(),
+                    None => true,
+                }
+            }
+
+            // Without a default label, no case may match
+            Stmt::Switch { body_stmt, .. } => {
+                let stmts = match body_stmt.as_ref() {
+                    Stmt::Block(stmts) => stmts.as_slice(),
+                    stmt => std::slice::from_ref(stmt),
+                };
+                let has_default = stmts.iter().any(|stmt| matches!(stmt, Stmt::Default));
+                !has_default || end_reachable(stmts) || body_stmt.has_break()
+            }
+
+            // Loops with a constant true condition only exit through a break
+            Stmt::While { test_expr, body_stmt } |
+            Stmt::For { test_expr, body_stmt, .. } => {
+                !test_expr.is_const_true() || body_stmt.has_break()
+            }
+
+            // The test of a do-while loop is only reached once the
+            // body falls through or continues
+            Stmt::DoWhile { test_expr, body_stmt } => {
+                let test_reached = body_stmt.falls_through() || body_stmt.has_continue();
+                (test_reached && !test_expr.is_const_true()) || body_stmt.has_break()
+            }
+        }
+    }
+
+    /// Check if this statement contains a break out of the enclosing loop
+    fn has_break(&self) -> bool
+    {
+        match self {
+            Stmt::Break => true,
+            Stmt::Block(stmts) => stmts.iter().any(|stmt| stmt.has_break()),
+
+            Stmt::If { then_stmt, else_stmt, .. } => {
+                then_stmt.has_break() || else_stmt.as_ref().is_some_and(|s| s.has_break())
+            }
+
+            // Breaks inside nested loops exit those loops
+            _ => false,
+        }
+    }
+
+    /// Check if this statement contains a continue of the enclosing loop
+    fn has_continue(&self) -> bool
+    {
+        match self {
+            Stmt::Continue => true,
+            Stmt::Block(stmts) => stmts.iter().any(|stmt| stmt.has_continue()),
+
+            Stmt::If { then_stmt, else_stmt, .. } => {
+                then_stmt.has_continue() || else_stmt.as_ref().is_some_and(|s| s.has_continue())
+            }
+
+            // Switches don't catch continues, unlike breaks
+            Stmt::Switch { body_stmt, .. } => body_stmt.has_continue(),
+
+            // Continues inside nested loops continue those loops
+            _ => false,
+        }
+    }
+
+    /// Warn about statements that follow a statement which never falls
+    /// through, other than the statements after a case label
+    fn find_unreachable(&self, fun_name: &str, warnings: &mut Vec<String>)
+    {
+        self.each_stmt(&mut |stmt| {
+            if let Stmt::Block(stmts) = stmt {
+                let mut reachable = true;
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Case(_) | Stmt::Default => reachable = true,
+                        Stmt::Line { .. } => {}
+                        _ if !reachable => {
+                            warnings.push(format!("unreachable code in function {}", fun_name));
+                            break;
+                        }
+                        _ => reachable = stmt.falls_through(),
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Check if execution can reach the end of a sequence of statements.
+/// Statements after one which never falls through can still be
+/// reached through a case label.
+fn end_reachable(stmts: &[Stmt]) -> bool
+{
+    stmts.iter().fold(true, |reachable, stmt| match stmt {
+        Stmt::Case(_) | Stmt::Default => true,
+        _ => reachable && stmt.falls_through(),
+    })
+}
+
+impl Expr
+{
+    /// Check if this is the trap intrinsic, which never returns
+    fn is_trap(&self) -> bool
+    {
+        matches!(self, Expr::Asm { text, .. } if text == "trap;")
+    }
+
+    /// Check if this is a constant that is always true
+    fn is_const_true(&self) -> bool
+    {
+        matches!(self, Expr::Int(n) if *n != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::parsing::Input;
+    use crate::parser::parse_unit;
+
+    fn check(src: &str) -> Result<Vec<String>, String>
+    {
+        let mut input = Input::new(src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        unit.resolve_types().unwrap();
+        unit.resolve_syms().unwrap();
+        unit.check_returns().map_err(|err| err.msg)
+    }
+
+    #[test]
+    fn missing_return()
+    {
+        assert!(check("void foo() {}").is_ok());
+        assert!(check("int foo() { return 1; }").is_ok());
+        assert!(check("int main() {}").is_ok());
+
+        assert!(check("int foo() {}").is_err());
+        assert!(check("int foo(int x) { if (x) return 1; }").is_err());
+        assert!(check("int foo(int x) { while (x) { return 1; } }").is_err());
+        assert!(check("int foo() { while (true) { break; } }").is_err());
+        assert!(check("int foo(int x) { do { if (x) continue; return 1; } while (x); }").is_err());
+        assert!(check("int foo(int x) { do { switch (x) { case 1: continue; } return 1; } while (x); }").is_err());
+        assert!(check("int foo() { do { break; } while (true); }").is_err());
+
+        assert!(check("int foo(int x) { if (x) return 1; else return 2; }").is_ok());
+        assert!(check("int foo(int x) { if (x) { return 1; } return 2; }").is_ok());
+        assert!(check("int foo() { while (true) {} }").is_ok());
+        assert!(check("int foo(int x) { do { return 1; } while (x); }").is_ok());
+        assert!(check("int foo(int x) { do { if (x) continue; return 1; } while (true); }").is_ok());
+        assert!(check("int foo() { do {} while (true); }").is_ok());
+        assert!(check("int foo() { for (;;) { while (true) { break; } } }").is_ok());
+        assert!(check("int foo() { __builtin_trap(); }").is_ok());
+
+        // Switches without a default label may not match any case
+        assert!(check("int foo(int x) { switch (x) { case 1: return 1; default: return 2; } }").is_ok());
+        assert!(check("int foo(int x) { switch (x) { case 1: return 1; } }").is_err());
+        assert!(check("int foo(int x) { switch (x) { case 1: break; default: return 2; } }").is_err());
+        assert!(check("int foo(int x) { switch (x) { case 1: return 1; default: x = 2; } }").is_err());
+        assert!(check("int foo(int x) { while (true) { switch (x) { case 1: break; } } }").is_ok());
+    }
+
+    #[test]
+    fn unreachable_code()
+    {
+        assert_eq!(check("void foo() { return; }").unwrap().len(), 0);
+        assert_eq!(check("void foo() { return; foo(); }").unwrap().len(), 1);
+        assert_eq!(check("int foo(int x) { if (x) { return 1; x = 2; } return 0; }").unwrap().len(), 1);
+        assert_eq!(check("void foo() { while (true) { break; foo(); } }").unwrap().len(), 1);
+        assert_eq!(check("void foo(int x) { switch (x) { case 1: return; case 2: return; } }").unwrap().len(), 0);
+        assert_eq!(check("void foo(int x) { switch (x) { case 1: return; foo(x); } }").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+//! Fully-typed intermediate representation, produced by lowering the AST
+//! once symbols are resolved and types are checked.
+//!
+//! The IR upholds invariants that codegen relies on: there are no
+//! unresolved identifiers, every expression carries its type, sizeof is
+//! folded into constants, conversions are explicit casts that are known to
+//! be supported, and assignments target a valid location. Anything the
+//! compiler can't handle is reported as an error while lowering.
+
+use crate::ast::{Type, BinOp};
+
+/// Typed expression
+#[derive(Clone, Debug)]
+pub struct Expr
+{
+    pub t: Type,
+    pub kind: ExprKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind
+{
+    Int(i128),
+    Float32(f32),
+
+    /// Value of a function argument or local variable slot
+    Arg(usize),
+    Local(usize),
+
+    /// Value loaded from a scalar global variable
+    Global(String),
+
+    /// Address of a global symbol. Functions and global arrays and
+    /// structs evaluate to their address rather than being loaded.
+    Addr(String),
+
+    /// Conversion of the child value into the type of this expression
+    Cast(Box<Expr>),
+
+    /// Load of a value of the given size from an address
+    Load {
+        addr: Box<Expr>,
+        num_bits: usize,
+    },
+
+    /// Struct field at an offset from the struct address, which is loaded
+    /// if it is a scalar, and otherwise evaluates to its address
+    Field {
+        base: Box<Expr>,
+        offset: usize,
+        num_bits: usize,
+    },
+
+    Neg(Box<Expr>),
+    BitNot(Box<Expr>),
+    Not(Box<Expr>),
+
+    /// Binary operator other than assignment
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+
+    Assign {
+        dst: LValue,
+        value: Box<Expr>,
+    },
+
+    Ternary {
+        test_expr: Box<Expr>,
+        then_expr: Box<Expr>,
+        else_expr: Box<Expr>,
+    },
+
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+
+    Asm {
+        text: String,
+        args: Vec<Expr>,
+    },
+}
+
+/// Location that can be assigned to
+#[derive(Clone, Debug)]
+pub enum LValue
+{
+    Arg(usize),
+    Local(usize),
+
+    Global {
+        name: String,
+        num_bits: usize,
+    },
+
+    /// Value of the given size stored at an address
+    Mem {
+        addr: Box<Expr>,
+        num_bits: usize,
+    },
+
+    /// Struct field at an offset from the struct address
+    Field {
+        base: Box<Expr>,
+        offset: usize,
+        num_bits: usize,
+    },
+
+    /// Struct at an address, which is assigned by copying
+    /// the struct at the address the value evaluates to
+    Copy {
+        addr: Box<Expr>,
+        num_bytes: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt
+{
+    Expr(Expr),
+
+    Return(Option<Expr>),
+
+    /// Break only appears inside of loops and switches,
+    /// and continue inside of loops
+    Break,
+    Continue,
+
+    Block(Vec<Stmt>),
+
+    If {
+        test_expr: Expr,
+        then_stmt: Box<Stmt>,
+        else_stmt: Option<Box<Stmt>>,
+    },
+
+    While {
+        test_expr: Expr,
+        body_stmt: Box<Stmt>,
+    },
+
+    DoWhile {
+        body_stmt: Box<Stmt>,
+        test_expr: Expr,
+    },
+
+    For {
+        init_stmt: Option<Box<Stmt>>,
+        test_expr: Expr,
+        incr_expr: Expr,
+        body_stmt: Box<Stmt>,
+    },
+
+    /// Switch with the value of each case label, converted to the type
+    /// of the test expression, and the index of the statement of the
+    /// body it is on. Labels at the end of the body have its length.
+    Switch {
+        test_expr: Expr,
+        cases: Vec<(i128, usize)>,
+        default: Option<usize>,
+        body: Vec<Stmt>,
+    },
+
+    /// Source line of the statements that follow, with -g
+    Line {
+        src_name: String,
+        line_no: u32,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Function
+{
+    pub name: String,
+    pub ret_type: Type,
+    pub params: Vec<(Type, String)>,
+    pub body: Stmt,
+
+    /// Number of local variable slots
+    pub num_locals: usize,
+
+    /// Number of bytes to stack allocate on entry, and the local
+    /// holding the stack allocation base pointer, if any
+    pub stack_alloc_size: usize,
+    pub stack_alloc_bp: Option<usize>,
+
+    /// The end of the body can be reached, so a final return is needed
+    pub needs_final_return: bool,
+
+    /// Executed often according to the profile,
+    /// so loops are laid out for speed
+    pub hot: bool,
+}
+
+/// Initial value of global data
+#[derive(Clone, Debug)]
+pub enum Data
+{
+    Zero(usize),
+    UInt { num_bits: usize, val: i128 },
+    Int { num_bits: usize, val: i128 },
+    Float32(f32),
+
+    /// Absolute address of a global symbol
+    Addr64(String),
+
+    /// Null-terminated string
+    Stringz(String),
+
+    Seq(Vec<Data>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Global
+{
+    pub name: String,
+
+    /// Alignment of the variable in bytes
+    pub align: usize,
+    pub init: Data,
+
+    /// String constant stored in the assembler's string table
+    pub interned: bool,
+
+    /// Variable with a separate instance for each thread
+    pub thread_local: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Unit
+{
+    pub global_vars: Vec<Global>,
+    pub fun_decls: Vec<Function>,
+
+    /// Some functions in this unit use stack allocation
+    pub stack_alloc: bool,
+}
@@ -0,0 +1,584 @@
+use crate::ast::*;
+use crate::ir;
+use crate::parsing::ParseError;
+use crate::types::*;
+use Type::*;
+
+impl Unit
+{
+    /// Lower the AST into the typed IR used by codegen. This must run
+    /// after symbols are resolved and casts are inserted.
+    pub fn lower(&self) -> Result<ir::Unit, ParseError>
+    {
+        let mut global_vars = Vec::default();
+        for global in &self.global_vars {
+            global_vars.push(global.lower()?);
+        }
+
+        let mut fun_decls = Vec::default();
+        for fun in &self.fun_decls {
+            fun_decls.push(fun.lower()?);
+        }
+
+        Ok(ir::Unit {
+            global_vars,
+            fun_decls,
+            stack_alloc: self.stack_alloc,
+        })
+    }
+}
+
+impl Global
+{
+    fn lower(&self) -> Result<ir::Global, ParseError>
+    {
+        // String constants go into the string table
+        if let (true, Some(Expr::String(s))) = (self.interned, &self.init_expr) {
+            return Ok(ir::Global {
+                name: self.name.clone(),
+                align: 1,
+                init: ir::Data::Stringz(s.clone()),
+                interned: true,
+                thread_local: self.thread_local,
+            });
+        }
+
+        Ok(ir::Global {
+            name: self.name.clone(),
+            align: self.var_type.align_bytes(),
+            init: lower_global_init(&self.var_type, &self.init_expr)?,
+            interned: false,
+            thread_local: self.thread_local,
+        })
+    }
+}
+
+/// Produce the initial data of a global variable
+fn lower_global_init(t: &Type, init_expr: &Option<Expr>) -> Result<ir::Data, ParseError>
+{
+    let init_expr = match init_expr {
+        Some(init_expr) => init_expr,
+        None => return Ok(ir::Data::Zero(t.sizeof()))
+    };
+
+    match (t, init_expr) {
+        (UInt(n), Expr::Int(v)) => Ok(ir::Data::UInt { num_bits: *n, val: *v }),
+        (Int(n), Expr::Int(v)) => Ok(ir::Data::Int { num_bits: *n, val: *v }),
+        (Float(32), Expr::Float32(v)) => Ok(ir::Data::Float32(*v)),
+        (Pointer(_), Expr::Int(0)) => Ok(ir::Data::UInt { num_bits: 64, val: 0 }),
+        (Pointer(_), Expr::Int(_)) => ParseError::msg_only("E0019", "pointer global variable initialized with an integer other than 0 or null"),
+
+        // Pointer to a global array
+        (Pointer(_), Expr::Ref(Decl::Global { name, t: Array { .. } })) => {
+            Ok(ir::Data::Addr64(name.clone()))
+        }
+
+        // Global string constant
+        (Array { elem_type, size_expr }, Expr::String(s)) => {
+            match (elem_type.as_ref(), size_expr.as_ref()) {
+                (UInt(8), Expr::Int(n)) if *n as usize == s.len() + 1 => {
+                    Ok(ir::Data::Stringz(s.clone()))
+                }
+                _ => ParseError::msg_only("E0019", "invalid string initializer for global array variable")
+            }
+        }
+
+        // Global array with initializer expression
+        (Array { elem_type, .. }, Expr::Array(elem_exprs)) => {
+            let mut elems = Vec::default();
+            for expr in elem_exprs {
+                elems.push(lower_global_init(elem_type, &Some(expr.clone()))?);
+            }
+            Ok(ir::Data::Seq(elems))
+        }
+
+        (Array { .. }, _) => {
+            ParseError::msg_only("E0019", "invalid initializer for global array variable")
+        }
+
+        _ => ParseError::msg_only("E0019", &format!("unsupported initializer for global variable of type {}", t))
+    }
+}
+
+impl Function
+{
+    fn lower(&self) -> Result<ir::Function, ParseError>
+    {
+        Ok(ir::Function {
+            name: self.name.clone(),
+            ret_type: self.ret_type.clone(),
+            params: self.params.clone(),
+            body: self.body.lower(false, false)?,
+            num_locals: self.num_locals,
+            stack_alloc_size: self.stack_alloc_size,
+            stack_alloc_bp: self.stack_alloc_bp,
+            needs_final_return: self.body.falls_through(),
+            hot: false,
+        })
+    }
+}
+
+impl Stmt
+{
+    fn lower(&self, in_loop: bool, in_switch: bool) -> Result<ir::Stmt, ParseError>
+    {
+        let stmt = match self {
+            Stmt::Expr(expr) => ir::Stmt::Expr(expr.lower()?),
+
+            Stmt::Line { src_name, line_no } => ir::Stmt::Line { src_name: src_name.clone(), line_no: *line_no },
+
+            Stmt::Break => {
+                if !in_loop && !in_switch {
+                    return ParseError::msg_only("E0024", "break outside of loop or switch context");
+                }
+                ir::Stmt::Break
+            }
+
+            Stmt::Continue => {
+                if !in_loop {
+                    return ParseError::msg_only("E0024", "continue outside of loop context");
+                }
+                ir::Stmt::Continue
+            }
+
+            Stmt::ReturnVoid => ir::Stmt::Return(None),
+            Stmt::ReturnExpr(expr) => ir::Stmt::Return(Some(expr.lower()?)),
+
+            Stmt::Block(stmts) => {
+                let mut ir_stmts = Vec::default();
+                for stmt in stmts {
+                    ir_stmts.push(stmt.lower(in_loop, in_switch)?);
+                }
+                ir::Stmt::Block(ir_stmts)
+            }
+
+            Stmt::If { test_expr, then_stmt, else_stmt } => {
+                ir::Stmt::If {
+                    test_expr: test_expr.lower()?,
+                    then_stmt: Box::new(then_stmt.lower(in_loop, in_switch)?),
+                    else_stmt: match else_stmt {
+                        Some(else_stmt) => Some(Box::new(else_stmt.lower(in_loop, in_switch)?)),
+                        None => None,
+                    },
+                }
+            }
+
+            Stmt::While { test_expr, body_stmt } => {
+                ir::Stmt::While {
+                    test_expr: test_expr.lower()?,
+                    body_stmt: Box::new(body_stmt.lower(true, in_switch)?),
+                }
+            }
+
+            Stmt::DoWhile { body_stmt, test_expr } => {
+                ir::Stmt::DoWhile {
+                    body_stmt: Box::new(body_stmt.lower(true, in_switch)?),
+                    test_expr: test_expr.lower()?,
+                }
+            }
+
+            Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
+                ir::Stmt::For {
+                    init_stmt: match init_stmt {
+                        Some(init_stmt) => Some(Box::new(init_stmt.lower(in_loop, in_switch)?)),
+                        None => None,
+                    },
+                    test_expr: test_expr.lower()?,
+                    incr_expr: incr_expr.lower()?,
+                    body_stmt: Box::new(body_stmt.lower(true, in_switch)?),
+                }
+            }
+
+            Stmt::Switch { test_expr, body_stmt } => {
+                let test_type = test_expr.eval_type()?;
+                if !matches!(test_type, Int(_) | UInt(_)) {
+                    return ParseError::msg_only("E0021", &format!("can't switch on a value of type {}", test_type));
+                }
+
+                // Case labels are only supported directly in the body of the switch
+                let stmts = match body_stmt.as_ref() {
+                    Stmt::Block(stmts) => stmts.as_slice(),
+                    stmt => std::slice::from_ref(stmt),
+                };
+
+                let mut cases: Vec<(i128, usize)> = Vec::default();
+                let mut default = None;
+                let mut body = Vec::default();
+
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Case(val) => {
+                            // Case values are converted to the type of the test expression
+                            let val = wrap_int(&test_type, *val);
+                            if cases.iter().any(|(other, _)| *other == val) {
+                                return ParseError::msg_only("E0022", &format!("duplicate case value {} in switch", val));
+                            }
+                            cases.push((val, body.len()));
+                        }
+
+                        Stmt::Default => {
+                            if default.is_some() {
+                                return ParseError::msg_only("E0022", "multiple default labels in switch");
+                            }
+                            default = Some(body.len());
+                        }
+
+                        _ => body.push(stmt.lower(in_loop, true)?),
+                    }
+                }
+
+                ir::Stmt::Switch {
+                    test_expr: test_expr.lower()?,
+                    cases,
+                    default,
+                    body,
+                }
+            }
+
+            Stmt::Case(_) | Stmt::Default => {
+                return ParseError::msg_only("E0023", "case label outside of the body of a switch");
+            }
+
+            Stmt::VarDecl { .. } => unreachable!("variable declarations are removed by resolve_syms"),
+        };
+
+        Ok(stmt)
+    }
+}
+
+/// Convert an integer constant to an integer type
+fn wrap_int(t: &Type, val: i128) -> i128
+{
+    match t {
+        Int(n) => (val << (128 - n)) >> (128 - n),
+        UInt(n) => val & ((1 << n) - 1),
+        _ => unreachable!(),
+    }
+}
+
+/// Check if a value can be converted from one type to another
+fn cast_supported(new_type: &Type, src_type: &Type) -> bool
+{
+    matches!((new_type, src_type),
+        (UInt(_) | Int(_), UInt(_) | Int(_)) |
+        (Float(32), Int(..=32) | UInt(..=32)) |
+        (Int(..=32), Float(32)) |
+        (Pointer(_), Pointer(_) | Array { .. } | UInt(_) | Int(_) | Fun { .. }) |
+        (UInt(64), Pointer(_))
+    )
+}
+
+/// Check if values of a type can be loaded and stored
+fn is_scalar(t: &Type) -> bool
+{
+    matches!(t, UInt(_) | Int(_) | Float(32) | Pointer(_))
+}
+
+impl Expr
+{
+    fn lower(&self) -> Result<ir::Expr, ParseError>
+    {
+        let t = self.eval_type()?;
+
+        let kind = match self {
+            Expr::Int(v) => ir::ExprKind::Int(*v),
+            Expr::Float32(v) => ir::ExprKind::Float32(*v),
+
+            Expr::Ref(Decl::Arg { idx, .. }) => ir::ExprKind::Arg(*idx),
+            Expr::Ref(Decl::Local { idx, .. }) => ir::ExprKind::Local(*idx),
+            Expr::Ref(Decl::Fun { name, .. }) => ir::ExprKind::Addr(name.clone()),
+
+            Expr::Ref(Decl::Global { name, t }) => {
+                match t {
+                    UInt(_) | Int(32) | Int(64) | Float(32) | Pointer(_) => ir::ExprKind::Global(name.clone()),
+                    Fun { .. } | Array { .. } | Struct { .. } => ir::ExprKind::Addr(name.clone()),
+                    _ => return ParseError::msg_only("E0027", &format!(
+                        "global variable {} of type {} can't be loaded", name, t
+                    ))
+                }
+            }
+
+            Expr::Cast { new_type, child } => {
+                let src_type = child.eval_type()?;
+
+                if !cast_supported(new_type, &src_type) {
+                    return ParseError::msg_only("E0012", &format!("cannot cast to {} from {}", new_type, src_type));
+                }
+
+                ir::ExprKind::Cast(Box::new(child.lower()?))
+            }
+
+            Expr::SizeofExpr { child } => ir::ExprKind::Int(child.eval_type()?.sizeof() as i128),
+            Expr::SizeofType { t } => ir::ExprKind::Int(t.sizeof() as i128),
+
+            Expr::Arrow { base, field } => {
+                let (offset, num_bits) = field_info(base, field)?;
+
+                ir::ExprKind::Field {
+                    base: Box::new(base.lower()?),
+                    offset,
+                    num_bits,
+                }
+            }
+
+            // The address of a dereference, which happens
+            // for addresses of array elements, is the pointer itself
+            Expr::Unary { op: UnOp::AddressOf, child } if matches!(
+                child.as_ref(), Expr::Unary { op: UnOp::Deref, .. }
+            ) => {
+                let Expr::Unary { child: ptr, .. } = child.as_ref() else { unreachable!() };
+                return Ok(ir::Expr { t, kind: ptr.lower()?.kind });
+            }
+
+            Expr::Unary { op, child } => {
+                let child_type = child.eval_type()?;
+                let ir_child = child.lower()?;
+
+                match op {
+                    UnOp::Deref => {
+                        let elem_type = child_type.elem_type();
+
+                        // A pointer to an array is the array itself
+                        if let Array { .. } = elem_type {
+                            return Ok(ir::Expr { t, kind: ir_child.kind });
+                        }
+
+                        // Structs are represented by their address,
+                        // so the struct pointed to is the pointer
+                        if let Struct { .. } = elem_type {
+                            return Ok(ir_child);
+                        }
+
+                        if !is_scalar(&elem_type) {
+                            return ParseError::msg_only("E0027", &format!("can't load a value of type {}", elem_type));
+                        }
+
+                        ir::ExprKind::Load {
+                            addr: Box::new(ir_child),
+                            num_bits: elem_type.num_bits(),
+                        }
+                    }
+
+                    // Structs are represented by their address
+                    UnOp::AddressOf => {
+                        if let Struct { .. } = child_type {
+                            return Ok(ir::Expr { t, kind: ir::ExprKind::Cast(Box::new(ir_child)) });
+                        }
+
+                        return ParseError::msg_only("E0025",
+                            "the address-of operator is only supported for structs and array elements"
+                        );
+                    }
+
+                    UnOp::Minus => {
+                        if !matches!(child_type, Float(32) | Int(_) | UInt(_)) {
+                            return ParseError::msg_only("E0027", &format!("can't negate a value of type {}", child_type));
+                        }
+                        ir::ExprKind::Neg(Box::new(ir_child))
+                    }
+
+                    UnOp::BitNot => {
+                        if !matches!(child_type, Int(_) | UInt(_)) {
+                            return ParseError::msg_only("E0027", &format!("can't complement a value of type {}", child_type));
+                        }
+                        ir::ExprKind::BitNot(Box::new(ir_child))
+                    }
+
+                    UnOp::Not => ir::ExprKind::Not(Box::new(ir_child)),
+                }
+            }
+
+            Expr::Binary { op: BinOp::Assign, lhs, rhs } => {
+                ir::ExprKind::Assign {
+                    dst: lhs.lower_lvalue()?,
+                    value: Box::new(rhs.lower()?),
+                }
+            }
+
+            Expr::Binary { op, lhs, rhs } => {
+                ir::ExprKind::Binary {
+                    op: *op,
+                    lhs: Box::new(lhs.lower()?),
+                    rhs: Box::new(rhs.lower()?),
+                }
+            }
+
+            Expr::Ternary { test_expr, then_expr, else_expr } => {
+                ir::ExprKind::Ternary {
+                    test_expr: Box::new(test_expr.lower()?),
+                    then_expr: Box::new(then_expr.lower()?),
+                    else_expr: Box::new(else_expr.lower()?),
+                }
+            }
+
+            Expr::Call { callee, args } => {
+                let name = match callee.as_ref() {
+                    Expr::Ref(Decl::Fun { name, .. }) => name.clone(),
+                    _ => return ParseError::msg_only("E0026", "calls through function pointers are not supported, use call_fp in an asm expression")
+                };
+
+                let mut ir_args = Vec::default();
+                for arg in args {
+                    ir_args.push(arg.lower()?);
+                }
+
+                ir::ExprKind::Call { name, args: ir_args }
+            }
+
+            Expr::Asm { text, args, .. } => {
+                let mut ir_args = Vec::default();
+                for arg in args {
+                    ir_args.push(arg.lower()?);
+                }
+
+                ir::ExprKind::Asm { text: text.clone(), args: ir_args }
+            }
+
+            Expr::String(_) => unreachable!("string constants are moved into globals by resolve_syms"),
+            Expr::Ident(_) => unreachable!("identifiers are resolved by resolve_syms"),
+            Expr::CompoundAssign { .. } => unreachable!("compound assignments are transformed by resolve_syms"),
+            Expr::Ref(Decl::TypeDef { .. }) => unreachable!("typedefs are not values"),
+
+            Expr::Array(_) => {
+                return ParseError::msg_only("E0020", "array literals are only supported as global initializers");
+            }
+        };
+
+        Ok(ir::Expr { t, kind })
+    }
+
+    /// Lower the target of an assignment
+    fn lower_lvalue(&self) -> Result<ir::LValue, ParseError>
+    {
+        // Structs are assigned by copying them to their address
+        let t = self.eval_type()?;
+        if let Struct { .. } = t {
+            return Ok(ir::LValue::Copy {
+                addr: Box::new(self.lower()?),
+                num_bytes: t.sizeof(),
+            });
+        }
+
+        match self {
+            Expr::Arrow { base, field } => {
+                let (offset, num_bits) = field_info(base, field)?;
+
+                if num_bits > 64 {
+                    return ParseError::msg_only("E0027", &format!("assignment to struct field {} larger than 64 bits", field));
+                }
+
+                Ok(ir::LValue::Field {
+                    base: Box::new(base.lower()?),
+                    offset,
+                    num_bits,
+                })
+            }
+
+            Expr::Unary { op: UnOp::Deref, child } => {
+                let elem_type = child.eval_type()?.elem_type();
+
+                if !is_scalar(&elem_type) {
+                    return ParseError::msg_only("E0027", &format!("can't store a value of type {}", elem_type));
+                }
+
+                Ok(ir::LValue::Mem {
+                    addr: Box::new(child.lower()?),
+                    num_bits: elem_type.num_bits(),
+                })
+            }
+
+            Expr::Ref(Decl::Arg { idx, .. }) => Ok(ir::LValue::Arg(*idx)),
+            Expr::Ref(Decl::Local { idx, .. }) => Ok(ir::LValue::Local(*idx)),
+
+            Expr::Ref(Decl::Global { name, t }) => {
+                if !is_scalar(t) {
+                    return ParseError::msg_only("E0027", &format!("can't assign to global {} of type {}", name, t));
+                }
+
+                Ok(ir::LValue::Global {
+                    name: name.clone(),
+                    num_bits: t.num_bits(),
+                })
+            }
+
+            _ => ParseError::msg_only("E0028", "invalid assignment target")
+        }
+    }
+}
+
+/// Get the offset and size in bits of a field accessed through a struct pointer
+fn field_info(base: &Expr, field: &str) -> Result<(usize, usize), ParseError>
+{
+    match base.eval_type()? {
+        Pointer(s) => {
+            let (offset, size_bytes) = s.get_field(field).unwrap();
+            Ok((offset, size_bytes * 8))
+        }
+        _ => ParseError::msg_only("E0018", "arrow operator only applicable to struct pointers")
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::parsing::Input;
+    use crate::parser::parse_unit;
+
+    fn lower(src: &str) -> Result<crate::ir::Unit, String>
+    {
+        let mut input = Input::new(src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        unit.resolve_types().unwrap();
+        unit.resolve_syms().unwrap();
+        unit.check_types().unwrap();
+        unit.insert_casts().unwrap();
+        unit.lower().map_err(|err| err.msg)
+    }
+
+    #[test]
+    fn lower_ok()
+    {
+        let unit = lower("int x = 3; int foo(int a) { int b = a + x; return b; }").unwrap();
+        assert_eq!(unit.global_vars.len(), 1);
+        assert_eq!(unit.fun_decls[0].num_locals, 1);
+        assert!(!unit.fun_decls[0].needs_final_return);
+
+        assert!(lower("void foo() {}").unwrap().fun_decls[0].needs_final_return);
+        assert!(lower("unsigned long foo() { return sizeof(int); }").is_ok());
+        assert!(lower("typedef struct { int x; } S; S s; S* foo() { return &s; }").is_ok());
+        assert!(lower("int a[4]; int* foo() { return &a[1]; }").is_ok());
+        assert!(lower("int* p = null; char* q = 0;").is_ok());
+
+        // Struct copies
+        assert!(lower("typedef struct { int x; } S; S a; S b; void foo() { a = b; }").is_ok());
+        assert!(lower("typedef struct { int x; } S; S a[2]; int foo(S s) { return s.x; } int bar() { return foo(a[1]); }").is_ok());
+        let unit = lower("typedef struct { int x; } S; S* p; S* q; void foo() { *p = *q; }").unwrap();
+        let crate::ir::Stmt::Block(stmts) = &unit.fun_decls[0].body else { panic!() };
+        let crate::ir::Stmt::Expr(expr) = &stmts[0] else { panic!() };
+        assert!(matches!(&expr.kind, crate::ir::ExprKind::Assign { dst: crate::ir::LValue::Copy { num_bytes: 4, .. }, .. }));
+
+        // Case values are converted to the type of the test expression
+        let unit = lower("void foo(unsigned char c) { switch (c) { case 1: break; default: case 258: break; } }").unwrap();
+        let crate::ir::Stmt::Block(stmts) = &unit.fun_decls[0].body else { panic!() };
+        let crate::ir::Stmt::Switch { cases, default, .. } = &stmts[0] else { panic!() };
+        assert_eq!(cases, &[(1, 0), (2, 1)]);
+        assert_eq!(*default, Some(1));
+    }
+
+    #[test]
+    fn lower_errors()
+    {
+        assert!(lower("void foo() { break; }").is_err());
+        assert!(lower("void foo() { continue; }").is_err());
+        assert!(lower("void foo() { int x; int* p = &x; }").is_err());
+        assert!(lower("int x = 1; int* p = &x;").is_err());
+        assert!(lower("int* p = 5;").is_err());
+        assert!(lower("void foo(int x) { switch (x) { case 1: continue; } }").is_err());
+        assert!(lower("void foo(int x) { switch (x) { case 1: case 1: break; } }").is_err());
+        assert!(lower("void foo(char x) { switch (x) { case 1: case 257: break; } }").is_err());
+        assert!(lower("void foo(int x) { switch (x) { default: default: break; } }").is_err());
+        assert!(lower("void foo(int x) { switch (x) { case 1: if (x) { case 2: break; } } }").is_err());
+        assert!(lower("void foo(int x) { case 1: x = 2; }").is_err());
+        assert!(lower("void foo(float x) { switch (x) {} }").is_err());
+    }
+}
@@ -5,7 +5,10 @@
 #![allow(unused_mut)]
 
 use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+mod errors;
 mod parsing;
 mod cpp;
 mod ast;
@@ -14,7 +17,15 @@ mod typedefs;
 mod symbols;
 mod types;
 mod casts;
+mod lower;
+mod ir;
 mod codegen;
+mod regir;
+mod pgo;
+mod cache;
+mod strip;
+mod constprop;
+mod flow;
 mod exec_tests;
 
 use std::env;
@@ -34,8 +45,24 @@ struct Options
     // Print the preprocessor output
     print_cpp_out: bool,
 
-    // Output file
-    out_file: String,
+    // Output file, out.asm by default
+    // With multiple input files, each output is written next to its input
+    out_file: Option<String>,
+
+    // Optimize the generated code with the register IR pass
+    optimize: bool,
+
+    // Emit the source line of each statement, for uvm --coverage
+    debug_info: bool,
+
+    // Profile written by the VM, used to optimize hot functions
+    profile_use: Option<String>,
+
+    // Directory where compiled outputs are cached
+    cache_dir: Option<String>,
+
+    // Error code to print the explanation of, instead of compiling
+    explain: Option<String>,
 
     // Preprocessor definitions
     // -D<macroname>=<value>
@@ -48,7 +75,12 @@ fn parse_args(args: Vec<String>) -> Options
 {
     let mut opts = Options {
         print_cpp_out: false,
-        out_file: "out.asm".to_string(),
+        out_file: None,
+        optimize: false,
+        debug_info: false,
+        profile_use: None,
+        cache_dir: None,
+        explain: None,
         defs: HashMap::new(),
         rest: Vec::default(),
     };
@@ -74,9 +106,37 @@ fn parse_args(args: Vec<String>) -> Options
             continue;
         }
 
+        if arg == "-O" {
+            opts.optimize = true;
+            continue;
+        }
+
+        if arg == "-g" {
+            opts.debug_info = true;
+            continue;
+        }
+
+        if arg == "--profile-use" {
+            opts.profile_use = Some(args[idx].clone());
+            idx += 1;
+            continue;
+        }
+
+        if arg == "--cache-dir" {
+            opts.cache_dir = Some(args[idx].clone());
+            idx += 1;
+            continue;
+        }
+
+        if arg == "--explain" {
+            opts.explain = Some(args[idx].clone());
+            idx += 1;
+            continue;
+        }
+
         // Output file name
         if arg == "-o" {
-            opts.out_file = args[idx].clone();
+            opts.out_file = Some(args[idx].clone());
             idx += 1;
             continue;
         }
@@ -107,7 +167,8 @@ fn parse_args(args: Vec<String>) -> Options
     opts
 }
 
-fn compile_file(file_name: &str, opts: &Options) -> Result<(), ParseError>
+/// Compile a source file, and return the warnings produced
+fn compile_file(file_name: &str, out_file: &str, opts: &Options) -> Result<Vec<String>, ParseError>
 {
     let mut input = Input::from_file(file_name)?;
 
@@ -117,18 +178,89 @@ fn compile_file(file_name: &str, opts: &Options) -> Result<(), ParseError>
         println!("{}", output);
     }
 
-    let mut input = Input::new(&output, file_name);
+    // Look for the output of a previous compilation of the same source.
+    // The file name is part of the key because it appears in warnings.
+    let cache = opts.cache_dir.as_ref().map(|dir| {
+        let profile = opts.profile_use.as_ref().and_then(|f| std::fs::read(f).ok()).unwrap_or_default();
+        let flags = [opts.optimize as u8, opts.debug_info as u8];
+        let key = cache::Cache::key(&output, &[file_name.as_bytes(), &flags, &profile]);
+        (cache::Cache::new(dir), key)
+    });
+
+    let entry = match cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        Some(entry) => entry,
+        None => {
+            let entry = compile_src(&output, file_name, opts)?;
+            if let Some((cache, key)) = &cache {
+                cache.put(key, &entry);
+            }
+            entry
+        }
+    };
+
+    std::fs::write(out_file, entry.asm).unwrap();
+
+    Ok(entry.warnings)
+}
+
+/// Compile independent source files on multiple threads.
+/// The results are returned in the order of the input files,
+/// so that diagnostics don't depend on thread scheduling.
+fn compile_files(files: &[(String, String)], opts: &Options) -> Vec<Result<Vec<String>, ParseError>>
+{
+    let num_threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let num_threads = num_threads.min(files.len());
+
+    let next_idx = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::from_iter(files.iter().map(|_| None)));
+
+    std::thread::scope(|s| {
+        for _ in 0..num_threads {
+            s.spawn(|| loop {
+                let idx = next_idx.fetch_add(1, Ordering::Relaxed);
+                if idx >= files.len() {
+                    break;
+                }
+
+                let (file_name, out_file) = &files[idx];
+                let result = compile_file(file_name, out_file, opts);
+                results.lock().unwrap()[idx] = Some(result);
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter().map(|r| r.unwrap()).collect()
+}
+
+/// Compile preprocessed source code into assembly
+fn compile_src(src: &str, file_name: &str, opts: &Options) -> Result<cache::Entry, ParseError>
+{
+    let mut input = Input::new(src, file_name);
+    input.debug_info = opts.debug_info;
     let mut unit = parse_unit(&mut input)?;
 
     unit.resolve_types()?;
     unit.resolve_syms()?;
     unit.check_types()?;
+
+    let warnings = unit.check_returns()?.iter().map(|w| w.to_string()).collect();
+
+    unit.propagate_consts();
     unit.insert_casts()?;
-    let out = unit.gen_code()?;
+    unit.strip_unused();
+    let mut unit = unit.lower()?;
+
+    if let Some(profile_file) = &opts.profile_use {
+        unit.apply_profile(&pgo::Profile::load(profile_file)?);
+    }
 
-    std::fs::write(&opts.out_file, out).unwrap();
+    let mut out = unit.gen_code();
 
-    Ok(())
+    if opts.optimize {
+        out = regir::optimize(&out);
+    }
+
+    Ok(cache::Entry { asm: out, warnings })
 }
 
 fn main()
@@ -136,21 +268,62 @@ fn main()
     let opts = parse_args(env::args().collect());
     //println!("{:?}", opts);
 
-    if opts.rest.len() != 1 {
-        panic!("Must specify exactly one input source file to compile.");
+    if let Some(code) = &opts.explain {
+        match errors::find_code(code) {
+            Some(error_code) => {
+                println!("{}: {}\n", error_code.code, error_code.title);
+                print!("{}", error_code.explanation);
+                return;
+            }
+            None => {
+                println!("Unknown error code {}", code);
+                std::process::exit(-1);
+            }
+        }
     }
 
-    let file_name = &opts.rest[0];
-    let result = compile_file(file_name, &opts);
+    if opts.rest.is_empty() {
+        panic!("Must specify at least one input source file to compile.");
+    }
 
-    if let Err(error) = result {
-        if error.line_no != 0 {
-            println!("Error {}@{}:{}: {}", error.src_name, error.line_no, error.col_no, error.msg);
-        } else
-        {
-            println!("Error: {}", error.msg);
+    if opts.rest.len() > 1 && (opts.out_file.is_some() || opts.print_cpp_out) {
+        panic!("Options -o and -E can only be used with a single input source file.");
+    }
+
+    let files: Vec<(String, String)> = opts.rest.iter().map(|file_name| {
+        let out_file = match (&opts.out_file, opts.rest.len()) {
+            (Some(out_file), _) => out_file.clone(),
+            (None, 1) => "out.asm".to_string(),
+            (None, _) => std::path::Path::new(file_name).with_extension("asm").display().to_string(),
+        };
+        (file_name.clone(), out_file)
+    }).collect();
+
+    let mut failed = false;
+
+    for result in compile_files(&files, &opts) {
+        match result {
+            Ok(warnings) => {
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+            }
+
+            Err(error) => {
+                if error.line_no != 0 {
+                    println!("Error[{}] {}@{}:{}: {}", error.code, error.src_name, error.line_no, error.col_no, error.msg);
+                } else
+                {
+                    println!("Error[{}]: {}", error.code, error.msg);
+                }
+
+                failed = true;
+            }
         }
+    }
 
+    if failed {
+        println!("Run ncc --explain <code> for a description of an error.");
         std::process::exit(-1);
     }
 }
@@ -39,7 +39,7 @@ fn parse_atom(input: &mut Input) -> Result<Expr, ParseError>
         let float_val: f32 = num_str.parse().unwrap();
 
         if !input.match_char('f') {
-            return input.parse_error(&concat!("
+            return input.error_code("E0005", &concat!("
                 only floats are supported for now, ",
                 "e.g. 3.5f (float), not 3.5 (double)"
             ));
@@ -93,8 +93,18 @@ fn parse_atom(input: &mut Input) -> Result<Expr, ParseError>
 
         // Try to parse this as a type casting expression
         let cast_expr = input.with_backtracking(|input| {
-            // Type we're casting to
+            // Type we're casting to. A name that isn't a typedef
+            // is a variable, e.g. (a) - b is a subtraction
             let new_type = parse_type(input)?;
+            let mut base_type = &new_type;
+            while let Type::Pointer(t) = base_type {
+                base_type = t;
+            }
+            if let Type::Named(name) = base_type {
+                if !input.type_names.contains(name) {
+                    return input.parse_error("not a type name");
+                }
+            }
             input.expect_token(")")?;
 
             // Expression being casted
@@ -125,8 +135,15 @@ fn parse_atom(input: &mut Input) -> Result<Expr, ParseError>
     }
 
     // Sizeof expression
-    if input.match_token("sizeof")? {
-        input.expect_token("(")?;
+    if input.match_keyword("sizeof")? {
+        // The operand of sizeof can be a unary expression without parens,
+        // e.g. sizeof *p, but a type name must be parenthesized
+        if !input.match_token("(")? {
+            let expr = parse_prefix(input)?;
+            return Ok(Expr::SizeofExpr {
+                child: Box::new(expr)
+            });
+        }
 
         // Try to parse this as sizeof(type)
         let sizeof_expr = input.with_backtracking(|input| {
@@ -152,6 +169,28 @@ fn parse_atom(input: &mut Input) -> Result<Expr, ParseError>
         return parse_asm_expr(input);
     }
 
+    // Trap intrinsics, lowered to the corresponding VM instructions
+    if input.match_keyword("__builtin_trap")? {
+        let loc = format!("{}@{}", input.src_name, input.line_no);
+        input.expect_token("(")?;
+        input.expect_token(")")?;
+        return Ok(Expr::Asm {
+            text: "trap;".to_string(),
+            args: vec![Expr::String(loc)],
+            out_type: Type::Void,
+        });
+    }
+
+    if input.match_keyword("__builtin_debugbreak")? {
+        input.expect_token("(")?;
+        input.expect_token(")")?;
+        return Ok(Expr::Asm {
+            text: "breakpoint;".to_string(),
+            args: Vec::default(),
+            out_type: Type::Void,
+        });
+    }
+
     // Identifier (variable reference)
     if is_ident_start(ch) {
         let ident = input.parse_ident()?;
@@ -281,16 +320,12 @@ fn parse_prefix(input: &mut Input) -> Result<Expr, ParseError>
     if input.match_token("++")? {
         let sub_expr = parse_prefix(input)?;
 
-        // Transform into i = i + 1
+        // Transform into i += 1
         return Ok(
-            Expr::Binary{
-                op: BinOp::Assign,
-                lhs: Box::new(sub_expr.clone()),
-                rhs: Box::new(Expr::Binary{
-                    op: BinOp::Add,
-                    lhs: Box::new(sub_expr.clone()),
-                    rhs: Box::new(Expr::Int(1))
-                })
+            Expr::CompoundAssign{
+                op: BinOp::Add,
+                lhs: Box::new(sub_expr),
+                rhs: Box::new(Expr::Int(1))
             }
         );
     }
@@ -299,16 +334,12 @@ fn parse_prefix(input: &mut Input) -> Result<Expr, ParseError>
     if input.match_token("--")? {
         let sub_expr = parse_prefix(input)?;
 
-        // Transform into i = i - 1
+        // Transform into i -= 1
         return Ok(
-            Expr::Binary{
-                op: BinOp::Assign,
-                lhs: Box::new(sub_expr.clone()),
-                rhs: Box::new(Expr::Binary{
-                    op: BinOp::Sub,
-                    lhs: Box::new(sub_expr.clone()),
-                    rhs: Box::new(Expr::Int(1))
-                })
+            Expr::CompoundAssign{
+                op: BinOp::Sub,
+                lhs: Box::new(sub_expr),
+                rhs: Box::new(Expr::Int(1))
             }
         );
     }
@@ -400,7 +431,7 @@ fn parse_expr_list(input: &mut Input, end_token: &str) -> Result<Vec<Expr>, Pars
         }
 
         // Parse one argument
-        arg_exprs.push(parse_infix_expr(input, true)?);
+        arg_exprs.push(parse_assign_expr(input)?);
 
         if input.match_token(end_token)? {
             break;
@@ -472,108 +503,113 @@ struct OpInfo
     op_str: &'static str,
     prec: usize,
     op: BinOp,
+
+    /// Evaluates right to left, e.g. a = b = c is a = (b = c)
     rtl: bool,
+
+    /// Compound assignment, e.g. a += b, which is lowered to a = a + b
+    assign: bool,
 }
 
 /// Binary operators and their precedence level
 /// Lower numbers mean higher precedence
 /// https://en.cppreference.com/w/c/language/operator_precedence
-const BIN_OPS: [OpInfo; 20] = [
-    OpInfo { op_str: "*", prec: 3, op: BinOp::Mul, rtl: false },
-    OpInfo { op_str: "/", prec: 3, op: BinOp::Div, rtl: false },
-    OpInfo { op_str: "%", prec: 3, op: BinOp::Mod, rtl: false },
-    OpInfo { op_str: "+", prec: 4, op: BinOp::Add, rtl: false },
-    OpInfo { op_str: "-", prec: 4, op: BinOp::Sub, rtl: false },
-
-    OpInfo { op_str: "<<", prec: 5, op: BinOp::LShift, rtl: false },
-    OpInfo { op_str: ">>", prec: 5, op: BinOp::RShift, rtl: false },
-
-    OpInfo { op_str: "<=", prec: 6, op: BinOp::Le, rtl: false },
-    OpInfo { op_str: "<" , prec: 6, op: BinOp::Lt, rtl: false },
-    OpInfo { op_str: ">=", prec: 6, op: BinOp::Ge, rtl: false },
-    OpInfo { op_str: ">" , prec: 6, op: BinOp::Gt, rtl: false },
-    OpInfo { op_str: "==", prec: 7, op: BinOp::Eq, rtl: false },
-    OpInfo { op_str: "!=", prec: 7, op: BinOp::Ne, rtl: false },
+const BIN_OPS: [OpInfo; 30] = [
+    OpInfo { op_str: "*", prec: 3, op: BinOp::Mul, rtl: false, assign: false },
+    OpInfo { op_str: "/", prec: 3, op: BinOp::Div, rtl: false, assign: false },
+    OpInfo { op_str: "%", prec: 3, op: BinOp::Mod, rtl: false, assign: false },
+    OpInfo { op_str: "+", prec: 4, op: BinOp::Add, rtl: false, assign: false },
+    OpInfo { op_str: "-", prec: 4, op: BinOp::Sub, rtl: false, assign: false },
+
+    OpInfo { op_str: "<<", prec: 5, op: BinOp::LShift, rtl: false, assign: false },
+    OpInfo { op_str: ">>", prec: 5, op: BinOp::RShift, rtl: false, assign: false },
+
+    OpInfo { op_str: "<=", prec: 6, op: BinOp::Le, rtl: false, assign: false },
+    OpInfo { op_str: "<" , prec: 6, op: BinOp::Lt, rtl: false, assign: false },
+    OpInfo { op_str: ">=", prec: 6, op: BinOp::Ge, rtl: false, assign: false },
+    OpInfo { op_str: ">" , prec: 6, op: BinOp::Gt, rtl: false, assign: false },
+    OpInfo { op_str: "==", prec: 7, op: BinOp::Eq, rtl: false, assign: false },
+    OpInfo { op_str: "!=", prec: 7, op: BinOp::Ne, rtl: false, assign: false },
+
+    OpInfo { op_str: "&", prec: 8, op: BinOp::BitAnd, rtl: false, assign: false },
+    OpInfo { op_str: "^", prec: 9, op: BinOp::BitXor, rtl: false, assign: false },
+    OpInfo { op_str: "|", prec: 10, op: BinOp::BitOr, rtl: false, assign: false },
 
     // Logical and, logical or
-    // We place these first because they are longer tokens
-    OpInfo { op_str: "&&", prec: 11, op: BinOp::And, rtl: false },
-    OpInfo { op_str: "||", prec: 12, op: BinOp::Or, rtl: false },
-
-    OpInfo { op_str: "&", prec: 8, op: BinOp::BitAnd, rtl: false },
-    OpInfo { op_str: "^", prec: 9, op: BinOp::BitXor, rtl: false },
-    OpInfo { op_str: "|", prec: 10, op: BinOp::BitOr, rtl: false },
-
-    // Assignment operator, evaluates right to left
-    OpInfo { op_str: "=", prec: 14, op: BinOp::Assign, rtl: true },
+    OpInfo { op_str: "&&", prec: 11, op: BinOp::And, rtl: false, assign: false },
+    OpInfo { op_str: "||", prec: 12, op: BinOp::Or, rtl: false, assign: false },
+
+    // Assignment operators, evaluate right to left
+    OpInfo { op_str: "=", prec: 14, op: BinOp::Assign, rtl: true, assign: false },
+    OpInfo { op_str: "+=", prec: 14, op: BinOp::Add, rtl: true, assign: true },
+    OpInfo { op_str: "-=", prec: 14, op: BinOp::Sub, rtl: true, assign: true },
+    OpInfo { op_str: "*=", prec: 14, op: BinOp::Mul, rtl: true, assign: true },
+    OpInfo { op_str: "/=", prec: 14, op: BinOp::Div, rtl: true, assign: true },
+    OpInfo { op_str: "%=", prec: 14, op: BinOp::Mod, rtl: true, assign: true },
+    OpInfo { op_str: "<<=", prec: 14, op: BinOp::LShift, rtl: true, assign: true },
+    OpInfo { op_str: ">>=", prec: 14, op: BinOp::RShift, rtl: true, assign: true },
+    OpInfo { op_str: "&=", prec: 14, op: BinOp::BitAnd, rtl: true, assign: true },
+    OpInfo { op_str: "^=", prec: 14, op: BinOp::BitXor, rtl: true, assign: true },
+    OpInfo { op_str: "|=", prec: 14, op: BinOp::BitOr, rtl: true, assign: true },
 
     // Sequencing operator
-    OpInfo { op_str: ",", prec: 15, op: BinOp::Comma, rtl: false },
+    OpInfo { op_str: ",", prec: 15, op: BinOp::Comma, rtl: false, assign: false },
 ];
 
 /// Precedence level of the ternary operator (a? b:c)
 const TERNARY_PREC: usize = 13;
 
-/// Try to match a binary operator in the input
-fn match_bin_op(input: &mut Input, no_comma: bool) -> Result<Option<OpInfo>, ParseError>
+/// Precedence level of assignment expressions, which is
+/// the lowest level allowed in argument lists
+const ASSIGN_PREC: usize = 14;
+
+/// Precedence level of the comma operator, the lowest level
+const COMMA_PREC: usize = 15;
+
+/// Try to match a binary operator with a precedence level of
+/// at most max_prec in the input. The longest matching operator
+/// token wins, so that e.g. <<= is never read as << followed by =.
+fn match_bin_op(input: &mut Input, max_prec: usize) -> Result<Option<&'static OpInfo>, ParseError>
 {
-    for op_info in BIN_OPS {
-        if no_comma && op_info.op_str == "," {
+    let mut longest: Option<&'static OpInfo> = None;
+
+    for op_info in &BIN_OPS {
+        if longest.is_some_and(|l| l.op_str.len() >= op_info.op_str.len()) {
             continue;
         }
 
-        if input.match_token(op_info.op_str)? {
-            return Ok(Some(op_info));
+        if input.peek_token(op_info.op_str)? {
+            longest = Some(op_info);
         }
     }
 
-    Ok(None)
+    match longest {
+        Some(op_info) if op_info.prec <= max_prec => {
+            input.match_token(op_info.op_str)?;
+            Ok(Some(op_info))
+        }
+        _ => Ok(None)
+    }
 }
 
 fn parse_expr(input: &mut Input) -> Result<Expr, ParseError>
 {
-    parse_infix_expr(input, false)
+    parse_bin_expr(input, COMMA_PREC)
 }
 
-/// Parse a complex infix expression
-/// This uses the shunting yard algorithm to parse infix expressions:
-/// https://en.wikipedia.org/wiki/Shunting_yard_algorithm
-fn parse_infix_expr(input: &mut Input, no_comma: bool) -> Result<Expr, ParseError>
+/// Parse an expression which can't contain a top-level comma,
+/// such as a function argument or array element
+fn parse_assign_expr(input: &mut Input) -> Result<Expr, ParseError>
 {
-    // Operator stack
-    let mut op_stack: Vec<OpInfo> = Vec::default();
-
-    // Expression stack
-    let mut expr_stack: Vec<Expr> = Vec::default();
-
-    // Parse the prefix sub-expression
-    expr_stack.push(parse_prefix(input)?);
+    parse_bin_expr(input, ASSIGN_PREC)
+}
 
-    // Evaluate the operators on the stack with lower
-    // precedence than a new operator we just read
-    fn eval_lower_prec(op_stack: &mut Vec<OpInfo>, expr_stack: &mut Vec<Expr>, new_op_prec: usize)
-    {
-        while op_stack.len() > 0 {
-            // Get the operator at the top of the stack
-            let top_op = &op_stack[op_stack.len() - 1];
-
-            if top_op.prec <= new_op_prec {
-                assert!(expr_stack.len() >= 2);
-                let rhs = expr_stack.pop().unwrap();
-                let lhs = expr_stack.pop().unwrap();
-                let top_op = op_stack.pop().unwrap();
-
-                expr_stack.push(Expr::Binary {
-                    op: top_op.op,
-                    lhs: Box::new(lhs),
-                    rhs: Box::new(rhs)
-                });
-            }
-            else {
-                break;
-            }
-        }
-    }
+/// Parse an infix expression containing only operators with
+/// a precedence level of at most max_prec, using precedence climbing:
+/// https://en.wikipedia.org/wiki/Operator-precedence_parser
+fn parse_bin_expr(input: &mut Input, max_prec: usize) -> Result<Expr, ParseError>
+{
+    let mut lhs = parse_prefix(input)?;
 
     loop
     {
@@ -581,78 +617,50 @@ fn parse_infix_expr(input: &mut Input, no_comma: bool) -> Result<Expr, ParseErro
             break;
         }
 
-        // Ternary operator
-        if input.match_token("?")? {
-            // We have to evaluate lower-precedence operators now
-            // in order to use the resulting value for the boolean test
-            eval_lower_prec(&mut op_stack, &mut expr_stack, TERNARY_PREC);
-
-            let test_expr = expr_stack.pop().unwrap();
+        // Ternary operator, which evaluates right to left.
+        // Any expression can appear between the ? and the :
+        if max_prec >= TERNARY_PREC && input.match_token("?")? {
             let then_expr = parse_expr(input)?;
             input.expect_token(":")?;
-            let else_expr = parse_expr(input)?;
+            let else_expr = parse_bin_expr(input, TERNARY_PREC)?;
 
-            expr_stack.push(Expr::Ternary {
-                test_expr: Box::new(test_expr),
+            lhs = Expr::Ternary {
+                test_expr: Box::new(lhs),
                 then_expr: Box::new(then_expr),
                 else_expr: Box::new(else_expr),
-            });
-
-            break;
-        }
-
-        let new_op = match_bin_op(input, no_comma)?;
+            };
 
-        // If no operator could be matched, stop
-        if new_op.is_none() {
-            break;
+            continue;
         }
-        let new_op = new_op.unwrap();
 
-        // If this operator evaluates right-to-left,
-        // e.g. an assignment operator
-        if new_op.rtl == true {
-            // Recursively parse the rhs expression,
-            // forcing it to be evaluated before the lhs
-            let rhs = parse_expr(input)?;
+        let op_info = match match_bin_op(input, max_prec)? {
+            Some(op_info) => op_info,
+            None => break
+        };
 
-            let lhs = expr_stack.pop().unwrap();
+        // Right-to-left operators accept operators of the same
+        // precedence level on their right-hand side
+        let rhs_prec = if op_info.rtl { op_info.prec } else { op_info.prec - 1 };
+        let rhs = parse_bin_expr(input, rhs_prec)?;
 
-            expr_stack.push(Expr::Binary {
-                op: new_op.op,
+        lhs = if op_info.assign {
+            Expr::CompoundAssign {
+                op: op_info.op,
                 lhs: Box::new(lhs),
-                rhs: Box::new(rhs)
-            });
-
-            break;
+                rhs: Box::new(rhs),
+            }
         }
-
-        // Evaluate the operators with lower precedence than
-        // the new operator we just read
-        eval_lower_prec(&mut op_stack, &mut expr_stack, new_op.prec);
-
-        op_stack.push(new_op);
-
-        // There must be another prefix sub-expression following
-        expr_stack.push(parse_prefix(input)?);
-    }
-
-    // Emit all operators remaining on the operator stack
-    while op_stack.len() > 0 {
-        assert!(expr_stack.len() >= 2);
-        let rhs = expr_stack.pop().unwrap();
-        let lhs = expr_stack.pop().unwrap();
-        let top_op = op_stack.pop().unwrap();
-
-        expr_stack.push(Expr::Binary {
-            op: top_op.op,
-            lhs: Box::new(lhs),
-            rhs: Box::new(rhs)
-        });
+        else
+        {
+            Expr::Binary {
+                op: op_info.op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs)
+            }
+        };
     }
 
-    assert!(expr_stack.len() == 1);
-    Ok(expr_stack.pop().unwrap())
+    Ok(lhs)
 }
 
 /// Parse a block statement
@@ -677,6 +685,10 @@ fn parse_block_stmt(input: &mut Input) -> Result<Stmt, ParseError>
             continue;
         }
 
+        if input.debug_info {
+            stmts.push(Stmt::Line { src_name: input.src_name.clone(), line_no: input.line_no });
+        }
+
         stmts.push(parse_stmt(input)?);
     }
 
@@ -703,6 +715,21 @@ fn parse_decl(input: &mut Input) -> Result<(Type, String, Option<Expr>), ParseEr
     Ok((var_type, var_name, init_expr))
 }
 
+/// Parse the body of an if statement or a loop. With -g, a body
+/// which isn't a block gets the source line it starts on.
+fn parse_body_stmt(input: &mut Input) -> Result<Stmt, ParseError>
+{
+    input.eat_ws()?;
+    let line = Stmt::Line { src_name: input.src_name.clone(), line_no: input.line_no };
+    let stmt = parse_stmt(input)?;
+
+    match stmt {
+        Stmt::Block(_) => Ok(stmt),
+        _ if input.debug_info => Ok(Stmt::Block(vec![line, stmt])),
+        _ => Ok(stmt),
+    }
+}
+
 /// Parse a statement
 fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
 {
@@ -740,12 +767,12 @@ fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
         input.expect_token(")")?;
 
         // Parse the then statement
-        let then_stmt = parse_stmt(input)?;
+        let then_stmt = parse_body_stmt(input)?;
 
         // If there is an else statement
         if input.match_keyword("else")? {
             // Parse the else statement
-            let else_stmt = parse_stmt(input)?;
+            let else_stmt = parse_body_stmt(input)?;
 
             return Ok(Stmt::If {
                 test_expr,
@@ -771,7 +798,7 @@ fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
         input.expect_token(")")?;
 
         // Parse the loop body
-        let body_stmt = parse_stmt(input)?;
+        let body_stmt = parse_body_stmt(input)?;
 
         return Ok(Stmt::While {
             test_expr,
@@ -783,7 +810,7 @@ fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
     if input.match_keyword("do")? {
 
         // Parse the loop body
-        let body_stmt = parse_stmt(input)?;
+        let body_stmt = parse_body_stmt(input)?;
 
         // Parse the test expression
         input.expect_token("while")?;
@@ -831,7 +858,7 @@ fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
         };
 
         // Parse the loop body
-        let body_stmt = parse_stmt(input)?;
+        let body_stmt = parse_body_stmt(input)?;
 
         return Ok(Stmt::For {
             init_stmt,
@@ -841,6 +868,34 @@ fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
         });
     }
 
+    // Switch statement
+    if input.match_keyword("switch")? {
+        input.expect_token("(")?;
+        let test_expr = parse_expr(input)?;
+        input.expect_token(")")?;
+        let body_stmt = parse_body_stmt(input)?;
+
+        return Ok(Stmt::Switch {
+            test_expr,
+            body_stmt: Box::new(body_stmt),
+        });
+    }
+
+    // Case labels are statements of the switch body
+    if input.match_keyword("case")? {
+        let val = match parse_expr(input)? {
+            Expr::Int(val) => val,
+            _ => return input.error_code("E0023", "case label must be an integer constant"),
+        };
+        input.expect_token(":")?;
+        return Ok(Stmt::Case(val));
+    }
+
+    if input.match_keyword("default")? {
+        input.expect_token(":")?;
+        return Ok(Stmt::Default);
+    }
+
     // Block statement
     if input.peek_ch() == '{' {
         return parse_block_stmt(input);
@@ -864,6 +919,66 @@ fn parse_stmt(input: &mut Input) -> Result<Stmt, ParseError>
     Ok(Stmt::Expr(expr))
 }
 
+/// Parse a combination of standard integer type specifiers,
+/// such as `unsigned long long` or `short int`, starting from
+/// the first keyword, which was already consumed
+fn parse_int_specifiers(input: &mut Input, first: &str) -> Result<Type, ParseError>
+{
+    let mut signed = false;
+    let mut unsigned = false;
+    let mut num_char = 0;
+    let mut num_short = 0;
+    let mut num_int = 0;
+    let mut num_long = 0;
+    let mut keyword = first.to_string();
+
+    loop
+    {
+        match keyword.as_str() {
+            "signed" => signed = true,
+            "unsigned" => unsigned = true,
+            "char" => num_char += 1,
+            "short" => num_short += 1,
+            "int" => num_int += 1,
+            "long" => num_long += 1,
+            _ => unreachable!()
+        }
+
+        // Look for another specifier
+        let next = ["signed", "unsigned", "char", "short", "int", "long"].into_iter().find(|k| {
+            matches!(input.match_keyword(k), Ok(true))
+        });
+
+        match next {
+            Some(next) => keyword = next.to_string(),
+            None => break
+        }
+    }
+
+    let repeated = num_char > 1 || num_short > 1 || num_int > 1 || num_long > 2;
+    let char_with_size = num_char > 0 && (num_short > 0 || num_int > 0 || num_long > 0);
+    if (signed && unsigned) || repeated || char_with_size || (num_short > 0 && num_long > 0) {
+        return input.parse_error("invalid combination of integer type specifiers");
+    }
+
+    let num_bits = if num_char > 0 {
+        8
+    } else if num_short > 0 {
+        16
+    } else if num_long > 0 {
+        64
+    } else {
+        32
+    };
+
+    // Plain char is unsigned, like the other 8-bit types
+    if unsigned || (num_char > 0 && !signed) {
+        Ok(Type::UInt(num_bits))
+    } else {
+        Ok(Type::Int(num_bits))
+    }
+}
+
 /// Parse an atomic type expression
 fn parse_type_atom(input: &mut Input) -> Result<Type, ParseError>
 {
@@ -885,39 +1000,17 @@ fn parse_type_atom(input: &mut Input) -> Result<Type, ParseError>
         "i32" => Ok(Type::Int(32)),
         "i64" => Ok(Type::Int(64)),
 
-        "char" => Ok(Type::UInt(8)),
         "bool" => Ok(Type::UInt(8)),
 
-        // Standard integer types
-        "short" => Ok(Type::Int(16)),
-        "int" => Ok(Type::Int(32)),
-        "long" => Ok(Type::Int(64)),
+        // Standard integer types, with their specifiers in any order
+        "char" | "short" | "int" | "long" | "signed" | "unsigned" => {
+            parse_int_specifiers(input, &keyword)
+        }
 
         // Floating-point types
         "float" | "f32" => Ok(Type::Float(32)),
         "double" | "f64" => Ok(Type::Float(64)),
 
-        // Unsigned qualifier
-        "unsigned" => {
-            if input.match_token("char")? {
-                return Ok(Type::UInt(8));
-            }
-
-            if input.match_token("short")? {
-                return Ok(Type::UInt(16));
-            }
-
-            if input.match_token("int")? {
-                return Ok(Type::UInt(32));
-            }
-
-            if input.match_token("long")? {
-                return Ok(Type::UInt(64));
-            }
-
-            return Ok(Type::UInt(32));
-        }
-
         // Struct type
         "struct" => {
             parse_struct(input)
@@ -1081,6 +1174,7 @@ pub fn parse_unit(input: &mut Input) -> Result<Unit, ParseError>
             let name = input.parse_ident()?;
             let t = parse_array_type(input, t)?;
             input.expect_token(";")?;
+            input.type_names.insert(name.clone());
             unit.typedefs.push((name, Rc::new(Box::new(RefCell::new(t)))));
             continue;
         }
@@ -1088,6 +1182,9 @@ pub fn parse_unit(input: &mut Input) -> Result<Unit, ParseError>
         // If this is an inline function attribute
         let inline = input.match_token("inline")?;
 
+        // If this is a thread-local variable
+        let thread_local = input.match_keyword("_Thread_local")?;
+
         // Parse the global declaration type and name
         let decl_type = parse_type(input)?;
         input.eat_ws()?;
@@ -1095,6 +1192,10 @@ pub fn parse_unit(input: &mut Input) -> Result<Unit, ParseError>
 
         // If this is the beginning of a function declaration
         if input.match_token("(")? {
+            if thread_local {
+                return input.error_code("E0029", "functions can't be thread-local");
+            }
+
             let fun = parse_function(input, name, decl_type, inline)?;
             unit.fun_decls.push(fun);
             continue;
@@ -1122,7 +1223,9 @@ pub fn parse_unit(input: &mut Input) -> Result<Unit, ParseError>
         unit.global_vars.push(Global {
             name,
             var_type: decl_type,
-            init_expr
+            init_expr,
+            interned: false,
+            thread_local,
         });
     }
 
@@ -1257,8 +1360,13 @@ mod tests
         // Regression
         parse_ok("u8 g0;//\n//\n//\nu8 g1;");
 
+        // Thread-local variables
+        parse_ok("_Thread_local int x = 1;");
+        parse_ok("_Thread_local u8 buf[16];");
+
         // Should fail
         parse_fails("u64x;");
+        parse_fails("_Thread_local int f() { return 0; }");
     }
 
     #[test]
@@ -1315,12 +1423,92 @@ mod tests
         parse_fails("u64 foo() { return 1 + 2 +; }");
     }
 
+    /// Parse an expression and print its tree in prefix form
+    fn expr_tree(src: &str) -> String
+    {
+        fn show(expr: &Expr) -> String
+        {
+            match expr {
+                Expr::Int(n) => n.to_string(),
+                Expr::Ident(name) => name.clone(),
+                Expr::Binary { op, lhs, rhs } => format!("({:?} {} {})", op, show(lhs), show(rhs)),
+                Expr::CompoundAssign { op, lhs, rhs } => format!("({:?}= {} {})", op, show(lhs), show(rhs)),
+                Expr::Unary { op, child } => format!("({:?} {})", op, show(child)),
+                Expr::Ternary { test_expr, then_expr, else_expr } => {
+                    format!("(? {} {} {})", show(test_expr), show(then_expr), show(else_expr))
+                }
+                Expr::Cast { new_type, child } => format!("(cast {})", show(child)),
+                Expr::SizeofExpr { child } => format!("(sizeof {})", show(child)),
+                _ => format!("{:?}", expr),
+            }
+        }
+
+        let mut input = Input::new(src, "src");
+        input.type_names.insert("T".to_string());
+        show(&parse_expr(&mut input).unwrap())
+    }
+
+    #[test]
+    fn precedence()
+    {
+        assert_eq!(expr_tree("1 + 2 * 3"), "(Add 1 (Mul 2 3))");
+        assert_eq!(expr_tree("1 - 2 - 3"), "(Sub (Sub 1 2) 3)");
+        assert_eq!(expr_tree("a << 1 < b"), "(Lt (LShift a 1) b)");
+        assert_eq!(expr_tree("a & b == c"), "(BitAnd a (Eq b c))");
+        assert_eq!(expr_tree("a || b && c | d"), "(Or a (And b (BitOr c d)))");
+
+        // Assignment is right-associative and binds looser than the ternary
+        assert_eq!(expr_tree("a = b = c"), "(Assign a (Assign b c))");
+        assert_eq!(expr_tree("a = b, c"), "(Comma (Assign a b) c)");
+        assert_eq!(expr_tree("a = b ? c : d"), "(Assign a (? b c d))");
+
+        // Nested ternaries, and a comma between ? and :
+        assert_eq!(expr_tree("a ? b : c ? d : e"), "(? a b (? c d e))");
+        assert_eq!(expr_tree("a ? b, c : d"), "(? a (Comma b c) d)");
+        assert_eq!(expr_tree("a || b ? c : d + 1"), "(? (Or a b) c (Add d 1))");
+
+        // Compound assignment, and the longest operator token wins
+        assert_eq!(expr_tree("a += b * 2"), "(Add= a (Mul b 2))");
+        assert_eq!(expr_tree("a <<= 1"), "(LShift= a 1)");
+        assert_eq!(expr_tree("a <= b"), "(Le a b)");
+        assert_eq!(expr_tree("a & &b"), "(BitAnd a (AddressOf b))");
+
+        // Casts versus parenthesized expressions
+        assert_eq!(expr_tree("(T)-a"), "(cast (Minus a))");
+        assert_eq!(expr_tree("(a) - b"), "(Sub a b)");
+        assert_eq!(expr_tree("(a) * b"), "(Mul a b)");
+        assert_eq!(expr_tree("(T*)a + 1"), "(Add (cast a) 1)");
+
+        // Sizeof of an expression doesn't need parens
+        assert_eq!(expr_tree("sizeof *p + 1"), "(Add (sizeof (Deref p)) 1)");
+    }
+
     #[test]
     fn cast_exprs()
     {
         parse_ok("int foo() { (int)1; }");
     }
 
+    #[test]
+    fn int_specifiers()
+    {
+        parse_ok("unsigned long long x;");
+        parse_ok("long long int x;");
+        parse_ok("short int x;");
+        parse_ok("unsigned short int x;");
+        parse_ok("signed char x;");
+        parse_ok("int unsigned x;");
+        parse_ok("signed x;");
+        parse_ok("long unsigned int foo(unsigned x) { return x; }");
+        parse_ok("int foo() { (unsigned long)1; }");
+
+        parse_fails("signed unsigned x;");
+        parse_fails("short long x;");
+        parse_fails("long long long x;");
+        parse_fails("int int x;");
+        parse_fails("long char x;");
+    }
+
     #[test]
     fn call_expr()
     {
@@ -1342,6 +1530,21 @@ mod tests
         parse_ok("void main() { asm (1, 2, 3) -> u64 { push 1;\n push2; }; }");
     }
 
+    #[test]
+    fn trap_intrinsics()
+    {
+        parse_ok("void main() { __builtin_trap(); }");
+        parse_ok("void main() { if (1) __builtin_debugbreak(); }");
+        parse_fails("void main() { __builtin_trap(1); }");
+
+        // The trap location is the line of the intrinsic
+        let mut input = Input::new("\n\n__builtin_trap()", "foo.c");
+        match parse_expr(&mut input).unwrap() {
+            Expr::Asm { args, .. } => assert!(matches!(&args[0], Expr::String(loc) if loc == "foo.c@3")),
+            _ => panic!()
+        }
+    }
+
     #[test]
     fn local_vars()
     {
@@ -1387,6 +1590,16 @@ mod tests
         parse_ok("void main() { for (size_t i = 0; i < 10; i = i + 1) {} }");
     }
 
+    #[test]
+    fn switch_stmt()
+    {
+        parse_ok("void main() { switch (x) {} }");
+        parse_ok("void main() { switch (x) { case 1: foo(); break; case -2: case 'a': default: bar(); } }");
+        parse_ok("void main() { switch (x) case 1: foo(); }");
+        parse_fails("void main() { switch (x) { case y: foo(); } }");
+        parse_fails("void main() { switch (x) { case 1 foo(); } }");
+    }
+
     #[test]
     fn if_stmt()
     {
@@ -1394,6 +1607,22 @@ mod tests
         parse_ok("void main() { if (1) { foo(); } else { bar(); } }");
     }
 
+    #[test]
+    fn debug_info()
+    {
+        let mut input = Input::new("void main()\n{\n    foo();\n    if (1)\n        bar();\n}", "src.c");
+        input.debug_info = true;
+        let unit = parse_unit(&mut input).unwrap();
+
+        let mut lines = Vec::new();
+        unit.fun_decls[0].body.each_stmt(&mut |stmt| {
+            if let Stmt::Line { src_name, line_no } = stmt {
+                lines.push((src_name.clone(), *line_no));
+            }
+        });
+        assert_eq!(lines, [("src.c".to_string(), 3), ("src.c".to_string(), 4), ("src.c".to_string(), 5)]);
+    }
+
     #[test]
     fn regress_ptr_buf_idx()
     {
@@ -1,5 +1,7 @@
 use std::fs;
 use std::fmt;
+use std::collections::HashSet;
+use crate::errors::SYNTAX_ERROR;
 
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
 pub struct SrcPos
@@ -20,6 +22,9 @@ impl fmt::Display for SrcPos
 #[derive(Debug, Clone)]
 pub struct ParseError
 {
+    /// Stable code of the error, see errors.rs
+    pub code: &'static str,
+
     pub msg: String,
     pub src_name: String,
     pub line_no: u32,
@@ -28,9 +33,10 @@ pub struct ParseError
 
 impl ParseError
 {
-    pub fn new(input: &Input, msg: &str) -> Self
+    pub fn new(input: &Input, code: &'static str, msg: &str) -> Self
     {
         ParseError {
+            code,
             msg: msg.to_string(),
             src_name: input.src_name.clone(),
             line_no: input.line_no,
@@ -39,9 +45,10 @@ impl ParseError
     }
 
     /// Parse error with just an error message, no location
-    pub fn msg_only<T>(msg: &str) -> Result<T, ParseError>
+    pub fn msg_only<T>(code: &'static str, msg: &str) -> Result<T, ParseError>
     {
         Err(ParseError {
+            code,
             msg: msg.to_string(),
             src_name: String::new(),
             line_no: 0,
@@ -86,6 +93,13 @@ pub struct Input
 
     // Current column number
     pub col_no: u32,
+
+    // Names declared with typedef so far, which the parser
+    // needs to tell casts apart from parenthesized expressions
+    pub type_names: HashSet<String>,
+
+    // Record the source line of each statement, for ncc -g
+    pub debug_info: bool,
 }
 
 impl Input
@@ -96,6 +110,7 @@ impl Input
             Ok(data) => data,
             Err(_) => {
                 return Err(ParseError {
+                    code: "E0003",
                     msg: format!("could not read input file \"{}\"", file_name),
                     src_name: String::new(),
                     line_no: 0,
@@ -114,7 +129,9 @@ impl Input
             src_name: src_name.to_string(),
             idx: 0,
             line_no: 1,
-            col_no: 1
+            col_no: 1,
+            type_names: HashSet::default(),
+            debug_info: false,
         }
     }
 
@@ -323,6 +340,17 @@ impl Input
         return Ok(self.match_chars(&token_chars));
     }
 
+    /// Check if a string comes next in the input, ignoring
+    /// preceding whitespace, without consuming the string
+    pub fn peek_token(&mut self, token: &str) -> Result<bool, ParseError>
+    {
+        self.eat_ws()?;
+
+        let token_chars: Vec<char> = token.chars().collect();
+        let end_pos = self.idx + token_chars.len();
+        Ok(end_pos <= self.input.len() && self.input[self.idx..end_pos] == token_chars[..])
+    }
+
     /// Match a keyword in the input, ignoring preceding whitespace
     /// This is different from match_token because there can't be a
     /// match if the following chars are also valid identifier chars.
@@ -345,7 +373,13 @@ impl Input
     /// Shortcut for yielding a parse error wrapped in a result type
     pub fn parse_error<T>(&self, msg: &str) -> Result<T, ParseError>
     {
-        Err(ParseError::new(self, msg))
+        Err(ParseError::new(self, SYNTAX_ERROR, msg))
+    }
+
+    /// Parse error with a more specific code than a syntax error
+    pub fn error_code<T>(&self, code: &'static str, msg: &str) -> Result<T, ParseError>
+    {
+        Err(ParseError::new(self, code, msg))
     }
 
     /// Produce an error if the input doesn't match a given token
@@ -0,0 +1,476 @@
+//! Profile-guided optimization
+//!
+//! The VM writes a profile of the calls and loop iterations executed by
+//! each function when run with --profile. Reading it back with
+//! --profile-use, we mark the functions that account for a significant
+//! share of the execution as hot. In hot functions, calls to small leaf
+//! functions are inlined, and loops are laid out with the test at the
+//! bottom. Cold functions keep their compact code.
+
+use std::collections::HashMap;
+use crate::ast::{Type, BinOp};
+use crate::ir::*;
+use crate::parsing::ParseError;
+
+/// A function is hot if its count is at least this percentage
+/// of the count of the hottest function
+const HOT_PERCENT: u64 = 1;
+
+/// Maximum number of expression nodes in an inlined function body
+const MAX_INLINE_SIZE: usize = 24;
+
+/// Maximum number of local slots, limited by the enter instruction
+const MAX_LOCALS: usize = 255;
+
+#[derive(Default, Debug)]
+pub struct Profile
+{
+    /// Number of calls plus loop iterations, by function name
+    counts: HashMap<String, u64>,
+}
+
+impl Profile
+{
+    pub fn load(file_name: &str) -> Result<Profile, ParseError>
+    {
+        match std::fs::read_to_string(file_name) {
+            Ok(text) => Profile::parse(&text),
+            Err(_) => ParseError::msg_only("E0003", &format!("could not read profile \"{}\"", file_name)),
+        }
+    }
+
+    /// Parse a profile, where each line has a function name
+    /// followed by its call count and loop iteration count
+    pub fn parse(text: &str) -> Result<Profile, ParseError>
+    {
+        let mut profile = Profile::default();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let counts: Option<Vec<u64>> = fields.iter().skip(1).map(|f| f.parse().ok()).collect();
+
+            match (fields.first(), counts) {
+                (Some(name), Some(counts)) if counts.len() == 2 => {
+                    *profile.counts.entry(name.to_string()).or_default() += counts[0] + counts[1];
+                }
+                _ => return ParseError::msg_only("E0004", &format!("invalid profile line \"{}\"", line)),
+            }
+        }
+
+        Ok(profile)
+    }
+
+    pub fn is_hot(&self, fun_name: &str) -> bool
+    {
+        let max_count = self.counts.values().copied().max().unwrap_or(0);
+        let count = self.counts.get(fun_name).copied().unwrap_or(0);
+        count > 0 && count * 100 >= max_count * HOT_PERCENT
+    }
+}
+
+impl Unit
+{
+    /// Optimize the hot functions according to a profile
+    pub fn apply_profile(&mut self, profile: &Profile)
+    {
+        // Functions which can be inlined, with their parameter count and body
+        let mut inlinable = HashMap::default();
+        for fun in &self.fun_decls {
+            if let Some(body) = fun.inline_body() {
+                inlinable.insert(fun.name.clone(), (fun.params.len(), body.clone()));
+            }
+        }
+
+        for fun in &mut self.fun_decls {
+            fun.hot = profile.is_hot(&fun.name);
+
+            if fun.hot {
+                let mut num_locals = fun.num_locals;
+                fun.body.inline_calls(&inlinable, &mut num_locals);
+                fun.num_locals = num_locals;
+            }
+        }
+    }
+}
+
+impl Function
+{
+    /// Get the returned expression if this function is small enough to be
+    /// inlined. Inlined functions consist of a single return statement
+    /// without calls, assignments or uses of locals.
+    fn inline_body(&self) -> Option<&Expr>
+    {
+        if self.num_locals > 0 || self.stack_alloc_bp.is_some() || matches!(self.ret_type, Type::Void) {
+            return None;
+        }
+
+        let expr = match &self.body {
+            Stmt::Return(Some(expr)) => expr,
+            // Source lines don't generate code
+            Stmt::Block(stmts) => match stmts.iter().filter(|s| !matches!(s, Stmt::Line { .. })).collect::<Vec<_>>().as_slice() {
+                [Stmt::Return(Some(expr))] => expr,
+                _ => return None,
+            }
+            _ => return None,
+        };
+
+        let mut size = 0;
+        if expr.can_inline(&mut size) && size <= MAX_INLINE_SIZE {
+            Some(expr)
+        } else {
+            None
+        }
+    }
+}
+
+impl Stmt
+{
+    fn inline_calls(&mut self, inlinable: &HashMap<String, (usize, Expr)>, num_locals: &mut usize)
+    {
+        match self {
+            Stmt::Expr(expr) | Stmt::Return(Some(expr)) => expr.inline_calls(inlinable, num_locals),
+            Stmt::Return(None) | Stmt::Break | Stmt::Continue => {}
+            Stmt::Line { .. } => {}
+
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    stmt.inline_calls(inlinable, num_locals);
+                }
+            }
+
+            Stmt::If { test_expr, then_stmt, else_stmt } => {
+                test_expr.inline_calls(inlinable, num_locals);
+                then_stmt.inline_calls(inlinable, num_locals);
+                if let Some(else_stmt) = else_stmt {
+                    else_stmt.inline_calls(inlinable, num_locals);
+                }
+            }
+
+            Stmt::While { test_expr, body_stmt } | Stmt::DoWhile { body_stmt, test_expr } => {
+                test_expr.inline_calls(inlinable, num_locals);
+                body_stmt.inline_calls(inlinable, num_locals);
+            }
+
+            Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
+                if let Some(init_stmt) = init_stmt {
+                    init_stmt.inline_calls(inlinable, num_locals);
+                }
+                test_expr.inline_calls(inlinable, num_locals);
+                incr_expr.inline_calls(inlinable, num_locals);
+                body_stmt.inline_calls(inlinable, num_locals);
+            }
+
+            Stmt::Switch { test_expr, body, .. } => {
+                test_expr.inline_calls(inlinable, num_locals);
+                for stmt in body {
+                    stmt.inline_calls(inlinable, num_locals);
+                }
+            }
+        }
+    }
+}
+
+impl Expr
+{
+    /// Check that an expression can be copied into another function,
+    /// and count its nodes
+    fn can_inline(&self, size: &mut usize) -> bool
+    {
+        *size += 1;
+
+        match &self.kind {
+            ExprKind::Int(_) | ExprKind::Float32(_) | ExprKind::Arg(_) => true,
+            ExprKind::Global(_) | ExprKind::Addr(_) => true,
+            ExprKind::Local(_) | ExprKind::Assign { .. } | ExprKind::Call { .. } => false,
+
+            ExprKind::Cast(child) | ExprKind::Neg(child) | ExprKind::BitNot(child) | ExprKind::Not(child) => {
+                child.can_inline(size)
+            }
+
+            ExprKind::Load { addr, .. } => addr.can_inline(size),
+            ExprKind::Field { base, .. } => base.can_inline(size),
+            ExprKind::Binary { lhs, rhs, .. } => lhs.can_inline(size) && rhs.can_inline(size),
+
+            ExprKind::Ternary { test_expr, then_expr, else_expr } => {
+                test_expr.can_inline(size) && then_expr.can_inline(size) && else_expr.can_inline(size)
+            }
+
+            // Assembly that refers to the stack frame or defines labels
+            // can't be copied into another function
+            ExprKind::Asm { text, args } => {
+                let frame_ops = ["arg", "local", "ret", "enter", ":"];
+                !frame_ops.iter().any(|op| text.contains(op)) && args.iter().all(|arg| arg.can_inline(size))
+            }
+        }
+    }
+
+    /// Check that evaluating an expression has no side effects
+    fn is_pure(&self) -> bool
+    {
+        match &self.kind {
+            ExprKind::Assign { .. } | ExprKind::Call { .. } | ExprKind::Asm { .. } => false,
+            _ => {
+                let mut pure = true;
+                self.each_child(&mut |child| pure = pure && child.is_pure());
+                pure
+            }
+        }
+    }
+
+    fn has_asm(&self) -> bool
+    {
+        let mut found = matches!(self.kind, ExprKind::Asm { .. });
+        self.each_child(&mut |child| found = found || child.has_asm());
+        found
+    }
+
+    /// Count the uses of a function argument
+    fn arg_uses(&self, idx: usize) -> usize
+    {
+        let mut count = if let ExprKind::Arg(n) = self.kind { (n == idx) as usize } else { 0 };
+        self.each_child(&mut |child| count += child.arg_uses(idx));
+        count
+    }
+
+    fn each_child<F>(&self, f: &mut F) where F: FnMut(&Expr)
+    {
+        match &self.kind {
+            ExprKind::Int(_) | ExprKind::Float32(_) | ExprKind::Arg(_) | ExprKind::Local(_) => {}
+            ExprKind::Global(_) | ExprKind::Addr(_) => {}
+
+            ExprKind::Cast(child) | ExprKind::Neg(child) | ExprKind::BitNot(child) | ExprKind::Not(child) => f(child),
+            ExprKind::Load { addr, .. } => f(addr),
+            ExprKind::Field { base, .. } => f(base),
+
+            ExprKind::Binary { lhs, rhs, .. } => {
+                f(lhs);
+                f(rhs);
+            }
+
+            ExprKind::Assign { dst, value } => {
+                match dst {
+                    LValue::Mem { addr, .. } | LValue::Copy { addr, .. } => f(addr),
+                    LValue::Field { base, .. } => f(base),
+                    _ => {}
+                }
+                f(value);
+            }
+
+            ExprKind::Ternary { test_expr, then_expr, else_expr } => {
+                f(test_expr);
+                f(then_expr);
+                f(else_expr);
+            }
+
+            ExprKind::Call { args, .. } | ExprKind::Asm { args, .. } => {
+                for arg in args {
+                    f(arg);
+                }
+            }
+        }
+    }
+
+    fn each_child_mut<F>(&mut self, f: &mut F) where F: FnMut(&mut Expr)
+    {
+        match &mut self.kind {
+            ExprKind::Int(_) | ExprKind::Float32(_) | ExprKind::Arg(_) | ExprKind::Local(_) => {}
+            ExprKind::Global(_) | ExprKind::Addr(_) => {}
+
+            ExprKind::Cast(child) | ExprKind::Neg(child) | ExprKind::BitNot(child) | ExprKind::Not(child) => f(child),
+            ExprKind::Load { addr, .. } => f(addr),
+            ExprKind::Field { base, .. } => f(base),
+
+            ExprKind::Binary { lhs, rhs, .. } => {
+                f(lhs);
+                f(rhs);
+            }
+
+            ExprKind::Assign { dst, value } => {
+                match dst {
+                    LValue::Mem { addr, .. } | LValue::Copy { addr, .. } => f(addr),
+                    LValue::Field { base, .. } => f(base),
+                    _ => {}
+                }
+                f(value);
+            }
+
+            ExprKind::Ternary { test_expr, then_expr, else_expr } => {
+                f(test_expr);
+                f(then_expr);
+                f(else_expr);
+            }
+
+            ExprKind::Call { args, .. } | ExprKind::Asm { args, .. } => {
+                for arg in args {
+                    f(arg);
+                }
+            }
+        }
+    }
+
+    /// Replace the uses of function arguments by expressions
+    fn subst_args(&mut self, args: &[Expr])
+    {
+        if let ExprKind::Arg(idx) = self.kind {
+            *self = args[idx].clone();
+            return;
+        }
+
+        self.each_child_mut(&mut |child| child.subst_args(args));
+    }
+
+    fn inline_calls(&mut self, inlinable: &HashMap<String, (usize, Expr)>, num_locals: &mut usize)
+    {
+        self.each_child_mut(&mut |child| child.inline_calls(inlinable, num_locals));
+
+        let (name, args) = match &self.kind {
+            ExprKind::Call { name, args } => (name, args),
+            _ => return,
+        };
+
+        let (num_params, body) = match inlinable.get(name) {
+            Some((num_params, body)) if *num_params == args.len() => (num_params, body),
+            _ => return,
+        };
+
+        // Arguments are substituted into the body when that doesn't change
+        // the order or number of their evaluations. Otherwise, they're
+        // evaluated into new local slots before the body.
+        let all_pure = args.iter().all(|arg| arg.is_pure());
+        let body_has_asm = body.has_asm();
+
+        let mut substs = Vec::default();
+        let mut temps = Vec::default();
+
+        for (idx, arg) in args.iter().enumerate() {
+            let is_const = matches!(arg.kind, ExprKind::Int(_) | ExprKind::Float32(_));
+            let is_frame_var = matches!(arg.kind, ExprKind::Arg(_) | ExprKind::Local(_));
+            let is_leaf = is_frame_var || matches!(arg.kind, ExprKind::Global(_) | ExprKind::Addr(_));
+            let single_use = body.arg_uses(idx) <= 1;
+
+            let direct = is_const || (all_pure && (is_frame_var || (!body_has_asm && (single_use || is_leaf))));
+
+            if direct {
+                substs.push(arg.clone());
+            } else {
+                let slot = *num_locals + temps.len();
+                substs.push(Expr { t: arg.t.clone(), kind: ExprKind::Local(slot) });
+                temps.push((slot, arg.clone()));
+            }
+        }
+
+        if *num_locals + temps.len() > MAX_LOCALS {
+            return;
+        }
+        *num_locals += temps.len();
+        assert!(substs.len() == *num_params);
+
+        let mut inlined = body.clone();
+        inlined.subst_args(&substs);
+        inlined.t = self.t.clone();
+
+        // Evaluate the arguments in order, then the body
+        for (slot, arg) in temps.into_iter().rev() {
+            let t = inlined.t.clone();
+            let assign = Expr {
+                t: arg.t.clone(),
+                kind: ExprKind::Assign { dst: LValue::Local(slot), value: Box::new(arg) },
+            };
+
+            inlined = Expr {
+                t,
+                kind: ExprKind::Binary { op: BinOp::Comma, lhs: Box::new(assign), rhs: Box::new(inlined) },
+            };
+        }
+
+        *self = inlined;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parsing::Input;
+    use crate::parser::parse_unit;
+
+    fn compile(src: &str, profile: &str) -> Unit
+    {
+        let mut input = Input::new(src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        unit.resolve_types().unwrap();
+        unit.resolve_syms().unwrap();
+        unit.check_types().unwrap();
+        unit.insert_casts().unwrap();
+
+        let mut unit = unit.lower().unwrap();
+        unit.apply_profile(&Profile::parse(profile).unwrap());
+        unit
+    }
+
+    fn fun<'a>(unit: &'a Unit, name: &str) -> &'a Function
+    {
+        unit.fun_decls.iter().find(|f| f.name == name).unwrap()
+    }
+
+    fn has_call(stmt: &Stmt) -> bool
+    {
+        format!("{:?}", stmt).contains("Call")
+    }
+
+    #[test]
+    fn parse_profile()
+    {
+        let profile = Profile::parse("# comment\nmain 1 5000\nf 100 0\ng 10 3\n").unwrap();
+        assert!(profile.is_hot("main"));
+        assert!(profile.is_hot("f"));
+        assert!(!profile.is_hot("g"));
+        assert!(!profile.is_hot("h"));
+
+        assert!(Profile::parse("main 1").is_err());
+        assert!(Profile::parse("main x 1").is_err());
+    }
+
+    #[test]
+    fn inline_hot()
+    {
+        let src = "
+            int sq(int x) { return x * x; }
+            int add(int a, int b) { return a + b; }
+            int f(int n) { return sq(n) + add(n, 1); }
+            int g(int n) { return sq(n); }
+            int h(int n) { return sq(add(n, 1)); }
+        ";
+
+        // Only calls in hot functions are inlined
+        let unit = compile(src, "f 1000 0\ng 1 0\nh 1000 0");
+        assert!(fun(&unit, "f").hot);
+        assert!(!has_call(&fun(&unit, "f").body));
+        assert!(has_call(&fun(&unit, "g").body));
+        assert_eq!(fun(&unit, "f").num_locals, 0);
+
+        // The argument used twice is evaluated into a local
+        assert!(!has_call(&fun(&unit, "h").body));
+        assert_eq!(fun(&unit, "h").num_locals, 1);
+    }
+
+    #[test]
+    fn no_inline()
+    {
+        let src = "
+            int rec(int n) { return n? rec(n - 1):0; }
+            int local(int n) { int x = n; return x; }
+            int f(int n) { return rec(n) + local(n); }
+        ";
+
+        let unit = compile(src, "f 1000 0");
+        let body = format!("{:?}", fun(&unit, "f").body);
+        assert!(body.contains("\"rec\""));
+        assert!(body.contains("\"local\""));
+    }
+}
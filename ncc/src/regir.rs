@@ -0,0 +1,512 @@
+//! Optional register-based backend pass, enabled with -O.
+//!
+//! The stack code produced by codegen is lifted, one basic block at a
+//! time, into a mid-level IR where every value pushed on the stack is a
+//! virtual register defined by an instruction over other virtual registers.
+//! Stack shuffles (dup, swap, getn, pop) become register renamings, and
+//! values are numbered so that repeated pure computations share a register.
+//! Constants are folded while building the IR.
+//!
+//! A scheduler then emits stack code from the registers. Values stay
+//! unevaluated until something needs them on the stack, and values that are
+//! needed again while still on the stack are fetched with dup/getn instead
+//! of being recomputed. Instructions with unknown stack effects, labels and
+//! jumps end the current block.
+
+use std::collections::HashMap;
+
+/// Virtual register
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct VReg(usize);
+
+/// Machine state read by an instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Loc
+{
+    Local(usize),
+    Arg(usize),
+    Mem,
+}
+
+/// Instruction defining a virtual register
+#[derive(Clone, Debug)]
+struct Inst
+{
+    /// Instruction text, without the trailing semicolon
+    text: String,
+
+    /// Operands, with the first one being the deepest on the stack
+    args: Vec<VReg>,
+
+    /// Value of integer constants
+    val: Option<u64>,
+
+    /// State read by this instruction and its operands
+    reads: Vec<Loc>,
+}
+
+#[derive(Default)]
+struct Scheduler
+{
+    insts: Vec<Inst>,
+
+    /// Values on the virtual stack that have not been emitted yet.
+    /// These are always above the values on the real stack.
+    lazy: Vec<VReg>,
+
+    /// Known top part of the real stack, with the register each slot
+    /// holds, if any. Slots further down are unknown.
+    real: Vec<Option<VReg>>,
+
+    /// Value numbering of pure instructions in the current block
+    numbering: HashMap<(String, Vec<VReg>), VReg>,
+
+    out: String,
+}
+
+/// Optimize the stack code generated for a unit
+pub fn optimize(asm: &str) -> String
+{
+    let mut sched = Scheduler::default();
+
+    for line in asm.lines() {
+        sched.process_line(line);
+    }
+
+    sched.end_block();
+    sched.out
+}
+
+/// Split a line holding a single instruction into its opcode and operand
+fn parse_inst(line: &str) -> Option<(&str, &str)>
+{
+    let body = line.trim().strip_suffix(';')?;
+
+    if body.contains(';') || body.contains('"') {
+        return None;
+    }
+
+    let (op, arg) = body.split_once(' ').unwrap_or((body, ""));
+    let is_ident = !op.is_empty() && op.chars().all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '_');
+
+    if is_ident { Some((op, arg.trim())) } else { None }
+}
+
+/// Number of operands of instructions which push one value and
+/// do nothing else, or None for other instructions
+fn pure_arity(op: &str) -> Option<usize>
+{
+    match op {
+        "push" | "push_f32" | "push_f64" | "push_0" | "push_1" | "push_2" |
+        "push_i8" | "push_u32" | "push_u64" |
+        "get_local" | "get_arg" => return Some(0),
+
+        "not_u32" | "not_u64" |
+        "sx_i8_i32" | "sx_i8_i64" | "sx_i16_i32" | "sx_i16_i64" | "sx_i32_i64" |
+        "trunc_u8" | "trunc_u16" | "trunc_u32" |
+        "sin_f32" | "cos_f32" | "tan_f32" | "asin_f32" | "acos_f32" | "atan_f32" | "sqrt_f32" |
+        "floor_f32" | "ceil_f32" | "round_f32" | "is_nan_f32" | "is_inf_f32" |
+        "i32_to_f32" | "i64_to_f32" | "f32_to_i32" |
+        "sqrt_f64" | "floor_f64" | "ceil_f64" | "round_f64" | "is_nan_f64" | "is_inf_f64" |
+        "i64_to_f64" | "f64_to_i64" | "f32_to_f64" | "f64_to_f32" |
+        "load_u8" | "load_u16" | "load_u32" | "load_u64" => return Some(1),
+
+        "pow_f32" => return Some(2),
+
+        _ => {}
+    }
+
+    let (base, suffix) = op.rsplit_once('_')?;
+
+    let is_bin_op = matches!(base,
+        "and" | "or" | "xor" | "lshift" | "rshift" |
+        "add" | "sub" | "mul" | "div" | "mod" |
+        "eq" | "ne" | "lt" | "le" | "gt" | "ge"
+    );
+
+    if is_bin_op && matches!(suffix, "u32" | "i32" | "u64" | "i64" | "f32" | "f64") {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Evaluate a unary instruction on a constant, as the VM does
+fn fold_unary(op: &str, a: u64) -> Option<u64>
+{
+    Some(match op {
+        "not_u32" => !(a as u32) as u64,
+        "not_u64" => !a,
+        "sx_i8_i32" | "sx_i8_i64" => a as i8 as i64 as u64,
+        "sx_i16_i32" | "sx_i16_i64" => a as i16 as i64 as u64,
+        "sx_i32_i64" => a as i32 as i64 as u64,
+        "trunc_u8" => a as u8 as u64,
+        "trunc_u16" => a as u16 as u64,
+        "trunc_u32" => a as u32 as u64,
+        _ => return None
+    })
+}
+
+/// Evaluate a binary instruction on constants, as the VM does
+fn fold_binary(op: &str, a: u64, b: u64) -> Option<u64>
+{
+    let (a32, b32) = (a as u32, b as u32);
+
+    Some(match op {
+        "and_u32" => (a32 & b32) as u64,
+        "or_u32" => (a32 | b32) as u64,
+        "xor_u32" => (a32 ^ b32) as u64,
+        "lshift_u32" => a32.wrapping_shl(b32) as u64,
+        "rshift_u32" => a32.wrapping_shr(b32) as u64,
+        "add_u32" => a32.wrapping_add(b32) as u64,
+        "sub_u32" => a32.wrapping_sub(b32) as u64,
+        "mul_u32" => a32.wrapping_mul(b32) as u64,
+        "eq_u32" => (a32 == b32) as u64,
+        "ne_u32" => (a32 != b32) as u64,
+
+        "and_u64" => a & b,
+        "or_u64" => a | b,
+        "xor_u64" => a ^ b,
+        "lshift_u64" => a.wrapping_shl(b32),
+        "rshift_u64" => a.wrapping_shr(b32),
+        "add_u64" => a.wrapping_add(b),
+        "sub_u64" => a.wrapping_sub(b),
+        "mul_u64" => a.wrapping_mul(b),
+        "eq_u64" => (a == b) as u64,
+        "ne_u64" => (a != b) as u64,
+
+        _ => return None
+    })
+}
+
+impl Scheduler
+{
+    fn process_line(&mut self, line: &str)
+    {
+        let (op, arg) = match parse_inst(line) {
+            Some(inst) => inst,
+            None => return self.barrier(line),
+        };
+
+        let idx = arg.parse::<usize>().ok();
+
+        match (op, idx) {
+            ("pop", _) => {
+                // Unused values are never computed
+                if self.lazy.pop().is_none() {
+                    self.emit_line("pop");
+                    self.real.pop();
+                }
+            }
+
+            ("dup", _) => {
+                if let Some(&v) = self.lazy.last() {
+                    self.lazy.push(v);
+                } else {
+                    self.emit_line("dup");
+                    let top = self.real.last().copied().flatten();
+                    self.real.push(top);
+                }
+            }
+
+            ("swap", _) => {
+                let n = self.lazy.len();
+                if n >= 2 {
+                    self.lazy.swap(n - 1, n - 2);
+                } else {
+                    self.materialize(n);
+                    self.emit_line("swap");
+                    let n = self.real.len();
+                    if n >= 2 {
+                        self.real.swap(n - 1, n - 2);
+                    } else {
+                        self.real.clear();
+                    }
+                }
+            }
+
+            ("getn", Some(idx)) => {
+                if idx < self.lazy.len() {
+                    let v = self.lazy[self.lazy.len() - 1 - idx];
+                    self.lazy.push(v);
+                } else {
+                    self.materialize(self.lazy.len());
+                    self.emit_line(&format!("getn {}", idx));
+                    let slot = self.real.len().checked_sub(idx + 1).and_then(|i| self.real[i]);
+                    self.real.push(slot);
+                }
+            }
+
+            ("set_local", Some(idx)) => self.effect(line, 1, Some(Loc::Local(idx))),
+            ("set_arg", Some(idx)) => self.effect(line, 1, Some(Loc::Arg(idx))),
+            ("store_u8" | "store_u16" | "store_u32" | "store_u64", _) => self.effect(line, 2, Some(Loc::Mem)),
+
+            ("get_local", Some(idx)) => self.pure_op(op, arg, 0, Some(Loc::Local(idx))),
+            ("get_arg", Some(idx)) => self.pure_op(op, arg, 0, Some(Loc::Arg(idx))),
+            ("get_local" | "get_arg", None) => self.barrier(line),
+
+            _ => {
+                match pure_arity(op) {
+                    Some(arity) => {
+                        let reads = if op.starts_with("load_") { Some(Loc::Mem) } else { None };
+                        self.pure_op(op, arg, arity, reads)
+                    }
+                    None => self.barrier(line),
+                }
+            }
+        }
+    }
+
+    /// Instruction with unknown effects, or the end of a basic block
+    fn barrier(&mut self, line: &str)
+    {
+        self.end_block();
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn end_block(&mut self)
+    {
+        self.materialize(self.lazy.len());
+        self.real.clear();
+        self.numbering.clear();
+    }
+
+    fn emit_line(&mut self, text: &str)
+    {
+        self.out.push_str(text);
+        self.out.push_str(";\n");
+    }
+
+    /// Pop operands off the virtual stack. Returns the operands that
+    /// haven't been emitted yet, and the number of operands that
+    /// are already on the real stack, below them.
+    fn take_operands(&mut self, arity: usize) -> (Vec<VReg>, usize)
+    {
+        let num_lazy = arity.min(self.lazy.len());
+        let lazy = self.lazy.split_off(self.lazy.len() - num_lazy);
+        (lazy, arity - num_lazy)
+    }
+
+    /// Instruction which pushes one value computed from its operands
+    fn pure_op(&mut self, op: &str, arg: &str, arity: usize, reads: Option<Loc>)
+    {
+        let text = if arg.is_empty() { op.to_string() } else { format!("{} {}", op, arg) };
+        let (args, num_real) = self.take_operands(arity);
+
+        // If some operands are already on the real stack,
+        // this has to be computed right away
+        if num_real > 0 {
+            for &v in &args {
+                self.emit(v);
+            }
+            self.emit_line(&text);
+            self.pop_real(arity);
+            self.real.push(None);
+            return;
+        }
+
+        let v = self.define(text, args, reads);
+        self.lazy.push(v);
+    }
+
+    /// Instruction which pops its operands and changes machine state
+    fn effect(&mut self, line: &str, arity: usize, clobbers: Option<Loc>)
+    {
+        let (args, num_real) = self.take_operands(arity);
+
+        // Values which read the clobbered state have to be computed first,
+        // and so do shared values that are needed again later
+        let mut num_early = 0;
+        for (i, &v) in self.lazy.iter().enumerate() {
+            let clobbered = clobbers.is_some_and(|loc| self.insts[v.0].reads.contains(&loc));
+            let shared = args.contains(&v) && !self.insts[v.0].args.is_empty();
+            if clobbered || shared {
+                num_early = i + 1;
+            }
+        }
+        self.materialize(num_early);
+
+        for &v in &args {
+            self.emit(v);
+        }
+        self.emit_line(line.trim().trim_end_matches(';'));
+        self.pop_real(args.len() + num_real);
+
+        if let Some(loc) = clobbers {
+            let insts = &self.insts;
+            self.numbering.retain(|_, v| !insts[v.0].reads.contains(&loc));
+        }
+    }
+
+    fn pop_real(&mut self, n: usize)
+    {
+        let n = n.min(self.real.len());
+        self.real.truncate(self.real.len() - n);
+    }
+
+    /// Emit the bottom n values of the virtual stack onto the real stack
+    fn materialize(&mut self, n: usize)
+    {
+        let vals: Vec<VReg> = self.lazy.drain(..n).collect();
+        for v in vals {
+            self.emit(v);
+        }
+    }
+
+    /// Emit code pushing the value of a register on the real stack
+    fn emit(&mut self, v: VReg)
+    {
+        let inst = self.insts[v.0].clone();
+
+        // Reuse a copy that's still on the stack rather than recomputing it
+        if !inst.args.is_empty() {
+            if let Some(depth) = self.real.iter().rev().position(|&slot| slot == Some(v)) {
+                if depth == 0 {
+                    self.emit_line("dup");
+                } else if depth <= u8::MAX as usize {
+                    self.emit_line(&format!("getn {}", depth));
+                }
+
+                if depth <= u8::MAX as usize {
+                    self.real.push(Some(v));
+                    return;
+                }
+            }
+        }
+
+        for &arg in &inst.args {
+            self.emit(arg);
+        }
+        self.emit_line(&inst.text);
+        self.pop_real(inst.args.len());
+        self.real.push(Some(v));
+    }
+
+    /// Get a register for the result of a pure instruction,
+    /// folding constants and reusing a previously computed value
+    fn define(&mut self, text: String, args: Vec<VReg>, reads: Option<Loc>) -> VReg
+    {
+        let (op, arg) = text.split_once(' ').unwrap_or((&text, ""));
+        let vals: Vec<Option<u64>> = args.iter().map(|v| self.insts[v.0].val).collect();
+
+        let val = match vals.as_slice() {
+            [] if op == "push" => arg.parse::<u64>().ok().or(arg.parse::<i64>().ok().map(|v| v as u64)),
+            [Some(a)] => fold_unary(op, *a),
+            [Some(a), Some(b)] => fold_binary(op, *a, *b),
+            _ => None,
+        };
+
+        if let Some(val) = val {
+            if !args.is_empty() {
+                return self.define(format!("push {}", val as i64), Vec::default(), None);
+            }
+        }
+
+        // Operations with no effect on 64-bit values
+        match (op, vals.as_slice()) {
+            ("add_u64" | "sub_u64" | "or_u64" | "xor_u64" | "lshift_u64" | "rshift_u64" | "rshift_i64", [_, Some(0)]) |
+            ("mul_u64" | "div_u64" | "div_i64", [_, Some(1)]) => return args[0],
+            ("add_u64" | "or_u64" | "xor_u64", [Some(0), _]) |
+            ("mul_u64", [Some(1), _]) => return args[1],
+            _ => {}
+        }
+
+        let key = (text, args);
+        if let Some(&v) = self.numbering.get(&key) {
+            return v;
+        }
+
+        let (text, args) = key.clone();
+        let mut all_reads: Vec<Loc> = reads.into_iter().collect();
+        for arg in &args {
+            for &loc in &self.insts[arg.0].reads {
+                if !all_reads.contains(&loc) {
+                    all_reads.push(loc);
+                }
+            }
+        }
+
+        let v = VReg(self.insts.len());
+        self.insts.push(Inst { text, args, val, reads: all_reads });
+        self.numbering.insert(key, v);
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn opt(src: &str) -> String
+    {
+        let src = src.replace("; ", ";\n");
+        optimize(&src).replace(";\n", "; ").trim().to_string()
+    }
+
+    #[test]
+    fn fold_constants()
+    {
+        assert_eq!(opt("push 2; push 3; add_u64; ret;"), "push 5; ret;");
+        assert_eq!(opt("push 1; push 2; sub_u64; ret;"), "push -1; ret;");
+        assert_eq!(opt("push 300; trunc_u8; ret;"), "push 44; ret;");
+        assert_eq!(opt("push 255; sx_i8_i64; ret;"), "push -1; ret;");
+        assert_eq!(opt("get_arg 0; push 0; lshift_u64; ret;"), "get_arg 0; ret;");
+        assert_eq!(opt("get_arg 0; push 0; add_u32; ret;"), "get_arg 0; push 0; add_u32; ret;");
+    }
+
+    #[test]
+    fn shuffles()
+    {
+        // Dead values are never computed
+        assert_eq!(opt("get_arg 0; get_arg 1; pop; ret;"), "get_arg 0; ret;");
+        assert_eq!(opt("get_arg 0; get_arg 1; swap; sub_u64; ret;"), "get_arg 1; get_arg 0; sub_u64; ret;");
+        assert_eq!(opt("get_arg 0; get_arg 1; getn 1; add_u64; add_u64; ret;"), "get_arg 0; get_arg 1; get_arg 0; add_u64; add_u64; ret;");
+
+        // Values from a previous block are on the real stack
+        assert_eq!(opt("L: push 1; add_u64; ret;"), "L: push 1; add_u64; ret;");
+        assert_eq!(opt("L: swap; pop; ret;"), "L: swap; pop; ret;");
+        assert_eq!(opt("L: push 1; swap; ret;"), "L: push 1; swap; ret;");
+    }
+
+    #[test]
+    fn shared_values()
+    {
+        // Common subexpressions are computed once
+        assert_eq!(
+            opt("get_arg 0; load_u32; get_arg 0; load_u32; mul_u32; ret;"),
+            "get_arg 0; load_u32; dup; mul_u32; ret;"
+        );
+        assert_eq!(
+            opt("get_arg 0; push 8; add_u64; get_arg 0; push 8; add_u64; load_u64; push 1; add_u64; store_u64;"),
+            "get_arg 0; push 8; add_u64; dup; load_u64; push 1; add_u64; store_u64;"
+        );
+
+        // Values used after an assignment are computed only once
+        assert_eq!(
+            opt("get_arg 0; get_arg 1; mul_u64; dup; set_local 0; ret;"),
+            "get_arg 0; get_arg 1; mul_u64; dup; set_local 0; ret;"
+        );
+    }
+
+    #[test]
+    fn clobbers()
+    {
+        // Reads are evaluated before writes to the same location
+        assert_eq!(
+            opt("get_local 0; push 1; set_local 0; ret;"),
+            "get_local 0; push 1; set_local 0; ret;"
+        );
+        assert_eq!(
+            opt("get_local 1; get_local 0; load_u8; push 0; push 7; store_u8; get_local 0; load_u8; add_u64; add_u64; ret;"),
+            "get_local 1; get_local 0; load_u8; push 0; push 7; store_u8; get_local 0; load_u8; add_u64; add_u64; ret;"
+        );
+
+        // Unrelated writes don't force evaluation
+        assert_eq!(
+            opt("get_local 1; push 1; add_u64; push 2; set_local 0; ret;"),
+            "push 2; set_local 0; get_local 1; push 1; add_u64; ret;"
+        );
+    }
+}
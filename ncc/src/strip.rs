@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::*;
+
+impl Unit
+{
+    /// Remove the functions and global variables that can't be reached
+    /// from the main function, so that including the whole standard
+    /// library doesn't bloat every program.
+    /// Units without a main function are left untouched.
+    pub fn strip_unused(&mut self)
+    {
+        if !self.fun_decls.iter().any(|f| f.name == "main") {
+            return;
+        }
+
+        // Symbols referenced by each function and global variable
+        let mut refs: HashMap<String, Vec<String>> = HashMap::default();
+
+        for fun in &self.fun_decls {
+            let mut names = Vec::default();
+            fun.body.collect_refs(&mut names);
+            refs.entry(fun.name.clone()).or_default().append(&mut names);
+        }
+
+        for global in &self.global_vars {
+            let mut names = Vec::default();
+            if let Some(init_expr) = &global.init_expr {
+                init_expr.collect_refs(&mut names);
+            }
+            refs.entry(global.name.clone()).or_default().append(&mut names);
+        }
+
+        // Find all symbols reachable from main
+        let mut live: HashSet<String> = HashSet::default();
+        let mut worklist = vec!["main".to_string()];
+        live.insert("main".to_string());
+
+        while let Some(name) = worklist.pop() {
+            for dep in refs.get(&name).into_iter().flatten() {
+                if live.insert(dep.clone()) {
+                    worklist.push(dep.clone());
+                }
+            }
+        }
+
+        self.fun_decls.retain(|f| live.contains(&f.name));
+        self.global_vars.retain(|g| live.contains(&g.name));
+
+        // The stripped functions may have been the only users of stack allocation
+        self.stack_alloc = self.fun_decls.iter().any(|f| f.stack_alloc_bp.is_some());
+    }
+}
+
+impl Stmt
+{
+    /// Collect the names of the global symbols referenced by a statement
+    fn collect_refs(&self, names: &mut Vec<String>)
+    {
+        match self {
+            Stmt::Expr(expr) => expr.collect_refs(names),
+            Stmt::ReturnExpr(expr) => expr.collect_refs(names),
+            Stmt::ReturnVoid | Stmt::Break | Stmt::Continue => {}
+            Stmt::Case(_) | Stmt::Default => {}
+            Stmt::Line { .. } => {}
+
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    stmt.collect_refs(names);
+                }
+            }
+
+            Stmt::If { test_expr, then_stmt, else_stmt } => {
+                test_expr.collect_refs(names);
+                then_stmt.collect_refs(names);
+                if let Some(else_stmt) = else_stmt {
+                    else_stmt.collect_refs(names);
+                }
+            }
+
+            Stmt::While { test_expr, body_stmt } |
+            Stmt::DoWhile { body_stmt, test_expr } |
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.collect_refs(names);
+                body_stmt.collect_refs(names);
+            }
+
+            Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
+                if let Some(init_stmt) = init_stmt {
+                    init_stmt.collect_refs(names);
+                }
+                test_expr.collect_refs(names);
+                incr_expr.collect_refs(names);
+                body_stmt.collect_refs(names);
+            }
+
+            Stmt::VarDecl { init_expr, .. } => {
+                if let Some(init_expr) = init_expr {
+                    init_expr.collect_refs(names);
+                }
+            }
+        }
+    }
+}
+
+impl Expr
+{
+    /// Collect the names of the global symbols referenced by an expression
+    fn collect_refs(&self, names: &mut Vec<String>)
+    {
+        match self {
+            Expr::Int(_) | Expr::String(_) | Expr::Float32(_) => {}
+            Expr::SizeofType { .. } => {}
+
+            Expr::Ident(name) => names.push(name.clone()),
+
+            Expr::Ref(Decl::Global { name, .. }) | Expr::Ref(Decl::Fun { name, .. }) => {
+                names.push(name.clone());
+            }
+            Expr::Ref(_) => {}
+
+            Expr::Array(exprs) => {
+                for expr in exprs {
+                    expr.collect_refs(names);
+                }
+            }
+
+            Expr::Cast { child, .. } |
+            Expr::SizeofExpr { child } |
+            Expr::Unary { child, .. } => child.collect_refs(names),
+
+            Expr::Arrow { base, .. } => base.collect_refs(names),
+
+            Expr::Binary { lhs, rhs, .. } |
+            Expr::CompoundAssign { lhs, rhs, .. } => {
+                lhs.collect_refs(names);
+                rhs.collect_refs(names);
+            }
+
+            Expr::Ternary { test_expr, then_expr, else_expr } => {
+                test_expr.collect_refs(names);
+                then_expr.collect_refs(names);
+                else_expr.collect_refs(names);
+            }
+
+            Expr::Call { callee, args } => {
+                callee.collect_refs(names);
+                for arg in args {
+                    arg.collect_refs(names);
+                }
+            }
+
+            Expr::Asm { text, args, .. } => {
+                // Inline assembly may refer to any label by name,
+                // so we conservatively keep every symbol it mentions
+                let idents = text.split(|c: char| !c.is_alphanumeric() && c != '_');
+                names.extend(idents.filter(|s| !s.is_empty()).map(|s| s.to_string()));
+
+                for arg in args {
+                    arg.collect_refs(names);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::parsing::Input;
+    use crate::parser::parse_unit;
+
+    fn strip(src: &str) -> (Vec<String>, Vec<String>)
+    {
+        let mut input = Input::new(src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        unit.resolve_types().unwrap();
+        unit.resolve_syms().unwrap();
+        unit.strip_unused();
+
+        (
+            unit.fun_decls.iter().map(|f| f.name.clone()).collect(),
+            unit.global_vars.iter().map(|g| g.name.clone()).collect(),
+        )
+    }
+
+    #[test]
+    fn unused_fns()
+    {
+        let (funs, _) = strip("void foo() {} void bar() {} void main() { bar(); }");
+        assert_eq!(funs, ["bar", "main"]);
+
+        // Transitive calls and function pointers are kept
+        let (funs, _) = strip("
+            void a() {} void b() { a(); } void c() {} void d() {}
+            void main() { void* p = (void*)c; b(); }
+        ");
+        assert_eq!(funs, ["a", "b", "c", "main"]);
+
+        // Without a main function, nothing is removed
+        let (funs, _) = strip("void foo() {} void bar() {}");
+        assert_eq!(funs, ["foo", "bar"]);
+    }
+
+    #[test]
+    fn unused_globals()
+    {
+        let (_, globals) = strip("
+            int x = 1; int y = 2; int z = 3;
+            int f() { return y; }
+            int main() { asm () -> void { push z; pop; }; return x; }
+        ");
+        assert_eq!(globals, ["x", "z"]);
+
+        // String constants are only kept if used
+        let (_, globals) = strip("
+            char* f() { return \"unused\"; }
+            char* main() { return \"used\"; }
+        ");
+        assert_eq!(globals.len(), 1);
+    }
+}
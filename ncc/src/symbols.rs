@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cmp::max;
 use crate::ast::*;
 use crate::parsing::{ParseError};
@@ -33,6 +33,9 @@ struct Env
 
     /// Map of strings to global symbols
     string_tbl: HashMap<String, Decl>,
+
+    /// Names of the thread-local global variables
+    thread_locals: HashSet<String>,
 }
 
 impl Env
@@ -113,6 +116,26 @@ impl Env
         top_scope.decls.insert(name.to_string(), decl);
     }
 
+    /// Define a local variable with no name in the topmost scope,
+    /// to hold an intermediate value
+    fn define_temp(&mut self, var_type: Type) -> Decl
+    {
+        let num_scopes = self.scopes.len();
+        let top_scope = &mut self.scopes[num_scopes - 1];
+
+        let decl = Decl::Local {
+            idx: top_scope.next_idx,
+            t: var_type,
+        };
+
+        top_scope.next_idx += 1;
+        if top_scope.next_idx > self.num_locals {
+            self.num_locals = top_scope.next_idx;
+        }
+
+        decl
+    }
+
     /// Define a new entity in the topmost scope
     fn define(&mut self, name: &str, decl: Decl)
     {
@@ -193,6 +216,10 @@ impl Unit
                 t: global.var_type.clone(),
             });
 
+            if global.thread_local {
+                env.thread_locals.insert(global.name.clone());
+            }
+
             // Resolve symbols in global variable initializers
             if let Some(init_expr) = &mut global.init_expr {
                 init_expr.resolve_syms(&mut env)?
@@ -222,7 +249,9 @@ impl Unit
                 self.global_vars.push(Global {
                     name: name.clone(),
                     var_type: t.clone(),
-                    init_expr: Some(Expr::String(str_const.clone()))
+                    init_expr: Some(Expr::String(str_const.clone())),
+                    interned: true,
+                    thread_local: false,
                 });
             }
         }
@@ -235,6 +264,161 @@ impl Unit
     }
 }
 
+/// Produce an expression referencing the current thread's instance of
+/// a thread-local variable. The TLS block of each thread is a copy of the
+/// template laid out in the data section, so the address of the variable is
+/// the TLS base plus the offset of its label from the template, which we
+/// store in __TLS_DELTA__ once the space is allocated.
+fn thread_local_ref(name: &str, t: Type) -> Expr
+{
+    let text = format!(
+        "syscall tls_base;\npush __TLS_DELTA__;\nload_u64;\nadd_u64;\npush {};\nadd_u64;",
+        name
+    );
+
+    Expr::Unary {
+        op: UnOp::Deref,
+        child: Box::new(Expr::Asm {
+            text,
+            args: Vec::default(),
+            out_type: Type::Pointer(Box::new(t)),
+        })
+    }
+}
+
+/// Transform a compound assignment into a = a op b, where the address
+/// of the target is evaluated once and kept in a temporary local if
+/// computing it could have side effects, as in a[f()] += 1
+fn compound_assign(op: BinOp, lhs: &Expr, rhs: &Expr, env: &mut Env) -> Result<Expr, ParseError>
+{
+    let assign = |target: Expr| Expr::Binary {
+        op: BinOp::Assign,
+        lhs: Box::new(target.clone()),
+        rhs: Box::new(Expr::Binary {
+            op,
+            lhs: Box::new(target),
+            rhs: Box::new(rhs.clone()),
+        }),
+    };
+
+    // The pointer the target is accessed through, if any
+    let ptr = match lhs {
+        Expr::Unary { op: UnOp::Deref, child } => child,
+        Expr::Arrow { base, .. } => base,
+        _ => return Ok(assign(lhs.clone())),
+    };
+
+    if let Expr::Ref(_) = ptr.as_ref() {
+        return Ok(assign(lhs.clone()));
+    }
+
+    // (t = ptr, *t = *t op b)
+    let temp = Expr::Ref(env.define_temp(ptr.eval_type()?));
+    let target = match lhs {
+        Expr::Arrow { field, .. } => Expr::Arrow { base: Box::new(temp.clone()), field: field.clone() },
+        _ => Expr::Unary { op: UnOp::Deref, child: Box::new(temp.clone()) },
+    };
+
+    Ok(Expr::Binary {
+        op: BinOp::Comma,
+        lhs: Box::new(Expr::Binary {
+            op: BinOp::Assign,
+            lhs: Box::new(temp),
+            rhs: ptr.clone(),
+        }),
+        rhs: Box::new(assign(target)),
+    })
+}
+
+/// Check if an initializer gives a value to every element of a variable
+fn init_fills(t: &Type, init_expr: &Expr) -> bool
+{
+    match (t, init_expr) {
+        (Type::Array { elem_type, .. }, Expr::Array(elems)) => {
+            elems.len() == t.sizeof() / elem_type.sizeof() &&
+            elems.iter().all(|elem| init_fills(elem_type, elem))
+        }
+
+        (Type::Struct { fields }, Expr::Array(elems)) => {
+            elems.len() == fields.len() &&
+            fields.iter().zip(elems).all(|((_, field_type), elem)| init_fills(field_type, elem))
+        }
+
+        _ => true
+    }
+}
+
+/// Generate the assignments which initialize a local variable, or an
+/// element or field of one, to the values of an initializer which can
+/// include expressions computed at run time
+fn init_stmts(target: Expr, t: &Type, init_expr: &Expr, stmts: &mut Vec<Stmt>) -> Result<(), ParseError>
+{
+    match (t, init_expr) {
+        (Type::Array { elem_type, .. }, Expr::Array(elems)) => {
+            let num_elems = t.sizeof() / elem_type.sizeof();
+            if elems.len() > num_elems {
+                return ParseError::msg_only("E0020", &format!(
+                    "too many initializers for array of {} elements", num_elems
+                ));
+            }
+
+            // target[idx] = elem
+            for (idx, elem) in elems.iter().enumerate() {
+                let elem_target = Expr::Unary {
+                    op: UnOp::Deref,
+                    child: Box::new(Expr::Binary {
+                        op: BinOp::Add,
+                        lhs: Box::new(target.clone()),
+                        rhs: Box::new(Expr::Int(idx as i128)),
+                    }),
+                };
+
+                init_stmts(elem_target, elem_type, elem, stmts)?;
+            }
+        }
+
+        (Type::Struct { fields }, Expr::Array(elems)) => {
+            if elems.len() > fields.len() {
+                return ParseError::msg_only("E0020", &format!(
+                    "too many initializers for struct with {} fields", fields.len()
+                ));
+            }
+
+            // (&target)->field = elem
+            for ((field_name, field_type), elem) in fields.iter().zip(elems) {
+                let field_target = Expr::Arrow {
+                    base: Box::new(Expr::Unary {
+                        op: UnOp::AddressOf,
+                        child: Box::new(target.clone()),
+                    }),
+                    field: field_name.clone(),
+                };
+
+                init_stmts(field_target, field_type, elem, stmts)?;
+            }
+        }
+
+        (Type::Array { .. }, _) => {
+            return ParseError::msg_only("E0020", "local array variables can only be initialized with an initializer list");
+        }
+
+        (_, Expr::Array(_)) => {
+            return ParseError::msg_only("E0020", &format!("initializer list for a value of type {}", t));
+        }
+
+        // Scalars, and structs copied from another struct
+        _ => {
+            stmts.push(Stmt::Expr(Expr::Binary {
+                op: BinOp::Assign,
+                lhs: Box::new(target),
+                rhs: Box::new(init_expr.clone()),
+            }));
+        }
+    }
+
+    Ok(())
+}
+
 impl Function
 {
     fn resolve_syms(&mut self, env: &mut Env) -> Result<(), ParseError>
@@ -256,7 +440,7 @@ impl Function
         // define a base pointer local
         self.body.each_stmt(&mut |stmt| {
             if let Stmt::VarDecl { var_type, .. } = stmt {
-                if let Type::Array { .. } = var_type {
+                if let Type::Array { .. } | Type::Struct { .. } = var_type {
                     if env.stack_alloc_bp.is_none() {
                         env.define_bp();
                     }
@@ -264,8 +448,52 @@ impl Function
             }
         });
 
+        // Struct arguments are passed by address, and copied into the
+        // stack allocation on entry, so that they are passed by value
+        let mut copy_stmts = Vec::default();
+        for (idx, (param_type, _)) in self.params.iter().enumerate() {
+            if let Type::Struct { .. } = param_type {
+                if env.stack_alloc_bp.is_none() {
+                    env.define_bp();
+                }
+
+                let byte_ptr_t = Type::Pointer(Box::new(Type::UInt(8)));
+                let offset = env.alloc(param_type.sizeof());
+                let bp_idx = env.stack_alloc_bp.unwrap();
+                let copy_addr = Expr::Binary {
+                    op: BinOp::Add,
+                    lhs: Box::new(Expr::Ref(Decl::Local { idx: bp_idx, t: byte_ptr_t.clone() })),
+                    rhs: Box::new(Expr::Int(offset as i128)),
+                };
+
+                // *(struct_t*)copy_addr = arg
+                copy_stmts.push(Stmt::Expr(Expr::Binary {
+                    op: BinOp::Assign,
+                    lhs: Box::new(Expr::Unary {
+                        op: UnOp::Deref,
+                        child: Box::new(Expr::Cast {
+                            new_type: Type::Pointer(Box::new(param_type.clone())),
+                            child: Box::new(copy_addr.clone()),
+                        }),
+                    }),
+                    rhs: Box::new(Expr::Ref(Decl::Arg { idx, t: param_type.clone() })),
+                }));
+
+                // Change the arg type for the assignment of the copy address
+                copy_stmts.push(Stmt::Expr(Expr::Binary {
+                    op: BinOp::Assign,
+                    lhs: Box::new(Expr::Ref(Decl::Arg { idx, t: byte_ptr_t })),
+                    rhs: Box::new(copy_addr),
+                }));
+            }
+        }
+
         self.body.resolve_syms(env)?;
 
+        if let Stmt::Block(stmts) = &mut self.body {
+            stmts.splice(0..0, copy_stmts);
+        }
+
         env.pop_scope();
 
         // Set the local variable slot count for the function
@@ -288,6 +516,10 @@ impl Stmt
 
             Stmt::Break | Stmt::Continue => {}
 
+            Stmt::Case(_) | Stmt::Default => {}
+
+            Stmt::Line { .. } => {}
+
             Stmt::ReturnVoid => {}
 
             Stmt::ReturnExpr(expr) => {
@@ -313,6 +545,11 @@ impl Stmt
                 body_stmt.resolve_syms(env)?;
             }
 
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.resolve_syms(env)?;
+                body_stmt.resolve_syms(env)?;
+            }
+
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 env.push_scope();
 
@@ -335,12 +572,8 @@ impl Stmt
                 let decl = env.lookup(var_name).unwrap();
                 let mut ref_expr = Expr::Ref(decl);
 
-                // If this is an array, which will be stack-allocated
-                if let Type::Array { elem_type, .. } = var_type {
-                    if init_expr.is_some() {
-                        return ParseError::msg_only("initialization of local array variables not yet implemented");
-                    }
-
+                // If this is an array or struct, which will be stack-allocated
+                if let Type::Array { .. } | Type::Struct { .. } = var_type {
                     let byte_ptr_t = Type::Pointer(Box::new(Type::UInt(8)));
 
                     // Change the lhs ref type for the assignment
@@ -366,7 +599,28 @@ impl Stmt
                         rhs: Box::new(bp_sub),
                     };
 
-                    *self = Stmt::Expr(assign_expr);
+                    let Some(init_expr) = init_expr else {
+                        *self = Stmt::Expr(assign_expr);
+                        return Ok(());
+                    };
+
+                    init_expr.resolve_syms(env)?;
+
+                    // Store the initial values into the stack allocation
+                    let var_ref = Expr::Ref(env.lookup(var_name).unwrap());
+                    let mut stmts = vec![Stmt::Expr(assign_expr)];
+
+                    // Elements missing from initializer lists are zeroed
+                    if matches!(init_expr, Expr::Array(_)) && !init_fills(var_type, init_expr) {
+                        stmts.push(Stmt::Expr(Expr::Asm {
+                            text: "syscall memset;".to_string(),
+                            args: vec![var_ref.clone(), Expr::Int(0), Expr::Int(num_bytes as i128)],
+                            out_type: Type::Void,
+                        }));
+                    }
+
+                    init_stmts(var_ref, var_type, init_expr, &mut stmts)?;
+                    *self = Stmt::Block(stmts);
 
                     return Ok(());
                 }
@@ -428,11 +682,16 @@ impl Expr
                 //dbg!(&name);
 
                 if let Some(decl) = env.lookup(name) {
-                    *self = Expr::Ref(decl);
+                    *self = match decl {
+                        Decl::Global { name, t } if env.thread_locals.contains(&name) => {
+                            thread_local_ref(&name, t)
+                        }
+                        _ => Expr::Ref(decl)
+                    };
                 }
                 else
                 {
-                    return ParseError::msg_only(&format!("reference to undeclared identifier \"{}\"", name));
+                    return ParseError::msg_only("E0008", &format!("reference to undeclared identifier \"{}\"", name));
                 }
             }
 
@@ -461,6 +720,12 @@ impl Expr
                 rhs.as_mut().resolve_syms(env)?;
             }
 
+            Expr::CompoundAssign { op, lhs, rhs } => {
+                lhs.as_mut().resolve_syms(env)?;
+                rhs.as_mut().resolve_syms(env)?;
+                *self = compound_assign(*op, lhs, rhs, env)?;
+            }
+
             Expr::Ternary { test_expr, then_expr, else_expr } => {
                 test_expr.as_mut().resolve_syms(env)?;
                 then_expr.as_mut().resolve_syms(env)?;
@@ -503,6 +768,16 @@ mod tests
         unit.resolve_syms().unwrap();
     }
 
+    fn parse_err(src: &str)
+    {
+        use crate::parsing::Input;
+        use crate::parser::parse_unit;
+
+        let mut input = Input::new(src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        assert!(unit.resolve_syms().is_err());
+    }
+
     fn parse_file(file_name: &str)
     {
         dbg!(file_name);
@@ -551,4 +826,13 @@ mod tests
     {
         parse_ok("void foo() {} void main() { foo(); }");
     }
+
+    #[test]
+    fn local_init()
+    {
+        parse_ok("u64 f() { return 1; } void main() { u64 a[2] = { f(), 2 }; }");
+        parse_ok("void main(u64 x) { u64 a[2][2] = { { x, 1 }, { 2 } }; }");
+        parse_err("void main() { u64 a[2] = { 1, 2, 3 }; }");
+        parse_err("void main() { u64 a[2] = 1; }");
+    }
 }
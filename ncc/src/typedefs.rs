@@ -14,7 +14,7 @@ fn resolve_types(
         Type::Named(name) => {
             if let Some(inside_def) = inside_def {
                 if name == inside_def {
-                    return ParseError::msg_only(&format!("recursive instance of \"{}\" in typedef", name));
+                    return ParseError::msg_only("E0007", &format!("recursive instance of \"{}\" in typedef", name));
                 }
             }
 
@@ -24,7 +24,7 @@ fn resolve_types(
             }
             else
             {
-                return ParseError::msg_only(&format!("reference to unknown type \"{}\"", name));
+                return ParseError::msg_only("E0006", &format!("reference to unknown type \"{}\"", name));
             }
         }
 
@@ -42,7 +42,7 @@ fn resolve_types(
                 }
                 else
                 {
-                    return ParseError::msg_only(&format!("reference to unknown type \"{}\"", name));
+                    return ParseError::msg_only("E0006", &format!("reference to unknown type \"{}\"", name));
                 }
             }
 
@@ -140,6 +140,10 @@ impl Stmt
 
             Stmt::Break | Stmt::Continue => {}
 
+            Stmt::Case(_) | Stmt::Default => {}
+
+            Stmt::Line { .. } => {}
+
             Stmt::ReturnVoid => {}
 
             Stmt::ReturnExpr(expr) => {
@@ -165,6 +169,11 @@ impl Stmt
                 body_stmt.resolve_types(typedefs)?;
             }
 
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.resolve_types(typedefs)?;
+                body_stmt.resolve_types(typedefs)?;
+            }
+
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 if init_stmt.is_some() {
                     init_stmt.as_mut().unwrap().resolve_types(typedefs)?;
@@ -223,7 +232,7 @@ impl Expr
                     }
                     else
                     {
-                        return ParseError::msg_only(&format!("reference to unknown type \"{}\" in cast expression", name));
+                        return ParseError::msg_only("E0006", &format!("reference to unknown type \"{}\" in cast expression", name));
                     }
                 }
                 else
@@ -265,7 +274,8 @@ impl Expr
                 child.as_mut().resolve_types(typedefs)?;
             }
 
-            Expr::Binary { op, lhs, rhs } => {
+            Expr::Binary { op, lhs, rhs } |
+            Expr::CompoundAssign { op, lhs, rhs } => {
                 lhs.as_mut().resolve_types(typedefs)?;
                 rhs.as_mut().resolve_types(typedefs)?;
             }
@@ -6,8 +6,8 @@ use Type::*;
 // TODO: we should probably automatically insert type promotions
 // and type casting operations in assignments
 
-/// Check if a value of one type can be assigned to another
-fn assign_compat(lhs_type: &Type, rhs_type: &Type) -> bool
+/// Check if a value can be assigned to a variable of a given type
+fn assign_compat(lhs_type: &Type, rhs_type: &Type, rhs: &Expr) -> bool
 {
     match (&lhs_type, &rhs_type)
     {
@@ -32,21 +32,43 @@ fn assign_compat(lhs_type: &Type, rhs_type: &Type) -> bool
         (Float(m), Int(n)) if n <= m => true,
         (Float(m), UInt(n)) if n <= m => true,
 
-        // Assigning an integer to a pointer
-        // Note: in C, this works but only for the value 0
-        (Pointer(base_type), UInt(_)) => true,
-        (Pointer(base_type), Int(_)) => true,
+        // Other integers need a cast to be assigned to a pointer
+        (Pointer(base_type), UInt(_) | Int(_)) => rhs.is_null_const(),
+
+        // void* converts to and from pointers to any object type,
+        // but not to pointers to functions
+        (Pointer(base_a), Pointer(base_b)) if base_a.is_void() || base_b.is_void() => {
+            !matches!(base_a.as_ref(), Fun { .. }) && !matches!(base_b.as_ref(), Fun { .. })
+        }
 
         // Assigning an array to a pointer
-        (Pointer(base_type), Array { elem_type, .. }) => base_type.eq(&elem_type),
+        (Pointer(base_type), Array { elem_type, .. }) => base_type.eq(&elem_type) || base_type.is_void(),
 
-        // Assigning a function to a void pointer
-        (Pointer(base_type), Fun { .. }) => base_type.eq(&Type::Void),
+        // Callbacks are passed to syscalls as void pointers,
+        // which is the only pointer type a function converts to
+        (Pointer(base_type), Fun { .. }) => base_type.is_void(),
 
         _ => lhs_type.eq(&rhs_type)
     }
 }
 
+/// Check if two values can be compared without a cast
+fn compare_compat(lhs_type: &Type, rhs_type: &Type, lhs: &Expr, rhs: &Expr) -> bool
+{
+    match (lhs_type, rhs_type) {
+        // Pointers can only be compared with
+        // integers which are null pointer constants
+        (Pointer(_), UInt(_) | Int(_)) => rhs.is_null_const(),
+        (UInt(_) | Int(_), Pointer(_)) => lhs.is_null_const(),
+
+        // Pointers to different types can be compared if one is void*
+        (Pointer(_), Pointer(_) | Fun { .. }) => assign_compat(lhs_type, rhs_type, rhs),
+        (Fun { .. }, Pointer(_)) => assign_compat(rhs_type, lhs_type, lhs),
+
+        _ => true,
+    }
+}
+
 impl Unit
 {
     pub fn check_types(&mut self) -> Result<(), ParseError>
@@ -84,18 +106,22 @@ impl Stmt
 
             Stmt::Break | Stmt::Continue => {}
 
+            Stmt::Case(_) | Stmt::Default => {}
+
+            Stmt::Line { .. } => {}
+
             // Return void
             Stmt::ReturnVoid => {
                 if !ret_type.eq(&Type::Void) {
-                    return ParseError::msg_only("return void in function not returning void");
+                    return ParseError::msg_only("E0015", "return void in function not returning void");
                 }
             }
 
             Stmt::ReturnExpr(expr) => {
                 let expr_type = expr.eval_type()?;
 
-                if !assign_compat(ret_type, &expr_type) {
-                    return ParseError::msg_only(&format!(
+                if !assign_compat(ret_type, &expr_type, expr) {
+                    return ParseError::msg_only("E0015", &format!(
                         "incompatible return type {}, expected {}",
                         expr_type,
                         ret_type
@@ -122,6 +148,11 @@ impl Stmt
                 body_stmt.check_types(ret_type)?;
             }
 
+            Stmt::Switch { test_expr, body_stmt } => {
+                test_expr.eval_type()?;
+                body_stmt.check_types(ret_type)?;
+            }
+
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 if init_stmt.is_some() {
                     init_stmt.as_mut().unwrap().check_types(ret_type)?;
@@ -147,6 +178,14 @@ impl Stmt
 
 impl Expr
 {
+    /// Check if this is a null pointer constant, which null and NULL
+    /// parse to, and the only integer that converts to a pointer
+    /// without a cast
+    fn is_null_const(&self) -> bool
+    {
+        matches!(self, Expr::Int(0))
+    }
+
     pub fn eval_type(&self) -> Result<Type, ParseError>
     {
         match self {
@@ -193,7 +232,7 @@ impl Expr
 
                             _ => {
                                 if !elem_type.eq(&expr_type) {
-                                    return ParseError::msg_only("array element types do not match");
+                                    return ParseError::msg_only("E0019", "array element types do not match");
                                 }
                             }
                         }
@@ -208,6 +247,7 @@ impl Expr
             }
 
             Expr::Ident(_) => panic!("IdentExpr made it past symbol resolution"),
+            Expr::CompoundAssign { .. } => panic!("CompoundAssign made it past symbol resolution"),
 
             Expr::Ref(decl) => {
                 Ok(decl.get_type())
@@ -233,9 +273,10 @@ impl Expr
                     (Pointer(_), Array{..}) => {},
                     (UInt(64), Pointer(_)) => {},
                     (Pointer(_), UInt(_)) => {},
+                    (Pointer(_), Fun {..}) => {},
                     (Pointer(_), Int(_)) => {},
 
-                    _ => return ParseError::msg_only(&format!(
+                    _ => return ParseError::msg_only("E0012", &format!(
                         "cannot cast type {} into {}",
                         src_type,
                         new_type
@@ -264,14 +305,14 @@ impl Expr
                             }
                         }
 
-                        return ParseError::msg_only(&format!(
+                        return ParseError::msg_only("E0017", &format!(
                             "unknown struct field \"{}\"",
                             field
                         ))
                     }
                 }
 
-                ParseError::msg_only(&format!(
+                ParseError::msg_only("E0018", &format!(
                     "arrow operator only applicable to struct pointers"
                 ))
             }
@@ -307,8 +348,8 @@ impl Expr
 
                 match op {
                     Assign => {
-                        if !assign_compat(&lhs_type, &rhs_type) {
-                            return ParseError::msg_only(&format!(
+                        if !assign_compat(&lhs_type, &rhs_type, rhs) {
+                            return ParseError::msg_only("E0009", &format!(
                                 "rhs type {} not assignable to lhs of type {}",
                                 rhs_type,
                                 lhs_type
@@ -332,7 +373,7 @@ impl Expr
                             (Array {elem_type, ..}, Int(n)) | (Int(n), Array {elem_type, ..}) => Ok(Pointer(elem_type)),
                             (Array {elem_type, ..}, UInt(n)) | (UInt(n), Array {elem_type, ..}) => Ok(Pointer(elem_type)),
 
-                            _ => ParseError::msg_only(&format!(
+                            _ => ParseError::msg_only("E0014", &format!(
                                 "incompatible types in add/sub {}, {}",
                                 lhs_type,
                                 rhs_type
@@ -349,7 +390,7 @@ impl Expr
                             (Float(32), Float(32)) => Ok(Float(32)),
                             (Float(32), Int(n)) | (Int(n), Float(32)) if n <= 32 => Ok(Float(32)),
 
-                            _ => ParseError::msg_only(&format!(
+                            _ => ParseError::msg_only("E0014", &format!(
                                 "incompatible types in arithmetic op {}, {}",
                                 lhs_type,
                                 rhs_type
@@ -364,7 +405,7 @@ impl Expr
                             (Int(m), UInt(n)) | (UInt(m), Int(n)) => Ok(UInt(max(m, n))),
                             (Int(m), Int(n)) => Ok(Int(max(m, n))),
 
-                            _ => ParseError::msg_only(&format!(
+                            _ => ParseError::msg_only("E0014", &format!(
                                 "incompatible types in bitwise op {}, {}",
                                 lhs_type,
                                 rhs_type
@@ -379,7 +420,7 @@ impl Expr
                             (Int(m), UInt(n)) => Ok(UInt(m)),
                             (UInt(m), Int(n)) => Ok(UInt(m)),
 
-                            _ => ParseError::msg_only(&format!(
+                            _ => ParseError::msg_only("E0014", &format!(
                                 "incompatible types in arithmetic op {}, {}",
                                 lhs_type,
                                 rhs_type
@@ -394,6 +435,14 @@ impl Expr
 
                     // Comparison operators
                     Eq | Ne | Lt | Le | Gt | Ge => {
+                        if !compare_compat(&lhs_type, &rhs_type, lhs, rhs) {
+                            return ParseError::msg_only("E0013", &format!(
+                                "comparison between {} and {} needs a cast",
+                                lhs_type,
+                                rhs_type
+                            ))
+                        }
+
                         Ok(UInt(8))
                     }
 
@@ -412,8 +461,13 @@ impl Expr
                 let then_type = then_expr.eval_type()?;
                 let else_type = else_expr.eval_type()?;
 
-                if !assign_compat(&then_type, &else_type) {
-                    return ParseError::msg_only("incompatible types in ternary (?) expression")
+                // Either branch can be null when the other is a pointer
+                if let (Pointer(_), true) = (&else_type, then_expr.is_null_const()) {
+                    return Ok(else_type);
+                }
+
+                if !assign_compat(&then_type, &else_type, else_expr) {
+                    return ParseError::msg_only("E0014", "incompatible types in ternary (?) expression")
                 }
 
                 Ok(then_type)
@@ -425,17 +479,17 @@ impl Expr
                 match fn_type {
                     Type::Fun { ret_type, param_types, var_arg } => {
                         if args.len() < param_types.len() {
-                            return ParseError::msg_only("argument count is less than function parameter count")
+                            return ParseError::msg_only("E0011", "argument count is less than function parameter count")
                         }
                         else if args.len() > param_types.len() && !var_arg {
-                            return ParseError::msg_only("argument count is greater than function parameter count")
+                            return ParseError::msg_only("E0011", "argument count is greater than function parameter count")
                         }
 
                         for (idx, param_type) in param_types.iter().enumerate() {
                             let arg_type = args[idx].eval_type()?;
 
-                            if !assign_compat(&param_type, &arg_type) {
-                                return ParseError::msg_only(&format!(
+                            if !assign_compat(&param_type, &arg_type, &args[idx]) {
+                                return ParseError::msg_only("E0010", &format!(
                                     "argument type {} not compatible with parameter type {}",
                                     arg_type,
                                     param_type,
@@ -450,7 +504,7 @@ impl Expr
 
                         Ok(*ret_type)
                     },
-                    _ => ParseError::msg_only(&format!("callee is not a function {:?}", callee))
+                    _ => ParseError::msg_only("E0026", &format!("callee is not a function {:?}", callee))
                 }
             }
 
@@ -484,6 +538,18 @@ mod tests
         unit.check_types().unwrap();
     }
 
+    fn parse_err(src: &str)
+    {
+        use crate::parsing::Input;
+        use crate::parser::parse_unit;
+
+        dbg!(src);
+        let mut input = Input::new(&src, "src");
+        let mut unit = parse_unit(&mut input).unwrap();
+        unit.resolve_syms().unwrap();
+        assert!(unit.check_types().is_err());
+    }
+
     fn parse_file(file_name: &str)
     {
         use crate::parser::{parse_file};
@@ -500,8 +566,43 @@ mod tests
         parse_ok("void foo() {} void main() { foo(); }");
         parse_ok("u64 foo(u64 v) { return v; } void main() { foo(1); }");
         parse_ok("u64 foo(u64 a, u64 b) { return a + b; } void main() { foo(1, 2); }");
+        parse_ok("u64 foo(u64 v, u8* p) { return v; } void main() { foo(1, null); }");
+        parse_err("u64 foo(u64 v, u8* p) { return v; } void main() { foo(1, 2); }");
+    }
+
+    #[test]
+    fn null_pointers()
+    {
+        parse_ok("void main() { u8* p = 0; p = null; p = NULL; }");
+        parse_ok("void main() { u8* p = null; if (p == null || null != p) {} }");
+        parse_ok("void main(u8 c, u8* q) { u8* p = c? null:q; p = c? q:0; }");
+        parse_ok("u8* foo() { return null; }");
+        parse_ok("void main() { u8* p = (u8*)5; u64 v = (u64)p; }");
+
+        parse_err("void main() { u8* p = 5; }");
+        parse_err("void main(u64 v) { u8* p; p = v; }");
+        parse_err("void main(u8* p) { if (p == 5) {} }");
+        parse_err("void main(u8* p) { if (1 < p) {} }");
+        parse_err("u8* foo() { return 1; }");
+    }
 
-        // FIXME:
-        //parse_ok("u64 foo(u64 v, u8* p) { return v; } void main() { foo(1, null); }");
+    #[test]
+    fn void_pointers()
+    {
+        parse_ok("void main(void* v) { u64* p = v; v = p; }");
+        parse_ok("void* alloc(u64 n) { return null; } void main() { u32* p = alloc(4); }");
+        parse_ok("void release(void* p) {} void main(u8* p) { release(p); }");
+        parse_ok("u16* foo(void* v) { return v; }");
+        parse_ok("void main(void* v, u64* p) { if (v == p || p != v) {} }");
+        parse_ok("void main(u8 c, void* v, u64* p) { p = c? p:v; }");
+        parse_ok("u64 a[4]; void main() { void* v = a; }");
+
+        // Functions only convert to void*, and other pointer types need a cast
+        parse_ok("void cb() {} void main() { void* v = cb; if (v == cb) {} }");
+        parse_err("void cb() {} void main() { u8* p = cb; }");
+        parse_err("void cb() {} void main() { u8* p = (u8*)0; if (p == cb) {} }");
+        parse_err("void main(u8* p) { u64* q = p; }");
+        parse_err("void main(u8* p, u64* q) { if (p == q) {} }");
+        parse_ok("void main(u8* p) { u64* q = (u64*)p; }");
     }
 }
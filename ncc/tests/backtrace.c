@@ -0,0 +1,40 @@
+#include <assert.h>
+#include <execinfo.h>
+
+void* addrs[8];
+int depth;
+
+void inner()
+{
+    depth = backtrace(addrs, 8);
+}
+
+void outer()
+{
+    inner();
+}
+
+int main()
+{
+    outer();
+    void* inner_ret = addrs[0];
+    void* main_ret = addrs[2];
+    void* start_ret = addrs[3];
+    int outer_depth = depth;
+    assert(depth >= 4);
+
+    // The two calls to inner() return into main at different call sites
+    inner();
+    assert(depth == outer_depth - 1);
+    assert(addrs[0] == inner_ret);
+    assert(addrs[1] != main_ret);
+    assert(addrs[2] == start_ret);
+
+    // The buffer size is respected
+    addrs[1] = 0;
+    assert(backtrace(addrs, 1) == 1);
+    assert(addrs[1] == 0);
+    assert(backtrace(addrs, 0) == 0);
+
+    return 0;
+}
@@ -0,0 +1,68 @@
+#include <assert.h>
+
+typedef struct
+{
+    int x;
+    char c;
+} point_t;
+
+int a[4];
+u8 bytes[4];
+int grid[3][3];
+point_t points[2];
+int num_calls = 0;
+
+int idx()
+{
+    num_calls = num_calls + 1;
+    return 1;
+}
+
+point_t* point()
+{
+    num_calls = num_calls + 1;
+    return &points[1];
+}
+
+void main()
+{
+    // The target of a compound assignment is evaluated once
+    a[idx()] += 3;
+    assert(a[1] == 3);
+    assert(num_calls == 1);
+
+    a[idx()] *= 5;
+    assert(a[1] == 15);
+    assert(num_calls == 2);
+
+    ++a[idx()];
+    --a[idx()];
+    --a[idx()];
+    assert(a[1] == 14);
+    assert(num_calls == 5);
+
+    bytes[idx()] -= 1;
+    assert(bytes[1] == 255);
+    assert(num_calls == 6);
+
+    grid[idx()][idx()] += 7;
+    assert(grid[1][1] == 7);
+    assert(num_calls == 8);
+
+    point()->x += 4;
+    point()->c |= 2;
+    assert(points[1].x == 4);
+    assert(points[1].c == 2);
+    assert(num_calls == 10);
+
+    // The value of the expression is the new value of the target
+    int* p = a;
+    assert((p[idx()] += 1) == 15);
+    assert((*p += 2) == 2);
+    assert(num_calls == 11);
+
+    int x = 1;
+    x <<= 3;
+    x += x;
+    assert(x == 16);
+}
@@ -0,0 +1,46 @@
+#include <assert.h>
+#include <stdint.h>
+
+// Globals that are never written are propagated into their uses
+int WIDTH = 320;
+int HEIGHT = 200;
+u8 SMALL = 200;
+i8 NEG = -3;
+u64 BIG = 5000000000;
+float SCALE = 0.5f;
+int ZERO;
+
+// Globals that are written keep their storage
+int counter = 10;
+int through_asm = 7;
+
+void bump()
+{
+    ++counter;
+}
+
+int main()
+{
+    int sum = 0;
+    for (int y = 0; y < HEIGHT; ++y)
+        for (int x = 0; x < WIDTH; ++x)
+            sum = sum + 1;
+    assert(sum == 64000);
+
+    assert(SMALL + 100 == 300);
+    assert(NEG == -3);
+    assert(NEG + 3 == 0);
+    assert(BIG == 5000000000);
+    assert(SCALE * 4.0f == 2.0f);
+    assert(ZERO == 0);
+    assert(sizeof(SMALL) == 1);
+    assert(sizeof(BIG) == 8);
+
+    bump();
+    assert(counter == 11);
+
+    asm () -> void { push through_asm; push 8; store_u32; };
+    assert(through_asm == 8);
+
+    return 0;
+}
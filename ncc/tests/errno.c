@@ -0,0 +1,28 @@
+#include <assert.h>
+#include <errno.h>
+#include <string.h>
+#include <uvm/syscalls.h>
+
+int main()
+{
+    assert(errno == 0);
+
+    // Syscalls return negated error codes
+    u8 buf[8];
+    long n = net_read(12345, buf, sizeof(buf));
+    assert(n == -EBADF);
+    assert(net_close(12345) == -EBADF);
+
+    // Failures are reported through errno
+    assert(__syscall_ret(n) == -1);
+    assert(errno == EBADF);
+    assert(__syscall_ret(5) == 5);
+    assert(errno == EBADF);
+
+    assert(strcmp(strerror(EBADF), "Invalid handle") == 0);
+    assert(strcmp(strerror(EINVAL), "Invalid argument") == 0);
+    assert(strcmp(strerror(EDQUOT), "Quota exceeded") == 0);
+    assert(strcmp(strerror(1000), "Unknown error") == 0);
+
+    return 0;
+}
@@ -0,0 +1,30 @@
+#include <assert.h>
+#include <stdlib.h>
+#include <uvm/syscalls.h>
+#include <uvm/utils.h>
+#include <uvm/event.h>
+
+event_t ev;
+
+void poll()
+{
+    // The timer with a null callback queued an event
+    assert(event_poll(&ev) == 1);
+    assert(ev.kind == EVENT_TIMER);
+    assert(ev.source == 0);
+    assert(event_poll(&ev) == 0);
+    exit(0);
+}
+
+int main()
+{
+    assert(sizeof(event_t) == EVENT_SIZE);
+
+    assert(event_poll(&ev) == 0);
+
+    time_delay_cb(1, NULL);
+    time_delay_cb(2, poll);
+    enable_event_loop();
+
+    return 0;
+}
@@ -44,5 +44,43 @@ int main()
     assert(!!bar()[0]);
     assert(bar()[0] + 1 == 78);
 
+    // Assignment is right-associative and binds tighter than comma
+    int b;
+    int c;
+    b = c = a, 1;
+    assert(b == 8 && c == 8);
+    assert((a) - b == 0);
+
+    // Nested ternaries associate to the right
+    int t = 0? 1 : 1? 2 : 3;
+    assert(t == 2);
+
+    // Compound assignment
+    int x = 5;
+    x += 3;
+    assert(x == 8);
+    x -= 1;
+    x *= 2;
+    assert(x == 14);
+    x /= 4;
+    assert(x == 3);
+    x %= 2;
+    assert(x == 1);
+    x <<= 4;
+    x |= 3;
+    assert(x == 19);
+    x &= 6;
+    x ^= 1;
+    assert(x == 3);
+    x >>= 1;
+    assert(x == 1);
+    arr[2] = 1;
+    arr[2] += 4;
+    assert(arr[2] == 5);
+
+    // Sizeof of an expression without parens
+    assert(sizeof x == 4);
+    assert(sizeof *bar() == 4);
+
     return 0;
 }
@@ -0,0 +1,58 @@
+#include <assert.h>
+#include <getopt.h>
+#include <string.h>
+
+char* a1[5] = { "prog", "-a", "-bc", "file", NULL };
+char* a2[7] = { "prog", "-ofoo", "-v", "-o", "bar", "rest", NULL };
+char* a3[5] = { "prog", "-v", "--", "-v", NULL };
+char* a4[4] = { "prog", "-", "-v", NULL };
+char* a5[4] = { "prog", "-xv", "-o", NULL };
+
+int parse(int argc, char** argv, char* options, char* expected)
+{
+    char result[64];
+    int n = 0;
+    int ch;
+
+    optind = 1;
+    while ((ch = getopt(argc, argv, options)) != -1)
+    {
+        result[n] = ch;
+        ++n;
+        if (optarg)
+        {
+            strncpy(result + n, optarg, 64 - n);
+            n = n + (int)strlen(optarg);
+        }
+    }
+    result[n] = '\0';
+
+    assert(strcmp(result, expected) == 0);
+    return optind;
+}
+
+int main(int argc, char** argv)
+{
+    // Run without arguments, argv only has the program path
+    assert(argc == 1);
+    assert(strlen(argv[0]) > 0);
+    assert(argv[1] == NULL);
+
+    opterr = 0;
+
+    assert(parse(4, a1, "abc", "abc") == 3);
+
+    // Option arguments in the same or the next argument
+    assert(parse(6, a2, "o:v", "ofoovobar") == 5);
+
+    // Options stop at "--", and a lone "-" isn't an option
+    assert(parse(4, a3, "v", "v") == 3);
+    assert(parse(3, a4, "v", "") == 1);
+
+    // Invalid options and missing arguments
+    assert(parse(3, a5, "o:v", "?v?") == 3);
+    assert(optopt == 'o');
+    assert(parse(3, a5, ":o:v", "?v:") == 3);
+
+    return 0;
+}
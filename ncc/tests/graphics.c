@@ -6,6 +6,16 @@
 
 // Frame buffer
 u32 fb[800][600];
+u64 window;
+
+bitmap_t fb_bitmap;
+bitmap_t sheet;
+u32 sheet_pixels[4];
+sprite_t sprites[2];
+u16 tiles[2];
+tilemap_t tilemap;
+u8 lut[256];
+rect_t rect;
 
 void keydown(u64 window_id, u16 keycode)
 {
@@ -48,12 +58,77 @@ int main()
         rgb32(255, 0, 0)
     );
 
+    // Host-side sprite and tile map rendering
+    fb_bitmap.pixels = (u32*)fb;
+    fb_bitmap.width = 800;
+    fb_bitmap.height = 600;
+    u32* pixels = (u32*)fb;
+
+    sheet_pixels[0] = COLOR_RED;
+    sheet_pixels[1] = 0;
+    sheet_pixels[2] = COLOR_GREEN;
+    sheet_pixels[3] = COLOR_BLUE;
+    sheet.pixels = sheet_pixels;
+    sheet.width = 2;
+    sheet.height = 2;
+
+    sprites[0].x = 10;
+    sprites[0].y = 20;
+    sprites[0].src_x = 0;
+    sprites[0].src_y = 0;
+    sprites[0].width = 2;
+    sprites[0].height = 2;
+    sprites[0].flags = 0;
+    sprites[1].x = -1;
+    sprites[1].y = 20;
+    sprites[1].src_x = 0;
+    sprites[1].src_y = 0;
+    sprites[1].width = 2;
+    sprites[1].height = 2;
+    sprites[1].flags = SPRITE_FLIP_Y;
+    blit_sprites(&fb_bitmap, &sheet, sprites, 2);
+    assert(pixels[20 * 800 + 10] == COLOR_RED);
+    assert(pixels[20 * 800 + 11] == COLOR_GREY);
+    assert(pixels[21 * 800 + 11] == COLOR_BLUE);
+    assert(pixels[20 * 800] == COLOR_BLUE);
+    assert(pixels[21 * 800] == COLOR_GREY);
+
+    tiles[0] = 2;
+    tiles[1] = TILE_EMPTY;
+    tilemap.tileset = &sheet;
+    tilemap.tiles = tiles;
+    tilemap.map_width = 2;
+    tilemap.map_height = 1;
+    tilemap.tile_width = 1;
+    tilemap.tile_height = 1;
+    draw_tilemap(&fb_bitmap, &tilemap, 0, 0);
+    assert(pixels[0] == COLOR_GREEN);
+    assert(pixels[1] == COLOR_GREY);
+
+    // Color helpers
+    gamma_lut(lut, 1.0f);
+    assert(lut[0] == 0);
+    assert(lut[100] == 100);
+    assert(lut[255] == 255);
+    gamma_lut(lut, 2.2f);
+    assert(lut[0] == 0);
+    assert(lut[128] > 128);
+    assert(lut[255] == 255);
+
+    rect.x = 0;
+    rect.y = 0;
+    rect.width = 1;
+    rect.height = 1;
+    apply_lut(&fb_bitmap, &rect, lut);
+    assert(pixels[0] == rgb32(0, lut[255], 0));
+    assert(pixels[1] == COLOR_GREY);
+
     // If this is not running as part of a unit test, create
     // a window so we can view the output
     #ifndef TEST
-    window_create(800, 600, "Graphics Test", 0);
-    window_on_keydown(0, keydown);
-    window_draw_frame(0, fb);
+    window = window_create(800, 600, "Graphics Test", 0);
+    window_on_keydown(window, keydown);
+    window_draw_frame(window, fb);
     enable_event_loop();
     #endif
 
@@ -86,5 +86,31 @@ int main()
     assert((int64_t)3 - (int8_t)1 == (int8_t)2);
     assert((int64_t)3 - (int8_t)5 == (int16_t)-2);
 
+    // Multiplication and division by powers of two
+    int neg = -7;
+    int pos = 7;
+    assert(pos * 8 == 56);
+    assert(8 * neg == -56);
+    assert(pos / 4 == 1);
+    assert(neg / 4 == -1);
+    assert(neg / 2 == -3);
+    assert(pos % 4 == 3);
+    assert(neg % 4 == -3);
+    assert(neg % 1 == 0);
+    assert(neg / 1 == -7);
+    assert((int)0x80000000 / 2 == -1073741824);
+    int64_t neg64 = -1000000000007;
+    assert(neg64 / 1024 == -976562500);
+    assert(neg64 % 1024 == -7);
+    assert(neg64 * 4 == -4000000000028);
+    unsigned int upos = 0xFFFFFFF7;
+    assert(upos / 16 == 0xFFFFFFF);
+    assert(upos % 16 == 7);
+    assert(upos * 2 == 0xFFFFFFEE);
+    uint8_t small = 200;
+    assert((uint8_t)(small * 2) == 144);
+    assert(small / 8 == 25);
+    assert(small % 8 == 0);
+
     return 0;
 }
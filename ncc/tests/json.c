@@ -0,0 +1,74 @@
+#include <assert.h>
+#include <string.h>
+#include <uvm/json.h>
+
+json_tok_t toks[32];
+json_writer_t writer;
+char out[128];
+char str_buf[32];
+
+int main()
+{
+    char* js = "{\"name\": \"uvm\", \"ver\": -12, \"tags\": [\"a\\\"b\", true, null], \"obj\": {}}";
+    int num_toks = json_parse(js, strlen(js), toks, 32);
+    assert(num_toks == 12);
+    assert(toks[0].type == JSON_OBJECT);
+    assert(toks[0].size == 4);
+
+    int name = json_obj_get(js, toks, 0, "name");
+    assert(name > 0);
+    assert(toks[name].type == JSON_STRING);
+    assert(json_eq(js, toks + name, "uvm"));
+
+    int ver = json_obj_get(js, toks, 0, "ver");
+    assert(json_get_int(js, toks + ver) == -12);
+
+    int tags = json_obj_get(js, toks, 0, "tags");
+    assert(toks[tags].type == JSON_ARRAY);
+    assert(toks[tags].size == 3);
+    int tag0 = json_arr_get(toks, tags, 0);
+    assert(json_get_str(js, toks + tag0, str_buf, 32) == 3);
+    assert(strcmp(str_buf, "a\"b") == 0);
+    assert(json_get_bool(js, toks + json_arr_get(toks, tags, 1)));
+    assert(json_is_null(js, toks + json_arr_get(toks, tags, 2)));
+    assert(json_arr_get(toks, tags, 3) == -1);
+
+    int obj = json_obj_get(js, toks, 0, "obj");
+    assert(toks[obj].type == JSON_OBJECT && toks[obj].size == 0);
+    assert(json_obj_get(js, toks, 0, "missing") == -1);
+
+    // Malformed and incomplete inputs
+    assert(json_parse("[1, 2", 5, toks, 32) == JSON_ERROR_PART);
+    assert(json_parse("{\"a\" 1}", 7, toks, 32) == JSON_ERROR_INVAL);
+    assert(json_parse("[1]]", 4, toks, 32) == JSON_ERROR_INVAL);
+    assert(json_parse("[1, 2, 3]", 9, toks, 2) == JSON_ERROR_NOMEM);
+
+    // Writer
+    json_writer_init(&writer, out, 128);
+    json_begin_object(&writer);
+    json_key(&writer, "id");
+    json_write_int(&writer, 42);
+    json_key(&writer, "msg");
+    json_write_str(&writer, "hi\n\"there\"");
+    json_key(&writer, "list");
+    json_begin_array(&writer);
+    json_write_bool(&writer, false);
+    json_write_null(&writer);
+    json_begin_object(&writer);
+    json_end_object(&writer);
+    json_end_array(&writer);
+    json_end_object(&writer);
+    assert(!writer.overflow);
+    assert(strcmp(out, "{\"id\":42,\"msg\":\"hi\\n\\\"there\\\"\",\"list\":[false,null,{}]}") == 0);
+
+    // Round trip through the parser
+    assert(json_parse(out, writer.len, toks, 32) == 10);
+
+    // Output that doesn't fit is truncated
+    json_writer_init(&writer, out, 4);
+    json_write_str(&writer, "long string");
+    assert(writer.overflow);
+    assert(strlen(out) == 3);
+
+    return 0;
+}
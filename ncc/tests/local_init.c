@@ -0,0 +1,96 @@
+#include <assert.h>
+
+typedef struct
+{
+    int x;
+    int y;
+} Vec2;
+
+typedef struct
+{
+    Vec2 pos;
+    u8 flags;
+    int vals[3];
+} Entity;
+
+int num_calls = 0;
+
+int f()
+{
+    num_calls = num_calls + 1;
+    return 10 * num_calls;
+}
+
+int g()
+{
+    num_calls = num_calls + 1;
+    return 20 * num_calls;
+}
+
+int sum_vec(Vec2 v)
+{
+    return v.x + v.y;
+}
+
+// Dirty the stack so that missing initializers have to be zeroed
+int dirty()
+{
+    int a[16];
+    for (int i = 0; i < 16; ++i)
+        a[i] = -1;
+    return a[15];
+}
+
+int partial()
+{
+    int a[4] = { f() };
+    Entity e = { { 1 }, 2 };
+    return a[0] + a[1] + a[2] + a[3] + e.pos.x + e.pos.y + e.flags + e.vals[2];
+}
+
+int main()
+{
+    int x = 3;
+    int y = 4;
+
+    Vec2 v = { x, y };
+    assert(v.x == 3 && v.y == 4);
+    assert(sum_vec(v) == 7);
+
+    // Initializers are evaluated in order
+    int a[2] = { f(), g() };
+    assert(a[0] == 10 && a[1] == 40);
+    assert(num_calls == 2);
+
+    // Nested initializer lists
+    Entity e = { { x + 1, v.y * 2 }, (u8)(y - 1), { a[1], a[0], x } };
+    assert(e.pos.x == 4 && e.pos.y == 8);
+    assert(e.flags == 3);
+    assert(e.vals[0] == 40 && e.vals[1] == 10 && e.vals[2] == 3);
+
+    Vec2 points[3] = { { 1, 2 }, v, { x * x, 0 } };
+    assert(points[0].x == 1 && points[0].y == 2);
+    assert(points[1].x == 3 && points[1].y == 4);
+    assert(points[2].x == 9 && points[2].y == 0);
+
+    // Struct variables copied from another struct
+    Vec2 w = points[2];
+    w.y = 5;
+    assert(w.x == 9 && w.y == 5 && points[2].y == 0);
+
+    // Elements without an initializer are zero
+    assert(dirty() == -1);
+    assert(partial() == 30 + 1 + 2);
+
+    // Variables in loops are initialized on each iteration
+    int total = 0;
+    for (int i = 0; i < 3; ++i)
+    {
+        int b[3] = { i, i + 1 };
+        total = total + b[0] + b[1] + b[2];
+        b[2] = 100;
+    }
+    assert(total == 9);
+
+    return 0;
+}
@@ -26,4 +26,19 @@ void main()
     uint8_t* ptr0 = (uint8_t*)malloc(3);
     uint32_t* ptr1 = (uint32_t*)malloc(4);
     *ptr1 = 333;
+
+    // Freed memory is reused
+    uint8_t* block = (uint8_t*)malloc(64);
+    free((void*)block);
+    assert((uint8_t*)malloc(64) == block);
+
+    // Resized blocks keep their contents
+    uint32_t* array = (uint32_t*)malloc(4 * sizeof(uint32_t));
+    for (int i = 0; i < 4; ++i)
+        array[i] = i;
+    array = (uint32_t*)realloc((void*)array, 1000 * sizeof(uint32_t));
+    for (int i = 0; i < 4; ++i)
+        assert(array[i] == i);
+    array[999] = 999;
+    free((void*)array);
 }
@@ -1,9 +1,48 @@
+#include <uvm/syscalls.h>
 #include <uvm/music.h>
+#include <stdint.h>
+
+i16 buf[64];
+osc_t osc;
+envelope_t env;
+biquad_t filter;
 
 int main()
 {
     float a4_f = pc_to_freq(A4_NOTE_NO, 0);
     assert(fabsf(a4_f - 440) < 0.1f);
 
+    // One period of a square wave per 4 samples
+    osc.sample_rate = 4;
+    osc.freq = 1;
+    osc.amplitude = 0.5f;
+    assert(audio_osc(buf, 4, AUDIO_WAVE_SQUARE, &osc) == 0);
+    assert(buf[1] == 16383 && buf[2] == -16383);
+    assert(osc.phase == 0);
+    assert(audio_osc(buf, 4, 9, &osc) < 0);
+
+    for (int i = 0; i < 8; ++i)
+    {
+        buf[i] = 1000;
+    }
+    env.attack = 2;
+    env.decay = 2;
+    env.sustain = 0.5f;
+    env.release = 2;
+    env.release_pos = 6;
+    audio_envelope(buf, 8, &env);
+    assert(buf[0] == 0 && buf[1] == 500 && buf[3] == 750 && buf[5] == 500 && buf[7] == 250);
+    assert(env.pos == 8);
+
+    // A low-pass filter lets a constant signal through
+    assert(audio_biquad_init(&filter, AUDIO_FILTER_LOWPASS, 0.1f, 0.7071f) == 0);
+    for (int i = 0; i < 64; ++i)
+    {
+        buf[i] = 1000;
+    }
+    audio_biquad(buf, 64, &filter);
+    assert(buf[0] < 1000 && buf[63] == 1000);
+    assert(audio_biquad_init(&filter, AUDIO_FILTER_LOWPASS, 0.6f, 0.7071f) < 0);
+
     return 0;
 }
@@ -5,6 +5,8 @@ int main()
     printf("");
     printf("Hello!\n");
     printf("100%%\n");
+    assert(printf("1%%%%2\n") == 5);
+    assert(printf("no %x specifier\n") == 16);
     printf("Decimal integer: %d\n", 1337);
     printf("String in quote \"%s\", integer value: %d\n", "foobar", 42);
 
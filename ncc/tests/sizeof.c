@@ -12,6 +12,25 @@ void main()
     assert(sizeof(int) == 4);
     assert(sizeof(long) == 8);
 
+    assert(sizeof(signed char) == 1);
+    assert(sizeof(short int) == 2);
+    assert(sizeof(unsigned short) == 2);
+    assert(sizeof(unsigned) == 4);
+    assert(sizeof(signed int) == 4);
+    assert(sizeof(long int) == 8);
+    assert(sizeof(long long) == 8);
+    assert(sizeof(unsigned long long int) == 8);
+
+    // Signedness follows the specifiers
+    signed char sc = -1;
+    assert(sc < 0);
+    unsigned short us = 0xFFFF;
+    assert(us > 0);
+    long long ll = -1;
+    assert(ll < 0);
+    unsigned long long ull = ll;
+    assert(ull > 0);
+
     assert(sizeof(int*) == 8);
     assert(sizeof(int *) == 8);
 
@@ -40,6 +40,9 @@ void main()
     assert(buf[0] == '0');
     assert(buf[1] == '\0');
 
+    // The start of the output string is returned
+    assert(itoa(-5, buf, 10) == buf);
+
     // Test the exit function
     exit(0);
 }
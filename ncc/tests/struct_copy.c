@@ -0,0 +1,86 @@
+#include <assert.h>
+#include <stdlib.h>
+
+typedef struct
+{
+    int x;
+    int y;
+} Point;
+
+typedef struct
+{
+    char name[8];
+    Point pos;
+    u64 score;
+} User;
+
+User a;
+User b;
+User users[4];
+
+// The argument is a copy, so changing it doesn't change the caller's struct
+u64 bump(User u)
+{
+    u.score = u.score + 1;
+    u.pos.x = 100;
+    return u.score;
+}
+
+int dist(Point p, Point q)
+{
+    return (q.x - p.x) + (q.y - p.y);
+}
+
+int main()
+{
+    b.name[0] = 'b';
+    b.pos.x = 3;
+    b.pos.y = 4;
+    b.score = 777;
+
+    a = b;
+    assert(a.name[0] == 'b');
+    assert(a.pos.x == 3 && a.pos.y == 4);
+    assert(a.score == 777);
+
+    // The copy is independent of the original
+    a.score = 1;
+    assert(b.score == 777);
+
+    // Array elements
+    for (int i = 0; i < 4; ++i)
+    {
+        users[i].score = i;
+        users[i].pos.y = i * 10;
+    }
+    users[0] = users[3];
+    assert(users[0].score == 3 && users[0].pos.y == 30);
+    assert(users[3].score == 3);
+
+    // Through pointers
+    User* p = malloc(sizeof(User));
+    User* q = &users[2];
+    *p = *q;
+    assert(p->score == 2 && p->pos.y == 20);
+
+    // Nested struct fields, and chained assignments
+    Point* r = malloc(sizeof(Point));
+    *r = b.pos;
+    assert(r->x == 3 && r->y == 4);
+    users[1].pos = users[2].pos = b.pos;
+    assert(users[1].pos.x == 3 && users[2].pos.y == 4);
+    assert(users[1].score == 1);
+
+    // Passing array elements by value
+    assert(bump(users[3]) == 4);
+    assert(users[3].score == 3);
+    assert(users[3].pos.x == 0);
+    assert(bump(*p) == 3);
+    assert(p->score == 2);
+    assert(dist(users[0].pos, b.pos) == 3 + (4 - 30));
+
+    free(p);
+    free(r);
+
+    return 0;
+}
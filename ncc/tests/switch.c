@@ -0,0 +1,114 @@
+#include <assert.h>
+
+// Dense cases, compiled to a jump table
+int dense(int x)
+{
+    switch (x)
+    {
+        case 0: return 10;
+        case 1: return 11;
+        case 2:
+        case 3: return 12;
+        case 5: return 15;
+        default: return -1;
+    }
+}
+
+// Sparse cases, compiled to comparisons
+int sparse(long x)
+{
+    int r = 0;
+
+    switch (x)
+    {
+        case -1000000:
+            r = 1;
+            break;
+
+        case 7:
+            r = 2;
+            break;
+
+        case 123456789:
+            r = 3;
+            break;
+    }
+
+    return r;
+}
+
+// Cases which fall through, and don't start at zero
+int fallthrough(unsigned char c)
+{
+    int n = 0;
+
+    switch (c)
+    {
+        case 'a': n = n + 1;
+        case 'b': n = n + 10;
+        case 'c': n = n + 100;
+            break;
+        default:
+            n = -1;
+    }
+
+    return n;
+}
+
+int main()
+{
+    assert(dense(0) == 10);
+    assert(dense(1) == 11);
+    assert(dense(2) == 12);
+    assert(dense(3) == 12);
+    assert(dense(4) == -1);
+    assert(dense(5) == 15);
+    assert(dense(6) == -1);
+    assert(dense(-1) == -1);
+
+    assert(sparse(-1000000) == 1);
+    assert(sparse(7) == 2);
+    assert(sparse(123456789) == 3);
+    assert(sparse(8) == 0);
+
+    assert(fallthrough('a') == 111);
+    assert(fallthrough('b') == 110);
+    assert(fallthrough('c') == 100);
+    assert(fallthrough('d') == -1);
+    assert(fallthrough('A') == -1);
+
+    // Breaks exit the switch, and continues go to the enclosing loop
+    int count = 0;
+    for (int i = 0; i < 10; i = i + 1)
+    {
+        switch (i % 3)
+        {
+            case 0:
+                continue;
+            case 1:
+                count = count + 1;
+                break;
+            default:
+                count = count + 100;
+        }
+
+        count = count + 1000;
+    }
+    assert(count == 6303);
+
+    // Negative cases, with the test value evaluated once
+    int calls = 0;
+    int k;
+    for (k = -2; k <= 2; k = k + 1)
+    {
+        switch (calls = calls + 1, k)
+        {
+            case -2: case -1: break;
+            case 0: calls = calls + 10; break;
+            case 2: calls = calls + 100;
+        }
+    }
+    assert(calls == 115);
+
+    return 0;
+}
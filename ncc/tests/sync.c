@@ -0,0 +1,110 @@
+#include <assert.h>
+#include <uvm/syscalls.h>
+
+#define NUM_CONSUMERS 3
+#define NUM_ITEMS 3000
+#define QUEUE_SIZE 8
+
+// Bounded queue shared by a producer and the consumers
+u64 queue[QUEUE_SIZE];
+u64 head = 0;
+u64 tail = 0;
+u64 num_items = 0;
+
+u64 mutex;
+u64 not_empty;
+u64 not_full;
+
+u64 totals[NUM_CONSUMERS];
+
+void push_item(u64 item)
+{
+    mutex_lock(mutex);
+
+    while (num_items == QUEUE_SIZE)
+    {
+        cond_wait(not_full, mutex);
+    }
+
+    queue[tail] = item;
+    tail = (tail + 1) % QUEUE_SIZE;
+    num_items = num_items + 1;
+
+    cond_signal(not_empty);
+    mutex_unlock(mutex);
+}
+
+u64 pop_item()
+{
+    mutex_lock(mutex);
+
+    while (num_items == 0)
+    {
+        cond_wait(not_empty, mutex);
+    }
+
+    u64 item = queue[head];
+    head = (head + 1) % QUEUE_SIZE;
+    num_items = num_items - 1;
+
+    cond_signal(not_full);
+    mutex_unlock(mutex);
+
+    return item;
+}
+
+// Each consumer adds up items until it gets a zero
+u64 consumer(void* arg)
+{
+    u64 idx = (u64)arg;
+    u64 count = 0;
+
+    for (;;)
+    {
+        u64 item = pop_item();
+        if (item == 0)
+            break;
+
+        totals[idx] = totals[idx] + item;
+        count = count + 1;
+    }
+
+    return count;
+}
+
+int main()
+{
+    mutex = mutex_create();
+    not_empty = cond_create();
+    not_full = cond_create();
+
+    u64 tids[NUM_CONSUMERS];
+    for (int i = 0; i < NUM_CONSUMERS; ++i)
+    {
+        tids[i] = thread_spawn(consumer, (void*)i);
+    }
+
+    for (int i = 1; i <= NUM_ITEMS; ++i)
+    {
+        push_item(i);
+    }
+
+    for (int i = 0; i < NUM_CONSUMERS; ++i)
+    {
+        push_item(0);
+    }
+
+    u64 count = 0;
+    u64 total = 0;
+    for (int i = 0; i < NUM_CONSUMERS; ++i)
+    {
+        count = count + thread_join(tids[i]);
+        total = total + totals[i];
+    }
+
+    assert(count == NUM_ITEMS);
+    assert(total == NUM_ITEMS * (NUM_ITEMS + 1) / 2);
+    assert(num_items == 0);
+
+    return 0;
+}
@@ -0,0 +1,43 @@
+#include <assert.h>
+#include <stdlib.h>
+#include <string.h>
+
+_Thread_local int counter = 5;
+_Thread_local u64 big;
+_Thread_local char name[8] = { 'a', 'b', 'c', 0, 0, 0, 0, 0 };
+int global = 3;
+
+int next()
+{
+    counter = counter + 1;
+    return counter;
+}
+
+int main()
+{
+    assert(counter == 5);
+    assert(next() == 6);
+    assert(next() == 7);
+    assert(big == 0);
+    big = 0xFFFFFFFFFFFF;
+    assert(big == 0xFFFFFFFFFFFF);
+    assert(global == 3);
+
+    // Thread-local arrays are addressable
+    char* p = name;
+    assert(p[1] == 'b');
+    p[1] = 'x';
+    assert(name[1] == 'x');
+
+    // Locals can shadow thread-local variables
+    int counter = 1;
+    assert(counter == 1);
+
+    // Allocations don't overlap the TLS block
+    u8* buf = (u8*)malloc(64);
+    memset((void*)buf, 0xFF, 64);
+    assert(next() == 8);
+    assert(name[0] == 'a');
+
+    return 0;
+}
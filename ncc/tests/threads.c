@@ -0,0 +1,46 @@
+#include <assert.h>
+#include <stdlib.h>
+#include <uvm/syscalls.h>
+
+#define NUM_THREADS 4
+
+// Each thread has its own copy, the array is shared
+_Thread_local int count = 1;
+u64 sums[NUM_THREADS];
+
+u64 worker(void* arg)
+{
+    u64 idx = (u64)arg;
+    assert(thread_id() == idx + 1);
+
+    for (int i = 0; i < 2000; ++i)
+    {
+        count = count + 1;
+        sums[idx] = sums[idx] + idx;
+    }
+
+    return count;
+}
+
+int main()
+{
+    assert(thread_id() == 0);
+
+    u64 tids[NUM_THREADS];
+    for (int i = 0; i < NUM_THREADS; ++i)
+    {
+        tids[i] = thread_spawn(worker, (void*)i);
+        assert(tids[i] == i + 1);
+    }
+
+    // Join the threads in reverse order
+    for (int i = NUM_THREADS - 1; i >= 0; --i)
+    {
+        assert(thread_join(tids[i]) == 2001);
+        assert(sums[i] == 2000 * i);
+    }
+
+    assert(count == 1);
+
+    return 0;
+}
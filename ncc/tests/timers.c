@@ -0,0 +1,50 @@
+#include <assert.h>
+#include <stdlib.h>
+#include <uvm/syscalls.h>
+#include <uvm/utils.h>
+
+u64 timer;
+int num_ticks = 0;
+int order = 0;
+
+void first()
+{
+    assert(order == 0);
+    order = 1;
+}
+
+void second()
+{
+    assert(order == 1);
+    order = 2;
+}
+
+void tick()
+{
+    num_ticks = num_ticks + 1;
+
+    // The timer can be cancelled from its own callback
+    if (num_ticks == 4)
+    {
+        time_cancel_cb(timer);
+    }
+}
+
+void end()
+{
+    assert(order == 2);
+    assert(num_ticks == 4);
+    exit(0);
+}
+
+int main()
+{
+    time_delay_cb(0, first);
+    time_delay_cb(0, second);
+    timer = time_interval_cb(2, tick);
+    time_delay_cb(50, end);
+
+    enable_event_loop();
+
+    return 0;
+}
@@ -0,0 +1,40 @@
+#include <stdlib.h>
+#include <assert.h>
+#include <uvm/syscalls.h>
+#include <uvm/loop.h>
+
+int num_updates = 0;
+int num_frames = 0;
+u64 start_time = 0;
+
+void update()
+{
+    ++num_updates;
+
+    if (num_updates == 10)
+    {
+        loop_stop();
+
+        // 10 steps of 5ms can't take less than 45ms,
+        // the first update happens after one full step
+        assert(time_current_ms() - start_time >= 45);
+        assert(num_frames > 0);
+        exit(0);
+    }
+}
+
+void render(float alpha)
+{
+    assert(alpha >= 0.0f);
+    assert(alpha < 1.0f);
+    ++num_frames;
+}
+
+void main()
+{
+    start_time = time_current_ms();
+
+    // Render at a slower rate than updates,
+    // so that some frames run multiple updates
+    loop_start(5, 12, update, render);
+}
@@ -0,0 +1,15 @@
+#include <assert.h>
+#include <uvm/syscalls.h>
+
+int main()
+{
+    u32 version = sys_version();
+    assert(version > 0);
+
+    // Every syscall listed in syscalls.h is available
+    assert(sys_has_syscall(SYS_print_str));
+    assert(sys_has_syscall(SYS_sys_has_syscall));
+    assert(!sys_has_syscall(0xFFFF));
+
+    return 0;
+}
@@ -0,0 +1,33 @@
+#
+# Recursive Fibonacci, which measures function calls and returns
+#
+
+.code;
+
+push 32;
+call FIB, 1;
+syscall print_i64;
+syscall print_endl;
+
+push 0;
+exit;
+
+# u64 fib(u64 n)
+FIB:
+get_arg 0;
+push 2;
+lt_i64;
+jz REC;
+get_arg 0;
+ret;
+REC:
+get_arg 0;
+push 1;
+sub_u64;
+call FIB, 1;
+get_arg 0;
+push 2;
+sub_u64;
+call FIB, 1;
+add_u64;
+ret;
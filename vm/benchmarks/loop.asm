@@ -0,0 +1,21 @@
+#
+# Stack-only arithmetic loop, which mostly measures instruction dispatch
+#
+
+.code;
+
+push 0;
+push 0;
+
+# acc = acc + 3, 10 instructions per iteration
+LOOP:
+swap; push 3; add_u64; swap;
+push 1; add_u64;
+dup; push 50_000_000; lt_u64; jnz LOOP;
+
+pop;
+syscall print_i64;
+syscall print_endl;
+
+push 0;
+exit;
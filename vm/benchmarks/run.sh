@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+# Time the benchmark programs, with the interpreter and with the JIT
+# Extra options are passed to the VM, e.g. ./benchmarks/run.sh --deterministic
+set -e
+cd "$(dirname "$0")/.."
+cargo build --release -q
+
+TIMEFORMAT="%R s"
+for prog in benchmarks/*.asm; do
+    for mode in "" "--jit"; do
+        printf "%-22s %-8s " "$prog" "${mode:-interp}"
+        time ./target/release/uvm $mode "$@" "$prog" > /dev/null
+    done
+done
@@ -0,0 +1,51 @@
+#
+# Sieve of Eratosthenes, repeated 30 times, which measures
+# local variable accesses and byte loads and stores
+#
+
+.data;
+FLAGS: .zero 100_000;
+
+.code;
+
+# Iteration count, local 0
+push 0;
+# Number of primes found, local 1
+push 0;
+# Outer loop index, local 2
+push 0;
+# Inner loop index, local 3
+push 0;
+
+ITER:
+push 0; set_local 1;
+
+# Set all the flags
+push 0; set_local 2;
+FILL:
+push FLAGS; get_local 2; add_u64; push 1; store_u8;
+get_local 2; push 1; add_u64; dup; set_local 2; push 100_000; lt_u64; jnz FILL;
+
+push 2; set_local 2;
+OUTER:
+push FLAGS; get_local 2; add_u64; load_u8; jz NEXT;
+get_local 1; push 1; add_u64; set_local 1;
+
+# Clear the flags of the multiples of i
+get_local 2; dup; add_u64; set_local 3;
+INNER:
+get_local 3; push 100_000; lt_u64; jz NEXT;
+push FLAGS; get_local 3; add_u64; push 0; store_u8;
+get_local 3; get_local 2; add_u64; set_local 3;
+jmp INNER;
+
+NEXT:
+get_local 2; push 1; add_u64; dup; set_local 2; push 100_000; lt_u64; jnz OUTER;
+get_local 0; push 1; add_u64; dup; set_local 0; push 30; lt_u64; jnz ITER;
+
+get_local 1;
+syscall print_i64;
+syscall print_endl;
+
+push 0;
+exit;
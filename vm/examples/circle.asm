@@ -13,25 +13,33 @@ WINDOW_TITLE:
 X_COORD:
 .u64 0;
 
+# Id of the window
+WINDOW_ID:
+.u64 0;
+
 ###########################################################
 
 # Code section
 .code;
 
 # Create a window
+push WINDOW_ID;
 push 800;
 push 600;
 push WINDOW_TITLE;
 push 0;
 syscall window_create;
+store_u64;
 
 push 100;
 push ANIM_CALLBACK;
 syscall time_delay_cb;
 
-push 0;
+push WINDOW_ID;
+load_u64;
 push PIXEL_BUFFER;
 syscall window_draw_frame;
+pop;
 
 # Wait for an event
 push 0;
@@ -76,9 +84,11 @@ push 20;
 call DRAW_CIRCLE, 3;
 pop;
 
-push 0;
+push WINDOW_ID;
+load_u64;
 push PIXEL_BUFFER;
 syscall window_draw_frame;
+pop;
 
 # Schedule the animation callback again
 push 25;
@@ -11,6 +11,11 @@ WINDOW_TITLE:
 MS_STR:
 .stringz " milliseconds to render\n";
 
+# Id of the window
+.align 8;
+WINDOW_ID:
+.u64 0;
+
 # Code section
 .code;
 
@@ -19,12 +24,13 @@ push 800;
 push 600;
 
 # Create the window
+push WINDOW_ID;
 get_local 0;
 get_local 1;
 push WINDOW_TITLE;
 push 0;
 syscall window_create;
-pop;
+store_u64;
 
 # Local 2: Y=0
 push 0;
@@ -114,9 +120,11 @@ syscall print_i64;
 push MS_STR;
 syscall print_str;
 
-push 0;
+push WINDOW_ID;
+load_u64;
 push PIXEL_BUFFER;
 syscall window_draw_frame;
+pop;
 
 # Return to the event loop
 push 0;
@@ -0,0 +1,317 @@
+//! Address sanitizer for guest memory
+//!
+//! The guest allocator reports the blocks it allocates and frees with
+//! the vm_track_alloc and vm_track_free syscalls. In --asan mode, we keep
+//! one shadow byte per heap byte and check every load and store executed
+//! by the guest against it, which detects heap overflows and use-after-free
+//! errors. Memory that was never allocated through the allocator, such as
+//! the data section, is always addressable.
+//!
+//! Note that memory accesses performed by syscalls are bounds-checked
+//! but not checked against the shadow memory.
+//!
+//! In --detect-leaks mode, we only track the allocated blocks, so that
+//! we can report the blocks that were never freed when the program exits.
+
+use std::collections::{BTreeMap, HashMap};
+
+// Shadow byte states
+const ADDRESSABLE: u8 = 0;
+const REDZONE: u8 = 1;
+const FREED: u8 = 2;
+
+// Size of the header the guest allocator places before each block.
+// The allocator doesn't write the header in --asan mode, so that we
+// can use it as a redzone on both sides of the block.
+const HEADER_SIZE: usize = 8;
+
+struct Block
+{
+    size: usize,
+
+    // Pc where the block was allocated
+    alloc_pc: usize,
+
+    // Pc where the block was freed, if it was
+    free_pc: Option<usize>,
+}
+
+pub struct Asan
+{
+    // Whether guest memory accesses are checked
+    check_accesses: bool,
+
+    // One shadow byte for each byte of the heap,
+    // empty if accesses aren't checked
+    shadow: Vec<u8>,
+
+    // Blocks allocated by the guest, indexed by start address
+    blocks: BTreeMap<usize, Block>,
+}
+
+impl Asan
+{
+    pub fn new(heap_size: usize, check_accesses: bool) -> Self
+    {
+        let shadow_size = if check_accesses { heap_size } else { 0 };
+
+        Self {
+            check_accesses,
+            shadow: vec![ADDRESSABLE; shadow_size],
+            blocks: BTreeMap::default(),
+        }
+    }
+
+    /// Check guest memory accesses from now on
+    pub fn enable_checks(&mut self, heap_size: usize)
+    {
+        if !self.check_accesses {
+            self.check_accesses = true;
+            self.shadow = vec![ADDRESSABLE; heap_size];
+        }
+    }
+
+    pub fn checks_enabled(&self) -> bool
+    {
+        self.check_accesses
+    }
+
+    /// Track a change in the heap size
+    pub fn resize(&mut self, heap_size: usize)
+    {
+        if self.check_accesses {
+            self.shadow.resize(heap_size, ADDRESSABLE);
+        }
+    }
+
+    fn fill(&mut self, start: usize, end: usize, state: u8)
+    {
+        let end = std::cmp::min(end, self.shadow.len());
+        if start < end {
+            self.shadow[start..end].fill(state);
+        }
+    }
+
+    /// Register a newly allocated block
+    pub fn on_alloc(&mut self, ptr: usize, size: usize, pc: usize)
+    {
+        let end = ptr + size;
+        let padded_end = (end + 7) & !7;
+
+        self.fill(ptr.saturating_sub(HEADER_SIZE), ptr, REDZONE);
+        self.fill(ptr, end, ADDRESSABLE);
+        self.fill(end, padded_end + HEADER_SIZE, REDZONE);
+
+        self.blocks.insert(ptr, Block { size, alloc_pc: pc, free_pc: None });
+    }
+
+    /// Register a block being freed
+    pub fn on_free(&mut self, ptr: usize, pc: usize) -> Result<(), String>
+    {
+        // Freeing a null pointer does nothing
+        if ptr == 0 {
+            return Ok(());
+        }
+
+        let block = match self.blocks.get_mut(&ptr) {
+            Some(block) => block,
+            None => return Err(format!(
+                "AddressSanitizer: invalid free of address 0x{:x} at pc {}, which is not the start of an allocated block",
+                ptr, pc
+            ))
+        };
+
+        if let Some(free_pc) = block.free_pc {
+            return Err(format!(
+                "AddressSanitizer: double free of address 0x{:x} at pc {}\n\
+                the block of {} bytes was allocated at pc {} and already freed at pc {}",
+                ptr, pc, block.size, block.alloc_pc, free_pc
+            ));
+        }
+
+        block.free_pc = Some(pc);
+        let size = block.size;
+        self.fill(ptr, ptr + size, FREED);
+
+        Ok(())
+    }
+
+    /// Check a memory access performed by the instruction at a given pc
+    /// The access must already be known to be within the heap
+    pub fn check(&self, addr: usize, num_bytes: usize, pc: usize, is_write: bool) -> Result<(), String>
+    {
+        let state = match self.shadow[addr..addr + num_bytes].iter().find(|&&s| s != ADDRESSABLE) {
+            Some(&state) => state,
+            None => return Ok(())
+        };
+
+        let kind = if state == FREED { "heap-use-after-free" } else { "heap-buffer-overflow" };
+        let access = if is_write { "write" } else { "read" };
+        let mut msg = format!(
+            "AddressSanitizer: {} on {} of {} bytes at address 0x{:x}, pc {}",
+            kind, access, num_bytes, addr, pc
+        );
+
+        // Describe the block closest to the faulting address,
+        // preferring the block before it since overflows are more common
+        let prev = self.blocks.range(..=addr).next_back();
+        let next = self.blocks.range(addr + 1..).next();
+
+        let (&start, block) = match (prev, next) {
+            (Some(prev), _) => prev,
+            (None, Some(next)) => next,
+            (None, None) => return Err(msg)
+        };
+
+        let location = if addr < start {
+            format!("{} bytes before", start - addr)
+        } else if addr < start + block.size {
+            format!("{} bytes inside", addr - start)
+        } else {
+            format!("{} bytes after", addr - (start + block.size))
+        };
+
+        msg += &format!(
+            "\nthe address is {} a block of {} bytes allocated at pc {}",
+            location, block.size, block.alloc_pc
+        );
+
+        if let Some(free_pc) = block.free_pc {
+            msg += &format!(" and freed at pc {}", free_pc);
+        }
+
+        Err(msg)
+    }
+
+    /// Iterate over the blocks that haven't been freed,
+    /// as (address, size, allocation pc), by increasing address
+    pub fn live_blocks(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_
+    {
+        self.blocks.iter()
+            .filter(|(_, b)| b.free_pc.is_none())
+            .map(|(&ptr, b)| (ptr, b.size, b.alloc_pc))
+    }
+
+    /// Produce a report of the blocks that were never freed,
+    /// grouped by allocation pc, or None if there are no leaks
+    pub fn leak_report(&self) -> Option<String>
+    {
+        // Count and total size of leaked blocks for each allocation pc
+        let mut leaks: HashMap<usize, (usize, usize)> = HashMap::default();
+        for block in self.blocks.values().filter(|b| b.free_pc.is_none()) {
+            let entry = leaks.entry(block.alloc_pc).or_default();
+            entry.0 += 1;
+            entry.1 += block.size;
+        }
+
+        if leaks.is_empty() {
+            return None;
+        }
+
+        // Report the biggest leaks first
+        let mut leaks: Vec<_> = leaks.into_iter().collect();
+        leaks.sort_by_key(|&(pc, (_, num_bytes))| (std::cmp::Reverse(num_bytes), pc));
+
+        let total_blocks: usize = leaks.iter().map(|(_, (n, _))| n).sum();
+        let total_bytes: usize = leaks.iter().map(|(_, (_, b))| b).sum();
+
+        let mut msg = format!(
+            "LeakSanitizer: {} bytes leaked in {} blocks",
+            total_bytes, total_blocks
+        );
+
+        for (pc, (num_blocks, num_bytes)) in leaks {
+            msg += &format!(
+                "\n  {} bytes in {} blocks allocated at pc {}",
+                num_bytes, num_blocks, pc
+            );
+        }
+
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_overflow()
+    {
+        let mut asan = Asan::new(256, true);
+        asan.on_alloc(72, 13, 5);
+
+        assert!(asan.check(72, 8, 0, false).is_ok());
+        assert!(asan.check(84, 1, 0, true).is_ok());
+
+        // One past the end, in the padding
+        let msg = asan.check(85, 1, 9, true).unwrap_err();
+        assert!(msg.contains("heap-buffer-overflow on write"));
+        assert!(msg.contains("0 bytes after a block of 13 bytes allocated at pc 5"));
+
+        // In the header of the next block
+        assert!(asan.check(88, 8, 0, false).is_err());
+
+        // In the header
+        let msg = asan.check(68, 4, 9, false).unwrap_err();
+        assert!(msg.contains("4 bytes before"));
+
+        // Memory outside of the allocator is addressable
+        assert!(asan.check(0, 64, 0, false).is_ok());
+        assert!(asan.check(96, 8, 0, false).is_ok());
+    }
+
+    #[test]
+    fn test_use_after_free()
+    {
+        let mut asan = Asan::new(256, true);
+        asan.on_alloc(8, 16, 1);
+        asan.on_free(8, 2).unwrap();
+
+        let msg = asan.check(16, 8, 3, false).unwrap_err();
+        assert!(msg.contains("heap-use-after-free"));
+        assert!(msg.contains("8 bytes inside a block of 16 bytes allocated at pc 1 and freed at pc 2"));
+    }
+
+    #[test]
+    fn test_invalid_free()
+    {
+        let mut asan = Asan::new(256, true);
+        asan.on_alloc(8, 16, 1);
+
+        assert!(asan.on_free(0, 2).is_ok());
+        assert!(asan.on_free(12, 2).unwrap_err().contains("invalid free"));
+
+        asan.on_free(8, 2).unwrap();
+        assert!(asan.on_free(8, 3).unwrap_err().contains("double free"));
+    }
+
+    #[test]
+    fn test_leak_report()
+    {
+        let mut asan = Asan::new(0, false);
+        assert!(asan.leak_report().is_none());
+
+        asan.on_alloc(8, 16, 1);
+        asan.on_alloc(32, 100, 7);
+        asan.on_alloc(140, 4, 7);
+        asan.on_alloc(152, 8, 3);
+        asan.on_free(152, 4).unwrap();
+
+        let live: Vec<_> = asan.live_blocks().collect();
+        assert_eq!(live, vec![(8, 16, 1), (32, 100, 7), (140, 4, 7)]);
+
+        let report = asan.leak_report().unwrap();
+        assert_eq!(report, "\
+            LeakSanitizer: 120 bytes leaked in 3 blocks\n  \
+            104 bytes in 2 blocks allocated at pc 7\n  \
+            16 bytes in 1 blocks allocated at pc 1"
+        );
+
+        asan.on_free(8, 5).unwrap();
+        asan.on_free(32, 5).unwrap();
+        asan.on_free(140, 5).unwrap();
+        assert!(asan.leak_report().is_none());
+    }
+}
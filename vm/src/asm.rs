@@ -1,9 +1,15 @@
 use std::fmt;
 use std::convert::{TryFrom};
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::mem::transmute;
+use std::borrow::Cow;
 use crate::vm::{VM, MemBlock, Op};
+use crate::image::{Image, Metadata, LineTable, Section, Reloc, RelocKind, Import, Export, ExportTable};
+use crate::utils::{parse_version, NameMap};
+use crate::sys::crypto::crc32;
+use crate::verify::{verify_restricted, OpRestrictions, OpClass};
 
 #[derive(Debug)]
 pub struct ParseError
@@ -45,10 +51,12 @@ impl fmt::Display for ParseError
     }
 }
 
+/// Input source, which is lexed as a stream of bytes. Non-ASCII
+/// characters can only appear inside of strings and comments.
 #[derive(Debug)]
-struct Input
+struct Input<'a>
 {
-    input: Vec<char>,
+    input: &'a [u8],
 
     idx: usize,
 
@@ -57,12 +65,12 @@ struct Input
     col_no: usize,
 }
 
-impl Input
+impl<'a> Input<'a>
 {
-    fn new(input: String) -> Self
+    fn new(input: &'a str) -> Self
     {
         Self {
-            input: input.chars().collect(),
+            input: input.as_bytes(),
             idx: 0,
             line_no: 1,
             col_no: 1,
@@ -82,38 +90,42 @@ impl Input
     }
 
     /// Peek at the next character in the input
+    #[inline(always)]
     fn peek_ch(&self) -> char
     {
         if self.idx < self.input.len() {
-            return self.input[self.idx]
+            return self.input[self.idx] as char
         }
 
         '\0'
     }
 
     /// Consume one character from the input
+    #[inline(always)]
     fn eat_ch(&mut self) -> char
     {
         if self.idx >= self.input.len() {
             panic!("unexpected end of input");
         }
 
-        let ch = self.input[self.idx];
+        let byte = self.input[self.idx];
         self.idx += 1;
 
-        if ch == '\n' {
+        if byte == b'\n' {
             self.line_no += 1;
             self.col_no = 1;
         }
-        else
+        else if byte & 0xC0 != 0x80
         {
+            // Columns count characters, not UTF-8 continuation bytes
             self.col_no += 1;
         }
 
-        ch
+        byte as char
     }
 
     /// Consume whitespace characters (excluding newlines)
+    #[inline(always)]
     fn eat_ws(&mut self) -> Result<(), ParseError>
     {
         loop
@@ -150,6 +162,7 @@ impl Input
     }
 
     /// Check that a separator character is present
+    #[inline(always)]
     fn expect_sep(&mut self) -> Result<(), ParseError>
     {
         match self.peek_ch() {
@@ -231,18 +244,12 @@ impl Input
     }
 
     /// Check if the input matches a given string
+    #[inline(always)]
     fn match_str(&mut self, token: &str) -> bool
     {
-        let tok_chars: Vec<char> = token.chars().collect();
-        let tok_end_idx = self.idx + tok_chars.len();
-
-        if tok_end_idx > self.input.len() {
-            return false;
-        }
-
         // If the token matches the input
-        if self.input[self.idx .. tok_end_idx] == tok_chars {
-            for i in 0..tok_chars.len() {
+        if self.input[self.idx..].starts_with(token.as_bytes()) {
+            for i in 0..token.len() {
                 self.eat_ch();
             }
 
@@ -253,6 +260,7 @@ impl Input
     }
 
     /// Expect a token to be present, which can be preceded by whitespace
+    #[inline(always)]
     fn expect_token(&mut self, token: &str) -> Result<(), ParseError>
     {
         self.eat_ws()?;
@@ -346,7 +354,7 @@ impl Input
         }
 
         let end_idx = self.idx;
-        let number_str: String = self.input[start_idx..end_idx].iter().collect();
+        let number_str = std::str::from_utf8(&self.input[start_idx..end_idx]).unwrap();
 
         match number_str.parse::<FloatType>() {
             Ok(float_val) => Ok(float_val),
@@ -355,12 +363,26 @@ impl Input
     }
 
     /// Parse a string literal
-    fn parse_str(&mut self) -> Result<String, ParseError>
+    fn parse_str(&mut self) -> Result<Cow<'a, str>, ParseError>
     {
         let open_ch = self.eat_ch();
         assert!(open_ch == '"');
 
-        let mut out = String::new();
+        // Strings without escape sequences borrow from the input
+        let start_idx = self.idx;
+        while !self.eof() && self.peek_ch() != open_ch && self.peek_ch() != '\\' {
+            self.eat_ch();
+        }
+
+        // The input is valid UTF-8 and strings end on an ASCII quote
+        if self.peek_ch() == open_ch {
+            let bytes = &self.input[start_idx..self.idx];
+            self.eat_ch();
+            return Ok(Cow::Borrowed(std::str::from_utf8(bytes).unwrap()));
+        }
+
+        // The bytes of non-ASCII characters are copied as they are
+        let mut out: Vec<u8> = self.input[start_idx..self.idx].to_vec();
 
         loop
         {
@@ -376,13 +398,13 @@ impl Input
 
             if ch == '\\' {
                 match self.eat_ch() {
-                    '\\' => out.push('\\'),
-                    '\'' => out.push('\''),
-                    '\"' => out.push('\"'),
-                    't' => out.push('\t'),
-                    'r' => out.push('\r'),
-                    'n' => out.push('\n'),
-                    '0' => out.push('\0'),
+                    '\\' => out.push(b'\\'),
+                    '\'' => out.push(b'\''),
+                    '\"' => out.push(b'\"'),
+                    't' => out.push(b'\t'),
+                    'r' => out.push(b'\r'),
+                    'n' => out.push(b'\n'),
+                    '0' => out.push(b'\0'),
 
                     // Hexadecimal escape sequence
                     'x' => {
@@ -392,7 +414,8 @@ impl Input
                         match (digit0, digit1) {
                             (Some(d0), Some(d1)) => {
                                 let byte_val = ((d0 << 4) + d1) as u8;
-                                out.push(byte_val as char);
+                                let mut buf = [0; 4];
+                                out.extend((byte_val as char).encode_utf8(&mut buf).as_bytes());
                             }
                             _ => return self.parse_error("invalid hexadecimal escape sequence")
                         }
@@ -404,48 +427,38 @@ impl Input
                 continue;
             }
 
-            out.push(ch);
+            out.push(ch as u8);
         }
 
-        return Ok(out);
+        return Ok(Cow::Owned(String::from_utf8(out).unwrap()));
     }
 
-    /// Parse an identifier
-    fn parse_ident(&mut self) -> Result<String, ParseError>
+    /// Parse an identifier, which borrows from the input
+    fn parse_ident(&mut self) -> Result<&'a str, ParseError>
     {
-        let mut ident = "".to_string();
-
-        loop
-        {
-            let ch = self.peek_ch();
+        let start_idx = self.idx;
 
-            if ch == '\0' {
-                break;
-            }
+        while self.idx < self.input.len() {
+            let ch = self.input[self.idx];
 
-            if !ch.is_ascii_alphanumeric() && ch != '_' {
+            if !ch.is_ascii_alphanumeric() && ch != b'_' {
                 break;
             }
 
-            ident.push(ch);
-            self.eat_ch();
+            // Identifiers never contain newlines
+            self.idx += 1;
+            self.col_no += 1;
         }
 
-        if ident.len() == 0 {
+        if self.idx == start_idx {
             return self.parse_error("expected identifier");
         }
 
-        Ok(ident)
+        // Identifiers are ASCII-only
+        Ok(std::str::from_utf8(&self.input[start_idx..self.idx]).unwrap())
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-enum Section
-{
-    Code,
-    Data,
-}
-
 #[derive(Copy, Clone)]
 struct LabelDef
 {
@@ -463,10 +476,10 @@ enum LabelRefKind
     Offset32(usize),
 }
 
+#[derive(Copy, Clone)]
 struct LabelRef
 {
     section: Section,
-    name: String,
     pos: usize,
     line_no: usize,
     col_no: usize,
@@ -476,10 +489,10 @@ struct LabelRef
 pub struct Assembler
 {
     /// Map of available special constants
-    const_map: HashMap<String, i128>,
+    const_map: NameMap<String, i128>,
 
     /// Map of available syscalls
-    syscall_map: HashMap<String, u16>,
+    syscall_map: NameMap<String, u16>,
 
     /// Set of syscalls referenced by this program
     syscall_set: HashSet<u16>,
@@ -491,13 +504,48 @@ pub struct Assembler
     data: MemBlock,
 
     /// Label definitions (name, position)
-    label_defs: HashMap<String, LabelDef>,
+    label_defs: NameMap<String, LabelDef>,
+
+    /// Relocation table for absolute label addresses
+    relocs: Vec<Reloc>,
+
+    /// Labels defined by other images
+    imports: HashSet<String>,
+
+    /// Labels to export to other images (name, line, column)
+    exports: Vec<(String, usize, usize)>,
 
-    /// References to labels (name, position)
-    label_refs: Vec<LabelRef>,
+    /// References to labels not defined yet (name, reference),
+    /// which get backpatched once all labels are known
+    label_refs: Vec<(String, LabelRef)>,
+
+    /// Interned strings (label, text, line, column), which are
+    /// placed in a shared string table after the data section
+    interned: Vec<(String, String, usize, usize)>,
+
+    /// Program information for the image header
+    metadata: Metadata,
+
+    /// Name of the file being assembled, if it is read from a file
+    src_name: Option<String>,
+
+    /// Source line set with .loc, which the following code comes from
+    loc: Option<(String, u32)>,
+
+    /// Source lines of the code
+    line_table: LineTable,
 
     /// Current section
     section: Section,
+
+    /// Export all the labels instead of only those listed with .export
+    export_all: bool,
+
+    /// Run the bytecode verifier on the main program image
+    verify: bool,
+
+    /// Classes of instructions the main program may not use
+    restrictions: OpRestrictions,
 }
 
 impl Assembler
@@ -518,7 +566,7 @@ impl Assembler
 
         /// Populate the available syscalls
         use crate::sys::constants::SYSCALL_DESCS;
-        let mut syscall_map = HashMap::new();
+        let mut syscall_map = NameMap::default();
         for syscall in SYSCALL_DESCS {
             if let Some(syscall) = syscall {
                 syscall_map.insert(syscall.name.to_string(), syscall.const_idx);
@@ -526,19 +574,64 @@ impl Assembler
         }
 
         Self {
-            const_map: HashMap::new(),
+            const_map: NameMap::default(),
             syscall_map: syscall_map,
             syscall_set: HashSet::new(),
             code: MemBlock::new(),
             data: MemBlock::new(),
-            label_defs: HashMap::default(),
+            label_defs: NameMap::default(),
+            relocs: Vec::default(),
+            imports: HashSet::default(),
+            exports: Vec::default(),
             label_refs: Vec::default(),
+            interned: Vec::default(),
+            metadata: Metadata::default(),
+            src_name: None,
+            loc: None,
+            line_table: LineTable::default(),
             section: Section::Code,
+            export_all: false,
+            verify: false,
+            restrictions: OpRestrictions::default(),
         }
     }
 
-    fn parse_input(mut self, input: &mut Input) -> Result<VM, ParseError>
+    /// Verify the code of the program before creating a VM
+    /// to run it, so that malformed code is reported up front
+    pub fn verified(mut self) -> Self
+    {
+        self.verify = true;
+        self
+    }
+
+    /// Reject programs using some classes of instructions, which implies
+    /// verifying them, and create a VM which only runs verified code
+    pub fn restricted(mut self, restrictions: OpRestrictions) -> Self
+    {
+        self.verify = true;
+        self.restrictions = restrictions;
+        self
+    }
+
+    /// Assemble code to be appended after previously loaded code. Labels
+    /// this image doesn't define are imported from the given symbols,
+    /// and all its labels are exported so later images can use them.
+    pub fn incremental(mut self, symbols: &ExportTable) -> Self
     {
+        self.imports.extend(symbols.keys().cloned());
+        self.export_all = true;
+        self
+    }
+
+    fn parse_input(mut self, input: &mut Input) -> Result<Image, ParseError>
+    {
+        // Preallocate the output buffers to avoid regrowing them for large
+        // inputs. Each code byte takes at least a few bytes of source, and
+        // each label definition is followed by a colon.
+        let num_colons = input.input.iter().filter(|&&ch| ch == b':').count();
+        self.label_defs.reserve(num_colons);
+        self.code.reserve(input.input.len() / 4);
+
         // Until we've reached the end of the input
         loop
         {
@@ -551,80 +644,238 @@ impl Assembler
             self.parse_line(input)?;
         }
 
-        // Link the labels
-        for label_ref in self.label_refs {
-            let def = self.label_defs.get(&label_ref.name);
+        self.layout_strings()?;
 
-            if def.is_none() {
-                return Err(ParseError {
-                    msg: format!("label not found {}", label_ref.name),
+        // Files with .loc directives were generated from other sources, and
+        // the code before the first directive, such as the startup code of
+        // a C program, has no source line
+        if let (Some(_), Some(src_name)) = (&self.loc, &self.src_name) {
+            self.line_table.remove_file(src_name);
+        }
+
+        let mut imports = Vec::default();
+
+        // Link the forward label references
+        for (name, label_ref) in std::mem::take(&mut self.label_refs) {
+            match self.label_defs.get(&name) {
+                Some(def) => {
+                    let def = *def;
+                    self.patch_label_ref(&name, &label_ref, &def)?;
+                }
+
+                // References to imported labels get patched when the image is loaded
+                None if self.imports.contains(&name) => {
+                    imports.push(Import {
+                        name,
+                        section: label_ref.section,
+                        pos: label_ref.pos,
+                        kind: match label_ref.kind {
+                            LabelRefKind::Address32 => RelocKind::Abs32,
+                            LabelRefKind::Address64 => RelocKind::Abs64,
+                            LabelRefKind::Offset32(end_offset) => RelocKind::Rel32 { end_offset },
+                        }
+                    });
+                }
+
+                None => return Err(ParseError {
+                    msg: format!("label not found {}", name),
                     line_no: label_ref.line_no,
                     col_no: label_ref.col_no,
-                });
+                })
             }
+        }
 
-            let def = *def.unwrap();
+        let mut exports = Vec::default();
+        for (name, line_no, col_no) in self.exports {
+            match self.label_defs.get(&name) {
+                Some(def) => exports.push(Export { name, section: def.section, pos: def.pos }),
+                None => return Err(ParseError {
+                    msg: format!("exported label not found {}", name),
+                    line_no,
+                    col_no,
+                })
+            }
+        }
 
-            match label_ref.kind {
-                LabelRefKind::Address32 => {
-                    let ptr32 = u32::try_from(def.pos);
+        if self.export_all {
+            exports = self.label_defs.iter().map(|(name, def)| {
+                Export { name: name.clone(), section: def.section, pos: def.pos }
+            }).collect();
+        }
 
-                    if ptr32.is_err() {
-                        return Err(ParseError {
-                            msg: format!("address doesn't fit in u32 {}", label_ref.name),
-                            line_no: label_ref.line_no,
-                            col_no: label_ref.col_no,
-                        });
-                    }
+        let mut code_labels: Vec<(String, usize)> = self.label_defs.into_iter()
+            .filter(|(_, def)| def.section == Section::Code)
+            .map(|(name, def)| (name, def.pos))
+            .collect();
+        code_labels.sort_by_key(|(_, pos)| *pos);
+
+        let data_checksum = crc32(0, self.data.as_slice());
+
+        Ok(Image {
+            code: self.code,
+            data: self.data,
+            relocs: self.relocs,
+            imports,
+            exports,
+            code_labels,
+            line_table: self.line_table,
+            syscalls: self.syscall_set,
+            metadata: Metadata {
+                data_checksum,
+                ..self.metadata
+            },
+            code_base: 0,
+            data_base: 0,
+        })
+    }
 
-                    match label_ref.section {
-                        Section::Code => self.code.write(label_ref.pos, ptr32.unwrap()),
-                        Section::Data => self.data.write(label_ref.pos, ptr32.unwrap()),
+    /// Append the string table to the data section and define the labels
+    /// of the interned strings. Identical strings are stored only once, and
+    /// strings that are a suffix of another string point into that string.
+    fn layout_strings(&mut self) -> Result<(), ParseError>
+    {
+        let interned = std::mem::take(&mut self.interned);
+
+        // Sort the distinct strings by their reversed bytes, so that each
+        // string comes right before the strings it is a suffix of
+        let mut strs: Vec<&[u8]> = interned.iter().map(|(_, text, ..)| text.as_bytes()).collect();
+        strs.sort_by(|a, b| a.iter().rev().cmp(b.iter().rev()));
+        strs.dedup();
+
+        // Place the longest strings first, and their suffixes inside of them
+        let mut offsets: HashMap<&[u8], usize> = HashMap::default();
+        let mut prev: Option<(&[u8], usize)> = None;
+
+        for s in strs.into_iter().rev() {
+            match prev {
+                Some((p, p_pos)) if p.ends_with(s) => {
+                    offsets.insert(s, p_pos + p.len() - s.len());
+                }
+                _ => {
+                    let pos = self.data.len();
+                    for &byte in s {
+                        self.data.push_u8(byte);
                     }
+                    self.data.push_u8(0);
+
+                    offsets.insert(s, pos);
+                    prev = Some((s, pos));
                 }
+            }
+        }
 
-                LabelRefKind::Address64 => {
-                    let ptr64 = def.pos as u64;
+        for (name, text, line_no, col_no) in &interned {
+            if self.label_defs.contains_key(name) {
+                return Err(ParseError {
+                    msg: format!("label already defined {}", name),
+                    line_no: *line_no,
+                    col_no: *col_no,
+                });
+            }
 
-                    match label_ref.section {
-                        Section::Code => self.code.write(label_ref.pos, ptr64),
-                        Section::Data => self.data.write(label_ref.pos, ptr64),
-                    }
+            self.label_defs.insert(
+                name.clone(),
+                LabelDef {
+                    section: Section::Data,
+                    pos: offsets[text.as_bytes()],
+                    line_no: *line_no,
+                    col_no: *col_no,
                 }
+            );
+        }
+
+        Ok(())
+    }
 
-                LabelRefKind::Offset32(end_offset) => {
-                    assert!(def.section == Section::Code);
-                    assert!(def.section == label_ref.section);
-                    let offs32 = (def.pos as i32) - ((label_ref.pos + end_offset) as i32 + 4);
+    pub fn parse_file(self, file_name: &str) -> Result<VM, ParseError>
+    {
+        let bytes = Self::read_file(file_name)?;
+        self.parse_bytes(file_name, bytes)
+    }
 
-                    match label_ref.section {
-                        Section::Code => self.code.write(label_ref.pos, offs32),
-                        Section::Data => self.data.write(label_ref.pos, offs32),
-                    }
-                }
-            }
+    /// Parse the contents of a file which was already read
+    pub fn parse_bytes(mut self, file_name: &str, bytes: Vec<u8>) -> Result<VM, ParseError>
+    {
+        let verify = self.verify;
+        let restrictions = std::mem::take(&mut self.restrictions);
+        Self::main_image(self.assemble_bytes(file_name, bytes)?, verify, restrictions)
+    }
+
+    /// Parse a string of source code
+    pub fn parse_str(mut self, src: &str) -> Result<VM, ParseError>
+    {
+        let verify = self.verify;
+        let restrictions = std::mem::take(&mut self.restrictions);
+        Self::main_image(self.assemble_str(src)?, verify, restrictions)
+    }
+
+    /// Create a VM for the main program image,
+    /// which has no other images to import symbols from
+    fn main_image(image: Image, verify: bool, restrictions: OpRestrictions) -> Result<VM, ParseError>
+    {
+        if let Some(import) = image.imports.first() {
+            return ParseError::msg_only(&format!("unresolved import {}", import.name));
+        }
+
+        if let Err(msg) = image.validate() {
+            return ParseError::msg_only(&msg);
+        }
+
+        let checked = match verify {
+            true => verify_restricted(&image, &restrictions).or_else(|msg| ParseError::msg_only(&msg))?,
+            false => Vec::default(),
+        };
+
+        let mut vm = image.into_vm();
+        if restrictions.denies(OpClass::HeapGrow) {
+            vm.lock_heap();
+        }
+        if !restrictions.is_empty() {
+            vm.restrict_to_verified(restrictions, checked);
         }
 
-        Ok(VM::new(self.code, self.data, self.syscall_set))
+        Ok(vm)
     }
 
-    pub fn parse_file(mut self, file_name: &str) -> Result<VM, ParseError>
+    /// Assemble a source file into a relocatable image. Binary image
+    /// files are loaded as they are, without assembling anything.
+    pub fn assemble_file(self, file_name: &str) -> Result<Image, ParseError>
     {
-        match std::fs::read_to_string(file_name) {
-            Err(_) => {
-                ParseError::msg_only(&format!("could not open asm file \"{}\"", file_name))
-            }
+        let bytes = Self::read_file(file_name)?;
+        self.assemble_bytes(file_name, bytes)
+    }
+
+    /// Read a source or binary image file, to be
+    /// assembled with assemble_bytes or parse_bytes
+    pub fn read_file(file_name: &str) -> Result<Vec<u8>, ParseError>
+    {
+        match std::fs::read(file_name) {
+            Ok(bytes) => Ok(bytes),
+            Err(_) => ParseError::msg_only(&format!("could not open asm file \"{}\"", file_name)),
+        }
+    }
+
+    /// Assemble the contents of a file which was already read
+    pub fn assemble_bytes(mut self, file_name: &str, bytes: Vec<u8>) -> Result<Image, ParseError>
+    {
+        if Image::is_binary(&bytes) {
+            return Image::from_bytes(&bytes).or_else(|msg| ParseError::msg_only(&msg));
+        }
+
+        match String::from_utf8(bytes) {
             Ok(input_str) => {
-                let mut input = Input::new(input_str);
+                let mut input = Input::new(&input_str);
+                self.src_name = Some(file_name.to_string());
                 self.parse_input(&mut input)
             }
+            Err(_) => ParseError::msg_only(&format!("asm file \"{}\" is not valid UTF-8", file_name)),
         }
     }
 
-    /// Parse a string of source code
-    pub fn parse_str(mut self, src: &str) -> Result<VM, ParseError>
+    /// Assemble a string of source code into a relocatable image
+    pub fn assemble_str(self, src: &str) -> Result<Image, ParseError>
     {
-        let mut input = Input::new(src.to_string());
+        let mut input = Input::new(src);
         return self.parse_input(&mut input);
     }
 
@@ -640,7 +891,7 @@ impl Assembler
             input.eat_ch();
             let const_name = input.parse_ident()?;
 
-            if let Some(int_val) = self.const_map.get(&const_name) {
+            if let Some(int_val) = self.const_map.get(const_name) {
                 return match (*int_val).try_into() {
                     Ok(out_val) => Ok(out_val),
                     Err(_) => input.parse_error("special constant did not fit required size")
@@ -674,27 +925,85 @@ impl Assembler
         }
     }
 
-    /// Add a new label reference at the current position
-    fn add_label_ref(&mut self, input: &Input, name: String, kind: LabelRefKind)
+    /// Add a new label reference at the current position. References to
+    /// labels which are already defined are patched immediately.
+    fn add_label_ref(&mut self, input: &Input, name: &str, kind: LabelRefKind) -> Result<(), ParseError>
     {
-        let label_ref_pos = self.mem().len();
-
-        self.label_refs.push(
-            LabelRef {
-                section: self.section,
-                name: name,
-                pos: label_ref_pos,
-                line_no: input.line_no,
-                col_no: input.line_no,
-                kind: kind
-            }
-        );
+        let label_ref = LabelRef {
+            section: self.section,
+            pos: self.mem().len(),
+            line_no: input.line_no,
+            col_no: input.col_no,
+            kind: kind
+        };
 
         match kind {
             LabelRefKind::Address32 => self.mem().push_u32(0),
             LabelRefKind::Address64 => self.mem().push_u64(0),
             LabelRefKind::Offset32(_) => self.mem().push_u32(0),
         }
+
+        match self.label_defs.get(name) {
+            Some(def) => {
+                let def = *def;
+                self.patch_label_ref(name, &label_ref, &def)
+            }
+            None => {
+                self.label_refs.push((name.to_string(), label_ref));
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the address or offset of a label at a reference to it
+    fn patch_label_ref(&mut self, name: &str, label_ref: &LabelRef, def: &LabelDef) -> Result<(), ParseError>
+    {
+        let mem = match label_ref.section {
+            Section::Code => &mut self.code,
+            Section::Data => &mut self.data,
+        };
+
+        match label_ref.kind {
+            LabelRefKind::Address32 => {
+                let ptr32 = match u32::try_from(def.pos) {
+                    Ok(ptr32) => ptr32,
+                    Err(_) => return Err(ParseError {
+                        msg: format!("address doesn't fit in u32 {}", name),
+                        line_no: label_ref.line_no,
+                        col_no: label_ref.col_no,
+                    })
+                };
+
+                mem.write(label_ref.pos, ptr32);
+
+                self.relocs.push(Reloc {
+                    section: label_ref.section,
+                    pos: label_ref.pos,
+                    kind: RelocKind::Abs32,
+                    target: def.section,
+                });
+            }
+
+            LabelRefKind::Address64 => {
+                mem.write(label_ref.pos, def.pos as u64);
+
+                self.relocs.push(Reloc {
+                    section: label_ref.section,
+                    pos: label_ref.pos,
+                    kind: RelocKind::Abs64,
+                    target: def.section,
+                });
+            }
+
+            LabelRefKind::Offset32(end_offset) => {
+                assert!(def.section == Section::Code);
+                assert!(def.section == label_ref.section);
+                let offs32 = (def.pos as i32) - ((label_ref.pos + end_offset) as i32 + 4);
+                mem.write(label_ref.pos, offs32);
+            }
+        }
+
+        Ok(())
     }
 
     /// Parse the current line of the input
@@ -729,23 +1038,25 @@ impl Assembler
             input.eat_ws()?;
 
             if input.match_str(":") {
-                if self.label_defs.get(&ident).is_some() {
-                    return input.parse_error(&format!("label already defined {}", ident));
-                }
+                let def = LabelDef {
+                    section: self.section,
+                    pos: self.mem().len(),
+                    line_no: input.line_no,
+                    col_no: input.col_no,
+                };
 
-                let label_pos = self.mem().len();
-                self.label_defs.insert(
-                    ident,
-                    LabelDef {
-                        section: self.section,
-                        pos: label_pos,
-                        line_no: input.line_no,
-                        col_no: input.col_no,
+                match self.label_defs.entry(ident.to_string()) {
+                    Entry::Occupied(_) => {
+                        return input.parse_error(&format!("label already defined {}", ident));
                     }
-                );
+                    Entry::Vacant(entry) => {
+                        entry.insert(def);
+                    }
+                }
             }
             else if self.section == Section::Code
             {
+                self.add_source_line(input.line_no);
                 self.parse_insn(input, ident)?;
             }
 
@@ -755,10 +1066,23 @@ impl Assembler
         input.parse_error("invalid input")
     }
 
+    /// Record the source line of the instruction at the current position,
+    /// which is the line set with .loc, or else the line of the asm file
+    fn add_source_line(&mut self, asm_line_no: usize)
+    {
+        let pos = self.code.len();
+
+        match (&self.loc, &self.src_name) {
+            (Some((file_name, line_no)), _) => self.line_table.add(pos, file_name, *line_no),
+            (None, Some(src_name)) => self.line_table.add(pos, src_name, asm_line_no as u32),
+            (None, None) => {}
+        }
+    }
+
     /// Parse an assembler command
-    fn parse_cmd(&mut self, input: &mut Input, cmd: String) -> Result<(), ParseError>
+    fn parse_cmd(&mut self, input: &mut Input, cmd: &str) -> Result<(), ParseError>
     {
-        match cmd.as_str() {
+        match cmd {
             "code" => self.section = Section::Code,
             "data" => self.section = Section::Data,
 
@@ -769,19 +1093,13 @@ impl Assembler
                 let pos_rem = cur_pos % align_bytes;
 
                 if pos_rem != 0 {
-                    let delta = align_bytes - pos_rem;
-                    for i in 0..delta {
-                        mem.push_u8(0);
-                    }
+                    mem.push_zeros(align_bytes - pos_rem);
                 }
             }
 
             "zero" => {
                 let num_bytes: u32 = self.parse_int_arg(input)?;
-                let mem = self.mem();
-                for i in 0..num_bytes {
-                    mem.push_u8(0);
-                }
+                self.mem().push_zeros(num_bytes as usize);
             }
 
             "fill" => {
@@ -851,6 +1169,12 @@ impl Assembler
                 self.mem().push_u32(val_u32);
             }
 
+            // 64-bit floating-point value
+            "f64" => {
+                let val: f64 = input.parse_float()?;
+                self.mem().push_u64(val.to_bits());
+            }
+
             // Command to read an arbitrary number of bytes
             // with optional whitespace between bytes
             "hex" => {
@@ -888,18 +1212,94 @@ impl Assembler
                 let val = input.parse_str()?;
 
                 let mem = self.mem();
-                for byte in val.bytes() {
-                    mem.push_u8(byte);
-                }
+                mem.push_bytes(val.as_bytes());
 
                 // Write a null terminator byte
                 mem.push_u8(0);
             }
 
+            // Null-terminated UTF-8 string in the string table,
+            // which may share its storage with other strings
+            "intern" => {
+                let label_name = input.parse_ident()?;
+                input.expect_token(",")?;
+                input.eat_ws()?;
+                let val = input.parse_str()?;
+                self.interned.push((label_name.to_string(), val.into_owned(), input.line_no, input.col_no));
+            }
+
+            // Label defined by another image, resolved when loading
+            "import" => {
+                let label_name = input.parse_ident()?;
+                self.imports.insert(label_name.to_string());
+            }
+
+            // Label made available to other images
+            "export" => {
+                let label_name = input.parse_ident()?;
+                self.exports.push((label_name.to_string(), input.line_no, input.col_no));
+            }
+
+            // Program name stored in the image header
+            "name" => {
+                if self.metadata.name.is_some() {
+                    return input.parse_error("program name already set");
+                }
+                self.metadata.name = Some(input.parse_str()?.into_owned());
+            }
+
+            // Program author stored in the image header
+            "author" => {
+                if self.metadata.author.is_some() {
+                    return input.parse_error("program author already set");
+                }
+                self.metadata.author = Some(input.parse_str()?.into_owned());
+            }
+
+            // Permission required by the program, checked when loading
+            "permission" => {
+                let perm = input.parse_ident()?;
+                if !self.metadata.permissions.iter().any(|p| p == perm) {
+                    self.metadata.permissions.push(perm.to_string());
+                }
+            }
+
+            // Oldest VM version able to run the program, "major.minor.patch"
+            "min_vm_version" => {
+                let version = input.parse_str()?;
+                match parse_version(&version) {
+                    Some(version) => self.metadata.min_vm_version = Some(version),
+                    None => return input.parse_error(&format!("invalid version \"{}\"", version))
+                }
+            }
+
+            // Source line the following code was compiled from,
+            // for coverage reports, e.g. .loc "file.c", 12;
+            "loc" => {
+                if input.peek_ch() != '"' {
+                    return input.parse_error("expected source file name");
+                }
+                let file_name = input.parse_str()?;
+                input.expect_token(",")?;
+                let line_no = self.parse_int_arg::<u32>(input)?;
+                self.loc = Some((file_name.into_owned(), line_no));
+            }
+
+            // Check of the number of values on the stack of the current
+            // function, including its locals, e.g. .assert_stack 2;
+            "assert_stack" => {
+                if self.section != Section::Code {
+                    return input.parse_error(".assert_stack outside of the code section");
+                }
+                let depth: u16 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::assert_stack);
+                self.code.push_u16(depth);
+            }
+
             // Absolute 64-bit address of a label
             "addr64" => {
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Address64);
+                self.add_label_ref(input, label_name, LabelRefKind::Address64)?;
             }
 
             _ => {
@@ -913,11 +1313,13 @@ impl Assembler
     }
 
     /// Parse an instruction and its arguments
-    fn parse_insn(&mut self, input: &mut Input, op_name: String) -> Result<(), ParseError>
+    fn parse_insn(&mut self, input: &mut Input, op_name: &str) -> Result<(), ParseError>
     {
-        match op_name.as_str() {
+        match op_name {
             "panic" => self.code.push_op(Op::panic),
             "nop" => self.code.push_op(Op::nop),
+            "breakpoint" => self.code.push_op(Op::breakpoint),
+            "trap" => self.code.push_op(Op::trap),
 
             "pop" => self.code.push_op(Op::pop),
             "dup" => self.code.push_op(Op::dup),
@@ -973,6 +1375,12 @@ impl Assembler
                 self.code.push_op(Op::push_2);
             }
 
+            "enter" => {
+                let n: u8 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::enter);
+                self.code.push_u8(n);
+            }
+
             "push_0n" => {
                 let n: u8 = self.parse_int_arg(input)?;
                 self.code.push_op(Op::push_0n);
@@ -1001,7 +1409,7 @@ impl Assembler
             "push_p32" => {
                 let label_name = input.parse_ident()?;
                 self.code.push_op(Op::push_u32);
-                self.add_label_ref(input, label_name, LabelRefKind::Address32);
+                self.add_label_ref(input, label_name, LabelRefKind::Address32)?;
             }
 
             // Push a 32-bit floating-point value
@@ -1012,6 +1420,12 @@ impl Assembler
                 self.code.push_u32(val_u32);
             }
 
+            "push_f64" => {
+                let val: f64 = input.parse_float()?;
+                self.code.push_op(Op::push_u64);
+                self.code.push_u64(val.to_bits());
+            }
+
             // Variable-size push
             "push" => {
                 self.gen_push(input)?;
@@ -1094,6 +1508,9 @@ impl Assembler
             "atan_f32" => self.code.push_op(Op::atan_f32),
             "pow_f32" => self.code.push_op(Op::pow_f32),
             "sqrt_f32" => self.code.push_op(Op::sqrt_f32),
+            "floor_f32" => self.code.push_op(Op::floor_f32),
+            "ceil_f32" => self.code.push_op(Op::ceil_f32),
+            "round_f32" => self.code.push_op(Op::round_f32),
 
             "eq_f32" => self.code.push_op(Op::eq_f32),
             "ne_f32" => self.code.push_op(Op::ne_f32),
@@ -1101,11 +1518,36 @@ impl Assembler
             "le_f32" => self.code.push_op(Op::le_f32),
             "gt_f32" => self.code.push_op(Op::gt_f32),
             "ge_f32" => self.code.push_op(Op::ge_f32),
+            "is_nan_f32" => self.code.push_op(Op::is_nan_f32),
+            "is_inf_f32" => self.code.push_op(Op::is_inf_f32),
 
             "i32_to_f32" => self.code.push_op(Op::i32_to_f32),
             "i64_to_f32" => self.code.push_op(Op::i64_to_f32),
             "f32_to_i32" => self.code.push_op(Op::f32_to_i32),
 
+            "add_f64" => self.code.push_op(Op::add_f64),
+            "sub_f64" => self.code.push_op(Op::sub_f64),
+            "mul_f64" => self.code.push_op(Op::mul_f64),
+            "div_f64" => self.code.push_op(Op::div_f64),
+            "sqrt_f64" => self.code.push_op(Op::sqrt_f64),
+            "floor_f64" => self.code.push_op(Op::floor_f64),
+            "ceil_f64" => self.code.push_op(Op::ceil_f64),
+            "round_f64" => self.code.push_op(Op::round_f64),
+
+            "eq_f64" => self.code.push_op(Op::eq_f64),
+            "ne_f64" => self.code.push_op(Op::ne_f64),
+            "lt_f64" => self.code.push_op(Op::lt_f64),
+            "le_f64" => self.code.push_op(Op::le_f64),
+            "gt_f64" => self.code.push_op(Op::gt_f64),
+            "ge_f64" => self.code.push_op(Op::ge_f64),
+            "is_nan_f64" => self.code.push_op(Op::is_nan_f64),
+            "is_inf_f64" => self.code.push_op(Op::is_inf_f64),
+
+            "i64_to_f64" => self.code.push_op(Op::i64_to_f64),
+            "f64_to_i64" => self.code.push_op(Op::f64_to_i64),
+            "f32_to_f64" => self.code.push_op(Op::f32_to_f64),
+            "f64_to_f32" => self.code.push_op(Op::f64_to_f32),
+
             "load_u8" => self.code.push_op(Op::load_u8),
             "load_u16" => self.code.push_op(Op::load_u16),
             "load_u32" => self.code.push_op(Op::load_u32),
@@ -1114,30 +1556,67 @@ impl Assembler
             "store_u16" => self.code.push_op(Op::store_u16),
             "store_u32" => self.code.push_op(Op::store_u32),
             "store_u64" => self.code.push_op(Op::store_u64),
+            "load_unaligned_u16" => self.code.push_op(Op::load_unaligned_u16),
+            "load_unaligned_u32" => self.code.push_op(Op::load_unaligned_u32),
+            "load_unaligned_u64" => self.code.push_op(Op::load_unaligned_u64),
+            "store_unaligned_u16" => self.code.push_op(Op::store_unaligned_u16),
+            "store_unaligned_u32" => self.code.push_op(Op::store_unaligned_u32),
+            "store_unaligned_u64" => self.code.push_op(Op::store_unaligned_u64),
 
             "jmp" => {
                 self.code.push_op(Op::jmp);
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0));
+                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0))?;
             }
 
             "jz" => {
                 self.code.push_op(Op::jz);
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0));
+                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0))?;
             }
 
             "jnz" => {
                 self.code.push_op(Op::jnz);
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0));
+                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0))?;
+            }
+
+            // Jump table, with the targets for each index
+            // followed by the default target
+            // jmp_table [L0, L1, ...], DEFAULT;
+            "jmp_table" => {
+                input.expect_token("[")?;
+                let mut targets = Vec::default();
+                loop {
+                    input.eat_ws()?;
+                    if input.match_char(']') {
+                        break;
+                    }
+                    if !targets.is_empty() {
+                        input.expect_token(",")?;
+                        input.eat_ws()?;
+                    }
+                    targets.push(input.parse_ident()?);
+                }
+                input.expect_token(",")?;
+                input.eat_ws()?;
+                let default_label = input.parse_ident()?;
+
+                // Offsets are relative to the end of the instruction
+                let num_entries = targets.len();
+                self.code.push_op(Op::jmp_table);
+                self.code.push_u32(num_entries as u32);
+                self.add_label_ref(input, default_label, LabelRefKind::Offset32(4 * num_entries))?;
+                for (idx, label_name) in targets.into_iter().enumerate() {
+                    self.add_label_ref(input, label_name, LabelRefKind::Offset32(4 * (num_entries - 1 - idx)))?;
+                }
             }
 
             "syscall" => {
                 // Get the index for this syscall
                 let syscall_idx: u16 = if input.peek_ch().is_ascii_alphabetic() {
                     let name = input.parse_ident()?;
-                    match self.syscall_map.get(&name) {
+                    match self.syscall_map.get(name) {
                         Some(syscall_idx) => *syscall_idx,
                         None => return input.parse_error(
                             &format!("unknown syscall \"{}\"", name)
@@ -1161,7 +1640,7 @@ impl Assembler
                 let argc: u8 = self.parse_int_arg(input)?;
 
                 self.code.push_op(Op::call);
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(1));
+                self.add_label_ref(input, label_name, LabelRefKind::Offset32(1))?;
                 self.code.push_u8(argc);
             }
 
@@ -1231,7 +1710,7 @@ impl Assembler
         // Assume that this must be a label reference
         let label_name = input.parse_ident()?;
         self.code.push_op(Op::push_u32);
-        self.add_label_ref(input, label_name, LabelRefKind::Address32);
+        self.add_label_ref(input, label_name, LabelRefKind::Address32)?;
 
         Ok(())
     }
@@ -1328,6 +1807,27 @@ mod tests
 
         // Callback label
         parse_ok("CB: ret; push_p32 CB; exit;");
+
+        // Backward references are patched immediately, and
+        // forward references once the label is defined
+        let image = Assembler::new().assemble_str("A: jmp A; jmp B; B: push A; push B;").unwrap();
+        assert_eq!(image.code.read::<i32>(1), -5);
+        assert_eq!(image.code.read::<i32>(6), 0);
+        assert_eq!(image.code.read::<u32>(11), 0);
+        assert_eq!(image.code.read::<u32>(16), 10);
+        assert_eq!(image.relocs.len(), 2);
+
+        // Jump table, with offsets relative to the end of the instruction
+        let image = Assembler::new().assemble_str("A: push_0; jmp_table [A, B], B; B: exit;").unwrap();
+        assert_eq!(image.code.read::<u32>(2), 2);
+        assert_eq!(image.code.read::<i32>(6), 0);
+        assert_eq!(image.code.read::<i32>(10), -18);
+        assert_eq!(image.code.read::<i32>(14), 0);
+        parse_fails("jmp_table [A B], A; A: exit;");
+        parse_fails("jmp_table [A], B; A: exit;");
+
+        parse_fails("jmp FOO;");
+        parse_fails("FOO: FOO:");
     }
 
     #[test]
@@ -1336,6 +1836,97 @@ mod tests
         parse_ok(".data; .stringz \"foo\";");
         parse_ok(".data; .stringz \"foo\\nbar\";");
         parse_ok(".data; .stringz \"foo\\nbar\\xAA\";");
+        parse_ok(".data; .stringz \"héllo wörld\";");
+        parse_ok(".data; .intern S, \"foo\"; .code; push S; exit;");
+        parse_fails(".intern S, \"foo\"; .intern S, \"bar\";");
+        parse_fails(".intern S, \"foo\"; S: ret;");
+    }
+
+    #[test]
+    fn test_utf8()
+    {
+        // Strings keep their non-ASCII characters, and \x escapes
+        // produce the character with that code point
+        let image = Assembler::new().assemble_str(".data; .stringz \"é\\xE9\";").unwrap();
+        assert_eq!(&image.data.as_slice()[..5], "éé\0".as_bytes());
+
+        // Columns count characters, not bytes
+        let error = Assembler::new().assemble_str("/* é */ foo;").err().unwrap();
+        assert_eq!((error.line_no, error.col_no), (1, 12));
+    }
+
+    #[test]
+    fn test_intern()
+    {
+        let image = Assembler::new().assemble_str("
+            .data;
+            .u8 1;
+            .addr64 C;
+            .addr64 B;
+            .intern A, \"hello world\";
+            .intern B, \"world\";
+            .intern C, \"hello world\";
+            .intern D, \"\";
+            .intern E, \"foo\";
+            .code;
+            push A; exit;
+        ").unwrap();
+
+        // Identical strings and suffixes share their storage
+        let strs = &image.data.as_slice()[17..];
+        assert_eq!(strs, b"foo\0hello world\0");
+        assert_eq!(image.data.read::<u64>(1), 21);
+        assert_eq!(image.data.read::<u64>(9), 27);
+    }
+
+    #[test]
+    fn test_metadata()
+    {
+        let image = Assembler::new().assemble_str("
+            .name \"demo\";
+            .author \"someone\";
+            .permission window_display;
+            .permission window_display;
+            .min_vm_version \"0.1.0\";
+            .data;
+            .u32 7;
+            .code;
+            push 0; exit;
+        ").unwrap();
+
+        let meta = &image.metadata;
+        assert_eq!(meta.name.as_deref(), Some("demo"));
+        assert_eq!(meta.author.as_deref(), Some("someone"));
+        assert_eq!(meta.permissions, vec!["window_display".to_string()]);
+        assert_eq!(meta.min_vm_version, Some((0, 1, 0)));
+        assert_eq!(meta.data_checksum, crc32(0, &7u32.to_le_bytes()));
+        assert!(image.validate().is_ok());
+
+        parse_fails(".name \"a\"; .name \"b\";");
+        parse_ok("push 0; .assert_stack 1; exit;");
+        parse_fails(".data; .assert_stack 0;");
+        parse_fails(".assert_stack 70000;");
+        parse_fails(".min_vm_version \"1.2\";");
+    }
+
+    #[test]
+    fn test_metadata_validate()
+    {
+        // Programs that don't declare permissions aren't checked
+        parse_ok("push 0; push 0; push 0; push 0; syscall window_create; exit;");
+
+        // Syscalls need their permission once any is declared
+        parse_ok(".permission window_display; push 0; push 0; push 0; push 0; syscall window_create; exit;");
+        parse_fails(".permission time_get_time; push 0; push 0; push 0; push 0; syscall window_create; exit;");
+        parse_ok(".permission time_get_time; push 0; push 0; syscall memcpy; push 0; exit;");
+
+        parse_fails(".permission not_a_permission; push 0; exit;");
+        parse_fails(".min_vm_version \"999.0.0\"; push 0; exit;");
+
+        // The data section must match its checksum
+        let mut image = Assembler::new().assemble_str(".data; .u32 7; .code; push 0; exit;").unwrap();
+        image.data.write(0, 8u32);
+        assert!(image.validate().is_err());
     }
 
     #[test]
@@ -0,0 +1,274 @@
+//! Micro-op cache
+//!
+//! The first time execution enters a basic block, its instructions are
+//! decoded into micro-ops, which hold the opcode, the decoded immediate
+//! operands and the absolute branch target of each instruction. Later
+//! executions of the block, such as the iterations of a loop, reuse the
+//! decoded micro-ops instead of decoding the instructions again.
+//!
+//! Blocks are keyed by their entry address. A block ends with the first
+//! instruction that can transfer control elsewhere, so a jump into the
+//! middle of a block starts a new block at the jump target.
+
+use crate::vm::{MemBlock, Op};
+use crate::jit::NativeBlock;
+
+/// Decoded instruction
+#[derive(Copy, Clone, Debug)]
+pub struct MicroOp
+{
+    pub op: Op,
+
+    /// Small operand, such as a local index or an argument count
+    pub idx: u8,
+
+    /// Immediate value, syscall index, absolute branch target,
+    /// or address of a jump table
+    pub imm: u64,
+
+    /// Address of the next instruction
+    pub next_pc: usize,
+}
+
+impl MicroOp
+{
+    /// Check if this instruction ends a basic block
+    fn ends_block(&self) -> bool
+    {
+        matches!(
+            self.op,
+            Op::jmp | Op::jz | Op::jnz | Op::jmp_table |
+            Op::call | Op::call_fp | Op::ret | Op::exit |
+            Op::panic | Op::breakpoint | Op::trap
+        )
+    }
+}
+
+/// Decode the instruction at a given address
+/// The instruction must be valid and fit in the code space
+pub fn decode(code: &MemBlock, pc: usize) -> MicroOp
+{
+    let mut next_pc = pc;
+    let op = code.read_pc::<Op>(&mut next_pc);
+    let mut idx = 0;
+    let mut imm = 0;
+
+    match op {
+        Op::getn | Op::setn |
+        Op::get_arg | Op::set_arg |
+        Op::get_local | Op::set_local |
+        Op::enter | Op::push_0n | Op::call_fp => {
+            idx = code.read_pc::<u8>(&mut next_pc);
+        }
+
+        Op::push_i8 => imm = code.read_pc::<i8>(&mut next_pc) as u64,
+        Op::push_u32 => imm = code.read_pc::<u32>(&mut next_pc) as u64,
+        Op::push_u64 => imm = code.read_pc::<u64>(&mut next_pc),
+        Op::syscall | Op::assert_stack => imm = code.read_pc::<u16>(&mut next_pc) as u64,
+
+        // Branch offsets are relative to the end of the instruction
+        Op::jmp | Op::jz | Op::jnz => {
+            let offset = code.read_pc::<i32>(&mut next_pc) as isize;
+            imm = ((next_pc as isize) + offset) as u64;
+        }
+
+        // The table is read when the instruction executes
+        Op::jmp_table => {
+            imm = next_pc as u64;
+            let num_entries = code.read_pc::<u32>(&mut next_pc) as usize;
+            next_pc += 4 * (num_entries + 1);
+        }
+
+        // call <offset:i32> <num_args:u8>
+        Op::call => {
+            let offset = code.read_pc::<i32>(&mut next_pc) as isize;
+            idx = code.read_pc::<u8>(&mut next_pc);
+            imm = ((next_pc as isize) + offset) as u64;
+        }
+
+        _ => {}
+    }
+
+    MicroOp { op, idx, imm, next_pc }
+}
+
+/// Target of a jmp_table instruction for a given index, which is the
+/// default target if the index is past the end of the table
+pub fn jmp_table_target(code: &MemBlock, uop: &MicroOp, idx: u64) -> usize
+{
+    let table = uop.imm as usize;
+    let num_entries = code.read::<u32>(table) as u64;
+
+    let entry_pos = if idx < num_entries { table + 8 + 4 * idx as usize } else { table + 4 };
+    let offset = code.read::<i32>(entry_pos) as isize;
+    ((uop.next_pc as isize) + offset) as usize
+}
+
+/// Decoded basic block
+pub struct Block
+{
+    /// Index range of the block's micro-ops
+    start: u32,
+    end: u32,
+
+    /// Number of times the block was executed by the interpreter
+    pub exec_count: u32,
+
+    /// Compiled machine code for the block, if any
+    pub native: Option<NativeBlock>,
+}
+
+#[derive(Default)]
+pub struct BlockCache
+{
+    /// Micro-ops of the decoded blocks, one block after the other
+    ops: Vec<MicroOp>,
+
+    /// Decoded blocks
+    blocks: Vec<Block>,
+
+    /// Index of the block starting at each code address,
+    /// plus one, or zero if no block was decoded there
+    block_idx: Vec<u32>,
+
+    /// Set when the code changed while a block may be executing,
+    /// so that the decoded blocks are only discarded at the next block
+    stale: bool,
+}
+
+impl BlockCache
+{
+    /// Get the index of the block starting at
+    /// a given address, decoding the block if needed
+    #[inline(always)]
+    pub fn get(&mut self, code: &MemBlock, pc: usize) -> usize
+    {
+        if pc >= code.len() {
+            panic!("pc outside bounds of code space")
+        }
+
+        match self.block_idx.get(pc) {
+            Some(&idx) if idx != 0 => idx as usize - 1,
+            _ => self.decode_block(code, pc),
+        }
+    }
+
+    /// Get the micro-op index range of a block
+    #[inline(always)]
+    pub fn op_range(&self, block_idx: usize) -> std::ops::Range<usize>
+    {
+        let block = &self.blocks[block_idx];
+        block.start as usize..block.end as usize
+    }
+
+    /// Get a decoded micro-op
+    #[inline(always)]
+    pub fn op(&self, idx: usize) -> MicroOp
+    {
+        self.ops[idx]
+    }
+
+    /// Get a block and its micro-ops
+    pub fn block_mut(&mut self, block_idx: usize) -> (&mut Block, &[MicroOp])
+    {
+        let block = &mut self.blocks[block_idx];
+        let ops = &self.ops[block.start as usize..block.end as usize];
+        (block, ops)
+    }
+
+    fn decode_block(&mut self, code: &MemBlock, start_pc: usize) -> usize
+    {
+        if self.stale {
+            self.ops.clear();
+            self.blocks.clear();
+            self.stale = false;
+        }
+
+        let start = self.ops.len();
+        let mut pc = start_pc;
+
+        // Decoding stops at the end of the code space, in which
+        // case the block continues with a block starting there
+        while pc < code.len() {
+            let uop = decode(code, pc);
+            self.ops.push(uop);
+            pc = uop.next_pc;
+
+            if uop.ends_block() {
+                break;
+            }
+        }
+
+        self.blocks.push(Block {
+            start: start as u32,
+            end: self.ops.len() as u32,
+            exec_count: 0,
+            native: None,
+        });
+
+        if self.block_idx.len() < code.len() {
+            self.block_idx.resize(code.len(), 0);
+        }
+        self.block_idx[start_pc] = self.blocks.len() as u32;
+
+        self.blocks.len() - 1
+    }
+
+    /// Discard the decoded blocks, which must be done
+    /// whenever the contents of the code space change
+    pub fn invalidate(&mut self)
+    {
+        self.block_idx.clear();
+        self.stale = true;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+
+    #[test]
+    fn test_decode_blocks()
+    {
+        let image = Assembler::new().assemble_str("
+            push 300; get_local 2; L: push_i8 -1; jnz L;
+            call F, 3; syscall 7; exit;
+            F: ret;
+        ").unwrap();
+
+        let mut cache = BlockCache::default();
+        let get = |cache: &mut BlockCache, pc: usize| {
+            let block_idx = cache.get(&image.code, pc);
+            cache.op_range(block_idx)
+        };
+
+        assert_eq!(get(&mut cache, 0), 0..4);
+        assert_eq!(cache.op(0).imm, 300);
+        assert_eq!(cache.op(1).idx, 2);
+        assert_eq!(cache.op(2).imm, u64::MAX);
+        assert_eq!(cache.op(3).imm, cache.op(1).next_pc as u64);
+
+        // Jumping into the middle of a block starts a new block
+        let pc = cache.op(1).next_pc;
+        assert_eq!(get(&mut cache, pc), 4..6);
+
+        let pc = cache.op(5).next_pc;
+        assert_eq!(get(&mut cache, pc), 6..7);
+        assert!(cache.op(6).op == Op::call);
+        assert_eq!(cache.op(6).idx, 3);
+
+        // Syscalls don't end blocks
+        let pc = cache.op(6).next_pc;
+        assert_eq!(get(&mut cache, pc), 7..9);
+        assert_eq!(cache.op(7).imm, 7);
+        assert!(cache.op(8).op == Op::exit);
+
+        // Decoded blocks are reused until the cache is invalidated
+        assert_eq!(get(&mut cache, 0), 0..4);
+        let pc = cache.op(1).next_pc;
+        cache.invalidate();
+        assert_eq!(get(&mut cache, pc), 0..2);
+    }
+}
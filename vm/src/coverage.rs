@@ -0,0 +1,104 @@
+//! Code coverage for --coverage
+//!
+//! We count the executions of each instruction by the interpreter, and
+//! map them to source lines using the line table of the program, which
+//! the assembler fills with the lines of the asm file, or with the lines
+//! of the C source set by ncc -g with .loc directives. A line counts as
+//! executed as many times as the most executed instruction in its code.
+//! The report uses the lcov tracefile format, which tools such as genhtml
+//! can turn into annotated sources:
+//!
+//!     SF:<source_file>
+//!     DA:<line_number>,<execution_count>
+//!     LF:<lines_found>
+//!     LH:<lines_hit>
+//!     end_of_record
+
+use std::collections::BTreeMap;
+use crate::image::LineTable;
+
+#[derive(Default)]
+pub struct Coverage
+{
+    // Number of executions, indexed by instruction address
+    counts: Vec<u64>,
+}
+
+impl Coverage
+{
+    pub fn count(&mut self, pc: usize)
+    {
+        // The code space grows when modules are loaded
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+        }
+        self.counts[pc] += 1;
+    }
+
+    /// Produce the lcov report for the lines in a line table
+    pub fn report(&self, line_table: &LineTable) -> String
+    {
+        let mut files: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::default();
+
+        for (idx, &(pos, file_idx, line_no)) in line_table.entries.iter().enumerate() {
+            let end = match line_table.entries.get(idx + 1) {
+                Some(&(next_pos, ..)) => next_pos,
+                None => self.counts.len(),
+            };
+
+            let count = self.counts.get(pos..end.max(pos)).and_then(|counts| counts.iter().max().copied()).unwrap_or(0);
+            let lines = files.entry(&line_table.files[file_idx as usize]).or_default();
+            let line_count = lines.entry(line_no).or_default();
+            *line_count = (*line_count).max(count);
+        }
+
+        let mut out = String::new();
+        for (file_name, lines) in files {
+            out.push_str("TN:\n");
+            out.push_str(&format!("SF:{}\n", file_name));
+            for (line_no, count) in &lines {
+                out.push_str(&format!("DA:{},{}\n", line_no, count));
+            }
+            out.push_str(&format!("LF:{}\n", lines.len()));
+            out.push_str(&format!("LH:{}\n", lines.values().filter(|&&count| count > 0).count()));
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::asm::Assembler;
+
+    #[test]
+    fn test_coverage()
+    {
+        let mut vm = Assembler::new().parse_str(r#"
+            .loc "main.c", 1;
+            push 0;
+            .loc "main.c", 2;
+            LOOP:
+            push 1; add_u64;
+            .loc "main.c", 3;
+            dup; push 3; lt_u64; jnz LOOP;
+            .loc "lib.h", 10;
+            push 5; jz SKIP;
+            .loc "main.c", 4;
+            exit;
+            .loc "main.c", 5;
+            SKIP: push 0; exit;
+        "#).unwrap();
+
+        vm.enable_coverage();
+        vm.call(0, &[]);
+
+        let report = vm.coverage_report().unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines, [
+            "TN:", "SF:lib.h", "DA:10,1", "LF:1", "LH:1", "end_of_record",
+            "TN:", "SF:main.c", "DA:1,1", "DA:2,3", "DA:3,3", "DA:4,1", "DA:5,0", "LF:5", "LH:4", "end_of_record",
+        ]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Differential testing of the JIT for --diff-jit
+//!
+//! The program runs once with the interpreter and once with the JIT, both
+//! in --deterministic mode, and the final states of the two runs are
+//! compared: the exit code, the text output, the heap, the value stack and
+//! the call frames. The first difference found is reported, so that a bug
+//! in the JIT shows up as a divergence rather than as a wrong result much
+//! later. Compiled code must behave exactly like the interpreter, so any
+//! difference is a JIT bug.
+
+use crate::vm::{VM, Value};
+use crate::snapshot::Snapshot;
+
+// Number of bytes of output shown around a difference
+const OUTPUT_CONTEXT: usize = 32;
+
+/// Final state of a run of the program
+pub struct RunResult
+{
+    pub exit_code: Value,
+    pub output: Vec<u8>,
+    pub state: Snapshot,
+}
+
+impl RunResult
+{
+    /// Capture the state of a VM whose program has exited. The output
+    /// must have been captured with sys_state.captured_output.
+    pub fn capture(vm: &VM, exit_code: Value) -> Self
+    {
+        Self {
+            exit_code,
+            output: vm.sys_state.captured_output.clone().unwrap_or_default(),
+            state: vm.snapshot(),
+        }
+    }
+}
+
+/// Position of the first difference between two slices,
+/// or of the end of the shorter one
+fn first_difference<T: PartialEq>(a: &[T], b: &[T]) -> Option<usize>
+{
+    if a == b {
+        return None;
+    }
+
+    Some(a.iter().zip(b).position(|(a, b)| a != b).unwrap_or(std::cmp::min(a.len(), b.len())))
+}
+
+/// Describe a part of the output starting at a given position
+fn output_at(output: &[u8], pos: usize) -> String
+{
+    let end = std::cmp::min(pos + OUTPUT_CONTEXT, output.len());
+    format!("{:?}", String::from_utf8_lossy(&output[pos..end]))
+}
+
+/// Describe the first difference between the runs with the
+/// interpreter and with the JIT, or None if they match
+pub fn find_divergence(interp: &RunResult, jit: &RunResult) -> Option<String>
+{
+    if interp.exit_code != jit.exit_code {
+        return Some(format!(
+            "exit code {} with the interpreter, {} with the JIT",
+            interp.exit_code.as_i64(),
+            jit.exit_code.as_i64()
+        ));
+    }
+
+    if let Some(pos) = first_difference(&interp.output, &jit.output) {
+        return Some(format!(
+            "output differs at byte {}, {} with the interpreter, {} with the JIT",
+            pos,
+            output_at(&interp.output, pos),
+            output_at(&jit.output, pos)
+        ));
+    }
+
+    let (a, b) = (&interp.state, &jit.state);
+
+    if a.heap.len() != b.heap.len() {
+        return Some(format!(
+            "heap size {} with the interpreter, {} with the JIT",
+            a.heap.len(),
+            b.heap.len()
+        ));
+    }
+
+    if let Some(addr) = first_difference(&a.heap, &b.heap) {
+        let num_bytes = a.heap.iter().zip(&b.heap).filter(|(a, b)| a != b).count();
+        return Some(format!(
+            "heap differs at address 0x{:x}, 0x{:02x} with the interpreter, 0x{:02x} with the JIT ({} bytes differ)",
+            addr,
+            a.heap[addr],
+            b.heap[addr],
+            num_bytes
+        ));
+    }
+
+    if let Some(idx) = first_difference(&a.stack, &b.stack) {
+        return match (a.stack.get(idx), b.stack.get(idx)) {
+            (Some(val_a), Some(val_b)) => Some(format!(
+                "stack slot {} is {} with the interpreter, {} with the JIT",
+                idx,
+                val_a,
+                val_b
+            )),
+            _ => Some(format!(
+                "stack size {} with the interpreter, {} with the JIT",
+                a.stack.len(),
+                b.stack.len()
+            )),
+        };
+    }
+
+    if a.frames != b.frames {
+        return Some(format!(
+            "call frames differ, {:?} with the interpreter, {:?} with the JIT",
+            a.frames,
+            b.frames
+        ));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn run(src: &str, jit: bool) -> RunResult
+    {
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.sys_state.captured_output = Some(Vec::new());
+        if jit {
+            vm.enable_jit();
+        }
+
+        let exit_code = match vm.call_sync(0, &[]) {
+            crate::vm::ExitReason::Exit(val) => val,
+            _ => panic!("program didn't exit"),
+        };
+
+        RunResult::capture(&vm, exit_code)
+    }
+
+    #[test]
+    fn test_diff_jit()
+    {
+        // Fill the heap with a hash of the loop counter, and print it
+        let src = "
+            .data;
+            .zero 4096;
+            .code;
+            push 0;
+            LOOP:
+            dup; push 8; mul_u64;
+            getn 1; push 0x9e3779b97f4a7c15; mul_u64; push 29; rshift_u64;
+            store_u64;
+            push 1; add_u64;
+            dup; push 512; lt_u64; jnz LOOP;
+            dup; syscall print_i64; syscall print_endl;
+            exit;
+        ";
+
+        let interp = run(src, false);
+        let jit = run(src, true);
+        assert_eq!(interp.output, b"512\n");
+        assert_eq!(find_divergence(&interp, &jit), None);
+
+        // Each part of the state is compared
+        let mut other = run(src, false);
+        other.state.heap[100] ^= 1;
+        assert!(find_divergence(&interp, &other).unwrap().starts_with("heap differs at address 0x64,"));
+
+        let mut other = run(src, false);
+        other.output.push(b'!');
+        assert_eq!(
+            find_divergence(&interp, &other).unwrap(),
+            "output differs at byte 4, \"\" with the interpreter, \"!\" with the JIT"
+        );
+
+        let mut other = run(src, false);
+        other.exit_code = Value::from(1);
+        assert_eq!(find_divergence(&interp, &other).unwrap(), "exit code 512 with the interpreter, 1 with the JIT");
+
+        let mut other = run(src, false);
+        other.state.stack.push(3);
+        assert_eq!(find_divergence(&interp, &other).unwrap(), "stack size 0 with the interpreter, 1 with the JIT");
+    }
+}
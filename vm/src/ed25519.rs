@@ -0,0 +1,511 @@
+//! Ed25519 signatures (RFC 8032)
+//!
+//! This is used to sign program images and to check their signatures
+//! before running them. Field elements are stored as five 51-bit limbs,
+//! and points on the curve use extended coordinates. The implementation
+//! favors simplicity over speed, and signing isn't constant time, so
+//! keys should only be used on machines that don't run untrusted code.
+
+use crate::sys::crypto::sha512;
+
+/// Element of the field of integers modulo 2^255 - 19
+#[derive(Copy, Clone)]
+struct Fe([u64; 5]);
+
+const MASK51: u64 = (1 << 51) - 1;
+
+impl Fe
+{
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_u64(n: u64) -> Fe
+    {
+        Fe([n & MASK51, n >> 51, 0, 0, 0])
+    }
+
+    /// Decode 32 little-endian bytes, ignoring the top bit
+    fn from_bytes(bytes: &[u8; 32]) -> Fe
+    {
+        let load = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Fe([
+            load(0) & MASK51,
+            (load(6) >> 3) & MASK51,
+            (load(12) >> 6) & MASK51,
+            (load(19) >> 1) & MASK51,
+            (load(24) >> 12) & MASK51,
+        ])
+    }
+
+    /// Encode as 32 little-endian bytes, fully reduced
+    fn to_bytes(self) -> [u8; 32]
+    {
+        let mut h = self.carry().0;
+
+        // Compute the carry out of h + 19, which is 1 if h >= p
+        let mut q = (h[0] + 19) >> 51;
+        for limb in &h[1..] {
+            q = (limb + q) >> 51;
+        }
+
+        h[0] += 19 * q;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK51;
+        }
+        h[4] &= MASK51;
+
+        let mut bytes = [0u8; 32];
+        let mut acc: u128 = 0;
+        let mut num_bits = 0;
+        let mut pos = 0;
+        for limb in h {
+            acc |= (limb as u128) << num_bits;
+            num_bits += 51;
+            while num_bits >= 8 && pos < 32 {
+                bytes[pos] = acc as u8;
+                acc >>= 8;
+                num_bits -= 8;
+                pos += 1;
+            }
+        }
+        bytes[31] |= acc as u8;
+        bytes
+    }
+
+    /// Propagate carries so that each limb fits in 51 bits, give or take
+    fn carry(self) -> Fe
+    {
+        let mut h = self.0;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK51;
+        }
+        h[0] += 19 * (h[4] >> 51);
+        h[4] &= MASK51;
+        Fe(h)
+    }
+
+    fn add(self, other: Fe) -> Fe
+    {
+        let mut h = self.0;
+        for (x, y) in h.iter_mut().zip(other.0) {
+            *x += y;
+        }
+        Fe(h).carry()
+    }
+
+    fn sub(self, other: Fe) -> Fe
+    {
+        // Add 2p so the limbs can't go negative
+        let two_p = [0xfffffffffffda, 0xffffffffffffe, 0xffffffffffffe, 0xffffffffffffe, 0xffffffffffffe];
+        let mut h = self.0;
+        for i in 0..5 {
+            h[i] = h[i] + two_p[i] - other.0[i];
+        }
+        Fe(h).carry()
+    }
+
+    fn neg(self) -> Fe
+    {
+        Fe::ZERO.sub(self)
+    }
+
+    fn mul(self, other: Fe) -> Fe
+    {
+        let a = self.0.map(|x| x as u128);
+        let b = other.0.map(|x| x as u128);
+
+        // Limbs that overflow 2^255 wrap around multiplied by 19
+        let b19 = b.map(|x| x * 19);
+        let r = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+
+        let mut h = [0u64; 5];
+        let mut carry: u128 = 0;
+        for i in 0..5 {
+            let x = r[i] + carry;
+            h[i] = (x as u64) & MASK51;
+            carry = x >> 51;
+        }
+        h[0] += (carry as u64) * 19;
+        Fe(h).carry()
+    }
+
+    fn square(self) -> Fe
+    {
+        self.mul(self)
+    }
+
+    /// Raise to a power given as 32 little-endian bytes
+    fn pow(self, exp: &[u8; 32]) -> Fe
+    {
+        let mut result = Fe::ONE;
+        for i in (0..256).rev() {
+            result = result.square();
+            if (exp[i / 8] >> (i % 8)) & 1 != 0 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    /// Little-endian bytes of 2^255 - n, for small n
+    fn pow2_255_minus(n: u8) -> [u8; 32]
+    {
+        let mut bytes = [0xff; 32];
+        bytes[0] = 0u8.wrapping_sub(n);
+        bytes[31] = 0x7f;
+        bytes
+    }
+
+    fn invert(self) -> Fe
+    {
+        // a^(p - 2) = a^-1
+        self.pow(&Fe::pow2_255_minus(21))
+    }
+
+    fn is_negative(self) -> bool
+    {
+        self.to_bytes()[0] & 1 != 0
+    }
+
+    fn is_zero(self) -> bool
+    {
+        self.to_bytes() == [0; 32]
+    }
+
+    fn equals(self, other: Fe) -> bool
+    {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+/// Curve constant d = -121665 / 121666
+fn curve_d() -> Fe
+{
+    Fe::from_u64(121665).neg().mul(Fe::from_u64(121666).invert())
+}
+
+/// Square root of -1, which is 2^((p - 1) / 4)
+fn sqrt_m1() -> Fe
+{
+    let mut exp = [0xff; 32];
+    exp[0] = 0xfb;
+    exp[31] = 0x1f;
+    Fe::from_u64(2).pow(&exp)
+}
+
+/// Point on the curve, in extended coordinates (X:Y:Z:T) with x = X/Z, y = Y/Z, xy = T/Z
+#[derive(Copy, Clone)]
+struct Point
+{
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+impl Point
+{
+    const IDENTITY: Point = Point { x: Fe::ZERO, y: Fe::ONE, z: Fe::ONE, t: Fe::ZERO };
+
+    fn base() -> Point
+    {
+        // The base point has y = 4/5 and a positive x
+        let mut bytes = [0x66; 32];
+        bytes[0] = 0x58;
+        Point::decode(&bytes).unwrap()
+    }
+
+    fn add(&self, other: &Point) -> Point
+    {
+        let d2 = curve_d().add(curve_d());
+        let a = self.y.sub(self.x).mul(other.y.sub(other.x));
+        let b = self.y.add(self.x).mul(other.y.add(other.x));
+        let c = self.t.mul(d2).mul(other.t);
+        let d = self.z.add(self.z).mul(other.z);
+        let e = b.sub(a);
+        let f = d.sub(c);
+        let g = d.add(c);
+        let h = b.add(a);
+
+        Point { x: e.mul(f), y: g.mul(h), z: f.mul(g), t: e.mul(h) }
+    }
+
+    fn neg(&self) -> Point
+    {
+        Point { x: self.x.neg(), y: self.y, z: self.z, t: self.t.neg() }
+    }
+
+    /// Multiply by a scalar given as 32 little-endian bytes
+    fn mul(&self, scalar: &[u8; 32]) -> Point
+    {
+        let mut result = Point::IDENTITY;
+        for i in (0..256).rev() {
+            result = result.add(&result);
+            if (scalar[i / 8] >> (i % 8)) & 1 != 0 {
+                result = result.add(self);
+            }
+        }
+        result
+    }
+
+    fn encode(&self) -> [u8; 32]
+    {
+        let z_inv = self.z.invert();
+        let x = self.x.mul(z_inv);
+        let mut bytes = self.y.mul(z_inv).to_bytes();
+        bytes[31] |= (x.is_negative() as u8) << 7;
+        bytes
+    }
+
+    /// Decode a point, which fails if the encoding is invalid
+    fn decode(bytes: &[u8; 32]) -> Option<Point>
+    {
+        let y = Fe::from_bytes(bytes);
+        let x_sign = bytes[31] >> 7 != 0;
+
+        // Reject non-canonical encodings of y
+        let mut y_bytes = *bytes;
+        y_bytes[31] &= 0x7f;
+        if y.to_bytes() != y_bytes {
+            return None;
+        }
+
+        // Solve x^2 = (y^2 - 1) / (d y^2 + 1)
+        let y2 = y.square();
+        let u = y2.sub(Fe::ONE);
+        let v = curve_d().mul(y2).add(Fe::ONE);
+        let v3 = v.square().mul(v);
+        let v7 = v3.square().mul(v);
+
+        // x = u v^3 (u v^7)^((p - 5) / 8)
+        let mut exp = [0xff; 32];
+        exp[0] = 0xfd;
+        exp[31] = 0x0f;
+        let mut x = u.mul(v3).mul(u.mul(v7).pow(&exp));
+
+        let vx2 = v.mul(x.square());
+        if !vx2.equals(u) {
+            if !vx2.equals(u.neg()) {
+                return None;
+            }
+            x = x.mul(sqrt_m1());
+        }
+
+        if x.is_zero() && x_sign {
+            return None;
+        }
+        if x.is_negative() != x_sign {
+            x = x.neg();
+        }
+
+        Some(Point { x, y, z: Fe::ONE, t: x.mul(y) })
+    }
+}
+
+/// Order of the base point, little-endian
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// Reduce a number of up to 64 bytes, one per element, modulo L
+fn reduce_mod_l(mut x: [i64; 64]) -> [u8; 32]
+{
+    // Fold the top bytes down, using 2^252 = -(L - 2^252) mod L
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        for j in (i - 32)..(i - 12) {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+        }
+        x[i - 12] += carry;
+        x[i] = 0;
+    }
+
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 0xff;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut out = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        out[i] = x[i] as u8;
+    }
+    out
+}
+
+/// Hash some data into a scalar modulo L
+fn hash_to_scalar(parts: &[&[u8]]) -> [u8; 32]
+{
+    let digest = sha512(&parts.concat());
+    let mut x = [0i64; 64];
+    for (x, &b) in x.iter_mut().zip(digest.iter()) {
+        *x = b as i64;
+    }
+    reduce_mod_l(x)
+}
+
+/// Check that a scalar is fully reduced, that is, smaller than L
+fn is_canonical(s: &[u8; 32]) -> bool
+{
+    for i in (0..32).rev() {
+        if (s[i] as i64) != L[i] {
+            return (s[i] as i64) < L[i];
+        }
+    }
+    false
+}
+
+/// Expand a secret key into its clamped scalar and its nonce prefix
+fn expand_secret(secret: &[u8; 32]) -> ([u8; 32], [u8; 32])
+{
+    let h = sha512(secret);
+    let mut scalar: [u8; 32] = h[..32].try_into().unwrap();
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    (scalar, h[32..].try_into().unwrap())
+}
+
+/// Compute the public key for a 32-byte secret key
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32]
+{
+    let (scalar, _) = expand_secret(secret);
+    Point::base().mul(&scalar).encode()
+}
+
+/// Sign a message
+pub fn sign(secret: &[u8; 32], msg: &[u8]) -> [u8; 64]
+{
+    let (scalar, prefix) = expand_secret(secret);
+    let public = Point::base().mul(&scalar).encode();
+
+    let r = hash_to_scalar(&[&prefix, msg]);
+    let big_r = Point::base().mul(&r).encode();
+    let k = hash_to_scalar(&[&big_r, &public, msg]);
+
+    // s = r + k * scalar mod L
+    let mut x = [0i64; 64];
+    for i in 0..32 {
+        x[i] = r[i] as i64;
+    }
+    for i in 0..32 {
+        for j in 0..32 {
+            x[i + j] += (k[i] as i64) * (scalar[j] as i64);
+        }
+    }
+    let s = reduce_mod_l(x);
+
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(&big_r);
+    sig[32..].copy_from_slice(&s);
+    sig
+}
+
+/// Check the signature of a message
+pub fn verify(public: &[u8; 32], msg: &[u8], sig: &[u8; 64]) -> bool
+{
+    let big_r: [u8; 32] = sig[..32].try_into().unwrap();
+    let s: [u8; 32] = sig[32..].try_into().unwrap();
+    if !is_canonical(&s) {
+        return false;
+    }
+
+    let a = match Point::decode(public) {
+        Some(a) => a,
+        None => return false,
+    };
+
+    // Check that s B = R + k A
+    let k = hash_to_scalar(&[&big_r, public, msg]);
+    let check = Point::base().mul(&s).add(&a.neg().mul(&k));
+    check.encode() == big_r
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn unhex<const N: usize>(s: &str) -> [u8; N]
+    {
+        let bytes: Vec<u8> = (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_rfc8032_vectors()
+    {
+        // Test vectors 1 and 2 from RFC 8032
+        let vectors: [(&str, &str, &[u8], &str); 2] = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                b"",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                &[0x72],
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+
+        for (secret, public, msg, sig) in vectors {
+            let secret: [u8; 32] = unhex(secret);
+            let public: [u8; 32] = unhex(public);
+            let sig: [u8; 64] = unhex(sig);
+
+            assert_eq!(public_key(&secret), public);
+            assert_eq!(sign(&secret, msg), sig);
+            assert!(verify(&public, msg, &sig));
+        }
+    }
+
+    #[test]
+    fn test_reject_invalid()
+    {
+        let secret = [7u8; 32];
+        let public = public_key(&secret);
+        let sig = sign(&secret, b"program");
+        assert!(verify(&public, b"program", &sig));
+
+        // Modified message, signature or key
+        assert!(!verify(&public, b"progran", &sig));
+
+        let mut bad_sig = sig;
+        bad_sig[5] ^= 1;
+        assert!(!verify(&public, b"program", &bad_sig));
+
+        let other = public_key(&[8u8; 32]);
+        assert!(!verify(&other, b"program", &sig));
+
+        // Non-canonical s, made by adding L
+        let mut big_s = sig;
+        let mut carry = 0;
+        for i in 0..32 {
+            let x = big_s[32 + i] as i64 + L[i] + carry;
+            big_s[32 + i] = x as u8;
+            carry = x >> 8;
+        }
+        assert!(!verify(&public, b"program", &big_s));
+    }
+}
@@ -0,0 +1,900 @@
+use std::collections::{HashMap, HashSet};
+use crate::vm::{VM, MemBlock};
+use crate::sys::constants::SYSCALL_DESCS;
+use crate::sys::crypto::crc32;
+use crate::sys::compress::{deflate, inflate};
+use crate::utils::{vm_version, ByteReader};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Section
+{
+    Code,
+    Data,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RelocKind
+{
+    Abs32,
+    Abs64,
+
+    /// 32-bit offset relative to the end of an instruction,
+    /// only used for imports, since offsets within an image
+    /// don't change when it is relocated
+    Rel32 { end_offset: usize },
+}
+
+/// Absolute address stored in an image, which must be adjusted
+/// when the image is loaded at a different base address
+#[derive(Copy, Clone, Debug)]
+pub struct Reloc
+{
+    /// Section and position of the address to patch
+    pub section: Section,
+    pub pos: usize,
+    pub kind: RelocKind,
+
+    /// Section the address points into
+    pub target: Section,
+}
+
+/// Reference to a symbol defined by another image
+#[derive(Clone, Debug)]
+pub struct Import
+{
+    pub name: String,
+    pub section: Section,
+    pub pos: usize,
+    pub kind: RelocKind,
+}
+
+/// Symbol made available to other images
+#[derive(Clone, Debug)]
+pub struct Export
+{
+    pub name: String,
+    pub section: Section,
+
+    /// Position within the section
+    pub pos: usize,
+}
+
+/// Address of an exported symbol once its image is loaded
+pub type ExportTable = HashMap<String, (Section, usize)>;
+
+/// Information about a program stored in its image header
+#[derive(Clone, Default, Debug)]
+pub struct Metadata
+{
+    /// Program name and author
+    pub name: Option<String>,
+    pub author: Option<String>,
+
+    /// Permissions the program requires, if any are declared
+    pub permissions: Vec<String>,
+
+    /// Oldest VM version able to run the program
+    pub min_vm_version: Option<(u32, u32, u32)>,
+
+    /// CRC-32 of the data section before relocation
+    pub data_checksum: u32,
+}
+
+/// Source lines of the code of an image, for coverage reports
+#[derive(Clone, Default, Debug)]
+pub struct LineTable
+{
+    /// Names of the source files
+    pub files: Vec<String>,
+
+    /// Position where the code of each source line starts, sorted by
+    /// position (position, file index, line number). The code of
+    /// a line extends to the position of the next entry.
+    pub entries: Vec<(usize, u32, u32)>,
+}
+
+impl LineTable
+{
+    /// Record that the code at a given position comes from a source line
+    pub fn add(&mut self, pos: usize, file_name: &str, line_no: u32)
+    {
+        let file_idx = match self.files.iter().position(|name| name == file_name) {
+            Some(idx) => idx as u32,
+            None => {
+                self.files.push(file_name.to_string());
+                (self.files.len() - 1) as u32
+            }
+        };
+
+        // Lines without code are replaced by the next line
+        if let Some(&(last_pos, last_file, last_line)) = self.entries.last() {
+            if (last_file, last_line) == (file_idx, line_no) {
+                return;
+            }
+            if last_pos == pos {
+                self.entries.pop();
+            }
+        }
+
+        self.entries.push((pos, file_idx, line_no));
+    }
+
+    /// Remove the lines of a source file
+    pub fn remove_file(&mut self, file_name: &str)
+    {
+        if let Some(idx) = self.files.iter().position(|name| name == file_name) {
+            let idx = idx as u32;
+            self.files.remove(idx as usize);
+            self.entries.retain(|&(_, file_idx, _)| file_idx != idx);
+            for entry in &mut self.entries {
+                if entry.1 > idx {
+                    entry.1 -= 1;
+                }
+            }
+        }
+    }
+
+    /// Find the source file and line of the code at a given position
+    pub fn find(&self, pos: usize) -> Option<(&str, u32)>
+    {
+        let idx = self.entries.partition_point(|&(start, ..)| start <= pos).checked_sub(1)?;
+        let (_, file_idx, line_no) = self.entries[idx];
+        Some((&self.files[file_idx as usize], line_no))
+    }
+
+    /// Append the lines of another table, whose code is at a base address
+    pub fn append(&mut self, other: &LineTable, code_base: usize)
+    {
+        for &(pos, file_idx, line_no) in &other.entries {
+            self.add(code_base + pos, &other.files[file_idx as usize], line_no);
+        }
+    }
+}
+
+/// Assembled program image
+///
+/// Jumps and calls use relative offsets, so code is position-independent
+/// except for the absolute addresses of labels, which are listed in the
+/// relocation table. This makes it possible to load the code and data
+/// sections of an image at non-zero base addresses.
+pub struct Image
+{
+    pub code: MemBlock,
+    pub data: MemBlock,
+
+    /// Absolute addresses to patch when relocating
+    pub relocs: Vec<Reloc>,
+
+    /// Symbols imported from and exported to other images
+    pub imports: Vec<Import>,
+    pub exports: Vec<Export>,
+
+    /// Labels defined in the code section (name, position),
+    /// used to name functions in profiles
+    pub code_labels: Vec<(String, usize)>,
+
+    /// Source lines of the code section
+    pub line_table: LineTable,
+
+    /// Syscalls referenced by the image
+    pub syscalls: HashSet<u16>,
+
+    /// Header information about the program
+    pub metadata: Metadata,
+
+    /// Base addresses the image is currently linked at
+    pub code_base: usize,
+    pub data_base: usize,
+}
+
+impl Image
+{
+    /// Check the image header against the image contents and this VM.
+    /// This must be done before the image is relocated.
+    pub fn validate(&self) -> Result<(), String>
+    {
+        let meta = &self.metadata;
+
+        assert!(self.data_base == 0);
+        if crc32(0, self.data.as_slice()) != meta.data_checksum {
+            return Err("data section checksum mismatch".to_string());
+        }
+
+        if let Some(min_version) = meta.min_vm_version {
+            if min_version > vm_version() {
+                let (major, minor, patch) = min_version;
+                return Err(format!("program requires VM version {}.{}.{} or newer", major, minor, patch));
+            }
+        }
+
+        for perm in &meta.permissions {
+            if !SYSCALL_DESCS.iter().flatten().any(|desc| desc.permission == perm) {
+                return Err(format!("unknown permission {}", perm));
+            }
+        }
+
+        // Programs that declare their permissions must declare all of them
+        if !meta.permissions.is_empty() {
+            let mut syscalls: Vec<u16> = self.syscalls.iter().copied().collect();
+            syscalls.sort();
+
+            for const_idx in syscalls {
+                let desc = SYSCALL_DESCS[const_idx as usize].as_ref().unwrap();
+                if desc.permission != "default_allowed" && !meta.permissions.iter().any(|p| p == desc.permission) {
+                    return Err(format!("syscall {} requires undeclared permission {}", desc.name, desc.permission));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the relocation table so that the code and data sections
+    /// can be loaded at the given base addresses
+    pub fn relocate(&mut self, code_base: usize, data_base: usize) -> Result<(), String>
+    {
+        // Keep the data aligned the way the assembler laid it out
+        if !data_base.is_multiple_of(8) {
+            return Err(format!("data base address 0x{:x} is not 8-byte aligned", data_base));
+        }
+
+        for reloc in &self.relocs {
+            let (old_base, new_base) = match reloc.target {
+                Section::Code => (self.code_base, code_base),
+                Section::Data => (self.data_base, data_base),
+            };
+
+            let mem = match reloc.section {
+                Section::Code => &mut self.code,
+                Section::Data => &mut self.data,
+            };
+
+            match reloc.kind {
+                RelocKind::Abs32 => {
+                    let addr = mem.read::<u32>(reloc.pos) as usize - old_base + new_base;
+                    let addr32 = match u32::try_from(addr) {
+                        Ok(addr32) => addr32,
+                        Err(_) => return Err(format!("relocated address 0x{:x} doesn't fit in u32", addr))
+                    };
+                    mem.write(reloc.pos, addr32);
+                }
+
+                RelocKind::Abs64 => {
+                    let addr = mem.read::<u64>(reloc.pos) as usize - old_base + new_base;
+                    mem.write(reloc.pos, addr as u64);
+                }
+
+                RelocKind::Rel32 { .. } => {}
+            }
+        }
+
+        self.code_base = code_base;
+        self.data_base = data_base;
+
+        Ok(())
+    }
+
+    /// Patch the imported symbol references using a table of exports.
+    /// This must be done after the image is relocated to its final location.
+    pub fn link_imports(&mut self, exports: &ExportTable) -> Result<(), String>
+    {
+        for import in &self.imports {
+            let (target, addr) = match exports.get(&import.name) {
+                Some(export) => *export,
+                None => return Err(format!("imported symbol {} not found", import.name))
+            };
+
+            let mem = match import.section {
+                Section::Code => &mut self.code,
+                Section::Data => &mut self.data,
+            };
+
+            match import.kind {
+                RelocKind::Abs32 => {
+                    let addr32 = match u32::try_from(addr) {
+                        Ok(addr32) => addr32,
+                        Err(_) => return Err(format!("address of {} doesn't fit in u32", import.name))
+                    };
+                    mem.write(import.pos, addr32);
+                }
+
+                RelocKind::Abs64 => {
+                    mem.write(import.pos, addr as u64);
+                }
+
+                RelocKind::Rel32 { end_offset } => {
+                    if target != Section::Code {
+                        return Err(format!("can't jump to data symbol {}", import.name));
+                    }
+
+                    let src = self.code_base + import.pos + end_offset + 4;
+                    let offs32 = match i32::try_from(addr as i64 - src as i64) {
+                        Ok(offs32) => offs32,
+                        Err(_) => return Err(format!("offset to {} doesn't fit in i32", import.name))
+                    };
+                    mem.write(import.pos, offs32);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the addresses of the exported symbols at the current base addresses
+    pub fn export_table(&self) -> ExportTable
+    {
+        self.exports.iter().map(|export| {
+            let base = match export.section {
+                Section::Code => self.code_base,
+                Section::Data => self.data_base,
+            };
+            (export.name.clone(), (export.section, base + export.pos))
+        }).collect()
+    }
+
+    /// Get the addresses of the code labels at the current base address
+    pub fn code_label_addrs(&self) -> Vec<(String, usize)>
+    {
+        self.code_labels.iter().map(|(name, pos)| (name.clone(), self.code_base + pos)).collect()
+    }
+
+    /// Append the code and data sections to a VM. The image must be
+    /// linked at the current end of the VM's code and heap.
+    pub fn load_into(&self, vm: &mut VM)
+    {
+        assert!(self.code_base == vm.code_size());
+        assert!(self.data_base == vm.heap_size());
+
+        vm.append_code(&self.code);
+        vm.add_code_labels(self.code_label_addrs());
+        vm.add_line_table(&self.line_table, self.code_base);
+        vm.resize_heap(self.data_base + self.data.len());
+        vm.mem_view().write_bytes(self.data_base, self.data.as_slice());
+    }
+
+    /// Create a VM to run this image, with the code and data sections
+    /// placed at the base addresses the image is linked at
+    pub fn into_vm(self) -> VM
+    {
+        let mut code = MemBlock::new();
+        code.push_zeros(self.code_base);
+        code.append(&self.code);
+
+        let mut heap = MemBlock::new();
+        heap.push_zeros(self.data_base);
+        heap.append(&self.data);
+
+        let exports = self.export_table();
+        let code_labels = self.code_label_addrs();
+        let mut vm = VM::new(code, heap, self.syscalls);
+        vm.add_code_labels(code_labels);
+        vm.add_line_table(&self.line_table, self.code_base);
+
+        // Make the exported symbols available to modules loaded later
+        vm.sys_state.module_state.exports = exports;
+
+        vm
+    }
+}
+
+// Binary image files, so that programs don't need to be assembled again
+// each time they are run. The file holds everything in the Image struct,
+// with all fields stored in little-endian order:
+//   magic "UVMIMG\0\0", format version (u32)
+//   entry point offset in the code section (u64)
+//   minimum VM version: present flag (u8), major, minor, patch (u32 each)
+//   CRC-32 of the data section (u32)
+//   name and author: present flag (u8) and string
+//   permissions: count (u64) and strings
+//   syscalls used: count (u64) and syscall numbers (u16 each)
+//   code and data sections: compression method (u8) and size (u64), followed
+//     by the bytes when stored, or by the compressed size (u64) and DEFLATE data
+//   relocations, imports, exports and code labels: count (u64) and entries
+//   source files: count (u64) and strings
+//   source lines: count (u64), and position (u64), file index and line (u32 each)
+// Strings are stored as a length (u64) followed by UTF-8 bytes.
+
+/// Magic bytes at the start of binary image files
+pub const IMAGE_MAGIC: &[u8; 8] = b"UVMIMG\0\0";
+
+/// Version of the binary image format
+const IMAGE_VERSION: u32 = 4;
+
+/// Compression methods of the code and data sections
+const METHOD_STORED: u8 = 0;
+const METHOD_DEFLATE: u8 = 1;
+
+/// Largest size of a compressed section once decompressed, checked before
+/// decompressing it, so that a small file can't use up the memory of the host
+pub const MAX_SECTION_SIZE: usize = 256 << 20;
+
+fn push_str(out: &mut MemBlock, s: &str)
+{
+    out.push_u64(s.len() as u64);
+    out.push_bytes(s.as_bytes());
+}
+
+fn push_opt_str(out: &mut MemBlock, s: &Option<String>)
+{
+    out.push_u8(s.is_some() as u8);
+    if let Some(s) = s {
+        push_str(out, s);
+    }
+}
+
+fn read_opt_str(r: &mut ByteReader) -> Result<Option<String>, String>
+{
+    match r.u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(r.string()?)),
+    }
+}
+
+/// Write the contents of the code or data section, compressed
+/// if requested and if that makes them smaller
+fn push_contents(out: &mut MemBlock, contents: &MemBlock, compress: bool)
+{
+    let bytes = contents.as_slice();
+    let compressed = if compress { deflate(bytes) } else { Vec::new() };
+
+    if compress && compressed.len() + 8 < bytes.len() {
+        out.push_u8(METHOD_DEFLATE);
+        out.push_u64(bytes.len() as u64);
+        out.push_u64(compressed.len() as u64);
+        out.push_bytes(&compressed);
+    } else {
+        out.push_u8(METHOD_STORED);
+        out.push_u64(bytes.len() as u64);
+        out.push_bytes(bytes);
+    }
+}
+
+fn read_contents(r: &mut ByteReader, name: &str) -> Result<MemBlock, String>
+{
+    match r.u8()? {
+        METHOD_STORED => {
+            let len = r.count(1)?;
+            Ok(MemBlock::from_bytes(r.bytes(len)?))
+        }
+
+        METHOD_DEFLATE => {
+            let len = r.usize()?;
+            if len > MAX_SECTION_SIZE {
+                return Err(format!("{} section of {} bytes is over the limit of {} bytes", name, len, MAX_SECTION_SIZE));
+            }
+
+            let comp_len = r.count(1)?;
+            match inflate(r.bytes(comp_len)?, len) {
+                Ok(bytes) if bytes.len() == len => Ok(MemBlock::from_bytes(&bytes)),
+                _ => Err(format!("invalid compressed {} section", name)),
+            }
+        }
+
+        n => Err(format!("invalid compression method {}", n)),
+    }
+}
+
+fn push_section(out: &mut MemBlock, section: Section)
+{
+    out.push_u8(match section {
+        Section::Code => 0,
+        Section::Data => 1,
+    });
+}
+
+fn read_section(r: &mut ByteReader) -> Result<Section, String>
+{
+    match r.u8()? {
+        0 => Ok(Section::Code),
+        1 => Ok(Section::Data),
+        n => Err(format!("invalid section {}", n)),
+    }
+}
+
+fn push_kind(out: &mut MemBlock, kind: RelocKind)
+{
+    match kind {
+        RelocKind::Abs32 => out.push_u8(0),
+        RelocKind::Abs64 => out.push_u8(1),
+        RelocKind::Rel32 { end_offset } => {
+            out.push_u8(2);
+            out.push_u64(end_offset as u64);
+        }
+    }
+}
+
+fn read_kind(r: &mut ByteReader) -> Result<RelocKind, String>
+{
+    match r.u8()? {
+        0 => Ok(RelocKind::Abs32),
+        1 => Ok(RelocKind::Abs64),
+        2 => Ok(RelocKind::Rel32 { end_offset: r.usize()? }),
+        n => Err(format!("invalid relocation kind {}", n)),
+    }
+}
+
+impl Image
+{
+    /// Check if some bytes are the contents of a binary image file
+    pub fn is_binary(bytes: &[u8]) -> bool
+    {
+        bytes.starts_with(IMAGE_MAGIC)
+    }
+
+    /// Serialize the image to the binary image file format.
+    /// The image must not be relocated yet.
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        self.encode(false)
+    }
+
+    /// Serialize the image with its code and data sections compressed,
+    /// which makes files smaller at the cost of decompressing them on load
+    pub fn to_compressed_bytes(&self) -> Vec<u8>
+    {
+        self.encode(true)
+    }
+
+    fn encode(&self, compress: bool) -> Vec<u8>
+    {
+        assert!(self.code_base == 0 && self.data_base == 0);
+        let meta = &self.metadata;
+        let mut out = MemBlock::new();
+
+        out.push_bytes(IMAGE_MAGIC);
+        out.push_u32(IMAGE_VERSION);
+
+        // Execution starts at the beginning of the code section
+        out.push_u64(0);
+
+        out.push_u8(meta.min_vm_version.is_some() as u8);
+        let (major, minor, patch) = meta.min_vm_version.unwrap_or((0, 0, 0));
+        out.push_u32(major);
+        out.push_u32(minor);
+        out.push_u32(patch);
+
+        out.push_u32(meta.data_checksum);
+        push_opt_str(&mut out, &meta.name);
+        push_opt_str(&mut out, &meta.author);
+
+        out.push_u64(meta.permissions.len() as u64);
+        for perm in &meta.permissions {
+            push_str(&mut out, perm);
+        }
+
+        // Sorted so that the output doesn't depend on hash ordering
+        let mut syscalls: Vec<u16> = self.syscalls.iter().copied().collect();
+        syscalls.sort();
+        out.push_u64(syscalls.len() as u64);
+        for const_idx in syscalls {
+            out.push_u16(const_idx);
+        }
+
+        push_contents(&mut out, &self.code, compress);
+        push_contents(&mut out, &self.data, compress);
+
+        out.push_u64(self.relocs.len() as u64);
+        for reloc in &self.relocs {
+            push_section(&mut out, reloc.section);
+            out.push_u64(reloc.pos as u64);
+            push_kind(&mut out, reloc.kind);
+            push_section(&mut out, reloc.target);
+        }
+
+        out.push_u64(self.imports.len() as u64);
+        for import in &self.imports {
+            push_str(&mut out, &import.name);
+            push_section(&mut out, import.section);
+            out.push_u64(import.pos as u64);
+            push_kind(&mut out, import.kind);
+        }
+
+        out.push_u64(self.exports.len() as u64);
+        for export in &self.exports {
+            push_str(&mut out, &export.name);
+            push_section(&mut out, export.section);
+            out.push_u64(export.pos as u64);
+        }
+
+        out.push_u64(self.code_labels.len() as u64);
+        for (name, pos) in &self.code_labels {
+            push_str(&mut out, name);
+            out.push_u64(*pos as u64);
+        }
+
+        out.push_u64(self.line_table.files.len() as u64);
+        for file_name in &self.line_table.files {
+            push_str(&mut out, file_name);
+        }
+
+        out.push_u64(self.line_table.entries.len() as u64);
+        for &(pos, file_idx, line_no) in &self.line_table.entries {
+            out.push_u64(pos as u64);
+            out.push_u32(file_idx);
+            out.push_u32(line_no);
+        }
+
+        out.as_slice().to_vec()
+    }
+
+    /// Read an image from the binary image file format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, String>
+    {
+        Self::read_binary(bytes).map_err(|msg| format!("invalid image file: {}", msg))
+    }
+
+    fn read_binary(bytes: &[u8]) -> Result<Image, String>
+    {
+        let mut r = ByteReader::new(bytes);
+
+        if !Self::is_binary(bytes) {
+            return Err("missing magic bytes".to_string());
+        }
+        r.bytes(IMAGE_MAGIC.len())?;
+
+        let version = r.u32()?;
+        if version != IMAGE_VERSION {
+            return Err(format!("unsupported format version {}", version));
+        }
+
+        if r.u64()? != 0 {
+            return Err("entry point must be at the start of the code".to_string());
+        }
+
+        let has_min_version = r.u8()? != 0;
+        let min_version = (r.u32()?, r.u32()?, r.u32()?);
+        let data_checksum = r.u32()?;
+        let name = read_opt_str(&mut r)?;
+        let author = read_opt_str(&mut r)?;
+
+        let mut permissions = Vec::new();
+        for _ in 0..r.count(8)? {
+            permissions.push(r.string()?);
+        }
+
+        // The program can't run if it needs syscalls this VM doesn't have
+        let mut syscalls = HashSet::new();
+        for _ in 0..r.count(2)? {
+            let const_idx = r.u16()?;
+            if SYSCALL_DESCS.get(const_idx as usize).is_none_or(|desc| desc.is_none()) {
+                return Err(format!("unknown syscall {}, the program may need a newer VM", const_idx));
+            }
+            syscalls.insert(const_idx);
+        }
+
+        let code = read_contents(&mut r, "code")?;
+        let data = read_contents(&mut r, "data")?;
+
+        // Relocations and symbols must point inside their section
+        let check_pos = |section: Section, pos: usize, size: usize| {
+            let len = match section {
+                Section::Code => code.len(),
+                Section::Data => data.len(),
+            };
+            match pos.checked_add(size) {
+                Some(end) if end <= len => Ok(()),
+                _ => Err(format!("position {} out of bounds", pos)),
+            }
+        };
+
+        let kind_size = |kind: RelocKind| match kind {
+            RelocKind::Abs64 => 8,
+            _ => 4,
+        };
+
+        let mut relocs = Vec::new();
+        for _ in 0..r.count(1)? {
+            let section = read_section(&mut r)?;
+            let pos = r.usize()?;
+            let kind = read_kind(&mut r)?;
+            let target = read_section(&mut r)?;
+            check_pos(section, pos, kind_size(kind))?;
+            relocs.push(Reloc { section, pos, kind, target });
+        }
+
+        let mut imports = Vec::new();
+        for _ in 0..r.count(1)? {
+            let name = r.string()?;
+            let section = read_section(&mut r)?;
+            let pos = r.usize()?;
+            let kind = read_kind(&mut r)?;
+            check_pos(section, pos, kind_size(kind))?;
+            imports.push(Import { name, section, pos, kind });
+        }
+
+        let mut exports = Vec::new();
+        for _ in 0..r.count(1)? {
+            let name = r.string()?;
+            let section = read_section(&mut r)?;
+            let pos = r.usize()?;
+            check_pos(section, pos, 0)?;
+            exports.push(Export { name, section, pos });
+        }
+
+        let mut code_labels = Vec::new();
+        for _ in 0..r.count(1)? {
+            let name = r.string()?;
+            let pos = r.usize()?;
+            check_pos(Section::Code, pos, 0)?;
+            code_labels.push((name, pos));
+        }
+
+        let mut line_table = LineTable::default();
+        for _ in 0..r.count(8)? {
+            line_table.files.push(r.string()?);
+        }
+
+        // Entries are checked so that code positions map to a single line
+        for _ in 0..r.count(16)? {
+            let pos = r.usize()?;
+            let file_idx = r.u32()?;
+            let line_no = r.u32()?;
+            check_pos(Section::Code, pos, 0)?;
+            if file_idx as usize >= line_table.files.len() {
+                return Err(format!("invalid source file index {}", file_idx));
+            }
+            if line_table.entries.last().is_some_and(|&(last_pos, ..)| last_pos >= pos) {
+                return Err("source lines are not sorted by position".to_string());
+            }
+            line_table.entries.push((pos, file_idx, line_no));
+        }
+
+        if !r.at_end() {
+            return Err("trailing data".to_string());
+        }
+
+        Ok(Image {
+            code,
+            data,
+            relocs,
+            imports,
+            exports,
+            code_labels,
+            line_table,
+            syscalls,
+            metadata: Metadata {
+                name,
+                author,
+                permissions,
+                min_vm_version: if has_min_version { Some(min_version) } else { None },
+                data_checksum,
+            },
+            code_base: 0,
+            data_base: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::vm::{Value, ExitReason};
+
+    const SRC: &str = "
+        .data;
+        .zero 5;
+        .align 8;
+        PTR: .addr64 X;
+        X: .u32 7;
+        .code;
+        push PTR; load_u64; load_u32;
+        push FN; call_fp 0;
+        add_u64;
+        exit;
+        FN: push X; load_u32; ret;
+    ";
+
+    fn run(code_base: usize, data_base: usize) -> Value
+    {
+        let mut image = Assembler::new().assemble_str(SRC).unwrap();
+        image.relocate(code_base, data_base).unwrap();
+        let mut vm = image.into_vm();
+
+        match vm.call(code_base as u64, &[]) {
+            ExitReason::Exit(val) => val,
+            ExitReason::Return(val) => val,
+            _ => panic!("execution was suspended"),
+        }
+    }
+
+    #[test]
+    fn test_relocate()
+    {
+        assert_eq!(run(0, 0), Value::from(14));
+        assert_eq!(run(100, 4096), Value::from(14));
+        assert_eq!(run(3, 8), Value::from(14));
+
+        // Relocating back to zero restores the original image
+        let orig = Assembler::new().assemble_str(SRC).unwrap();
+        let mut image = Assembler::new().assemble_str(SRC).unwrap();
+        image.relocate(64, 128).unwrap();
+        image.relocate(0, 0).unwrap();
+        assert!(image.code.as_slice() == orig.code.as_slice());
+        assert!(image.data.as_slice() == orig.data.as_slice());
+    }
+
+    #[test]
+    fn test_binary_image()
+    {
+        let src = format!(".name \"test\"; .author \"me\"; .min_vm_version \"0.1.0\"; .export FN; .loc \"test.c\", 3; {}", SRC);
+        let image = Assembler::new().assemble_str(&src).unwrap();
+        let bytes = image.to_bytes();
+        assert!(Image::is_binary(&bytes));
+
+        let mut loaded = Image::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert_eq!(loaded.metadata.name.as_deref(), Some("test"));
+        assert_eq!(loaded.metadata.min_vm_version, Some((0, 1, 0)));
+        assert_eq!(loaded.exports.len(), 1);
+        assert_eq!(loaded.syscalls, image.syscalls);
+        assert_eq!(loaded.line_table.entries, [(0, 0, 3)]);
+        loaded.validate().unwrap();
+
+        // The relocation table still works after loading the image
+        loaded.relocate(100, 4096).unwrap();
+        let mut vm = loaded.into_vm();
+        assert!(matches!(vm.call(100, &[]), ExitReason::Exit(val) if val.as_u64() == 14));
+
+        // Truncated files are rejected
+        for len in 0..bytes.len() {
+            assert!(Image::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_compressed_image()
+    {
+        let src = format!(".data; .fill 4096, 7; .code; {}", SRC);
+        let image = Assembler::new().assemble_str(&src).unwrap();
+        let bytes = image.to_compressed_bytes();
+        assert!(bytes.len() < image.to_bytes().len() - 4000);
+
+        let loaded = Image::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(), image.to_bytes());
+        assert_eq!(loaded.to_compressed_bytes(), bytes);
+        loaded.validate().unwrap();
+
+        for len in 0..bytes.len() {
+            assert!(Image::from_bytes(&bytes[..len]).is_err());
+        }
+
+        // Sizes are checked before decompressing anything
+        let mut out = MemBlock::new();
+        out.push_u8(METHOD_DEFLATE);
+        out.push_u64(MAX_SECTION_SIZE as u64 + 1);
+        out.push_u64(0);
+        let msg = read_contents(&mut ByteReader::new(out.as_slice()), "data").err().unwrap();
+        assert!(msg.contains("over the limit"));
+
+        // Data that doesn't decompress to the declared size is rejected
+        let mut out = MemBlock::new();
+        let compressed = deflate(&[1; 100]);
+        out.push_u8(METHOD_DEFLATE);
+        out.push_u64(99);
+        out.push_u64(compressed.len() as u64);
+        out.push_bytes(&compressed);
+        assert!(read_contents(&mut ByteReader::new(out.as_slice()), "code").is_err());
+    }
+
+    #[test]
+    fn test_binary_image_syscalls()
+    {
+        // Images that need syscalls the VM doesn't have can't be loaded
+        let mut image = Assembler::new().assemble_str("syscall vm_heap_size; exit;").unwrap();
+        assert!(Image::from_bytes(&image.to_bytes()).is_ok());
+        image.syscalls.insert(60000);
+        match Image::from_bytes(&image.to_bytes()) {
+            Err(msg) => assert!(msg.contains("unknown syscall 60000")),
+            Ok(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_relocate_overflow()
+    {
+        let mut image = Assembler::new().assemble_str(SRC).unwrap();
+        assert!(image.relocate(0, u32::MAX as usize + 1).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Incremental assembler
+//!
+//! Chunks of assembly are appended to the code and data of a VM one at a
+//! time, without reassembling the program. Each chunk can refer to the
+//! labels defined by earlier chunks. A chunk can also redefine a label, in
+//! which case the chunks that come after it use the new definition. Code
+//! that was already appended keeps the definitions it was linked against.
+
+use crate::vm::VM;
+use crate::asm::Assembler;
+use crate::image::{ExportTable, Section};
+
+pub struct IncrementalAsm
+{
+    /// Addresses of all the labels defined so far
+    symbols: ExportTable,
+}
+
+impl IncrementalAsm
+{
+    /// Start appending to a VM, whose exported labels are made available
+    pub fn new(vm: &VM) -> Self
+    {
+        Self {
+            symbols: vm.sys_state.module_state.exports.clone(),
+        }
+    }
+
+    /// Assemble a chunk of source code and append it to the VM
+    /// Returns the address the chunk's code starts at
+    pub fn append(&mut self, vm: &mut VM, src: &str) -> Result<usize, String>
+    {
+        let mut image = match Assembler::new().incremental(&self.symbols).assemble_str(src) {
+            Ok(image) => image,
+            Err(error) => return Err(error.to_string())
+        };
+
+        image.validate()?;
+
+        // Nothing is modified until the chunk is fully linked,
+        // so that a chunk with an error can simply be retyped
+        let code_base = vm.code_size();
+        let data_base = vm.heap_size();
+        image.relocate(code_base, data_base)?;
+        image.link_imports(&self.symbols)?;
+
+        image.load_into(vm);
+        self.symbols.extend(image.export_table());
+
+        Ok(code_base)
+    }
+
+    /// Get the current address of a label
+    pub fn symbol(&self, name: &str) -> Option<(Section, usize)>
+    {
+        self.symbols.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::{Value, ExitReason};
+
+    fn eval(vm: &mut VM, asm: &mut IncrementalAsm, src: &str) -> u64
+    {
+        let pc = asm.append(vm, src).unwrap();
+
+        match vm.call(pc as u64, &[]) {
+            ExitReason::Return(val) => val.as_u64(),
+            ExitReason::Exit(val) => val.as_u64(),
+            _ => panic!("execution was suspended"),
+        }
+    }
+
+    #[test]
+    fn test_incremental()
+    {
+        let mut vm = Assembler::new().parse_str("exit;").unwrap();
+        let mut asm = IncrementalAsm::new(&vm);
+
+        asm.append(&mut vm, "SQ: get_arg 0; get_arg 0; mul_u64; ret;").unwrap();
+        asm.append(&mut vm, ".data; X: .u64 7;").unwrap();
+        assert_eq!(eval(&mut vm, &mut asm, "push X; load_u64; call SQ, 1; ret;"), 49);
+
+        // Chunks with errors don't change the VM
+        let code_size = vm.code_size();
+        assert!(asm.append(&mut vm, "call FOO, 0; ret;").is_err());
+        assert!(asm.append(&mut vm, "push 1; bad_op;").is_err());
+        assert_eq!(vm.code_size(), code_size);
+
+        // Functions which call a redefined function keep calling
+        // the old definition until they are redefined too
+        asm.append(&mut vm, "F: push 3; call SQ, 1; ret;").unwrap();
+        asm.append(&mut vm, "SQ: get_arg 0; push 2; mul_u64; ret;").unwrap();
+        assert_eq!(eval(&mut vm, &mut asm, "call F, 0; ret;"), 9);
+        asm.append(&mut vm, "F: push 3; call SQ, 1; ret;").unwrap();
+        assert_eq!(eval(&mut vm, &mut asm, "call F, 0; ret;"), 6);
+
+        assert!(asm.symbol("X").unwrap().0 == Section::Data);
+        assert!(asm.symbol("FOO").is_none());
+    }
+}
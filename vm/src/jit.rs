@@ -0,0 +1,873 @@
+//! Baseline JIT compiler
+//!
+//! With --jit, basic blocks that the interpreter executes often are
+//! translated to x86-64 machine code, one instruction template at a time.
+//! Compiled code keeps the value stack in memory, exactly as the
+//! interpreter does, so that control can return to the interpreter
+//! after any instruction.
+//!
+//! Only a subset of the instructions can be compiled, mostly the integer,
+//! stack, local variable, memory and jump instructions. A block made of
+//! other instructions has its longest compilable prefix compiled, and the
+//! interpreter takes over at the first instruction that isn't supported.
+//! Instructions that would panic, such as out of bounds memory accesses,
+//! also exit to the interpreter, which executes them again and reports
+//! the error. On other architectures, nothing is compiled and the
+//! interpreter runs everything.
+
+use crate::block_cache::MicroOp;
+
+/// Number of times a block is interpreted before it gets compiled
+pub const JIT_THRESHOLD: u32 = 32;
+
+/// Size of the executable memory for compiled code
+const CODE_MEM_SIZE: usize = 16 * 1024 * 1024;
+
+/// VM state passed to compiled code, whose layout the code relies on
+#[repr(C)]
+pub struct JitCtx
+{
+    pub stack: *mut u64,
+    pub stack_len: usize,
+    pub bp: usize,
+
+    /// Number of locals of the current frame, or usize::MAX if unchecked
+    pub num_locals: usize,
+    pub argc: usize,
+    pub heap: *mut u8,
+    pub heap_len: usize,
+}
+
+/// Entry point of compiled code, which returns the pc to continue at
+pub type NativeFn = unsafe extern "C" fn(*mut JitCtx) -> u64;
+
+#[derive(Copy, Clone)]
+pub struct NativeBlock
+{
+    pub entry: NativeFn,
+
+    /// Number of values the block may pop below the stack top
+    pub min_depth: usize,
+
+    /// Number of values the block may push above the stack top
+    pub max_growth: usize,
+}
+
+/// Memory mapped region holding the compiled code
+struct CodeMem
+{
+    ptr: *mut u8,
+    used: usize,
+}
+
+// The code memory is only accessed by the thread which owns the VM
+unsafe impl Send for CodeMem {}
+
+impl CodeMem
+{
+    fn new() -> Self
+    {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                CODE_MEM_SIZE,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            panic!("could not allocate executable memory for the JIT");
+        }
+
+        Self { ptr: ptr as *mut u8, used: 0 }
+    }
+
+    /// Copy machine code into executable memory
+    fn write(&mut self, code: &[u8]) -> Option<*const u8>
+    {
+        if self.used + code.len() > CODE_MEM_SIZE {
+            return None;
+        }
+
+        // The memory is only writable while code is being copied in
+        unsafe {
+            let start = self.ptr.add(self.used);
+            libc::mprotect(self.ptr as *mut libc::c_void, CODE_MEM_SIZE, libc::PROT_READ | libc::PROT_WRITE);
+            std::ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+            libc::mprotect(self.ptr as *mut libc::c_void, CODE_MEM_SIZE, libc::PROT_READ | libc::PROT_EXEC);
+
+            // Keep entry points aligned
+            self.used = (self.used + code.len() + 15) & !15;
+            Some(start)
+        }
+    }
+}
+
+impl Drop for CodeMem
+{
+    fn drop(&mut self)
+    {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, CODE_MEM_SIZE); }
+    }
+}
+
+pub struct Jit
+{
+    mem: CodeMem,
+
+    /// Number of blocks compiled so far
+    num_compiled: usize,
+}
+
+impl Jit
+{
+    pub fn new() -> Self
+    {
+        Self {
+            mem: CodeMem::new(),
+            num_compiled: 0,
+        }
+    }
+
+    pub fn num_compiled(&self) -> usize
+    {
+        self.num_compiled
+    }
+
+    /// Discard all compiled code, which must not be running
+    pub fn reset(&mut self)
+    {
+        self.mem.used = 0;
+    }
+
+    /// Compile a block starting at a given pc, or as much of it as possible
+    pub fn compile(&mut self, ops: &[MicroOp], start_pc: usize) -> Option<NativeBlock>
+    {
+        let (code, min_depth, max_growth) = x86_64::compile(ops, start_pc)?;
+        let entry = self.mem.write(&code)?;
+        self.num_compiled += 1;
+
+        Some(NativeBlock {
+            entry: unsafe { std::mem::transmute::<*const u8, NativeFn>(entry) },
+            min_depth,
+            max_growth,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod x86_64
+{
+    use crate::block_cache::MicroOp;
+
+    pub fn compile(_ops: &[MicroOp], _start_pc: usize) -> Option<(Vec<u8>, usize, usize)>
+    {
+        None
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64
+{
+    use crate::vm::Op;
+    use crate::block_cache::MicroOp;
+
+    // Register numbers
+    const RAX: u8 = 0;
+    const RCX: u8 = 1;
+    const RDX: u8 = 2;
+    const RBX: u8 = 3;
+    const RDI: u8 = 7;
+    const R12: u8 = 12;
+    const R13: u8 = 13;
+    const R14: u8 = 14;
+    const R15: u8 = 15;
+
+    // Register assignment within compiled code
+    const CTX: u8 = RDI;
+    const STACK: u8 = RBX;
+    const LEN: u8 = R12;
+    const BP: u8 = R13;
+    const NUM_LOCALS: u8 = R14;
+    const HEAP: u8 = R15;
+
+    // Offsets of the JitCtx fields
+    const CTX_STACK: i32 = 0;
+    const CTX_LEN: i32 = 8;
+    const CTX_BP: i32 = 16;
+    const CTX_NUM_LOCALS: i32 = 24;
+    const CTX_ARGC: i32 = 32;
+    const CTX_HEAP: i32 = 40;
+    const CTX_HEAP_LEN: i32 = 48;
+
+    // Condition codes
+    const CC_B: u8 = 0x2;
+    const CC_AE: u8 = 0x3;
+    const CC_E: u8 = 0x4;
+    const CC_NE: u8 = 0x5;
+    const CC_BE: u8 = 0x6;
+    const CC_A: u8 = 0x7;
+    const CC_L: u8 = 0xC;
+    const CC_GE: u8 = 0xD;
+    const CC_LE: u8 = 0xE;
+    const CC_G: u8 = 0xF;
+
+    struct Emitter
+    {
+        code: Vec<u8>,
+
+        /// Offset of the shared exit sequence
+        exit_pos: usize,
+
+        /// Branches to patch to exit to the interpreter (rel32 offset, pc)
+        bailouts: Vec<(usize, usize)>,
+    }
+
+    impl Emitter
+    {
+        fn bytes(&mut self, bytes: &[u8])
+        {
+            self.code.extend_from_slice(bytes);
+        }
+
+        fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8)
+        {
+            let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (base >> 3);
+            if rex != 0x40 {
+                self.code.push(rex);
+            }
+        }
+
+        /// Instruction with a register and a register operand
+        fn op_rr(&mut self, opcode: &[u8], w: bool, reg: u8, rm: u8)
+        {
+            self.rex(w, reg, 0, rm);
+            self.bytes(opcode);
+            self.code.push(0xC0 | (reg & 7) << 3 | (rm & 7));
+        }
+
+        /// Instruction with a register and a memory operand
+        /// [base + index << scale + disp]
+        fn op_mem(&mut self, opcode: &[u8], w: bool, reg: u8, base: u8, index: Option<(u8, u8)>, disp: i32)
+        {
+            match index {
+                Some((index, scale)) => {
+                    self.rex(w, reg, index, base);
+                    self.bytes(opcode);
+                    self.code.push(0x84 | (reg & 7) << 3);
+                    self.code.push(scale << 6 | (index & 7) << 3 | (base & 7));
+                }
+                None => {
+                    self.rex(w, reg, 0, base);
+                    self.bytes(opcode);
+                    self.code.push(0x80 | (reg & 7) << 3 | (base & 7));
+                    if base & 7 == 4 {
+                        self.code.push(0x24);
+                    }
+                }
+            }
+            self.bytes(&disp.to_le_bytes());
+        }
+
+        /// Load a stack slot relative to the stack top
+        fn load_slot(&mut self, reg: u8, offset: i32)
+        {
+            self.op_mem(&[0x8B], true, reg, STACK, Some((LEN, 3)), offset * 8);
+        }
+
+        /// Store to a stack slot relative to the stack top
+        fn store_slot(&mut self, reg: u8, offset: i32)
+        {
+            self.op_mem(&[0x89], true, reg, STACK, Some((LEN, 3)), offset * 8);
+        }
+
+        /// Change the stack length
+        fn adjust_len(&mut self, delta: i8)
+        {
+            self.op_rr(&[0x83], true, 0, LEN);
+            self.code.push(delta as u8);
+        }
+
+        fn mov_imm(&mut self, reg: u8, val: u64)
+        {
+            if val as i64 == (val as i32) as i64 {
+                self.op_rr(&[0xC7], true, 0, reg);
+                self.bytes(&(val as u32).to_le_bytes());
+            } else {
+                self.rex(true, 0, 0, reg);
+                self.code.push(0xB8 | (reg & 7));
+                self.bytes(&val.to_le_bytes());
+            }
+        }
+
+        /// Return to the interpreter, which continues at a given pc
+        fn exit(&mut self, pc: usize)
+        {
+            self.rex(true, 0, 0, RAX);
+            self.code.push(0xB8);
+            self.bytes(&(pc as u64).to_le_bytes());
+            self.code.push(0xE9);
+            let rel = self.exit_pos as i64 - (self.code.len() + 4) as i64;
+            self.bytes(&(rel as i32).to_le_bytes());
+        }
+
+        /// Return to the interpreter at a given pc if a condition holds
+        fn bailout_if(&mut self, cc: u8, pc: usize)
+        {
+            self.bytes(&[0x0F, 0x80 | cc]);
+            self.bailouts.push((self.code.len(), pc));
+            self.bytes(&[0; 4]);
+        }
+
+        /// Pop two values into rax and rcx, in push order
+        fn load_operands(&mut self)
+        {
+            self.load_slot(RCX, -1);
+            self.load_slot(RAX, -2);
+        }
+
+        /// Replace two values by the result in rax
+        fn store_result(&mut self)
+        {
+            self.store_slot(RAX, -2);
+            self.adjust_len(-1);
+        }
+
+        /// Binary ALU operation on rax and rcx
+        fn alu(&mut self, opcode: u8, w: bool)
+        {
+            self.load_operands();
+            self.op_rr(&[opcode], w, RCX, RAX);
+            self.store_result();
+        }
+
+        /// Shift rax by cl
+        fn shift(&mut self, ext: u8, w: bool, sign_extend: bool)
+        {
+            self.load_operands();
+            self.op_rr(&[0xD3], w, ext, RAX);
+            if sign_extend {
+                self.op_rr(&[0x63], true, RAX, RAX);
+            }
+            self.store_result();
+        }
+
+        /// Compare rax and rcx and produce a boolean
+        fn compare(&mut self, w: bool, cc: u8)
+        {
+            self.load_operands();
+            self.op_rr(&[0x39], w, RCX, RAX);
+            self.op_rr(&[0x0F, 0x90 | cc], false, 0, RAX);
+            self.op_rr(&[0x0F, 0xB6], false, RAX, RAX);
+            self.store_result();
+        }
+
+        /// Unsigned division of rax by rcx, exiting if rcx is zero
+        fn divide(&mut self, w: bool, remainder: bool, pc: usize)
+        {
+            self.load_operands();
+            self.op_rr(&[0x85], w, RCX, RCX);
+            self.bailout_if(CC_E, pc);
+            self.op_rr(&[0x31], false, RDX, RDX);
+            self.op_rr(&[0xF7], w, 6, RCX);
+            self.store_slot(if remainder { RDX } else { RAX }, -2);
+            self.adjust_len(-1);
+        }
+
+        /// Operation on the stack top in place
+        fn unary(&mut self, opcode: &[u8], w: bool, reg: u8)
+        {
+            self.load_slot(RAX, -1);
+            self.op_rr(opcode, w, reg, RAX);
+            self.store_slot(RAX, -1);
+        }
+
+        /// Check that a heap access of a given size at
+        /// the address in rax is in bounds and aligned
+        fn check_heap_access(&mut self, num_bytes: u8, pc: usize)
+        {
+            // rdx = addr + num_bytes, which must not overflow or exceed the heap size
+            self.op_rr(&[0x89], true, RAX, RDX);
+            self.op_rr(&[0x83], true, 0, RDX);
+            self.code.push(num_bytes);
+            self.bailout_if(CC_B, pc);
+            self.op_mem(&[0x3B], true, RDX, CTX, None, CTX_HEAP_LEN);
+            self.bailout_if(CC_A, pc);
+
+            if num_bytes > 1 {
+                self.bytes(&[0xA8, num_bytes - 1]);
+                self.bailout_if(CC_NE, pc);
+            }
+        }
+
+        fn load(&mut self, num_bytes: u8, pc: usize)
+        {
+            self.load_slot(RAX, -1);
+            self.check_heap_access(num_bytes, pc);
+
+            let mem = Some((RAX, 0));
+            match num_bytes {
+                1 => self.op_mem(&[0x0F, 0xB6], false, RAX, HEAP, mem, 0),
+                2 => self.op_mem(&[0x0F, 0xB7], false, RAX, HEAP, mem, 0),
+                4 => self.op_mem(&[0x8B], false, RAX, HEAP, mem, 0),
+                _ => self.op_mem(&[0x8B], true, RAX, HEAP, mem, 0),
+            }
+
+            self.store_slot(RAX, -1);
+        }
+
+        fn store(&mut self, num_bytes: u8, pc: usize)
+        {
+            self.load_operands();
+            self.check_heap_access(num_bytes, pc);
+
+            let mem = Some((RAX, 0));
+            match num_bytes {
+                1 => self.op_mem(&[0x88], false, RCX, HEAP, mem, 0),
+                2 => {
+                    self.code.push(0x66);
+                    self.op_mem(&[0x89], false, RCX, HEAP, mem, 0)
+                }
+                4 => self.op_mem(&[0x89], false, RCX, HEAP, mem, 0),
+                _ => self.op_mem(&[0x89], true, RCX, HEAP, mem, 0),
+            }
+
+            self.adjust_len(-2);
+        }
+
+        /// Exit if a local variable index is out of range.
+        /// The index must be below the number of locals, and the local
+        /// must be below the stack top, after popping num_pops values.
+        fn check_local(&mut self, idx: u8, num_pops: u8, pc: usize)
+        {
+            self.op_rr(&[0x81], true, 7, NUM_LOCALS);
+            self.bytes(&(idx as u32).to_le_bytes());
+            self.bailout_if(CC_BE, pc);
+
+            self.op_rr(&[0x89], true, BP, RAX);
+            self.op_rr(&[0x81], true, 0, RAX);
+            self.bytes(&(idx as u32 + num_pops as u32).to_le_bytes());
+            self.op_rr(&[0x39], true, LEN, RAX);
+            self.bailout_if(CC_AE, pc);
+        }
+
+        /// Compute the stack index of the first argument
+        /// in rax, exiting if an argument index is out of range
+        fn arg_base(&mut self, idx: u8, pc: usize)
+        {
+            self.op_mem(&[0x81], true, 7, CTX, None, CTX_ARGC);
+            self.bytes(&(idx as u32).to_le_bytes());
+            self.bailout_if(CC_BE, pc);
+
+            self.op_rr(&[0x89], true, BP, RAX);
+            self.op_mem(&[0x2B], true, RAX, CTX, None, CTX_ARGC);
+        }
+
+        /// Pop the stack top and exit to one of two pcs,
+        /// the first one if the value is zero
+        fn branch(&mut self, zero_pc: usize, nonzero_pc: usize)
+        {
+            self.load_slot(RAX, -1);
+            self.adjust_len(-1);
+            self.op_rr(&[0x85], true, RAX, RAX);
+
+            // Skip over the first exit sequence
+            let jnz_pos = self.code.len();
+            self.bytes(&[0x75, 0]);
+            self.exit(zero_pc);
+            self.code[jnz_pos + 1] = (self.code.len() - (jnz_pos + 2)) as u8;
+            self.exit(nonzero_pc);
+        }
+    }
+
+    /// Compile an instruction, returning false if it isn't supported
+    fn compile_op(e: &mut Emitter, uop: &MicroOp, pc: usize) -> bool
+    {
+        let idx = uop.idx;
+
+        match uop.op {
+            Op::nop => {}
+
+            Op::push_0 | Op::push_1 | Op::push_2 |
+            Op::push_i8 | Op::push_u32 | Op::push_u64 => {
+                let val = match uop.op {
+                    Op::push_0 => 0,
+                    Op::push_1 => 1,
+                    Op::push_2 => 2,
+                    _ => uop.imm,
+                };
+                e.mov_imm(RAX, val);
+                e.store_slot(RAX, 0);
+                e.adjust_len(1);
+            }
+
+            Op::pop => e.adjust_len(-1),
+
+            Op::dup => {
+                e.load_slot(RAX, -1);
+                e.store_slot(RAX, 0);
+                e.adjust_len(1);
+            }
+
+            Op::swap => {
+                e.load_operands();
+                e.store_slot(RCX, -2);
+                e.store_slot(RAX, -1);
+            }
+
+            Op::getn => {
+                e.load_slot(RAX, -1 - idx as i32);
+                e.store_slot(RAX, 0);
+                e.adjust_len(1);
+            }
+
+            Op::setn => {
+                e.load_slot(RAX, -1);
+                e.adjust_len(-1);
+                e.store_slot(RAX, -1 - idx as i32);
+            }
+
+            Op::get_local => {
+                e.check_local(idx, 0, pc);
+                e.op_mem(&[0x8B], true, RAX, STACK, Some((BP, 3)), idx as i32 * 8);
+                e.store_slot(RAX, 0);
+                e.adjust_len(1);
+            }
+
+            Op::set_local => {
+                e.check_local(idx, 1, pc);
+                e.load_slot(RCX, -1);
+                e.adjust_len(-1);
+                e.op_mem(&[0x89], true, RCX, STACK, Some((BP, 3)), idx as i32 * 8);
+            }
+
+            Op::get_arg => {
+                e.arg_base(idx, pc);
+                e.op_mem(&[0x8B], true, RAX, STACK, Some((RAX, 3)), idx as i32 * 8);
+                e.store_slot(RAX, 0);
+                e.adjust_len(1);
+            }
+
+            Op::set_arg => {
+                e.arg_base(idx, pc);
+                e.load_slot(RCX, -1);
+                e.adjust_len(-1);
+                e.op_mem(&[0x89], true, RCX, STACK, Some((RAX, 3)), idx as i32 * 8);
+            }
+
+            Op::add_u32 => e.alu(0x01, false),
+            Op::sub_u32 => e.alu(0x29, false),
+            Op::and_u32 => e.alu(0x21, false),
+            Op::or_u32 => e.alu(0x09, false),
+            Op::xor_u32 => e.alu(0x31, false),
+            Op::add_u64 => e.alu(0x01, true),
+            Op::sub_u64 => e.alu(0x29, true),
+            Op::and_u64 => e.alu(0x21, true),
+            Op::or_u64 => e.alu(0x09, true),
+            Op::xor_u64 => e.alu(0x31, true),
+
+            Op::mul_u32 | Op::mul_u64 => {
+                e.load_operands();
+                e.op_rr(&[0x0F, 0xAF], uop.op == Op::mul_u64, RAX, RCX);
+                e.store_result();
+            }
+
+            Op::div_u32 => e.divide(false, false, pc),
+            Op::mod_u32 => e.divide(false, true, pc),
+            Op::div_u64 => e.divide(true, false, pc),
+            Op::mod_u64 => e.divide(true, true, pc),
+
+            // The hardware masks shift amounts like wrapping_shl/shr
+            Op::lshift_u32 => e.shift(4, false, false),
+            Op::rshift_u32 => e.shift(5, false, false),
+            Op::rshift_i32 => e.shift(7, false, true),
+            Op::lshift_u64 => e.shift(4, true, false),
+            Op::rshift_u64 => e.shift(5, true, false),
+            Op::rshift_i64 => e.shift(7, true, false),
+
+            Op::not_u32 => e.unary(&[0xF7], false, 2),
+            Op::not_u64 => e.unary(&[0xF7], true, 2),
+
+            Op::eq_u32 => e.compare(false, CC_E),
+            Op::ne_u32 => e.compare(false, CC_NE),
+            Op::lt_u32 => e.compare(false, CC_B),
+            Op::le_u32 => e.compare(false, CC_BE),
+            Op::gt_u32 => e.compare(false, CC_A),
+            Op::ge_u32 => e.compare(false, CC_AE),
+            Op::lt_i32 => e.compare(false, CC_L),
+            Op::le_i32 => e.compare(false, CC_LE),
+            Op::gt_i32 => e.compare(false, CC_G),
+            Op::ge_i32 => e.compare(false, CC_GE),
+            Op::eq_u64 => e.compare(true, CC_E),
+            Op::ne_u64 => e.compare(true, CC_NE),
+            Op::lt_u64 => e.compare(true, CC_B),
+            Op::le_u64 => e.compare(true, CC_BE),
+            Op::gt_u64 => e.compare(true, CC_A),
+            Op::ge_u64 => e.compare(true, CC_AE),
+            Op::lt_i64 => e.compare(true, CC_L),
+            Op::le_i64 => e.compare(true, CC_LE),
+            Op::gt_i64 => e.compare(true, CC_G),
+            Op::ge_i64 => e.compare(true, CC_GE),
+
+            // i32 values are sign-extended to 64 bits on the stack
+            Op::sx_i8_i32 | Op::sx_i8_i64 => e.unary(&[0x0F, 0xBE], true, RAX),
+            Op::sx_i16_i32 | Op::sx_i16_i64 => e.unary(&[0x0F, 0xBF], true, RAX),
+            Op::sx_i32_i64 => e.unary(&[0x63], true, RAX),
+            Op::trunc_u8 => e.unary(&[0x0F, 0xB6], false, RAX),
+            Op::trunc_u16 => e.unary(&[0x0F, 0xB7], false, RAX),
+            Op::trunc_u32 => e.unary(&[0x89], false, RAX),
+
+            Op::load_u8 => e.load(1, pc),
+            Op::load_u16 => e.load(2, pc),
+            Op::load_u32 => e.load(4, pc),
+            Op::load_u64 => e.load(8, pc),
+            Op::store_u8 => e.store(1, pc),
+            Op::store_u16 => e.store(2, pc),
+            Op::store_u32 => e.store(4, pc),
+            Op::store_u64 => e.store(8, pc),
+
+            Op::jmp => e.exit(uop.imm as usize),
+            Op::jz => e.branch(uop.imm as usize, uop.next_pc),
+            Op::jnz => e.branch(uop.next_pc, uop.imm as usize),
+
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Number of values an instruction reads below the
+    /// stack top, and change in the stack size it makes
+    fn stack_effect(uop: &MicroOp) -> (i64, i64)
+    {
+        let idx = uop.idx as i64;
+
+        match uop.op {
+            Op::nop | Op::jmp => (0, 0),
+            Op::get_local | Op::get_arg => (0, 1),
+            Op::push_0 | Op::push_1 | Op::push_2 |
+            Op::push_i8 | Op::push_u32 | Op::push_u64 => (0, 1),
+            Op::pop | Op::set_local | Op::set_arg | Op::jz | Op::jnz => (1, -1),
+            Op::dup => (1, 1),
+            Op::swap => (2, 0),
+            Op::getn => (idx + 1, 1),
+            Op::setn => (idx + 2, -1),
+            Op::store_u8 | Op::store_u16 | Op::store_u32 | Op::store_u64 => (2, -2),
+
+            Op::not_u32 | Op::not_u64 |
+            Op::sx_i8_i32 | Op::sx_i8_i64 | Op::sx_i16_i32 | Op::sx_i16_i64 | Op::sx_i32_i64 |
+            Op::trunc_u8 | Op::trunc_u16 | Op::trunc_u32 |
+            Op::load_u8 | Op::load_u16 | Op::load_u32 | Op::load_u64 => (1, 0),
+
+            // Binary operations
+            _ => (2, -1),
+        }
+    }
+
+    /// Compile the longest supported prefix of a block starting at a given pc
+    /// Returns the machine code and the stack requirements
+    pub fn compile(ops: &[MicroOp], start_pc: usize) -> Option<(Vec<u8>, usize, usize)>
+    {
+        let mut e = Emitter {
+            code: Vec::default(),
+            exit_pos: 0,
+            bailouts: Vec::default(),
+        };
+
+        // Save the callee-saved registers we use
+        e.bytes(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        e.op_mem(&[0x8B], true, STACK, CTX, None, CTX_STACK);
+        e.op_mem(&[0x8B], true, LEN, CTX, None, CTX_LEN);
+        e.op_mem(&[0x8B], true, BP, CTX, None, CTX_BP);
+        e.op_mem(&[0x8B], true, NUM_LOCALS, CTX, None, CTX_NUM_LOCALS);
+        e.op_mem(&[0x8B], true, HEAP, CTX, None, CTX_HEAP);
+
+        // Jump over the exit sequence
+        e.bytes(&[0xEB, 0]);
+        let skip_pos = e.code.len();
+
+        // Exit sequence: write back the stack length and restore registers
+        e.exit_pos = e.code.len();
+        e.op_mem(&[0x89], true, LEN, CTX, None, CTX_LEN);
+        e.bytes(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+        e.code[skip_pos - 1] = (e.code.len() - skip_pos) as u8;
+
+        let mut num_compiled = 0;
+        let mut depth: i64 = 0;
+        let mut min_depth: i64 = 0;
+        let mut max_depth: i64 = 0;
+        let mut pc = start_pc;
+        let mut ends_with_jump = false;
+
+        for uop in ops {
+            let (num_reads, delta) = stack_effect(uop);
+            if !compile_op(&mut e, uop, pc) {
+                break;
+            }
+
+            min_depth = min_depth.max(num_reads - depth);
+            depth += delta;
+            max_depth = max_depth.max(depth);
+            num_compiled += 1;
+            ends_with_jump = matches!(uop.op, Op::jmp | Op::jz | Op::jnz);
+            pc = uop.next_pc;
+        }
+
+        // Not worth exiting to the interpreter right away
+        if num_compiled < 2 && !ends_with_jump {
+            return None;
+        }
+
+        // Continue with the first instruction that wasn't compiled
+        if !ends_with_jump {
+            e.exit(pc);
+        }
+
+        // Exits to the interpreter for instructions that would panic
+        for (patch_pos, pc) in std::mem::take(&mut e.bailouts) {
+            let rel = (e.code.len() - (patch_pos + 4)) as u32;
+            e.code[patch_pos..patch_pos + 4].copy_from_slice(&rel.to_le_bytes());
+            e.exit(pc);
+        }
+
+        Some((e.code, min_depth as usize, max_depth as usize))
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests
+{
+    use crate::asm::Assembler;
+    use crate::vm::{ExitReason, TrapKind};
+
+    fn run(src: &str, jit: bool) -> (u64, usize)
+    {
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        if jit {
+            vm.enable_jit();
+        }
+
+        let val = match vm.call(0, &[]) {
+            ExitReason::Return(val) => val.as_u64(),
+            ExitReason::Exit(val) => val.as_u64(),
+            _ => panic!("execution was suspended"),
+        };
+
+        (val, vm.jit_blocks_compiled())
+    }
+
+    /// Check that a program produces the same result with and without the JIT
+    fn check(src: &str)
+    {
+        let (expected, _) = run(src, false);
+        let (val, num_compiled) = run(src, true);
+        assert_eq!(val, expected);
+        assert!(num_compiled > 0);
+    }
+
+    #[test]
+    fn test_jit_arith()
+    {
+        // acc = (acc * 31 + i) ^ (i >> 3), with i counting down from 1000
+        check("
+            push 0; push 1000;
+            LOOP:
+            swap; push 31; mul_u64; getn 1; add_u64; getn 1; push 3; rshift_u64; xor_u64;
+            dup; push 17; lshift_u64; or_u64; push 0x7fff_ffff_ffff; and_u64;
+            swap; push 1; sub_u64; dup; jnz LOOP;
+            pop; exit;
+        ");
+
+        // 32-bit and signed operations
+        check("
+            push 0; push 500;
+            LOOP:
+            swap; getn 1; push 0xdeadbeef; mul_u32; add_u32; not_u32;
+            dup; push 5; rshift_i32; sx_i32_i64; add_u64; trunc_u32;
+            getn 1; push -1; lt_i64; add_u64;
+            getn 1; push 250; sub_u64; push 0; gt_i32; add_u64;
+            dup; sx_i8_i64; add_u64; dup; sx_i16_i32; xor_u64;
+            dup; push 1000; mod_u64; add_u64; dup; push 7; div_u32; sub_u64;
+            swap; push 1; sub_u64; dup; jz DONE; jmp LOOP;
+            DONE: pop; exit;
+        ");
+    }
+
+    #[test]
+    fn test_jit_memory()
+    {
+        check("
+            .data;
+            .align 8;
+            BUF: .zero 1024;
+            .code;
+            push 0;
+            FILL:
+            dup; push BUF; add_u64; getn 1; push 3; mul_u64; store_u8;
+            push 1; add_u64; dup; push 1024; lt_u64; jnz FILL;
+
+            pop; push 0; push 0;
+            SUM:
+            dup; push BUF; add_u64; load_u8; getn 2; add_u64; setn 1;
+            dup; push 8; mul_u64; push 1016; and_u64; push BUF; add_u64; dup;
+            load_u64; getn 3; add_u64; store_u64;
+            dup; push 2; mul_u64; push 1022; and_u64; push BUF; add_u64; load_u16; getn 2; xor_u64; setn 1;
+            dup; push 4; mul_u64; push 1020; and_u64; push BUF; add_u64; getn 0; load_u32; push 9; add_u32; store_u32;
+            push 1; add_u64; dup; push 1024; lt_u64; jnz SUM;
+
+            pop; push BUF; push 512; add_u64; load_u64; add_u64; exit;
+        ");
+    }
+
+    #[test]
+    fn test_jit_locals()
+    {
+        check("
+            push 7; push 100; call F, 2; exit;
+
+            F:
+            enter 2;
+            push 0; set_local 0;
+            get_arg 1; set_local 1;
+            LOOP:
+            get_local 0; get_arg 0; add_u64; get_local 1; mul_u32; set_local 0;
+            get_arg 0; push 1; add_u64; set_arg 0;
+            get_local 1; push 1; sub_u64; dup; set_local 1; jnz LOOP;
+            get_local 0; ret;
+        ");
+    }
+
+    #[test]
+    #[should_panic(expected = "past end of heap")]
+    fn test_jit_bailout()
+    {
+        // Reads past the end of the heap once the loop is compiled
+        run("
+            push 0;
+            LOOP:
+            dup; load_u32; pop;
+            push 1024; add_u64; jmp LOOP;
+        ", true);
+    }
+
+    #[test]
+    fn test_jit_div_zero()
+    {
+        let mut vm = Assembler::new().parse_str("
+            push 100;
+            LOOP:
+            push 1000; getn 1; div_u64; pop;
+            push 1; sub_u64; jmp LOOP;
+        ").unwrap();
+        vm.enable_jit();
+
+        match vm.call(0, &[]) {
+            ExitReason::Trap(trap) => assert_eq!(trap.kind, TrapKind::DivByZero),
+            _ => panic!("expected a trap"),
+        }
+        assert!(vm.jit_blocks_compiled() > 0);
+    }
+}
@@ -4,20 +4,40 @@
 #![allow(unused_imports)]
 
 mod vm;
+mod asan;
+mod protect;
+mod profile;
+mod coverage;
+mod block_cache;
+mod jit;
 mod sys;
 mod asm;
+mod image;
+mod incremental;
 mod utils;
+mod ed25519;
+mod signing;
+mod verify;
+mod snapshot;
+mod minidump;
+mod threads;
+mod test_runner;
+mod diff_jit;
 
 extern crate sdl2;
 extern crate libc;
 use std::env;
+use std::io::{stdout, Write};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::process::exit;
 use std::sync::{Arc, Mutex};
 use crate::vm::{VM, Value, MemBlock, ExitReason};
 use crate::asm::{Assembler};
+use crate::verify::OpRestrictions;
+use crate::incremental::IncrementalAsm;
 use crate::sys::{SysState};
+use crate::sys::constants::SYSCALL_DESCS;
 use crate::utils::{thousands_sep};
 
 /// Command-line options
@@ -27,17 +47,128 @@ struct Options
     // Only parse/validate the input, but don't run it
     parse_only: bool,
 
+    // Collect and print per-syscall call counts and timings
+    syscall_stats: bool,
+
+    // Deny network access to addresses that aren't explicitly allowed
+    net_deny_all: bool,
+
+    // Network addresses (host:port) the program is allowed to use
+    net_allow: Vec<String>,
+
+    // File listing the network addresses the program is allowed to use
+    net_policy: Option<String>,
+
+    // Host directories visible to the program (guest_dir=host_dir)
+    fs_maps: Vec<String>,
+
+    // Archives mounted as read-only directories (archive_file:guest_dir)
+    fs_mounts: Vec<String>,
+
+    // Maximum heap size the program can grow the heap to, in bytes
+    max_heap: Option<usize>,
+
+    // Resource quotas (name=limit)
+    quotas: Vec<String>,
+
+    // Permissions whose syscalls the program can't use
+    denied: Vec<String>,
+
+    // Classes of instructions the program can't use
+    denied_ops: Vec<String>,
+
+    // Allow the program to run host commands
+    allow_exec: bool,
+
+    // Give the program the host paths of files dropped onto its windows
+    allow_dropfile: bool,
+
+    // Detect invalid guest heap accesses using shadow memory
+    asan: bool,
+
+    // Report guest allocations that were never freed on exit
+    detect_leaks: bool,
+
+    // File to write the function call and loop counts to
+    profile: Option<String>,
+
+    // File to write the lcov coverage report to
+    coverage: Option<String>,
+
+    // Write the spans recorded with trace_begin/trace_end to this file
+    trace_out: Option<String>,
+
+    // Write a minidump to this file when a trap stops the program
+    dump_on_trap: Option<String>,
+
+    // Read assembly from the console and run it line by line
+    repl: bool,
+
+    // Make execution reproducible across runs and host platforms
+    deterministic: bool,
+
+    // Compile frequently executed code to machine code
+    jit: bool,
+
+    // Run the program with the interpreter and with the JIT, and compare the runs
+    diff_jit: bool,
+
+    // Stop with an error message on invalid memory accesses
+    checked_memory: bool,
+
+    // Use the program as a filter in a pipeline
+    stdio: bool,
+
+    // Skip the bytecode verifier
+    no_verify: bool,
+
+    // Compress the sections of the images written by uvm build
+    compress: bool,
+
+    // Refuse to run programs that aren't signed by a trusted key
+    require_signed: bool,
+
+    // Public key files of the trusted signers
+    trusted_keys: Vec<String>,
+
     rest: Vec<String>,
 }
 
 // TODO: parse permissions
 // --allow <permissions>
-// --deny <permissions>
 // --allow-all
 fn parse_args(args: Vec<String>) -> Options
 {
     let mut opts = Options {
         parse_only: false,
+        syscall_stats: false,
+        net_deny_all: false,
+        net_allow: Vec::default(),
+        fs_maps: Vec::default(),
+        fs_mounts: Vec::default(),
+        net_policy: None,
+        max_heap: None,
+        quotas: Vec::default(),
+        denied: Vec::default(),
+        denied_ops: Vec::default(),
+        allow_exec: false,
+        allow_dropfile: false,
+        asan: false,
+        detect_leaks: false,
+        profile: None,
+        coverage: None,
+        trace_out: None,
+        dump_on_trap: None,
+        repl: false,
+        deterministic: false,
+        jit: false,
+        diff_jit: false,
+        checked_memory: false,
+        stdio: false,
+        no_verify: false,
+        compress: false,
+        require_signed: false,
+        trusted_keys: Vec::default(),
         rest: Vec::default(),
     };
 
@@ -59,10 +190,177 @@ fn parse_args(args: Vec<String>) -> Options
 
         // Try to match this argument as an option
         match arg.as_str() {
+            "--repl" => {
+                opts.repl = true;
+            }
+
+            "--deterministic" => {
+                opts.deterministic = true;
+            }
+
+            "--jit" => {
+                opts.jit = true;
+            }
+
+            "--diff-jit" => {
+                opts.diff_jit = true;
+            }
+
+            "--checked-memory" => {
+                opts.checked_memory = true;
+            }
+
+            "--stdio" => {
+                opts.stdio = true;
+            }
+
+            "--no-verify" => {
+                opts.no_verify = true;
+            }
+
+            "--compress" => {
+                opts.compress = true;
+            }
+
+            "--require-signed" => {
+                opts.require_signed = true;
+            }
+
+            "--trusted-key" => {
+                if idx >= args.len() {
+                    panic!("--trusted-key expects a public key file argument");
+                }
+                opts.trusted_keys.push(args[idx].clone());
+                idx += 1;
+            }
+
             "--parse-only" => {
                 opts.parse_only = true;
             }
 
+            "--syscall-stats" => {
+                opts.syscall_stats = true;
+            }
+
+            "--net-deny-all" => {
+                opts.net_deny_all = true;
+            }
+
+            "--net-allow" => {
+                if idx >= args.len() {
+                    panic!("--net-allow expects a host:port argument");
+                }
+                opts.net_allow.push(args[idx].clone());
+                idx += 1;
+            }
+
+            "--map" => {
+                if idx >= args.len() {
+                    panic!("--map expects a guest_dir=host_dir argument");
+                }
+                opts.fs_maps.push(args[idx].clone());
+                idx += 1;
+            }
+
+            "--mount" => {
+                if idx >= args.len() {
+                    panic!("--mount expects an archive_file:guest_dir argument");
+                }
+                opts.fs_mounts.push(args[idx].clone());
+                idx += 1;
+            }
+
+            "--net-policy" => {
+                if idx >= args.len() {
+                    panic!("--net-policy expects a file name argument");
+                }
+                opts.net_policy = Some(args[idx].clone());
+                idx += 1;
+            }
+
+            "--max-heap" => {
+                if idx >= args.len() {
+                    panic!("--max-heap expects a size in bytes");
+                }
+                match args[idx].parse::<usize>() {
+                    Ok(num_bytes) => opts.max_heap = Some(num_bytes),
+                    Err(_) => panic!("invalid --max-heap size \"{}\"", args[idx]),
+                }
+                idx += 1;
+            }
+
+            "--quota" => {
+                if idx >= args.len() {
+                    panic!("--quota expects a name=limit argument");
+                }
+                opts.quotas.push(args[idx].clone());
+                idx += 1;
+            }
+
+            "--deny" => {
+                if idx >= args.len() {
+                    panic!("--deny expects a permission argument");
+                }
+                opts.denied.push(args[idx].clone());
+                idx += 1;
+            }
+
+            "--deny-ops" => {
+                if idx >= args.len() {
+                    panic!("--deny-ops expects an instruction class argument");
+                }
+                opts.denied_ops.push(args[idx].clone());
+                idx += 1;
+            }
+
+            "--allow-exec" => {
+                opts.allow_exec = true;
+            }
+
+            "--allow-dropfile" => {
+                opts.allow_dropfile = true;
+            }
+
+            "--asan" => {
+                opts.asan = true;
+            }
+
+            "--detect-leaks" => {
+                opts.detect_leaks = true;
+            }
+
+            "--profile" => {
+                if idx >= args.len() {
+                    panic!("--profile expects an output file name argument");
+                }
+                opts.profile = Some(args[idx].clone());
+                idx += 1;
+            }
+
+            "--coverage" => {
+                if idx >= args.len() {
+                    panic!("--coverage expects an output file name argument");
+                }
+                opts.coverage = Some(args[idx].clone());
+                idx += 1;
+            }
+
+            "--trace-out" => {
+                if idx >= args.len() {
+                    panic!("--trace-out expects an output file name argument");
+                }
+                opts.trace_out = Some(args[idx].clone());
+                idx += 1;
+            }
+
+            "--dump-on-trap" => {
+                if idx >= args.len() {
+                    panic!("--dump-on-trap expects an output file name argument");
+                }
+                opts.dump_on_trap = Some(args[idx].clone());
+                idx += 1;
+            }
+
             _ => panic!("unknown option {}", arg)
         }
     }
@@ -74,7 +372,7 @@ fn run_program(mutex: &mut Arc<Mutex<VM>>) -> Value
 {
     let mut vm = mutex.lock().unwrap();
 
-    match vm.call(0, &[])
+    match vm.call_sync(0, &[])
     {
         ExitReason::Exit(val) => {
             return val;
@@ -83,65 +381,545 @@ fn run_program(mutex: &mut Arc<Mutex<VM>>) -> Value
         // Keep processig events
         ExitReason::Return(val) => {
         }
+
+        // call_sync() continues after yields
+        _ => unreachable!(),
     }
 
     drop(vm);
 
     loop
     {
+        // Stop the event loop if ctrl-C was pressed while we were idle
+        if sys::stop_requested() {
+            return Value::from(sys::SIGINT_EXIT_CODE);
+        }
+
         let mut vm = mutex.lock().unwrap();
 
+        // A callback running on another thread may have exited
+        if let Some(val) = vm.sys_state.proc_state.pending_exit.take() {
+            return val;
+        }
+
         if let ExitReason::Exit(val) = sys::window::process_events(&mut vm) {
             return val;
         }
 
+        // Give a time slice to the other guest threads, if some can
+        // run, instead of sleeping while the main thread is idle
+        let threads_ran = vm.has_runnable_threads();
+        if threads_ran {
+            if let Some(val) = vm.run_threads() {
+                return val;
+            }
+        }
+
         let next_cb_time = sys::time::time_until_next_cb(&mut vm);
 
+        // With a virtual clock, skip ahead to the next callback instead of sleeping
+        if let (true, Some(delay_ms)) = (vm.sys_state.time_state.is_virtual(), next_cb_time) {
+            if let Some(val) = sys::time::advance_time(&mut vm, delay_ms) {
+                return val;
+            }
+            continue;
+        }
+
         // Unlock the VM mutex before going to sleep, so that other threads,
         // such as the audio thread, may use the VM
         drop(vm);
 
-        // Sleep until the next callback
-        if let Some(delay_ms) = next_cb_time {
-            let min_delay = std::cmp::min(delay_ms, 10);
-            sleep(Duration::from_millis(min_delay));
+        // Sleep until the next callback, unless the threads are busy
+        if !threads_ran {
+            let delay_ms = next_cb_time.map_or(10, |delay_ms| std::cmp::min(delay_ms, 10));
+            sleep(Duration::from_millis(delay_ms));
         }
-        else
-        {
-            sleep(Duration::from_millis(10));
+
+        let mut vm = mutex.lock().unwrap();
+
+        // Run the callbacks which are due
+        if let Some(val) = sys::time::run_due_cbs(&mut vm) {
+            return val;
         }
+    }
+}
 
+/// Run a program with the interpreter, then with the JIT, in deterministic
+/// mode, and report the first difference between the runs, for --diff-jit.
+/// Returns the exit code of the uvm command.
+fn diff_jit_program(vm: VM, jit_vm: VM, opts: &Options) -> i32
+{
+    sys::install_sigint_handler();
+
+    let mut results = Vec::new();
+    for (mut vm, jit) in [(vm, false), (jit_vm, true)] {
+        let opts = Options { jit, deterministic: true, ..opts.clone() };
+        setup_vm(&mut vm, &opts);
+        vm.sys_state.captured_output = Some(Vec::new());
+
+        let mut mutex = SysState::get_mutex(vm);
+        let exit_code = run_program(&mut mutex);
         let mut vm = mutex.lock().unwrap();
+        sys::proc::run_atexit_cbs(&mut vm);
 
-        // For each callback to run
-        for pc in sys::time::get_cbs_to_run(&mut vm)
-        {
-            match vm.call(pc, &[])
-            {
-                ExitReason::Exit(val) => {
-                    return val;
-                }
-                ExitReason::Return(val) => {
-                }
+        results.push((diff_jit::RunResult::capture(&vm, exit_code), vm.jit_blocks_compiled()));
+    }
+
+    let (jit, num_compiled) = results.pop().unwrap();
+    let (interp, _) = results.pop().unwrap();
+
+    // Only the output of the interpreter run is shown
+    let mut out = stdout();
+    out.write_all(&interp.output).unwrap();
+    out.flush().unwrap();
+
+    if let Some(divergence) = diff_jit::find_divergence(&interp, &jit) {
+        println!("Error: the interpreter and JIT runs diverge: {}", divergence);
+        return -1;
+    }
+
+    if num_compiled == 0 {
+        eprintln!("warning: no code was compiled by the JIT, so both runs used the interpreter");
+    }
+
+    interp.exit_code.as_i32()
+}
+
+/// Parse the program read from a file, checking it
+/// with the verifier as the options require
+fn parse_program(file_name: &str, source: Vec<u8>, opts: &Options) -> Result<VM, String>
+{
+    let asm = if opts.no_verify { Assembler::new() } else { Assembler::new().verified() };
+
+    // Instruction restrictions are checked by the verifier, even with --no-verify
+    let asm = if opts.denied_ops.is_empty() { asm } else {
+        let mut restrictions = OpRestrictions::default();
+        for class in &opts.denied_ops {
+            restrictions.deny_name(class)?;
+        }
+        asm.restricted(restrictions)
+    };
+
+    asm.parse_bytes(file_name, source).map_err(|error| error.to_string())
+}
+
+/// Configure a VM to run the program according to the options
+fn setup_vm(vm: &mut VM, opts: &Options)
+{
+    if opts.syscall_stats {
+        vm.sys_state.enable_syscall_stats();
+    }
+
+    vm.sys_state.proc_state.allow_exec = opts.allow_exec;
+    vm.sys_state.allow_dropfile = opts.allow_dropfile;
+    vm.sys_state.proc_state.args = opts.rest.clone();
+
+    if opts.asan {
+        vm.enable_asan();
+    }
+
+    if opts.detect_leaks {
+        vm.enable_leak_detection();
+    }
+
+    if opts.profile.is_some() {
+        vm.enable_profiler();
+    }
+
+    if opts.coverage.is_some() {
+        vm.enable_coverage();
+    }
+
+    if opts.jit {
+        vm.enable_jit();
+    }
+
+    if opts.checked_memory {
+        vm.set_checked_memory(true);
+    }
+
+    if opts.stdio {
+        vm.sys_state.stdio_mode = true;
+    }
+
+    if let Some(num_bytes) = opts.max_heap {
+        vm.set_max_heap_size(num_bytes);
+    }
+
+    if opts.dump_on_trap.is_some() {
+        vm.enable_minidumps();
+    }
+
+    if opts.trace_out.is_some() {
+        vm.sys_state.trace_state.enable();
+    }
+
+    if opts.deterministic {
+        vm.enable_deterministic_floats();
+        vm.sys_state.time_state.use_virtual_clock(0);
+    }
+
+    // Set up the network policy before any guest code runs
+    let policy = &mut vm.sys_state.net_state.policy;
+    if opts.net_deny_all {
+        policy.deny_all();
+    }
+    for rule in &opts.net_allow {
+        if let Err(error) = policy.allow(rule) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+    if let Some(file_name) = &opts.net_policy {
+        if let Err(error) = policy.load_file(file_name) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+
+    for mapping in &opts.fs_maps {
+        if let Err(error) = vm.sys_state.fs_state.map_host_dir(mapping) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+
+    for mount in &opts.fs_mounts {
+        if let Err(error) = vm.sys_state.fs_state.mount_archive(mount) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+
+    for setting in &opts.quotas {
+        if let Err(error) = vm.sys_state.quotas.set(setting) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+
+    for perm in &opts.denied {
+        if let Err(error) = vm.sys_state.deny_permission(perm) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+}
+
+/// Run the keygen, sign and verify commands, returning the exit code
+fn signing_command(args: &[String]) -> i32
+{
+    let result = match (args[0].as_str(), &args[1..]) {
+        ("keygen", [name]) => signing::generate_key(name).map(|key| {
+            println!("wrote {}.key and {}.pub", name, name);
+            println!("public key: {}", signing::to_hex(&key));
+        }),
+
+        ("sign", [key_file, program]) => signing::sign_file(key_file, program).map(|key| {
+            println!("wrote {}", signing::sig_path(program).display());
+            println!("signed by: {}", signing::to_hex(&key));
+        }),
+
+        // Any public key files given are the trusted signers
+        ("verify", [program, key_files @ ..]) => {
+            key_files.iter()
+                .map(|path| signing::read_public_key(path))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|keys| signing::verify_file(program, &keys))
+                .map(|key| {
+                    println!("signature: valid");
+                    println!("signed by: {}", signing::to_hex(&key));
+                })
+        }
+
+        _ => Err(
+            "usage: uvm keygen <name> | uvm sign <secret key file> <program> | \
+             uvm verify <program> [trusted public key files...]".to_string()
+        ),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            println!("Error: {}", error);
+            1
+        }
+    }
+}
+
+/// Print the image header and a summary of the contents of a program
+fn print_info(file_name: &str)
+{
+    let image = match Assembler::new().assemble_file(file_name) {
+        Ok(image) => image,
+        Err(error) => {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    };
+
+    let meta = &image.metadata;
+    println!("name: {}", meta.name.as_deref().unwrap_or("(none)"));
+    println!("author: {}", meta.author.as_deref().unwrap_or("(none)"));
+
+    if meta.permissions.is_empty() {
+        println!("permissions: (not declared)");
+    } else {
+        println!("permissions: {}", meta.permissions.join(", "));
+    }
+
+    match meta.min_vm_version {
+        Some((major, minor, patch)) => println!("min VM version: {}.{}.{}", major, minor, patch),
+        None => println!("min VM version: (none)"),
+    }
+
+    println!("code size: {} bytes", thousands_sep(image.code.len() as u64));
+    println!("data size: {} bytes", thousands_sep(image.data.len() as u64));
+    println!("data checksum: {:08x}", meta.data_checksum);
+
+    let mut syscalls: Vec<&str> = image.syscalls.iter().map(|&idx| {
+        SYSCALL_DESCS[idx as usize].as_ref().unwrap().name
+    }).collect();
+    syscalls.sort();
+    println!("syscalls: {}", syscalls.join(", "));
+
+    let mut exports: Vec<&str> = image.exports.iter().map(|e| e.name.as_str()).collect();
+    exports.sort();
+    println!("exports: {}", exports.join(", "));
+
+    match image.validate() {
+        Ok(()) => println!("valid: yes"),
+        Err(msg) => {
+            println!("valid: no ({})", msg);
+            exit(-1);
+        }
+    }
+
+    match verify::verify_image(&image) {
+        Ok(()) => println!("verified: yes"),
+        Err(msg) => {
+            println!("verified: no ({})", msg);
+            exit(-1);
+        }
+    }
+}
+
+/// Assemble a program into a binary image file, which
+/// can then be run without assembling it again
+fn build_image(src_file: &str, out_file: &str, compress: bool) -> i32
+{
+    let image = match Assembler::new().assemble_file(src_file) {
+        Ok(image) => image,
+        Err(error) => {
+            println!("Error: {}", error);
+            return 1;
+        }
+    };
+
+    let result = image.validate()
+        .and_then(|_| verify::verify_image(&image))
+        .map(|_| if compress { image.to_compressed_bytes() } else { image.to_bytes() })
+        .and_then(|bytes| {
+            std::fs::write(out_file, &bytes)
+                .map(|_| bytes.len())
+                .map_err(|err| format!("could not write \"{}\": {}", out_file, err))
+        });
+
+    match result {
+        Ok(num_bytes) => {
+            println!("wrote {} ({} bytes)", out_file, thousands_sep(num_bytes as u64));
+            0
+        }
+        Err(msg) => {
+            println!("Error: {}", msg);
+            1
+        }
+    }
+}
+
+/// Inspect a minidump written by --dump-on-trap, printing a summary,
+/// then the parts of it named by the commands read from stdin
+fn debug_dump(dump_file: &str) -> i32
+{
+    let dump = match std::fs::read(dump_file) {
+        Ok(data) => minidump::Minidump::decode(&data),
+        Err(error) => Err(format!("could not read \"{}\": {}", dump_file, error)),
+    };
+
+    let dump = match dump {
+        Ok(dump) => dump,
+        Err(error) => {
+            println!("Error: {}", error);
+            return -1;
+        }
+    };
+
+    print!("{}backtrace:\n{}", dump.summary(), dump.backtrace());
+
+    for line in std::io::stdin().lines() {
+        match line.unwrap().trim() {
+            "" => continue,
+            "bt" => print!("{}", dump.backtrace()),
+            "stack" => print!("{}", dump.stack_values()),
+            "code" => print!("{}", dump.code_dump()),
+            "heap" => print!("{}", dump.heap_dump()),
+            "info" => print!("{}", dump.summary()),
+            "quit" => break,
+            cmd => println!("unknown command \"{}\", expected bt, stack, code, heap, info or quit", cmd),
+        }
+    }
+
+    0
+}
+
+/// Check if a line of REPL input defines labels or data,
+/// as opposed to being code to run
+fn is_definition(line: &str) -> bool
+{
+    if line.starts_with('.') {
+        return true;
+    }
+
+    let ident_len = line.find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_').unwrap_or(line.len());
+    ident_len > 0 && line[ident_len..].trim_start().starts_with(':')
+}
+
+/// Read-eval-print loop, starting from the definitions of an optional
+/// program file. Lines which start with a label or an assembler
+/// command are definitions, which are appended to the program. Other lines
+/// are run as code, and the value they leave on the stack is printed.
+fn repl(file_name: Option<&String>)
+{
+    let mut vm = Assembler::new().parse_str("").unwrap();
+    let mut asm = IncrementalAsm::new(&vm);
+
+    // Load the definitions of the program, without running it
+    if let Some(file_name) = file_name {
+        let result = match std::fs::read_to_string(file_name) {
+            Ok(src) => asm.append(&mut vm, &src),
+            Err(_) => Err(format!("could not open asm file \"{}\"", file_name)),
+        };
+
+        if let Err(error) = result {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+
+    for line in std::io::stdin().lines() {
+        let line = line.unwrap();
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if is_definition(line) {
+            if let Err(error) = asm.append(&mut vm, line) {
+                println!("Error: {}", error);
             }
+            continue;
+        }
+
+        let pc = match asm.append(&mut vm, &format!("{}\nret;", line)) {
+            Ok(pc) => pc,
+            Err(error) => {
+                println!("Error: {}", error);
+                continue;
+            }
+        };
+
+        match vm.call_sync(pc as u64, &[]) {
+            ExitReason::Return(val) => println!("{}", val.as_i64()),
+            ExitReason::Exit(val) => exit(val.as_i32()),
+            _ => unreachable!(),
         }
     }
 }
 
 fn main()
 {
-    let opts = parse_args(env::args().collect());
+    let args: Vec<String> = env::args().collect();
+    let opts = parse_args(args.clone());
     //println!("{:?}", opts);
 
-    if opts.rest.len() != 1 {
-        panic!("must specify exactly one input file to run");
+    if opts.repl {
+        repl(opts.rest.first());
+        exit(0);
+    }
+
+    if opts.rest.len() == 2 && opts.rest[0] == "info" {
+        print_info(&opts.rest[1]);
+        exit(0);
+    }
+
+    if !opts.rest.is_empty() && opts.rest[0] == "debug" {
+        match &opts.rest[1..] {
+            [flag, dump_file] if flag == "--open-dump" => exit(debug_dump(dump_file)),
+            _ => {
+                println!("usage: uvm debug --open-dump <minidump file>");
+                exit(-1);
+            }
+        }
+    }
+
+    if opts.rest.len() == 3 && opts.rest[0] == "build" {
+        exit(build_image(&opts.rest[1], &opts.rest[2], opts.compress));
+    }
+
+    // The options before the command are passed to each test
+    if opts.rest.len() == 2 && opts.rest[0] == "test" {
+        let vm_args = &args[1..args.len() - 2];
+        exit(test_runner::run_tests(&opts.rest[1], vm_args));
+    }
+
+    if !opts.rest.is_empty() && ["keygen", "sign", "verify"].contains(&opts.rest[0].as_str()) {
+        exit(signing_command(&opts.rest));
+    }
+
+    // Arguments after the input file are passed to the program
+    if opts.rest.is_empty() {
+        panic!("must specify an input file to run");
     }
 
     let file_name = &opts.rest[0];
 
-    // Parse/compile the program
-    let asm = Assembler::new();
-    let result = asm.parse_file(file_name);
+    // The file is read once, so that the bytes which run are
+    // the bytes whose signature gets checked
+    let source = match Assembler::read_file(file_name) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    };
+
+    // Check the signature before parsing anything from the file,
+    // the modules the program loads are checked the same way
+    let trusted_keys = if !opts.require_signed { None } else {
+        if opts.trusted_keys.is_empty() {
+            println!("Error: --require-signed needs at least one --trusted-key");
+            exit(-1);
+        }
+
+        let result = opts.trusted_keys.iter()
+            .map(|path| signing::read_public_key(path))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|keys| signing::verify_bytes(file_name, &source, &keys).map(|_| keys));
+
+        match result {
+            Ok(keys) => Some(keys),
+            Err(error) => {
+                println!("Error: {}", error);
+                exit(-1);
+            }
+        }
+    };
+
+    // Parse/compile the program. With --diff-jit, the VM for the
+    // JIT run is created from the same bytes in the same way.
+    let jit_source = if opts.diff_jit { Some(source.clone()) } else { None };
+    let result = parse_program(file_name, source, &opts);
 
     if let Err(error) = &result {
         println!("Error: {}", error);
@@ -153,10 +931,72 @@ fn main()
         exit(0);
     }
 
-    let vm = result.unwrap();
+    let mut vm = result.unwrap();
+    vm.sys_state.module_state.trusted_keys = trusted_keys.clone();
+
+    if let Some(source) = jit_source {
+        let mut jit_vm = match parse_program(file_name, source, &opts) {
+            Ok(vm) => vm,
+            Err(error) => {
+                println!("Error: {}", error);
+                exit(-1);
+            }
+        };
+        jit_vm.sys_state.module_state.trusted_keys = trusted_keys;
+        exit(diff_jit_program(vm, jit_vm, &opts));
+    }
+
+    setup_vm(&mut vm, &opts);
+
     let mut mutex = SysState::get_mutex(vm);
+    sys::install_sigint_handler();
+    let start_time = Instant::now();
     let ret_val = run_program(&mut mutex);
 
+    // Written before the atexit callbacks run, which could trap as well
+    if let Some(dump_file) = &opts.dump_on_trap {
+        if let Some(mut dump) = mutex.lock().unwrap().take_minidump() {
+            dump.program = file_name.clone();
+            match std::fs::write(dump_file, dump.encode()) {
+                Ok(_) => eprintln!("wrote minidump to \"{}\"", dump_file),
+                Err(error) => eprintln!("could not write minidump to \"{}\": {}", dump_file, error),
+            }
+        }
+    }
+
+    // Give the program a chance to clean up before exiting
+    sys::proc::run_atexit_cbs(&mut mutex.lock().unwrap());
+
+    if opts.syscall_stats {
+        let vm = mutex.lock().unwrap();
+        vm.sys_state.print_syscall_stats(start_time.elapsed());
+    }
+
+    if opts.detect_leaks {
+        mutex.lock().unwrap().print_leak_report();
+    }
+
+    if let Some(file_name) = &opts.profile {
+        let report = mutex.lock().unwrap().profile_report().unwrap();
+        if let Err(error) = std::fs::write(file_name, report) {
+            eprintln!("could not write profile to \"{}\": {}", file_name, error);
+        }
+    }
+
+    if let Some(file_name) = &opts.coverage {
+        let report = mutex.lock().unwrap().coverage_report().unwrap();
+        if let Err(error) = std::fs::write(file_name, report) {
+            eprintln!("could not write coverage report to \"{}\": {}", file_name, error);
+        }
+    }
+
+    if let Some(file_name) = &opts.trace_out {
+        let trace = mutex.lock().unwrap().sys_state.trace_state.to_json();
+        if let Err(error) = std::fs::write(file_name, trace) {
+            eprintln!("could not write trace to \"{}\": {}", file_name, error);
+        }
+    }
+
     #[cfg(feature = "count_insns")]
     {
         let mut vm = mutex.lock().unwrap();
@@ -164,5 +1004,8 @@ fn main()
         println!("insns executed: {}", thousands_sep(insn_count));
     }
 
+    // Make sure buffered guest output isn't lost on exit
+    stdout().flush().unwrap();
+
     exit(ret_val.as_i32());
 }
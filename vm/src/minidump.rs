@@ -0,0 +1,371 @@
+use std::fmt::Write;
+use crate::utils::ByteReader;
+
+// Minidumps of the state of a program when a trap stops it, so that the
+// crash can be inspected later with `uvm debug --open-dump` without running
+// the program again. A dump holds the trap, the value stack, the call frames,
+// the code around the faulting instruction and the heap around the faulting
+// address. Function names and source lines are resolved when the dump is
+// written, and the program is referred to by its file name and the checksum
+// of its code, so that the dump can be matched with the program later.
+//
+// All fields are stored in little-endian order, with strings stored as a
+// length (u64) followed by UTF-8 bytes:
+//   magic "UVMDUMP\0", format version (u32)
+//   program file name (string), CRC-32 of the code (u32)
+//   trap description (string), TRAP_* code (u32), faulting address or syscall (u64)
+//   pc, bp (u64 each), instruction at the pc (string)
+//   stack size (u64), stack values (u64 each)
+//   frame count (u64), for each frame fn_pc and pc (u64 each), then the
+//   function name and source line: present flag (u8) and string
+//   code and heap regions: start (u64), size (u64) and bytes
+//   heap size (u64)
+
+const MAGIC: &[u8; 8] = b"UVMDUMP\0";
+const VERSION: u32 = 1;
+
+/// Number of bytes of code kept before and after the faulting instruction
+pub const CODE_CONTEXT: usize = 32;
+
+/// Number of bytes of heap kept before and after the faulting address
+pub const HEAP_CONTEXT: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpFrame
+{
+    /// Start of the function, and the position execution is at in it
+    pub fn_pc: usize,
+    pub pc: usize,
+
+    pub fn_name: Option<String>,
+
+    /// Source file and line of the pc, as "file:line"
+    pub source: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Minidump
+{
+    /// File the program was loaded from, empty if unknown
+    pub program: String,
+    pub code_checksum: u32,
+
+    /// Description of the trap, TRAP_* code and faulting address or syscall
+    pub trap: String,
+    pub trap_code: u32,
+    pub trap_addr: u64,
+
+    pub pc: usize,
+    pub bp: usize,
+
+    /// Name of the faulting instruction
+    pub insn: String,
+
+    pub stack: Vec<u64>,
+
+    /// Call frames, innermost first
+    pub frames: Vec<DumpFrame>,
+
+    /// Code around the faulting instruction
+    pub code_start: usize,
+    pub code: Vec<u8>,
+
+    /// Heap around the faulting address, empty for
+    /// traps which aren't caused by a memory access
+    pub heap_start: usize,
+    pub heap: Vec<u8>,
+
+    pub heap_size: usize,
+}
+
+fn push_u64(out: &mut Vec<u8>, val: usize)
+{
+    out.extend_from_slice(&(val as u64).to_le_bytes());
+}
+
+fn push_str(out: &mut Vec<u8>, s: &str)
+{
+    push_u64(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn push_opt_str(out: &mut Vec<u8>, s: &Option<String>)
+{
+    out.push(s.is_some() as u8);
+    if let Some(s) = s {
+        push_str(out, s);
+    }
+}
+
+fn read_opt_str(r: &mut ByteReader) -> Result<Option<String>, String>
+{
+    match r.u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(r.string()?)),
+    }
+}
+
+/// Format bytes as rows of 16, each starting with the address of its first
+/// byte, with the byte at a given address marked with brackets
+fn hex_dump(start: usize, bytes: &[u8], mark: usize) -> String
+{
+    let mut out = String::new();
+
+    for (row_idx, row) in bytes.chunks(16).enumerate() {
+        let row_start = start + row_idx * 16;
+        write!(out, "  {:8}:", row_start).unwrap();
+
+        for (idx, byte) in row.iter().enumerate() {
+            match row_start + idx == mark {
+                true => write!(out, "[{:02x}]", byte).unwrap(),
+                false => write!(out, " {:02x} ", byte).unwrap(),
+            }
+        }
+
+        out.push('\n');
+    }
+
+    out
+}
+
+impl Minidump
+{
+    pub fn encode(&self) -> Vec<u8>
+    {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        push_str(&mut out, &self.program);
+        out.extend_from_slice(&self.code_checksum.to_le_bytes());
+
+        push_str(&mut out, &self.trap);
+        out.extend_from_slice(&self.trap_code.to_le_bytes());
+        out.extend_from_slice(&self.trap_addr.to_le_bytes());
+        push_u64(&mut out, self.pc);
+        push_u64(&mut out, self.bp);
+        push_str(&mut out, &self.insn);
+
+        push_u64(&mut out, self.stack.len());
+        for val in &self.stack {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+
+        push_u64(&mut out, self.frames.len());
+        for frame in &self.frames {
+            push_u64(&mut out, frame.fn_pc);
+            push_u64(&mut out, frame.pc);
+            push_opt_str(&mut out, &frame.fn_name);
+            push_opt_str(&mut out, &frame.source);
+        }
+
+        for (start, bytes) in [(self.code_start, &self.code), (self.heap_start, &self.heap)] {
+            push_u64(&mut out, start);
+            push_u64(&mut out, bytes.len());
+            out.extend_from_slice(bytes);
+        }
+
+        push_u64(&mut out, self.heap_size);
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Minidump, String>
+    {
+        let mut r = ByteReader::new(data);
+
+        if r.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err("not a minidump".to_string());
+        }
+
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(format!("unsupported minidump version {}", version));
+        }
+
+        let program = r.string()?;
+        let code_checksum = r.u32()?;
+
+        let trap = r.string()?;
+        let trap_code = r.u32()?;
+        let trap_addr = r.u64()?;
+        let pc = r.usize()?;
+        let bp = r.usize()?;
+        let insn = r.string()?;
+
+        let stack_size = r.count(8)?;
+        let mut stack = Vec::with_capacity(stack_size);
+        for _ in 0..stack_size {
+            stack.push(r.u64()?);
+        }
+
+        let num_frames = r.count(2 * 8 + 2)?;
+        let mut frames = Vec::with_capacity(num_frames);
+        for _ in 0..num_frames {
+            frames.push(DumpFrame {
+                fn_pc: r.usize()?,
+                pc: r.usize()?,
+                fn_name: read_opt_str(&mut r)?,
+                source: read_opt_str(&mut r)?,
+            });
+        }
+
+        let code_start = r.usize()?;
+        let code_len = r.count(1)?;
+        let code = r.bytes(code_len)?.to_vec();
+        let heap_start = r.usize()?;
+        let heap_len = r.count(1)?;
+        let heap = r.bytes(heap_len)?.to_vec();
+        let heap_size = r.usize()?;
+
+        if !r.at_end() {
+            return Err("trailing data after minidump".to_string());
+        }
+
+        Ok(Minidump {
+            program,
+            code_checksum,
+            trap,
+            trap_code,
+            trap_addr,
+            pc,
+            bp,
+            insn,
+            stack,
+            frames,
+            code_start,
+            code,
+            heap_start,
+            heap,
+            heap_size,
+        })
+    }
+
+    /// Describe the trap and where it happened
+    pub fn summary(&self) -> String
+    {
+        let mut out = String::new();
+
+        let program = if self.program.is_empty() { "unknown" } else { &self.program };
+        writeln!(out, "program: {} (code checksum {:08x})", program, self.code_checksum).unwrap();
+        writeln!(out, "trap: {}", self.trap).unwrap();
+        writeln!(out, "instruction: {}", self.insn).unwrap();
+
+        if let Some(source) = self.frames.first().and_then(|frame| frame.source.as_ref()) {
+            writeln!(out, "source: {}", source).unwrap();
+        }
+
+        writeln!(out, "pc: {}, bp: {}, stack size: {}, heap size: {}", self.pc, self.bp, self.stack.len(), self.heap_size).unwrap();
+        out
+    }
+
+    /// Describe the call frames, innermost first, in
+    /// the same format as the backtraces of traps
+    pub fn backtrace(&self) -> String
+    {
+        let mut out = String::new();
+
+        for (idx, frame) in self.frames.iter().enumerate() {
+            match (&frame.fn_name, frame.pc.checked_sub(frame.fn_pc)) {
+                (Some(name), Some(offset)) => write!(out, "  #{} pc {} ({}+{})", idx, frame.pc, name, offset),
+                (Some(name), None) => write!(out, "  #{} pc {} (in {})", idx, frame.pc, name),
+                (None, _) => write!(out, "  #{} pc {}", idx, frame.pc),
+            }.unwrap();
+
+            match &frame.source {
+                Some(source) => writeln!(out, " at {}", source).unwrap(),
+                None => out.push('\n'),
+            }
+        }
+
+        out
+    }
+
+    /// List the values on the stack, top first, with a line under
+    /// the values of the innermost frame, which are above the base pointer
+    pub fn stack_values(&self) -> String
+    {
+        let mut out = String::new();
+
+        for (idx, val) in self.stack.iter().enumerate().rev() {
+            if idx + 1 == self.bp {
+                out.push_str("  ---- bp\n");
+            }
+            writeln!(out, "  [{}] {} (0x{:x})", idx, *val as i64, val).unwrap();
+        }
+
+        out
+    }
+
+    /// Hex dump of the code around the faulting instruction, which is marked
+    pub fn code_dump(&self) -> String
+    {
+        hex_dump(self.code_start, &self.code, self.pc)
+    }
+
+    /// Hex dump of the heap around the faulting address, which is marked
+    pub fn heap_dump(&self) -> String
+    {
+        if self.heap.is_empty() {
+            return "  no heap memory was saved with this trap\n".to_string();
+        }
+
+        hex_dump(self.heap_start, &self.heap, self.trap_addr as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn dump() -> Minidump
+    {
+        Minidump {
+            program: "prog.asm".to_string(),
+            code_checksum: 0xDEADBEEF,
+            trap: "division by zero, pc 18".to_string(),
+            trap_code: 3,
+            trap_addr: 0,
+            pc: 18,
+            bp: 1,
+            insn: "div_u64".to_string(),
+            stack: vec![1, u64::MAX, 0],
+            frames: vec![
+                DumpFrame { fn_pc: 15, pc: 18, fn_name: Some("HELPER".to_string()), source: Some("prog.c:7".to_string()) },
+                DumpFrame { fn_pc: 0, pc: 6, fn_name: None, source: None },
+            ],
+            code_start: 0,
+            code: (0..20).collect(),
+            heap_start: 0,
+            heap: vec![],
+            heap_size: 4096,
+        }
+    }
+
+    #[test]
+    fn test_encode_decode()
+    {
+        let dump = dump();
+        let data = dump.encode();
+        assert_eq!(Minidump::decode(&data), Ok(dump));
+
+        for len in 0..data.len() {
+            assert!(Minidump::decode(&data[..len]).is_err());
+        }
+        assert!(Minidump::decode(b"UVMSNAP\0\x01\0\0\0").is_err());
+    }
+
+    #[test]
+    fn test_format()
+    {
+        let dump = dump();
+        assert_eq!(dump.backtrace(), "  #0 pc 18 (HELPER+3) at prog.c:7\n  #1 pc 6\n");
+        assert_eq!(dump.stack_values(), "  [2] 0 (0x0)\n  [1] -1 (0xffffffffffffffff)\n  ---- bp\n  [0] 1 (0x1)\n");
+        assert_eq!(dump.heap_dump(), "  no heap memory was saved with this trap\n");
+        assert!(dump.summary().contains("trap: division by zero, pc 18\n"));
+        assert!(dump.summary().contains("source: prog.c:7\n"));
+        assert_eq!(dump.code_dump().lines().nth(1).unwrap(), "        16: 10  11 [12] 13 ");
+    }
+}
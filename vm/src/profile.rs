@@ -0,0 +1,102 @@
+//! Execution profiler for --profile
+//!
+//! We count the calls to each function and the backward jumps taken
+//! inside of each function, which correspond to loop iterations.
+//! The profile is written as a text file with one line per function,
+//! hottest first:
+//!
+//!     <function_name> <num_calls> <num_loop_iterations>
+//!
+//! ncc can read this file with --profile-use to optimize hot functions.
+
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct Profiler
+{
+    // Number of calls, indexed by function address
+    calls: HashMap<usize, u64>,
+
+    // Number of backward jumps taken, indexed by jump target address
+    back_edges: HashMap<usize, u64>,
+}
+
+impl Profiler
+{
+    pub fn count_call(&mut self, callee_pc: usize)
+    {
+        *self.calls.entry(callee_pc).or_default() += 1;
+    }
+
+    pub fn count_back_edge(&mut self, target_pc: usize)
+    {
+        *self.back_edges.entry(target_pc).or_default() += 1;
+    }
+
+    /// Produce the profile report. Functions are named using the code
+    /// labels (name, address) defined at their entry point.
+    pub fn report(&self, labels: &[(String, usize)]) -> String
+    {
+        let mut fun_addrs: Vec<usize> = self.calls.keys().copied().collect();
+        fun_addrs.sort();
+
+        // Attribute each loop to the function it is located in
+        let mut loop_counts: HashMap<usize, u64> = HashMap::default();
+        for (&target_pc, &count) in &self.back_edges {
+            let idx = fun_addrs.partition_point(|&addr| addr <= target_pc);
+            if idx > 0 {
+                *loop_counts.entry(fun_addrs[idx - 1]).or_default() += count;
+            }
+        }
+
+        let mut rows: Vec<(String, u64, u64)> = fun_addrs.iter().map(|addr| {
+            // Prefer names that don't look like local labels
+            let mut names = labels.iter().filter(|(_, pos)| pos == addr).map(|(name, _)| name);
+            let name = names.clone().find(|name| !name.starts_with('_')).or(names.next());
+
+            (
+                name.cloned().unwrap_or_else(|| format!("0x{:x}", addr)),
+                self.calls[addr],
+                loop_counts.get(addr).copied().unwrap_or(0),
+            )
+        }).collect();
+
+        rows.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(&b.0)));
+
+        let mut out = String::new();
+        out.push_str("# function calls loop_iterations\n");
+        for (name, calls, loops) in rows {
+            out.push_str(&format!("{} {} {}\n", name, calls, loops));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::asm::Assembler;
+
+    #[test]
+    fn test_profile()
+    {
+        let mut vm = Assembler::new().parse_str("
+            push 0; call MAIN, 1; exit;
+            MAIN:
+            push 0;
+            LOOP:
+            push 1; add_u64;
+            dup; call F, 1; pop;
+            dup; push 10; lt_u64; jnz LOOP;
+            ret;
+            F: get_arg 0; ret;
+        ").unwrap();
+
+        vm.enable_profiler();
+        vm.call(0, &[]);
+
+        let report = vm.profile_report().unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines, ["# function calls loop_iterations", "F 10 0", "MAIN 1 9", "0x0 1 0"]);
+    }
+}
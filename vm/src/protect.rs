@@ -0,0 +1,106 @@
+//! Page protections of the heap
+//!
+//! The guest can make ranges of heap pages read-only or inaccessible with
+//! the vm_mem_protect syscall, for instance to place guard pages around
+//! the metadata of its allocator. The page size is fixed so that the
+//! behavior doesn't depend on the host. Protections are checked by the
+//! load and store instructions, with checked memory, but not by syscalls.
+
+use crate::sys::constants::*;
+
+pub const PAGE_SIZE: usize = MEM_PAGE_SIZE as usize;
+
+// Protection of the pages which were never changed
+const PROT_ALL: u8 = (MEM_PROT_READ | MEM_PROT_WRITE) as u8;
+
+pub struct PageProt
+{
+    // Protection flags of each page of the heap
+    pages: Vec<u8>,
+}
+
+impl PageProt
+{
+    pub fn new(heap_size: usize) -> Self
+    {
+        let mut page_prot = Self { pages: Vec::default() };
+        page_prot.resize(heap_size);
+        page_prot
+    }
+
+    /// Follow a change of the heap size, new pages are accessible
+    pub fn resize(&mut self, heap_size: usize)
+    {
+        self.pages.resize(heap_size.div_ceil(PAGE_SIZE), PROT_ALL);
+    }
+
+    /// Set the protection of a range of pages. Returns false if the
+    /// range doesn't start on a page boundary or is outside the heap.
+    pub fn protect(&mut self, addr: usize, num_bytes: usize, prot: u8) -> bool
+    {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return false;
+        }
+
+        let first = addr / PAGE_SIZE;
+        let end = match addr.checked_add(num_bytes) {
+            Some(end) => end.div_ceil(PAGE_SIZE),
+            None => return false,
+        };
+
+        if end > self.pages.len() {
+            return false;
+        }
+
+        self.pages[first..end].fill(prot & PROT_ALL);
+        true
+    }
+
+    /// Get the protection of the page containing an address
+    pub fn query(&self, addr: usize) -> Option<u8>
+    {
+        self.pages.get(addr / PAGE_SIZE).copied()
+    }
+
+    /// Check if an access of num_bytes is allowed. The access must be
+    /// inside the heap. Only unaligned accesses can span two pages.
+    #[inline(always)]
+    pub fn allows(&self, addr: usize, num_bytes: usize, is_write: bool) -> bool
+    {
+        let flag = if is_write { MEM_PROT_WRITE } else { MEM_PROT_READ } as u8;
+        let first = self.pages[addr / PAGE_SIZE];
+        let last = self.pages[(addr + num_bytes - 1) / PAGE_SIZE];
+        first & last & flag != 0
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_protect()
+    {
+        let mut page_prot = PageProt::new(4 * PAGE_SIZE);
+        assert!(page_prot.protect(PAGE_SIZE, 1, MEM_PROT_READ as u8));
+        assert_eq!(page_prot.query(PAGE_SIZE + 100), Some(MEM_PROT_READ as u8));
+        assert_eq!(page_prot.query(2 * PAGE_SIZE), Some(PROT_ALL));
+
+        assert!(page_prot.allows(PAGE_SIZE, 8, false));
+        assert!(!page_prot.allows(PAGE_SIZE, 8, true));
+
+        // An access spanning two pages needs both to allow it
+        assert!(!page_prot.allows(PAGE_SIZE - 4, 8, true));
+        assert!(page_prot.allows(PAGE_SIZE - 4, 4, true));
+
+        // Unaligned and out-of-bounds ranges
+        assert!(!page_prot.protect(100, PAGE_SIZE, 0));
+        assert!(!page_prot.protect(3 * PAGE_SIZE, PAGE_SIZE + 1, 0));
+        assert!(!page_prot.protect(PAGE_SIZE, usize::MAX, 0));
+        assert_eq!(page_prot.query(4 * PAGE_SIZE), None);
+
+        page_prot.resize(8 * PAGE_SIZE);
+        assert_eq!(page_prot.query(7 * PAGE_SIZE), Some(PROT_ALL));
+    }
+}
@@ -0,0 +1,196 @@
+//! Program signing
+//!
+//! A program is signed with `uvm sign`, which writes a detached signature
+//! file next to it, named after the program with a `.sig` extension added.
+//! The signature file records the public key of the signer, so `uvm verify`
+//! can check that the program wasn't modified since it was signed, and
+//! `--require-signed` can check that it was signed by a trusted key.
+//!
+//! Keys are stored as hexadecimal text. The secret key file holds the
+//! 32-byte Ed25519 seed, and the public key file the 32-byte public key.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use crate::ed25519;
+
+/// First line of signature files
+const SIG_HEADER: &str = "uvm signature v1";
+
+/// Prefix of the signed message, so that signatures
+/// of programs can't be mistaken for anything else
+const SIG_CONTEXT: &[u8] = b"uvm program signature\0";
+
+pub type PublicKey = [u8; 32];
+
+pub fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]>
+{
+    let s = s.trim();
+    if s.len() != 2 * N || !s.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn read_key<const N: usize>(path: &str) -> Result<[u8; N], String>
+{
+    let text = fs::read_to_string(path).map_err(|e| format!("could not read key file \"{}\": {}", path, e))?;
+    from_hex(&text).ok_or(format!("invalid key file \"{}\"", path))
+}
+
+pub fn read_public_key(path: &str) -> Result<PublicKey, String>
+{
+    read_key(path)
+}
+
+/// Path of the signature file for a program
+pub fn sig_path(program: &str) -> PathBuf
+{
+    PathBuf::from(format!("{}.sig", program))
+}
+
+fn read_program(program: &str) -> Result<Vec<u8>, String>
+{
+    fs::read(program).map_err(|e| format!("could not read \"{}\": {}", program, e))
+}
+
+fn signed_message(contents: &[u8]) -> Vec<u8>
+{
+    [SIG_CONTEXT, contents].concat()
+}
+
+/// Generate a new key pair, written to <name>.key and <name>.pub
+pub fn generate_key(name: &str) -> Result<PublicKey, String>
+{
+    let mut secret = [0u8; 32];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut secret))
+        .map_err(|e| format!("could not generate a random key: {}", e))?;
+    let public = ed25519::public_key(&secret);
+
+    let key_path = format!("{}.key", name);
+    write_secret(Path::new(&key_path), &format!("{}\n", to_hex(&secret)))
+        .map_err(|e| format!("could not write \"{}\": {}", key_path, e))?;
+
+    let pub_path = format!("{}.pub", name);
+    fs::write(&pub_path, format!("{}\n", to_hex(&public)))
+        .map_err(|e| format!("could not write \"{}\": {}", pub_path, e))?;
+
+    Ok(public)
+}
+
+/// Write a file that only the current user can read
+fn write_secret(path: &Path, contents: &str) -> std::io::Result<()>
+{
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Sign a program with a secret key, writing its signature file
+pub fn sign_file(key_path: &str, program: &str) -> Result<PublicKey, String>
+{
+    let secret = read_key(key_path)?;
+    let public = ed25519::public_key(&secret);
+    let sig = ed25519::sign(&secret, &signed_message(&read_program(program)?));
+
+    let path = sig_path(program);
+    let text = format!("{}\nkey {}\nsig {}\n", SIG_HEADER, to_hex(&public), to_hex(&sig));
+    fs::write(&path, text).map_err(|e| format!("could not write \"{}\": {}", path.display(), e))?;
+
+    Ok(public)
+}
+
+/// Check the signature of a program, returning the key it was signed with
+/// If the list of trusted keys isn't empty, the key must be one of them
+pub fn verify_file(program: &str, trusted_keys: &[PublicKey]) -> Result<PublicKey, String>
+{
+    verify_bytes(program, &read_program(program)?, trusted_keys)
+}
+
+/// Check the signature of a program which was already read. The caller
+/// then runs these same bytes, so that the file can't be swapped for
+/// another one after it was checked.
+pub fn verify_bytes(program: &str, contents: &[u8], trusted_keys: &[PublicKey]) -> Result<PublicKey, String>
+{
+    let path = sig_path(program);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => return Err(format!("\"{}\" is not signed, no \"{}\" file", program, path.display())),
+    };
+
+    let mut lines = text.lines();
+    let mut field = |name: &str| lines.next().and_then(|line| line.strip_prefix(name)).map(|s| s.to_string());
+    let header = field("");
+    let public = field("key ").and_then(|s| from_hex::<32>(&s));
+    let sig = field("sig ").and_then(|s| from_hex::<64>(&s));
+
+    let (public, sig) = match (header.as_deref(), public, sig) {
+        (Some(SIG_HEADER), Some(public), Some(sig)) => (public, sig),
+        _ => return Err(format!("invalid signature file \"{}\"", path.display())),
+    };
+
+    if !ed25519::verify(&public, &signed_message(contents), &sig) {
+        return Err(format!("invalid signature for \"{}\"", program));
+    }
+
+    if !trusted_keys.is_empty() && !trusted_keys.contains(&public) {
+        return Err(format!("\"{}\" is signed by untrusted key {}", program, to_hex(&public)));
+    }
+
+    Ok(public)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_sign_verify()
+    {
+        let dir = std::env::temp_dir().join(format!("uvm_signing_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("test").to_str().unwrap().to_string();
+        let program = dir.join("prog.asm").to_str().unwrap().to_string();
+        fs::write(&program, "push 0; exit;\n").unwrap();
+
+        let public = generate_key(&name).unwrap();
+        assert_eq!(read_public_key(&format!("{}.pub", name)).unwrap(), public);
+        assert!(generate_key(&name).is_err());
+
+        assert!(verify_file(&program, &[]).unwrap_err().contains("not signed"));
+        assert_eq!(sign_file(&format!("{}.key", name), &program).unwrap(), public);
+        assert_eq!(verify_file(&program, &[]).unwrap(), public);
+        assert_eq!(verify_file(&program, &[[0; 32], public]).unwrap(), public);
+        assert!(verify_file(&program, &[[0; 32]]).unwrap_err().contains("untrusted"));
+
+        // Modifying the program invalidates the signature
+        fs::write(&program, "push 1; exit;\n").unwrap();
+        assert!(verify_file(&program, &[]).unwrap_err().contains("invalid signature"));
+
+        // The bytes which are checked are the ones given, not the file contents
+        assert_eq!(verify_bytes(&program, b"push 0; exit;\n", &[public]).unwrap(), public);
+        assert!(verify_bytes(&program, b"push 1; exit;\n", &[public]).unwrap_err().contains("invalid signature"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
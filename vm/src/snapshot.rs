@@ -0,0 +1,222 @@
+use crate::sys::compress::{deflate, inflate};
+use crate::utils::ByteReader;
+
+// Snapshots of the execution state of a VM, so that long-running programs
+// can be checkpointed and resumed after the host restarts. A snapshot holds
+// the heap, the value stack, the call frames and the position where execution
+// was suspended. The code space isn't included, the program is loaded again
+// and its checksum must match. Host resources such as sockets, windows and
+// pending timer callbacks aren't part of the snapshot.
+//
+// All fields are stored in little-endian order:
+//   magic "UVMSNAP\0", format version (u32), CRC-32 of the code (u32)
+//   suspended flag (u8), pc (u64), bp (u64)
+//   heap size (u64), compressed size (u64), DEFLATE-compressed heap
+//   stack size (u64), stack values (u64 each)
+//   frame count (u64), for each frame prev_bp, ret_addr, fn_pc, argc and
+//   num_locals + 1, or 0 if the frame doesn't declare locals (u64 each)
+
+const MAGIC: &[u8; 8] = b"UVMSNAP\0";
+const VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameData
+{
+    pub prev_bp: usize,
+    pub ret_addr: usize,
+    pub fn_pc: usize,
+    pub argc: usize,
+    pub num_locals: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot
+{
+    pub code_checksum: u32,
+
+    /// Where to continue execution (pc, bp), if it was suspended
+    pub suspended_at: Option<(usize, usize)>,
+
+    pub heap: Vec<u8>,
+    pub stack: Vec<u64>,
+    pub frames: Vec<FrameData>,
+}
+
+impl Snapshot
+{
+    pub fn encode(&self) -> Vec<u8>
+    {
+        fn push_u64(out: &mut Vec<u8>, val: usize)
+        {
+            out.extend_from_slice(&(val as u64).to_le_bytes());
+        }
+
+        let mut out = Vec::new();
+
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.code_checksum.to_le_bytes());
+
+        let (pc, bp) = self.suspended_at.unwrap_or((0, 0));
+        out.push(self.suspended_at.is_some() as u8);
+        push_u64(&mut out, pc);
+        push_u64(&mut out, bp);
+
+        // The heap is mostly zeros, so it compresses well
+        let heap = deflate(&self.heap);
+        push_u64(&mut out, self.heap.len());
+        push_u64(&mut out, heap.len());
+        out.extend_from_slice(&heap);
+
+        push_u64(&mut out, self.stack.len());
+        for val in &self.stack {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+
+        push_u64(&mut out, self.frames.len());
+        for frame in &self.frames {
+            push_u64(&mut out, frame.prev_bp);
+            push_u64(&mut out, frame.ret_addr);
+            push_u64(&mut out, frame.fn_pc);
+            push_u64(&mut out, frame.argc);
+            push_u64(&mut out, frame.num_locals.map(|n| n + 1).unwrap_or(0));
+        }
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Snapshot, String>
+    {
+        let mut r = ByteReader::new(data);
+
+        if r.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err("not a VM snapshot".to_string());
+        }
+
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+
+        let code_checksum = r.u32()?;
+
+        let suspended = r.u8()? != 0;
+        let pc = r.usize()?;
+        let bp = r.usize()?;
+
+        let heap_size = r.usize()?;
+        let compressed_size = r.count(1)?;
+        let heap = inflate(r.bytes(compressed_size)?, heap_size)
+            .map_err(|_| "corrupted heap in snapshot".to_string())?;
+        if heap.len() != heap_size {
+            return Err("corrupted heap in snapshot".to_string());
+        }
+
+        let stack_size = r.count(8)?;
+        let mut stack = Vec::with_capacity(stack_size);
+        for _ in 0..stack_size {
+            stack.push(r.u64()?);
+        }
+
+        let num_frames = r.count(5 * 8)?;
+        let mut frames = Vec::with_capacity(num_frames);
+        for _ in 0..num_frames {
+            frames.push(FrameData {
+                prev_bp: r.usize()?,
+                ret_addr: r.usize()?,
+                fn_pc: r.usize()?,
+                argc: r.usize()?,
+                num_locals: r.usize()?.checked_sub(1),
+            });
+        }
+
+        if !r.at_end() {
+            return Err("trailing data after snapshot".to_string());
+        }
+
+        Ok(Snapshot {
+            code_checksum,
+            suspended_at: if suspended { Some((pc, bp)) } else { None },
+            heap,
+            stack,
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::vm::{ExitReason, VM};
+
+    fn parse(src: &str) -> VM
+    {
+        Assembler::new().parse_str(src).unwrap()
+    }
+
+    #[test]
+    fn test_encode_decode()
+    {
+        let snapshot = Snapshot {
+            code_checksum: 0xDEADBEEF,
+            suspended_at: Some((12, 3)),
+            heap: vec![0, 1, 2, 0, 0, 0, 0, 7],
+            stack: vec![1, u64::MAX, 3],
+            frames: vec![
+                FrameData { prev_bp: usize::MAX, ret_addr: usize::MAX, fn_pc: 0, argc: 0, num_locals: None },
+                FrameData { prev_bp: 0, ret_addr: 5, fn_pc: 10, argc: 2, num_locals: Some(0) },
+            ],
+        };
+
+        let data = snapshot.encode();
+        assert_eq!(Snapshot::decode(&data), Ok(snapshot));
+
+        // Corrupted and truncated snapshots are rejected
+        for len in 0..data.len() {
+            assert!(Snapshot::decode(&data[..len]).is_err());
+        }
+        assert!(Snapshot::decode(b"UVMSNAP\0\x09\0\0\0").is_err());
+    }
+
+    #[test]
+    fn test_save_load()
+    {
+        // Fill the heap while being suspended, in a function
+        // call so that there is more than one frame to restore
+        let src = "
+            .data;
+            BUF: .zero 100;
+            .code;
+            push 0;
+            LOOP:
+            dup; push 100; eq_u64; jnz DONE;
+            dup; call STORE, 1; pop;
+            push 1; add_u64;
+            jmp LOOP;
+            DONE: pop;
+            push BUF; push 99; add_u64; load_u8; exit;
+            STORE: get_arg 0; push BUF; add_u64; get_arg 0; store_u8; push 0; ret;
+        ";
+
+        let mut vm = parse(src);
+        vm.set_insn_budget(Some(333));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Suspended));
+        let data = vm.save_snapshot();
+
+        // Resuming in another VM gives the same result
+        let mut vm2 = parse(src);
+        vm2.load_snapshot(&data).unwrap();
+        assert!(vm2.is_suspended());
+        assert!(matches!(vm2.resume(), ExitReason::Exit(val) if val.as_u64() == 99));
+
+        vm.set_insn_budget(None);
+        assert!(matches!(vm.resume(), ExitReason::Exit(val) if val.as_u64() == 99));
+
+        // The snapshot must match the program
+        let mut vm3 = parse("push 0; exit;");
+        assert!(vm3.load_snapshot(&data).is_err());
+        assert!(!vm3.is_suspended());
+    }
+}
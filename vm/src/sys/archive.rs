@@ -0,0 +1,339 @@
+// Zip archives mounted as read-only directories of the virtual filesystem
+//
+// The whole archive is loaded in memory when it is mounted, and files are
+// decompressed when they are opened. Only the stored and DEFLATE methods
+// are supported, which are the ones zip tools use by default. Encrypted
+// archives and zip64 archives, for files over 4GiB, aren't supported.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, ErrorKind};
+use crate::sys::compress::inflate;
+use crate::sys::crypto::crc32;
+use crate::sys::vfs::{FileSystem, FileStat, VfsFile, OpenMode, normalize_path, child_names};
+use crate::utils::ByteReader;
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const END_RECORD_SIG: u32 = 0x06054b50;
+
+// Size of the end of central directory record, without its comment
+const END_RECORD_SIZE: usize = 22;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// File stored in an archive
+struct Entry
+{
+    method: u16,
+    crc: u32,
+
+    /// Position and size of the compressed data in the archive
+    data_pos: usize,
+    comp_size: usize,
+
+    /// Size of the file once decompressed
+    size: usize,
+}
+
+pub struct ZipArchive
+{
+    data: Vec<u8>,
+
+    /// Files by path, relative to the root of the archive
+    files: HashMap<String, Entry>,
+
+    /// Directories, including the ones implied by the paths of the files
+    dirs: HashSet<String>,
+}
+
+impl ZipArchive
+{
+    pub fn load(file_name: &str) -> Result<Self, String>
+    {
+        let data = match std::fs::read(file_name) {
+            Ok(data) => data,
+            Err(e) => return Err(format!("could not read \"{}\": {}", file_name, e)),
+        };
+
+        Self::parse(data).map_err(|e| format!("invalid archive \"{}\": {}", file_name, e))
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self, String>
+    {
+        if data.len() < END_RECORD_SIZE {
+            return Err("unexpected end of data".to_string());
+        }
+
+        // The end record is last, except for a comment of up to 64KiB
+        let last_pos = data.len() - END_RECORD_SIZE;
+        let end_pos = (last_pos.saturating_sub(0xFFFF)..=last_pos).rev()
+            .find(|&pos| data[pos..pos + 4] == END_RECORD_SIG.to_le_bytes())
+            .ok_or("end of central directory not found")?;
+
+        // Skip the signature and the disk numbers, archives
+        // split on multiple disks aren't supported
+        let mut end_record = ByteReader::new(&data[end_pos + 8..]);
+        let num_entries = end_record.u16()?;
+        if end_record.u16()? != num_entries {
+            return Err("multi-disk archives are not supported".to_string());
+        }
+        end_record.u32()?;
+        let dir_pos = end_record.u32()? as usize;
+
+        let mut files = HashMap::new();
+        let mut dirs = HashSet::from([String::new()]);
+        let mut reader = ByteReader::new(data.get(dir_pos..).ok_or("invalid central directory position")?);
+
+        for _ in 0..num_entries {
+            if reader.u32()? != CENTRAL_HEADER_SIG {
+                return Err("invalid central directory entry".to_string());
+            }
+
+            // Skip the versions
+            reader.u32()?;
+            let flags = reader.u16()?;
+            let method = reader.u16()?;
+            // Skip the modification time
+            reader.u32()?;
+            let crc = reader.u32()?;
+            let comp_size = reader.u32()?;
+            let size = reader.u32()?;
+            let name_len = reader.u16()? as usize;
+            let extra_len = reader.u16()? as usize;
+            let comment_len = reader.u16()? as usize;
+            // Skip the disk number and file attributes
+            reader.bytes(8)?;
+            let header_pos = reader.u32()?;
+            let name = reader.bytes(name_len)?;
+            reader.bytes(extra_len + comment_len)?;
+
+            let name = std::str::from_utf8(name).map_err(|_| "file name is not valid UTF-8")?;
+            let parts = match normalize_path(name) {
+                Some(parts) if !parts.is_empty() => parts,
+                _ => return Err(format!("invalid file name \"{}\"", name)),
+            };
+
+            for num_parts in 1..parts.len() {
+                dirs.insert(parts[..num_parts].join("/"));
+            }
+
+            if name.ends_with('/') {
+                dirs.insert(parts.join("/"));
+                continue;
+            }
+
+            if flags & 1 != 0 {
+                return Err(format!("\"{}\" is encrypted", name));
+            }
+            if method != METHOD_STORED && method != METHOD_DEFLATE {
+                return Err(format!("\"{}\" uses unsupported compression method {}", name, method));
+            }
+            if [comp_size, size, header_pos].contains(&u32::MAX) {
+                return Err("zip64 archives are not supported".to_string());
+            }
+
+            // The data follows the local header, whose extra field
+            // can differ from the one in the central directory
+            let mut header = ByteReader::new(data.get(header_pos as usize..).ok_or("invalid file header position")?);
+            if header.u32()? != LOCAL_HEADER_SIG {
+                return Err(format!("invalid file header for \"{}\"", name));
+            }
+            header.bytes(22)?;
+            let local_name_len = header.u16()? as usize;
+            let local_extra_len = header.u16()? as usize;
+            let data_pos = header_pos as usize + 30 + local_name_len + local_extra_len;
+
+            if data_pos + comp_size as usize > data.len() {
+                return Err(format!("data of \"{}\" is past the end of the archive", name));
+            }
+
+            let entry = Entry {
+                method,
+                crc,
+                data_pos,
+                comp_size: comp_size as usize,
+                size: size as usize,
+            };
+            files.insert(parts.join("/"), entry);
+        }
+
+        Ok(Self { data, files, dirs })
+    }
+}
+
+impl FileSystem for ZipArchive
+{
+    fn open(&mut self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>
+    {
+        if mode.write || mode.create || mode.truncate || mode.append {
+            return Err(io::Error::from(ErrorKind::ReadOnlyFilesystem));
+        }
+
+        if self.dirs.contains(path) {
+            return Err(io::Error::from(ErrorKind::IsADirectory));
+        }
+
+        let entry = self.files.get(path).ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        let data = &self.data[entry.data_pos..entry.data_pos + entry.comp_size];
+
+        let contents = match entry.method {
+            METHOD_STORED => data.to_vec(),
+            _ => inflate(data, entry.size).unwrap_or_default(),
+        };
+
+        if contents.len() != entry.size || crc32(0, &contents) != entry.crc {
+            return Err(io::Error::from(ErrorKind::InvalidData));
+        }
+
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>>
+    {
+        if !self.dirs.contains(path) {
+            return match self.files.contains_key(path) {
+                true => Err(io::Error::from(ErrorKind::NotADirectory)),
+                false => Err(io::Error::from(ErrorKind::NotFound)),
+            };
+        }
+
+        Ok(child_names(path, self.files.keys().chain(self.dirs.iter())))
+    }
+
+    fn stat(&mut self, path: &str) -> io::Result<FileStat>
+    {
+        match self.files.get(path) {
+            Some(entry) => Ok(FileStat { is_dir: false, size: entry.size as u64 }),
+            None if self.dirs.contains(path) => Ok(FileStat { is_dir: true, size: 0 }),
+            None => Err(io::Error::from(ErrorKind::NotFound)),
+        }
+    }
+
+    fn mkdir(&mut self, _path: &str) -> io::Result<()>
+    {
+        Err(io::Error::from(ErrorKind::ReadOnlyFilesystem))
+    }
+
+    fn remove(&mut self, _path: &str) -> io::Result<()>
+    {
+        Err(io::Error::from(ErrorKind::ReadOnlyFilesystem))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Read;
+    use crate::sys::compress::deflate;
+
+    // Build a zip archive from (name, contents, compress) triples
+    fn build_zip(files: &[(&str, &[u8], bool)]) -> Vec<u8>
+    {
+        let mut out = Vec::new();
+        let mut dir = Vec::new();
+
+        for (name, contents, compress) in files {
+            let (method, data) = if *compress { (METHOD_DEFLATE, deflate(contents)) } else { (METHOD_STORED, contents.to_vec()) };
+            let header_pos = out.len() as u32;
+
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc32(0, contents).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+
+            out.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&fields);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&data);
+
+            dir.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+            dir.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            dir.extend_from_slice(&fields);
+            dir.extend_from_slice(&[0; 10]);
+            dir.extend_from_slice(&header_pos.to_le_bytes());
+            dir.extend_from_slice(name.as_bytes());
+        }
+
+        let dir_pos = out.len() as u32;
+        out.extend_from_slice(&dir);
+        out.extend_from_slice(&END_RECORD_SIG.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(dir.len() as u32).to_le_bytes());
+        out.extend_from_slice(&dir_pos.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out
+    }
+
+    fn read_all(archive: &mut ZipArchive, path: &str) -> io::Result<Vec<u8>>
+    {
+        let mut contents = Vec::new();
+        archive.open(path, OpenMode { read: true, ..Default::default() })?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn test_zip_archive()
+    {
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(20);
+        let data = build_zip(&[
+            ("a.txt", b"stored", false),
+            ("img/", b"", false),
+            ("img/./b.txt", &text, true),
+            ("img/sub/c.txt", b"", true),
+        ]);
+        let mut archive = ZipArchive::parse(data).unwrap();
+
+        assert_eq!(read_all(&mut archive, "a.txt").unwrap(), b"stored");
+        assert_eq!(read_all(&mut archive, "img/b.txt").unwrap(), text);
+        assert_eq!(read_all(&mut archive, "img/sub/c.txt").unwrap(), b"");
+
+        let kind = |result: io::Result<Vec<u8>>| result.err().unwrap().kind();
+        assert_eq!(kind(read_all(&mut archive, "b.txt")), ErrorKind::NotFound);
+        assert_eq!(kind(read_all(&mut archive, "")), ErrorKind::IsADirectory);
+        assert_eq!(kind(read_all(&mut archive, "img/sub")), ErrorKind::IsADirectory);
+
+        let write = OpenMode { write: true, create: true, ..Default::default() };
+        assert_eq!(archive.open("new.txt", write).err().unwrap().kind(), ErrorKind::ReadOnlyFilesystem);
+
+        let mut names = archive.list_dir("").unwrap();
+        names.sort();
+        assert_eq!(names, ["a.txt", "img"]);
+        let mut names = archive.list_dir("img").unwrap();
+        names.sort();
+        assert_eq!(names, ["b.txt", "sub"]);
+        assert_eq!(archive.list_dir("a.txt").err().unwrap().kind(), ErrorKind::NotADirectory);
+
+        assert_eq!(archive.stat("img/b.txt").unwrap(), FileStat { is_dir: false, size: text.len() as u64 });
+        assert!(archive.stat("img/sub").unwrap().is_dir);
+        assert_eq!(archive.stat("c.txt").err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(archive.remove("a.txt").err().unwrap().kind(), ErrorKind::ReadOnlyFilesystem);
+    }
+
+    #[test]
+    fn test_zip_errors()
+    {
+        assert!(ZipArchive::parse(vec![]).is_err());
+        assert!(ZipArchive::parse(vec![0; 100]).is_err());
+        assert!(ZipArchive::parse(build_zip(&[("../a.txt", b"", false)])).is_err());
+
+        // Truncated archive
+        let data = build_zip(&[("a.txt", b"contents", false)]);
+        let end_record = data[data.len() - END_RECORD_SIZE..].to_vec();
+        assert!(ZipArchive::parse([&data[..20], &end_record].concat()).is_err());
+
+        // Corrupted data is detected with the checksum
+        let mut data = build_zip(&[("a.txt", b"contents", false)]);
+        data[35] ^= 1;
+        let mut archive = ZipArchive::parse(data).unwrap();
+        assert_eq!(read_all(&mut archive, "a.txt").err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+}
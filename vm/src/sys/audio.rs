@@ -1,7 +1,9 @@
 use sdl2::audio::{AudioCallback, AudioSpecDesired, AudioDevice};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::sync::{Arc, Weak, Mutex};
 use crate::vm::{Value, VM, ExitReason};
-use crate::sys::{get_sdl_context};
+use crate::sys::{get_sdl_context, stop_requested, sys_err};
 use crate::sys::constants::*;
 
 #[derive(Clone)]
@@ -31,24 +33,35 @@ impl AudioCallback for AudioCB
         assert!(output_len % self.num_channels == 0);
         let samples_per_chan = output_len / self.num_channels;
 
+        // Output silence while the VM is shutting down
+        if stop_requested() {
+            return;
+        }
+
         let arc = self.vm.upgrade().unwrap();
         let mut vm = arc.lock().unwrap();
 
-        match vm.call(self.cb, &[Value::from(self.num_channels), Value::from(samples_per_chan)]) {
+        match vm.call_sync(self.cb, &[Value::from(self.num_channels), Value::from(samples_per_chan)]) {
             ExitReason::Return(ptr) => {
-                let mem_slice: &[i16] = vm.get_heap_slice(ptr.as_usize(), output_len);
-                out.copy_from_slice(&mem_slice);
+                let mem = vm.mem_view();
+                let mem_slice: &[i16] = mem.slice(ptr.as_usize(), output_len);
+                out.copy_from_slice(mem_slice);
             }
             _ => panic!()
         }
     }
 }
 
-// TODO: support multiple audio devices
-/// We have to keep the audio device alive
-/// This is a global variable because it doesn't implement
-/// the Send trait, and so can't be referenced from another thread
-static mut DEVICE: Option<AudioDevice<AudioCB>> = None;
+/// Entry of an audio device in the handle table
+/// The device itself is kept in DEVICES
+struct AudioHandle;
+
+thread_local! {
+    /// We have to keep the audio devices alive, indexed by handle
+    /// This is thread-local because audio devices don't implement
+    /// the Send trait, and so can't be referenced from another thread
+    static DEVICES: RefCell<HashMap<u64, AudioDevice<AudioCB>>> = RefCell::new(HashMap::default());
+}
 
 // NOTE: this can only be called from the main thread since it uses SDL
 // However, it creates a new thread to generate audio sample, this thread
@@ -61,20 +74,29 @@ pub fn audio_open_output(vm: &mut VM, sample_rate: Value, num_channels: Value, f
     let cb = cb.as_u64();
 
     if sample_rate != 44100 {
-        panic!("for now, only 44100Hz sample rate suppored");
+        eprintln!("audio_open_output: for now, only 44100Hz sample rate supported");
+        return sys_err(EINVAL);
     }
 
     //if num_channels > 2 {
     if num_channels != 1 {
-        panic!("for now, only one output channel supported");
+        eprintln!("audio_open_output: for now, only one output channel supported");
+        return sys_err(EINVAL);
     }
 
     if format != AUDIO_FORMAT_I16 {
-        panic!("for now, only i16, 16-bit signed audio format supported");
+        eprintln!("audio_open_output: for now, only i16, 16-bit signed audio format supported");
+        return sys_err(EINVAL);
     }
 
     let sdl = get_sdl_context();
-    let audio_subsystem = sdl.audio().unwrap();
+    let audio_subsystem = match sdl.audio() {
+        Ok(audio_subsystem) => audio_subsystem,
+        Err(msg) => {
+            eprintln!("audio_open_output: could not initialize audio: {}", msg);
+            return sys_err(EIO);
+        }
+    };
 
     let desired_spec = AudioSpecDesired {
         freq: Some(sample_rate as i32),
@@ -89,16 +111,232 @@ pub fn audio_open_output(vm: &mut VM, sample_rate: Value, num_channels: Value, f
             cb: cb,
             num_channels: num_channels.into()
         }
-    }).unwrap();
+    });
+
+    let device = match device {
+        Ok(device) => device,
+        Err(msg) => {
+            eprintln!("audio_open_output: could not open device: {}", msg);
+            return sys_err(EIO);
+        }
+    };
 
     // Start playback
     device.resume();
 
     // Keep the audio device alive
-    unsafe {
-        DEVICE = Some(device);
+    let handle = vm.sys_state.handles.insert(AudioHandle);
+    DEVICES.with_borrow_mut(|devices| devices.insert(handle, device));
+
+    Value::from(handle)
+}
+
+/// Mix a buffer of samples into another, with a gain factor and saturation
+pub fn audio_mix(vm: &mut VM, dst_ptr: Value, src_ptr: Value, num_samples: Value, gain: Value)
+{
+    let num_samples = num_samples.as_usize();
+    let gain = gain.as_f32();
+
+    let mut mem = vm.mem_view();
+    let src: Vec<i16> = mem.slice::<i16>(src_ptr.as_usize(), num_samples).to_vec();
+    let dst: &mut [i16] = mem.slice_mut(dst_ptr.as_usize(), num_samples);
+
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        // Float to int casts saturate in Rust
+        let mixed = (*d as f32) + (*s as f32) * gain;
+        *d = mixed as i16;
+    }
+}
+
+/// Resample a buffer of samples to a different length using linear interpolation
+pub fn audio_resample(vm: &mut VM, dst_ptr: Value, dst_len: Value, src_ptr: Value, src_len: Value)
+{
+    let dst_len = dst_len.as_usize();
+    let src_len = src_len.as_usize();
+
+    let mut mem = vm.mem_view();
+    let src: Vec<i16> = mem.slice::<i16>(src_ptr.as_usize(), src_len).to_vec();
+    let dst: &mut [i16] = mem.slice_mut(dst_ptr.as_usize(), dst_len);
+
+    if src_len == 0 {
+        dst.fill(0);
+        return;
+    }
+
+    // Map the first and last output samples onto the first and last input samples
+    let step = if dst_len > 1 {
+        (src_len - 1) as f64 / (dst_len - 1) as f64
+    } else {
+        0.0
+    };
+
+    for (idx, d) in dst.iter_mut().enumerate() {
+        let pos = idx as f64 * step;
+        let i0 = pos as usize;
+        let i1 = std::cmp::min(i0 + 1, src_len - 1);
+        let t = pos - i0 as f64;
+
+        let s0 = src[i0] as f64;
+        let s1 = src[i1] as f64;
+        *d = (s0 + (s1 - s0) * t).round() as i16;
+    }
+}
+
+/// Value of a waveform at a phase in [0, 1), in [-1, 1]
+fn wave_value(waveform: u16, phase: f64) -> f64
+{
+    match waveform {
+        AUDIO_WAVE_SINE => (2.0 * std::f64::consts::PI * phase).sin(),
+        AUDIO_WAVE_SQUARE => if phase < 0.5 { 1.0 } else { -1.0 },
+        AUDIO_WAVE_SAW => 2.0 * phase - 1.0,
+        AUDIO_WAVE_TRIANGLE => 1.0 - 4.0 * (phase - 0.5).abs(),
+        _ => unreachable!(),
+    }
+}
+
+/// Fill a buffer of samples with a waveform, described by the C struct
+/// osc_t { u32 sample_rate; float freq; float amplitude; float phase; }
+/// The phase is updated so that the next buffer continues the wave
+pub fn audio_osc(vm: &mut VM, dst_ptr: Value, num_samples: Value, waveform: Value, osc_ptr: Value) -> Value
+{
+    let num_samples = num_samples.as_usize();
+    let waveform = waveform.as_u16();
+    let osc_ptr = osc_ptr.as_usize();
+
+    let mut mem = vm.mem_view();
+    let sample_rate = mem.read::<u32>(osc_ptr);
+    let freq = mem.read::<f32>(osc_ptr + 4) as f64;
+    let amplitude = mem.read::<f32>(osc_ptr + 8) as f64;
+    let mut phase = mem.read::<f32>(osc_ptr + 12) as f64;
+
+    if waveform > AUDIO_WAVE_TRIANGLE || sample_rate == 0 {
+        return sys_err(EINVAL);
+    }
+
+    let step = freq / sample_rate as f64;
+    let dst: &mut [i16] = mem.slice_mut(dst_ptr.as_usize(), num_samples);
+
+    for d in dst.iter_mut() {
+        // Float to int casts saturate in Rust
+        *d = (wave_value(waveform, phase) * amplitude * i16::MAX as f64) as i16;
+        phase = (phase + step).rem_euclid(1.0);
     }
 
-    // TODO: return the device_id (u32)
+    mem.write(osc_ptr + 12, phase as f32);
     Value::from(0)
 }
+
+/// Level of an ADSR envelope, in [0, 1], a given number of samples after the note started
+fn envelope_level(attack: u32, decay: u32, sustain: f64, release: u32, pos: u64, release_pos: u64) -> f64
+{
+    let held_level = |pos: u64| {
+        let (attack, decay) = (attack as u64, decay as u64);
+
+        if pos < attack {
+            pos as f64 / attack as f64
+        } else if pos < attack + decay {
+            1.0 - (1.0 - sustain) * (pos - attack) as f64 / decay as f64
+        } else {
+            sustain
+        }
+    };
+
+    if pos < release_pos {
+        return held_level(pos);
+    }
+
+    // The release starts from the level the note was at when it was released
+    let since_release = pos - release_pos;
+    match since_release < release as u64 {
+        true => held_level(release_pos) * (1.0 - since_release as f64 / release as f64),
+        false => 0.0,
+    }
+}
+
+/// Apply an ADSR envelope to a buffer of samples, described by the C struct
+/// envelope_t { u32 attack; u32 decay; float sustain; u32 release; u64 pos; u64 release_pos; }
+/// Durations and positions are in samples, and the position is updated
+pub fn audio_envelope(vm: &mut VM, buf_ptr: Value, num_samples: Value, env_ptr: Value)
+{
+    let num_samples = num_samples.as_usize();
+    let env_ptr = env_ptr.as_usize();
+
+    let mut mem = vm.mem_view();
+    let attack = mem.read::<u32>(env_ptr);
+    let decay = mem.read::<u32>(env_ptr + 4);
+    let sustain = (mem.read::<f32>(env_ptr + 8) as f64).clamp(0.0, 1.0);
+    let release = mem.read::<u32>(env_ptr + 12);
+    let mut pos = mem.read::<u64>(env_ptr + 16);
+    let release_pos = mem.read::<u64>(env_ptr + 24);
+
+    let buf: &mut [i16] = mem.slice_mut(buf_ptr.as_usize(), num_samples);
+
+    for s in buf.iter_mut() {
+        let level = envelope_level(attack, decay, sustain, release, pos, release_pos);
+        *s = (*s as f64 * level).round() as i16;
+        pos = pos.saturating_add(1);
+    }
+
+    mem.write(env_ptr + 16, pos);
+}
+
+/// Compute the coefficients of a biquad filter, described by the C struct
+/// biquad_t { float b0, b1, b2, a1, a2; float x1, x2, y1, y2; }
+/// The cutoff frequency is relative to the sample rate
+pub fn audio_biquad_init(vm: &mut VM, filter_ptr: Value, filter_type: Value, cutoff: Value, q: Value) -> Value
+{
+    let filter_ptr = filter_ptr.as_usize();
+    let filter_type = filter_type.as_u16();
+    let cutoff = cutoff.as_f32() as f64;
+    let q = q.as_f32() as f64;
+
+    if filter_type > AUDIO_FILTER_BANDPASS || !(cutoff > 0.0 && cutoff < 0.5 && q > 0.0) {
+        return sys_err(EINVAL);
+    }
+
+    // Coefficients from the Audio EQ Cookbook by Robert Bristow-Johnson
+    let w0 = 2.0 * std::f64::consts::PI * cutoff;
+    let (sin_w0, cos_w0) = w0.sin_cos();
+    let alpha = sin_w0 / (2.0 * q);
+
+    let (b0, b1, b2) = match filter_type {
+        AUDIO_FILTER_LOWPASS => ((1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0),
+        AUDIO_FILTER_HIGHPASS => ((1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0),
+        _ => (alpha, 0.0, -alpha),
+    };
+    let a0 = 1.0 + alpha;
+    let coeffs = [b0 / a0, b1 / a0, b2 / a0, -2.0 * cos_w0 / a0, (1.0 - alpha) / a0];
+
+    // The filter starts with no previous input or output
+    let mut mem = vm.mem_view();
+    let filter: &mut [f32] = mem.slice_mut(filter_ptr, 9);
+    for (f, c) in filter.iter_mut().zip(coeffs.iter().chain([0.0; 4].iter())) {
+        *f = *c as f32;
+    }
+
+    Value::from(0)
+}
+
+/// Run a biquad filter over a buffer of samples, updating the state of the filter
+pub fn audio_biquad(vm: &mut VM, buf_ptr: Value, num_samples: Value, filter_ptr: Value)
+{
+    let num_samples = num_samples.as_usize();
+    let filter_ptr = filter_ptr.as_usize();
+
+    let mut mem = vm.mem_view();
+    let f: Vec<f64> = mem.slice::<f32>(filter_ptr, 9).iter().map(|&v| v as f64).collect();
+    let (b0, b1, b2, a1, a2) = (f[0], f[1], f[2], f[3], f[4]);
+    let (mut x1, mut x2, mut y1, mut y2) = (f[5], f[6], f[7], f[8]);
+
+    let buf: &mut [i16] = mem.slice_mut(buf_ptr.as_usize(), num_samples);
+
+    for s in buf.iter_mut() {
+        let x0 = *s as f64;
+        let y0 = b0 * x0 + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+        (x2, x1, y2, y1) = (x1, x0, y1, y0);
+        *s = y0.round() as i16;
+    }
+
+    let state: &mut [f32] = mem.slice_mut(filter_ptr + 20, 4);
+    state.copy_from_slice(&[x1 as f32, x2 as f32, y1 as f32, y2 as f32]);
+}
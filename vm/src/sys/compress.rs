@@ -0,0 +1,606 @@
+use crate::vm::{VM, Value};
+use crate::sys::sys_err;
+use crate::sys::constants::*;
+
+/// Reason why data couldn't be decompressed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InflateError
+{
+    /// The input is not valid DEFLATE data
+    Invalid,
+
+    /// The output would exceed the maximum length
+    TooLong,
+}
+
+// Base lengths and extra bits for length codes 257..285
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+
+// Base distances and extra bits for distance codes 0..29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+
+// Order in which code length code lengths are stored in dynamic blocks
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15
+];
+
+const MAX_BITS: usize = 15;
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// Maximum number of hash chain entries to visit when looking for a match
+const MAX_CHAIN: usize = 128;
+
+/// Reads bits from a DEFLATE stream, least significant bit first
+struct BitReader<'a>
+{
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a>
+{
+    fn new(data: &'a [u8]) -> Self
+    {
+        Self { data, pos: 0, bit_buf: 0, bit_count: 0 }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, InflateError>
+    {
+        while self.bit_count < n {
+            if self.pos >= self.data.len() {
+                return Err(InflateError::Invalid);
+            }
+            self.bit_buf |= (self.data[self.pos] as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+
+        let val = self.bit_buf & ((1u64 << n) - 1) as u32;
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(val)
+    }
+
+    /// Discard the remaining bits of the current byte
+    fn align(&mut self)
+    {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+/// Canonical Huffman decoding table
+struct Huffman
+{
+    // Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+
+    // Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman
+{
+    fn new(lengths: &[u8]) -> Result<Self, InflateError>
+    {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Reject over-subscribed code sets
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::Invalid);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, InflateError>
+    {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+
+        for len in 1..=MAX_BITS {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::Invalid)
+    }
+}
+
+/// Code lengths of the fixed literal/length and distance codes
+fn fixed_lengths() -> ([u8; 288], [u8; 30])
+{
+    let mut lit = [0u8; 288];
+    lit[0..144].fill(8);
+    lit[144..256].fill(9);
+    lit[256..280].fill(7);
+    lit[280..288].fill(8);
+    (lit, [5u8; 30])
+}
+
+/// Decode the compressed data of a block
+fn inflate_codes(
+    input: &mut BitReader,
+    out: &mut Vec<u8>,
+    max_len: usize,
+    lit: &Huffman,
+    dist: &Huffman
+) -> Result<(), InflateError>
+{
+    loop
+    {
+        let sym = lit.decode(input)? as usize;
+
+        if sym < 256 {
+            if out.len() >= max_len {
+                return Err(InflateError::TooLong);
+            }
+            out.push(sym as u8);
+            continue;
+        }
+
+        // End of block
+        if sym == 256 {
+            return Ok(());
+        }
+
+        let sym = sym - 257;
+        if sym >= LEN_BASE.len() {
+            return Err(InflateError::Invalid);
+        }
+        let len = LEN_BASE[sym] as usize + input.bits(LEN_EXTRA[sym] as u32)? as usize;
+
+        let dsym = dist.decode(input)? as usize;
+        if dsym >= DIST_BASE.len() {
+            return Err(InflateError::Invalid);
+        }
+        let dist = DIST_BASE[dsym] as usize + input.bits(DIST_EXTRA[dsym] as u32)? as usize;
+
+        if dist > out.len() {
+            return Err(InflateError::Invalid);
+        }
+        if out.len() + len > max_len {
+            return Err(InflateError::TooLong);
+        }
+
+        // The source and destination may overlap, so copy byte by byte
+        let start = out.len() - dist;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+/// Read the code tables of a dynamic block
+fn read_dynamic_tables(input: &mut BitReader) -> Result<(Huffman, Huffman), InflateError>
+{
+    let num_lit = input.bits(5)? as usize + 257;
+    let num_dist = input.bits(5)? as usize + 1;
+    let num_clen = input.bits(4)? as usize + 4;
+
+    if num_lit > 286 || num_dist > 30 {
+        return Err(InflateError::Invalid);
+    }
+
+    let mut clen_lengths = [0u8; 19];
+    for &idx in CLEN_ORDER.iter().take(num_clen) {
+        clen_lengths[idx] = input.bits(3)? as u8;
+    }
+    let clen = Huffman::new(&clen_lengths)?;
+
+    let mut lengths = vec![0u8; num_lit + num_dist];
+    let mut idx = 0;
+
+    while idx < lengths.len() {
+        let sym = clen.decode(input)?;
+
+        let (val, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => {
+                if idx == 0 {
+                    return Err(InflateError::Invalid);
+                }
+                (lengths[idx - 1], 3 + input.bits(2)? as usize)
+            }
+            17 => (0, 3 + input.bits(3)? as usize),
+            18 => (0, 11 + input.bits(7)? as usize),
+            _ => return Err(InflateError::Invalid)
+        };
+
+        if idx + repeat > lengths.len() {
+            return Err(InflateError::Invalid);
+        }
+
+        lengths[idx..idx + repeat].fill(val);
+        idx += repeat;
+    }
+
+    // The end of block code must be present
+    if lengths[256] == 0 {
+        return Err(InflateError::Invalid);
+    }
+
+    let lit = Huffman::new(&lengths[..num_lit])?;
+    let dist = Huffman::new(&lengths[num_lit..])?;
+    Ok((lit, dist))
+}
+
+/// Decompress raw DEFLATE data, failing if the output would exceed max_len
+pub fn inflate(data: &[u8], max_len: usize) -> Result<Vec<u8>, InflateError>
+{
+    let mut input = BitReader::new(data);
+    let mut out = Vec::new();
+
+    loop
+    {
+        let last = input.bits(1)? == 1;
+
+        match input.bits(2)? {
+            // Stored block
+            0 => {
+                input.align();
+
+                if input.pos + 4 > data.len() {
+                    return Err(InflateError::Invalid);
+                }
+                let len = u16::from_le_bytes([data[input.pos], data[input.pos + 1]]) as usize;
+                let nlen = u16::from_le_bytes([data[input.pos + 2], data[input.pos + 3]]) as usize;
+                input.pos += 4;
+
+                if len != !nlen & 0xFFFF || input.pos + len > data.len() {
+                    return Err(InflateError::Invalid);
+                }
+                if out.len() + len > max_len {
+                    return Err(InflateError::TooLong);
+                }
+
+                out.extend_from_slice(&data[input.pos..input.pos + len]);
+                input.pos += len;
+            }
+
+            // Fixed Huffman codes
+            1 => {
+                let (lit, dist) = fixed_lengths();
+                let lit = Huffman::new(&lit)?;
+                let dist = Huffman::new(&dist)?;
+                inflate_codes(&mut input, &mut out, max_len, &lit, &dist)?;
+            }
+
+            // Dynamic Huffman codes
+            2 => {
+                let (lit, dist) = read_dynamic_tables(&mut input)?;
+                inflate_codes(&mut input, &mut out, max_len, &lit, &dist)?;
+            }
+
+            _ => return Err(InflateError::Invalid)
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Writes bits to a DEFLATE stream, least significant bit first
+struct BitWriter
+{
+    out: Vec<u8>,
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl BitWriter
+{
+    fn new() -> Self
+    {
+        Self { out: Vec::new(), bit_buf: 0, bit_count: 0 }
+    }
+
+    fn bits(&mut self, val: u32, n: u32)
+    {
+        self.bit_buf |= (val as u64) << self.bit_count;
+        self.bit_count += n;
+
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Huffman codes are stored starting from their most significant bit
+    fn code(&mut self, code: u32, len: u32)
+    {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8>
+    {
+        if self.bit_count > 0 {
+            self.out.push(self.bit_buf as u8);
+        }
+        self.out
+    }
+}
+
+/// Write a literal/length symbol using the fixed Huffman code
+fn write_fixed_lit(w: &mut BitWriter, sym: usize)
+{
+    let sym = sym as u32;
+    match sym {
+        0..=143 => w.code(0x30 + sym, 8),
+        144..=255 => w.code(0x190 + sym - 144, 9),
+        256..=279 => w.code(sym - 256, 7),
+        _ => w.code(0xC0 + sym - 280, 8),
+    }
+}
+
+/// Write a match as a length and distance pair
+fn write_fixed_match(w: &mut BitWriter, len: usize, dist: usize)
+{
+    let lcode = LEN_BASE.iter().rposition(|&base| base as usize <= len).unwrap();
+    write_fixed_lit(w, 257 + lcode);
+    w.bits((len - LEN_BASE[lcode] as usize) as u32, LEN_EXTRA[lcode] as u32);
+
+    let dcode = DIST_BASE.iter().rposition(|&base| base as usize <= dist).unwrap();
+    w.code(dcode as u32, 5);
+    w.bits((dist - DIST_BASE[dcode] as usize) as u32, DIST_EXTRA[dcode] as u32);
+}
+
+fn hash3(data: &[u8], pos: usize) -> usize
+{
+    let val = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+    (val.wrapping_mul(2654435761) >> 17) as usize
+}
+
+/// Compress data using LZ77 matching and the fixed Huffman code,
+/// falling back to stored blocks for incompressible data
+pub fn deflate(data: &[u8]) -> Vec<u8>
+{
+    let mut w = BitWriter::new();
+
+    // Final block with fixed Huffman codes
+    w.bits(1, 1);
+    w.bits(1, 2);
+
+    // Most recent position for each hash value, and previous
+    // position with the same hash for each position in the window
+    let mut head = vec![usize::MAX; 1 << 15];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash3(data, pos);
+            let max_len = std::cmp::min(MAX_MATCH, data.len() - pos);
+            let mut cand = head[h];
+            let mut chain = 0;
+
+            while cand != usize::MAX && pos - cand <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = data[cand..cand + max_len].iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - cand;
+                    if len == max_len {
+                        break;
+                    }
+                }
+
+                cand = prev[cand % WINDOW_SIZE];
+                chain += 1;
+            }
+        }
+
+        let step = if best_len >= MIN_MATCH {
+            write_fixed_match(&mut w, best_len, best_dist);
+            best_len
+        }
+        else
+        {
+            write_fixed_lit(&mut w, data[pos] as usize);
+            1
+        };
+
+        // Insert every position we move past into the hash chains
+        for p in pos..pos + step {
+            if p + MIN_MATCH <= data.len() {
+                let h = hash3(data, p);
+                prev[p % WINDOW_SIZE] = head[h];
+                head[h] = p;
+            }
+        }
+
+        pos += step;
+    }
+
+    // End of block
+    write_fixed_lit(&mut w, 256);
+    let out = w.finish();
+
+    // Stored blocks add 5 bytes of overhead per 64KB
+    let stored_len = data.len() + 5 * std::cmp::max(1, data.len().div_ceil(0xFFFF));
+    if out.len() <= stored_len {
+        return out;
+    }
+
+    let mut out = Vec::with_capacity(stored_len);
+    let mut chunks = data.chunks(0xFFFF).peekable();
+
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+
+    out
+}
+
+// Syscall to compress data into raw DEFLATE format
+// i64 num_bytes = compress(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+pub fn compress(vm: &mut VM, dst_ptr: Value, dst_len: Value, src_ptr: Value, src_len: Value) -> Value
+{
+    let dst_len = dst_len.as_usize();
+    let mut mem = vm.mem_view();
+    let out = deflate(mem.slice(src_ptr.as_usize(), src_len.as_usize()));
+
+    if out.len() > dst_len {
+        return sys_err(ENOSPC);
+    }
+
+    mem.write_bytes(dst_ptr.as_usize(), &out);
+    Value::from(out.len())
+}
+
+// Syscall to decompress data in raw DEFLATE format
+// i64 num_bytes = decompress(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+pub fn decompress(vm: &mut VM, dst_ptr: Value, dst_len: Value, src_ptr: Value, src_len: Value) -> Value
+{
+    let dst_len = dst_len.as_usize();
+    let mut mem = vm.mem_view();
+
+    let out = match inflate(mem.slice(src_ptr.as_usize(), src_len.as_usize()), dst_len) {
+        Ok(out) => out,
+        Err(InflateError::Invalid) => return sys_err(EINVAL),
+        Err(InflateError::TooLong) => return sys_err(ENOSPC),
+    };
+
+    mem.write_bytes(dst_ptr.as_usize(), &out);
+    Value::from(out.len())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn round_trip(data: &[u8])
+    {
+        let compressed = deflate(data);
+        assert_eq!(inflate(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_round_trip()
+    {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"hello hello hello hello world");
+        round_trip(&[0u8; 100_000]);
+
+        // Pseudo-random data is incompressible and gets stored
+        let mut x: u32 = 1;
+        let noise: Vec<u8> = (0..70_000).map(|_| { x = x.wrapping_mul(1103515245).wrapping_add(12345); (x >> 16) as u8 }).collect();
+        round_trip(&noise);
+        assert!(deflate(&noise).len() <= noise.len() + 10);
+
+        // Repetitive data should compress well
+        let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog. ".repeat(200);
+        round_trip(&text);
+        assert!(deflate(&text).len() < text.len() / 10);
+    }
+
+    #[test]
+    fn test_inflate_dynamic()
+    {
+        // Produced by zlib from skewed pseudo-random text, which
+        // makes it pick dynamic Huffman codes
+        let data: [u8; 115] = [
+            0x3d, 0x8f, 0xdb, 0x11, 0x44, 0x31, 0x08, 0x42, 0x5b, 0xb1, 0x35, 0x1e,
+            0xfd, 0xd7, 0xb0, 0xa0, 0xb9, 0xeb, 0x87, 0x63, 0x04, 0x8e, 0x13, 0x18,
+            0x24, 0x41, 0x40, 0x23, 0x01, 0x18, 0xb8, 0x2d, 0x95, 0xbd, 0x22, 0x55,
+            0x03, 0x75, 0x4a, 0xad, 0x99, 0x09, 0x0f, 0x39, 0xb3, 0x2b, 0x89, 0xae,
+            0xc9, 0xa2, 0x7c, 0xc9, 0xf9, 0x42, 0x01, 0x14, 0x01, 0x61, 0x78, 0xcc,
+            0xfa, 0xa2, 0xf6, 0xd4, 0xde, 0x29, 0x8d, 0xe7, 0x6d, 0xf3, 0x46, 0x03,
+            0x50, 0x43, 0x41, 0x43, 0xf6, 0x2c, 0xff, 0x00, 0xc7, 0xc0, 0xe3, 0x7d,
+            0xc5, 0xb1, 0xb3, 0x4f, 0x8c, 0x7e, 0x7a, 0x09, 0xfd, 0x01, 0xf8, 0x4f,
+            0x6e, 0xe9, 0x1b, 0x3b, 0xac, 0x39, 0x9e, 0xa4, 0xb5, 0xec, 0x5e, 0x12,
+            0x9f, 0xbd, 0x5f, 0x3c, 0xe3, 0xbe, 0x7f,
+        ];
+        assert_eq!((data[0] >> 1) & 3, 2);
+
+        let mut x: u32 = 1;
+        let expected: Vec<u8> = (0..300).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            b"aaaaaaaabbbbccd "[((x >> 16) & 0xFF) as usize % 16]
+        }).collect();
+
+        assert_eq!(inflate(&data, 300).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_inflate_errors()
+    {
+        // Output too large for the buffer
+        let compressed = deflate(&[7u8; 1000]);
+        assert_eq!(inflate(&compressed, 999), Err(InflateError::TooLong));
+        assert_eq!(inflate(&[1, 3, 0, 0xFC, 0xFF, 1, 2, 3], 2), Err(InflateError::TooLong));
+
+        // Truncated and invalid inputs
+        assert_eq!(inflate(&compressed[..compressed.len() / 2], 1000), Err(InflateError::Invalid));
+        assert_eq!(inflate(&[], 1000), Err(InflateError::Invalid));
+        assert_eq!(inflate(&[0xFF, 0xFF], 1000), Err(InflateError::Invalid));
+    }
+}
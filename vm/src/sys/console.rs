@@ -0,0 +1,210 @@
+// Text console with cursor control and unbuffered key input,
+// implemented with ANSI escape sequences and termios
+
+use std::io::{stdout, Write};
+use std::sync::Mutex;
+use crate::vm::{VM, Value};
+use crate::sys::write_stdout;
+use crate::sys::constants::*;
+
+/// Terminal settings to restore when leaving raw mode
+static SAVED_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Write an escape sequence to stdout, like the rest of the output of the
+/// guest, so that it is charged to the bytes written quota and captured
+fn write_esc(vm: &mut VM, seq: &str)
+{
+    if vm.sys_state.quotas.bytes_written.acquire(seq.len() as u64) {
+        let _ = write_stdout(vm, seq.as_bytes());
+    }
+}
+
+pub fn console_clear(vm: &mut VM)
+{
+    write_esc(vm, "\x1b[2J\x1b[H");
+}
+
+pub fn console_move_cursor(vm: &mut VM, row: Value, col: Value)
+{
+    let row = row.as_u32() as u64;
+    let col = col.as_u32() as u64;
+    write_esc(vm, &format!("\x1b[{};{}H", row + 1, col + 1));
+}
+
+/// SGR parameter for a color, with the base code
+/// for the normal (30 or 40) and bright (90 or 100) colors
+fn color_param(color: u8, normal: u32, bright: u32) -> u32
+{
+    match color {
+        CONSOLE_DEFAULT => normal + 9,
+        0..=7 => normal + color as u32,
+        8..=15 => bright + (color - 8) as u32,
+        _ => panic!("console_set_style: invalid color {}", color),
+    }
+}
+
+pub fn console_set_style(vm: &mut VM, fg: Value, bg: Value, attrs: Value)
+{
+    let fg = color_param(fg.as_u8(), 30, 90);
+    let bg = color_param(bg.as_u8(), 40, 100);
+    let attrs = attrs.as_u32();
+
+    let mut seq = format!("\x1b[0;{};{}", fg, bg);
+    if attrs & CONSOLE_BOLD != 0 {
+        seq += ";1";
+    }
+    if attrs & CONSOLE_UNDERLINE != 0 {
+        seq += ";4";
+    }
+    if attrs & CONSOLE_REVERSE != 0 {
+        seq += ";7";
+    }
+    seq += "m";
+
+    write_esc(vm, &seq);
+}
+
+pub fn console_show_cursor(vm: &mut VM, visible: Value)
+{
+    write_esc(vm, if visible.as_u64() != 0 { "\x1b[?25h" } else { "\x1b[?25l" });
+}
+
+pub fn console_size(vm: &mut VM) -> Value
+{
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return Value::from(0);
+    }
+
+    Value::from(((size.ws_row as u64) << 32) | size.ws_col as u64)
+}
+
+/// Restore the terminal settings saved when entering raw mode
+/// This is registered to run when the process exits
+pub extern "C" fn restore_terminal()
+{
+    if let Some(termios) = SAVED_TERMIOS.lock().unwrap().take() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &termios) };
+
+        // Leave the terminal with the default style and a visible cursor
+        let _ = stdout().write_all(b"\x1b[0m\x1b[?25h");
+        let _ = stdout().flush();
+    }
+}
+
+pub fn console_set_raw(vm: &mut VM, enabled: Value) -> Value
+{
+    let _ = stdout().flush();
+
+    if enabled.as_u64() == 0 {
+        restore_terminal();
+        return Value::from(true);
+    }
+
+    let mut saved = SAVED_TERMIOS.lock().unwrap();
+    if saved.is_some() {
+        return Value::from(true);
+    }
+
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+        return Value::from(false);
+    }
+
+    // Disable line buffering and echo, but keep signals so that
+    // ctrl-C still works, and keep output processing for newlines
+    let mut raw = termios;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+    raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+        return Value::from(false);
+    }
+
+    // Only register the exit handler the first time
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+    REGISTERED.call_once(|| unsafe { libc::atexit(restore_terminal); });
+
+    *saved = Some(termios);
+    Value::from(true)
+}
+
+/// Read one byte of input, waiting for up to timeout_ms
+/// milliseconds or indefinitely if negative
+fn read_byte(timeout_ms: i32) -> Option<u8>
+{
+    let mut pollfd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    if unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } <= 0 {
+        return None;
+    }
+
+    let mut byte = 0u8;
+    match unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+        1 => Some(byte),
+        _ => None,
+    }
+}
+
+/// Translate the final byte of a CSI escape sequence
+fn translate_csi(byte: u8) -> Option<u16>
+{
+    match byte {
+        b'A' => Some(KEY_UP),
+        b'B' => Some(KEY_DOWN),
+        b'C' => Some(KEY_RIGHT),
+        b'D' => Some(KEY_LEFT),
+        _ => None,
+    }
+}
+
+/// Read a key, decoding escape sequences
+/// Returns None when no key is available
+fn read_key(blocking: bool) -> Option<u16>
+{
+    loop
+    {
+        let byte = read_byte(if blocking { -1 } else { 0 })?;
+
+        match byte {
+            b'\r' | b'\n' => return Some(KEY_RETURN),
+            0x7F | 0x08 => return Some(KEY_BACKSPACE),
+            0x1B => {}
+            _ => return Some(byte as u16),
+        }
+
+        // The rest of an escape sequence arrives right after the escape
+        // byte, so a lone escape byte is the escape key being pressed
+        match read_byte(20) {
+            Some(b'[') | Some(b'O') => {}
+            Some(_) | None => return Some(KEY_ESCAPE),
+        }
+
+        // Skip the parameters up to the final byte of the sequence
+        let mut last = None;
+        while let Some(byte) = read_byte(20) {
+            if (0x40..=0x7E).contains(&byte) {
+                last = Some(byte);
+                break;
+            }
+        }
+
+        // Sequences for other keys are ignored
+        if let Some(key) = last.and_then(translate_csi) {
+            return Some(key);
+        }
+    }
+}
+
+pub fn console_read_key(vm: &mut VM, blocking: Value) -> Value
+{
+    // Make sure the output is visible before waiting for input
+    let _ = stdout().flush();
+
+    match read_key(blocking.as_u64() != 0) {
+        Some(key) => Value::from(key as i64),
+        None => Value::from(-1_i64),
+    }
+}
@@ -4,7 +4,7 @@
 
 #![allow(unused)]
 
-pub const SYSCALL_TBL_LEN: usize = 28;
+pub const SYSCALL_TBL_LEN: usize = 119;
 
 pub const TIME_CURRENT_MS: u16 = 0;
 pub const WINDOW_CREATE: u16 = 1;
@@ -34,6 +34,97 @@ pub const NET_WRITE: u16 = 24;
 pub const NET_CLOSE: u16 = 25;
 pub const PUTCHAR: u16 = 26;
 pub const MEMCMP: u16 = 27;
+pub const PROC_ATEXIT: u16 = 28;
+pub const AUDIO_MIX: u16 = 29;
+pub const AUDIO_RESAMPLE: u16 = 30;
+pub const WINDOW_FRAME_STATS: u16 = 31;
+pub const WINDOW_ON_DROPFILE: u16 = 32;
+pub const PAD_RUMBLE: u16 = 33;
+pub const WS_CONNECT: u16 = 34;
+pub const WS_SEND: u16 = 35;
+pub const COMPRESS: u16 = 36;
+pub const DECOMPRESS: u16 = 37;
+pub const CRYPTO_SHA256: u16 = 38;
+pub const CRYPTO_CRC32: u16 = 39;
+pub const STR_VALIDATE_UTF8: u16 = 40;
+pub const STR_CHAR_COUNT: u16 = 41;
+pub const STR_TO_UPPER: u16 = 42;
+pub const STR_TO_LOWER: u16 = 43;
+pub const PROC_SPAWN: u16 = 44;
+pub const VM_TRACK_ALLOC: u16 = 45;
+pub const VM_TRACK_FREE: u16 = 46;
+pub const MODULE_LOAD: u16 = 47;
+pub const MODULE_SYMBOL: u16 = 48;
+pub const TLS_ALLOC: u16 = 49;
+pub const TLS_BASE: u16 = 50;
+pub const SYS_VERSION: u16 = 51;
+pub const SYS_HAS_SYSCALL: u16 = 52;
+pub const BLIT_SPRITES: u16 = 53;
+pub const DRAW_TILEMAP: u16 = 54;
+pub const SURFACE_CREATE: u16 = 55;
+pub const SURFACE_DESTROY: u16 = 56;
+pub const SURFACE_BLIT: u16 = 57;
+pub const WINDOW_DRAW_SURFACE: u16 = 58;
+pub const BLEND_OVER: u16 = 59;
+pub const HSV_TO_RGB: u16 = 60;
+pub const APPLY_LUT: u16 = 61;
+pub const VM_HEAP_WALK: u16 = 62;
+pub const VM_BACKTRACE: u16 = 63;
+pub const PROC_ARGC: u16 = 64;
+pub const PROC_ARGV: u16 = 65;
+pub const VM_YIELD: u16 = 66;
+pub const CONSOLE_CLEAR: u16 = 67;
+pub const CONSOLE_MOVE_CURSOR: u16 = 68;
+pub const CONSOLE_SET_STYLE: u16 = 69;
+pub const CONSOLE_SHOW_CURSOR: u16 = 70;
+pub const CONSOLE_SIZE: u16 = 71;
+pub const CONSOLE_SET_RAW: u16 = 72;
+pub const CONSOLE_READ_KEY: u16 = 73;
+pub const TRACE_BEGIN: u16 = 74;
+pub const TRACE_END: u16 = 75;
+pub const VM_SET_TRAP_HANDLER: u16 = 76;
+pub const VM_MEM_LAYOUT: u16 = 77;
+pub const VM_MEM_PROTECT: u16 = 78;
+pub const VM_MEM_QUERY: u16 = 79;
+pub const FS_OPEN: u16 = 80;
+pub const FS_READ: u16 = 81;
+pub const FS_WRITE: u16 = 82;
+pub const FS_CLOSE: u16 = 83;
+pub const MEM_ALLOC: u16 = 84;
+pub const MEM_FREE: u16 = 85;
+pub const MEM_RESIZE: u16 = 86;
+pub const VM_GROW_HEAP: u16 = 87;
+pub const READ_LINE: u16 = 88;
+pub const THREAD_SPAWN: u16 = 89;
+pub const THREAD_JOIN: u16 = 90;
+pub const THREAD_ID: u16 = 91;
+pub const MUTEX_CREATE: u16 = 92;
+pub const MUTEX_LOCK: u16 = 93;
+pub const MUTEX_UNLOCK: u16 = 94;
+pub const COND_CREATE: u16 = 95;
+pub const COND_WAIT: u16 = 96;
+pub const COND_SIGNAL: u16 = 97;
+pub const TIME_INTERVAL_CB: u16 = 98;
+pub const TIME_CANCEL_CB: u16 = 99;
+pub const FS_SEEK: u16 = 100;
+pub const SYS_BATCH: u16 = 101;
+pub const FS_LIST_DIR: u16 = 102;
+pub const FS_STAT: u16 = 103;
+pub const FS_MKDIR: u16 = 104;
+pub const FS_REMOVE: u16 = 105;
+pub const NET_TCP_CONNECT: u16 = 106;
+pub const AUDIO_OSC: u16 = 107;
+pub const AUDIO_ENVELOPE: u16 = 108;
+pub const AUDIO_BIQUAD_INIT: u16 = 109;
+pub const AUDIO_BIQUAD: u16 = 110;
+pub const NET_FETCH: u16 = 111;
+pub const EVENT_POLL: u16 = 112;
+pub const PRINT_F64: u16 = 113;
+pub const IO_WRITE: u16 = 114;
+pub const PIPE_CREATE: u16 = 115;
+pub const PIPE_WRITE: u16 = 116;
+pub const PIPE_READ: u16 = 117;
+pub const PIPE_CLOSE: u16 = 118;
 
 pub struct SysCallDesc
 {
@@ -41,39 +132,177 @@ pub struct SysCallDesc
     pub const_idx: u16,
     pub argc: usize,
     pub has_ret: bool,
+    pub permission: &'static str,
 }
 
 pub const SYSCALL_DESCS: [Option<SysCallDesc>; SYSCALL_TBL_LEN] = [
-    Some(SysCallDesc { name: "time_current_ms", const_idx: 0, argc: 0, has_ret: true }),
-    Some(SysCallDesc { name: "window_create", const_idx: 1, argc: 4, has_ret: true }),
-    Some(SysCallDesc { name: "time_delay_cb", const_idx: 2, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "memcpy", const_idx: 3, argc: 3, has_ret: false }),
-    Some(SysCallDesc { name: "memset", const_idx: 4, argc: 3, has_ret: false }),
-    Some(SysCallDesc { name: "print_i64", const_idx: 5, argc: 1, has_ret: false }),
-    Some(SysCallDesc { name: "print_str", const_idx: 6, argc: 1, has_ret: false }),
-    Some(SysCallDesc { name: "print_endl", const_idx: 7, argc: 0, has_ret: false }),
-    Some(SysCallDesc { name: "getchar", const_idx: 8, argc: 0, has_ret: true }),
-    Some(SysCallDesc { name: "window_on_keydown", const_idx: 9, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "window_draw_frame", const_idx: 10, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "window_on_mousemove", const_idx: 11, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "window_on_mousedown", const_idx: 12, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "window_on_mouseup", const_idx: 13, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "vm_heap_size", const_idx: 14, argc: 0, has_ret: true }),
-    Some(SysCallDesc { name: "window_on_keyup", const_idx: 15, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "memset32", const_idx: 16, argc: 3, has_ret: false }),
-    Some(SysCallDesc { name: "vm_resize_heap", const_idx: 17, argc: 1, has_ret: true }),
-    Some(SysCallDesc { name: "audio_open_output", const_idx: 18, argc: 4, has_ret: true }),
-    Some(SysCallDesc { name: "window_on_textinput", const_idx: 19, argc: 2, has_ret: false }),
-    Some(SysCallDesc { name: "print_f32", const_idx: 20, argc: 1, has_ret: false }),
-    Some(SysCallDesc { name: "net_listen", const_idx: 21, argc: 2, has_ret: true }),
-    Some(SysCallDesc { name: "net_accept", const_idx: 22, argc: 4, has_ret: true }),
-    Some(SysCallDesc { name: "net_read", const_idx: 23, argc: 3, has_ret: true }),
-    Some(SysCallDesc { name: "net_write", const_idx: 24, argc: 3, has_ret: true }),
-    Some(SysCallDesc { name: "net_close", const_idx: 25, argc: 1, has_ret: false }),
-    Some(SysCallDesc { name: "putchar", const_idx: 26, argc: 1, has_ret: true }),
-    Some(SysCallDesc { name: "memcmp", const_idx: 27, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "time_current_ms", const_idx: 0, argc: 0, has_ret: true, permission: "time_get_time" }),
+    Some(SysCallDesc { name: "window_create", const_idx: 1, argc: 4, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "time_delay_cb", const_idx: 2, argc: 2, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "memcpy", const_idx: 3, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "memset", const_idx: 4, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "print_i64", const_idx: 5, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "print_str", const_idx: 6, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "print_endl", const_idx: 7, argc: 0, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "getchar", const_idx: 8, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "window_on_keydown", const_idx: 9, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "window_draw_frame", const_idx: 10, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "window_on_mousemove", const_idx: 11, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "window_on_mousedown", const_idx: 12, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "window_on_mouseup", const_idx: 13, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "vm_heap_size", const_idx: 14, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "window_on_keyup", const_idx: 15, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "memset32", const_idx: 16, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_resize_heap", const_idx: 17, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "audio_open_output", const_idx: 18, argc: 4, has_ret: true, permission: "audio_output" }),
+    Some(SysCallDesc { name: "window_on_textinput", const_idx: 19, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "print_f32", const_idx: 20, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "net_listen", const_idx: 21, argc: 2, has_ret: true, permission: "net_server" }),
+    Some(SysCallDesc { name: "net_accept", const_idx: 22, argc: 4, has_ret: true, permission: "net_server" }),
+    Some(SysCallDesc { name: "net_read", const_idx: 23, argc: 3, has_ret: true, permission: "net_io" }),
+    Some(SysCallDesc { name: "net_write", const_idx: 24, argc: 3, has_ret: true, permission: "net_io" }),
+    Some(SysCallDesc { name: "net_close", const_idx: 25, argc: 1, has_ret: true, permission: "net_io" }),
+    Some(SysCallDesc { name: "putchar", const_idx: 26, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "memcmp", const_idx: 27, argc: 3, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "proc_atexit", const_idx: 28, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "audio_mix", const_idx: 29, argc: 4, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "audio_resample", const_idx: 30, argc: 4, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "window_frame_stats", const_idx: 31, argc: 2, has_ret: true, permission: "window_display" }),
+    Some(SysCallDesc { name: "window_on_dropfile", const_idx: 32, argc: 4, has_ret: true, permission: "window_dropfile" }),
+    Some(SysCallDesc { name: "pad_rumble", const_idx: 33, argc: 4, has_ret: true, permission: "pad_haptics" }),
+    Some(SysCallDesc { name: "ws_connect", const_idx: 34, argc: 3, has_ret: true, permission: "net_client" }),
+    Some(SysCallDesc { name: "ws_send", const_idx: 35, argc: 4, has_ret: true, permission: "net_io" }),
+    Some(SysCallDesc { name: "compress", const_idx: 36, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "decompress", const_idx: 37, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "crypto_sha256", const_idx: 38, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "crypto_crc32", const_idx: 39, argc: 3, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "str_validate_utf8", const_idx: 40, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "str_char_count", const_idx: 41, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "str_to_upper", const_idx: 42, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "str_to_lower", const_idx: 43, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "proc_spawn", const_idx: 44, argc: 4, has_ret: true, permission: "proc_exec" }),
+    Some(SysCallDesc { name: "vm_track_alloc", const_idx: 45, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_track_free", const_idx: 46, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "module_load", const_idx: 47, argc: 1, has_ret: true, permission: "module_load" }),
+    Some(SysCallDesc { name: "module_symbol", const_idx: 48, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "tls_alloc", const_idx: 49, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "tls_base", const_idx: 50, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "sys_version", const_idx: 51, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "sys_has_syscall", const_idx: 52, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "blit_sprites", const_idx: 53, argc: 4, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "draw_tilemap", const_idx: 54, argc: 4, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "surface_create", const_idx: 55, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "surface_destroy", const_idx: 56, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "surface_blit", const_idx: 57, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "window_draw_surface", const_idx: 58, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "blend_over", const_idx: 59, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "hsv_to_rgb", const_idx: 60, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "apply_lut", const_idx: 61, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_heap_walk", const_idx: 62, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_backtrace", const_idx: 63, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "proc_argc", const_idx: 64, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "proc_argv", const_idx: 65, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_yield", const_idx: 66, argc: 0, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_clear", const_idx: 67, argc: 0, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_move_cursor", const_idx: 68, argc: 2, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_set_style", const_idx: 69, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_show_cursor", const_idx: 70, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_size", const_idx: 71, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_set_raw", const_idx: 72, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "console_read_key", const_idx: 73, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "trace_begin", const_idx: 74, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "trace_end", const_idx: 75, argc: 0, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_set_trap_handler", const_idx: 76, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_mem_layout", const_idx: 77, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_mem_protect", const_idx: 78, argc: 3, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_mem_query", const_idx: 79, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "fs_open", const_idx: 80, argc: 2, has_ret: true, permission: "fs_open" }),
+    Some(SysCallDesc { name: "fs_read", const_idx: 81, argc: 3, has_ret: true, permission: "fs_io" }),
+    Some(SysCallDesc { name: "fs_write", const_idx: 82, argc: 3, has_ret: true, permission: "fs_io" }),
+    Some(SysCallDesc { name: "fs_close", const_idx: 83, argc: 1, has_ret: true, permission: "fs_io" }),
+    Some(SysCallDesc { name: "mem_alloc", const_idx: 84, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "mem_free", const_idx: 85, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "mem_resize", const_idx: 86, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "vm_grow_heap", const_idx: 87, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "read_line", const_idx: 88, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "thread_spawn", const_idx: 89, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "thread_join", const_idx: 90, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "thread_id", const_idx: 91, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "mutex_create", const_idx: 92, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "mutex_lock", const_idx: 93, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "mutex_unlock", const_idx: 94, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "cond_create", const_idx: 95, argc: 0, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "cond_wait", const_idx: 96, argc: 2, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "cond_signal", const_idx: 97, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "time_interval_cb", const_idx: 98, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "time_cancel_cb", const_idx: 99, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "fs_seek", const_idx: 100, argc: 3, has_ret: true, permission: "fs_io" }),
+    Some(SysCallDesc { name: "sys_batch", const_idx: 101, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "fs_list_dir", const_idx: 102, argc: 3, has_ret: true, permission: "fs_list" }),
+    Some(SysCallDesc { name: "fs_stat", const_idx: 103, argc: 2, has_ret: true, permission: "fs_list" }),
+    Some(SysCallDesc { name: "fs_mkdir", const_idx: 104, argc: 1, has_ret: true, permission: "fs_modify" }),
+    Some(SysCallDesc { name: "fs_remove", const_idx: 105, argc: 1, has_ret: true, permission: "fs_modify" }),
+    Some(SysCallDesc { name: "net_tcp_connect", const_idx: 106, argc: 3, has_ret: true, permission: "net_client" }),
+    Some(SysCallDesc { name: "audio_osc", const_idx: 107, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "audio_envelope", const_idx: 108, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "audio_biquad_init", const_idx: 109, argc: 4, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "audio_biquad", const_idx: 110, argc: 3, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "net_fetch", const_idx: 111, argc: 2, has_ret: true, permission: "net_client" }),
+    Some(SysCallDesc { name: "event_poll", const_idx: 112, argc: 1, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "print_f64", const_idx: 113, argc: 1, has_ret: false, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "io_write", const_idx: 114, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "pipe_create", const_idx: 115, argc: 2, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "pipe_write", const_idx: 116, argc: 3, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "pipe_read", const_idx: 117, argc: 3, has_ret: true, permission: "default_allowed" }),
+    Some(SysCallDesc { name: "pipe_close", const_idx: 118, argc: 1, has_ret: true, permission: "default_allowed" }),
 ];
 
+pub const TRAP_DIV_ZERO: u32 = 1;
+pub const TRAP_OUT_OF_BOUNDS: u32 = 2;
+pub const TRAP_UNALIGNED: u32 = 3;
+pub const TRAP_INVALID_SYSCALL: u32 = 4;
+pub const TRAP_STACK_OVERFLOW: u32 = 5;
+pub const TRAP_PROTECTION: u32 = 6;
+pub const TRAP_UNVERIFIED_CODE: u32 = 7;
+pub const MEM_REGION_CODE: u32 = 0;
+pub const MEM_REGION_DATA: u32 = 1;
+pub const MEM_REGION_HEAP: u32 = 2;
+pub const MEM_REGION_STACK: u32 = 3;
+pub const MEM_PROT_READ: u32 = 1;
+pub const MEM_PROT_WRITE: u32 = 2;
+pub const MEM_PAGE_SIZE: u32 = 4096;
+pub const SYS_BATCH_ENTRY_SIZE: u32 = 48;
+pub const CONSOLE_BLACK: u8 = 0;
+pub const CONSOLE_RED: u8 = 1;
+pub const CONSOLE_GREEN: u8 = 2;
+pub const CONSOLE_YELLOW: u8 = 3;
+pub const CONSOLE_BLUE: u8 = 4;
+pub const CONSOLE_MAGENTA: u8 = 5;
+pub const CONSOLE_CYAN: u8 = 6;
+pub const CONSOLE_WHITE: u8 = 7;
+pub const CONSOLE_BRIGHT: u8 = 8;
+pub const CONSOLE_DEFAULT: u8 = 255;
+pub const CONSOLE_BOLD: u32 = 1;
+pub const CONSOLE_UNDERLINE: u32 = 2;
+pub const CONSOLE_REVERSE: u32 = 4;
+pub const EVENT_KEYDOWN: u32 = 1;
+pub const EVENT_KEYUP: u32 = 2;
+pub const EVENT_MOUSEMOVE: u32 = 3;
+pub const EVENT_MOUSEDOWN: u32 = 4;
+pub const EVENT_MOUSEUP: u32 = 5;
+pub const EVENT_TEXTINPUT: u32 = 6;
+pub const EVENT_TIMER: u32 = 7;
+pub const EVENT_NET_CONN: u32 = 8;
+pub const EVENT_NET_DATA: u32 = 9;
+pub const EVENT_WS_MESSAGE: u32 = 10;
+pub const EVENT_NET_FETCH: u32 = 11;
+pub const EVENT_NET_CONNECT: u32 = 12;
+pub const EVENT_SIZE: u32 = 40;
+pub const EVENT_OFS_KIND: u32 = 0;
+pub const EVENT_OFS_SOURCE: u32 = 8;
+pub const EVENT_OFS_ARG0: u32 = 16;
+pub const EVENT_OFS_ARG1: u32 = 24;
+pub const EVENT_OFS_ARG2: u32 = 32;
 pub const KEY_BACKSPACE: u16 = 8;
 pub const KEY_TAB: u16 = 9;
 pub const KEY_RETURN: u16 = 10;
@@ -127,4 +356,55 @@ pub const KEY_RIGHT: u16 = 16002;
 pub const KEY_UP: u16 = 16003;
 pub const KEY_DOWN: u16 = 16004;
 pub const KEY_SHIFT: u16 = 16005;
+pub const PIXEL_FORMAT_BGRA32: u8 = 0;
+pub const PIXEL_FORMAT_RGBA32: u8 = 1;
+pub const PIXEL_FORMAT_RGB565: u8 = 2;
+pub const SPRITE_FLIP_X: u32 = 1;
+pub const SPRITE_FLIP_Y: u32 = 2;
+pub const SPRITE_OPAQUE: u32 = 4;
+pub const TILE_EMPTY: u16 = 65535;
+pub const SURFACE_TRANSPARENT: u32 = 1;
 pub const AUDIO_FORMAT_I16: u16 = 0;
+pub const AUDIO_WAVE_SINE: u16 = 0;
+pub const AUDIO_WAVE_SQUARE: u16 = 1;
+pub const AUDIO_WAVE_SAW: u16 = 2;
+pub const AUDIO_WAVE_TRIANGLE: u16 = 3;
+pub const AUDIO_FILTER_LOWPASS: u16 = 0;
+pub const AUDIO_FILTER_HIGHPASS: u16 = 1;
+pub const AUDIO_FILTER_BANDPASS: u16 = 2;
+pub const WS_MSG_TEXT: u8 = 1;
+pub const WS_MSG_BINARY: u8 = 2;
+pub const FS_OPEN_READ: u32 = 1;
+pub const FS_OPEN_WRITE: u32 = 2;
+pub const FS_OPEN_CREATE: u32 = 4;
+pub const FS_OPEN_TRUNCATE: u32 = 8;
+pub const FS_OPEN_APPEND: u32 = 16;
+pub const FS_SEEK_SET: u8 = 0;
+pub const FS_SEEK_CUR: u8 = 1;
+pub const FS_SEEK_END: u8 = 2;
+pub const FS_TYPE_FILE: u32 = 1;
+pub const FS_TYPE_DIR: u32 = 2;
+
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOSPC: i64 = 28;
+pub const EROFS: i64 = 30;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const EADDRINUSE: i64 = 98;
+pub const ECONNRESET: i64 = 104;
+pub const ENOTCONN: i64 = 107;
+pub const ETIMEDOUT: i64 = 110;
+pub const ECONNREFUSED: i64 = 111;
+pub const EDQUOT: i64 = 122;
@@ -0,0 +1,114 @@
+// Queue of the host events which have no callback, dequeued by the
+// guest with event_poll. Events which have a callback are passed to it
+// directly, so that programs can mix callbacks and polling.
+
+use std::collections::VecDeque;
+use crate::sys::constants::*;
+use crate::vm::{VM, Value, ExitReason};
+
+/// Maximum number of queued events, older events are dropped when it is reached
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Event
+{
+    /// EVENT_* kind
+    kind: u32,
+
+    /// Window, timer or socket the event comes from, followed
+    /// by the other arguments the callback would have received
+    args: [u64; 4],
+}
+
+#[derive(Default)]
+pub struct EventQueue
+{
+    events: VecDeque<Event>,
+}
+
+impl EventQueue
+{
+    /// Queue an event, given the arguments its callback would have received
+    pub fn push(&mut self, kind: u32, args: &[Value])
+    {
+        assert!(!args.is_empty() && args.len() <= 4);
+
+        let mut event = Event { kind, args: [0; 4] };
+        for (idx, arg) in args.iter().enumerate() {
+            event.args[idx] = arg.as_u64();
+        }
+
+        if self.events.len() == MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(event);
+    }
+
+    fn pop(&mut self) -> Option<Event>
+    {
+        self.events.pop_front()
+    }
+
+    fn len(&self) -> usize
+    {
+        self.events.len()
+    }
+}
+
+/// Pass an event to its callback, or queue it if the callback is null
+pub fn dispatch_event(vm: &mut VM, cb: u64, kind: u32, args: &[Value]) -> ExitReason
+{
+    if cb == 0 {
+        vm.sys_state.event_queue.push(kind, args);
+        return ExitReason::default();
+    }
+
+    vm.call_sync(cb, args)
+}
+
+/// Remove the oldest queued event and write it into a guest event_t struct
+/// u64 found = event_poll(event_t* event)
+pub fn event_poll(vm: &mut VM, event_ptr: Value) -> Value
+{
+    let event_ptr = event_ptr.as_usize();
+
+    let event = match vm.sys_state.event_queue.pop() {
+        Some(event) => event,
+        None => return Value::from(0),
+    };
+
+    let mut mem = vm.mem_view();
+    mem.write(event_ptr + EVENT_OFS_KIND as usize, event.kind as u64);
+    mem.write(event_ptr + EVENT_OFS_SOURCE as usize, event.args[0]);
+    mem.write(event_ptr + EVENT_OFS_ARG0 as usize, event.args[1]);
+    mem.write(event_ptr + EVENT_OFS_ARG1 as usize, event.args[2]);
+    mem.write(event_ptr + EVENT_OFS_ARG2 as usize, event.args[3]);
+
+    Value::from(1)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_event_queue()
+    {
+        let mut queue = EventQueue::default();
+        queue.push(EVENT_KEYDOWN, &[Value::from(0), Value::from(KEY_A)]);
+        queue.push(EVENT_MOUSEDOWN, &[Value::from(0), Value::from(2), Value::from(-1), Value::from(7)]);
+
+        assert_eq!(queue.pop(), Some(Event { kind: EVENT_KEYDOWN, args: [0, KEY_A as u64, 0, 0] }));
+        assert_eq!(queue.pop(), Some(Event { kind: EVENT_MOUSEDOWN, args: [0, 2, u64::MAX, 7] }));
+        assert_eq!(queue.pop(), None);
+
+        // When the queue is full, the oldest events are dropped
+        for idx in 0..(MAX_QUEUED_EVENTS + 3) {
+            queue.push(EVENT_TIMER, &[Value::from(idx)]);
+        }
+        assert_eq!(queue.len(), MAX_QUEUED_EVENTS);
+        assert_eq!(queue.pop().unwrap().args[0], 3);
+    }
+}
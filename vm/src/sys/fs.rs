@@ -0,0 +1,229 @@
+// File I/O syscalls, which operate on the virtual filesystem
+// The open files are kept in the handle table
+
+use std::io::{Read, Write, Seek, SeekFrom};
+use crate::vm::{VM, Value};
+use crate::sys::{sys_err, io_err_code};
+use crate::sys::vfs::{Vfs, VfsFile, OpenMode, HostDir};
+use crate::sys::archive::ZipArchive;
+use crate::sys::constants::*;
+
+#[derive(Default)]
+pub struct FsState
+{
+    /// Directories visible to the guest
+    pub vfs: Vfs,
+}
+
+impl FsState
+{
+    /// Make a host directory visible to the guest, given
+    /// a "guest_dir=host_dir" mapping
+    pub fn map_host_dir(&mut self, mapping: &str) -> Result<(), String>
+    {
+        let (guest_dir, host_dir) = match mapping.split_once('=') {
+            Some((guest_dir, host_dir)) if !guest_dir.is_empty() && !host_dir.is_empty() => (guest_dir, host_dir),
+            _ => return Err(format!("invalid mapping \"{}\", expected guest_dir=host_dir", mapping))
+        };
+
+        self.vfs.mount(guest_dir, Box::new(HostDir::new(host_dir)?))
+    }
+
+    /// Make the contents of a zip archive visible to the guest as a
+    /// read-only directory, given an "archive_file:guest_dir" mapping
+    pub fn mount_archive(&mut self, mapping: &str) -> Result<(), String>
+    {
+        // Guest paths can't contain a drive letter, but host paths can
+        let (file_name, guest_dir) = match mapping.rsplit_once(':') {
+            Some((file_name, guest_dir)) if !file_name.is_empty() && !guest_dir.is_empty() => (file_name, guest_dir),
+            _ => return Err(format!("invalid mount \"{}\", expected archive_file:guest_dir", mapping))
+        };
+
+        self.vfs.mount(guest_dir, Box::new(ZipArchive::load(file_name)?))
+    }
+}
+
+// State associated with an open file
+struct OpenFile
+{
+    file: Box<dyn VfsFile>,
+    mode: OpenMode,
+}
+
+// i64 file = fs_open(const char* path, u32 flags)
+pub fn fs_open(vm: &mut VM, path: Value, flags: Value) -> Value
+{
+    let path = vm.mem_view().str(path.as_usize()).to_string();
+    let flags = flags.as_u32();
+
+    let mode = OpenMode {
+        read: flags & FS_OPEN_READ != 0,
+        write: flags & FS_OPEN_WRITE != 0,
+        create: flags & FS_OPEN_CREATE != 0,
+        truncate: flags & FS_OPEN_TRUNCATE != 0,
+        append: flags & FS_OPEN_APPEND != 0,
+    };
+
+    // Creating or changing a file requires opening it for writing
+    let changes_file = mode.create || mode.truncate || mode.append;
+    if !mode.write && (changes_file || !mode.read) {
+        return sys_err(EINVAL);
+    }
+
+    if !vm.sys_state.quotas.open_files.acquire(1) {
+        return sys_err(EMFILE);
+    }
+
+    match vm.sys_state.fs_state.vfs.open(&path, mode) {
+        Ok(file) => Value::from(vm.sys_state.handles.insert(OpenFile { file, mode })),
+        Err(e) => {
+            vm.sys_state.quotas.open_files.release(1);
+            sys_err(io_err_code(&e))
+        }
+    }
+}
+
+// i64 num_bytes = fs_read(u64 file, void* buf_ptr, u64 buf_len)
+pub fn fs_read(vm: &mut VM, file: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let buf_ptr = buf_ptr.as_usize();
+    let buf_len = buf_len.as_usize();
+    vm.mem_view().slice::<u8>(buf_ptr, buf_len);
+
+    let open_file = match vm.sys_state.handles.get_mut::<OpenFile>(file.as_u64()) {
+        Some(open_file) if open_file.mode.read => open_file,
+        _ => return sys_err(EBADF),
+    };
+
+    let mut data = vec![0; buf_len];
+    let num_bytes = match open_file.file.read(&mut data) {
+        Ok(num_bytes) => num_bytes,
+        Err(e) => return sys_err(io_err_code(&e)),
+    };
+
+    vm.mem_view().write_bytes(buf_ptr, &data[..num_bytes]);
+    Value::from(num_bytes)
+}
+
+// i64 num_bytes = fs_write(u64 file, const void* buf_ptr, u64 buf_len)
+pub fn fs_write(vm: &mut VM, file: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let buf_len = buf_len.as_usize();
+    let data = vm.mem_view().slice::<u8>(buf_ptr.as_usize(), buf_len).to_vec();
+
+    let open_file = match vm.sys_state.handles.get_mut::<OpenFile>(file.as_u64()) {
+        Some(open_file) if open_file.mode.write => open_file,
+        _ => return sys_err(EBADF),
+    };
+
+    if !vm.sys_state.quotas.bytes_written.acquire(buf_len as u64) {
+        return sys_err(EDQUOT);
+    }
+
+    match open_file.file.write_all(&data) {
+        Ok(_) => Value::from(buf_len),
+        Err(e) => sys_err(io_err_code(&e)),
+    }
+}
+
+// i64 pos = fs_seek(u64 file, i64 offset, u8 whence)
+pub fn fs_seek(vm: &mut VM, file: Value, offset: Value, whence: Value) -> Value
+{
+    let offset = offset.as_i64();
+
+    let pos = match whence.as_u8() {
+        FS_SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        FS_SEEK_CUR => SeekFrom::Current(offset),
+        FS_SEEK_END => SeekFrom::End(offset),
+        _ => return sys_err(EINVAL),
+    };
+
+    let open_file = match vm.sys_state.handles.get_mut::<OpenFile>(file.as_u64()) {
+        Some(open_file) => open_file,
+        None => return sys_err(EBADF),
+    };
+
+    match open_file.file.seek(pos) {
+        Ok(pos) => Value::from(pos),
+        Err(e) => sys_err(io_err_code(&e)),
+    }
+}
+
+// i64 result = fs_close(u64 file)
+pub fn fs_close(vm: &mut VM, file: Value) -> Value
+{
+    // This drops the file, which closes it
+    match vm.sys_state.handles.remove::<OpenFile>(file.as_u64()) {
+        Some(_) => {
+            vm.sys_state.quotas.open_files.release(1);
+            Value::from(0)
+        }
+        None => sys_err(EBADF),
+    }
+}
+
+// i64 num_bytes = fs_list_dir(const char* path, char* buf_ptr, u64 buf_len)
+pub fn fs_list_dir(vm: &mut VM, path: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let path = vm.mem_view().str(path.as_usize()).to_string();
+    let buf_ptr = buf_ptr.as_usize();
+    let buf_len = buf_len.as_usize();
+    vm.mem_view().slice::<u8>(buf_ptr, buf_len);
+
+    let names = match vm.sys_state.fs_state.vfs.list_dir(&path) {
+        Ok(names) => names,
+        Err(e) => return sys_err(io_err_code(&e)),
+    };
+
+    let mut data = Vec::new();
+    for name in names {
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+    }
+
+    // The guest can call again with a larger buffer
+    if data.len() <= buf_len {
+        vm.mem_view().write_bytes(buf_ptr, &data);
+    }
+
+    Value::from(data.len())
+}
+
+// i64 result = fs_stat(const char* path, u64* stat_ptr)
+pub fn fs_stat(vm: &mut VM, path: Value, stat_ptr: Value) -> Value
+{
+    let path = vm.mem_view().str(path.as_usize()).to_string();
+    let stat_ptr = stat_ptr.as_usize();
+
+    let stat = match vm.sys_state.fs_state.vfs.stat(&path) {
+        Ok(stat) => stat,
+        Err(e) => return sys_err(io_err_code(&e)),
+    };
+
+    let file_type = if stat.is_dir { FS_TYPE_DIR } else { FS_TYPE_FILE };
+    vm.mem_view().write(stat_ptr, file_type as u64);
+    vm.mem_view().write(stat_ptr + 8, stat.size);
+    Value::from(0)
+}
+
+// i64 result = fs_mkdir(const char* path)
+pub fn fs_mkdir(vm: &mut VM, path: Value) -> Value
+{
+    let path = vm.mem_view().str(path.as_usize()).to_string();
+
+    match vm.sys_state.fs_state.vfs.mkdir(&path) {
+        Ok(_) => Value::from(0),
+        Err(e) => sys_err(io_err_code(&e)),
+    }
+}
+
+// i64 result = fs_remove(const char* path)
+pub fn fs_remove(vm: &mut VM, path: Value) -> Value
+{
+    let path = vm.mem_view().str(path.as_usize()).to_string();
+
+    match vm.sys_state.fs_state.vfs.remove(&path) {
+        Ok(_) => Value::from(0),
+        Err(e) => sys_err(io_err_code(&e)),
+    }
+}
@@ -0,0 +1,431 @@
+use crate::vm::{VM, Value, MemView};
+use crate::sys::sys_err;
+use crate::sys::constants::*;
+
+/// Offscreen surface registered by the guest
+#[derive(Copy, Clone, Debug)]
+pub struct Surface
+{
+    pub bitmap: Bitmap,
+    pub flags: u32,
+}
+
+/// Bitmap in guest memory, described by the C struct
+/// bitmap_t { u32* pixels; u32 width; u32 height; }
+#[derive(Copy, Clone, Debug)]
+pub struct Bitmap
+{
+    pub pixels: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Bitmap
+{
+    /// Read a bitmap descriptor and validate its pixel data
+    pub fn read(mem: &MemView, desc_ptr: usize) -> Self
+    {
+        let pixels = mem.read::<u64>(desc_ptr) as usize;
+        let width = mem.read::<u32>(desc_ptr + 8) as usize;
+        let height = mem.read::<u32>(desc_ptr + 12) as usize;
+
+        mem.slice::<u32>(pixels, width * height);
+
+        Self { pixels, width, height }
+    }
+
+    /// Copy a rectangle of pixels out of the bitmap
+    fn read_rect(&self, mem: &MemView, x: usize, y: usize, width: usize, height: usize) -> Vec<u32>
+    {
+        if x + width > self.width || y + height > self.height {
+            panic!(
+                "rectangle ({}, {}, {}, {}) is outside of {}x{} bitmap",
+                x, y, width, height, self.width, self.height
+            );
+        }
+
+        let mut out = Vec::with_capacity(width * height);
+        for row in y..(y + height) {
+            out.extend_from_slice(mem.slice::<u32>(self.pixels + 4 * (row * self.width + x), width));
+        }
+
+        out
+    }
+
+    /// Get the pixels of the bitmap for writing
+    fn pixels_mut<'a>(&self, mem: &'a mut MemView) -> &'a mut [u32]
+    {
+        mem.slice_mut::<u32>(self.pixels, self.width * self.height)
+    }
+}
+
+/// Rectangle of pixels to copy from a source image with a given row stride
+struct SrcRect<'a>
+{
+    pixels: &'a [u32],
+    stride: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+/// Draw a rectangle of source pixels at position (x, y) of a bitmap,
+/// clipped to the bitmap bounds
+fn draw_rect(dst: &Bitmap, dst_pixels: &mut [u32], x: i64, y: i64, src: &SrcRect, flags: u32)
+{
+    let x_min = x.max(0);
+    let y_min = y.max(0);
+    let x_max = (x + src.width as i64).min(dst.width as i64);
+    let y_max = (y + src.height as i64).min(dst.height as i64);
+
+    // The rectangle can lie entirely outside of the bitmap
+    if x_min >= x_max || y_min >= y_max {
+        return;
+    }
+
+    let flip_x = flags & SPRITE_FLIP_X != 0;
+    let flip_y = flags & SPRITE_FLIP_Y != 0;
+    let opaque = flags & SPRITE_OPAQUE != 0;
+
+    for dst_y in y_min..y_max {
+        let mut row = (dst_y - y) as usize;
+        if flip_y {
+            row = src.height - 1 - row;
+        }
+
+        let src_row = &src.pixels[(src.y + row) * src.stride + src.x..];
+        let dst_row = &mut dst_pixels[dst_y as usize * dst.width..];
+
+        // Opaque rows can be copied directly
+        if opaque && !flip_x {
+            let col_min = (x_min - x) as usize;
+            let col_max = (x_max - x) as usize;
+            dst_row[x_min as usize..x_max as usize].copy_from_slice(&src_row[col_min..col_max]);
+            continue;
+        }
+
+        for dst_x in x_min..x_max {
+            let mut col = (dst_x - x) as usize;
+            if flip_x {
+                col = src.width - 1 - col;
+            }
+
+            // Pixels with zero alpha are transparent
+            let color = src_row[col];
+            if opaque || color >> 24 != 0 {
+                dst_row[dst_x as usize] = color;
+            }
+        }
+    }
+}
+
+// Syscall to draw a batch of sprites from a sprite sheet
+// blit_sprites(const bitmap_t* dst, const bitmap_t* sheet, const sprite_t* sprites, u64 num_sprites)
+pub fn blit_sprites(vm: &mut VM, dst: Value, sheet: Value, sprites: Value, num_sprites: Value)
+{
+    let mut mem = vm.mem_view();
+    let dst = Bitmap::read(&mem, dst.as_usize());
+    let sheet = Bitmap::read(&mem, sheet.as_usize());
+
+    // Each entry is a sprite_t { i32 x; i32 y; u32 src_x; u32 src_y; u32 width; u32 height; u32 flags; }
+    let num_sprites = num_sprites.as_usize();
+    let entries = mem.slice::<u32>(sprites.as_usize(), 7 * num_sprites).to_vec();
+
+    for entry in entries.chunks_exact(7) {
+        let x = entry[0] as i32 as i64;
+        let y = entry[1] as i32 as i64;
+        let width = entry[4] as usize;
+        let height = entry[5] as usize;
+        let flags = entry[6];
+
+        let pixels = sheet.read_rect(&mem, entry[2] as usize, entry[3] as usize, width, height);
+        let src = SrcRect { pixels: &pixels, stride: width, x: 0, y: 0, width, height };
+        draw_rect(&dst, dst.pixels_mut(&mut mem), x, y, &src, flags);
+    }
+}
+
+// Syscall to draw a tile map
+// draw_tilemap(const bitmap_t* dst, const tilemap_t* map, i32 scroll_x, i32 scroll_y)
+pub fn draw_tilemap(vm: &mut VM, dst: Value, map: Value, scroll_x: Value, scroll_y: Value)
+{
+    let mut mem = vm.mem_view();
+    let dst = Bitmap::read(&mem, dst.as_usize());
+
+    // tilemap_t { bitmap_t* tileset; u16* tiles; u32 map_width; u32 map_height; u32 tile_width; u32 tile_height; }
+    let map = map.as_usize();
+    let tileset = Bitmap::read(&mem, mem.read::<u64>(map) as usize);
+    let tiles_ptr = mem.read::<u64>(map + 8) as usize;
+    let map_width = mem.read::<u32>(map + 16) as i64;
+    let map_height = mem.read::<u32>(map + 20) as i64;
+    let tile_width = mem.read::<u32>(map + 24) as usize;
+    let tile_height = mem.read::<u32>(map + 28) as usize;
+
+    if tile_width == 0 || tile_height == 0 {
+        panic!("draw_tilemap: tile size must not be zero");
+    }
+
+    let tiles = mem.slice::<u16>(tiles_ptr, (map_width * map_height) as usize).to_vec();
+    let tileset_pixels = tileset.read_rect(&mem, 0, 0, tileset.width, tileset.height);
+    let tiles_per_row = tileset.width / tile_width;
+    let num_tiles = tiles_per_row * (tileset.height / tile_height);
+
+    let scroll_x = scroll_x.as_i32() as i64;
+    let scroll_y = scroll_y.as_i32() as i64;
+    let tw = tile_width as i64;
+    let th = tile_height as i64;
+
+    // Only visit the tiles that overlap the destination
+    let tx_min = scroll_x.div_euclid(tw).max(0);
+    let ty_min = scroll_y.div_euclid(th).max(0);
+    let tx_max = (scroll_x + dst.width as i64 + tw - 1).div_euclid(tw).min(map_width);
+    let ty_max = (scroll_y + dst.height as i64 + th - 1).div_euclid(th).min(map_height);
+
+    let dst_pixels = dst.pixels_mut(&mut mem);
+
+    for ty in ty_min..ty_max {
+        for tx in tx_min..tx_max {
+            let tile = tiles[(ty * map_width + tx) as usize];
+            if tile == TILE_EMPTY {
+                continue;
+            }
+
+            let tile = tile as usize;
+            if tile >= num_tiles {
+                panic!("draw_tilemap: tile index {} is outside of the tileset", tile);
+            }
+
+            let src = SrcRect {
+                pixels: &tileset_pixels,
+                stride: tileset.width,
+                x: (tile % tiles_per_row) * tile_width,
+                y: (tile / tiles_per_row) * tile_height,
+                width: tile_width,
+                height: tile_height,
+            };
+
+            draw_rect(&dst, dst_pixels, tx * tw - scroll_x, ty * th - scroll_y, &src, 0);
+        }
+    }
+}
+
+// Syscall to register a bitmap as an offscreen surface
+// i64 surface_id = surface_create(const bitmap_t* bitmap, u32 flags)
+pub fn surface_create(vm: &mut VM, bitmap: Value, flags: Value) -> Value
+{
+    let bitmap = Bitmap::read(&vm.mem_view(), bitmap.as_usize());
+    let flags = flags.as_u32();
+
+    if bitmap.width == 0 || bitmap.height == 0 {
+        return sys_err(EINVAL);
+    }
+
+    let surface_id = vm.sys_state.handles.insert(Surface { bitmap, flags });
+    Value::from(surface_id)
+}
+
+// Syscall to unregister a surface
+// i64 result = surface_destroy(u64 surface_id)
+pub fn surface_destroy(vm: &mut VM, surface_id: Value) -> Value
+{
+    match vm.sys_state.handles.remove::<Surface>(surface_id.as_u64()) {
+        Some(_) => Value::from(0),
+        None => sys_err(EBADF)
+    }
+}
+
+// Syscall to copy a surface into another
+// i64 result = surface_blit(u64 dst_id, u64 src_id, i32 x, i32 y)
+pub fn surface_blit(vm: &mut VM, dst_id: Value, src_id: Value, x: Value, y: Value) -> Value
+{
+    let handles = &vm.sys_state.handles;
+    let (dst, src) = match (handles.get::<Surface>(dst_id.as_u64()), handles.get::<Surface>(src_id.as_u64())) {
+        (Some(dst), Some(src)) => (dst.bitmap, *src),
+        _ => return sys_err(EBADF)
+    };
+
+    let flags = if src.flags & SURFACE_TRANSPARENT != 0 { 0 } else { SPRITE_OPAQUE };
+
+    // Copy the source pixels first, since they may overlap the destination
+    let mut mem = vm.mem_view();
+    let pixels = src.bitmap.read_rect(&mem, 0, 0, src.bitmap.width, src.bitmap.height);
+    let src_rect = SrcRect {
+        pixels: &pixels,
+        stride: src.bitmap.width,
+        x: 0,
+        y: 0,
+        width: src.bitmap.width,
+        height: src.bitmap.height,
+    };
+
+    let x = x.as_i32() as i64;
+    let y = y.as_i32() as i64;
+    draw_rect(&dst, dst.pixels_mut(&mut mem), x, y, &src_rect, flags);
+
+    Value::from(0)
+}
+
+/// Composite a non-premultiplied pixel over another
+fn blend_pixel(dst: u32, src: u32) -> u32
+{
+    let src_a = src >> 24;
+    if src_a == 255 {
+        return src;
+    }
+    if src_a == 0 {
+        return dst;
+    }
+
+    let dst_a = dst >> 24;
+    let inv_a = 255 - src_a;
+    let out_a = src_a + (dst_a * inv_a + 127) / 255;
+
+    // Weighted average of the channels, rounded to nearest
+    let mut out = out_a << 24;
+    for shift in [0, 8, 16] {
+        let src_c = (src >> shift) & 0xFF;
+        let dst_c = (dst >> shift) & 0xFF;
+        let num = src_c * src_a * 255 + dst_c * dst_a * inv_a;
+        let den = out_a * 255;
+        out |= ((num + den / 2) / den) << shift;
+    }
+
+    out
+}
+
+// Syscall to alpha blend a buffer of pixels over another
+// blend_over(u32* dst, const u32* src, u64 num_pixels)
+pub fn blend_over(vm: &mut VM, dst: Value, src: Value, num_pixels: Value)
+{
+    let num_pixels = num_pixels.as_usize();
+    let mut mem = vm.mem_view();
+    let src = mem.slice::<u32>(src.as_usize(), num_pixels).to_vec();
+    let dst = mem.slice_mut::<u32>(dst.as_usize(), num_pixels);
+
+    for (dst, src) in dst.iter_mut().zip(src) {
+        *dst = blend_pixel(*dst, src);
+    }
+}
+
+/// Convert a color from HSV to an opaque 32-bit pixel
+fn hsv_pixel(h: f32, s: f32, v: f32) -> u32
+{
+    let s = s.clamp(0.0, 1.0);
+    let v = v.clamp(0.0, 1.0);
+    let h = h.rem_euclid(360.0) / 60.0;
+
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let m = v - c;
+
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let to_u8 = |f: f32| ((f + m) * 255.0).round().clamp(0.0, 255.0) as u32;
+    0xFF00_0000 | (to_u8(r) << 16) | (to_u8(g) << 8) | to_u8(b)
+}
+
+// Syscall to convert an array of colors from HSV to RGB
+// hsv_to_rgb(u32* dst, const f32* hsv, u64 num_colors)
+pub fn hsv_to_rgb(vm: &mut VM, dst: Value, hsv: Value, num_colors: Value)
+{
+    let num_colors = num_colors.as_usize();
+    let mut mem = vm.mem_view();
+    let hsv = mem.slice::<f32>(hsv.as_usize(), 3 * num_colors).to_vec();
+    let dst = mem.slice_mut::<u32>(dst.as_usize(), num_colors);
+
+    for (dst, hsv) in dst.iter_mut().zip(hsv.chunks_exact(3)) {
+        *dst = hsv_pixel(hsv[0], hsv[1], hsv[2]);
+    }
+}
+
+// Syscall to apply a lookup table to the color channels of a bitmap region
+// apply_lut(const bitmap_t* bitmap, const rect_t* rect, const u8* lut)
+pub fn apply_lut(vm: &mut VM, bitmap: Value, rect: Value, lut: Value)
+{
+    let mut mem = vm.mem_view();
+    let bitmap = Bitmap::read(&mem, bitmap.as_usize());
+    let lut = mem.slice::<u8>(lut.as_usize(), 256).to_vec();
+
+    // rect_t { u32 x; u32 y; u32 width; u32 height; }, or null for the whole bitmap
+    let (x, y, width, height) = match rect.as_usize() {
+        0 => (0, 0, bitmap.width, bitmap.height),
+        rect => {
+            let rect = mem.slice::<u32>(rect, 4);
+            (rect[0] as usize, rect[1] as usize, rect[2] as usize, rect[3] as usize)
+        }
+    };
+
+    let x_max = x.saturating_add(width).min(bitmap.width);
+    let y_max = y.saturating_add(height).min(bitmap.height);
+    let pixels = bitmap.pixels_mut(&mut mem);
+
+    for row in y..y_max {
+        for pixel in &mut pixels[row * bitmap.width..][x.min(x_max)..x_max] {
+            let p = *pixel;
+            let r = lut[((p >> 16) & 0xFF) as usize] as u32;
+            let g = lut[((p >> 8) & 0xFF) as usize] as u32;
+            let b = lut[(p & 0xFF) as usize] as u32;
+            *pixel = (p & 0xFF00_0000) | (r << 16) | (g << 8) | b;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_blend_pixel()
+    {
+        // Fully opaque and fully transparent sources
+        assert_eq!(blend_pixel(0xFF0000FF, 0xFFFF0000), 0xFFFF0000);
+        assert_eq!(blend_pixel(0xFF0000FF, 0x00FF0000), 0xFF0000FF);
+
+        // Half-transparent red over opaque blue
+        assert_eq!(blend_pixel(0xFF0000FF, 0x80FF0000), 0xFF80007F);
+
+        // Over a transparent destination, the source color is kept
+        assert_eq!(blend_pixel(0x00000000, 0x80FF0000), 0x80FF0000);
+    }
+
+    #[test]
+    fn test_draw_rect_offscreen()
+    {
+        let dst = Bitmap { pixels: 0, width: 4, height: 4 };
+        let src_pixels = [0xFF00_0001u32; 4];
+        let src = SrcRect { pixels: &src_pixels, stride: 2, x: 0, y: 0, width: 2, height: 2 };
+
+        // Rectangles entirely outside of the bitmap draw nothing
+        for flags in [0, SPRITE_OPAQUE, SPRITE_OPAQUE | SPRITE_FLIP_X, SPRITE_FLIP_X | SPRITE_FLIP_Y] {
+            let mut pixels = [0u32; 16];
+            for (x, y) in [(10, 0), (-2, 0), (-5, 1), (0, 4), (0, -2), (4, 4), (i32::MAX as i64, 0)] {
+                draw_rect(&dst, &mut pixels, x, y, &src, flags);
+            }
+            assert_eq!(pixels, [0; 16]);
+
+            // Partially visible rectangles are clipped
+            draw_rect(&dst, &mut pixels, -1, 3, &src, flags);
+            assert_eq!(pixels.iter().filter(|&&p| p != 0).count(), 1);
+            assert_ne!(pixels[12], 0);
+        }
+    }
+
+    #[test]
+    fn test_hsv_pixel()
+    {
+        assert_eq!(hsv_pixel(0.0, 1.0, 1.0), 0xFFFF0000);
+        assert_eq!(hsv_pixel(120.0, 1.0, 1.0), 0xFF00FF00);
+        assert_eq!(hsv_pixel(240.0, 1.0, 0.5), 0xFF000080);
+        assert_eq!(hsv_pixel(360.0, 1.0, 1.0), 0xFFFF0000);
+        assert_eq!(hsv_pixel(-60.0, 1.0, 1.0), 0xFFFF00FF);
+        assert_eq!(hsv_pixel(77.0, 0.0, 1.0), 0xFFFFFFFF);
+    }
+}
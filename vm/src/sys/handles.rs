@@ -0,0 +1,146 @@
+use std::any::Any;
+
+// Table of the host resources (sockets, surfaces, ...) that the guest
+// refers to by handle.
+//
+// A handle packs the index of a slot in the table with the generation
+// of that slot, which is incremented each time the slot is freed. A
+// handle that was closed stays invalid even once its slot gets reused,
+// and looking up a handle checks the type of the resource, so a stale
+// handle or a handle of the wrong kind is reported as an error instead
+// of silently referring to another resource.
+
+/// Bits of the handle used for the slot index
+const INDEX_BITS: u32 = 32;
+
+/// Generations wrap around before reaching the sign bit,
+/// so that handles can't be mistaken for error codes
+const MAX_GENERATION: u64 = (1 << 31) - 1;
+
+struct Slot
+{
+    generation: u64,
+    resource: Option<Box<dyn Any + Send>>,
+}
+
+#[derive(Default)]
+pub struct HandleTable
+{
+    slots: Vec<Slot>,
+
+    /// Indices of the free slots
+    free: Vec<usize>,
+}
+
+impl HandleTable
+{
+    fn handle(index: usize, generation: u64) -> u64
+    {
+        (generation << INDEX_BITS) | index as u64
+    }
+
+    /// Find the index of the slot a handle refers to,
+    /// if the handle is still valid
+    fn slot_index(&self, handle: u64) -> Option<usize>
+    {
+        let index = (handle & ((1 << INDEX_BITS) - 1)) as usize;
+        let slot = self.slots.get(index)?;
+
+        if Self::handle(index, slot.generation) != handle {
+            return None;
+        }
+
+        Some(index)
+    }
+
+    /// Add a resource to the table and return a new handle for it
+    /// Handles are never 0
+    pub fn insert<T: Any + Send>(&mut self, resource: T) -> u64
+    {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot { generation: 1, resource: None });
+                self.slots.len() - 1
+            }
+        };
+
+        let slot = &mut self.slots[index];
+        slot.resource = Some(Box::new(resource));
+        Self::handle(index, slot.generation)
+    }
+
+    /// Check if a handle refers to a resource of a given type
+    pub fn contains<T: Any>(&self, handle: u64) -> bool
+    {
+        self.get::<T>(handle).is_some()
+    }
+
+    pub fn get<T: Any>(&self, handle: u64) -> Option<&T>
+    {
+        let index = self.slot_index(handle)?;
+        self.slots[index].resource.as_ref()?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any>(&mut self, handle: u64) -> Option<&mut T>
+    {
+        let index = self.slot_index(handle)?;
+        self.slots[index].resource.as_mut()?.downcast_mut()
+    }
+
+    /// Remove a resource of a given type from the table,
+    /// which invalidates its handle
+    pub fn remove<T: Any>(&mut self, handle: u64) -> Option<T>
+    {
+        if !self.contains::<T>(handle) {
+            return None;
+        }
+
+        let index = self.slot_index(handle)?;
+        let slot = &mut self.slots[index];
+        let resource = slot.resource.take().unwrap();
+
+        slot.generation += 1;
+        if slot.generation > MAX_GENERATION {
+            slot.generation = 1;
+        }
+        self.free.push(index);
+
+        Some(*resource.downcast().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_handles()
+    {
+        let mut table = HandleTable::default();
+        let a = table.insert(1u32);
+        let b = table.insert("b");
+        assert!(a != 0 && b != 0 && a != b);
+        assert!((a as i64) > 0 && (b as i64) > 0);
+
+        assert_eq!(table.get::<u32>(a), Some(&1));
+        assert_eq!(table.get::<&str>(b), Some(&"b"));
+        *table.get_mut::<u32>(a).unwrap() = 2;
+        assert_eq!(table.get::<u32>(a), Some(&2));
+
+        // Handles of the wrong type are rejected
+        assert_eq!(table.get::<u32>(b), None);
+        assert_eq!(table.remove::<&str>(a), None);
+        assert_eq!(table.get::<u32>(0), None);
+        assert_eq!(table.get::<u32>(12345), None);
+
+        // Stale handles stay invalid when their slot is reused
+        assert_eq!(table.remove::<u32>(a), Some(2));
+        assert_eq!(table.remove::<u32>(a), None);
+        let c = table.insert(3u32);
+        assert!(c != a);
+        assert_eq!(table.get::<u32>(a), None);
+        assert_eq!(table.get::<u32>(c), Some(&3));
+    }
+}
@@ -0,0 +1,341 @@
+// Heap allocator provided to the guest by the mem syscalls
+//
+// The allocator keeps its metadata on the host, so that the guest can't
+// corrupt it, and manages memory at the end of the heap, which it grows as
+// needed, up to the maximum heap size. Memory below the heap size at the
+// time of an allocation may be in use by the program, so the allocator never
+// hands it out, even when the heap was grown by something else than the
+// allocator. Each block is
+// preceded by an unused 8-byte header, which serves as a redzone
+// in --asan mode, and the blocks are tracked as with vm_track_alloc.
+
+use std::collections::BTreeMap;
+use crate::vm::{VM, Value};
+
+// Alignment of the blocks, enough for any value the guest can load
+const ALIGN: usize = 8;
+
+// Space left before each block
+const HEADER_SIZE: usize = 8;
+
+#[derive(Default)]
+pub struct Allocator
+{
+    /// Size of the chunk of each allocated block, including
+    /// its header, indexed by the address of the block
+    blocks: BTreeMap<usize, usize>,
+
+    /// Size of the free chunks, indexed by start address,
+    /// adjacent free chunks are always merged
+    free: BTreeMap<usize, usize>,
+
+    /// End of the memory managed by the allocator
+    end: usize,
+}
+
+/// Size of the chunk holding a block of a given size
+fn chunk_size(num_bytes: usize) -> Option<usize>
+{
+    let size = num_bytes.max(1).checked_add(HEADER_SIZE + ALIGN - 1)?;
+    Some(size & !(ALIGN - 1))
+}
+
+impl Allocator
+{
+    /// End of the memory managed by the allocator, which can be
+    /// past the end of the heap after an allocation, in which
+    /// case the heap must be resized and extend() called
+    pub fn end(&self) -> usize
+    {
+        self.end
+    }
+
+    /// Hand over the memory from the end of the allocator to the end
+    /// of the heap, after the heap was resized for an allocation
+    pub fn extend(&mut self, heap_size: usize)
+    {
+        if heap_size > self.end {
+            self.insert_free(self.end, heap_size - self.end);
+            self.end = heap_size;
+        }
+    }
+
+    /// Usable size of an allocated block, None if
+    /// the address isn't the start of a block
+    pub fn block_size(&self, ptr: usize) -> Option<usize>
+    {
+        self.blocks.get(&ptr).map(|size| size - HEADER_SIZE)
+    }
+
+    /// Add a free chunk, merging it with its neighbors
+    fn insert_free(&mut self, mut start: usize, mut size: usize)
+    {
+        if let Some((&prev_start, &prev_size)) = self.free.range(..start).next_back() {
+            if prev_start + prev_size == start {
+                self.free.remove(&prev_start);
+                start = prev_start;
+                size += prev_size;
+            }
+        }
+
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+
+        self.free.insert(start, size);
+    }
+
+    /// Extend the memory managed by the allocator, without going past
+    /// max_size, so that it ends with a free chunk of a given size,
+    /// and get the start of that chunk
+    fn grow(&mut self, chunk_size: usize, heap_size: usize, max_size: usize) -> Option<usize>
+    {
+        let end = std::cmp::max(self.end, heap_size);
+
+        let start = match self.free.last_key_value() {
+            Some((&start, &size)) if start + size == end => start,
+            _ => end,
+        };
+
+        self.end = start.checked_add(chunk_size).filter(|&end| end <= max_size)?;
+        self.free.insert(start, chunk_size);
+        Some(start)
+    }
+
+    /// Allocate a block of num_bytes, using the first free chunk large
+    /// enough to hold it. Returns None if the heap would grow past max_size.
+    pub fn alloc(&mut self, num_bytes: usize, heap_size: usize, max_size: usize) -> Option<usize>
+    {
+        let chunk_size = chunk_size(num_bytes)?;
+
+        let free_chunk = self.free.iter().find(|(_, &size)| size >= chunk_size).map(|(&start, _)| start);
+        let start = match free_chunk {
+            Some(start) => start,
+            None => self.grow(chunk_size, heap_size, max_size)?,
+        };
+
+        let free_size = self.free.remove(&start).unwrap();
+        if free_size > chunk_size {
+            self.free.insert(start + chunk_size, free_size - chunk_size);
+        }
+
+        let ptr = start + HEADER_SIZE;
+        self.blocks.insert(ptr, chunk_size);
+        Some(ptr)
+    }
+
+    /// Free a block, returns false if the address isn't the start of a block
+    pub fn free(&mut self, ptr: usize) -> bool
+    {
+        match self.blocks.remove(&ptr) {
+            Some(chunk_size) => {
+                self.insert_free(ptr - HEADER_SIZE, chunk_size);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Change the size of an allocated block, in place if possible,
+    /// and get its new address. The contents of a block that moved
+    /// still have to be copied. Returns None if the block can't grow.
+    pub fn resize(&mut self, ptr: usize, num_bytes: usize, heap_size: usize, max_size: usize) -> Option<usize>
+    {
+        let new_size = chunk_size(num_bytes)?;
+        let old_size = self.blocks[&ptr];
+        let start = ptr - HEADER_SIZE;
+        let next = start + old_size;
+
+        if new_size <= old_size {
+            if new_size < old_size {
+                self.insert_free(start + new_size, old_size - new_size);
+            }
+            self.blocks.insert(ptr, new_size);
+            return Some(ptr);
+        }
+
+        // Grow the block into the free chunk after it
+        if let Some(&next_size) = self.free.get(&next) {
+            if old_size + next_size >= new_size {
+                self.free.remove(&next);
+                if old_size + next_size > new_size {
+                    self.free.insert(start + new_size, old_size + next_size - new_size);
+                }
+                self.blocks.insert(ptr, new_size);
+                return Some(ptr);
+            }
+        }
+
+        // Grow the last block along with the heap
+        if next == self.end && heap_size <= self.end {
+            if let Some(end) = start.checked_add(new_size).filter(|&end| end <= max_size) {
+                self.end = end;
+                self.blocks.insert(ptr, new_size);
+                return Some(ptr);
+            }
+        }
+
+        let new_ptr = self.alloc(num_bytes, heap_size, max_size)?;
+        self.free(ptr);
+        Some(new_ptr)
+    }
+}
+
+/// Resize the heap if the allocator needs more memory. This can only
+/// fail if the host is out of memory, since the allocator doesn't grow
+/// past the maximum heap size.
+fn grow_heap(vm: &mut VM)
+{
+    let end = vm.sys_state.allocator.end();
+
+    if end > vm.heap_size() {
+        let heap_size = vm.try_resize_heap(end).expect("out of memory");
+        vm.sys_state.allocator.extend(heap_size);
+    }
+}
+
+// void* ptr = mem_alloc(u64 num_bytes)
+pub fn mem_alloc(vm: &mut VM, num_bytes: Value) -> Value
+{
+    let num_bytes = num_bytes.as_usize();
+    let heap_size = vm.heap_size();
+    let max_size = vm.max_heap_size();
+
+    let ptr = match vm.sys_state.allocator.alloc(num_bytes, heap_size, max_size) {
+        Some(ptr) => ptr,
+        None => return Value::from(0),
+    };
+    grow_heap(vm);
+
+    let pc = vm.caller_pc();
+    if let Some(asan) = vm.asan() {
+        asan.on_alloc(ptr, num_bytes, pc);
+    }
+
+    Value::from(ptr)
+}
+
+// void mem_free(void* ptr)
+pub fn mem_free(vm: &mut VM, ptr: Value)
+{
+    let ptr = ptr.as_usize();
+
+    // Freeing a null pointer does nothing
+    if ptr == 0 {
+        return;
+    }
+
+    // In --asan mode, double frees are reported with the pcs
+    // where the block was allocated and freed
+    let pc = vm.caller_pc();
+    if let Some(asan) = vm.asan() {
+        if let Err(msg) = asan.on_free(ptr, pc) {
+            panic!("{}", msg);
+        }
+    }
+
+    if !vm.sys_state.allocator.free(ptr) {
+        panic!("mem_free: invalid free of address 0x{:x}, which is not the start of an allocated block", ptr);
+    }
+}
+
+// void* new_ptr = mem_resize(void* ptr, u64 num_bytes)
+pub fn mem_resize(vm: &mut VM, ptr: Value, num_bytes: Value) -> Value
+{
+    let ptr = ptr.as_usize();
+    let num_bytes = num_bytes.as_usize();
+
+    // Resizing a null pointer allocates a new block
+    if ptr == 0 {
+        return mem_alloc(vm, Value::from(num_bytes));
+    }
+
+    let old_size = match vm.sys_state.allocator.block_size(ptr) {
+        Some(size) => size,
+        None => panic!("mem_resize: address 0x{:x} is not the start of an allocated block", ptr),
+    };
+
+    let heap_size = vm.heap_size();
+    let max_size = vm.max_heap_size();
+    let new_ptr = match vm.sys_state.allocator.resize(ptr, num_bytes, heap_size, max_size) {
+        Some(new_ptr) => new_ptr,
+        None => return Value::from(0),
+    };
+    grow_heap(vm);
+
+    if new_ptr != ptr {
+        vm.mem_view().copy(new_ptr, ptr, std::cmp::min(old_size, num_bytes));
+    }
+
+    // The old block is freed even if it didn't move, so that
+    // the end of a block which shrank isn't addressable
+    let pc = vm.caller_pc();
+    if let Some(asan) = vm.asan() {
+        if let Err(msg) = asan.on_free(ptr, pc) {
+            panic!("{}", msg);
+        }
+        asan.on_alloc(new_ptr, num_bytes, pc);
+    }
+
+    Value::from(new_ptr)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_allocator()
+    {
+        let mut alloc = Allocator::default();
+        let heap_size = 4096;
+
+        // Blocks are placed after the heap and never overlap
+        let a = alloc.alloc(10, heap_size, usize::MAX).unwrap();
+        let b = alloc.alloc(0, heap_size, usize::MAX).unwrap();
+        let c = alloc.alloc(100, heap_size, usize::MAX).unwrap();
+        assert_eq!(a, heap_size + HEADER_SIZE);
+        assert_eq!(b, a + 16 + HEADER_SIZE);
+        assert!(c >= b + 8 + HEADER_SIZE);
+        assert!(c % ALIGN == 0);
+        assert_eq!(alloc.end(), c + 104);
+
+        // Freed chunks are merged and reused
+        assert!(alloc.free(a));
+        assert!(alloc.free(b));
+        assert!(!alloc.free(b));
+        assert_eq!(alloc.alloc(24, heap_size, usize::MAX), Some(a));
+        assert_eq!(alloc.block_size(a), Some(24));
+
+        // Memory added to the heap by the program isn't used
+        alloc.extend(8192);
+        assert!(alloc.alloc(8192, 9000, usize::MAX).unwrap() >= 9000 + HEADER_SIZE);
+
+        // The heap can't grow past the maximum size
+        assert_eq!(alloc.alloc(10000, 9000, 16384), None);
+        assert_eq!(alloc.alloc(usize::MAX - 4, heap_size, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_allocator_resize()
+    {
+        let mut alloc = Allocator::default();
+        let a = alloc.alloc(16, 0, usize::MAX).unwrap();
+        let b = alloc.alloc(16, 0, usize::MAX).unwrap();
+
+        // The last block grows in place, along with the heap
+        assert_eq!(alloc.resize(b, 100, 0, usize::MAX), Some(b));
+        assert_eq!(alloc.end(), b + 104);
+
+        // Shrinking frees the end of the block, which it can grow into again
+        assert_eq!(alloc.resize(b, 8, 0, usize::MAX), Some(b));
+        assert_eq!(alloc.resize(b, 64, 0, usize::MAX), Some(b));
+
+        // Blocks move when the memory after them is in use
+        let c = alloc.resize(a, 32, 0, usize::MAX).unwrap();
+        assert!(c > b);
+        assert_eq!(alloc.block_size(a), None);
+        assert_eq!(alloc.alloc(8, 0, usize::MAX), Some(a));
+    }
+}
@@ -1,20 +1,58 @@
+pub mod mem;
+pub mod console;
 pub mod window;
+pub mod graphics;
+pub mod pad;
 pub mod audio;
 pub mod net;
+pub mod fs;
+pub mod vfs;
+pub mod archive;
 pub mod time;
+pub mod event;
+pub mod trace;
+pub mod proc;
+pub mod module;
+pub mod thread;
+pub mod sync;
+pub mod compress;
+pub mod crypto;
+pub mod str;
+pub mod quota;
+pub mod handles;
 pub mod constants;
 
 extern crate sdl2;
 use std::collections::HashMap;
 use std::io::Write;
 use std::io::Read;
+use std::io::BufRead;
 use std::io::{stdout, stdin};
 use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use crate::vm::{Value, VM};
+use crate::utils::{thousands_sep, vm_version};
+use mem::*;
+use console::*;
 use window::*;
+use graphics::*;
+use pad::*;
 use audio::*;
 use net::*;
+use fs::*;
 use time::*;
+use event::*;
+use trace::*;
+use proc::*;
+use module::*;
+use thread::*;
+use sync::*;
+use compress::*;
+use crypto::*;
+use str::*;
+use quota::*;
+use handles::*;
 use constants::*;
 
 /// System call function signature
@@ -57,7 +95,7 @@ impl SysCallFn
         }
     }
 
-    fn has_ret(&self) -> bool
+    pub fn has_ret(&self) -> bool
     {
         match self {
             Self::Fn0_0(_) => false,
@@ -72,6 +110,26 @@ impl SysCallFn
             Self::Fn4_1(_) => true,
         }
     }
+
+    /// Call the function with its arguments taken from an array,
+    /// producing 0 for functions which don't return a value
+    pub fn call(&self, vm: &mut VM, args: [Value; 4]) -> Value
+    {
+        let [a0, a1, a2, a3] = args;
+
+        match *self {
+            Self::Fn0_0(fun) => { fun(vm); Value::from(0) }
+            Self::Fn0_1(fun) => fun(vm),
+            Self::Fn1_0(fun) => { fun(vm, a0); Value::from(0) }
+            Self::Fn1_1(fun) => fun(vm, a0),
+            Self::Fn2_0(fun) => { fun(vm, a0, a1); Value::from(0) }
+            Self::Fn2_1(fun) => fun(vm, a0, a1),
+            Self::Fn3_0(fun) => { fun(vm, a0, a1, a2); Value::from(0) }
+            Self::Fn3_1(fun) => fun(vm, a0, a1, a2),
+            Self::Fn4_0(fun) => { fun(vm, a0, a1, a2, a3); Value::from(0) }
+            Self::Fn4_1(fun) => fun(vm, a0, a1, a2, a3),
+        }
+    }
 }
 
 /// SDL context (used for UI and audio)
@@ -92,6 +150,85 @@ pub fn get_sdl_context() -> &'static mut sdl2::Sdl
     }
 }
 
+/// Value returned by a syscall that fails with a given error code
+/// Error codes are positive, and are negated when returned to the guest
+pub fn sys_err(code: i64) -> Value
+{
+    Value::from(-code)
+}
+
+/// Map a host I/O error to the closest guest error code
+pub fn io_err_code(error: &std::io::Error) -> i64
+{
+    use std::io::ErrorKind::*;
+
+    match error.kind() {
+        NotFound => ENOENT,
+        PermissionDenied => EACCES,
+        AlreadyExists => EEXIST,
+        NotADirectory => ENOTDIR,
+        IsADirectory => EISDIR,
+        StorageFull => ENOSPC,
+        ReadOnlyFilesystem => EROFS,
+        DirectoryNotEmpty => ENOTEMPTY,
+        ResourceBusy => EBUSY,
+        WouldBlock => EAGAIN,
+        InvalidInput => EINVAL,
+        OutOfMemory => ENOMEM,
+        Unsupported => ENOSYS,
+        AddrInUse => EADDRINUSE,
+        ConnectionRefused => ECONNREFUSED,
+        ConnectionReset | ConnectionAborted | BrokenPipe => ECONNRESET,
+        NotConnected => ENOTCONN,
+        TimedOut => ETIMEDOUT,
+        _ => EIO,
+    }
+}
+
+/// Call count and timing information for one syscall
+#[derive(Copy, Clone, Default)]
+pub struct SysCallStats
+{
+    pub count: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+/// Exit code used when execution is stopped by SIGINT (128 + signal number)
+pub const SIGINT_EXIT_CODE: i64 = 130;
+
+/// Set by the SIGINT handler to ask the VM to stop at the next instruction boundary
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn sigint_handler(sig: libc::c_int)
+{
+    // If a stop was already requested and the VM didn't react, for example
+    // because it is blocked inside a syscall, terminate immediately
+    if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(SIGINT_EXIT_CODE as libc::c_int); }
+    }
+}
+
+/// Install a SIGINT (ctrl-C) handler that requests a clean VM shutdown
+pub fn install_sigint_handler()
+{
+    unsafe {
+        libc::signal(libc::SIGINT, sigint_handler as *const () as libc::sighandler_t);
+    }
+}
+
+/// Check if the host has requested that execution stop
+pub fn stop_requested() -> bool
+{
+    STOP_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Allow execution to resume after a stop request, e.g. to run exit callbacks
+pub fn clear_stop_request()
+{
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+}
+
 pub struct SysState
 {
     /// Map of indices to syscall functions
@@ -100,11 +237,53 @@ pub struct SysState
     /// Weak reference to a mutex for the VM
     mutex: Weak<Mutex<VM>>,
 
+    /// Heap allocator of the mem subsystem
+    pub allocator: Allocator,
+
     /// Time subsystem state
     pub time_state: TimeState,
 
     /// Network subsystem state
     pub net_state: NetState,
+
+    /// Events without a callback, dequeued with event_poll
+    pub event_queue: EventQueue,
+
+    /// Filesystem subsystem state
+    pub fs_state: FsState,
+
+    /// Process subsystem state
+    pub proc_state: ProcState,
+
+    /// Spans recorded with --trace-out
+    pub trace_state: TraceState,
+
+    /// Module subsystem state
+    pub module_state: ModuleState,
+
+    /// Thread subsystem state
+    pub thread_state: ThreadState,
+
+    /// Resources the guest refers to by handle
+    pub handles: HandleTable,
+
+    /// Resource quotas for untrusted programs
+    pub quotas: Quotas,
+
+    /// Pipe mode enabled with --stdio, where the text
+    /// output of the guest isn't buffered
+    pub stdio_mode: bool,
+
+    /// Allow window_on_dropfile, which reveals host paths to
+    /// the guest, enabled with --allow-dropfile
+    pub allow_dropfile: bool,
+
+    /// Text output of the guest, kept here instead of being written
+    /// to stdout, so that runs of a program can be compared
+    pub captured_output: Option<Vec<u8>>,
+
+    /// Per-syscall statistics, only collected when enabled
+    syscall_stats: Option<Vec<SysCallStats>>,
 }
 
 impl SysState
@@ -114,8 +293,21 @@ impl SysState
         let mut sys_state = Self {
             syscalls: [None; SYSCALL_TBL_LEN],
             mutex: Weak::new(),
+            allocator: Allocator::default(),
             time_state: TimeState::new(),
             net_state: NetState::default(),
+            event_queue: EventQueue::default(),
+            fs_state: FsState::default(),
+            proc_state: ProcState::new(),
+            trace_state: TraceState::default(),
+            module_state: ModuleState::default(),
+            thread_state: ThreadState::default(),
+            handles: HandleTable::default(),
+            quotas: Quotas::default(),
+            stdio_mode: false,
+            allow_dropfile: false,
+            captured_output: None,
+            syscall_stats: None,
         };
 
         sys_state.init_syscalls();
@@ -152,16 +344,101 @@ impl SysState
         self.syscalls[const_idx as usize] = Some(fun);
     }
 
-    /// Get the syscall with a given index
-    pub fn get_syscall(&self, const_idx: u16) -> SysCallFn
+    /// Remove the syscalls which require a permission, so that the guest
+    /// can't use them. They then trap as unknown syscalls, and
+    /// sys_has_syscall reports that they are missing.
+    pub fn deny_permission(&mut self, perm: &str) -> Result<(), String>
     {
-        if let Some(syscall_fn) = self.syscalls[const_idx as usize] {
-            return syscall_fn;
+        let descs = SYSCALL_DESCS.iter().flatten().filter(|desc| desc.permission == perm);
+
+        if perm == "default_allowed" || descs.clone().next().is_none() {
+            return Err(format!("unknown permission {}", perm));
         }
-        else
-        {
-            panic!("unknown syscall \"{}\"", const_idx);
+
+        for desc in descs {
+            self.syscalls[desc.const_idx as usize] = None;
         }
+
+        Ok(())
+    }
+
+    /// Get the function of a syscall, if the VM implements it
+    pub fn find_syscall(&self, const_idx: u16) -> Option<SysCallFn>
+    {
+        self.syscalls.get(const_idx as usize).copied().flatten()
+    }
+
+    /// Start collecting per-syscall call counts and timings
+    pub fn enable_syscall_stats(&mut self)
+    {
+        self.syscall_stats = Some(vec![SysCallStats::default(); SYSCALL_TBL_LEN]);
+    }
+
+    pub fn syscall_stats_enabled(&self) -> bool
+    {
+        self.syscall_stats.is_some()
+    }
+
+    /// Record the time taken by one call to a syscall
+    pub fn record_syscall(&mut self, const_idx: u16, time: Duration)
+    {
+        if let Some(stats) = &mut self.syscall_stats {
+            let entry = &mut stats[const_idx as usize];
+            entry.count += 1;
+            entry.total_time += time;
+            entry.max_time = std::cmp::max(entry.max_time, time);
+        }
+    }
+
+    /// Print a summary of the syscall statistics, most expensive first
+    pub fn print_syscall_stats(&self, run_time: Duration)
+    {
+        let stats = match &self.syscall_stats {
+            Some(stats) => stats,
+            None => return,
+        };
+
+        let mut entries: Vec<(usize, &SysCallStats)> = stats
+            .iter()
+            .enumerate()
+            .filter(|(idx, entry)| entry.count > 0)
+            .collect();
+        entries.sort_by_key(|(idx, entry)| std::cmp::Reverse(entry.total_time));
+
+        let total_time: Duration = entries.iter().map(|(idx, entry)| entry.total_time).sum();
+        let total_count: u64 = entries.iter().map(|(idx, entry)| entry.count).sum();
+
+        eprintln!();
+        eprintln!("{:<24} {:>12} {:>12} {:>12} {:>12}", "syscall", "calls", "total ms", "avg us", "max us");
+
+        for (idx, entry) in entries {
+            let name = SYSCALL_DESCS[idx].as_ref().unwrap().name;
+            let avg_us = entry.total_time.as_secs_f64() * 1_000_000.0 / entry.count as f64;
+
+            eprintln!(
+                "{:<24} {:>12} {:>12.3} {:>12.1} {:>12.1}",
+                name,
+                thousands_sep(entry.count),
+                entry.total_time.as_secs_f64() * 1000.0,
+                avg_us,
+                entry.max_time.as_secs_f64() * 1_000_000.0,
+            );
+        }
+
+        // Report how much of the run time was spent inside the host
+        let pct = if run_time.is_zero() {
+            0.0
+        } else {
+            100.0 * total_time.as_secs_f64() / run_time.as_secs_f64()
+        };
+
+        eprintln!(
+            "{} syscalls, {:.3} ms in syscalls, {:.1}% of {:.3} ms total run time",
+            thousands_sep(total_count),
+            total_time.as_secs_f64() * 1000.0,
+            pct,
+            run_time.as_secs_f64() * 1000.0,
+        );
     }
 
     fn init_syscalls(&mut self)
@@ -171,37 +448,139 @@ impl SysState
         // Core VM syscalls
         self.reg_syscall(VM_HEAP_SIZE, SysCallFn::Fn0_1(vm_heap_size));
         self.reg_syscall(VM_RESIZE_HEAP, SysCallFn::Fn1_1(vm_resize_heap));
+        self.reg_syscall(VM_GROW_HEAP, SysCallFn::Fn1_1(vm_grow_heap));
+        self.reg_syscall(VM_TRACK_ALLOC, SysCallFn::Fn2_1(vm_track_alloc));
+        self.reg_syscall(VM_TRACK_FREE, SysCallFn::Fn1_1(vm_track_free));
+        self.reg_syscall(VM_HEAP_WALK, SysCallFn::Fn2_1(vm_heap_walk));
+        self.reg_syscall(VM_BACKTRACE, SysCallFn::Fn2_1(vm_backtrace));
+        self.reg_syscall(VM_YIELD, SysCallFn::Fn0_0(vm_yield));
+        self.reg_syscall(VM_SET_TRAP_HANDLER, SysCallFn::Fn1_0(vm_set_trap_handler));
+        self.reg_syscall(VM_MEM_LAYOUT, SysCallFn::Fn1_0(vm_mem_layout));
+        self.reg_syscall(VM_MEM_PROTECT, SysCallFn::Fn3_1(vm_mem_protect));
+        self.reg_syscall(VM_MEM_QUERY, SysCallFn::Fn1_1(vm_mem_query));
+        self.reg_syscall(SYS_VERSION, SysCallFn::Fn0_1(sys_version));
+        self.reg_syscall(SYS_HAS_SYSCALL, SysCallFn::Fn1_1(sys_has_syscall));
+        self.reg_syscall(SYS_BATCH, SysCallFn::Fn2_1(sys_batch));
         self.reg_syscall(MEMSET, SysCallFn::Fn3_0(memset));
         self.reg_syscall(MEMSET32, SysCallFn::Fn3_0(memset32));
         self.reg_syscall(MEMCPY, SysCallFn::Fn3_0(memcpy));
         self.reg_syscall(MEMCMP, SysCallFn::Fn3_1(memcmp));
 
+        self.reg_syscall(MEM_ALLOC, SysCallFn::Fn1_1(mem_alloc));
+        self.reg_syscall(MEM_FREE, SysCallFn::Fn1_0(mem_free));
+        self.reg_syscall(MEM_RESIZE, SysCallFn::Fn2_1(mem_resize));
+
         self.reg_syscall(PRINT_I64, SysCallFn::Fn1_0(print_i64));
         self.reg_syscall(PRINT_F32, SysCallFn::Fn1_0(print_f32));
+        self.reg_syscall(PRINT_F64, SysCallFn::Fn1_0(print_f64));
         self.reg_syscall(PRINT_STR, SysCallFn::Fn1_0(print_str));
         self.reg_syscall(PRINT_ENDL, SysCallFn::Fn0_0(print_endl));
         self.reg_syscall(PUTCHAR, SysCallFn::Fn1_1(putchar));
+        self.reg_syscall(IO_WRITE, SysCallFn::Fn2_1(io_write));
         self.reg_syscall(GETCHAR, SysCallFn::Fn0_1(getchar));
+        self.reg_syscall(READ_LINE, SysCallFn::Fn2_1(read_line));
+        self.reg_syscall(CONSOLE_CLEAR, SysCallFn::Fn0_0(console_clear));
+        self.reg_syscall(CONSOLE_MOVE_CURSOR, SysCallFn::Fn2_0(console_move_cursor));
+        self.reg_syscall(CONSOLE_SET_STYLE, SysCallFn::Fn3_0(console_set_style));
+        self.reg_syscall(CONSOLE_SHOW_CURSOR, SysCallFn::Fn1_0(console_show_cursor));
+        self.reg_syscall(CONSOLE_SIZE, SysCallFn::Fn0_1(console_size));
+        self.reg_syscall(CONSOLE_SET_RAW, SysCallFn::Fn1_1(console_set_raw));
+        self.reg_syscall(CONSOLE_READ_KEY, SysCallFn::Fn1_1(console_read_key));
+
+        self.reg_syscall(PROC_ATEXIT, SysCallFn::Fn1_0(proc_atexit));
+        self.reg_syscall(PROC_SPAWN, SysCallFn::Fn4_1(proc_spawn));
+        self.reg_syscall(PROC_ARGC, SysCallFn::Fn0_1(proc_argc));
+        self.reg_syscall(PROC_ARGV, SysCallFn::Fn0_1(proc_argv));
+
+        self.reg_syscall(MODULE_LOAD, SysCallFn::Fn1_1(module_load));
+        self.reg_syscall(MODULE_SYMBOL, SysCallFn::Fn1_1(module_symbol));
+
+        self.reg_syscall(TLS_ALLOC, SysCallFn::Fn2_1(tls_alloc));
+        self.reg_syscall(TLS_BASE, SysCallFn::Fn0_1(tls_base));
+        self.reg_syscall(THREAD_SPAWN, SysCallFn::Fn2_1(thread_spawn));
+        self.reg_syscall(THREAD_JOIN, SysCallFn::Fn1_1(thread_join));
+        self.reg_syscall(THREAD_ID, SysCallFn::Fn0_1(thread_id));
+
+        self.reg_syscall(MUTEX_CREATE, SysCallFn::Fn0_1(mutex_create));
+        self.reg_syscall(MUTEX_LOCK, SysCallFn::Fn1_0(mutex_lock));
+        self.reg_syscall(MUTEX_UNLOCK, SysCallFn::Fn1_0(mutex_unlock));
+        self.reg_syscall(COND_CREATE, SysCallFn::Fn0_1(cond_create));
+        self.reg_syscall(COND_WAIT, SysCallFn::Fn2_0(cond_wait));
+        self.reg_syscall(COND_SIGNAL, SysCallFn::Fn1_0(cond_signal));
+        self.reg_syscall(PIPE_CREATE, SysCallFn::Fn2_1(pipe_create));
+        self.reg_syscall(PIPE_WRITE, SysCallFn::Fn3_1(pipe_write));
+        self.reg_syscall(PIPE_READ, SysCallFn::Fn3_1(pipe_read));
+        self.reg_syscall(PIPE_CLOSE, SysCallFn::Fn1_1(pipe_close));
 
         self.reg_syscall(TIME_CURRENT_MS, SysCallFn::Fn0_1(time_current_ms));
         self.reg_syscall(TIME_DELAY_CB, SysCallFn::Fn2_0(time_delay_cb));
+        self.reg_syscall(TIME_INTERVAL_CB, SysCallFn::Fn2_1(time_interval_cb));
+        self.reg_syscall(TIME_CANCEL_CB, SysCallFn::Fn1_1(time_cancel_cb));
+        self.reg_syscall(EVENT_POLL, SysCallFn::Fn1_1(event_poll));
+        self.reg_syscall(TRACE_BEGIN, SysCallFn::Fn1_0(trace_begin));
+        self.reg_syscall(TRACE_END, SysCallFn::Fn0_0(trace_end));
 
         self.reg_syscall(WINDOW_CREATE, SysCallFn::Fn4_1(window_create));
-        self.reg_syscall(WINDOW_DRAW_FRAME, SysCallFn::Fn2_0(window_draw_frame));
-        self.reg_syscall(WINDOW_ON_MOUSEMOVE, SysCallFn::Fn2_0(window_on_mousemove));
-        self.reg_syscall(WINDOW_ON_MOUSEDOWN, SysCallFn::Fn2_0(window_on_mousedown));
-        self.reg_syscall(WINDOW_ON_MOUSEUP, SysCallFn::Fn2_0(window_on_mouseup));
-        self.reg_syscall(WINDOW_ON_KEYDOWN, SysCallFn::Fn2_0(window_on_keydown));
-        self.reg_syscall(WINDOW_ON_KEYUP, SysCallFn::Fn2_0(window_on_keyup));
-        self.reg_syscall(WINDOW_ON_TEXTINPUT, SysCallFn::Fn2_0(window_on_textinput));
+        self.reg_syscall(WINDOW_DRAW_FRAME, SysCallFn::Fn2_1(window_draw_frame));
+        self.reg_syscall(WINDOW_DRAW_SURFACE, SysCallFn::Fn2_1(window_draw_surface));
+        self.reg_syscall(WINDOW_FRAME_STATS, SysCallFn::Fn2_1(window_frame_stats));
+        self.reg_syscall(WINDOW_ON_MOUSEMOVE, SysCallFn::Fn2_1(window_on_mousemove));
+        self.reg_syscall(WINDOW_ON_MOUSEDOWN, SysCallFn::Fn2_1(window_on_mousedown));
+        self.reg_syscall(WINDOW_ON_MOUSEUP, SysCallFn::Fn2_1(window_on_mouseup));
+        self.reg_syscall(WINDOW_ON_KEYDOWN, SysCallFn::Fn2_1(window_on_keydown));
+        self.reg_syscall(WINDOW_ON_KEYUP, SysCallFn::Fn2_1(window_on_keyup));
+        self.reg_syscall(WINDOW_ON_TEXTINPUT, SysCallFn::Fn2_1(window_on_textinput));
+        self.reg_syscall(WINDOW_ON_DROPFILE, SysCallFn::Fn4_1(window_on_dropfile));
+
+        self.reg_syscall(BLIT_SPRITES, SysCallFn::Fn4_0(blit_sprites));
+        self.reg_syscall(DRAW_TILEMAP, SysCallFn::Fn4_0(draw_tilemap));
+        self.reg_syscall(SURFACE_CREATE, SysCallFn::Fn2_1(surface_create));
+        self.reg_syscall(SURFACE_DESTROY, SysCallFn::Fn1_1(surface_destroy));
+        self.reg_syscall(SURFACE_BLIT, SysCallFn::Fn4_1(surface_blit));
+        self.reg_syscall(BLEND_OVER, SysCallFn::Fn3_0(blend_over));
+        self.reg_syscall(HSV_TO_RGB, SysCallFn::Fn3_0(hsv_to_rgb));
+        self.reg_syscall(APPLY_LUT, SysCallFn::Fn3_0(apply_lut));
+
+        self.reg_syscall(PAD_RUMBLE, SysCallFn::Fn4_1(pad_rumble));
 
         self.reg_syscall(AUDIO_OPEN_OUTPUT, SysCallFn::Fn4_1(audio_open_output));
+        self.reg_syscall(AUDIO_MIX, SysCallFn::Fn4_0(audio_mix));
+        self.reg_syscall(AUDIO_RESAMPLE, SysCallFn::Fn4_0(audio_resample));
+        self.reg_syscall(AUDIO_OSC, SysCallFn::Fn4_1(audio_osc));
+        self.reg_syscall(AUDIO_ENVELOPE, SysCallFn::Fn3_0(audio_envelope));
+        self.reg_syscall(AUDIO_BIQUAD_INIT, SysCallFn::Fn4_1(audio_biquad_init));
+        self.reg_syscall(AUDIO_BIQUAD, SysCallFn::Fn3_0(audio_biquad));
 
         self.reg_syscall(NET_LISTEN, SysCallFn::Fn2_1(net_listen));
         self.reg_syscall(NET_ACCEPT, SysCallFn::Fn4_1(net_accept));
+        self.reg_syscall(NET_TCP_CONNECT, SysCallFn::Fn3_1(net_tcp_connect));
         self.reg_syscall(NET_READ, SysCallFn::Fn3_1(net_read));
         self.reg_syscall(NET_WRITE, SysCallFn::Fn3_1(net_write));
-        self.reg_syscall(NET_CLOSE, SysCallFn::Fn1_0(net_close));
+        self.reg_syscall(NET_CLOSE, SysCallFn::Fn1_1(net_close));
+        self.reg_syscall(WS_CONNECT, SysCallFn::Fn3_1(ws_connect));
+        self.reg_syscall(WS_SEND, SysCallFn::Fn4_1(ws_send));
+        self.reg_syscall(NET_FETCH, SysCallFn::Fn2_1(net_fetch));
+
+        self.reg_syscall(FS_OPEN, SysCallFn::Fn2_1(fs_open));
+        self.reg_syscall(FS_READ, SysCallFn::Fn3_1(fs_read));
+        self.reg_syscall(FS_WRITE, SysCallFn::Fn3_1(fs_write));
+        self.reg_syscall(FS_SEEK, SysCallFn::Fn3_1(fs_seek));
+        self.reg_syscall(FS_CLOSE, SysCallFn::Fn1_1(fs_close));
+        self.reg_syscall(FS_LIST_DIR, SysCallFn::Fn3_1(fs_list_dir));
+        self.reg_syscall(FS_STAT, SysCallFn::Fn2_1(fs_stat));
+        self.reg_syscall(FS_MKDIR, SysCallFn::Fn1_1(fs_mkdir));
+        self.reg_syscall(FS_REMOVE, SysCallFn::Fn1_1(fs_remove));
+
+        self.reg_syscall(COMPRESS, SysCallFn::Fn4_1(compress));
+        self.reg_syscall(DECOMPRESS, SysCallFn::Fn4_1(decompress));
+
+        self.reg_syscall(CRYPTO_SHA256, SysCallFn::Fn3_0(crypto_sha256));
+        self.reg_syscall(CRYPTO_CRC32, SysCallFn::Fn3_1(crypto_crc32));
+
+        self.reg_syscall(STR_VALIDATE_UTF8, SysCallFn::Fn2_1(str_validate_utf8));
+        self.reg_syscall(STR_CHAR_COUNT, SysCallFn::Fn2_1(str_char_count));
+        self.reg_syscall(STR_TO_UPPER, SysCallFn::Fn4_1(str_to_upper));
+        self.reg_syscall(STR_TO_LOWER, SysCallFn::Fn4_1(str_to_lower));
     }
 }
 
@@ -213,8 +592,196 @@ fn vm_heap_size(vm: &mut VM) -> Value
 fn vm_resize_heap(vm: &mut VM, num_bytes: Value) -> Value
 {
     let num_bytes = num_bytes.as_usize();
-    let new_size = vm.resize_heap(num_bytes);
-    Value::from(new_size)
+    match vm.try_resize_heap(num_bytes) {
+        Some(new_size) => Value::from(new_size),
+        None => Value::from(u64::MAX),
+    }
+}
+
+fn vm_grow_heap(vm: &mut VM, num_bytes: Value) -> Value
+{
+    let old_size = vm.heap_size();
+
+    match old_size.checked_add(num_bytes.as_usize()).and_then(|size| vm.try_resize_heap(size)) {
+        Some(_) => Value::from(old_size),
+        None => sys_err(ENOMEM),
+    }
+}
+
+fn vm_track_alloc(vm: &mut VM, ptr: Value, num_bytes: Value) -> Value
+{
+    let ptr = ptr.as_usize();
+    let num_bytes = num_bytes.as_usize();
+
+    // Validate the block bounds even when not tracking allocations
+    vm.mem_view().slice::<u8>(ptr, num_bytes);
+
+    let pc = vm.caller_pc();
+
+    match vm.asan() {
+        Some(asan) => {
+            asan.on_alloc(ptr, num_bytes, pc);
+            Value::from(true)
+        }
+        None => Value::from(false)
+    }
+}
+
+fn vm_track_free(vm: &mut VM, ptr: Value) -> Value
+{
+    let pc = vm.caller_pc();
+
+    match vm.asan() {
+        Some(asan) => {
+            if let Err(msg) = asan.on_free(ptr.as_usize(), pc) {
+                panic!("{}", msg);
+            }
+            Value::from(true)
+        }
+        None => Value::from(false)
+    }
+}
+
+fn vm_heap_walk(vm: &mut VM, entries: Value, max_entries: Value) -> Value
+{
+    let entries_ptr = entries.as_usize();
+    let max_entries = max_entries.as_usize();
+
+    let blocks: Vec<_> = match vm.asan() {
+        Some(asan) => asan.live_blocks().collect(),
+        None => return Value::from(u64::MAX)
+    };
+
+    let num_written = std::cmp::min(blocks.len(), max_entries);
+    let mut mem = vm.mem_view();
+    let out = mem.slice_mut::<u64>(entries_ptr, num_written * 3);
+    for (entry, &(ptr, size, pc)) in out.chunks_exact_mut(3).zip(&blocks) {
+        entry.copy_from_slice(&[ptr as u64, size as u64, pc as u64]);
+    }
+
+    Value::from(blocks.len())
+}
+
+fn vm_backtrace(vm: &mut VM, ret_addrs: Value, max_frames: Value) -> Value
+{
+    let ret_addrs_ptr = ret_addrs.as_usize();
+    let max_frames = max_frames.as_usize();
+
+    let addrs: Vec<u64> = vm.backtrace().filter_map(|frame| frame.ret_addr).map(|pc| pc as u64).collect();
+
+    let num_written = std::cmp::min(addrs.len(), max_frames);
+    vm.mem_view().slice_mut::<u64>(ret_addrs_ptr, num_written).copy_from_slice(&addrs[..num_written]);
+
+    Value::from(addrs.len())
+}
+
+fn vm_yield(vm: &mut VM)
+{
+    vm.request_yield();
+}
+
+fn vm_set_trap_handler(vm: &mut VM, handler: Value)
+{
+    let handler_pc = handler.as_usize();
+    vm.set_trap_handler(if handler_pc == 0 { None } else { Some(handler_pc) });
+}
+
+fn vm_mem_layout(vm: &mut VM, regions: Value)
+{
+    let layout = vm.mem_layout();
+    let mut mem = vm.mem_view();
+    let out = mem.slice_mut::<u64>(regions.as_usize(), 2 * layout.len());
+
+    for (idx, (start, size)) in layout.into_iter().enumerate() {
+        out[2 * idx] = start as u64;
+        out[2 * idx + 1] = size as u64;
+    }
+}
+
+fn vm_mem_protect(vm: &mut VM, addr: Value, num_bytes: Value, prot: Value) -> Value
+{
+    if vm.protect_memory(addr.as_usize(), num_bytes.as_usize(), prot.as_u8()) {
+        Value::from(0)
+    } else {
+        sys_err(EINVAL)
+    }
+}
+
+fn vm_mem_query(vm: &mut VM, addr: Value) -> Value
+{
+    match vm.memory_protection(addr.as_usize()) {
+        Some(prot) => Value::from(prot as i64),
+        None => sys_err(EINVAL),
+    }
+}
+
+/// Version of the VM, encoded as (major << 16) | (minor << 8) | patch
+fn sys_version(vm: &mut VM) -> Value
+{
+    let (major, minor, patch) = vm_version();
+    Value::from((major << 16) | (minor << 8) | patch)
+}
+
+fn sys_has_syscall(vm: &mut VM, const_idx: Value) -> Value
+{
+    let const_idx = const_idx.as_usize();
+
+    match vm.sys_state.syscalls.get(const_idx) {
+        Some(syscall_fn) => Value::from(syscall_fn.is_some()),
+        None => Value::from(false)
+    }
+}
+
+/// Syscalls which can't run in a batch, because they
+/// make the calling thread wait or give up its time slice
+const UNBATCHABLE: [u16; 7] = [SYS_BATCH, VM_YIELD, THREAD_JOIN, MUTEX_LOCK, COND_WAIT, PIPE_WRITE, PIPE_READ];
+
+/// Run the syscalls of an array of entries, each made of the syscall
+/// index, four arguments, and a slot for the result
+fn sys_batch(vm: &mut VM, entries: Value, num_entries: Value) -> Value
+{
+    const ENTRY_WORDS: usize = SYS_BATCH_ENTRY_SIZE as usize / 8;
+
+    let entries = entries.as_usize();
+    let num_entries = num_entries.as_usize();
+
+    // Check that the whole array is accessible before running anything
+    vm.mem_view().slice::<u64>(entries, num_entries.saturating_mul(ENTRY_WORDS));
+
+    for idx in 0..num_entries {
+        let entry_ptr = entries + idx * SYS_BATCH_ENTRY_SIZE as usize;
+        let result_ptr = entry_ptr + 8 * (ENTRY_WORDS - 1);
+
+        // Entries are read as they run, since earlier ones can write to later ones
+        let mut entry = [0; ENTRY_WORDS];
+        entry.copy_from_slice(vm.mem_view().slice::<u64>(entry_ptr, ENTRY_WORDS));
+
+        let syscall = u16::try_from(entry[0]).ok()
+            .filter(|const_idx| !UNBATCHABLE.contains(const_idx))
+            .and_then(|const_idx| Some((const_idx, vm.sys_state.find_syscall(const_idx)?)));
+
+        let Some((const_idx, syscall_fn)) = syscall else {
+            vm.mem_view().write(result_ptr, sys_err(ENOSYS).as_u64());
+            return Value::from(idx);
+        };
+
+        let start_time = if vm.sys_state.syscall_stats_enabled() {
+            Some(Instant::now())
+        } else {
+            None
+        };
+
+        let args = [entry[1], entry[2], entry[3], entry[4]].map(Value::from);
+        let result = syscall_fn.call(vm, args);
+
+        if let Some(start_time) = start_time {
+            vm.sys_state.record_syscall(const_idx, start_time.elapsed());
+        }
+
+        vm.mem_view().write(result_ptr, result.as_u64());
+    }
+
+    Value::from(num_entries)
 }
 
 fn memset(vm: &mut VM, dst_ptr: Value, val: Value, num_bytes: Value)
@@ -223,8 +790,7 @@ fn memset(vm: &mut VM, dst_ptr: Value, val: Value, num_bytes: Value)
     let val = val.as_u8();
     let num_bytes = num_bytes.as_usize();
 
-    let mem_slice: &mut [u8] = vm.get_heap_slice(dst_ptr, num_bytes);
-    mem_slice.fill(val);
+    vm.mem_view().slice_mut::<u8>(dst_ptr, num_bytes).fill(val);
 }
 
 fn memset32(vm: &mut VM, dst_ptr: Value, word: Value, num_words: Value)
@@ -233,8 +799,7 @@ fn memset32(vm: &mut VM, dst_ptr: Value, word: Value, num_words: Value)
     let word = word.as_u32();
     let num_words = num_words.as_usize();
 
-    let mem_slice: &mut [u32] = vm.get_heap_slice(dst_ptr, num_words);
-    mem_slice.fill(word);
+    vm.mem_view().slice_mut::<u32>(dst_ptr, num_words).fill(word);
 }
 
 fn memcpy(vm: &mut VM, dst_ptr: Value, src_ptr: Value, num_bytes: Value)
@@ -245,51 +810,90 @@ fn memcpy(vm: &mut VM, dst_ptr: Value, src_ptr: Value, num_bytes: Value)
 
     // TODO: panic if slices are overlapping
 
-    unsafe {
-        let dst_ptr: *mut u8 = vm.get_heap_ptr(dst_ptr, num_bytes);
-        let src_ptr: *mut u8 = vm.get_heap_ptr(src_ptr, num_bytes);
-
-        std::ptr::copy_nonoverlapping(src_ptr, dst_ptr, num_bytes);
-    }
+    vm.mem_view().copy(dst_ptr, src_ptr, num_bytes);
 }
 
 fn memcmp(vm: &mut VM, ptr_a: Value, ptr_b: Value, num_bytes: Value) -> Value
 {
     let num_bytes = num_bytes.as_usize();
 
-    unsafe {
-        let ptr_a: *const libc::c_void = vm.get_heap_ptr(ptr_a.as_usize(), num_bytes);
-        let ptr_b: *const libc::c_void  = vm.get_heap_ptr(ptr_b.as_usize(), num_bytes);
+    let mem = vm.mem_view();
+    let a: &[u8] = mem.slice(ptr_a.as_usize(), num_bytes);
+    let b: &[u8] = mem.slice(ptr_b.as_usize(), num_bytes);
+
+    let result: i32 = match a.cmp(b) {
+        std::cmp::Ordering::Less => -1,
+        std::cmp::Ordering::Equal => 0,
+        std::cmp::Ordering::Greater => 1,
+    };
+
+    Value::from(result as u64)
+}
+
+/// Write output of the guest to stdout. With --stdio, the output is
+/// flushed right away, and the program stops quietly when the reader
+/// at the other end of the pipe has exited, as for `uvm prog.asm | head`.
+fn write_stdout(vm: &mut VM, bytes: &[u8]) -> std::io::Result<()>
+{
+    if let Some(output) = &mut vm.sys_state.captured_output {
+        output.extend_from_slice(bytes);
+        return Ok(());
+    }
+
+    let mut out = stdout().lock();
+    let mut result = out.write_all(bytes);
+
+    if vm.sys_state.stdio_mode {
+        result = result.and_then(|_| out.flush());
+
+        if matches!(&result, Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe) {
+            std::process::exit(0);
+        }
+    }
 
-        let result = libc::memcmp(ptr_a, ptr_b, num_bytes);
+    result
+}
 
-        Value::from(result as u64)
+/// Print some text to stdout, charging it to the bytes written quota
+/// The void print syscalls can't report errors, so once the quota
+/// is used up, their output is dropped
+fn print_quota(vm: &mut VM, text: &str)
+{
+    if vm.sys_state.quotas.bytes_written.acquire(text.len() as u64) {
+        write_stdout(vm, text.as_bytes()).expect("failed printing to stdout");
     }
 }
 
 fn print_i64(vm: &mut VM, v: Value)
 {
     let v = v.as_i64();
-    print!("{}", v);
+    print_quota(vm, &v.to_string());
 }
 
 fn print_f32(vm: &mut VM, v: Value)
 {
     let v = v.as_f32();
-    print!("{}", v);
+    print_quota(vm, &v.to_string());
+}
+
+fn print_f64(vm: &mut VM, v: Value)
+{
+    let v = v.as_f64();
+    print_quota(vm, &v.to_string());
 }
 
 /// Print a null-terminated UTF-8 string to stdout
 fn print_str(vm: &mut VM, str_ptr: Value)
 {
-    let rust_str = vm.get_heap_str(str_ptr.as_usize());
-    print!("{}", rust_str);
+    let mem = vm.mem_view();
+    let rust_str = mem.str(str_ptr.as_usize()).to_string();
+    print_quota(vm, &rust_str);
 }
 
 /// Print a newline characted to stdout
 fn print_endl(vm: &mut VM)
 {
-    println!();
+    print_quota(vm, "\n");
 }
 
 /// Write one byte of input to stdout.
@@ -299,12 +903,33 @@ fn putchar(vm: &mut VM, byte: Value) -> Value
     let byte = byte.as_u8();
     let bytes = byte.to_le_bytes();
 
-    match stdout().write_all(&bytes) {
+    if !vm.sys_state.quotas.bytes_written.acquire(1) {
+        return sys_err(EDQUOT);
+    }
+
+    match write_stdout(vm, &bytes) {
         Ok(_) => Value::from(byte),
         Err(_) => Value::from(-1 as i64),
     }
 }
 
+/// Write a buffer of bytes to stdout, all or nothing with respect
+/// to the bytes written quota. Returns the number of bytes written.
+fn io_write(vm: &mut VM, buf_ptr: Value, num_bytes: Value) -> Value
+{
+    let num_bytes = num_bytes.as_usize();
+    let bytes = vm.mem_view().slice::<u8>(buf_ptr.as_usize(), num_bytes).to_vec();
+
+    if !vm.sys_state.quotas.bytes_written.acquire(num_bytes as u64) {
+        return sys_err(EDQUOT);
+    }
+
+    match write_stdout(vm, &bytes) {
+        Ok(_) => Value::from(num_bytes),
+        Err(e) => sys_err(io_err_code(&e)),
+    }
+}
+
 /// Read one byte of input from stdin.
 /// Analogous to C's getchar
 fn getchar(vm: &mut VM) -> Value
@@ -316,3 +941,52 @@ fn getchar(vm: &mut VM) -> Value
         None | Some(Err(_)) => Value::from(-1 as i64),
     }
 }
+
+/// Read a line of input from stdin, including the newline, as a
+/// null-terminated string. Lines which don't fit in the buffer are
+/// returned in multiple parts. Returns 0 at the end of the input.
+fn read_line(vm: &mut VM, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let buf_ptr = buf_ptr.as_usize();
+    let buf_len = buf_len.as_usize();
+
+    if buf_len == 0 {
+        return sys_err(EINVAL);
+    }
+    vm.mem_view().slice::<u8>(buf_ptr, buf_len);
+
+    // Only consume the bytes which are returned, so that
+    // the rest of the line is left for the next call
+    let mut line = Vec::new();
+    let mut input = stdin().lock();
+    while line.len() < buf_len - 1 {
+        let available = match input.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return sys_err(io_err_code(&e)),
+        };
+
+        if available.is_empty() {
+            break;
+        }
+
+        let max_bytes = std::cmp::min(available.len(), buf_len - 1 - line.len());
+        let (num_bytes, end_of_line) = match available[..max_bytes].iter().position(|&b| b == b'\n') {
+            Some(pos) => (pos + 1, true),
+            None => (max_bytes, false),
+        };
+
+        line.extend_from_slice(&available[..num_bytes]);
+        input.consume(num_bytes);
+
+        if end_of_line {
+            break;
+        }
+    }
+    drop(input);
+
+    let mut mem = vm.mem_view();
+    mem.write_bytes(buf_ptr, &line);
+    mem.write(buf_ptr + line.len(), 0u8);
+    Value::from(line.len())
+}
@@ -0,0 +1,89 @@
+use crate::vm::{VM, Value};
+use crate::asm::Assembler;
+use crate::verify::verify_restricted;
+use crate::image::ExportTable;
+use crate::signing::{self, PublicKey};
+
+#[derive(Default)]
+pub struct ModuleState
+{
+    // Labels exported by the main program and the loaded modules
+    pub exports: ExportTable,
+
+    // With --require-signed, the keys modules must be signed with
+    pub trusted_keys: Option<Vec<PublicKey>>,
+}
+
+/// Load a module after the code and data of the running program
+/// Returns the address of the module's entry point
+fn load_module(vm: &mut VM, path: &str) -> Result<usize, String>
+{
+    // The data of the module would go at the end of the heap
+    if vm.is_heap_locked() {
+        return Err("the heap can't grow, heap_grow instructions are denied".to_string());
+    }
+
+    // Modules are checked like the main program before parsing anything,
+    // and the bytes which were checked are the ones that get loaded
+    let bytes = Assembler::read_file(path).map_err(|error| error.to_string())?;
+    if let Some(keys) = &vm.sys_state.module_state.trusted_keys {
+        signing::verify_bytes(path, &bytes, keys)?;
+    }
+
+    let mut image = match Assembler::new().assemble_bytes(path, bytes) {
+        Ok(image) => image,
+        Err(error) => return Err(error.to_string())
+    };
+
+    image.validate()?;
+
+    // Modules are checked against the restrictions of the program,
+    // and only their checked instructions may run in a restricted VM
+    let checked = verify_restricted(&image, vm.restrictions())?;
+
+    let code_base = vm.code_size();
+    let data_base = vm.heap_size();
+    image.relocate(code_base, data_base)?;
+    image.link_imports(&vm.sys_state.module_state.exports)?;
+
+    // Check for conflicts before modifying the VM
+    let exports = image.export_table();
+    for name in exports.keys() {
+        if vm.sys_state.module_state.exports.contains_key(name) {
+            return Err(format!("label {} is already exported", name));
+        }
+    }
+
+    image.load_into(vm);
+    vm.add_verified_code(code_base, &checked);
+    vm.sys_state.module_state.exports.extend(exports);
+
+    Ok(code_base)
+}
+
+// Syscall to load a module from an assembly file
+// u64 entry_pc = module_load(const char* path)
+pub fn module_load(vm: &mut VM, path: Value) -> Value
+{
+    let path = vm.mem_view().str(path.as_usize()).to_string();
+
+    match load_module(vm, &path) {
+        Ok(entry_pc) => Value::from(entry_pc),
+        Err(msg) => {
+            eprintln!("module_load: could not load \"{}\": {}", path, msg);
+            Value::from(0)
+        }
+    }
+}
+
+// Syscall to get the address of an exported label
+// u64 addr = module_symbol(const char* name)
+pub fn module_symbol(vm: &mut VM, name: Value) -> Value
+{
+    let name = vm.mem_view().str(name.as_usize()).to_string();
+
+    match vm.sys_state.module_state.exports.get(&name) {
+        Some((_, addr)) => Value::from(*addr),
+        None => Value::from(0)
+    }
+}
@@ -1,33 +1,104 @@
-use std::collections::{HashMap, VecDeque};
-use std::slice;
+use std::collections::VecDeque;
 use std::thread;
 use std::net::{TcpListener, TcpStream};
-use std::io::{self, Read, Write, Error};
+use std::io::{self, BufRead, BufReader, Read, Write, Error};
 use std::sync::{Arc, Weak, Mutex};
+use std::time::Duration;
 use crate::vm::{VM, Value, ExitReason};
+use crate::sys::{stop_requested, sys_err, io_err_code};
+use crate::sys::constants::*;
+use crate::sys::event::dispatch_event;
 
-// State for the networking subsystem
-pub struct NetState
+/// Host-side policy restricting which addresses the net subsystem may use
+#[derive(Debug, Clone, Default)]
+pub struct NetPolicy
 {
-    /// Next socket id to use
-    next_id: u64,
+    // Deny any address that isn't explicitly allowed
+    default_deny: bool,
 
-    /// Map of open sockets
-    sockets: HashMap<u64, Socket>,
+    // Allowed (host, port) pairs, where "*" matches anything
+    allowed: Vec<(String, String)>,
 }
 
-impl Default for NetState
+impl NetPolicy
 {
-    fn default() -> Self
+    /// Deny all addresses that aren't explicitly allowed
+    pub fn deny_all(&mut self)
+    {
+        self.default_deny = true;
+    }
+
+    /// Allow a "host:port" address, where either part may be "*"
+    /// Adding an allow rule implies that everything else is denied
+    pub fn allow(&mut self, rule: &str) -> Result<(), String>
+    {
+        let (host, port) = match rule.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !port.is_empty() => (host, port),
+            _ => return Err(format!("invalid network rule \"{}\", expected host:port", rule))
+        };
+
+        if port != "*" && port.parse::<u16>().is_err() {
+            return Err(format!("invalid port in network rule \"{}\"", rule));
+        }
+
+        self.default_deny = true;
+        self.allowed.push((host.to_string(), port.to_string()));
+        Ok(())
+    }
+
+    /// Load allow rules from a policy file, one "host:port" per line
+    /// Empty lines and lines starting with '#' are ignored
+    pub fn load_file(&mut self, file_name: &str) -> Result<(), String>
+    {
+        let text = match std::fs::read_to_string(file_name) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("could not read network policy file \"{}\": {}", file_name, e))
+        };
+
+        // An empty policy file denies everything
+        self.default_deny = true;
+
+        for line in text.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            self.allow(line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check if the guest may use a given "host:port" address
+    pub fn is_allowed(&self, addr: &str) -> bool
     {
-        Self {
-            // Start at FFFF so we can reserve the low values for error codes
-            next_id: 0xFF_FF,
-            sockets: HashMap::default(),
+        if !self.default_deny {
+            return true;
         }
+
+        let (host, port) = match addr.rsplit_once(':') {
+            Some(pair) => pair,
+            None => return false
+        };
+
+        self.allowed.iter().any(|(rule_host, rule_port)| {
+            (rule_host == "*" || rule_host.eq_ignore_ascii_case(host)) &&
+            (rule_port == "*" || rule_port == port)
+        })
     }
 }
 
+// State for the networking subsystem
+// The sockets are kept in the handle table
+#[derive(Default)]
+pub struct NetState
+{
+    /// Addresses the guest is allowed to use
+    pub policy: NetPolicy,
+}
+
 // State associated with a socket
 enum Socket
 {
@@ -38,11 +109,28 @@ enum Socket
         incoming: VecDeque<TcpStream>,
     },
 
+    // TCP connection being opened by net_tcp_connect
+    Connecting,
+
     Stream {
         stream: TcpStream,
 
         // Read buffer
         read_buf: Vec<u8>,
+    },
+
+    // WebSocket client connection
+    Ws {
+        stream: TcpStream,
+
+        // Payloads of the received messages, read with net_read
+        read_buf: Vec<u8>,
+    },
+
+    // HTTP request made with net_fetch
+    Fetch {
+        // Body of the response once it has been received, read with net_read
+        read_buf: Vec<u8>,
     }
 }
 
@@ -68,12 +156,16 @@ fn listen_thread(
             Err(e) => panic!("encountered IO error: {e}"),
         };
 
+        // Don't call into the VM while it is shutting down
+        if stop_requested() {
+            break;
+        }
+
         let arc = vm_mutex.upgrade().unwrap();
         let mut vm = arc.lock().unwrap();
 
         // Add the new connection to the queue
-        let mut net_state = &mut vm.sys_state.net_state;
-        match net_state.sockets.get_mut(&socket_id) {
+        match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
             Some(Socket::Listen{ incoming, .. }) => {
                 incoming.push_back(stream);
             }
@@ -85,15 +177,15 @@ fn listen_thread(
         }
 
         // Call on_new_conn to signal an incoming connection
-        match vm.call(on_new_conn, &[Value::from(socket_id)]) {
-            ExitReason::Return(val) => {}
-            _ => panic!()
+        if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_new_conn, EVENT_NET_CONN, &[Value::from(socket_id)]) {
+            vm.sys_state.proc_state.pending_exit = Some(val);
+            break;
         }
     }
 }
 
 // Syscall to create a TCP listening socket to accept incoming connections
-// u64 socket_id = net_listen(
+// i64 socket_id = net_listen(
 //     const char* listen_addr,    // Network interface address to listen on, null for any address
 //     callback on_new_connection, // Called on new incoming connection
 // )
@@ -104,10 +196,25 @@ pub fn net_listen(
 ) -> Value
 {
     // Get the input address and port to listen on
-    let listen_addr = vm.get_heap_str(listen_addr.as_usize());
+    let listen_addr = vm.mem_view().str(listen_addr.as_usize()).to_string();
+
+    // Check the address against the host's network policy
+    if !vm.sys_state.net_state.policy.is_allowed(&listen_addr) {
+        eprintln!("net_listen: address \"{}\" denied by network policy", listen_addr);
+        return sys_err(EACCES);
+    }
 
-    // TODO: return 0 on failure
-    let listener = TcpListener::bind(listen_addr).unwrap();
+    if vm.sys_state.quotas.net_conns.remaining() == 0 {
+        return sys_err(EMFILE);
+    }
+
+    let listener = match TcpListener::bind(&listen_addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("net_listen: could not listen on \"{}\": {}", listen_addr, e);
+            return sys_err(io_err_code(&e));
+        }
+    };
 
     // Set the listener to non-blocking
     // We do this because Rust offers us no way to close the TcpListener
@@ -116,11 +223,8 @@ pub fn net_listen(
     listener.set_nonblocking(true).expect("Cannot set non-blocking");
 
     // Assign a socket id to the socket
-    let mut net_state = &mut vm.sys_state.net_state;
-    let socket_id = net_state.next_id;
-    net_state.next_id += 1;
-    net_state.sockets.insert(
-        socket_id,
+    vm.sys_state.quotas.net_conns.acquire(1);
+    let socket_id = vm.sys_state.handles.insert(
         Socket::Listen {
             listener: listener.try_clone().unwrap(),
             incoming: VecDeque::default(),
@@ -156,18 +260,39 @@ fn read_thread(
         let mut buf: [u8; 16384] = [0; 16384];
 
         match stream.read(&mut buf) {
-            // End of file, connection closed, stop
+            // End of file, the connection was closed by the peer
+            // Call on_incoming_data with 0 bytes to signal it, then stop
             Ok(0) => {
+                if stop_requested() {
+                    break;
+                }
+
+                let arc = vm_mutex.upgrade().unwrap();
+                let mut vm = arc.lock().unwrap();
+
+                // The guest closed the socket itself, it doesn't need to be told
+                if !vm.sys_state.handles.contains::<Socket>(socket_id) {
+                    break;
+                }
+
+                if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_incoming_data, EVENT_NET_DATA, &[Value::from(socket_id), Value::from(0)]) {
+                    vm.sys_state.proc_state.pending_exit = Some(val);
+                }
+
                 break;
             }
 
             Ok(num_bytes) => {
+                // Don't call into the VM while it is shutting down
+                if stop_requested() {
+                    break;
+                }
+
                 let arc = vm_mutex.upgrade().unwrap();
                 let mut vm = arc.lock().unwrap();
 
                 // Append to the read buffer
-                let mut net_state = &mut vm.sys_state.net_state;
-                match net_state.sockets.get_mut(&socket_id) {
+                match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
                     Some(Socket::Stream { read_buf, .. }) => {
                         read_buf.extend_from_slice(&buf[0..num_bytes]);
                     }
@@ -180,9 +305,9 @@ fn read_thread(
                 }
 
                 // Call on_incoming_data to signal an incoming data
-                match vm.call(on_incoming_data, &[Value::from(socket_id), Value::from(num_bytes)]) {
-                    ExitReason::Return(val) => {}
-                    _ => panic!()
+                if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_incoming_data, EVENT_NET_DATA, &[Value::from(socket_id), Value::from(num_bytes)]) {
+                    vm.sys_state.proc_state.pending_exit = Some(val);
+                    break;
                 }
             }
 
@@ -195,7 +320,7 @@ fn read_thread(
             // Most errors are the result of a lost connection
             // Stop the read thread
             Err(e) => {
-                println!("error in read thread: {e}");
+                eprintln!("error in read thread: {e}");
                 break
             }
         }
@@ -204,7 +329,7 @@ fn read_thread(
 
 // Syscall to accept a new connection
 // Writes the client address in the buffer you specify
-// u64 socket_id = net_accept(u64 socket_id, char* client_addr, u64 client_addr_len, callback on_incoming_data)
+// i64 socket_id = net_accept(u64 socket_id, char* client_addr, u64 client_addr_len, callback on_incoming_data)
 pub fn net_accept(
     vm: &mut VM,
     socket_id: Value,
@@ -216,43 +341,46 @@ pub fn net_accept(
     let socket_id = socket_id.as_u64();
     let client_addr_buf = client_addr_buf.as_usize();
     let addr_buf_len = addr_buf_len.as_usize();
-    let addr_buf_ptr: *mut u8 = vm.get_heap_ptr(client_addr_buf, addr_buf_len);
+    vm.mem_view().slice::<u8>(client_addr_buf, addr_buf_len);
     let on_incoming_data = on_incoming_data.as_u64();
 
-    let mut net_state = &mut vm.sys_state.net_state;
+    // Leave the connection pending if the guest has too many open sockets
+    let quota_exceeded = vm.sys_state.quotas.net_conns.remaining() == 0;
 
     // If there is a connection waiting
-    match net_state.sockets.get_mut(&socket_id) {
+    match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
         Some(Socket::Listen { incoming, .. }) => {
-            if incoming.len() == 0 {
-                panic!();
+            if quota_exceeded && !incoming.is_empty() {
+                return sys_err(EMFILE);
             }
 
-            let stream = incoming.pop_front().unwrap();
+            let stream = match incoming.pop_front() {
+                Some(stream) => stream,
+                None => return sys_err(EAGAIN)
+            };
             stream.set_nonblocking(false).expect("could not set stream to blocking");
 
-            // TODO: handle the error case here
-            // The connection could have dropped
             // Copy the client address into the buffer
-            let peer_addr = stream.peer_addr().unwrap();
+            // The connection could have dropped already
+            let peer_addr = match stream.peer_addr() {
+                Ok(peer_addr) => peer_addr,
+                Err(e) => return sys_err(io_err_code(&e))
+            };
             let mut addr_str = peer_addr.to_string().into_bytes();
             addr_str.push(0);
             let num_bytes = std::cmp::min(addr_str.len(), addr_buf_len);
-            unsafe {
-                std::ptr::copy_nonoverlapping(addr_str.as_ptr(), addr_buf_ptr, num_bytes);
-            }
 
             // Assign a socket id to the socket
-            let socket_id = net_state.next_id;
-            net_state.next_id += 1;
-            net_state.sockets.insert(
-                socket_id,
+            vm.sys_state.quotas.net_conns.acquire(1);
+            let socket_id = vm.sys_state.handles.insert(
                 Socket::Stream {
                     stream: stream.try_clone().unwrap(),
                     read_buf: Vec::default(),
                 }
             );
 
+            vm.mem_view().write_bytes(client_addr_buf, &addr_str[..num_bytes]);
+
             // Create a listening thread to accept incoming connections
             let vm_mutex = vm.sys_state.mutex.clone();
             thread::spawn(move || {
@@ -267,12 +395,116 @@ pub fn net_accept(
             // Return the socket id
             Value::from(socket_id)
         }
-        _ => panic!()
+        _ => sys_err(EBADF)
     }
 }
 
+/// Thread opening a TCP connection for net_tcp_connect
+fn connect_thread(
+    vm_mutex: Weak<Mutex<VM>>,
+    addr: String,
+    socket_id: u64,
+    on_connect: u64,
+    on_incoming_data: u64
+)
+{
+    let result = TcpStream::connect(&addr);
+
+    // Don't call into the VM while it is shutting down
+    if stop_requested() {
+        return;
+    }
+
+    let arc = vm_mutex.upgrade().unwrap();
+    let mut vm = arc.lock().unwrap();
+
+    // net_close removed the socket, the connection is dropped
+    if !matches!(vm.sys_state.handles.get::<Socket>(socket_id), Some(Socket::Connecting)) {
+        return;
+    }
+
+    let result = match result {
+        Ok(stream) => {
+            *vm.sys_state.handles.get_mut::<Socket>(socket_id).unwrap() = Socket::Stream {
+                stream: stream.try_clone().unwrap(),
+                read_buf: Vec::default(),
+            };
+
+            // Create a read thread to receive incoming data
+            let vm_mutex = vm_mutex.clone();
+            thread::spawn(move || {
+                read_thread(
+                    vm_mutex,
+                    stream,
+                    socket_id,
+                    on_incoming_data,
+                )
+            });
+
+            0
+        }
+
+        // The socket is closed on failure, its id becomes invalid
+        Err(e) => {
+            eprintln!("net_tcp_connect: could not connect to \"{}\": {}", addr, e);
+            vm.sys_state.handles.remove::<Socket>(socket_id);
+            vm.sys_state.quotas.net_conns.release(1);
+            -io_err_code(&e)
+        }
+    };
+
+    // Call on_connect to signal that the connection was opened or failed
+    let args = [Value::from(socket_id), Value::from(result)];
+    if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_connect, EVENT_NET_CONNECT, &args) {
+        vm.sys_state.proc_state.pending_exit = Some(val);
+    }
+}
+
+// Syscall to open a TCP connection to a server
+// i64 socket_id = net_tcp_connect(const char* addr, callback on_connect, callback on_incoming_data)
+pub fn net_tcp_connect(
+    vm: &mut VM,
+    addr: Value,
+    on_connect: Value,
+    on_incoming_data: Value,
+) -> Value
+{
+    let addr = vm.mem_view().str(addr.as_usize()).to_string();
+
+    // Check the address against the host's network policy
+    if !vm.sys_state.net_state.policy.is_allowed(&addr) {
+        eprintln!("net_tcp_connect: address \"{}\" denied by network policy", addr);
+        return sys_err(EACCES);
+    }
+
+    if vm.sys_state.quotas.net_conns.remaining() == 0 {
+        return sys_err(EMFILE);
+    }
+
+    // Assign a socket id to the socket
+    vm.sys_state.quotas.net_conns.acquire(1);
+    let socket_id = vm.sys_state.handles.insert(Socket::Connecting);
+
+    // Connect on another thread, so that the guest keeps running
+    let vm_mutex = vm.sys_state.mutex.clone();
+    let on_connect = on_connect.as_u64();
+    let on_incoming_data = on_incoming_data.as_u64();
+    thread::spawn(move || {
+        connect_thread(
+            vm_mutex,
+            addr,
+            socket_id,
+            on_connect,
+            on_incoming_data,
+        )
+    });
+
+    // Return the socket id
+    Value::from(socket_id)
+}
+
 // Syscall to read data from a given socket into a buffer you specify
-// u64 num_bytes_read = net_read(u64 socket_id, void* buf_ptr, u64 buf_len)
+// i64 num_bytes_read = net_read(u64 socket_id, void* buf_ptr, u64 buf_len)
 pub fn net_read(
     vm: &mut VM,
     socket_id: Value,
@@ -283,28 +515,25 @@ pub fn net_read(
     let socket_id = socket_id.as_u64();
     let buf_len = buf_len.as_usize();
     let buf_ptr = buf_ptr.as_usize();
-    let buf_ptr: *mut u8 = vm.get_heap_ptr(buf_ptr, buf_len);
+    vm.mem_view().slice::<u8>(buf_ptr, buf_len);
 
-    let mut net_state = &mut vm.sys_state.net_state;
-    match net_state.sockets.get_mut(&socket_id) {
-        Some(Socket::Stream { read_buf, .. }) => {
+    let data: Vec<u8> = match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
+        Some(Socket::Stream { read_buf, .. }) |
+        Some(Socket::Ws { read_buf, .. }) |
+        Some(Socket::Fetch { read_buf }) => {
             let num_bytes = std::cmp::min(buf_len, read_buf.len());
-
-            unsafe {
-                std::ptr::copy_nonoverlapping(read_buf.as_ptr(), buf_ptr, num_bytes);
-            }
-
-            read_buf.rotate_left(num_bytes);
-            read_buf.truncate(read_buf.len() - num_bytes);
-
-            Value::from(num_bytes)
+            read_buf.drain(..num_bytes).collect()
         }
-        _ => panic!("invalid socket id {} in net_read", socket_id)
-    }
+        Some(Socket::Connecting) => return sys_err(ENOTCONN),
+        _ => return sys_err(EBADF)
+    };
+
+    vm.mem_view().write_bytes(buf_ptr, &data);
+    Value::from(data.len())
 }
 
 // Syscall to write data on a given socket
-// u64 num_bytes = net_write(u64 socket_id, void* buf_ptr, u64 buf_len);
+// i64 num_bytes = net_write(u64 socket_id, void* buf_ptr, u64 buf_len);
 pub fn net_write(
     vm: &mut VM,
     socket_id: Value,
@@ -315,33 +544,43 @@ pub fn net_write(
     let socket_id = socket_id.as_u64();
     let buf_len = buf_len.as_usize();
     let buf_ptr = buf_ptr.as_usize();
-    let buf_ptr: *mut u8 = vm.get_heap_ptr(buf_ptr, buf_len);
+    let data: Vec<u8> = vm.mem_view().slice::<u8>(buf_ptr, buf_len).to_vec();
 
-    let mut net_state = &mut vm.sys_state.net_state;
-    match net_state.sockets.get_mut(&socket_id) {
+    match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
         Some(Socket::Stream { stream, .. }) => {
-            let mem_slice = unsafe { slice::from_raw_parts(buf_ptr, buf_len) };
-            stream.write_all(&mem_slice).unwrap();
-            Value::from(buf_len)
+            if !vm.sys_state.quotas.bytes_written.acquire(buf_len as u64) {
+                return sys_err(EDQUOT);
+            }
+
+            match stream.write_all(&data) {
+                Ok(_) => Value::from(buf_len),
+                Err(e) => sys_err(io_err_code(&e))
+            }
         }
-        _ => panic!()
+        Some(Socket::Connecting) => sys_err(ENOTCONN),
+        _ => sys_err(EBADF)
     }
 }
 
 // Syscall to close a socket
-// net_close(u64 socket_id)
+// i64 result = net_close(u64 socket_id)
 pub fn net_close(
     vm: &mut VM,
     socket_id: Value,
-)
+) -> Value
 {
     let socket_id = socket_id.as_u64();
 
-    let mut net_state = &mut vm.sys_state.net_state;
-
-    match net_state.sockets.get_mut(&socket_id) {
+    match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
         Some(Socket::Stream { stream, .. }) => {
-            stream.shutdown(std::net::Shutdown::Both).unwrap();
+            // The connection may already be lost
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+
+        Some(Socket::Ws { stream, .. }) => {
+            // Let the server know we're going away, the connection may already be lost
+            let _ = write_ws_frame(stream, WS_OP_CLOSE, &[]);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
         }
 
         Some(Socket::Listen { listener, .. }) => {
@@ -349,9 +588,808 @@ pub fn net_close(
             // has been removed and exit
         }
 
-        _ => panic!()
+        Some(Socket::Connecting) => {
+            // The connection is dropped when it is opened
+        }
+
+        Some(Socket::Fetch { .. }) => {
+            // A pending request is dropped when it completes
+        }
+
+        None => return sys_err(EBADF)
     }
 
     // This drops the socket
-    net_state.sockets.remove(&socket_id);
+    vm.sys_state.handles.remove::<Socket>(socket_id);
+    vm.sys_state.quotas.net_conns.release(1);
+
+    Value::from(0)
+}
+
+// WebSocket frame opcodes
+const WS_OP_CONT: u8 = 0x0;
+const WS_OP_CLOSE: u8 = 0x8;
+const WS_OP_PING: u8 = 0x9;
+const WS_OP_PONG: u8 = 0xA;
+
+// Time the server has to answer the opening handshake
+const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Maximum size of a received WebSocket message
+const WS_MAX_MSG_LEN: usize = 16 * 1024 * 1024;
+
+/// Produce a random 64-bit value without depending on an RNG crate
+/// The standard library seeds each RandomState with fresh randomness
+fn random_u64() -> u64
+{
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos());
+    hasher.finish()
+}
+
+/// Compute the SHA-1 digest of some data
+/// This is only used to validate the WebSocket handshake
+fn sha1(data: &[u8]) -> [u8; 20]
+{
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(chunk[4*i..4*i+4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[4*i..4*i+4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Encode bytes as base64 with padding
+fn base64_encode(data: &[u8]) -> String
+{
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::new();
+
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            }
+            else
+            {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Split a ws:// URL into a host, port and resource path
+fn parse_ws_url(url: &str) -> Option<(String, u16, String)>
+{
+    parse_url(url, "ws://")
+}
+
+/// Split a URL with a given scheme, such as "http://", into
+/// a host, port and resource path, with 80 as the default port
+fn parse_url(url: &str, scheme: &str) -> Option<(String, u16, String)>
+{
+    let rest = url.strip_prefix(scheme)?;
+
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok()?),
+        None => (authority, 80),
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((host.to_string(), port, path.to_string()))
+}
+
+/// Perform the client side of the WebSocket opening handshake
+fn ws_handshake(stream: &mut TcpStream, host: &str, port: u16, path: &str) -> io::Result<()>
+{
+    let key = base64_encode(&[random_u64().to_le_bytes(), random_u64().to_le_bytes()].concat());
+
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+        Host: {host}:{port}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: {key}\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // Read the response headers one byte at a time so that we
+    // don't consume any frame data sent right after them
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 16384 {
+            return Err(Error::new(io::ErrorKind::InvalidData, "handshake response too long"));
+        }
+
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let mut lines = response.lines();
+
+    let status = lines.next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(Error::new(io::ErrorKind::InvalidData, format!("unexpected handshake status \"{status}\"")));
+    }
+
+    // The server must prove that it understood the handshake
+    let expected = base64_encode(&sha1(format!("{key}258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes()));
+    let accepted = lines.any(|line| {
+        match line.split_once(':') {
+            Some((name, value)) => name.trim().eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected,
+            None => false
+        }
+    });
+
+    if !accepted {
+        return Err(Error::new(io::ErrorKind::InvalidData, "invalid Sec-WebSocket-Accept header"));
+    }
+
+    Ok(())
+}
+
+/// Write a single masked WebSocket frame, as required for clients
+fn write_ws_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> io::Result<()>
+{
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+
+    // Final fragment bit and opcode
+    frame.push(0x80 | opcode);
+
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    }
+    else if payload.len() <= 0xFFFF
+    {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    else
+    {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    let mask = (random_u64() as u32).to_be_bytes();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+    stream.write_all(&frame)
+}
+
+/// Read a single WebSocket frame, returns (fin, opcode, payload)
+fn read_ws_frame(stream: &mut TcpStream) -> io::Result<(bool, u8, Vec<u8>)>
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+
+    let fin = (header[0] & 0x80) != 0;
+    let opcode = header[0] & 0x0F;
+    let masked = (header[1] & 0x80) != 0;
+
+    let payload_len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize
+    };
+
+    if payload_len > WS_MAX_MSG_LEN {
+        return Err(Error::new(io::ErrorKind::InvalidData, "WebSocket frame too large"));
+    }
+
+    // Servers shouldn't mask frames, but we accept it anyway
+    let mut mask = [0u8; 4];
+    if masked {
+        stream.read_exact(&mut mask)?;
+    }
+
+    let mut payload = vec![0u8; payload_len];
+    stream.read_exact(&mut payload)?;
+
+    if masked {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+
+    Ok((fin, opcode, payload))
+}
+
+/// WebSocket read thread, assembles incoming messages
+fn ws_read_thread(
+    vm_mutex: Weak<Mutex<VM>>,
+    mut stream: TcpStream,
+    socket_id: u64,
+    on_message: u64
+)
+{
+    // Message being assembled from fragments
+    let mut msg = Vec::new();
+
+    loop
+    {
+        let (fin, opcode, payload) = match read_ws_frame(&mut stream) {
+            Ok(frame) => frame,
+
+            // Most errors are the result of a lost connection
+            // Stop the read thread
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    eprintln!("error in WebSocket read thread: {e}");
+                }
+                break;
+            }
+        };
+
+        // Don't call into the VM while it is shutting down
+        if stop_requested() {
+            break;
+        }
+
+        let arc = vm_mutex.upgrade().unwrap();
+        let mut vm = arc.lock().unwrap();
+
+        // net_close removes the socket, stop the read thread
+        if !vm.sys_state.handles.contains::<Socket>(socket_id) {
+            break;
+        }
+
+        // Control frames are answered while holding the VM lock so that
+        // they can't get interleaved with frames written by ws_send
+        match opcode {
+            WS_OP_PING => {
+                let _ = write_ws_frame(&mut stream, WS_OP_PONG, &payload);
+                continue;
+            }
+
+            WS_OP_PONG => continue,
+
+            WS_OP_CLOSE => {
+                let _ = write_ws_frame(&mut stream, WS_OP_CLOSE, &payload);
+                break;
+            }
+
+            WS_OP_CONT => {}
+
+            // Text and binary messages are both delivered as bytes
+            op if op == WS_MSG_TEXT || op == WS_MSG_BINARY => {
+                msg.clear();
+            }
+
+            _ => {
+                eprintln!("unknown WebSocket opcode {opcode}");
+                break;
+            }
+        }
+
+        msg.extend_from_slice(&payload);
+
+        if msg.len() > WS_MAX_MSG_LEN {
+            eprintln!("WebSocket message too large");
+            break;
+        }
+
+        if !fin {
+            continue;
+        }
+
+        let msg_len = msg.len();
+        match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
+            Some(Socket::Ws { read_buf, .. }) => {
+                read_buf.append(&mut msg);
+            }
+            _ => panic!()
+        }
+
+        // Call on_message to signal that a full message was received
+        if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_message, EVENT_WS_MESSAGE, &[Value::from(socket_id), Value::from(msg_len)]) {
+            vm.sys_state.proc_state.pending_exit = Some(val);
+            break;
+        }
+    }
+}
+
+/// Thread opening a WebSocket connection for ws_connect
+fn ws_connect_thread(
+    vm_mutex: Weak<Mutex<VM>>,
+    url: String,
+    socket_id: u64,
+    on_connect: u64,
+    on_message: u64
+)
+{
+    let (host, port, path) = parse_ws_url(&url).unwrap();
+
+    // A server which doesn't answer the handshake can't keep the socket connecting forever
+    let result = TcpStream::connect(format!("{host}:{port}")).and_then(|mut stream| {
+        stream.set_read_timeout(Some(WS_HANDSHAKE_TIMEOUT))?;
+        match ws_handshake(&mut stream, &host, port, &path) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(Error::from(io::ErrorKind::TimedOut)),
+            result => result?,
+        }
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    });
+
+    // Don't call into the VM while it is shutting down
+    if stop_requested() {
+        return;
+    }
+
+    let arc = vm_mutex.upgrade().unwrap();
+    let mut vm = arc.lock().unwrap();
+
+    // net_close removed the socket, the connection is dropped
+    if !matches!(vm.sys_state.handles.get::<Socket>(socket_id), Some(Socket::Connecting)) {
+        return;
+    }
+
+    let result = match result {
+        Ok(stream) => {
+            *vm.sys_state.handles.get_mut::<Socket>(socket_id).unwrap() = Socket::Ws {
+                stream: stream.try_clone().unwrap(),
+                read_buf: Vec::default(),
+            };
+
+            // Create a read thread to receive incoming messages
+            let vm_mutex = vm_mutex.clone();
+            thread::spawn(move || {
+                ws_read_thread(
+                    vm_mutex,
+                    stream,
+                    socket_id,
+                    on_message,
+                )
+            });
+
+            0
+        }
+
+        // The socket is closed on failure, its id becomes invalid
+        Err(e) => {
+            eprintln!("ws_connect: could not connect to \"{}\": {}", url, e);
+            vm.sys_state.handles.remove::<Socket>(socket_id);
+            vm.sys_state.quotas.net_conns.release(1);
+            -io_err_code(&e)
+        }
+    };
+
+    // Call on_connect to signal that the connection was opened or failed
+    let args = [Value::from(socket_id), Value::from(result)];
+    if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_connect, EVENT_NET_CONNECT, &args) {
+        vm.sys_state.proc_state.pending_exit = Some(val);
+    }
+}
+
+// Syscall to open a WebSocket connection
+// i64 socket_id = ws_connect(const char* url, callback on_connect, callback on_message)
+pub fn ws_connect(
+    vm: &mut VM,
+    url: Value,
+    on_connect: Value,
+    on_message: Value,
+) -> Value
+{
+    let url = vm.mem_view().str(url.as_usize()).to_string();
+
+    let (host, port, _) = match parse_ws_url(&url) {
+        Some(parts) => parts,
+        None => {
+            eprintln!("ws_connect: invalid WebSocket URL \"{}\", expected ws://host[:port]/path", url);
+            return sys_err(EINVAL);
+        }
+    };
+
+    // Check the address against the host's network policy
+    let addr = format!("{host}:{port}");
+    if !vm.sys_state.net_state.policy.is_allowed(&addr) {
+        eprintln!("ws_connect: address \"{}\" denied by network policy", addr);
+        return sys_err(EACCES);
+    }
+
+    if vm.sys_state.quotas.net_conns.remaining() == 0 {
+        return sys_err(EMFILE);
+    }
+
+    // Assign a socket id to the socket
+    vm.sys_state.quotas.net_conns.acquire(1);
+    let socket_id = vm.sys_state.handles.insert(Socket::Connecting);
+
+    // Connect and do the handshake on another thread, so that the guest keeps running
+    let vm_mutex = vm.sys_state.mutex.clone();
+    let on_connect = on_connect.as_u64();
+    let on_message = on_message.as_u64();
+    thread::spawn(move || {
+        ws_connect_thread(
+            vm_mutex,
+            url,
+            socket_id,
+            on_connect,
+            on_message,
+        )
+    });
+
+    // Return the socket id
+    Value::from(socket_id)
+}
+
+// Syscall to send a message on a WebSocket connection
+// i64 result = ws_send(u64 socket_id, const void* buf_ptr, u64 buf_len, u8 msg_type)
+pub fn ws_send(
+    vm: &mut VM,
+    socket_id: Value,
+    buf_ptr: Value,
+    buf_len: Value,
+    msg_type: Value,
+) -> Value
+{
+    let socket_id = socket_id.as_u64();
+    let buf_len = buf_len.as_usize();
+    let buf_ptr = buf_ptr.as_usize();
+    let data: Vec<u8> = vm.mem_view().slice::<u8>(buf_ptr, buf_len).to_vec();
+    let msg_type = msg_type.as_u8();
+
+    if msg_type != WS_MSG_TEXT && msg_type != WS_MSG_BINARY {
+        return sys_err(EINVAL);
+    }
+
+    match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
+        Some(Socket::Ws { stream, .. }) => {
+            if !vm.sys_state.quotas.bytes_written.acquire(buf_len as u64) {
+                return sys_err(EDQUOT);
+            }
+
+            match write_ws_frame(stream, msg_type, &data) {
+                Ok(_) => Value::from(0),
+                Err(e) => sys_err(io_err_code(&e))
+            }
+        }
+        Some(Socket::Connecting) => sys_err(ENOTCONN),
+        _ => sys_err(EBADF)
+    }
+}
+
+// Maximum size of the body of a response to net_fetch
+const FETCH_MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+/// Read the body of a response encoded with chunked transfer encoding
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>>
+{
+    let mut body = Vec::new();
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        // Chunk extensions after a ';' are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size \"{}\"", size))),
+        };
+
+        if body.len() + size > FETCH_MAX_BODY_LEN {
+            return Err(Error::new(io::ErrorKind::InvalidData, "response body too large"));
+        }
+
+        // The last chunk is followed by optional trailers and an empty line
+        if size == 0 {
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                    return Ok(body);
+                }
+            }
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+}
+
+/// Perform an HTTP GET request, returns the status code and the body of the response
+fn http_get(host: &str, port: u16, path: &str) -> io::Result<(u16, Vec<u8>)>
+{
+    let mut stream = TcpStream::connect((host, port))?;
+
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+        Host: {host}:{port}\r\n\
+        User-Agent: uvm\r\n\
+        Connection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut reader = BufReader::new(stream);
+    let invalid = |msg: String| Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = match status_line.split_whitespace().nth(1).map(|code| code.parse::<u16>()) {
+        Some(Ok(status)) if status_line.starts_with("HTTP/") => status,
+        _ => return Err(invalid(format!("unexpected status line \"{}\"", status_line.trim_end()))),
+    };
+
+    let mut content_len = None;
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                content_len = Some(value.parse::<usize>().map_err(|_| invalid(format!("invalid Content-Length \"{}\"", value)))?);
+            }
+            if name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked") {
+                chunked = true;
+            }
+        }
+    }
+
+    let body = match (chunked, content_len) {
+        (true, _) => read_chunked(&mut reader)?,
+
+        (false, Some(len)) => {
+            if len > FETCH_MAX_BODY_LEN {
+                return Err(invalid("response body too large".to_string()));
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            body
+        }
+
+        // Without a length, the body ends when the server closes the connection
+        (false, None) => {
+            let mut body = Vec::new();
+            reader.take(FETCH_MAX_BODY_LEN as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > FETCH_MAX_BODY_LEN {
+                return Err(invalid("response body too large".to_string()));
+            }
+            body
+        }
+    };
+
+    Ok((status, body))
+}
+
+/// Thread performing a request for net_fetch
+fn fetch_thread(
+    vm_mutex: Weak<Mutex<VM>>,
+    host: String,
+    port: u16,
+    path: String,
+    socket_id: u64,
+    on_done: u64
+)
+{
+    let result = http_get(&host, port, &path);
+
+    // Don't call into the VM while it is shutting down
+    if stop_requested() {
+        return;
+    }
+
+    let arc = vm_mutex.upgrade().unwrap();
+    let mut vm = arc.lock().unwrap();
+
+    let (status, body_len) = match result {
+        Ok((status, body)) => {
+            let body_len = body.len();
+            match vm.sys_state.handles.get_mut::<Socket>(socket_id) {
+                Some(Socket::Fetch { read_buf }) => *read_buf = body,
+
+                // net_close removed the request
+                _ => return,
+            }
+            (status as i64, body_len)
+        }
+
+        Err(e) => {
+            if !vm.sys_state.handles.contains::<Socket>(socket_id) {
+                return;
+            }
+            eprintln!("net_fetch: request to \"{}:{}\" failed: {}", host, port, e);
+            (-io_err_code(&e), 0)
+        }
+    };
+
+    // Call on_done to signal that the response was received
+    let args = [Value::from(socket_id), Value::from(status), Value::from(body_len)];
+    if let ExitReason::Exit(val) = dispatch_event(&mut vm, on_done, EVENT_NET_FETCH, &args) {
+        vm.sys_state.proc_state.pending_exit = Some(val);
+    }
+}
+
+// Syscall to make an HTTP GET request
+// i64 request_id = net_fetch(const char* url, callback on_done)
+pub fn net_fetch(
+    vm: &mut VM,
+    url: Value,
+    on_done: Value,
+) -> Value
+{
+    let url = vm.mem_view().str(url.as_usize()).to_string();
+
+    let (host, port, path) = match parse_url(&url, "http://") {
+        Some(parts) => parts,
+        None => {
+            eprintln!("net_fetch: invalid URL \"{}\", expected http://host[:port]/path", url);
+            return sys_err(EINVAL);
+        }
+    };
+
+    // Check the address against the host's network policy
+    let addr = format!("{host}:{port}");
+    if !vm.sys_state.net_state.policy.is_allowed(&addr) {
+        eprintln!("net_fetch: address \"{}\" denied by network policy", addr);
+        return sys_err(EACCES);
+    }
+
+    if vm.sys_state.quotas.net_conns.remaining() == 0 {
+        return sys_err(EMFILE);
+    }
+
+    // The request counts as an open socket until it is closed
+    vm.sys_state.quotas.net_conns.acquire(1);
+    let socket_id = vm.sys_state.handles.insert(
+        Socket::Fetch {
+            read_buf: Vec::default(),
+        }
+    );
+
+    // Make the request on another thread, so that the guest keeps running
+    let vm_mutex = vm.sys_state.mutex.clone();
+    let on_done = on_done.as_u64();
+    thread::spawn(move || {
+        fetch_thread(
+            vm_mutex,
+            host,
+            port,
+            path,
+            socket_id,
+            on_done,
+        )
+    });
+
+    // Return the request id
+    Value::from(socket_id)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_policy()
+    {
+        // Everything is allowed by default
+        let policy = NetPolicy::default();
+        assert!(policy.is_allowed("0.0.0.0:8080"));
+
+        let mut policy = NetPolicy::default();
+        policy.deny_all();
+        assert!(!policy.is_allowed("0.0.0.0:8080"));
+
+        let mut policy = NetPolicy::default();
+        policy.allow("localhost:8080").unwrap();
+        policy.allow("*:9000").unwrap();
+        policy.allow("127.0.0.1:*").unwrap();
+        assert!(policy.is_allowed("localhost:8080"));
+        assert!(policy.is_allowed("LocalHost:8080"));
+        assert!(!policy.is_allowed("localhost:8081"));
+        assert!(policy.is_allowed("0.0.0.0:9000"));
+        assert!(policy.is_allowed("127.0.0.1:22"));
+        assert!(!policy.is_allowed("0.0.0.0:22"));
+        assert!(!policy.is_allowed("garbage"));
+
+        assert!(policy.allow("localhost").is_err());
+        assert!(policy.allow("localhost:http").is_err());
+        assert!(policy.allow(":80").is_err());
+    }
+
+    #[test]
+    fn test_ws_helpers()
+    {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+
+        // Example handshake from RFC 6455
+        let key = "dGhlIHNhbXBsZSBub25jZQ==258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+        assert_eq!(base64_encode(&sha1(key.as_bytes())), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        assert_eq!(parse_ws_url("ws://localhost:8080/chat"), Some(("localhost".to_string(), 8080, "/chat".to_string())));
+        assert_eq!(parse_ws_url("ws://example.com"), Some(("example.com".to_string(), 80, "/".to_string())));
+        assert_eq!(parse_ws_url("wss://example.com"), None);
+        assert_eq!(parse_ws_url("ws://:80"), None);
+        assert_eq!(parse_url("http://example.com:8000/a?b", "http://"), Some(("example.com".to_string(), 8000, "/a?b".to_string())));
+    }
+
+    #[test]
+    fn test_read_chunked()
+    {
+        let mut input = "4\r\nWiki\r\n7;ext=1\r\npedia i\r\n0\r\nTrailer: x\r\n\r\n".as_bytes();
+        assert_eq!(read_chunked(&mut input).unwrap(), b"Wikipedia i");
+        assert!(read_chunked(&mut "zz\r\n".as_bytes()).is_err());
+        assert!(read_chunked(&mut "8\r\nabc".as_bytes()).is_err());
+    }
 }
@@ -0,0 +1,58 @@
+// Gamepad/game controller device
+
+extern crate sdl2;
+use sdl2::controller::GameController;
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use crate::sys::get_sdl_context;
+use crate::vm::{VM, Value};
+
+thread_local! {
+    /// SDL game controller subsystem
+    /// This is thread-local because it doesn't implement the Send trait,
+    /// and syscalls always run on the thread that runs the VM
+    static SDL_CONTROLLER: OnceCell<sdl2::GameControllerSubsystem> = const { OnceCell::new() };
+
+    /// Gamepads that have been opened, indexed by pad id
+    /// Controllers have to stay open for rumble effects to keep playing
+    static PADS: RefCell<HashMap<u32, GameController>> = RefCell::new(HashMap::default());
+}
+
+/// Run a function on the gamepad with a given id, opening it if needed
+/// Returns None if there is no such gamepad
+fn with_pad<R>(pad_id: u32, f: impl FnOnce(&mut GameController) -> R) -> Option<R>
+{
+    SDL_CONTROLLER.with(|subsystem| {
+        let subsystem = subsystem.get_or_init(|| get_sdl_context().game_controller().unwrap());
+
+        PADS.with_borrow_mut(|pads| {
+            let pad = match pads.entry(pad_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    if !subsystem.is_game_controller(pad_id) {
+                        return None;
+                    }
+                    entry.insert(subsystem.open(pad_id).ok()?)
+                }
+            };
+
+            Some(f(pad))
+        })
+    })
+}
+
+pub fn pad_rumble(vm: &mut VM, pad_id: Value, low_freq: Value, high_freq: Value, duration_ms: Value) -> Value
+{
+    let pad_id = pad_id.as_u32();
+    let low_freq = low_freq.as_u16();
+    let high_freq = high_freq.as_u16();
+    let duration_ms = duration_ms.as_u32();
+
+    let success = with_pad(pad_id, |pad| {
+        pad.set_rumble(low_freq, high_freq, duration_ms).is_ok()
+    }).unwrap_or(false);
+
+    Value::from(success)
+}
@@ -0,0 +1,180 @@
+use std::process::Command;
+use std::sync::{Weak, Mutex};
+use std::thread;
+use crate::vm::{VM, Value, ExitReason};
+use crate::sys::{clear_stop_request, stop_requested};
+
+pub struct ProcState
+{
+    // Callbacks to run when the program terminates, in registration order
+    atexit_cbs: Vec<u64>,
+
+    // Allow the program to run host commands with proc_spawn
+    pub allow_exec: bool,
+
+    // Next process id to use, 0 is reserved for errors
+    next_id: u64,
+
+    // Exit code set by a callback that exited from another thread,
+    // picked up by the event loop on the main thread
+    pub pending_exit: Option<Value>,
+
+    // Command-line arguments of the program, starting with its path
+    pub args: Vec<String>,
+
+    // Address of the argv array, once it has been copied to the heap
+    argv_ptr: Option<usize>,
+}
+
+impl ProcState
+{
+    pub fn new() -> Self
+    {
+        Self {
+            atexit_cbs: Vec::default(),
+            allow_exec: false,
+            next_id: 1,
+            pending_exit: None,
+            args: Vec::default(),
+            argv_ptr: None,
+        }
+    }
+}
+
+/// Register a callback to be run when the program terminates
+pub fn proc_atexit(vm: &mut VM, callback_pc: Value)
+{
+    let callback_pc = callback_pc.as_u64();
+    vm.sys_state.proc_state.atexit_cbs.push(callback_pc);
+}
+
+/// Run the atexit callbacks, most recently registered first
+/// Each callback runs at most once, even if this is called again
+pub fn run_atexit_cbs(vm: &mut VM)
+{
+    // If we are exiting because of ctrl-C, the stop request must be
+    // cleared or the callbacks would be interrupted immediately
+    clear_stop_request();
+
+    let cbs = std::mem::take(&mut vm.sys_state.proc_state.atexit_cbs);
+
+    for pc in cbs.into_iter().rev() {
+        // A callback calling exit doesn't prevent the others from running
+        vm.call_sync(pc, &[]);
+    }
+}
+
+/// Thread waiting for a spawned process to terminate
+fn spawn_thread(
+    vm_mutex: Weak<Mutex<VM>>,
+    cmd: String,
+    proc_id: u64,
+    out_buf: usize,
+    out_buf_len: usize,
+    on_exit: u64
+)
+{
+    // Failing to start the shell is reported like a failed command
+    let (exit_code, stdout) = match Command::new("sh").arg("-c").arg(&cmd).output() {
+        Ok(output) => (output.status.code().map(|c| c as i64).unwrap_or(-1), output.stdout),
+        Err(e) => {
+            eprintln!("proc_spawn: could not run \"{}\": {}", cmd, e);
+            (-1, Vec::default())
+        }
+    };
+
+    // Don't call into the VM while it is shutting down
+    if stop_requested() {
+        return;
+    }
+
+    let arc = match vm_mutex.upgrade() {
+        Some(arc) => arc,
+        None => return
+    };
+    let mut vm = arc.lock().unwrap();
+
+    // The program has already exited
+    if vm.sys_state.proc_state.pending_exit.is_some() {
+        return;
+    }
+
+    let num_bytes = std::cmp::min(stdout.len(), out_buf_len);
+    vm.mem_view().write_bytes(out_buf, &stdout[..num_bytes]);
+
+    if let ExitReason::Exit(val) = vm.call_sync(on_exit, &[Value::from(proc_id), Value::from(exit_code), Value::from(num_bytes)]) {
+        vm.sys_state.proc_state.pending_exit = Some(val);
+    }
+}
+
+// Syscall to run a shell command on the host in the background
+// u64 proc_id = proc_spawn(const char* cmd, u8* out_buf, u64 out_buf_len, callback on_exit)
+pub fn proc_spawn(vm: &mut VM, cmd: Value, out_buf: Value, out_buf_len: Value, on_exit: Value) -> Value
+{
+    let cmd = vm.mem_view().str(cmd.as_usize()).to_string();
+    let out_buf_len = out_buf_len.as_usize();
+    let out_buf = out_buf.as_usize();
+
+    if !vm.sys_state.proc_state.allow_exec {
+        eprintln!("proc_spawn: running host commands requires --allow-exec");
+        return Value::from(0);
+    }
+
+    // Check the output buffer now rather than when the process exits
+    vm.mem_view().slice::<u8>(out_buf, out_buf_len);
+
+    let proc_state = &mut vm.sys_state.proc_state;
+    let proc_id = proc_state.next_id;
+    proc_state.next_id += 1;
+
+    let vm_mutex = vm.sys_state.mutex.clone();
+    let on_exit = on_exit.as_u64();
+    thread::spawn(move || {
+        spawn_thread(
+            vm_mutex,
+            cmd,
+            proc_id,
+            out_buf,
+            out_buf_len,
+            on_exit,
+        )
+    });
+
+    Value::from(proc_id)
+}
+
+// Syscall to get the number of command-line arguments
+// u64 argc = proc_argc()
+pub fn proc_argc(vm: &mut VM) -> Value
+{
+    Value::from(vm.sys_state.proc_state.args.len())
+}
+
+// Syscall to get the command-line arguments
+// char** argv = proc_argv()
+pub fn proc_argv(vm: &mut VM) -> Value
+{
+    if let Some(argv_ptr) = vm.sys_state.proc_state.argv_ptr {
+        return Value::from(argv_ptr);
+    }
+
+    // The array of pointers is followed by the strings
+    let args = vm.sys_state.proc_state.args.clone();
+    let argv_ptr = vm.heap_size();
+    let array_size = 8 * (args.len() + 1);
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    vm.resize_heap(argv_ptr + array_size + strings_size);
+
+    let mut mem = vm.mem_view();
+    let mut str_ptr = argv_ptr + array_size;
+    for (idx, arg) in args.iter().enumerate() {
+        mem.write(argv_ptr + 8 * idx, str_ptr as u64);
+        mem.write_bytes(str_ptr, arg.as_bytes());
+        mem.write(str_ptr + arg.len(), 0u8);
+        str_ptr += arg.len() + 1;
+    }
+    mem.write(argv_ptr + 8 * args.len(), 0u64);
+
+    vm.sys_state.proc_state.argv_ptr = Some(argv_ptr);
+    Value::from(argv_ptr)
+}
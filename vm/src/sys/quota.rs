@@ -0,0 +1,135 @@
+// Resource quotas for running untrusted programs
+//
+// Each quota is disabled by default. When a quota is set, syscalls that
+// would go over it fail and report the error to the guest, using
+// EMFILE for handles (files, network connections) and EDQUOT for
+// the number of bytes written.
+
+/// A limit and the amount of the resource currently used
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Quota
+{
+    /// Maximum amount, no limit if None
+    pub limit: Option<u64>,
+
+    /// Amount currently used
+    pub used: u64,
+}
+
+impl Quota
+{
+    /// Try to use some amount of the resource,
+    /// returning false if this would exceed the limit
+    pub fn acquire(&mut self, amount: u64) -> bool
+    {
+        let used = self.used.saturating_add(amount);
+
+        if let Some(limit) = self.limit {
+            if used > limit {
+                return false;
+            }
+        }
+
+        self.used = used;
+        true
+    }
+
+    /// Give back some amount of the resource
+    pub fn release(&mut self, amount: u64)
+    {
+        self.used = self.used.saturating_sub(amount);
+    }
+
+    /// Amount of the resource left before reaching the limit
+    pub fn remaining(&self) -> u64
+    {
+        match self.limit {
+            Some(limit) => limit.saturating_sub(self.used),
+            None => u64::MAX,
+        }
+    }
+}
+
+/// Per-subsystem quotas, configured on the VM before the program runs
+#[derive(Default, Debug, Clone)]
+pub struct Quotas
+{
+    /// Number of files open at the same time
+    pub open_files: Quota,
+
+    /// Total number of bytes written to stdout, files and sockets
+    pub bytes_written: Quota,
+
+    /// Number of network sockets open at the same time,
+    /// including listening sockets
+    pub net_conns: Quota,
+}
+
+impl Quotas
+{
+    /// Parse a quota setting of the form "name=limit"
+    pub fn set(&mut self, setting: &str) -> Result<(), String>
+    {
+        let (name, limit) = match setting.split_once('=') {
+            Some(pair) => pair,
+            None => return Err(format!("invalid quota \"{}\", expected name=limit", setting))
+        };
+
+        let limit = match limit.parse::<u64>() {
+            Ok(limit) => limit,
+            Err(_) => return Err(format!("invalid limit in quota \"{}\"", setting))
+        };
+
+        let quota = match name {
+            "open-files" => &mut self.open_files,
+            "bytes-written" => &mut self.bytes_written,
+            "net-conns" => &mut self.net_conns,
+            _ => return Err(format!(
+                "unknown quota \"{}\", expected open-files, bytes-written or net-conns",
+                name
+            ))
+        };
+
+        quota.limit = Some(limit);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_quota()
+    {
+        // No limit by default
+        let mut quota = Quota::default();
+        assert!(quota.acquire(u64::MAX));
+        assert!(quota.acquire(1));
+
+        let mut quota = Quota { limit: Some(2), used: 0 };
+        assert!(quota.acquire(1));
+        assert!(quota.acquire(1));
+        assert!(!quota.acquire(1));
+        assert_eq!(quota.used, 2);
+        quota.release(1);
+        assert_eq!(quota.remaining(), 1);
+        assert!(quota.acquire(1));
+    }
+
+    #[test]
+    fn test_set()
+    {
+        let mut quotas = Quotas::default();
+        quotas.set("net-conns=4").unwrap();
+        quotas.set("bytes-written=1024").unwrap();
+        assert_eq!(quotas.net_conns.limit, Some(4));
+        assert_eq!(quotas.bytes_written.limit, Some(1024));
+        assert_eq!(quotas.open_files.limit, None);
+
+        assert!(quotas.set("net-conns").is_err());
+        assert!(quotas.set("net-conns=-1").is_err());
+        assert!(quotas.set("sockets=1").is_err());
+    }
+}
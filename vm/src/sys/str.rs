@@ -0,0 +1,66 @@
+use crate::vm::{VM, Value};
+use crate::sys::sys_err;
+use crate::sys::constants::*;
+
+/// Count the code points in a UTF-8 buffer,
+/// where each invalid byte sequence counts as one character
+pub fn char_count(data: &[u8]) -> usize
+{
+    data.utf8_chunks().map(|chunk| {
+        let invalid = if chunk.invalid().is_empty() { 0 } else { 1 };
+        chunk.valid().chars().count() + invalid
+    }).sum()
+}
+
+/// Write a case-converted string into a guest buffer
+fn write_converted(vm: &mut VM, dst_ptr: Value, dst_len: Value, src_ptr: Value, src_len: Value, to_upper: bool) -> Value
+{
+    let mut mem = vm.mem_view();
+    let src: &[u8] = mem.slice(src_ptr.as_usize(), src_len.as_usize());
+
+    let text = match std::str::from_utf8(src) {
+        Ok(text) => text,
+        Err(_) => return sys_err(EINVAL)
+    };
+
+    let out = if to_upper { text.to_uppercase() } else { text.to_lowercase() };
+
+    if out.len() > dst_len.as_usize() {
+        return sys_err(ENOSPC);
+    }
+
+    mem.write_bytes(dst_ptr.as_usize(), out.as_bytes());
+    Value::from(out.len())
+}
+
+// Syscall to check if a buffer contains valid UTF-8 text
+// bool valid = str_validate_utf8(const u8* src, u64 num_bytes)
+pub fn str_validate_utf8(vm: &mut VM, src_ptr: Value, num_bytes: Value) -> Value
+{
+    let mem = vm.mem_view();
+    let src: &[u8] = mem.slice(src_ptr.as_usize(), num_bytes.as_usize());
+    Value::from(std::str::from_utf8(src).is_ok())
+}
+
+// Syscall to count the code points in a UTF-8 buffer
+// u64 num_chars = str_char_count(const u8* src, u64 num_bytes)
+pub fn str_char_count(vm: &mut VM, src_ptr: Value, num_bytes: Value) -> Value
+{
+    let mem = vm.mem_view();
+    let src: &[u8] = mem.slice(src_ptr.as_usize(), num_bytes.as_usize());
+    Value::from(char_count(src))
+}
+
+// Syscall to convert UTF-8 text to uppercase
+// i64 num_bytes = str_to_upper(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+pub fn str_to_upper(vm: &mut VM, dst_ptr: Value, dst_len: Value, src_ptr: Value, src_len: Value) -> Value
+{
+    write_converted(vm, dst_ptr, dst_len, src_ptr, src_len, true)
+}
+
+// Syscall to convert UTF-8 text to lowercase
+// i64 num_bytes = str_to_lower(u8* dst, u64 dst_len, const u8* src, u64 src_len)
+pub fn str_to_lower(vm: &mut VM, dst_ptr: Value, dst_len: Value, src_ptr: Value, src_len: Value) -> Value
+{
+    write_converted(vm, dst_ptr, dst_len, src_ptr, src_len, false)
+}
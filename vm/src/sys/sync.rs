@@ -0,0 +1,114 @@
+use crate::vm::{VM, Value};
+use crate::sys::sys_err;
+use crate::sys::constants::*;
+
+// Syscall to create a mutex
+// u64 mutex = mutex_create()
+pub fn mutex_create(vm: &mut VM) -> Value
+{
+    Value::from(vm.create_mutex())
+}
+
+// Syscall to lock a mutex, waiting if another thread holds it
+// void mutex_lock(u64 mutex)
+pub fn mutex_lock(vm: &mut VM, mutex: Value)
+{
+    vm.lock_mutex(mutex.as_u64());
+}
+
+// Syscall to unlock a mutex held by the current thread
+// void mutex_unlock(u64 mutex)
+pub fn mutex_unlock(vm: &mut VM, mutex: Value)
+{
+    vm.unlock_mutex(mutex.as_u64());
+}
+
+// Syscall to create a condition variable
+// u64 cond = cond_create()
+pub fn cond_create(vm: &mut VM) -> Value
+{
+    Value::from(vm.create_cond())
+}
+
+// Syscall to unlock a mutex and wait until a condition variable is signaled
+// void cond_wait(u64 cond, u64 mutex)
+pub fn cond_wait(vm: &mut VM, cond: Value, mutex: Value)
+{
+    vm.wait_cond(cond.as_u64(), mutex.as_u64());
+}
+
+// Syscall to wake up the thread waiting the longest on a condition variable
+// void cond_signal(u64 cond)
+pub fn cond_signal(vm: &mut VM, cond: Value)
+{
+    vm.signal_cond(cond.as_u64());
+}
+
+/// Entry of a pipe in the handle table
+/// Its read and write positions are kept by the scheduler
+struct PipeHandle;
+
+// Syscall to create a pipe which queues up to capacity bytes in a buffer
+// of guest memory. The buffer must stay valid until the pipe is closed.
+// i64 pipe = pipe_create(u8* buf, u64 capacity)
+pub fn pipe_create(vm: &mut VM, buf: Value, capacity: Value) -> Value
+{
+    let buf = buf.as_usize();
+    let capacity = capacity.as_usize();
+
+    if capacity == 0 {
+        return sys_err(EINVAL);
+    }
+
+    // Check the buffer now, so that a bad pointer faults in the caller
+    vm.mem_view().slice::<u8>(buf, capacity);
+
+    let pipe = vm.sys_state.handles.insert(PipeHandle);
+    vm.create_pipe(pipe, buf, capacity);
+    Value::from(pipe)
+}
+
+// Syscall to write bytes to a pipe, waiting until it has room for all of them
+// Returns num_bytes, -EBADF if the pipe is closed, or -EINVAL if it
+// can never hold num_bytes bytes
+// i64 num_written = pipe_write(u64 pipe, const u8* src, u64 num_bytes)
+pub fn pipe_write(vm: &mut VM, pipe: Value, src: Value, num_bytes: Value) -> Value
+{
+    let pipe = pipe.as_u64();
+
+    if !vm.sys_state.handles.contains::<PipeHandle>(pipe) {
+        return sys_err(EBADF);
+    }
+
+    vm.write_pipe(pipe, src.as_usize(), num_bytes.as_usize())
+}
+
+// Syscall to read up to num_bytes bytes from a pipe, waiting until it isn't empty
+// Returns the number of bytes read, or -EBADF if the pipe is closed
+// i64 num_read = pipe_read(u64 pipe, u8* dst, u64 num_bytes)
+pub fn pipe_read(vm: &mut VM, pipe: Value, dst: Value, num_bytes: Value) -> Value
+{
+    let pipe = pipe.as_u64();
+
+    if !vm.sys_state.handles.contains::<PipeHandle>(pipe) {
+        return sys_err(EBADF);
+    }
+
+    vm.read_pipe(pipe, dst.as_usize(), num_bytes.as_usize())
+}
+
+// Syscall to close a pipe. Threads waiting to read from or write to
+// it get -EBADF.
+// i64 result = pipe_close(u64 pipe)
+pub fn pipe_close(vm: &mut VM, pipe: Value) -> Value
+{
+    let pipe = pipe.as_u64();
+
+    match vm.sys_state.handles.remove::<PipeHandle>(pipe) {
+        Some(_) => {
+            vm.close_pipe(pipe);
+            Value::from(0)
+        }
+        None => sys_err(EBADF),
+    }
+}
@@ -0,0 +1,117 @@
+use crate::vm::{VM, Value};
+use crate::sys::mem::mem_alloc;
+
+#[derive(Default)]
+pub struct ThreadState
+{
+    // Initial contents of the TLS block of each thread
+    tls_template: Vec<u8>,
+
+    // Address of the TLS block of the current thread, 0 if there is none
+    pub tls_base: usize,
+}
+
+// Syscall to reserve space in the TLS block of every thread
+// u64 offset = tls_alloc(const void* init, u64 num_bytes)
+pub fn tls_alloc(vm: &mut VM, init_ptr: Value, num_bytes: Value) -> Value
+{
+    let init_ptr = init_ptr.as_usize();
+    let num_bytes = num_bytes.as_usize();
+
+    // The TLS blocks of the other threads would have to grow as well
+    if vm.has_other_threads() {
+        panic!("tls_alloc: can't be called while other threads are running");
+    }
+
+    // The TLS block of the current thread moves to the end of the heap
+    if vm.is_heap_locked() && num_bytes > 0 {
+        panic!("tls_alloc: the heap can't grow, heap_grow instructions are denied");
+    }
+
+    let init = if init_ptr == 0 {
+        vec![0; num_bytes]
+    } else {
+        vm.mem_view().slice::<u8>(init_ptr, num_bytes).to_vec()
+    };
+
+    let state = &mut vm.sys_state.thread_state;
+    let old_base = state.tls_base;
+    let old_size = state.tls_template.len();
+
+    // Keep the new space 8-byte aligned
+    let offset = old_size.next_multiple_of(8);
+    state.tls_template.resize(offset, 0);
+    state.tls_template.extend_from_slice(&init);
+    let new_size = state.tls_template.len();
+
+    // Move the TLS block of the current thread to the end of the heap so
+    // it can grow. The old block is never reused, but TLS allocations
+    // normally happen once per library, when the program starts.
+    if new_size > 0 {
+        let new_base = vm.heap_size();
+        vm.resize_heap(new_base + new_size);
+
+        let mut mem = vm.mem_view();
+        if old_size > 0 {
+            mem.copy(new_base, old_base, old_size);
+        }
+        mem.write_bytes(new_base + offset, &init);
+
+        vm.sys_state.thread_state.tls_base = new_base;
+    }
+
+    Value::from(offset)
+}
+
+// Syscall to get the address of the TLS block of the current thread
+// void* base = tls_base()
+pub fn tls_base(vm: &mut VM) -> Value
+{
+    Value::from(vm.sys_state.thread_state.tls_base)
+}
+
+// Syscall to start a thread which calls a function with one argument
+// u64 tid = thread_spawn(void* fun, void* arg)
+pub fn thread_spawn(vm: &mut VM, fun: Value, arg: Value) -> Value
+{
+    let fun = fun.as_usize();
+    if fun >= vm.code_size() {
+        panic!("thread_spawn: invalid function address 0x{:x}", fun);
+    }
+
+    // Each thread gets its own copy of the TLS template, which
+    // is allocated like a block of the guest so that it can be
+    // freed once the thread finishes
+    let tls_size = vm.sys_state.thread_state.tls_template.len();
+    let tls_base = if tls_size > 0 {
+        let base = mem_alloc(vm, Value::from(tls_size)).as_usize();
+        if base == 0 {
+            return Value::from(0);
+        }
+
+        let template = std::mem::take(&mut vm.sys_state.thread_state.tls_template);
+        vm.mem_view().write_bytes(base, &template);
+        vm.sys_state.thread_state.tls_template = template;
+        base
+    } else {
+        0
+    };
+
+    Value::from(vm.spawn_thread(fun, arg, tls_base))
+}
+
+// Syscall to wait for a thread to finish and get its return value
+// u64 ret_val = thread_join(u64 tid)
+pub fn thread_join(vm: &mut VM, tid: Value) -> Value
+{
+    // If the thread is still running, the running thread waits
+    // for it, and this value is replaced by the return value
+    vm.join_thread(tid.as_u64()).unwrap_or(Value::from(0))
+}
+
+// Syscall to get the id of the current thread
+// u64 tid = thread_id()
+pub fn thread_id(vm: &mut VM) -> Value
+{
+    Value::from(vm.thread_id())
+}
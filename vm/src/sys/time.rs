@@ -1,18 +1,74 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::vm::{VM, Value};
+use crate::vm::{VM, Value, ExitReason};
+use crate::sys::sys_err;
+use crate::sys::constants::*;
 
 // Callback function to be run at a given time stamp
 #[derive(Debug, Copy, Clone)]
 struct DelayCb
 {
     time_ms: u64,
+
+    // Order in which the callbacks were scheduled, so that
+    // callbacks with the same time stamp run in that order
+    seq: u64,
+
     pc: u64,
+
+    // Handle and interval of a repeating timer, 0 for one-shot callbacks
+    timer_id: u64,
+    interval_ms: u64,
+}
+
+impl PartialEq for DelayCb
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DelayCb {}
+
+impl PartialOrd for DelayCb
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering>
+    {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayCb
+{
+    fn cmp(&self, other: &Self) -> Ordering
+    {
+        (self.time_ms, self.seq).cmp(&(other.time_ms, other.seq))
+    }
+}
+
+// Source of the time stamps seen by the guest program
+#[derive(Debug, Copy, Clone)]
+enum Clock
+{
+    // Host wall-clock time
+    Real,
+
+    // Time which only moves forward when advanced by the host,
+    // in milliseconds since the unix epoch
+    Virtual(u64),
 }
 
 pub struct TimeState
 {
-    // List of delay callbacks
-    delay_cbs: Vec<DelayCb>,
+    // Priority queue of delay callbacks, the next one to run first
+    delay_cbs: BinaryHeap<Reverse<DelayCb>>,
+
+    // Number of callbacks scheduled so far
+    num_scheduled: u64,
+
+    clock: Clock,
 }
 
 impl TimeState
@@ -20,13 +76,81 @@ impl TimeState
     pub fn new() -> Self
     {
         Self {
-            delay_cbs: Vec::default(),
+            delay_cbs: BinaryHeap::default(),
+            num_scheduled: 0,
+            clock: Clock::Real,
+        }
+    }
+
+    /// Stop following the host clock. Time starts at the given time stamp
+    /// and then only moves forward through advance_time, so that the timing
+    /// of callbacks is reproducible and sleeping is never needed
+    pub fn use_virtual_clock(&mut self, start_ms: u64)
+    {
+        self.clock = Clock::Virtual(start_ms);
+    }
+
+    pub fn is_virtual(&self) -> bool
+    {
+        matches!(self.clock, Clock::Virtual(_))
+    }
+
+    /// Get the current time stamp in milliseconds
+    pub fn now_ms(&self) -> u64
+    {
+        match self.clock {
+            Clock::Real => get_time_ms(),
+            Clock::Virtual(time_ms) => time_ms,
+        }
+    }
+
+    /// Add a callback to the queue
+    fn schedule(&mut self, time_ms: u64, pc: u64, timer_id: u64, interval_ms: u64)
+    {
+        self.delay_cbs.push(Reverse(DelayCb {
+            time_ms,
+            seq: self.num_scheduled,
+            pc,
+            timer_id,
+            interval_ms,
+        }));
+        self.num_scheduled += 1;
+    }
+
+    /// Time stamp of the next callback to run
+    fn next_time_ms(&self) -> Option<u64>
+    {
+        self.delay_cbs.peek().map(|Reverse(cb)| cb.time_ms)
+    }
+
+    /// Take the next callback off the queue if it is due at a given time
+    /// stamp, and was scheduled before a given number of callbacks had
+    /// been scheduled. Repeating timers are scheduled again before their callback
+    /// runs, so that the callback can cancel its own timer. If the host
+    /// falls behind, the runs of a timer which were missed are skipped.
+    fn pop_due(&mut self, time_ms: u64, max_seq: u64) -> Option<DelayCb>
+    {
+        let Reverse(next_cb) = self.delay_cbs.peek()?;
+        if next_cb.time_ms > time_ms || next_cb.seq >= max_seq {
+            return None;
+        }
+
+        let Reverse(cb) = self.delay_cbs.pop().unwrap();
+
+        if cb.timer_id != 0 {
+            let mut next_ms = cb.time_ms + cb.interval_ms;
+            if next_ms <= time_ms {
+                next_ms = time_ms + cb.interval_ms;
+            }
+            self.schedule(next_ms, cb.pc, cb.timer_id, cb.interval_ms);
         }
+
+        Some(cb)
     }
 }
 
-/// Get the current time stamp in milliseconds
-pub fn get_time_ms() -> u64
+/// Get the current host time stamp in milliseconds
+fn get_time_ms() -> u64
 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
 }
@@ -34,7 +158,7 @@ pub fn get_time_ms() -> u64
 /// Get the current time stamp in milliseconds since the unix epoch
 pub fn time_current_ms(vm: &mut VM) -> Value
 {
-    Value::from(get_time_ms())
+    Value::from(vm.sys_state.time_state.now_ms())
 }
 
 /// Call a callback function after a given delay in milliseconds
@@ -43,51 +167,191 @@ pub fn time_delay_cb(vm: &mut VM, delay_ms: Value, callback_pc: Value)
     let delay_ms = delay_ms.as_u64();
     let callback_pc = callback_pc.as_u64();
 
-    let time_ms = get_time_ms();
+    let time_state = &mut vm.sys_state.time_state;
+    let time_ms = time_state.now_ms();
+    time_state.schedule(time_ms + delay_ms, callback_pc, 0, 0);
+}
 
-    let cb_entry = DelayCb {
-        time_ms: time_ms + delay_ms,
-        pc: callback_pc
-    };
+/// Entry of a repeating timer in the handle table
+/// Its callbacks are kept in the queue of delay callbacks
+struct TimerHandle;
+
+/// Call a callback function repeatedly, at a given interval in
+/// milliseconds, and get the handle of the timer
+pub fn time_interval_cb(vm: &mut VM, interval_ms: Value, callback_pc: Value) -> Value
+{
+    let interval_ms = interval_ms.as_u64();
+    let callback_pc = callback_pc.as_u64();
+
+    if interval_ms == 0 {
+        panic!("time_interval_cb: the interval must be at least 1ms");
+    }
+
+    let timer_id = vm.sys_state.handles.insert(TimerHandle);
 
-    // Add the new callback to the list
     let time_state = &mut vm.sys_state.time_state;
-    time_state.delay_cbs.push(cb_entry);
+    let time_ms = time_state.now_ms();
+    time_state.schedule(time_ms + interval_ms, callback_pc, timer_id, interval_ms);
 
-    // Sort the callbacks by decreasing trigger time
-    time_state.delay_cbs.sort_by(|a, b| b.time_ms.cmp(&a.time_ms));
+    Value::from(timer_id)
 }
 
-/// Compute the time untl the next delay callback needs to run
+/// Stop a repeating timer
+/// Returns -EBADF if the handle isn't a timer which is still running
+pub fn time_cancel_cb(vm: &mut VM, timer_id: Value) -> Value
+{
+    let timer_id = timer_id.as_u64();
+
+    if vm.sys_state.handles.remove::<TimerHandle>(timer_id).is_none() {
+        return sys_err(EBADF);
+    }
+
+    let time_state = &mut vm.sys_state.time_state;
+    time_state.delay_cbs.retain(|Reverse(cb)| cb.timer_id != timer_id);
+
+    Value::from(0)
+}
+
+/// Compute the time until the next delay callback needs to run
 pub fn time_until_next_cb(vm: &VM) -> Option<u64>
 {
     let time_state = &vm.sys_state.time_state;
+    let cb_time = time_state.next_time_ms()?;
+    Some(cb_time.saturating_sub(time_state.now_ms()))
+}
 
-    let last_cb = time_state.delay_cbs.last();
+/// Run a timer callback, or queue an EVENT_TIMER event with
+/// the id of the timer if the callback is null
+fn run_cb(vm: &mut VM, cb: DelayCb) -> ExitReason
+{
+    if cb.pc == 0 {
+        vm.sys_state.event_queue.push(EVENT_TIMER, &[Value::from(cb.timer_id)]);
+        return ExitReason::default();
+    }
+
+    vm.call_sync(cb.pc, &[])
+}
 
-    match last_cb {
-        None => return None,
-        Some(cb) => {
-            let cb_time = cb.time_ms;
-            let cur_time = get_time_ms();
-            let time_to = if cb_time > cur_time { cb_time - cur_time } else { 0 };
-            return Some(time_to);
+/// Run the callbacks which are due, in the order of their trigger times.
+/// Callbacks scheduled while they run, including the next runs of
+/// repeating timers, wait for the next iteration of the event loop.
+/// Returns the exit value if a callback exits.
+pub fn run_due_cbs(vm: &mut VM) -> Option<Value>
+{
+    let time_state = &vm.sys_state.time_state;
+    let cur_time_ms = time_state.now_ms();
+    let num_scheduled = time_state.num_scheduled;
+
+    while let Some(cb) = vm.sys_state.time_state.pop_due(cur_time_ms, num_scheduled) {
+        if let ExitReason::Exit(val) = run_cb(vm, cb) {
+            return Some(val);
         }
     }
+
+    None
 }
 
-/// Get the list of PCs for callbacks to be run now
-pub fn get_cbs_to_run(vm: &mut VM) -> Vec<u64>
+/// Move the virtual clock forward by a given number of milliseconds,
+/// running the delay callbacks which become due in the order of their
+/// trigger times. Each callback sees the clock at its own trigger time.
+/// Returns the exit value if a callback exits.
+pub fn advance_time(vm: &mut VM, delta_ms: u64) -> Option<Value>
 {
-    let time_state = &mut vm.sys_state.time_state;
+    let end_ms = match vm.sys_state.time_state.clock {
+        Clock::Virtual(time_ms) => time_ms + delta_ms,
+        Clock::Real => panic!("advance_time requires a virtual clock"),
+    };
+
+    loop {
+        let time_state = &mut vm.sys_state.time_state;
+        let time_ms = match time_state.next_time_ms() {
+            Some(time_ms) if time_ms <= end_ms => time_ms.max(time_state.now_ms()),
+            _ => break,
+        };
+
+        time_state.clock = Clock::Virtual(time_ms);
+        let cb = time_state.pop_due(time_ms, u64::MAX).unwrap();
+
+        if let ExitReason::Exit(val) = run_cb(vm, cb) {
+            return Some(val);
+        }
+    }
+
+    vm.sys_state.time_state.clock = Clock::Virtual(end_ms);
+    None
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+
+    #[test]
+    fn test_virtual_clock()
+    {
+        // Interval timer which fires every 10ms, and exits with 1
+        // if it ever observes a time other than its trigger time
+        let mut vm = Assembler::new().parse_str("
+            .data;
+            COUNT: .u64 0;
+            .code;
+            push 10; push CB; syscall time_delay_cb;
+            push 0; ret;
+            CB:
+            push COUNT; push COUNT; load_u64; push 1; add_u64; store_u64;
+            syscall time_current_ms; push 1000; sub_u64;
+            push COUNT; load_u64; push 10; mul_u64;
+            ne_u64; jnz FAIL;
+            push 10; push CB; syscall time_delay_cb;
+            push 0; ret;
+            FAIL: push 1; exit;
+        ").unwrap();
+
+        vm.sys_state.time_state.use_virtual_clock(1000);
+        vm.call(0, &[]);
+
+        let count = |vm: &mut VM| unsafe { *vm.get_heap_ptr::<u64>(0, 1) };
+
+        assert_eq!(advance_time(&mut vm, 9), None);
+        assert_eq!(count(&mut vm), 0);
+        assert_eq!(advance_time(&mut vm, 1), None);
+        assert_eq!(count(&mut vm), 1);
 
-    // Extract callbacks to run and extract the PCs
-    let cur_time_ms = get_time_ms();
-    let cbs_to_run = time_state.delay_cbs.iter().filter(|cb| cb.time_ms <= cur_time_ms);
-    let pcs_to_run = cbs_to_run.map(|cb| cb.pc).collect();
+        // Callbacks scheduled by callbacks run within the same advance
+        assert_eq!(advance_time(&mut vm, 95), None);
+        assert_eq!(count(&mut vm), 10);
+        assert_eq!(vm.sys_state.time_state.now_ms(), 1105);
+        assert_eq!(time_until_next_cb(&vm), Some(5));
+    }
+
+    #[test]
+    fn test_timer_queue()
+    {
+        let mut state = TimeState::new();
+        state.use_virtual_clock(0);
+        state.schedule(20, 1, 0, 0);
+        state.schedule(10, 2, 0, 0);
+        state.schedule(10, 3, 0, 0);
+        state.schedule(5, 4, 7, 5);
 
-    // Remove the callbacks to run from the list
-    time_state.delay_cbs.retain(|cb| cb.time_ms > cur_time_ms);
+        // Callbacks due at the same time run in the order they were scheduled
+        let mut pcs = Vec::new();
+        while let Some(cb) = state.pop_due(12, 4) {
+            pcs.push(cb.pc);
+        }
+        assert_eq!(pcs, [4, 2, 3]);
+
+        // The repeating timer missed its run at 10, so the next one is at 17
+        assert_eq!(state.next_time_ms(), Some(17));
+        assert_eq!(state.pop_due(20, u64::MAX).unwrap().pc, 4);
+        assert_eq!(state.pop_due(20, u64::MAX).unwrap().pc, 1);
+        assert!(state.pop_due(20, u64::MAX).is_none());
 
-    return pcs_to_run;
+        // Further behind, the timer runs once and then waits a full interval
+        let cb = state.pop_due(40, u64::MAX).unwrap();
+        assert_eq!((cb.pc, cb.time_ms), (4, 22));
+        assert_eq!(state.next_time_ms(), Some(45));
+        assert!(state.pop_due(40, u64::MAX).is_none());
+    }
 }
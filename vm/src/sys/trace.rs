@@ -0,0 +1,116 @@
+// Spans recorded by the guest for profiling, in the Chrome trace event format
+// https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use std::fmt::Write;
+use std::time::Instant;
+use crate::vm::{VM, Value};
+
+struct Event
+{
+    /// Span name, only recorded for begin events
+    name: Option<String>,
+
+    /// Microseconds since tracing started
+    ts: f64,
+}
+
+#[derive(Default)]
+pub struct TraceState
+{
+    /// Time tracing was enabled at, if it is enabled
+    start_time: Option<Instant>,
+
+    events: Vec<Event>,
+
+    /// Number of spans currently open
+    depth: usize,
+}
+
+impl TraceState
+{
+    pub fn enable(&mut self)
+    {
+        self.start_time = Some(Instant::now());
+    }
+
+    fn elapsed_us(&self) -> f64
+    {
+        self.start_time.unwrap().elapsed().as_nanos() as f64 / 1000.0
+    }
+
+    /// Produce the JSON trace of the spans recorded so far,
+    /// ending the spans which are still open
+    pub fn to_json(&self) -> String
+    {
+        let end_ts = self.elapsed_us();
+        let open_ends: Vec<Event> = (0..self.depth).map(|_| Event { name: None, ts: end_ts }).collect();
+
+        let mut lines = Vec::new();
+        for event in self.events.iter().chain(open_ends.iter()) {
+            let mut line = match &event.name {
+                Some(name) => format!("{{\"name\":{},\"ph\":\"B\"", json_str(name)),
+                None => "{\"ph\":\"E\"".to_string(),
+            };
+            write!(line, ",\"ts\":{:.3},\"pid\":1,\"tid\":1}}", event.ts).unwrap();
+            lines.push(line);
+        }
+
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", lines.join(",\n"))
+    }
+}
+
+/// Quote a string for JSON output
+fn json_str(s: &str) -> String
+{
+    let mut out = "\"".to_string();
+
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            ch if (ch as u32) < 0x20 => write!(out, "\\u{:04x}", ch as u32).unwrap(),
+            ch => out.push(ch),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+pub fn trace_begin(vm: &mut VM, name: Value)
+{
+    if vm.sys_state.trace_state.start_time.is_none() {
+        return;
+    }
+
+    let name = vm.mem_view().str(name.as_usize()).to_string();
+    let state = &mut vm.sys_state.trace_state;
+    let ts = state.elapsed_us();
+    state.events.push(Event { name: Some(name), ts });
+    state.depth += 1;
+}
+
+pub fn trace_end(vm: &mut VM)
+{
+    let state = &mut vm.sys_state.trace_state;
+    if state.start_time.is_none() || state.depth == 0 {
+        return;
+    }
+
+    let ts = state.elapsed_us();
+    state.events.push(Event { name: None, ts });
+    state.depth -= 1;
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_json_str()
+    {
+        assert_eq!(json_str("a\"b\\c\n\x01é"), "\"a\\\"b\\\\c\\n\\u0001é\"");
+    }
+}
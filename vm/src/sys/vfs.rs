@@ -0,0 +1,648 @@
+// Virtual filesystem that the fs syscalls operate on
+//
+// The guest sees a single tree of absolute paths, made of mount points
+// which map a guest directory to a host directory, or to a filesystem
+// kept in memory for tests and hosts without a filesystem. Nothing is
+// accessible outside of the mount points, except for the directories
+// containing them, which can be listed. Guest paths are normalized
+// before being resolved, and a path can't go above its mount point
+// with ".." components, so that guests can't reach other host files.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write, Seek, SeekFrom, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File opened through the virtual filesystem
+pub trait VfsFile: Read + Write + Seek + Send {}
+impl<T: Read + Write + Seek + Send> VfsFile for T {}
+
+/// How a file is opened
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OpenMode
+{
+    pub read: bool,
+    pub write: bool,
+
+    /// Create the file if it doesn't exist
+    pub create: bool,
+
+    /// Discard the contents of the file
+    pub truncate: bool,
+
+    /// Write at the end of the file
+    pub append: bool,
+}
+
+/// Kind and size of a file or directory
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FileStat
+{
+    pub is_dir: bool,
+
+    /// Size in bytes, 0 for directories
+    pub size: u64,
+}
+
+const DIR_STAT: FileStat = FileStat { is_dir: true, size: 0 };
+
+/// Filesystem which can be mounted in the virtual filesystem
+/// Paths are relative to the mount point, normalized, with components
+/// separated by '/', and empty for the mount point itself
+pub trait FileSystem: Send
+{
+    fn open(&mut self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Get the names of the entries of a directory, in any order
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>>;
+
+    fn stat(&mut self, path: &str) -> io::Result<FileStat>;
+
+    /// Create a directory, whose parent must exist
+    fn mkdir(&mut self, path: &str) -> io::Result<()>;
+
+    /// Remove a file or an empty directory, other than the mount point
+    fn remove(&mut self, path: &str) -> io::Result<()>;
+}
+
+/// Split a guest path into its components, resolving "." and ".."
+/// Relative paths are relative to the root directory
+/// Returns None if the path goes above the root directory
+pub fn normalize_path(path: &str) -> Option<Vec<&str>>
+{
+    let mut parts = Vec::new();
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop()?; }
+            _ => parts.push(part),
+        }
+    }
+
+    Some(parts)
+}
+
+/// Get the names of the entries of a directory, given the
+/// paths of all the files and directories of a filesystem
+pub fn child_names<'a>(dir: &str, paths: impl Iterator<Item = &'a String>) -> Vec<String>
+{
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+
+    let names: HashSet<&str> = paths
+        .filter_map(|path| path.strip_prefix(&prefix))
+        .filter(|rest| !rest.is_empty())
+        .map(|rest| rest.split('/').next().unwrap())
+        .collect();
+
+    names.into_iter().map(String::from).collect()
+}
+
+fn not_found() -> io::Error
+{
+    io::Error::from(ErrorKind::NotFound)
+}
+
+/// Host directory mapped into the guest filesystem
+pub struct HostDir
+{
+    root: PathBuf,
+}
+
+impl HostDir
+{
+    pub fn new(root: &str) -> Result<Self, String>
+    {
+        match std::fs::canonicalize(root) {
+            Ok(root) if root.is_dir() => Ok(Self { root }),
+            _ => Err(format!("\"{}\" is not a directory", root)),
+        }
+    }
+
+    /// Get the host path for a path relative to the mount point. Symbolic
+    /// links can point outside of the directory, so the closest existing
+    /// ancestor of the path must still be inside of it once resolved.
+    fn host_path(&self, path: &str) -> io::Result<PathBuf>
+    {
+        let host_path = self.root.join(path);
+
+        let mut ancestor: &Path = &host_path;
+        let resolved = loop {
+            match std::fs::canonicalize(ancestor) {
+                Ok(resolved) => break resolved,
+                Err(_) => ancestor = ancestor.parent().ok_or_else(not_found)?,
+            }
+        };
+
+        if !resolved.starts_with(&self.root) {
+            return Err(io::Error::from(ErrorKind::PermissionDenied));
+        }
+
+        Ok(host_path)
+    }
+}
+
+impl FileSystem for HostDir
+{
+    fn open(&mut self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>
+    {
+        let file = std::fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write)
+            .create(mode.create)
+            .truncate(mode.truncate)
+            .append(mode.append)
+            .open(self.host_path(path)?)?;
+
+        // Directories can be opened for reading on some hosts
+        if file.metadata()?.is_dir() {
+            return Err(io::Error::from(ErrorKind::IsADirectory));
+        }
+
+        Ok(Box::new(file))
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>>
+    {
+        let mut names = Vec::new();
+
+        for entry in std::fs::read_dir(self.host_path(path)?)? {
+            // Names which aren't valid UTF-8 can't be used in guest paths
+            if let Ok(name) = entry?.file_name().into_string() {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn stat(&mut self, path: &str) -> io::Result<FileStat>
+    {
+        let metadata = std::fs::metadata(self.host_path(path)?)?;
+
+        match metadata.is_dir() {
+            true => Ok(DIR_STAT),
+            false => Ok(FileStat { is_dir: false, size: metadata.len() }),
+        }
+    }
+
+    fn mkdir(&mut self, path: &str) -> io::Result<()>
+    {
+        std::fs::create_dir(self.host_path(path)?)
+    }
+
+    fn remove(&mut self, path: &str) -> io::Result<()>
+    {
+        let host_path = self.host_path(path)?;
+
+        // A symbolic link is removed, rather than what it points to
+        match std::fs::symlink_metadata(&host_path)?.is_dir() {
+            true => std::fs::remove_dir(host_path),
+            false => std::fs::remove_file(host_path),
+        }
+    }
+}
+
+/// Maximum size of a file of a MemFs, so that writing far past the
+/// end of a file can't exhaust the memory of the host
+pub const MAX_MEM_FILE_SIZE: u64 = 256 << 20;
+
+/// Filesystem kept in memory, which is empty when created
+/// Files can be created in directories which don't exist,
+/// and directories are implied by the paths of the files
+#[derive(Default)]
+pub struct MemFs
+{
+    files: HashMap<String, Arc<Mutex<Vec<u8>>>>,
+
+    /// Directories which were created or had all of their files removed
+    dirs: HashSet<String>,
+}
+
+impl MemFs
+{
+    fn is_dir(&self, path: &str) -> bool
+    {
+        let prefix = format!("{}/", path);
+        path.is_empty() || self.dirs.contains(path) ||
+            self.files.keys().chain(self.dirs.iter()).any(|name| name.starts_with(&prefix))
+    }
+
+    /// Error for a path which should be a directory
+    fn not_dir(&self, path: &str) -> io::Error
+    {
+        match self.files.contains_key(path) {
+            true => io::Error::from(ErrorKind::NotADirectory),
+            false => not_found(),
+        }
+    }
+}
+
+/// File of a MemFs, the contents are shared by all the open files
+struct MemFile
+{
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+    append: bool,
+}
+
+impl FileSystem for MemFs
+{
+    fn open(&mut self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>
+    {
+        if self.is_dir(path) {
+            return Err(io::Error::from(ErrorKind::IsADirectory));
+        }
+
+        let data = match self.files.get(path) {
+            Some(data) => data.clone(),
+            None if mode.create => {
+                let data = Arc::new(Mutex::new(Vec::new()));
+                self.files.insert(path.to_string(), data.clone());
+                data
+            }
+            None => return Err(not_found()),
+        };
+
+        if mode.truncate {
+            data.lock().unwrap().clear();
+        }
+
+        Ok(Box::new(MemFile { data, pos: 0, append: mode.append }))
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>>
+    {
+        if !self.is_dir(path) {
+            return Err(self.not_dir(path));
+        }
+
+        Ok(child_names(path, self.files.keys().chain(self.dirs.iter())))
+    }
+
+    fn stat(&mut self, path: &str) -> io::Result<FileStat>
+    {
+        match self.files.get(path) {
+            Some(data) => Ok(FileStat { is_dir: false, size: data.lock().unwrap().len() as u64 }),
+            None if self.is_dir(path) => Ok(DIR_STAT),
+            None => Err(not_found()),
+        }
+    }
+
+    fn mkdir(&mut self, path: &str) -> io::Result<()>
+    {
+        if self.files.contains_key(path) || self.is_dir(path) {
+            return Err(io::Error::from(ErrorKind::AlreadyExists));
+        }
+
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        if !self.is_dir(parent) {
+            return Err(self.not_dir(parent));
+        }
+
+        self.dirs.insert(path.to_string());
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> io::Result<()>
+    {
+        if self.files.remove(path).is_none() {
+            if !self.is_dir(path) {
+                return Err(not_found());
+            }
+
+            if !child_names(path, self.files.keys().chain(self.dirs.iter())).is_empty() {
+                return Err(io::Error::from(ErrorKind::DirectoryNotEmpty));
+            }
+
+            self.dirs.remove(path);
+        }
+
+        // Keep the parent directory when its last entry is removed
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.dirs.insert(parent.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for MemFile
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let data = self.data.lock().unwrap();
+        let start = std::cmp::min(self.pos, data.len() as u64) as usize;
+        let num_bytes = std::cmp::min(buf.len(), data.len() - start);
+
+        buf[..num_bytes].copy_from_slice(&data[start..start + num_bytes]);
+        self.pos += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl Write for MemFile
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let mut data = self.data.lock().unwrap();
+        if self.append {
+            self.pos = data.len() as u64;
+        }
+
+        // Writing past the end fills the gap with zeros
+        let end = match self.pos.checked_add(buf.len() as u64) {
+            Some(end) if end <= MAX_MEM_FILE_SIZE => end as usize,
+            _ => return Err(io::Error::from(ErrorKind::StorageFull)),
+        };
+        let start = self.pos as usize;
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64>
+    {
+        let len = self.data.lock().unwrap().len() as i64;
+
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset as i64),
+            SeekFrom::End(offset) => len.checked_add(offset),
+            SeekFrom::Current(offset) => (self.pos as i64).checked_add(offset),
+        };
+
+        match new_pos {
+            Some(new_pos) if new_pos >= 0 => {
+                self.pos = new_pos as u64;
+                Ok(self.pos)
+            }
+            _ => Err(io::Error::from(ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+/// Mount points of the guest filesystem
+#[derive(Default)]
+pub struct Vfs
+{
+    /// Guest directory of each mount point and its filesystem
+    mounts: Vec<(Vec<String>, Box<dyn FileSystem>)>,
+}
+
+impl Vfs
+{
+    /// Make a filesystem visible at a guest directory
+    pub fn mount(&mut self, guest_dir: &str, fs: Box<dyn FileSystem>) -> Result<(), String>
+    {
+        let dir: Vec<String> = match normalize_path(guest_dir) {
+            Some(parts) => parts.into_iter().map(String::from).collect(),
+            None => return Err(format!("invalid mount point \"{}\"", guest_dir)),
+        };
+
+        if self.mounts.iter().any(|(mount_dir, _)| *mount_dir == dir) {
+            return Err(format!("\"{}\" is already mounted", guest_dir));
+        }
+
+        self.mounts.push((dir, fs));
+        Ok(())
+    }
+
+    /// Find the filesystem a guest path is in, and the path relative to
+    /// its mount point. The innermost mount point containing the path wins.
+    fn resolve(&mut self, path: &str) -> io::Result<(&mut dyn FileSystem, String)>
+    {
+        let parts = normalize_path(path).ok_or_else(not_found)?;
+
+        let (dir, fs) = self.mounts.iter_mut()
+            .filter(|(dir, _)| parts.len() >= dir.len() && parts.iter().zip(dir.iter()).all(|(a, b)| a == b))
+            .max_by_key(|(dir, _)| dir.len())
+            .ok_or_else(not_found)?;
+
+        Ok((fs.as_mut(), parts[dir.len()..].join("/")))
+    }
+
+    /// Get the names of the mount points directly inside of a guest directory
+    fn mounts_in(&self, parts: &[&str]) -> Vec<String>
+    {
+        self.mounts.iter()
+            .filter(|(dir, _)| dir.len() > parts.len() && parts.iter().zip(dir.iter()).all(|(a, b)| a == b))
+            .map(|(dir, _)| dir[parts.len()].clone())
+            .collect()
+    }
+
+    /// Check if a guest directory is a mount point or contains mount points
+    fn has_mounts(&self, parts: &[&str]) -> bool
+    {
+        self.mounts.iter().any(|(dir, _)| dir.len() >= parts.len() && parts.iter().zip(dir.iter()).all(|(a, b)| a == b))
+    }
+
+    pub fn open(&mut self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>
+    {
+        let (fs, path) = self.resolve(path)?;
+        fs.open(&path, mode)
+    }
+
+    /// Get the sorted names of the entries of a directory,
+    /// including the mount points directly inside of it
+    pub fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>>
+    {
+        let parts = normalize_path(path).ok_or_else(not_found)?;
+        let mut names = self.mounts_in(&parts);
+
+        match self.resolve(path).and_then(|(fs, path)| fs.list_dir(&path)) {
+            Ok(fs_names) => names.extend(fs_names),
+            // Directories containing mount points exist outside of any mount point
+            Err(e) if e.kind() == ErrorKind::NotFound && !names.is_empty() => {}
+            Err(e) => return Err(e),
+        }
+
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    pub fn stat(&mut self, path: &str) -> io::Result<FileStat>
+    {
+        let has_mounts = self.has_mounts(&normalize_path(path).ok_or_else(not_found)?);
+
+        match self.resolve(path).and_then(|(fs, path)| fs.stat(&path)) {
+            Err(e) if e.kind() == ErrorKind::NotFound && has_mounts => Ok(DIR_STAT),
+            result => result,
+        }
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> io::Result<()>
+    {
+        if self.has_mounts(&normalize_path(path).ok_or_else(not_found)?) {
+            return Err(io::Error::from(ErrorKind::AlreadyExists));
+        }
+
+        let (fs, path) = self.resolve(path)?;
+        fs.mkdir(&path)
+    }
+
+    /// Remove a file or an empty directory. Mount points
+    /// and the directories containing them can't be removed.
+    pub fn remove(&mut self, path: &str) -> io::Result<()>
+    {
+        if self.has_mounts(&normalize_path(path).ok_or_else(not_found)?) {
+            return Err(io::Error::from(ErrorKind::ResourceBusy));
+        }
+
+        let (fs, path) = self.resolve(path)?;
+        fs.remove(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const READ: OpenMode = OpenMode { read: true, write: false, create: false, truncate: false, append: false };
+    const CREATE: OpenMode = OpenMode { read: false, write: true, create: true, truncate: true, append: false };
+
+    fn read_all(vfs: &mut Vfs, path: &str) -> io::Result<String>
+    {
+        let mut text = String::new();
+        vfs.open(path, READ)?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    #[test]
+    fn test_normalize_path()
+    {
+        assert_eq!(normalize_path("/a//b/./c/"), Some(vec!["a", "b", "c"]));
+        assert_eq!(normalize_path("a/b/../c"), Some(vec!["a", "c"]));
+        assert_eq!(normalize_path("/"), Some(vec![]));
+        assert_eq!(normalize_path("/a/../.."), None);
+    }
+
+    #[test]
+    fn test_mem_fs()
+    {
+        let mut vfs = Vfs::default();
+        vfs.mount("/tmp", Box::new(MemFs::default())).unwrap();
+
+        vfs.open("/tmp/dir/a.txt", CREATE).unwrap().write_all(b"hello").unwrap();
+        assert_eq!(read_all(&mut vfs, "/tmp/dir/../dir/a.txt").unwrap(), "hello");
+
+        let mut file = vfs.open("/tmp/dir/a.txt", OpenMode { write: true, ..Default::default() }).unwrap();
+        file.seek(SeekFrom::Start(7)).unwrap();
+        file.write_all(b"!").unwrap();
+        assert_eq!(read_all(&mut vfs, "/tmp/dir/a.txt").unwrap(), "hello\0\0!");
+
+        let mut file = vfs.open("/tmp/dir/a.txt", OpenMode { write: true, append: true, ..Default::default() }).unwrap();
+        file.write_all(b"?").unwrap();
+        assert_eq!(read_all(&mut vfs, "/tmp/dir/a.txt").unwrap(), "hello\0\0!?");
+
+        // Files can't grow past the size limit, wherever they are written to
+        let mut file = vfs.open("/tmp/dir/a.txt", OpenMode { write: true, ..Default::default() }).unwrap();
+        for offset in [MAX_MEM_FILE_SIZE, 1 << 40, i64::MAX as u64] {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            assert_eq!(file.write(b"!").err().unwrap().kind(), ErrorKind::StorageFull);
+        }
+        assert_eq!(vfs.stat("/tmp/dir/a.txt").unwrap().size, 9);
+
+        assert_eq!(vfs.open("/tmp/b.txt", READ).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(vfs.open("/tmp/dir", READ).err().unwrap().kind(), ErrorKind::IsADirectory);
+        assert_eq!(vfs.open("/other/a.txt", CREATE).err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_dirs()
+    {
+        let mut vfs = Vfs::default();
+        vfs.mount("/tmp", Box::new(MemFs::default())).unwrap();
+        vfs.mount("/tmp/mem", Box::new(MemFs::default())).unwrap();
+        vfs.mount("/data/saves", Box::new(MemFs::default())).unwrap();
+
+        vfs.open("/tmp/dir/a.txt", CREATE).unwrap().write_all(b"hello").unwrap();
+        vfs.mkdir("/tmp/empty").unwrap();
+        assert_eq!(vfs.list_dir("/tmp").unwrap(), ["dir", "empty", "mem"]);
+        assert_eq!(vfs.list_dir("/tmp/dir").unwrap(), ["a.txt"]);
+        assert_eq!(vfs.list_dir("/tmp/empty").unwrap(), Vec::<String>::new());
+        assert_eq!(vfs.list_dir("/tmp/dir/a.txt").err().unwrap().kind(), ErrorKind::NotADirectory);
+
+        assert_eq!(vfs.stat("/tmp/dir/a.txt").unwrap(), FileStat { is_dir: false, size: 5 });
+        assert_eq!(vfs.stat("/tmp/empty").unwrap(), DIR_STAT);
+        assert_eq!(vfs.stat("/tmp/b.txt").err().unwrap().kind(), ErrorKind::NotFound);
+
+        // Directories containing mount points can be listed
+        assert_eq!(vfs.list_dir("/").unwrap(), ["data", "tmp"]);
+        assert_eq!(vfs.list_dir("/data").unwrap(), ["saves"]);
+        assert_eq!(vfs.stat("/data").unwrap(), DIR_STAT);
+        assert_eq!(vfs.list_dir("/other").err().unwrap().kind(), ErrorKind::NotFound);
+
+        assert_eq!(vfs.mkdir("/tmp/dir").err().unwrap().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(vfs.mkdir("/tmp/dir/a.txt/b").err().unwrap().kind(), ErrorKind::NotADirectory);
+        assert_eq!(vfs.mkdir("/tmp/x/y").err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(vfs.mkdir("/data").err().unwrap().kind(), ErrorKind::AlreadyExists);
+
+        // Removing the last file of a directory keeps the directory
+        assert_eq!(vfs.remove("/tmp/dir").err().unwrap().kind(), ErrorKind::DirectoryNotEmpty);
+        vfs.remove("/tmp/dir/a.txt").unwrap();
+        assert_eq!(vfs.open("/tmp/dir/a.txt", READ).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(vfs.stat("/tmp/dir").unwrap(), DIR_STAT);
+        vfs.remove("/tmp/dir").unwrap();
+        vfs.remove("/tmp/empty").unwrap();
+        assert_eq!(vfs.list_dir("/tmp").unwrap(), ["mem"]);
+        assert_eq!(vfs.remove("/tmp/empty").err().unwrap().kind(), ErrorKind::NotFound);
+
+        // Mount points can't be removed
+        assert_eq!(vfs.remove("/tmp/mem").err().unwrap().kind(), ErrorKind::ResourceBusy);
+        assert_eq!(vfs.remove("/data").err().unwrap().kind(), ErrorKind::ResourceBusy);
+    }
+
+    #[test]
+    fn test_host_dir()
+    {
+        let root = std::env::temp_dir().join(format!("uvm_vfs_test_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("assets/a.txt"), "asset").unwrap();
+        std::fs::write(root.join("secret.txt"), "secret").unwrap();
+
+        let mut vfs = Vfs::default();
+        vfs.mount("/assets", Box::new(HostDir::new(root.join("assets").to_str().unwrap()).unwrap())).unwrap();
+        vfs.mount("/assets/mem", Box::new(MemFs::default())).unwrap();
+        assert!(vfs.mount("/assets/", Box::new(MemFs::default())).is_err());
+
+        assert_eq!(read_all(&mut vfs, "/assets/a.txt").unwrap(), "asset");
+        assert!(read_all(&mut vfs, "/assets/../secret.txt").is_err());
+        assert!(read_all(&mut vfs, "/assets/../../secret.txt").is_err());
+
+        // The innermost mount point is used
+        vfs.open("/assets/mem/b.txt", CREATE).unwrap();
+        assert!(!root.join("assets/mem").exists());
+
+        vfs.mkdir("/assets/sub").unwrap();
+        assert!(root.join("assets/sub").is_dir());
+        assert_eq!(vfs.list_dir("/assets").unwrap(), ["a.txt", "mem", "sub"]);
+        assert_eq!(vfs.stat("/assets/a.txt").unwrap(), FileStat { is_dir: false, size: 5 });
+        assert_eq!(vfs.stat("/assets/sub").unwrap(), DIR_STAT);
+        vfs.remove("/assets/sub").unwrap();
+        assert!(!root.join("assets/sub").exists());
+        assert_eq!(vfs.list_dir("/assets/..").unwrap(), ["assets"]);
+
+        // Symbolic links can't lead out of the directory
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("secret.txt"), root.join("assets/link.txt")).unwrap();
+            assert_eq!(read_all(&mut vfs, "/assets/link.txt").err().unwrap().kind(), ErrorKind::PermissionDenied);
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
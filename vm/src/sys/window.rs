@@ -10,9 +10,13 @@ use sdl2::render::Texture;
 use sdl2::render::TextureAccess;
 use sdl2::pixels::PixelFormatEnum;
 
-use std::time::Duration;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
-use crate::sys::{SysState, get_sdl_context};
+use crate::sys::{SysState, get_sdl_context, sys_err, graphics};
+use crate::sys::event::dispatch_event;
+use crate::sys::constants::*;
 use crate::vm::{VM, Value, ExitReason};
 
 /// SDL video subsystem
@@ -35,16 +39,27 @@ fn get_video_subsystem() -> &'static mut sdl2::VideoSubsystem
     }
 }
 
-struct Window<'a>
+struct Window
 {
     width: u32,
     height: u32,
-    window_id: u32,
+
+    // Handle the guest refers to the window by
+    handle: u64,
+
+    // Id SDL reports events for the window with
+    sdl_id: u32,
+
+    // Pixel format of the frame buffer and its size in bytes per pixel
+    pixel_format: PixelFormatEnum,
+    bytes_per_pixel: u32,
 
     // SDL canvas to draw into
+    // The texture creator lives as long as the program,
+    // like the window, since windows are never destroyed
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
-    texture_creator: sdl2::render::TextureCreator<sdl2::video::WindowContext>,
-    texture: Option<Texture<'a>>,
+    texture_creator: &'static sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+    texture: Option<Texture<'static>>,
 
     // Callbacks for mouse events
     cb_mousemove: u64,
@@ -55,35 +70,147 @@ struct Window<'a>
     cb_keydown: u64,
     cb_keyup: u64,
     cb_textinput: u64,
+
+    // Callback and destination buffer for drag and drop file events
+    cb_dropfile: u64,
+    dropfile_buf: usize,
+    dropfile_buf_len: usize,
+
+    // Frame timing statistics
+    frame_stats: FrameStats,
 }
 
-// Note: we're leaving this global to avoid the Window lifetime
-// bubbling up everywhere.
-// TODO: eventually we will likely want to allow multiple windows
-static mut WINDOW: Option<Window> = None;
+/// Number of recent frames used to compute the average frame rate
+const FRAME_STATS_WINDOW: usize = 60;
 
-fn get_window(window_id: u32) -> &'static mut Window<'static>
+/// Timing information about the frames drawn into a window
+struct FrameStats
 {
-    if window_id != 0 {
-        panic!("for now, only one window supported");
+    // Time between display refreshes
+    refresh_period: Duration,
+
+    // Time at which the last frame was drawn
+    last_frame_time: Option<Instant>,
+
+    // Durations of the most recent frames
+    frame_times: VecDeque<Duration>,
+
+    // Number of display refreshes missed between frames
+    dropped_frames: u64,
+
+    // Total number of frames drawn
+    frame_count: u64,
+}
+
+impl FrameStats
+{
+    fn new(refresh_rate: i32) -> Self
+    {
+        // Assume 60Hz if the display doesn't report its refresh rate
+        let refresh_rate = if refresh_rate > 0 { refresh_rate } else { 60 };
+
+        Self {
+            refresh_period: Duration::from_secs(1) / refresh_rate as u32,
+            last_frame_time: None,
+            frame_times: VecDeque::default(),
+            dropped_frames: 0,
+            frame_count: 0,
+        }
     }
 
-    unsafe {
-        WINDOW.as_mut().unwrap()
+    /// Record that a new frame was just drawn
+    fn add_frame(&mut self)
+    {
+        let now = Instant::now();
+
+        if let Some(last_time) = self.last_frame_time {
+            let frame_time = now - last_time;
+
+            if self.frame_times.len() == FRAME_STATS_WINDOW {
+                self.frame_times.pop_front();
+            }
+            self.frame_times.push_back(frame_time);
+
+            // A frame spanning n refresh periods means n-1 refreshes were missed
+            let num_periods = (frame_time.as_secs_f64() / self.refresh_period.as_secs_f64()).round() as u64;
+            if num_periods > 1 {
+                self.dropped_frames += num_periods - 1;
+            }
+        }
+
+        self.last_frame_time = Some(now);
+        self.frame_count += 1;
+    }
+
+    fn last_frame_us(&self) -> u64
+    {
+        match self.frame_times.back() {
+            Some(time) => time.as_micros() as u64,
+            None => 0,
+        }
+    }
+
+    fn avg_fps(&self) -> u64
+    {
+        let total: Duration = self.frame_times.iter().sum();
+
+        if total.is_zero() {
+            return 0;
+        }
+
+        (self.frame_times.len() as f64 / total.as_secs_f64()).round() as u64
     }
 }
 
-pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, flags: Value) -> Value
+/// Entry of a window in the handle table
+/// The window itself is kept in WINDOWS
+struct WindowHandle;
+
+thread_local! {
+    /// Windows that have been created, indexed by handle
+    /// This is thread-local because SDL windows don't implement the Send
+    /// trait, and syscalls always run on the thread that runs the VM
+    static WINDOWS: RefCell<HashMap<u64, Window>> = RefCell::new(HashMap::default());
+}
+
+/// Run a function on the window a handle refers to,
+/// or return -EBADF if the handle isn't a valid window handle
+/// The function can't make calls into the guest, which could use the window
+fn with_window(vm: &mut VM, window_id: Value, f: impl FnOnce(&mut VM, &mut Window) -> Value) -> Value
 {
-    unsafe {
-        if WINDOW.is_some() {
-            panic!("for now, only one window supported");
-        }
+    let handle = window_id.as_u64();
+
+    if !vm.sys_state.handles.contains::<WindowHandle>(handle) {
+        return sys_err(EBADF);
     }
 
+    WINDOWS.with_borrow_mut(|windows| f(vm, windows.get_mut(&handle).unwrap()))
+}
+
+/// Get information about the window an SDL event is for,
+/// or None if the event is for a window the guest doesn't know
+fn window_info<R>(sdl_id: u32, f: impl FnOnce(&Window) -> R) -> Option<R>
+{
+    WINDOWS.with_borrow(|windows| windows.values().find(|window| window.sdl_id == sdl_id).map(f))
+}
+
+pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, flags: Value) -> Value
+{
     let width: u32 = width.as_usize().try_into().unwrap();
     let height: u32 = height.as_usize().try_into().unwrap();
-    let title_str = vm.get_heap_str(title.as_usize()).to_owned();
+    let title_str = vm.mem_view().str(title.as_usize()).to_owned();
+
+    // The low 8 bits of the flags select the pixel format
+    // SDL takes care of converting the frame data for display
+    let (pixel_format, bytes_per_pixel) = match flags.as_u8() {
+        // Pixels use the BGRA byte order (0xAA_RR_GG_BB on a little-endian machine)
+        PIXEL_FORMAT_BGRA32 => (PixelFormatEnum::BGRA32, 4),
+        // Pixels use the RGBA byte order (0xAA_BB_GG_RR on a little-endian machine)
+        PIXEL_FORMAT_RGBA32 => (PixelFormatEnum::RGBA32, 4),
+        // 16-bit little-endian pixels, 5 bits red (high), 6 bits green, 5 bits blue (low)
+        PIXEL_FORMAT_RGB565 => (PixelFormatEnum::RGB565, 2),
+        _ => return sys_err(EINVAL),
+    };
 
     let video_subsystem = get_video_subsystem();
 
@@ -99,12 +226,23 @@ pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, fla
     canvas.clear();
     canvas.present();
 
-    let texture_creator = canvas.texture_creator();
+    let texture_creator = Box::leak(Box::new(canvas.texture_creator()));
+    let sdl_id = canvas.window().id();
+
+    let refresh_rate = match canvas.window().display_mode() {
+        Ok(mode) => mode.refresh_rate,
+        Err(_) => 0,
+    };
+
+    let handle = vm.sys_state.handles.insert(WindowHandle);
 
     let window = Window {
         width,
         height,
-        window_id: 0,
+        handle,
+        sdl_id,
+        pixel_format,
+        bytes_per_pixel,
         canvas,
         texture_creator,
         texture: None,
@@ -114,30 +252,38 @@ pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, fla
         cb_keydown: 0,
         cb_keyup: 0,
         cb_textinput: 0,
+        cb_dropfile: 0,
+        dropfile_buf: 0,
+        dropfile_buf_len: 0,
+        frame_stats: FrameStats::new(refresh_rate),
     };
 
-    unsafe {
-        WINDOW = Some(window)
-    }
+    WINDOWS.with_borrow_mut(|windows| windows.insert(handle, window));
 
-    // TODO: return unique window id
-    Value::from(0)
+    Value::from(handle)
 }
 
-pub fn window_draw_frame(vm: &mut VM, window_id: Value, src_addr: Value)
+pub fn window_draw_frame(vm: &mut VM, window_id: Value, src_addr: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
+    with_window(vm, window_id, |vm, window| {
+        draw_frame(vm, window, src_addr.as_usize());
+        Value::from(0)
+    })
+}
 
+/// Copy a frame of pixels from guest memory into a window
+fn draw_frame(vm: &mut VM, window: &mut Window, src_addr: usize)
+{
     // Get the address to copy pixel data from
-    let data_len = (4 * window.width * window.height) as usize;
-    let data_ptr = vm.get_heap_ptr(src_addr.as_usize(), data_len);
+    let data_len = (window.bytes_per_pixel * window.width * window.height) as usize;
+    let mem = vm.mem_view();
+    let pixel_slice: &[u8] = mem.slice(src_addr, data_len);
 
     // If no frame has been drawn yet
     if window.texture.is_none() {
         // Creat the texture to render into
-        // Pixels use the BGRA byte order (0xAA_RR_GG_BB on a little-endian machine)
         window.texture = Some(window.texture_creator.create_texture(
-            PixelFormatEnum::BGRA32,
+            window.pixel_format,
             TextureAccess::Streaming,
             window.width,
             window.height
@@ -150,8 +296,7 @@ pub fn window_draw_frame(vm: &mut VM, window_id: Value, src_addr: Value)
     }
 
     // Update the texture
-    let pitch = 4 * window.width as usize;
-    let pixel_slice = unsafe { std::slice::from_raw_parts(data_ptr, data_len) };
+    let pitch = (window.bytes_per_pixel * window.width) as usize;
     window.texture.as_mut().unwrap().update(None, pixel_slice, pitch).unwrap();
 
     // Copy the texture into the canvas
@@ -163,44 +308,118 @@ pub fn window_draw_frame(vm: &mut VM, window_id: Value, src_addr: Value)
 
     // Update the screen with any rendering performed since the previous call
     window.canvas.present();
+
+    window.frame_stats.add_frame();
 }
 
-pub fn window_on_mousemove(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_draw_surface(vm: &mut VM, window_id: Value, surface_id: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
-    window.cb_mousemove = cb.as_u64();
+    let bitmap = match vm.sys_state.handles.get::<graphics::Surface>(surface_id.as_u64()) {
+        Some(surface) => surface.bitmap,
+        None => return sys_err(EBADF)
+    };
+
+    with_window(vm, window_id, |vm, window| {
+        // Surfaces always have 32-bit pixels
+        if window.bytes_per_pixel != 4 ||
+           bitmap.width != window.width as usize ||
+           bitmap.height != window.height as usize {
+            return sys_err(EINVAL);
+        }
+
+        draw_frame(vm, window, bitmap.pixels);
+        Value::from(0)
+    })
 }
 
-pub fn window_on_mousedown(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_frame_stats(vm: &mut VM, window_id: Value, stats_ptr: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
-    window.cb_mousedown = cb.as_u64();
+    with_window(vm, window_id, |vm, window| {
+        let frame_stats = &window.frame_stats;
+
+        let mut mem = vm.mem_view();
+        let stats: &mut [u64] = mem.slice_mut(stats_ptr.as_usize(), 4);
+        stats[0] = frame_stats.last_frame_us();
+        stats[1] = frame_stats.avg_fps();
+        stats[2] = frame_stats.dropped_frames;
+        stats[3] = frame_stats.frame_count;
+        Value::from(0)
+    })
 }
 
-pub fn window_on_mouseup(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_mousemove(vm: &mut VM, window_id: Value, cb: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
-    window.cb_mouseup = cb.as_u64();
+    with_window(vm, window_id, |_, window| {
+        window.cb_mousemove = cb.as_u64();
+        Value::from(0)
+    })
 }
 
-pub fn window_on_keydown(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_mousedown(vm: &mut VM, window_id: Value, cb: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
-    window.cb_keydown = cb.as_u64();
+    with_window(vm, window_id, |_, window| {
+        window.cb_mousedown = cb.as_u64();
+        Value::from(0)
+    })
 }
 
-pub fn window_on_keyup(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_mouseup(vm: &mut VM, window_id: Value, cb: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
-    window.cb_keyup = cb.as_u64();
+    with_window(vm, window_id, |_, window| {
+        window.cb_mouseup = cb.as_u64();
+        Value::from(0)
+    })
 }
 
-pub fn window_on_textinput(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_keydown(vm: &mut VM, window_id: Value, cb: Value) -> Value
 {
-    let window = get_window(window_id.as_u32());
-    let video_subsystem = get_video_subsystem();
-    video_subsystem.text_input().start();
-    window.cb_textinput = cb.as_u64();
+    with_window(vm, window_id, |_, window| {
+        window.cb_keydown = cb.as_u64();
+        Value::from(0)
+    })
+}
+
+pub fn window_on_keyup(vm: &mut VM, window_id: Value, cb: Value) -> Value
+{
+    with_window(vm, window_id, |_, window| {
+        window.cb_keyup = cb.as_u64();
+        Value::from(0)
+    })
+}
+
+pub fn window_on_textinput(vm: &mut VM, window_id: Value, cb: Value) -> Value
+{
+    with_window(vm, window_id, |_, window| {
+        let video_subsystem = get_video_subsystem();
+        video_subsystem.text_input().start();
+        window.cb_textinput = cb.as_u64();
+        Value::from(0)
+    })
+}
+
+pub fn window_on_dropfile(vm: &mut VM, window_id: Value, path_buf: Value, buf_len: Value, cb: Value) -> Value
+{
+    let path_buf = path_buf.as_usize();
+    let buf_len = buf_len.as_usize();
+
+    // Dropped files are only reported if the host allows it
+    if !vm.sys_state.allow_dropfile {
+        return sys_err(EPERM);
+    }
+
+    if buf_len == 0 {
+        panic!("window_on_dropfile needs room for at least a null terminator");
+    }
+
+    with_window(vm, window_id, |vm, window| {
+        // Validate the buffer bounds now rather than when a file gets dropped
+        vm.mem_view().slice::<u8>(path_buf, buf_len);
+
+        window.dropfile_buf = path_buf;
+        window.dropfile_buf_len = buf_len;
+        window.cb_dropfile = cb.as_u64();
+        Value::from(0)
+    })
 }
 
 /// Process SDL events
@@ -247,6 +466,12 @@ pub fn process_events(vm: &mut VM) -> ExitReason
                 }
             }
 
+            Event::DropFile { window_id, filename, .. } => {
+                if let ExitReason::Exit(val) = window_call_dropfile(vm, window_id, &filename) {
+                    return ExitReason::Exit(val);
+                }
+            }
+
             Event::TextInput { window_id, text, .. } => {
                 // For each UTF-8 byte of input
                 for ch in text.bytes() {
@@ -272,14 +497,12 @@ pub fn process_events(vm: &mut VM) -> ExitReason
 // we should handle window-related events here instead
 fn window_call_mousemove(vm: &mut VM, window_id: u32, x: i32, y: i32) -> ExitReason
 {
-    let window = get_window(0);
-    let cb = window.cb_mousemove;
-
-    if cb == 0 {
-        return ExitReason::default();
-    }
+    let (handle, cb) = match window_info(window_id, |window| (window.handle, window.cb_mousemove)) {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
 
-    vm.call(cb, &[Value::from(window.window_id), Value::from(x), Value::from(y)])
+    dispatch_event(vm, cb, EVENT_MOUSEMOVE, &[Value::from(handle), Value::from(x), Value::from(y)])
 }
 
 /*
@@ -294,12 +517,10 @@ MouseButtonDown {
 */
 fn window_call_mousedown(vm: &mut VM, window_id: u32, mouse_btn: MouseButton, x: i32, y: i32) -> ExitReason
 {
-    let window = get_window(0);
-    let cb = window.cb_mousedown;
-
-    if cb == 0 {
-        return ExitReason::default();
-    }
+    let (handle, cb) = match window_info(window_id, |window| (window.handle, window.cb_mousedown)) {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
 
     // TODO: ignore SDL_TOUCH_MOUSEID
     // where is that defined in Rust?
@@ -317,8 +538,8 @@ fn window_call_mousedown(vm: &mut VM, window_id: u32, mouse_btn: MouseButton, x:
         }
     };
 
-    vm.call(cb, &[
-        Value::from(window.window_id),
+    dispatch_event(vm, cb, EVENT_MOUSEDOWN, &[
+        Value::from(handle),
         Value::from(btn_id),
         Value::from(x),
         Value::from(y),
@@ -327,12 +548,10 @@ fn window_call_mousedown(vm: &mut VM, window_id: u32, mouse_btn: MouseButton, x:
 
 fn window_call_mouseup(vm: &mut VM, window_id: u32, mouse_btn: MouseButton, x: i32, y: i32) -> ExitReason
 {
-    let window = get_window(0);
-    let cb = window.cb_mouseup;
-
-    if cb == 0 {
-        return ExitReason::default();
-    }
+    let (handle, cb) = match window_info(window_id, |window| (window.handle, window.cb_mouseup)) {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
 
     // TODO: ignore SDL_TOUCH_MOUSEID
     // where is that defined in Rust?
@@ -350,8 +569,8 @@ fn window_call_mouseup(vm: &mut VM, window_id: u32, mouse_btn: MouseButton, x: i
         }
     };
 
-    vm.call(cb, &[
-        Value::from(window.window_id),
+    dispatch_event(vm, cb, EVENT_MOUSEUP, &[
+        Value::from(handle),
         Value::from(btn_id),
         Value::from(x),
         Value::from(y),
@@ -428,17 +647,15 @@ fn translate_keycode(sdl_keycode: Keycode) -> Option<u16>
 
 fn window_call_keydown(vm: &mut VM, window_id: u32, keycode: Keycode) -> ExitReason
 {
-    let window = get_window(0);
-    let cb = window.cb_keydown;
-
-    if cb == 0 {
-        return ExitReason::default();
-    }
+    let (handle, cb) = match window_info(window_id, |window| (window.handle, window.cb_keydown)) {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
 
     let keycode = translate_keycode(keycode);
 
     if let Some(keycode) = keycode {
-        vm.call(cb, &[Value::from(window.window_id), Value::from(keycode)])
+        dispatch_event(vm, cb, EVENT_KEYDOWN, &[Value::from(handle), Value::from(keycode)])
     } else {
         ExitReason::default()
     }
@@ -446,17 +663,15 @@ fn window_call_keydown(vm: &mut VM, window_id: u32, keycode: Keycode) -> ExitRea
 
 fn window_call_keyup(vm: &mut VM, window_id: u32, keycode: Keycode) -> ExitReason
 {
-    let window = get_window(0);
-    let cb = window.cb_keyup;
-
-    if cb == 0 {
-        return ExitReason::default();
-    }
+    let (handle, cb) = match window_info(window_id, |window| (window.handle, window.cb_keyup)) {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
 
     let keycode = translate_keycode(keycode);
 
     if let Some(keycode) = keycode {
-        vm.call(cb, &[Value::from(window.window_id), Value::from(keycode)])
+        dispatch_event(vm, cb, EVENT_KEYUP, &[Value::from(handle), Value::from(keycode)])
     } else {
         ExitReason::default()
     }
@@ -464,12 +679,36 @@ fn window_call_keyup(vm: &mut VM, window_id: u32, keycode: Keycode) -> ExitReaso
 
 fn window_call_textinput(vm: &mut VM, window_id: u32, utf8_byte: u8) -> ExitReason
 {
-    let window = get_window(0);
-    let cb = window.cb_textinput;
+    let (handle, cb) = match window_info(window_id, |window| (window.handle, window.cb_textinput)) {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
+
+    dispatch_event(vm, cb, EVENT_TEXTINPUT, &[Value::from(handle), Value::from(utf8_byte)])
+}
+
+fn window_call_dropfile(vm: &mut VM, window_id: u32, path: &str) -> ExitReason
+{
+    let info = window_info(window_id, |window| {
+        (window.handle, window.cb_dropfile, window.dropfile_buf, window.dropfile_buf_len)
+    });
+    let (handle, cb, dropfile_buf, dropfile_buf_len) = match info {
+        Some(info) => info,
+        None => return ExitReason::default(),
+    };
 
     if cb == 0 {
         return ExitReason::default();
     }
 
-    vm.call(cb, &[Value::from(window.window_id), Value::from(utf8_byte)])
+    // Copy the path into the guest buffer, truncating it so that
+    // there is always room for the null terminator
+    let path_bytes = path.as_bytes();
+    let path_len = std::cmp::min(path_bytes.len(), dropfile_buf_len - 1);
+    let mut mem = vm.mem_view();
+    let buf: &mut [u8] = mem.slice_mut(dropfile_buf, path_len + 1);
+    buf[..path_len].copy_from_slice(&path_bytes[..path_len]);
+    buf[path_len] = 0;
+
+    vm.call_sync(cb, &[Value::from(handle), Value::from(path_len)])
 }
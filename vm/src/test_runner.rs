@@ -0,0 +1,164 @@
+//! Runner for the test suites of guest programs and libraries
+//!
+//! `uvm test dir/` runs every image, asm and C file in a directory as a
+//! separate program, and a test passes if its program exits with code 0.
+//! Each test runs in its own uvm process, with the VM options given before
+//! the `test` command, so that a test which fails a check or crashes the
+//! VM doesn't stop the others. The output of the failed tests is printed
+//! along with a summary. C files are compiled with the ncc command, which
+//! can be set with the NCC environment variable, and runs in the current
+//! directory so that it finds its include files. With --coverage, each test
+//! writes its own report, and the reports are concatenated into the given
+//! file, which lcov tools read as the combined coverage of the tests.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Extensions of the files which are tests
+const TEST_EXTENSIONS: [&str; 3] = ["uvm", "asm", "c"];
+
+/// List the test files in a directory, sorted by name
+fn find_tests(dir: &str) -> Result<Vec<PathBuf>, String>
+{
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("could not read \"{}\": {}", dir, e))?;
+
+    let mut tests: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| path.extension().is_some_and(|ext| TEST_EXTENSIONS.iter().any(|e| ext == *e)))
+        .collect();
+
+    tests.sort();
+    Ok(tests)
+}
+
+/// Compile a C test to an asm file next to the other temporary files
+fn compile_c(path: &Path, idx: usize) -> Result<PathBuf, Output>
+{
+    let ncc = std::env::var("NCC").unwrap_or("ncc".to_string());
+    let out_file = std::env::temp_dir().join(format!("uvm_test_{}_{}.asm", std::process::id(), idx));
+
+    let output = Command::new(&ncc)
+        .arg("-o")
+        .arg(&out_file)
+        .arg(path)
+        .stdin(Stdio::null())
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => Ok(out_file),
+        Ok(output) => Err(output),
+        Err(e) => Err(Output {
+            status: Default::default(),
+            stdout: Vec::default(),
+            stderr: format!("could not run {}: {}\n", ncc, e).into_bytes(),
+        }),
+    }
+}
+
+/// Describe how a test process ended
+fn status_text(output: &Output) -> String
+{
+    match output.status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "killed by a signal".to_string(),
+    }
+}
+
+/// Print the output captured from a failed test
+fn print_output(name: &str, output: &Output)
+{
+    println!("---- output of {} ----", name);
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    println!();
+}
+
+/// Run the tests in a directory with some VM options,
+/// and get the exit code of the uvm command
+pub fn run_tests(dir: &str, vm_args: &[String]) -> i32
+{
+    let tests = match find_tests(dir) {
+        Ok(tests) => tests,
+        Err(error) => {
+            println!("Error: {}", error);
+            return 1;
+        }
+    };
+
+    if tests.is_empty() {
+        println!("Error: no tests found in \"{}\"", dir);
+        return 1;
+    }
+
+    let uvm = std::env::current_exe().unwrap();
+    println!("running {} tests", tests.len());
+
+    // Each test writes its coverage report to a temporary file
+    let coverage_idx = vm_args.iter().position(|arg| arg == "--coverage").map(|idx| idx + 1).filter(|&idx| idx < vm_args.len());
+    let coverage_out = coverage_idx.map(|idx| vm_args[idx].clone());
+    let mut vm_args = vm_args.to_vec();
+    let mut coverage = String::new();
+
+    let mut failed = Vec::new();
+    for (idx, path) in tests.iter().enumerate() {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        let program = if path.extension().is_some_and(|ext| ext == "c") {
+            match compile_c(path, idx) {
+                Ok(out_file) => out_file,
+                Err(output) => {
+                    println!("{}: FAILED (compilation failed)", name);
+                    failed.push((name, output));
+                    continue;
+                }
+            }
+        } else {
+            path.clone()
+        };
+
+        let coverage_file = std::env::temp_dir().join(format!("uvm_test_{}_{}.info", std::process::id(), idx));
+        if let Some(arg_idx) = coverage_idx {
+            vm_args[arg_idx] = coverage_file.display().to_string();
+        }
+
+        let output = Command::new(&uvm)
+            .args(&vm_args)
+            .arg(&program)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        if program != *path {
+            let _ = std::fs::remove_file(&program);
+        }
+
+        if coverage_idx.is_some() {
+            coverage.push_str(&std::fs::read_to_string(&coverage_file).unwrap_or_default());
+            let _ = std::fs::remove_file(&coverage_file);
+        }
+
+        if output.status.success() {
+            println!("{}: ok", name);
+        } else {
+            println!("{}: FAILED ({})", name, status_text(&output));
+            failed.push((name, output));
+        }
+    }
+
+    if !failed.is_empty() {
+        println!();
+        for (name, output) in &failed {
+            print_output(name, output);
+        }
+    }
+
+    if let Some(file_name) = coverage_out {
+        if let Err(error) = std::fs::write(&file_name, coverage) {
+            println!("Error: could not write coverage report to \"{}\": {}", file_name, error);
+        }
+    }
+
+    println!("{} passed, {} failed", tests.len() - failed.len(), failed.len());
+    if failed.is_empty() { 0 } else { 1 }
+}
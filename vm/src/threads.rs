@@ -0,0 +1,535 @@
+//! Green threads for the thread syscalls
+//!
+//! Guest threads are multiplexed by the interpreter on the host thread
+//! which runs the VM. Each thread has its own value stack, call frames
+//! and TLS block, while the heap is shared. The running thread switches
+//! to the next one in round-robin order when it has run a time slice of
+//! basic blocks, when it waits in thread_join, on a mutex or condition
+//! variable or on a pipe, and when it finishes.
+//! The interleaving of threads is deterministic, and since compiled code
+//! can leave a block halfway, the JIT isn't used while there are several
+//! threads, so that they switch at the same points with --jit.
+//!
+//! The main thread, which the host calls functions on, has id 0. When it
+//! returns to the host with other threads left, those threads continue
+//! the next time guest code runs, or when the event loop of the host
+//! gives them the time the main thread is idle with run_threads().
+//!
+//! Mutexes and condition variables are part of the scheduler, so that a
+//! waiting thread doesn't run until it can continue. Unlocking a mutex
+//! hands it to the thread which has waited for it the longest, and the
+//! threads waiting on a condition variable are woken up in order too.
+//!
+//! Pipes are bounded queues of bytes, kept in ring buffers of guest
+//! memory, whose read and write positions the scheduler tracks. A thread
+//! reading from an empty pipe waits until there is data to read, and a
+//! thread writing to a pipe waits until there is room for all the bytes
+//! it writes, so that the bytes of different writes never get interleaved.
+//! The VM completes the read or write once the thread can run again, or
+//! fails it if the pipe was closed in the meantime.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use crate::vm::{Value, StackFrame};
+
+/// Number of basic blocks a thread runs before switching to the next one
+pub const THREAD_SLICE: usize = 1000;
+
+/// What a thread which can't run waits for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WaitFor
+{
+    /// Thread being joined
+    Thread(u64),
+
+    /// Mutex the thread gets once it is handed over
+    Mutex(u64),
+
+    /// Condition variable, with the mutex to lock again once signaled
+    Signal { cond: u64, mutex: u64 },
+
+    /// Pipe with room for a write, with the bytes to write
+    PipeWrite { pipe: u64, src: usize, num_bytes: usize },
+
+    /// Pipe with data to read, with the buffer to read into
+    PipeRead { pipe: u64, dst: usize, num_bytes: usize },
+}
+
+impl fmt::Display for WaitFor
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            WaitFor::Thread(id) => write!(f, "thread {}", id),
+            WaitFor::Mutex(id) => write!(f, "mutex {}", id),
+            WaitFor::Signal { cond, .. } => write!(f, "condition variable {}", cond),
+            WaitFor::PipeWrite { pipe, .. } | WaitFor::PipeRead { pipe, .. } => write!(f, "pipe {}", pipe),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Mutex
+{
+    owner: Option<u64>,
+
+    /// Threads waiting for the mutex, in the order they will get it
+    waiters: VecDeque<u64>,
+}
+
+/// Ring buffer of bytes in guest memory
+struct Pipe
+{
+    /// Address of the buffer, which holds up to capacity bytes
+    buf: usize,
+    capacity: usize,
+
+    /// Offset of the oldest byte in the buffer, and number of bytes queued
+    start: usize,
+    len: usize,
+}
+
+impl Pipe
+{
+    fn room(&self) -> usize
+    {
+        self.capacity - self.len
+    }
+
+    /// Get the ranges of guest memory, as (address, length) pairs, of a
+    /// number of bytes starting at an offset from the oldest byte
+    fn ranges(&self, ofs: usize, num_bytes: usize) -> [(usize, usize); 2]
+    {
+        let pos = (self.start + ofs) % self.capacity;
+        let first = num_bytes.min(self.capacity - pos);
+        [(self.buf + pos, first), (self.buf, num_bytes - first)]
+    }
+}
+
+/// Execution state of a thread which isn't running
+pub struct ThreadContext
+{
+    pub id: u64,
+    pub stack: Vec<Value>,
+
+    /// Call frames, empty for the main thread
+    /// when it is idle in the host
+    pub frames: Vec<StackFrame>,
+
+    pub pc: usize,
+    pub bp: usize,
+    pub tls_base: usize,
+
+    /// What this thread is waiting for, if it can't run
+    pub wait_for: Option<WaitFor>,
+}
+
+pub struct Threads
+{
+    /// Id of the running thread
+    pub cur_id: u64,
+
+    /// Basic blocks left to run before switching threads
+    pub slice_left: usize,
+
+    next_id: u64,
+
+    /// Threads other than the running one, in the order they will run
+    waiting: VecDeque<ThreadContext>,
+
+    /// Return values of the threads which finished and weren't joined
+    results: HashMap<u64, Value>,
+
+    mutexes: Vec<Mutex>,
+
+    /// Threads waiting on each condition variable, in the order they
+    /// will be woken up, with the mutex each one has to lock again
+    conds: Vec<VecDeque<(u64, u64)>>,
+
+    /// Pipes by handle
+    pipes: HashMap<u64, Pipe>,
+}
+
+impl Default for Threads
+{
+    fn default() -> Self
+    {
+        Self {
+            cur_id: 0,
+            slice_left: THREAD_SLICE,
+            next_id: 1,
+            waiting: VecDeque::default(),
+            results: HashMap::default(),
+            mutexes: Vec::default(),
+            conds: Vec::default(),
+            pipes: HashMap::default(),
+        }
+    }
+}
+
+impl Threads
+{
+    /// Check if there are threads other than the running one
+    pub fn has_others(&self) -> bool
+    {
+        !self.waiting.is_empty()
+    }
+
+    /// Check if a thread was spawned and hasn't finished
+    pub fn is_alive(&self, id: u64) -> bool
+    {
+        id == self.cur_id || self.waiting.iter().any(|ctx| ctx.id == id)
+    }
+
+    /// Check if a thread can run, because it isn't waiting, it waits
+    /// for a thread that finished, it was handed the mutex it waits for,
+    /// or the pipe it waits on has room for its write or data to read,
+    /// or was closed
+    fn can_run(&self, ctx: &ThreadContext) -> bool
+    {
+        match ctx.wait_for {
+            None => true,
+            Some(WaitFor::Thread(id)) => self.results.contains_key(&id),
+            Some(WaitFor::Mutex(id)) => self.mutexes[id as usize].owner == Some(ctx.id),
+            Some(WaitFor::Signal { .. }) => false,
+            Some(WaitFor::PipeWrite { pipe, num_bytes, .. }) => self.pipes.get(&pipe).is_none_or(|p| p.room() >= num_bytes),
+            Some(WaitFor::PipeRead { pipe, .. }) => self.pipes.get(&pipe).is_none_or(|p| p.len > 0),
+        }
+    }
+
+    /// Check if one of the waiting threads can run
+    pub fn has_runnable(&self) -> bool
+    {
+        self.waiting.iter().any(|ctx| self.can_run(ctx))
+    }
+
+    /// Add a new thread which will run after the waiting ones
+    pub fn spawn(&mut self, mut ctx: ThreadContext) -> u64
+    {
+        ctx.id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(ctx);
+        self.next_id - 1
+    }
+
+    /// Put the running thread at the end of the queue
+    pub fn save(&mut self, ctx: ThreadContext)
+    {
+        self.waiting.push_back(ctx);
+    }
+
+    /// Record the return value of the running thread, which finished
+    pub fn finish(&mut self, ret_val: Value)
+    {
+        self.results.insert(self.cur_id, ret_val);
+    }
+
+    /// Get the return value of a finished thread, which can only be joined once
+    pub fn take_result(&mut self, id: u64) -> Option<Value>
+    {
+        self.results.remove(&id)
+    }
+
+    /// Take the next thread which can run off the queue, and make it the
+    /// running thread. A thread waiting in thread_join gets the return
+    /// value of the thread it joined on its stack, and a thread waiting
+    /// on a pipe keeps what it waits for, so that the VM can complete its
+    /// read or write. Returns None if all the threads are waiting.
+    pub fn next(&mut self) -> Option<ThreadContext>
+    {
+        let idx = self.waiting.iter().position(|ctx| self.can_run(ctx))?;
+
+        // Threads skipped over go to the end of the queue
+        self.waiting.rotate_left(idx);
+        let mut ctx = self.waiting.pop_front().unwrap();
+
+        match ctx.wait_for.take() {
+            Some(WaitFor::Thread(id)) => ctx.stack.push(self.take_result(id).unwrap()),
+            Some(wait_for @ (WaitFor::PipeWrite { .. } | WaitFor::PipeRead { .. })) => ctx.wait_for = Some(wait_for),
+            _ => {}
+        }
+
+        self.cur_id = ctx.id;
+        self.slice_left = THREAD_SLICE;
+        Some(ctx)
+    }
+
+    /// Create an unlocked mutex and get its id
+    pub fn create_mutex(&mut self) -> u64
+    {
+        self.mutexes.push(Mutex::default());
+        self.mutexes.len() as u64 - 1
+    }
+
+    /// Create a condition variable and get its id
+    pub fn create_cond(&mut self) -> u64
+    {
+        self.conds.push(VecDeque::default());
+        self.conds.len() as u64 - 1
+    }
+
+    pub fn is_mutex(&self, id: u64) -> bool
+    {
+        (id as usize) < self.mutexes.len()
+    }
+
+    pub fn is_cond(&self, id: u64) -> bool
+    {
+        (id as usize) < self.conds.len()
+    }
+
+    /// Thread holding a mutex, if it is locked
+    pub fn mutex_owner(&self, id: u64) -> Option<u64>
+    {
+        self.mutexes[id as usize].owner
+    }
+
+    /// Lock a mutex for the running thread. Returns false if another
+    /// thread holds it, in which case the running thread has to wait
+    /// until the mutex is handed to it.
+    pub fn lock_mutex(&mut self, id: u64) -> bool
+    {
+        self.acquire(id, self.cur_id)
+    }
+
+    /// Lock a mutex for a thread if it is unlocked, otherwise
+    /// add the thread to the threads waiting for it
+    fn acquire(&mut self, id: u64, thread_id: u64) -> bool
+    {
+        let mutex = &mut self.mutexes[id as usize];
+
+        if mutex.owner.is_none() {
+            mutex.owner = Some(thread_id);
+            return true;
+        }
+
+        mutex.waiters.push_back(thread_id);
+        false
+    }
+
+    /// Unlock a mutex, and hand it to the next thread waiting for it
+    pub fn unlock_mutex(&mut self, id: u64)
+    {
+        let mutex = &mut self.mutexes[id as usize];
+        mutex.owner = mutex.waiters.pop_front();
+    }
+
+    /// Add the running thread to the threads waiting on a condition variable
+    pub fn wait_cond(&mut self, cond: u64, mutex: u64)
+    {
+        self.conds[cond as usize].push_back((self.cur_id, mutex));
+    }
+
+    /// Wake up the thread which has waited the longest on a condition
+    /// variable, which then waits until it gets its mutex back
+    pub fn signal_cond(&mut self, cond: u64)
+    {
+        let Some((id, mutex)) = self.conds[cond as usize].pop_front() else {
+            return;
+        };
+
+        self.acquire(mutex, id);
+
+        let ctx = self.waiting.iter_mut().find(|ctx| ctx.id == id).unwrap();
+        ctx.wait_for = Some(WaitFor::Mutex(mutex));
+    }
+
+    /// Add an empty pipe, which queues its bytes in a buffer of guest memory
+    pub fn create_pipe(&mut self, id: u64, buf: usize, capacity: usize)
+    {
+        assert!(capacity > 0);
+        self.pipes.insert(id, Pipe { buf, capacity, start: 0, len: 0 });
+    }
+
+    /// Remove a pipe, the threads waiting on it can then run
+    /// and find out that it was closed
+    pub fn remove_pipe(&mut self, id: u64)
+    {
+        self.pipes.remove(&id);
+    }
+
+    pub fn pipe_capacity(&self, id: u64) -> Option<usize>
+    {
+        self.pipes.get(&id).map(|pipe| pipe.capacity)
+    }
+
+    /// Queue a number of bytes at the end of a pipe, and get the ranges
+    /// of guest memory to copy them into. Returns None if the pipe
+    /// doesn't have room for all of them, in which case the running
+    /// thread has to wait until it does, or if it doesn't exist.
+    pub fn write_pipe(&mut self, id: u64, num_bytes: usize) -> Option<[(usize, usize); 2]>
+    {
+        let pipe = self.pipes.get_mut(&id)?;
+
+        if pipe.room() < num_bytes {
+            return None;
+        }
+
+        let ranges = pipe.ranges(pipe.len, num_bytes);
+        pipe.len += num_bytes;
+        Some(ranges)
+    }
+
+    /// Dequeue up to a given number of bytes from a pipe, and get the
+    /// ranges of guest memory to copy them from. Returns None if the
+    /// pipe is empty, in which case the running thread has to wait until
+    /// there is data to read, or if it doesn't exist.
+    pub fn read_pipe(&mut self, id: u64, max_bytes: usize) -> Option<[(usize, usize); 2]>
+    {
+        let pipe = self.pipes.get_mut(&id)?;
+
+        if pipe.len == 0 {
+            return None;
+        }
+
+        let ranges = pipe.ranges(0, max_bytes.min(pipe.len));
+        let num_bytes = ranges[0].1 + ranges[1].1;
+        pipe.start = (pipe.start + num_bytes) % pipe.capacity;
+        pipe.len -= num_bytes;
+        Some(ranges)
+    }
+
+    /// Remove the threads other than the running one, and get their
+    /// contexts, so that the main thread is the running thread again
+    pub fn clear(&mut self) -> Vec<ThreadContext>
+    {
+        let waiting = std::mem::take(&mut self.waiting);
+        *self = Self::default();
+        waiting.into()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn context(wait_for: Option<WaitFor>) -> ThreadContext
+    {
+        ThreadContext { id: 0, stack: Vec::new(), frames: Vec::new(), pc: 0, bp: 0, tls_base: 0, wait_for }
+    }
+
+    /// Switch away from the running thread, waiting for something
+    fn switch(threads: &mut Threads, wait_for: Option<WaitFor>) -> u64
+    {
+        let mut ctx = context(wait_for);
+        ctx.id = threads.cur_id;
+        threads.save(ctx);
+        threads.next().unwrap().id
+    }
+
+    #[test]
+    fn test_scheduling()
+    {
+        let mut threads = Threads::default();
+        assert!(!threads.has_others());
+
+        // Threads run in the order they were spawned
+        assert_eq!(threads.spawn(context(None)), 1);
+        assert_eq!(threads.spawn(context(None)), 2);
+        assert!(threads.is_alive(0) && threads.is_alive(2) && !threads.is_alive(3));
+
+        let mut main = context(Some(WaitFor::Thread(2)));
+        main.id = threads.cur_id;
+        threads.save(main);
+        assert_eq!(threads.next().unwrap().id, 1);
+        assert_eq!(threads.cur_id, 1);
+
+        // The main thread waits until thread 2 finishes
+        threads.finish(Value::from(5));
+        assert_eq!(threads.next().unwrap().id, 2);
+        assert!(threads.has_others() && !threads.has_runnable());
+        assert!(threads.next().is_none());
+
+        threads.finish(Value::from(7));
+        let main = threads.next().unwrap();
+        assert_eq!(main.id, 0);
+        assert_eq!(main.stack, [Value::from(7)]);
+
+        // Each result can only be taken once
+        assert_eq!(threads.take_result(1), Some(Value::from(5)));
+        assert_eq!(threads.take_result(1), None);
+        assert!(!threads.has_others());
+    }
+
+    #[test]
+    fn test_sync()
+    {
+        let mut threads = Threads::default();
+        threads.spawn(context(None));
+        threads.spawn(context(None));
+        let m = threads.create_mutex();
+        let c = threads.create_cond();
+        assert!(threads.is_mutex(m) && !threads.is_mutex(m + 1) && threads.is_cond(c));
+
+        // Threads 1 and 2 wait for the mutex held by the main thread
+        assert!(threads.lock_mutex(m));
+        assert_eq!(switch(&mut threads, None), 1);
+        assert!(!threads.lock_mutex(m));
+        assert_eq!(switch(&mut threads, Some(WaitFor::Mutex(m))), 2);
+        assert!(!threads.lock_mutex(m));
+        assert_eq!(switch(&mut threads, Some(WaitFor::Mutex(m))), 0);
+
+        // Unlocking hands the mutex to the thread which waited the longest
+        threads.unlock_mutex(m);
+        assert_eq!(threads.mutex_owner(m), Some(1));
+        assert_eq!(switch(&mut threads, None), 1);
+
+        // Thread 1 waits on the condition variable, which gives the mutex to thread 2
+        threads.unlock_mutex(m);
+        threads.wait_cond(c, m);
+        assert_eq!(switch(&mut threads, Some(WaitFor::Signal { cond: c, mutex: m })), 2);
+        assert_eq!(threads.mutex_owner(m), Some(2));
+
+        // Once signaled, thread 1 waits until thread 2 hands the mutex back
+        threads.signal_cond(c);
+        assert_eq!(threads.mutex_owner(m), Some(2));
+        threads.unlock_mutex(m);
+        assert_eq!(threads.mutex_owner(m), Some(1));
+
+        // Signaling with no waiting threads does nothing
+        threads.signal_cond(c);
+        assert_eq!(threads.mutex_owner(m), Some(1));
+    }
+
+    #[test]
+    fn test_pipes()
+    {
+        let mut threads = Threads::default();
+        threads.spawn(context(None));
+        threads.create_pipe(7, 100, 4);
+        assert_eq!(threads.pipe_capacity(7), Some(4));
+        assert_eq!(threads.pipe_capacity(8), None);
+
+        // Thread 1 waits until it can read from the pipe
+        assert_eq!(threads.read_pipe(7, 8), None);
+        assert_eq!(switch(&mut threads, Some(WaitFor::PipeRead { pipe: 7, dst: 0, num_bytes: 8 })), 1);
+        assert!(!threads.has_runnable());
+
+        // Writes are only done when all the bytes fit
+        assert_eq!(threads.write_pipe(7, 3), Some([(100, 3), (100, 0)]));
+        assert_eq!(threads.write_pipe(7, 2), None);
+        assert!(threads.has_runnable());
+
+        // The waiting thread gets what it waited for back
+        let wait_for = Some(WaitFor::PipeWrite { pipe: 7, src: 0, num_bytes: 2 });
+        let ctx = { threads.save(ThreadContext { id: 1, ..context(wait_for) }); threads.next().unwrap() };
+        assert_eq!(ctx.id, 0);
+        assert!(matches!(ctx.wait_for, Some(WaitFor::PipeRead { num_bytes: 8, .. })));
+
+        // Thread 1 can write once there is room, the write wraps around
+        assert!(!threads.has_runnable());
+        assert_eq!(threads.read_pipe(7, 2), Some([(100, 2), (100, 0)]));
+        assert!(threads.has_runnable());
+        assert_eq!(threads.write_pipe(7, 2), Some([(103, 1), (100, 1)]));
+        assert_eq!(threads.read_pipe(7, 8), Some([(102, 2), (100, 1)]));
+
+        // Closing a pipe wakes up the threads waiting on it
+        assert_eq!(switch(&mut threads, Some(WaitFor::PipeRead { pipe: 7, dst: 0, num_bytes: 8 })), 1);
+        assert!(!threads.has_runnable());
+        threads.remove_pipe(7);
+        assert!(threads.has_runnable());
+        assert_eq!(threads.read_pipe(7, 8), None);
+        assert_eq!(threads.write_pipe(7, 1), None);
+    }
+}
@@ -16,3 +16,147 @@ pub fn thousands_sep(n: u64) -> String
 
     num_str
 }
+
+/// Version of the VM as (major, minor, patch)
+pub fn vm_version() -> (u32, u32, u32)
+{
+    let major = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+    let minor = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+    let patch = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap();
+    (major, minor, patch)
+}
+
+/// Parse a version string of the form "major.minor.patch"
+pub fn parse_version(s: &str) -> Option<(u32, u32, u32)>
+{
+    let parts: Vec<&str> = s.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let major = parts[0].parse().ok()?;
+    let minor = parts[1].parse().ok()?;
+    let patch = parts[2].parse().ok()?;
+    Some((major, minor, patch))
+}
+
+/// Hasher for short keys such as label and opcode names, which is much
+/// faster than the default SipHash. It isn't resistant to collisions
+/// chosen by an attacker, which only slows down the tables using it.
+#[derive(Default, Clone, Copy)]
+pub struct NameHasher
+{
+    hash: u64,
+}
+
+impl std::hash::Hasher for NameHasher
+{
+    fn write(&mut self, bytes: &[u8])
+    {
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let word = u64::from_le_bytes(chunk.try_into().unwrap());
+            self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+        }
+        for &byte in chunks.remainder() {
+            self.hash = (self.hash.rotate_left(5) ^ byte as u64).wrapping_mul(SEED);
+        }
+    }
+
+    fn write_u8(&mut self, val: u8)
+    {
+        self.write_u64(val as u64);
+    }
+
+    fn write_u64(&mut self, val: u64)
+    {
+        self.hash = (self.hash.rotate_left(5) ^ val).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn finish(&self) -> u64
+    {
+        self.hash
+    }
+}
+
+/// Hash map keyed by names, using NameHasher
+pub type NameMap<K, V> = std::collections::HashMap<K, V, std::hash::BuildHasherDefault<NameHasher>>;
+
+/// Reader for little-endian binary formats,
+/// which reports truncated input as an error
+pub struct ByteReader<'a>
+{
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a>
+{
+    pub fn new(data: &'a [u8]) -> Self
+    {
+        Self { data, pos: 0 }
+    }
+
+    /// Check if all of the input was read
+    pub fn at_end(&self) -> bool
+    {
+        self.pos == self.data.len()
+    }
+
+    pub fn bytes(&mut self, num_bytes: usize) -> Result<&'a [u8], String>
+    {
+        if num_bytes > self.data.len() - self.pos {
+            return Err("unexpected end of data".to_string());
+        }
+
+        let bytes = &self.data[self.pos..(self.pos + num_bytes)];
+        self.pos += num_bytes;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String>
+    {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String>
+    {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String>
+    {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String>
+    {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String>
+    {
+        usize::try_from(self.u64()?).map_err(|_| "value out of range".to_string())
+    }
+
+    /// Read a u64 count of items of a given size, checking
+    /// that there is enough data left for them
+    pub fn count(&mut self, item_size: usize) -> Result<usize, String>
+    {
+        let count = self.usize()?;
+        if count > (self.data.len() - self.pos) / item_size.max(1) {
+            return Err("unexpected end of data".to_string());
+        }
+        Ok(count)
+    }
+
+    /// Read a string stored as a u64 length followed by UTF-8 bytes
+    pub fn string(&mut self) -> Result<String, String>
+    {
+        let len = self.count(1)?;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "invalid UTF-8 string".to_string())
+    }
+}
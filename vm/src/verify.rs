@@ -0,0 +1,752 @@
+//! Bytecode verifier
+//!
+//! Checks a program image before it runs, so that malformed code is
+//! reported with its location instead of failing partway through execution.
+//! Starting from the entry point and from every code address the program
+//! refers to, the verifier follows the control flow of each function, and
+//! checks that:
+//!
+//! - every instruction has a valid opcode, and its operands fit in the code
+//! - jump and call targets are in range and start an instruction
+//! - execution can't fall through past the end of the code
+//! - no instruction pops more values than the function pushed, on any path
+//!   reaching it. Paths can reach an instruction with different stack depths,
+//!   such as jumps to a shared error handler, in which case the smallest
+//!   depth is the one checked
+//! - direct calls pass every argument the called function reads
+//! - syscalls exist, and loads and stores directly at the address of a
+//!   data label stay within the data section
+//! - the stack depth at each .assert_stack directive is the one asserted,
+//!   which is also checked when the instruction runs with debug assertions
+//!
+//! Code that can't be reached from these entry points isn't checked.
+//!
+//! Embedders can also deny classes of instructions, such as syscalls or
+//! floating-point arithmetic, to run small untrusted programs. Programs
+//! using them are rejected here, and since unchecked code could use them
+//! too, a VM with restrictions traps if execution reaches an instruction
+//! the verifier didn't check, such as through a computed function pointer.
+
+use std::collections::{HashMap, HashSet};
+use crate::vm::{MemBlock, Op};
+use crate::block_cache::{decode, jmp_table_target, MicroOp};
+use crate::image::{Image, RelocKind, Section};
+use crate::sys::constants::{SYSCALL_DESCS, VM_GROW_HEAP, VM_RESIZE_HEAP};
+
+/// Class of instructions which can be denied to a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpClass
+{
+    /// All syscalls
+    Syscall,
+
+    /// The vm_grow_heap and vm_resize_heap syscalls, so that the heap keeps
+    /// its initial size. The VM also locks the heap, since allocations,
+    /// modules and TLS blocks grow it too, including through sys_batch.
+    HeapGrow,
+
+    /// Loads and stores
+    Memory,
+
+    /// Floating-point arithmetic and conversions
+    Float,
+
+    /// Calls through function pointers
+    IndirectCall,
+}
+
+impl OpClass
+{
+    pub const ALL: [OpClass; 5] = [
+        OpClass::Syscall,
+        OpClass::HeapGrow,
+        OpClass::Memory,
+        OpClass::Float,
+        OpClass::IndirectCall,
+    ];
+
+    pub fn name(self) -> &'static str
+    {
+        match self {
+            OpClass::Syscall => "syscall",
+            OpClass::HeapGrow => "heap_grow",
+            OpClass::Memory => "memory",
+            OpClass::Float => "float",
+            OpClass::IndirectCall => "indirect_call",
+        }
+    }
+
+    /// Check if an instruction belongs to the class
+    fn contains(self, uop: &MicroOp) -> bool
+    {
+        use Op::*;
+
+        match self {
+            OpClass::Syscall => uop.op == syscall,
+            OpClass::HeapGrow => uop.op == syscall && matches!(uop.imm as u16, VM_GROW_HEAP | VM_RESIZE_HEAP),
+            OpClass::Memory => access_size(uop.op).is_some(),
+            OpClass::IndirectCall => uop.op == call_fp,
+            OpClass::Float => matches!(uop.op,
+                add_f32 | sub_f32 | mul_f32 | div_f32 | pow_f32 |
+                sin_f32 | cos_f32 | tan_f32 | asin_f32 | acos_f32 | atan_f32 | sqrt_f32 |
+                floor_f32 | ceil_f32 | round_f32 | is_nan_f32 | is_inf_f32 |
+                eq_f32 | ne_f32 | lt_f32 | le_f32 | gt_f32 | ge_f32 |
+                i32_to_f32 | i64_to_f32 | f32_to_i32 |
+                add_f64 | sub_f64 | mul_f64 | div_f64 |
+                sqrt_f64 | floor_f64 | ceil_f64 | round_f64 | is_nan_f64 | is_inf_f64 |
+                eq_f64 | ne_f64 | lt_f64 | le_f64 | gt_f64 | ge_f64 |
+                i64_to_f64 | f64_to_i64 | f32_to_f64 | f64_to_f32
+            ),
+        }
+    }
+}
+
+/// Classes of instructions a VM doesn't allow its program to use
+#[derive(Clone, Debug, Default)]
+pub struct OpRestrictions
+{
+    denied: Vec<OpClass>,
+}
+
+impl OpRestrictions
+{
+    pub fn deny(&mut self, class: OpClass)
+    {
+        if !self.denied.contains(&class) {
+            self.denied.push(class);
+        }
+    }
+
+    /// Deny a class of instructions given by name
+    pub fn deny_name(&mut self, name: &str) -> Result<(), String>
+    {
+        match OpClass::ALL.iter().find(|class| class.name() == name) {
+            Some(&class) => {
+                self.deny(class);
+                Ok(())
+            }
+            None => {
+                let names: Vec<&str> = OpClass::ALL.iter().map(|class| class.name()).collect();
+                Err(format!("unknown instruction class {}, expected one of {}", name, names.join(", ")))
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.denied.is_empty()
+    }
+
+    pub fn denies(&self, class: OpClass) -> bool
+    {
+        self.denied.contains(&class)
+    }
+
+    /// Denied class an instruction belongs to, if any
+    fn denied_class(&self, uop: &MicroOp) -> Option<OpClass>
+    {
+        self.denied.iter().copied().find(|class| class.contains(uop))
+    }
+}
+
+/// Size in bytes of the operands following an opcode
+fn operand_size(op: Op) -> usize
+{
+    match op {
+        Op::getn | Op::setn |
+        Op::get_arg | Op::set_arg |
+        Op::get_local | Op::set_local |
+        Op::enter | Op::push_0n | Op::call_fp |
+        Op::push_i8 => 1,
+        Op::syscall | Op::assert_stack => 2,
+        Op::push_u32 | Op::jmp | Op::jz | Op::jnz => 4,
+        Op::call => 5,
+        Op::push_u64 => 8,
+
+        // Followed by the entries of the table
+        Op::jmp_table => 8,
+        _ => 0,
+    }
+}
+
+/// Number of values an instruction pops and pushes
+fn stack_effect(uop: &MicroOp) -> (usize, usize)
+{
+    use Op::*;
+
+    match uop.op {
+        panic | nop | breakpoint | assert_stack | jmp => (0, 0),
+
+        push_0 | push_1 | push_2 | push_i8 | push_u32 | push_u64 |
+        getn | get_argc | get_arg | get_local => (0, 1),
+        push_0n | enter => (0, uop.idx as usize),
+
+        trap | pop | setn | set_arg | set_local | jz | jnz | jmp_table | ret | exit => (1, 0),
+        dup => (1, 2),
+        swap => (2, 2),
+
+        get_var_arg |
+        not_u32 | not_u64 |
+        sx_i8_i32 | sx_i8_i64 | sx_i16_i32 | sx_i16_i64 | sx_i32_i64 |
+        trunc_u8 | trunc_u16 | trunc_u32 |
+        sin_f32 | cos_f32 | tan_f32 | asin_f32 | acos_f32 | atan_f32 | sqrt_f32 |
+        floor_f32 | ceil_f32 | round_f32 | is_nan_f32 | is_inf_f32 |
+        i32_to_f32 | i64_to_f32 | f32_to_i32 |
+        sqrt_f64 | floor_f64 | ceil_f64 | round_f64 | is_nan_f64 | is_inf_f64 |
+        i64_to_f64 | f64_to_i64 | f32_to_f64 | f64_to_f32 |
+        load_u8 | load_u16 | load_u32 | load_u64 |
+        load_unaligned_u16 | load_unaligned_u32 | load_unaligned_u64 => (1, 1),
+
+        and_u32 | or_u32 | xor_u32 | lshift_u32 | rshift_u32 | rshift_i32 |
+        add_u32 | sub_u32 | mul_u32 | div_u32 | mod_u32 | div_i32 | mod_i32 |
+        eq_u32 | ne_u32 | lt_u32 | le_u32 | gt_u32 | ge_u32 |
+        lt_i32 | le_i32 | gt_i32 | ge_i32 |
+        and_u64 | or_u64 | xor_u64 | lshift_u64 | rshift_u64 | rshift_i64 |
+        add_u64 | sub_u64 | mul_u64 | div_u64 | mod_u64 | div_i64 | mod_i64 |
+        eq_u64 | ne_u64 | lt_u64 | le_u64 | gt_u64 | ge_u64 |
+        lt_i64 | le_i64 | gt_i64 | ge_i64 |
+        add_f32 | sub_f32 | mul_f32 | div_f32 | pow_f32 |
+        eq_f32 | ne_f32 | lt_f32 | le_f32 | gt_f32 | ge_f32 |
+        add_f64 | sub_f64 | mul_f64 | div_f64 |
+        eq_f64 | ne_f64 | lt_f64 | le_f64 | gt_f64 | ge_f64 => (2, 1),
+
+        store_u8 | store_u16 | store_u32 | store_u64 |
+        store_unaligned_u16 | store_unaligned_u32 | store_unaligned_u64 => (2, 0),
+
+        call => (uop.idx as usize, 1),
+        call_fp => (uop.idx as usize + 1, 1),
+
+        // Unknown syscalls are reported before this is used
+        syscall => {
+            let desc = SYSCALL_DESCS[uop.imm as usize].as_ref().unwrap();
+            (desc.argc, desc.has_ret as usize)
+        }
+
+        // Rejected as an invalid opcode when decoding
+        OP_EXT => unreachable!(),
+    }
+}
+
+/// Size of the value accessed by a load or store instruction
+fn access_size(op: Op) -> Option<usize>
+{
+    match op {
+        Op::load_u8 | Op::store_u8 => Some(1),
+        Op::load_u16 | Op::store_u16 | Op::load_unaligned_u16 | Op::store_unaligned_u16 => Some(2),
+        Op::load_u32 | Op::store_u32 | Op::load_unaligned_u32 | Op::store_unaligned_u32 => Some(4),
+        Op::load_u64 | Op::store_u64 | Op::load_unaligned_u64 | Op::store_unaligned_u64 => Some(8),
+        _ => None,
+    }
+}
+
+/// Targets of a jmp_table instruction, the default one first
+fn jmp_table_targets(code: &MemBlock, uop: &MicroOp) -> Vec<usize>
+{
+    let num_entries = code.read::<u32>(uop.imm as usize) as u64;
+    (0..=num_entries).rev().map(|idx| jmp_table_target(code, uop, idx)).collect()
+}
+
+/// What is known about the functions of the program
+#[derive(Default)]
+struct FnInfo
+{
+    /// Number of arguments the function reads, one past the highest index
+    num_args_read: usize,
+}
+
+struct Verifier<'a>
+{
+    image: &'a Image,
+
+    /// Classes of instructions the program may not use
+    restrictions: &'a OpRestrictions,
+
+    /// Smallest stack depth, relative to the base pointer,
+    /// before each instruction checked so far
+    depths: HashMap<usize, usize>,
+
+    /// Set for the bytes that hold the operands of an instruction
+    operand_bytes: Vec<bool>,
+
+    /// Positions in the code of the absolute addresses of data labels
+    data_addrs: HashMap<usize, RelocKind>,
+
+    /// Positions in the code of the offsets to labels imported by a
+    /// module, whose targets are only known once it is linked
+    imported_offsets: HashSet<usize>,
+
+    /// Functions found so far, by entry address
+    functions: HashMap<usize, FnInfo>,
+
+    /// Direct calls (call pc, callee pc, argument count)
+    calls: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> Verifier<'a>
+{
+    fn new(image: &'a Image, restrictions: &'a OpRestrictions) -> Self
+    {
+        let data_addrs = image.relocs.iter()
+            .filter(|r| r.section == Section::Code && r.target == Section::Data)
+            .map(|r| (r.pos, r.kind))
+            .collect();
+
+        let imported_offsets = image.imports.iter()
+            .filter(|i| i.section == Section::Code && matches!(i.kind, RelocKind::Rel32 { .. }))
+            .map(|i| i.pos)
+            .collect();
+
+        Self {
+            image,
+            restrictions,
+            depths: HashMap::default(),
+            operand_bytes: vec![false; image.code.len()],
+            data_addrs,
+            imported_offsets,
+            functions: HashMap::default(),
+            calls: Vec::default(),
+        }
+    }
+
+    /// Describe a code address, using the closest label before it
+    fn location(&self, pc: usize) -> String
+    {
+        let label = self.image.code_labels.iter()
+            .filter(|(_, pos)| *pos <= pc)
+            .max_by_key(|(_, pos)| *pos);
+
+        match label {
+            Some((name, pos)) if *pos == pc => format!("pc {} ({})", pc, name),
+            Some((name, pos)) => format!("pc {} ({}+{})", pc, name, pc - pos),
+            None => format!("pc {}", pc),
+        }
+    }
+
+    fn error(&self, pc: usize, msg: &str) -> String
+    {
+        format!("verification failed at {}: {}", self.location(pc), msg)
+    }
+
+    /// Decode an instruction, checking that it is valid
+    fn decode(&self, pc: usize) -> Result<MicroOp, String>
+    {
+        let code = &self.image.code;
+
+        if pc >= code.len() {
+            return Err(self.error(pc, "execution runs past the end of the code"));
+        }
+        if self.operand_bytes[pc] {
+            return Err(self.error(pc, "jump into the middle of an instruction"));
+        }
+
+        let opcode = code.read::<u8>(pc);
+        if opcode > Op::exit as u8 {
+            return Err(self.error(pc, &format!("invalid opcode {}", opcode)));
+        }
+        let op: Op = unsafe { std::mem::transmute(opcode) };
+
+        if pc + 1 + operand_size(op) > code.len() {
+            return Err(self.error(pc, &format!("operands of {:?} past the end of the code", op)));
+        }
+
+        if op == Op::jmp_table {
+            let num_entries = code.read::<u32>(pc + 1) as usize;
+            if pc + 9 + 4 * num_entries > code.len() {
+                return Err(self.error(pc, "jump table past the end of the code"));
+            }
+        }
+
+        let uop = decode(code, pc);
+
+        if op == Op::syscall && SYSCALL_DESCS.get(uop.imm as usize).is_none_or(|desc| desc.is_none()) {
+            return Err(self.error(pc, &format!("unknown syscall {}", uop.imm)));
+        }
+
+        Ok(uop)
+    }
+
+    /// Known address of a data label pushed by an instruction, if any
+    fn data_addr(&self, pc: usize, uop: &MicroOp) -> Option<usize>
+    {
+        match (uop.op, self.data_addrs.get(&(pc + 1))) {
+            (Op::push_u32, Some(RelocKind::Abs32)) |
+            (Op::push_u64, Some(RelocKind::Abs64)) => Some(uop.imm as usize),
+            _ => None,
+        }
+    }
+
+    /// Check the instructions of the function starting at a given address
+    fn verify_fn(&mut self, entry_pc: usize, fn_queue: &mut Vec<usize>) -> Result<(), String>
+    {
+        let mut num_locals = None;
+        let mut num_args_read = 0;
+
+        // Instructions to check, with the stack depth at each
+        let mut queue = vec![(entry_pc, 0)];
+
+        while let Some((start_pc, start_depth)) = queue.pop() {
+            let mut pc = start_pc;
+            let mut depth = start_depth;
+
+            // Data addresses known to be on the stack, tracked
+            // along straight-line code only
+            let mut known: Vec<Option<usize>> = vec![None; depth];
+
+            loop {
+                // Checks that pass with some stack depth also pass with
+                // a larger one, so only smaller depths need checking again
+                if self.depths.get(&pc).is_some_and(|&d| d <= depth) {
+                    break;
+                }
+
+                let uop = self.decode(pc)?;
+                if let Some(class) = self.restrictions.denied_class(&uop) {
+                    let insn = match uop.op {
+                        Op::syscall => format!("syscall {}", SYSCALL_DESCS[uop.imm as usize].as_ref().unwrap().name),
+                        op => format!("{:?}", op),
+                    };
+                    return Err(self.error(pc, &format!("{} is not allowed, {} instructions are denied", insn, class.name())));
+                }
+                self.depths.insert(pc, depth);
+                for i in pc + 1..uop.next_pc {
+                    if self.depths.contains_key(&i) {
+                        return Err(self.error(i, "jump into the middle of an instruction"));
+                    }
+                    self.operand_bytes[i] = true;
+                }
+
+                let (num_pops, num_pushes) = stack_effect(&uop);
+                if depth < num_pops {
+                    return Err(self.error(pc, &format!("stack underflow in {:?}", uop.op)));
+                }
+
+                let idx = uop.idx as usize;
+                match uop.op {
+                    Op::enter => {
+                        if pc != entry_pc {
+                            return Err(self.error(pc, "enter must be the first instruction of a function"));
+                        }
+                        num_locals = Some(idx);
+                    }
+
+                    Op::getn if idx >= depth => {
+                        return Err(self.error(pc, &format!("getn {} with {} values on the stack", idx, depth)));
+                    }
+                    Op::setn if idx + 2 > depth => {
+                        return Err(self.error(pc, &format!("setn {} with {} values on the stack", idx, depth)));
+                    }
+
+                    Op::get_arg | Op::set_arg => {
+                        num_args_read = num_args_read.max(idx + 1);
+                    }
+
+                    Op::get_local | Op::set_local => {
+                        let limit = num_locals.unwrap_or(depth - num_pops);
+                        if idx >= limit {
+                            return Err(self.error(pc, &format!("invalid local index {}, only {} locals", idx, limit)));
+                        }
+                    }
+
+                    Op::assert_stack if depth != uop.imm as usize => {
+                        return Err(self.error(pc, &format!("stack depth is {}, asserted {}", depth, uop.imm)));
+                    }
+
+                    Op::ret if depth <= num_locals.unwrap_or(0) => {
+                        return Err(self.error(pc, "ret with no return value on stack"));
+                    }
+
+                    // Imported functions are checked with the image defining them
+                    Op::call if self.imported_offsets.contains(&(pc + 1)) => {}
+
+                    Op::jmp | Op::jz | Op::jnz if self.imported_offsets.contains(&(pc + 1)) => {
+                        return Err(self.error(pc, &format!("{:?} to an imported label", uop.op)));
+                    }
+
+                    Op::jmp | Op::jz | Op::jnz | Op::call => {
+                        let target = uop.imm as usize;
+                        if target >= self.image.code.len() {
+                            return Err(self.error(pc, &format!("{:?} target {} outside of the code", uop.op, target)));
+                        }
+                    }
+
+                    Op::jmp_table => {
+                        for target in jmp_table_targets(&self.image.code, &uop) {
+                            if target >= self.image.code.len() {
+                                return Err(self.error(pc, &format!("jmp_table target {} outside of the code", target)));
+                            }
+                        }
+                    }
+
+                    _ => {}
+                }
+
+                if let Some(size) = access_size(uop.op) {
+                    if let Some(addr) = known[depth - num_pops] {
+                        if addr + size > self.image.data.len() {
+                            return Err(self.error(pc, &format!("{:?} at {} past the end of the data section", uop.op, addr)));
+                        }
+                    }
+                }
+
+                // Track the data addresses on the stack
+                let pushed = match uop.op {
+                    Op::dup => vec![known[depth - 1]; 2],
+                    Op::swap => vec![known[depth - 1], known[depth - 2]],
+                    Op::getn => vec![known[depth - 1 - idx]],
+                    _ => vec![self.data_addr(pc, &uop); num_pushes],
+                };
+                if uop.op == Op::setn {
+                    known[depth - 2 - idx] = known[depth - 1];
+                }
+                known.truncate(depth - num_pops);
+                known.extend(pushed);
+
+                depth = depth - num_pops + num_pushes;
+
+                match uop.op {
+                    Op::jmp => {
+                        queue.push((uop.imm as usize, depth));
+                        break;
+                    }
+
+                    // Conditional branches can also fall through
+                    Op::jz | Op::jnz => {
+                        queue.push((uop.imm as usize, depth));
+                        queue.push((uop.next_pc, depth));
+                        break;
+                    }
+
+                    Op::jmp_table => {
+                        for target in jmp_table_targets(&self.image.code, &uop) {
+                            queue.push((target, depth));
+                        }
+                        break;
+                    }
+
+                    Op::call if self.imported_offsets.contains(&(pc + 1)) => {
+                        known = vec![None; depth];
+                    }
+
+                    Op::call => {
+                        let target = uop.imm as usize;
+                        self.calls.push((pc, target, idx));
+                        fn_queue.push(target);
+                        known = vec![None; depth];
+                    }
+
+                    Op::call_fp | Op::syscall => {
+                        known = vec![None; depth];
+                    }
+
+                    Op::ret | Op::exit | Op::panic | Op::trap | Op::breakpoint => break,
+
+                    _ => {}
+                }
+
+                pc = uop.next_pc;
+            }
+        }
+
+        self.functions.insert(entry_pc, FnInfo { num_args_read });
+        Ok(())
+    }
+
+    /// Code addresses the program refers to other than through direct calls,
+    /// such as function pointers, callbacks and exported labels
+    fn address_taken(&self) -> Vec<usize>
+    {
+        let mut addrs = Vec::default();
+
+        for reloc in self.image.relocs.iter().filter(|r| r.target == Section::Code) {
+            let mem = match reloc.section {
+                Section::Code => &self.image.code,
+                Section::Data => &self.image.data,
+            };
+
+            match reloc.kind {
+                RelocKind::Abs32 => addrs.push(mem.read::<u32>(reloc.pos) as usize),
+                RelocKind::Abs64 => addrs.push(mem.read::<u64>(reloc.pos) as usize),
+                RelocKind::Rel32 { .. } => {}
+            }
+        }
+
+        for export in self.image.exports.iter().filter(|e| e.section == Section::Code) {
+            addrs.push(export.pos);
+        }
+
+        addrs
+    }
+
+    /// Check the functions in a queue, and the functions they call
+    fn verify_fns(&mut self, mut fn_queue: Vec<usize>) -> Result<(), String>
+    {
+        while let Some(entry_pc) = fn_queue.pop() {
+            if self.functions.contains_key(&entry_pc) {
+                continue;
+            }
+
+            // A function can't start in the middle of another one
+            if self.depths.get(&entry_pc).is_some_and(|&depth| depth != 0) {
+                return Err(self.error(entry_pc, "function entry point inside another function"));
+            }
+
+            self.verify_fn(entry_pc, &mut fn_queue)?;
+        }
+
+        Ok(())
+    }
+
+    fn verify(mut self) -> Result<Vec<bool>, String>
+    {
+        if self.image.code.len() > 0 {
+            self.verify_fns(vec![0])?;
+        }
+
+        // The address of a label can be taken for other reasons than calling it,
+        // such as comparing return addresses, so addresses that are already
+        // part of a function aren't checked as separate functions
+        for addr in self.address_taken() {
+            if !self.depths.contains_key(&addr) {
+                self.verify_fns(vec![addr])?;
+            }
+        }
+
+        for &(call_pc, callee_pc, num_args) in &self.calls {
+            let num_args_read = self.functions[&callee_pc].num_args_read;
+            if num_args < num_args_read {
+                return Err(self.error(call_pc, &format!(
+                    "call to {} passes {} arguments, but the function reads {}",
+                    self.location(callee_pc), num_args, num_args_read
+                )));
+            }
+        }
+
+        let mut checked = vec![false; self.image.code.len()];
+        for &pc in self.depths.keys() {
+            checked[pc] = true;
+        }
+
+        Ok(checked)
+    }
+}
+
+/// Check the code of a program image before it runs.
+/// The image must not be relocated yet.
+pub fn verify_image(image: &Image) -> Result<(), String>
+{
+    verify_restricted(image, &OpRestrictions::default()).map(|_| ())
+}
+
+/// Check the code of a program image, and that it doesn't use the denied
+/// classes of instructions. Returns a flag for each position in the code,
+/// which is set for the instructions that were checked.
+pub fn verify_restricted(image: &Image, restrictions: &OpRestrictions) -> Result<Vec<bool>, String>
+{
+    assert!(image.code_base == 0 && image.data_base == 0);
+    Verifier::new(image, restrictions).verify()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn verify_str(src: &str) -> Result<(), String>
+    {
+        verify_image(&Assembler::new().assemble_str(src).unwrap())
+    }
+
+    fn verify_err(src: &str, msg: &str)
+    {
+        match verify_str(src) {
+            Ok(()) => panic!("verification should fail: {}", src),
+            Err(err) => assert!(err.contains(msg), "{}", err),
+        }
+    }
+
+    #[test]
+    fn test_verify_ok()
+    {
+        verify_str("push 0; exit;").unwrap();
+        verify_str("push 1; push 2; call F, 2; exit; F: get_arg 1; ret;").unwrap();
+        verify_str("push 3; L: push 1; sub_u64; dup; jnz L; exit;").unwrap();
+        verify_str("F: enter 2; push 1; set_local 1; get_local 0; ret;").unwrap();
+
+        // Functions only referred to by their address are checked too
+        verify_str("push G; call_fp 0; exit; G: push 5; ret;").unwrap();
+        verify_err("push G; call_fp 0; exit; G: ret;", "at pc 8 (G): stack underflow in ret");
+
+        // Every target of a jump table is checked
+        verify_str("push 1; push 0; jmp_table [A, B], B; A: exit; B: pop; push 0; exit;").unwrap();
+        verify_err("push 0; jmp_table [A, B], A; A: push 0; exit; B: exit;", "stack underflow in exit");
+
+        // Loads and stores at the address of a data label
+        verify_str(".data; X: .u64 0; .code; push X; push 7; store_u64; push X; load_u64; exit;").unwrap();
+    }
+
+    #[test]
+    fn test_verify_errors()
+    {
+        verify_err("pop; push 0; exit;", "stack underflow in pop");
+        verify_err("push 0; push 1; jz L; push 2; L: add_u64; exit;", "stack underflow in add_u64");
+        verify_err("push 0;", "past the end of the code");
+        verify_err("push 0; push 1; call F, 1; exit; F: get_arg 1; ret;", "passes 1 arguments, but the function reads 2");
+        verify_err("call F, 0; exit; F: push 0; enter 1; ret;", "enter must be the first instruction");
+        verify_err("push 0; getn 1; exit;", "getn 1");
+        verify_err("push 0; get_local 1; exit;", "invalid local index 1");
+        verify_err("F: enter 1; get_local 1; ret;", "invalid local index 1");
+        verify_err(".data; X: .u32 0; .code; push X; load_u64; exit;", "load_u64 at 0 past the end of the data section");
+        verify_err(".data; X: .u8 0; .code; push 1; push X; swap; store_u16; push 0; exit;", "store_u16");
+
+        verify_str("push 0; F: .assert_stack 1; exit;").unwrap();
+        verify_err("push 0; F: .assert_stack 2; exit;", "at pc 1 (F): stack depth is 1, asserted 2");
+
+        // Invalid opcodes, and errors are located relative to labels
+        verify_err("push 0; L: .u8 250; exit;", "at pc 1 (L): invalid opcode 250");
+
+        // Calls to the functions imported by a module aren't followed
+        verify_str(".import f; push 1; call f, 1; ret;").unwrap();
+        verify_err(".import f; push 1; call f, 1; pop; exit;", "stack underflow in exit");
+        verify_err(".import f; jmp f;", "jmp to an imported label");
+    }
+
+    #[test]
+    fn test_restrictions()
+    {
+        let restricted = |src: &str, names: &[&str]| {
+            let mut restrictions = OpRestrictions::default();
+            for name in names {
+                restrictions.deny_name(name).unwrap();
+            }
+            verify_restricted(&Assembler::new().assemble_str(src).unwrap(), &restrictions)
+        };
+
+        let src = "push 1; push 2; add_u64; exit;";
+        let checked = restricted(src, &["syscall", "memory", "float", "indirect_call"]).unwrap();
+        assert_eq!(checked, vec![true, true, true, true]);
+
+        let err = restricted("push 0; syscall time_current_ms; exit;", &["syscall"]).unwrap_err();
+        assert!(err.contains("at pc 1: syscall time_current_ms is not allowed, syscall instructions are denied"), "{}", err);
+
+        // Only the syscalls resizing the heap are denied with heap_grow
+        restricted("push 0; syscall time_current_ms; exit;", &["heap_grow"]).unwrap();
+        let err = restricted("push 1; syscall vm_grow_heap; exit;", &["heap_grow"]).unwrap_err();
+        assert!(err.contains("heap_grow instructions are denied"), "{}", err);
+        let err = restricted("push 0; syscall vm_resize_heap; exit;", &["heap_grow"]).unwrap_err();
+        assert!(err.contains("heap_grow instructions are denied"), "{}", err);
+
+        let err = restricted("push 0; load_u8; exit;", &["memory"]).unwrap_err();
+        assert!(err.contains("load_u8 is not allowed"), "{}", err);
+        let err = restricted("push 0; i64_to_f64; exit;", &["float"]).unwrap_err();
+        assert!(err.contains("i64_to_f64 is not allowed"), "{}", err);
+        let err = restricted("push F; call_fp 0; exit; F: push 0; ret;", &["indirect_call"]).unwrap_err();
+        assert!(err.contains("call_fp is not allowed"), "{}", err);
+
+        // Unreachable code isn't checked, and isn't marked as checked
+        let checked = restricted("push 0; exit; push 0; syscall time_current_ms; exit;", &["syscall"]).unwrap();
+        assert_eq!(checked, vec![true, true, false, false, false, false, false]);
+
+        assert!(OpRestrictions::default().deny_name("jump").is_err());
+    }
+}
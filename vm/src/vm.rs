@@ -1,7 +1,23 @@
+use std::fmt::{self, Write};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::mem::{transmute, size_of};
 use std::collections::HashSet;
-use std::ffi::CStr;
+use std::time::Instant;
 use crate::sys::*;
+use crate::asan::Asan;
+use crate::protect::PageProt;
+use crate::profile::Profiler;
+use crate::coverage::Coverage;
+use crate::image::LineTable;
+use crate::verify::OpRestrictions;
+use crate::block_cache::{BlockCache, jmp_table_target};
+use crate::jit::{Jit, JitCtx, JIT_THRESHOLD};
+use crate::snapshot::{Snapshot, FrameData};
+use crate::minidump::{Minidump, DumpFrame, CODE_CONTEXT, HEAP_CONTEXT};
+use crate::threads::{Threads, ThreadContext, WaitFor};
+use crate::sys::mem::mem_free;
+use crate::sys::constants::*;
+use crate::sys::crypto::crc32;
 
 /// Instruction opcodes
 /// Note: commonly used upcodes should be in the [0, 127] range (one byte)
@@ -20,8 +36,19 @@ pub enum Op
 
     // Debugger breakpoint.
     // This instruction must be just one byte so it can be patched anywhere.
+    // Execution stops here when no debugger is attached.
     breakpoint,
 
+    // Halt execution and report the source location of the trap
+    // trap (location_str)
+    trap,
+
+    // Check the number of values on the stack of the current function,
+    // including its locals, when debug assertions are enabled, which
+    // includes the release profile for now. Written with .assert_stack
+    // assert_stack <depth:u16>
+    assert_stack,
+
     // Push common constants (0, 1, 2)
     push_0,
     push_1,
@@ -80,6 +107,13 @@ pub enum Op
     // set_local <idx:u8> (value)
     set_local,
 
+    // Allocate zero-initialized local variables and record their count in
+    // the stack frame, so that local accesses and ret can be validated.
+    // This must be the first instruction of a function. Functions that
+    // allocate their locals with push instructions instead are unchecked.
+    // enter <num_locals:u8>
+    enter,
+
     // 32-bit bitwise operations
     and_u32,
     or_u32,
@@ -177,6 +211,12 @@ pub enum Op
     pow_f32,
     sqrt_f32,
 
+    // Round to an integral value, towards -inf, towards +inf,
+    // or to the nearest integer with ties to even
+    floor_f32,
+    ceil_f32,
+    round_f32,
+
     // 32-bit floating-point comparison instructions
     eq_f32,
     ne_f32,
@@ -185,11 +225,41 @@ pub enum Op
     gt_f32,
     ge_f32,
 
+    // Floating-point classification instructions
+    is_nan_f32,
+    is_inf_f32,
+
     // Int/float conversion
     i32_to_f32,
     i64_to_f32,
     f32_to_i32,
 
+    // 64-bit floating-point arithmetic
+    add_f64,
+    sub_f64,
+    mul_f64,
+    div_f64,
+    sqrt_f64,
+    floor_f64,
+    ceil_f64,
+    round_f64,
+
+    // 64-bit floating-point comparison instructions
+    eq_f64,
+    ne_f64,
+    lt_f64,
+    le_f64,
+    gt_f64,
+    ge_f64,
+    is_nan_f64,
+    is_inf_f64,
+
+    // Conversion to and from 64-bit floats
+    i64_to_f64,
+    f64_to_i64,
+    f32_to_f64,
+    f64_to_f32,
+
     // Load a value at a given adress
     // store (addr)
     load_u8,
@@ -204,6 +274,17 @@ pub enum Op
     store_u32,
     store_u64,
 
+    // Load and store values at addresses that don't need to be
+    // aligned, for packed data structures and file formats
+    // load_unaligned (addr)
+    // store_unaligned (addr) (value)
+    load_unaligned_u16,
+    load_unaligned_u32,
+    load_unaligned_u64,
+    store_unaligned_u16,
+    store_unaligned_u32,
+    store_unaligned_u64,
+
     /*
     // TODO:
     // Load from heap at fixed address
@@ -239,6 +320,11 @@ pub enum Op
     // jnz <offset:i32>
     jnz,
 
+    // Jump to the pc offset at an index in a table, or to a default
+    // offset if the index is past the end of the table
+    // jmp_table <num_entries:u32> <default:i32> <offset:i32>... (idx)
+    jmp_table,
+
     // Call a function using the call stack
     // call <offset:i32> <num_args:u8> (arg0, arg1, ..., argN)
     call,
@@ -270,6 +356,7 @@ pub enum Op
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(transparent)]
 pub struct Value(u64);
 
 impl Value
@@ -329,6 +416,11 @@ impl Value
         let val = val as i32;
         unsafe { transmute(val) }
     }
+
+    pub fn as_f64(&self) -> f64 {
+        let Value(val) = *self;
+        f64::from_bits(val)
+    }
 }
 
 impl From<bool> for Value {
@@ -392,6 +484,12 @@ impl From<f32> for Value {
     }
 }
 
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value(val.to_bits())
+    }
+}
+
 pub struct MemBlock
 {
     data: Vec<u8>
@@ -406,29 +504,57 @@ impl MemBlock
         }
     }
 
+    /// Create a memory block holding a copy of some bytes
+    pub fn from_bytes(bytes: &[u8]) -> Self
+    {
+        Self {
+            data: bytes.to_vec()
+        }
+    }
+
+    /// Reserve capacity for at least num_bytes more bytes
+    pub fn reserve(&mut self, num_bytes: usize)
+    {
+        self.data.reserve(num_bytes);
+    }
+
     /// Get the memory block size in bytes
     pub fn len(&self) -> usize
     {
         self.data.len()
     }
 
-    /// Resize to a new size in bytes
-    pub fn resize(&mut self, mut num_bytes: usize) -> usize
+    /// Round a size in bytes up to a multiple of the host page size
+    pub fn round_size(num_bytes: usize) -> Option<usize>
     {
-        // Round up to a page size multiple
         let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
         assert!(page_size % 8 == 0);
-        let rem = num_bytes % page_size;
-        if rem != 0 {
-            num_bytes += page_size - rem;
-        }
+        num_bytes.checked_next_multiple_of(page_size)
+    }
 
-        assert!(num_bytes % page_size == 0);
+    /// Resize to a new size in bytes
+    pub fn resize(&mut self, num_bytes: usize) -> usize
+    {
+        let num_bytes = Self::round_size(num_bytes).unwrap();
         self.data.resize(num_bytes, 0);
-
         num_bytes
     }
 
+    /// Resize to a new size in bytes, if the size once rounded up is at
+    /// most max_size bytes. Returns None if the size is over the limit,
+    /// or if the host is out of memory.
+    pub fn try_resize(&mut self, num_bytes: usize, max_size: usize) -> Option<usize>
+    {
+        let num_bytes = Self::round_size(num_bytes).filter(|&size| size <= max_size)?;
+
+        if num_bytes > self.data.len() {
+            self.data.try_reserve_exact(num_bytes - self.data.len()).ok()?;
+        }
+
+        self.data.resize(num_bytes, 0);
+        Some(num_bytes)
+    }
+
     pub fn push_op(&mut self, op: Op)
     {
         self.data.push(op as u8);
@@ -441,9 +567,7 @@ impl MemBlock
 
     pub fn push_u16(&mut self, val: u16)
     {
-        for byte in val.to_le_bytes() {
-            self.data.push(byte);
-        }
+        self.data.extend_from_slice(&val.to_le_bytes());
     }
 
     pub fn push_i8(&mut self, val: i8)
@@ -453,22 +577,49 @@ impl MemBlock
 
     pub fn push_i32(&mut self, val: i32)
     {
-        for byte in val.to_le_bytes() {
-            self.data.push(byte);
-        }
+        self.data.extend_from_slice(&val.to_le_bytes());
     }
 
     pub fn push_u32(&mut self, val: u32)
     {
-        for byte in val.to_le_bytes() {
-            self.data.push(byte);
-        }
+        self.data.extend_from_slice(&val.to_le_bytes());
     }
 
     pub fn push_u64(&mut self, val: u64)
     {
-        for byte in val.to_le_bytes() {
-            self.data.push(byte);
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8])
+    {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Append zero bytes
+    pub fn push_zeros(&mut self, num_bytes: usize)
+    {
+        self.data.resize(self.data.len() + num_bytes, 0);
+    }
+
+    /// Append the contents of another memory block
+    pub fn append(&mut self, other: &MemBlock)
+    {
+        self.data.extend_from_slice(&other.data);
+    }
+
+    pub fn as_slice(&self) -> &[u8]
+    {
+        &self.data
+    }
+
+    /// Read a value at the given address
+    pub fn read<T>(&self, pos: usize) -> T where T: Copy
+    {
+        assert!(pos + size_of::<T>() <= self.data.len());
+
+        unsafe {
+            let val_ptr = self.data.as_ptr().add(pos) as *const T;
+            std::ptr::read_unaligned(val_ptr)
         }
     }
 
@@ -494,7 +645,121 @@ impl MemBlock
     }
 }
 
-struct StackFrame
+/// Plain data types that syscalls may read from and write to the heap
+///
+/// # Safety
+///
+/// Values are read from and written to guest memory as raw bytes, so
+/// implementors must be `Copy` types without padding bytes, for which
+/// any bit pattern is a valid value.
+pub unsafe trait MemType: Copy {}
+unsafe impl MemType for u8 {}
+unsafe impl MemType for u16 {}
+unsafe impl MemType for u32 {}
+unsafe impl MemType for u64 {}
+unsafe impl MemType for i8 {}
+unsafe impl MemType for i16 {}
+unsafe impl MemType for i32 {}
+unsafe impl MemType for i64 {}
+unsafe impl MemType for f32 {}
+unsafe impl MemType for f64 {}
+
+/// Bounds-checked view of the heap used by syscall implementations
+/// Every access validates the address and length against the heap size
+/// and the alignment of the element type, and panics if they are invalid,
+/// so that a guest program can't make the host access memory out of range
+pub struct MemView<'a>
+{
+    data: &'a mut [u8],
+}
+
+impl<'a> MemView<'a>
+{
+    /// Validate an access and get its byte range
+    fn check<T>(&self, addr: usize, num_elems: usize) -> std::ops::Range<usize>
+    {
+        let end = size_of::<T>().checked_mul(num_elems).and_then(|n| addr.checked_add(n));
+
+        match end {
+            Some(end) if end <= self.data.len() => {}
+            _ => panic!("attempting to access memory slice past end of heap")
+        }
+
+        if addr & (size_of::<T>() - 1) != 0 {
+            panic!(
+                "attempting to access unaligned memory slice of type {}",
+                std::any::type_name::<T>()
+            );
+        }
+
+        addr..end.unwrap()
+    }
+
+    /// Get a slice to read a memory region in the heap
+    pub fn slice<T: MemType>(&self, addr: usize, num_elems: usize) -> &[T]
+    {
+        let range = self.check::<T>(addr, num_elems);
+
+        // The range is in bounds and aligned, and MemType values are plain data
+        unsafe {
+            std::slice::from_raw_parts(self.data[range].as_ptr() as *const T, num_elems)
+        }
+    }
+
+    /// Get a mutable slice to access a memory region in the heap
+    pub fn slice_mut<T: MemType>(&mut self, addr: usize, num_elems: usize) -> &mut [T]
+    {
+        let range = self.check::<T>(addr, num_elems);
+
+        unsafe {
+            std::slice::from_raw_parts_mut(self.data[range].as_mut_ptr() as *mut T, num_elems)
+        }
+    }
+
+    /// Read a single value
+    pub fn read<T: MemType>(&self, addr: usize) -> T
+    {
+        self.slice::<T>(addr, 1)[0]
+    }
+
+    /// Write a single value
+    pub fn write<T: MemType>(&mut self, addr: usize, val: T)
+    {
+        self.slice_mut::<T>(addr, 1)[0] = val;
+    }
+
+    /// Copy bytes from the host into the heap
+    pub fn write_bytes(&mut self, addr: usize, bytes: &[u8])
+    {
+        self.slice_mut::<u8>(addr, bytes.len()).copy_from_slice(bytes);
+    }
+
+    /// Copy bytes between two regions of the heap, which may overlap
+    pub fn copy(&mut self, dst: usize, src: usize, num_bytes: usize)
+    {
+        self.check::<u8>(dst, num_bytes);
+        let range = self.check::<u8>(src, num_bytes);
+        self.data.copy_within(range, dst);
+    }
+
+    /// Get the UTF-8 string at a given address in the heap
+    pub fn str(&self, str_ptr: usize) -> &str
+    {
+        // Verify that there is a null-terminator for this string
+        // within the bounds of the heap
+        let str_len = match self.data.get(str_ptr..).and_then(|s| s.iter().position(|&b| b == 0)) {
+            Some(len) => len,
+            None => panic!("string is not properly null-terminated")
+        };
+
+        match std::str::from_utf8(&self.data[str_ptr..str_ptr + str_len]) {
+            Ok(s) => s,
+            Err(_) => panic!("string is not valid UTF-8")
+        }
+    }
+}
+
+pub struct StackFrame
 {
     // Previous base pointer at the time of call
     prev_bp: usize,
@@ -502,15 +767,140 @@ struct StackFrame
     // Return address
     ret_addr: usize,
 
+    // Address of the function called
+    fn_pc: usize,
+
     // Argument count
     argc: usize,
+
+    // Local variable count, if declared with the enter instruction
+    num_locals: Option<usize>,
+}
+
+/// Entry in a backtrace of the call stack
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameInfo
+{
+    /// Address of the function the frame belongs to
+    pub fn_pc: usize,
+
+    /// Address execution continues at when the function returns,
+    /// or None for the function that was called by the VM
+    pub ret_addr: Option<usize>,
+}
+
+/// Kind of error in guest code which stops its execution
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrapKind
+{
+    /// Memory access past the end of the heap
+    OutOfBounds { addr: usize, size: usize },
+
+    /// Memory access at an address which isn't a multiple of its size
+    Unaligned { addr: usize, size: usize },
+
+    /// Integer division or remainder by zero
+    DivByZero,
+
+    /// Syscall which the VM doesn't implement
+    InvalidSyscall { idx: u16 },
+
+    /// The call stack is more than MAX_CALL_DEPTH frames deep
+    StackOverflow,
+
+    /// Memory access to a page protected with vm_mem_protect
+    Protection { addr: usize, size: usize, is_write: bool },
+
+    /// Execution reached code which the verifier didn't check,
+    /// in a VM with instruction restrictions
+    UnverifiedCode,
+}
+
+impl TrapKind
+{
+    /// TRAP_* code and faulting address or syscall
+    /// number, which are passed to the trap handler
+    fn handler_args(&self) -> (u32, u64)
+    {
+        match *self {
+            TrapKind::DivByZero => (TRAP_DIV_ZERO, 0),
+            TrapKind::OutOfBounds { addr, .. } => (TRAP_OUT_OF_BOUNDS, addr as u64),
+            TrapKind::Unaligned { addr, .. } => (TRAP_UNALIGNED, addr as u64),
+            TrapKind::InvalidSyscall { idx } => (TRAP_INVALID_SYSCALL, idx as u64),
+            TrapKind::StackOverflow => (TRAP_STACK_OVERFLOW, 0),
+            TrapKind::Protection { addr, .. } => (TRAP_PROTECTION, addr as u64),
+            TrapKind::UnverifiedCode => (TRAP_UNVERIFIED_CODE, 0),
+        }
+    }
+}
+
+/// Error in guest code, reported with ExitReason::Trap
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Trap
+{
+    pub kind: TrapKind,
+
+    /// Address of the instruction which caused the error
+    pub pc: usize,
+}
+
+impl fmt::Display for Trap
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.kind {
+            TrapKind::OutOfBounds { addr, size } => {
+                write!(f, "out of bounds access of {} bytes at address 0x{:x}", size, addr)?
+            }
+            TrapKind::Unaligned { addr, size } => {
+                write!(f, "unaligned access of {} bytes at address 0x{:x}", size, addr)?
+            }
+            TrapKind::DivByZero => write!(f, "division by zero")?,
+            TrapKind::InvalidSyscall { idx } => write!(f, "unknown syscall {}", idx)?,
+            TrapKind::StackOverflow => write!(f, "call stack overflow")?,
+            TrapKind::Protection { addr, size, is_write } => {
+                let access = if is_write { "write" } else { "read" };
+                write!(f, "{} of {} bytes at protected address 0x{:x}", access, size, addr)?
+            }
+            TrapKind::UnverifiedCode => write!(f, "execution of unverified code")?,
+        }
+
+        write!(f, ", pc {}", self.pc)
+    }
 }
 
+/// Exit code used when call_sync() stops because of a trap
+pub const TRAP_EXIT_CODE: i64 = 134;
+
+/// Maximum number of nested calls before a stack overflow trap
+pub const MAX_CALL_DEPTH: usize = 1 << 20;
+
+/// Return address of the frame of the trap handler
+const TRAP_HANDLER_RET: usize = usize::MAX - 1;
+
+#[derive(Debug, PartialEq)]
 pub enum ExitReason
 {
     Return(Value),
     Exit(Value),
-    //Panic,
+
+    /// The instruction budget ran out, execution can be continued with resume()
+    Suspended,
+
+    /// The program called vm_yield, execution can be continued with resume()
+    Yield,
+
+    /// A syscall was deferred by the host, execution can be continued
+    /// with resume_with() once its result is available
+    SyscallPending(u16),
+
+    /// Stopped at the breakpoint instruction at this address,
+    /// execution can be continued with resume()
+    Breakpoint(usize),
+
+    /// Execution was stopped by an error in guest code which had no
+    /// trap handler. The stack and call frames are discarded.
+    Trap(Trap),
 }
 
 impl Default for ExitReason
@@ -520,6 +910,11 @@ impl Default for ExitReason
     }
 }
 
+/// Quiet NaN with a positive sign and an empty payload, which float ops
+/// produce instead of other NaNs when deterministic floats are enabled
+pub const CANONICAL_NAN_F32: f32 = f32::from_bits(0x7fc0_0000);
+pub const CANONICAL_NAN_F64: f64 = f64::from_bits(0x7ff8_0000_0000_0000);
+
 pub struct VM
 {
     // Host system state
@@ -537,6 +932,86 @@ pub struct VM
     // List of stack frames (activation records)
     frames: Vec<StackFrame>,
 
+    // Allocation tracking and shadow memory for --asan and --detect-leaks
+    asan: Option<Box<Asan>>,
+
+    // Page protections set with vm_mem_protect
+    page_prot: Option<Box<PageProt>>,
+
+    // Size of the initialized data at the start of the heap
+    data_size: usize,
+
+    // Size the guest can grow the heap to
+    max_heap_size: usize,
+
+    // Set when heap_grow instructions are denied, the heap then
+    // keeps the size it had when the program was loaded
+    heap_locked: bool,
+
+    // Call and loop counts for --profile
+    profiler: Option<Box<Profiler>>,
+
+    // Instruction execution counts for --coverage
+    coverage: Option<Box<Coverage>>,
+
+    // Names and addresses of the labels in the code space
+    code_labels: Vec<(String, usize)>,
+
+    // Source lines of the code space
+    line_table: LineTable,
+
+    // Address of the instruction being run by the interpreter,
+    // for the backtrace printed when the VM panics
+    cur_pc: usize,
+
+    // Backtrace of the guest at the last trap which stopped execution
+    trap_backtrace: String,
+
+    // Record a minidump when a trap stops execution, and the last one recorded
+    minidumps_enabled: bool,
+    minidump: Option<Box<Minidump>>,
+
+    // With instruction restrictions, set for the positions of the
+    // instructions the verifier checked, the only ones which may run
+    verified_code: Option<Vec<bool>>,
+
+    // Classes of instructions the program and its modules may not use
+    restrictions: OpRestrictions,
+
+    // Decoded basic blocks of the code space
+    block_cache: BlockCache,
+
+    // Compiler for hot blocks, with --jit
+    jit: Option<Box<Jit>>,
+
+    // Replace NaN results of float ops by the canonical NaN
+    canonical_nans: bool,
+
+    // Stop with a trap on invalid memory accesses instead of panicking
+    checked_memory: bool,
+
+    // Function registered by the guest to call on traps
+    trap_handler: Option<usize>,
+
+    // Trap being handled by the trap handler, if it is running
+    active_trap: Option<Trap>,
+
+    // Number of instructions left to run before suspending, if metered
+    insn_budget: Option<u64>,
+
+    // Where to continue execution (pc, bp) after being suspended
+    suspended_at: Option<(usize, usize)>,
+
+    // Set by syscalls to stop execution once they return
+    yield_requested: bool,
+    syscall_deferred: bool,
+
+    // Guest threads started with thread_spawn
+    threads: Threads,
+
+    // What the running thread waits for once the syscall returns
+    wait_requested: Option<WaitFor>,
+
     // Count of executed instructions
     #[cfg(feature = "count_insns")]
     insn_count: u64,
@@ -550,6 +1025,7 @@ impl VM
         let sys_state = SysState::new();
 
         // Resize the code and heap space to a page size multiple
+        let data_size = heap.len();
         code.resize(code.len());
         heap.resize(heap.len());
 
@@ -559,6 +1035,33 @@ impl VM
             heap,
             stack: Vec::default(),
             frames: Vec::default(),
+            asan: None,
+            page_prot: None,
+            data_size,
+            max_heap_size: usize::MAX,
+            heap_locked: false,
+            profiler: None,
+            coverage: None,
+            code_labels: Vec::default(),
+            line_table: LineTable::default(),
+            cur_pc: 0,
+            trap_backtrace: String::default(),
+            minidumps_enabled: false,
+            minidump: None,
+            verified_code: None,
+            restrictions: OpRestrictions::default(),
+            block_cache: BlockCache::default(),
+            jit: None,
+            canonical_nans: false,
+            checked_memory: false,
+            trap_handler: None,
+            active_trap: None,
+            insn_budget: None,
+            suspended_at: None,
+            yield_requested: false,
+            syscall_deferred: false,
+            threads: Threads::default(),
+            wait_requested: None,
             #[cfg(feature = "count_insns")]
             insn_count: 0,
         }
@@ -588,991 +1091,2570 @@ impl VM
         }
     }
 
-    /// Get the current size of the heap in bytes
-    pub fn heap_size(&self) -> usize
+    /// Get the current size of the code space in bytes
+    pub fn code_size(&self) -> usize
     {
-        self.heap.len()
+        self.code.len()
     }
 
-    /// Resize the heap to a new size in bytes
-    pub fn resize_heap(&mut self, num_bytes: usize) -> usize
+    /// Append code at the end of the code space
+    pub fn append_code(&mut self, code: &MemBlock)
     {
-        self.heap.resize(num_bytes)
+        self.code.append(code);
+        self.invalidate_code_cache();
     }
 
-    // FIXME: this function should be marked unsafe
-    //
-    /// Get a pointer to an address/offset in the heap
-    pub fn get_heap_ptr<T>(&mut self, addr: usize, num_elems: usize) -> *mut T
+    /// Discard the decoded instructions of the code space, which
+    /// must be done after the code is modified, e.g. by a hot reload
+    pub fn invalidate_code_cache(&mut self)
     {
-        if addr + std::mem::size_of::<T>() * num_elems > self.heap.len() {
-            panic!("attempting to access memory slice past end of heap");
-        }
+        self.block_cache.invalidate();
 
-        if addr & (size_of::<T>() - 1) != 0 {
-            panic!(
-                "attempting to access data of type {} at unaligned address",
-                std::any::type_name::<T>()
-            );
+        if let Some(jit) = &mut self.jit {
+            jit.reset();
         }
+    }
 
-        unsafe {
-            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
-            transmute::<*mut u8 , *mut T>(heap_ptr)
-        }
+    /// Compile frequently executed code to machine code
+    pub fn enable_jit(&mut self)
+    {
+        self.jit = Some(Box::new(Jit::new()));
     }
 
-    /// Get a mutable slice to access a memory region in the heap
-    pub fn get_heap_slice<T>(&mut self, addr: usize, num_elems: usize) -> &mut [T]
+    /// Check loads and stores against the heap size and alignment, and
+    /// stop with ExitReason::Trap when they are invalid instead of
+    /// panicking. This makes memory accesses slightly slower.
+    pub fn set_checked_memory(&mut self, enabled: bool)
     {
-        if addr + std::mem::size_of::<T>() * num_elems > self.heap.len() {
-            panic!("attempting to access memory slice past end of heap");
-        }
+        self.checked_memory = enabled;
+    }
 
-        if addr & (size_of::<T>() - 1) != 0 {
-            panic!(
-                "attempting to access unaligned memory slice of type {}",
-                std::any::type_name::<T>()
-            );
-        }
+    /// Set the function to call when a trap occurs, which also enables
+    /// checked memory, or remove it with None
+    pub fn set_trap_handler(&mut self, handler_pc: Option<usize>)
+    {
+        self.trap_handler = handler_pc;
 
-        unsafe {
-            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
-            let start_ptr = transmute::<*mut u8 , *mut T>(heap_ptr);
-            std::slice::from_raw_parts_mut(start_ptr, num_elems)
+        if handler_pc.is_some() {
+            self.checked_memory = true;
         }
     }
 
-    /// Copy an UTF-8 string at a given address in the heap
-    pub fn get_heap_str(&mut self, str_ptr: usize) -> &str
+    /// Limit the number of instructions to run before execution is
+    /// suspended, or remove the limit with None. Each instruction run
+    /// decrements the budget. Compiled code can't be metered, so
+    /// the JIT is not used while there is a budget.
+    pub fn set_insn_budget(&mut self, budget: Option<u64>)
     {
-        // Verify that there is a null-terminator for this string
-        // within the bounds of the heap
-        let mut str_len = 0;
-        loop
-        {
-            let char_idx = str_ptr + str_len;
-            if char_idx >= self.heap.len() {
-                panic!("string is not properly null-terminated");
-            }
+        self.insn_budget = budget;
+    }
 
-            if self.heap.data[char_idx] == 0 {
-                break;
-            }
+    /// Number of instructions left in the budget
+    pub fn insn_budget(&self) -> Option<u64>
+    {
+        self.insn_budget
+    }
 
-            str_len += 1;
-        }
+    /// Check if execution was suspended and can be resumed
+    pub fn is_suspended(&self) -> bool
+    {
+        self.suspended_at.is_some()
+    }
 
-        // Convert the string to a Rust string
-        let char_ptr = self.get_heap_ptr(str_ptr, str_len);
-        let c_str = unsafe { CStr::from_ptr(char_ptr as *const i8) };
-        let rust_str = c_str.to_str().unwrap();
-        rust_str
+    /// Get the number of blocks compiled by the JIT
+    pub fn jit_blocks_compiled(&self) -> usize
+    {
+        self.jit.as_ref().map(|jit| jit.num_compiled()).unwrap_or(0)
     }
 
-    /// Call a function at a given address
-    pub fn call(&mut self, callee_pc: u64, args: &[Value]) -> ExitReason
+    /// Run the compiled code for a block, compiling the block if it became
+    /// hot. Returns the pc to continue at, or None if the block must be
+    /// run by the interpreter.
+    fn run_native(&mut self, block_idx: usize, pc: usize, bp: usize) -> Option<usize>
     {
-        assert!(self.stack.len() == 0);
-        assert!(self.frames.len() == 0);
+        let (block, ops) = self.block_cache.block_mut(block_idx);
+
+        let native = match block.native {
+            Some(native) => native,
+            None => {
+                // Blocks which fail to compile are not retried
+                block.exec_count = block.exec_count.saturating_add(1);
+                if block.exec_count != JIT_THRESHOLD {
+                    return None;
+                }
 
-        // Push a new stack frame
-        self.frames.push(StackFrame {
-            prev_bp: usize::MAX,
-            ret_addr: usize::MAX,
-            argc: args.len(),
-        });
+                block.native = self.jit.as_mut().unwrap().compile(ops, pc);
+                block.native?
+            }
+        };
 
-        // Push the arguments on the stack
-        for arg in args {
-            self.stack.push(*arg);
+        // The interpreter reports stack underflows
+        if self.stack.len() < native.min_depth {
+            return None;
         }
+        self.stack.reserve(native.max_growth);
+
+        let frame = &self.frames[self.frames.len() - 1];
+        let mut ctx = JitCtx {
+            stack: self.stack.as_mut_ptr() as *mut u64,
+            stack_len: self.stack.len(),
+            bp,
+            num_locals: frame.num_locals.unwrap_or(usize::MAX),
+            argc: frame.argc,
+            heap: self.heap.data.as_mut_ptr(),
+            heap_len: self.heap.len(),
+        };
 
-        // The base pointer will point at the first local
-        let mut bp = self.stack.len();
-        let mut pc = callee_pc as usize;
+        unsafe {
+            let next_pc = (native.entry)(&mut ctx);
+            self.stack.set_len(ctx.stack_len);
+            Some(next_pc as usize)
+        }
+    }
 
-        // For each instruction to execute
-        loop
-        {
-            #[cfg(feature = "count_insns")]
-            {
-                self.insn_count += 1;
-            }
+    /// Register the names of code labels (name, address)
+    pub fn add_code_labels(&mut self, labels: Vec<(String, usize)>)
+    {
+        self.code_labels.extend(labels);
+    }
 
-            if pc >= self.code.len() {
-                panic!("pc outside bounds of code space")
-            }
+    /// Register the source lines of code loaded at a base address
+    pub fn add_line_table(&mut self, line_table: &LineTable, code_base: usize)
+    {
+        self.line_table.append(line_table, code_base);
+    }
 
-            let op = self.code.read_pc::<Op>(&mut pc);
-            //dbg!(op);
+    /// Produce bit-identical float results on every host platform by
+    /// replacing NaN results, whose sign and payload bits vary between
+    /// architectures, with the canonical quiet NaN
+    pub fn enable_deterministic_floats(&mut self)
+    {
+        self.canonical_nans = true;
+    }
 
-            match op
-            {
-                Op::panic => panic!("execution error, encountered panic opcode"),
+    /// Push the result of a float operation
+    #[inline(always)]
+    fn push_f32(&mut self, val: f32)
+    {
+        if self.canonical_nans && val.is_nan() {
+            self.push(CANONICAL_NAN_F32);
+        } else {
+            self.push(val);
+        }
+    }
 
-                Op::nop => continue,
+    /// Push the result of a 64-bit float operation
+    #[inline(always)]
+    fn push_f64(&mut self, val: f64)
+    {
+        if self.canonical_nans && val.is_nan() {
+            self.push(CANONICAL_NAN_F64);
+        } else {
+            self.push(val);
+        }
+    }
+
+    /// Start counting function calls and loop iterations
+    pub fn enable_profiler(&mut self)
+    {
+        self.profiler = Some(Box::default());
+    }
+
+    /// Get the profile report, if the profiler is enabled
+    pub fn profile_report(&self) -> Option<String>
+    {
+        self.profiler.as_ref().map(|profiler| profiler.report(&self.code_labels))
+    }
+
+    /// Start counting the executions of each instruction
+    pub fn enable_coverage(&mut self)
+    {
+        self.coverage = Some(Box::default());
+    }
+
+    /// Get the lcov coverage report, if coverage is enabled
+    pub fn coverage_report(&self) -> Option<String>
+    {
+        self.coverage.as_ref().map(|coverage| coverage.report(&self.line_table))
+    }
+
+    /// Get the current size of the heap in bytes
+    pub fn heap_size(&self) -> usize
+    {
+        self.heap.len()
+    }
+
+    /// Resize the heap to a new size in bytes
+    pub fn resize_heap(&mut self, num_bytes: usize) -> usize
+    {
+        if self.heap_locked && num_bytes > self.heap.len() {
+            panic!("the heap can't grow, heap_grow instructions are denied");
+        }
+
+        let new_size = self.heap.resize(num_bytes);
+        self.on_heap_resized(new_size);
+        new_size
+    }
+
+    /// Resize the heap on behalf of the guest, which can't grow it past
+    /// the maximum heap size. Returns None if the heap can't be resized.
+    pub fn try_resize_heap(&mut self, num_bytes: usize) -> Option<usize>
+    {
+        if self.heap_locked && num_bytes > self.heap.len() {
+            return None;
+        }
+
+        let new_size = self.heap.try_resize(num_bytes, self.max_heap_size)?;
+        self.on_heap_resized(new_size);
+        Some(new_size)
+    }
+
+    fn on_heap_resized(&mut self, new_size: usize)
+    {
+        if let Some(asan) = &mut self.asan {
+            asan.resize(new_size);
+        }
+
+        if let Some(page_prot) = &mut self.page_prot {
+            page_prot.resize(new_size);
+        }
+    }
+
+    /// Limit the size the guest can grow the heap to, which is
+    /// rounded up to a multiple of the host page size. The heap
+    /// can still grow past it to load modules.
+    pub fn set_max_heap_size(&mut self, num_bytes: usize)
+    {
+        self.max_heap_size = MemBlock::round_size(num_bytes).unwrap_or(usize::MAX);
+    }
+
+    /// Size the guest can grow the heap to, which is
+    /// its current size once the heap is locked
+    pub fn max_heap_size(&self) -> usize
+    {
+        match self.heap_locked {
+            true => self.heap.len(),
+            false => self.max_heap_size,
+        }
+    }
+
+    /// Prevent the heap from growing, for programs which are denied
+    /// heap_grow instructions. This covers the syscalls resizing the
+    /// heap as well as the allocations, modules and TLS growing it.
+    pub fn lock_heap(&mut self)
+    {
+        self.heap_locked = true;
+    }
+
+    pub fn is_heap_locked(&self) -> bool
+    {
+        self.heap_locked
+    }
+
+    /// Start address and size of the code, data, heap and stack
+    /// regions, indexed by the MEM_REGION_* constants
+    pub fn mem_layout(&self) -> [(usize, usize); 4]
+    {
+        [
+            (0, self.code.len()),
+            (0, self.data_size),
+            (self.data_size, self.heap.len() - self.data_size),
+            (0, self.stack.len() * 8),
+        ]
+    }
+
+    /// Set the MEM_PROT_* protection of a range of heap pages, which also
+    /// enables checked memory. Returns false if the range is invalid.
+    pub fn protect_memory(&mut self, addr: usize, num_bytes: usize, prot: u8) -> bool
+    {
+        let heap_size = self.heap.len();
+        let page_prot = self.page_prot.get_or_insert_with(|| Box::new(PageProt::new(heap_size)));
+
+        if !page_prot.protect(addr, num_bytes, prot) {
+            return false;
+        }
+
+        self.checked_memory = true;
+        true
+    }
+
+    /// Get the MEM_PROT_* protection of the heap page containing an address
+    pub fn memory_protection(&self, addr: usize) -> Option<u8>
+    {
+        match &self.page_prot {
+            Some(page_prot) => page_prot.query(addr),
+            None if addr < self.heap.len() => Some((MEM_PROT_READ | MEM_PROT_WRITE) as u8),
+            None => None,
+        }
+    }
+
+    /// Check guest heap accesses against shadow memory from now on
+    pub fn enable_asan(&mut self)
+    {
+        match &mut self.asan {
+            Some(asan) => asan.enable_checks(self.heap.len()),
+            None => self.asan = Some(Box::new(Asan::new(self.heap.len(), true)))
+        }
+    }
+
+    /// Track guest allocations so that leaks can be reported on exit
+    pub fn enable_leak_detection(&mut self)
+    {
+        if self.asan.is_none() {
+            self.asan = Some(Box::new(Asan::new(self.heap.len(), false)));
+        }
+    }
+
+    /// Print a report of the guest allocations that were never freed
+    pub fn print_leak_report(&self)
+    {
+        if let Some(report) = self.asan.as_ref().and_then(|asan| asan.leak_report()) {
+            eprintln!("{}", report);
+        }
+    }
+
+    /// Get the allocation tracking state, if running in --asan
+    /// or --detect-leaks mode
+    pub fn asan(&mut self) -> Option<&mut Asan>
+    {
+        self.asan.as_deref_mut()
+    }
+
+    /// Get the address the current function will return to,
+    /// which identifies the call site of a syscall wrapper
+    pub fn caller_pc(&self) -> usize
+    {
+        match self.frames.last() {
+            Some(frame) => frame.ret_addr,
+            None => usize::MAX
+        }
+    }
+
+    /// Walk the call stack, innermost frame first
+    pub fn backtrace(&self) -> impl Iterator<Item = FrameInfo> + '_
+    {
+        self.frames.iter().rev().map(|frame| FrameInfo {
+            fn_pc: frame.fn_pc,
+            ret_addr: match frame.ret_addr {
+                usize::MAX => None,
+                // The trap handler is called from the faulting instruction
+                TRAP_HANDLER_RET => self.active_trap.map(|trap| trap.pc),
+                ret_addr => Some(ret_addr),
+            },
+        })
+    }
+
+    /// Name of the function starting at an address, preferring
+    /// names that don't look like local labels
+    fn fn_name(&self, fn_pc: usize) -> Option<&str>
+    {
+        let mut names = self.code_labels.iter().filter(|(_, pos)| *pos == fn_pc).map(|(name, _)| name);
+        names.clone().find(|name| !name.starts_with('_')).or(names.next()).map(|name| name.as_str())
+    }
+
+    /// Describe the call stack, innermost frame first, with the function and
+    /// offset in it of each active call, given the pc execution stopped at
+    pub fn symbolic_backtrace(&self, mut pc: usize) -> String
+    {
+        let mut out = String::new();
+
+        for (idx, frame) in self.backtrace().enumerate() {
+            match (self.fn_name(frame.fn_pc), pc.checked_sub(frame.fn_pc)) {
+                (Some(name), Some(offset)) => writeln!(out, "  #{} pc {} ({}+{})", idx, pc, name, offset),
+                (Some(name), None) => writeln!(out, "  #{} pc {} (in {})", idx, pc, name),
+                (None, _) => writeln!(out, "  #{} pc {}", idx, pc),
+            }.unwrap();
+
+            match frame.ret_addr {
+                Some(ret_addr) => pc = ret_addr,
+                None => break,
+            }
+        }
+
+        out
+    }
+
+    /// Backtrace of the guest at the last trap which stopped execution
+    pub fn trap_backtrace(&self) -> &str
+    {
+        &self.trap_backtrace
+    }
+
+    /// Record a minidump of the state of the program
+    /// when a trap without a handler stops execution
+    pub fn enable_minidumps(&mut self)
+    {
+        self.minidumps_enabled = true;
+    }
+
+    /// Only run the instructions which the verifier checked, given a flag
+    /// for each position in the code, so that a program verified with
+    /// instruction restrictions can't get around them
+    pub fn restrict_to_verified(&mut self, restrictions: OpRestrictions, mut checked: Vec<bool>)
+    {
+        checked.resize(self.code.len(), false);
+        self.verified_code = Some(checked);
+        self.restrictions = restrictions;
+    }
+
+    /// Allow the instructions of a module loaded at a given code address
+    /// to run, given the flags returned by the verifier for its code
+    pub fn add_verified_code(&mut self, code_base: usize, checked: &[bool])
+    {
+        if let Some(verified_code) = &mut self.verified_code {
+            verified_code.resize(code_base, false);
+            verified_code.extend_from_slice(checked);
+            verified_code.resize(self.code.len(), false);
+        }
+    }
+
+    pub fn is_restricted(&self) -> bool
+    {
+        self.verified_code.is_some()
+    }
+
+    /// Instruction restrictions, which also apply to the modules loaded
+    pub fn restrictions(&self) -> &OpRestrictions
+    {
+        &self.restrictions
+    }
+
+    /// Take the minidump of the last trap which stopped execution
+    pub fn take_minidump(&mut self) -> Option<Minidump>
+    {
+        self.minidump.take().map(|dump| *dump)
+    }
+
+    /// Capture the state of the program at a trap, before the stack
+    /// and call frames are discarded, given the base pointer of the
+    /// faulting frame
+    fn capture_minidump(&self, trap: Trap, bp: usize) -> Minidump
+    {
+        let mut frames = Vec::new();
+        let mut pc = trap.pc;
+
+        for frame in self.backtrace() {
+            frames.push(DumpFrame {
+                fn_pc: frame.fn_pc,
+                pc,
+                fn_name: self.fn_name(frame.fn_pc).map(String::from),
+                source: self.line_table.find(pc).map(|(file_name, line_no)| format!("{}:{}", file_name, line_no)),
+            });
+
+            match frame.ret_addr {
+                Some(ret_addr) => pc = ret_addr,
+                None => break,
+            }
+        }
+
+        let code_start = trap.pc.saturating_sub(CODE_CONTEXT);
+        let code_end = std::cmp::min(trap.pc + CODE_CONTEXT, self.code.len());
+
+        let opcode = self.code.as_slice().get(trap.pc).copied().unwrap_or(u8::MAX);
+        let insn = match opcode <= Op::exit as u8 {
+            true => format!("{:?}", unsafe { transmute::<u8, Op>(opcode) }),
+            false => format!("invalid opcode {}", opcode),
+        };
+
+        let (trap_code, trap_addr) = trap.kind.handler_args();
+        let (heap_start, heap_end) = match trap.kind {
+            TrapKind::OutOfBounds { addr, .. } |
+            TrapKind::Unaligned { addr, .. } |
+            TrapKind::Protection { addr, .. } => {
+                let end = std::cmp::min(addr.saturating_add(HEAP_CONTEXT), self.heap.len());
+                (std::cmp::min(addr.saturating_sub(HEAP_CONTEXT), end), end)
+            }
+            _ => (0, 0),
+        };
+
+        Minidump {
+            program: String::new(),
+            code_checksum: crc32(0, self.code.as_slice()),
+            trap: trap.to_string(),
+            trap_code,
+            trap_addr,
+            pc: trap.pc,
+            bp,
+            insn,
+            stack: self.stack.iter().map(|val| val.as_u64()).collect(),
+            frames,
+            code_start,
+            code: self.code.as_slice()[code_start..code_end].to_vec(),
+            heap_start,
+            heap: self.heap.as_slice()[heap_start..heap_end].to_vec(),
+            heap_size: self.heap.len(),
+        }
+    }
+
+    /// Count backward jumps, which are loop iterations, in --profile mode
+    #[inline(always)]
+    fn profile_jump(&mut self, next_pc: usize, target_pc: usize)
+    {
+        if target_pc < next_pc {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.count_back_edge(target_pc);
+            }
+        }
+    }
+
+    /// Check a memory access by the instruction at a given pc in --asan mode
+    #[inline(always)]
+    fn asan_check(&self, addr: usize, num_bytes: usize, pc: usize, is_write: bool)
+    {
+        if let Some(asan) = &self.asan {
+            if !asan.checks_enabled() {
+                return;
+            }
+
+            if let Err(msg) = asan.check(addr, num_bytes, pc, is_write) {
+                panic!("{}", msg);
+            }
+        }
+    }
+
+    // FIXME: this function should be marked unsafe
+    //
+    /// Get a pointer to an address/offset in the heap
+    pub fn get_heap_ptr<T>(&mut self, addr: usize, num_elems: usize) -> *mut T
+    {
+        if addr.checked_add(size_of::<T>() * num_elems).is_none_or(|end| end > self.heap.len()) {
+            panic!("attempting to access memory slice past end of heap");
+        }
+
+        if addr & (size_of::<T>() - 1) != 0 {
+            panic!(
+                "attempting to access data of type {} at unaligned address 0x{:x}",
+                std::any::type_name::<T>(),
+                addr
+            );
+        }
+
+        unsafe {
+            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
+            transmute::<*mut u8 , *mut T>(heap_ptr)
+        }
+    }
+
+    /// Check that a memory access is within the heap, aligned,
+    /// and allowed by the page protections
+    #[inline(always)]
+    fn check_access(&self, addr: usize, size: usize, align: usize, is_write: bool) -> Option<TrapKind>
+    {
+        if addr.checked_add(size).is_none_or(|end| end > self.heap.len()) {
+            return Some(TrapKind::OutOfBounds { addr, size });
+        }
+
+        if addr & (align - 1) != 0 {
+            return Some(TrapKind::Unaligned { addr, size });
+        }
+
+        if let Some(page_prot) = &self.page_prot {
+            if !page_prot.allows(addr, size, is_write) {
+                return Some(TrapKind::Protection { addr, size, is_write });
+            }
+        }
+
+        None
+    }
+
+    /// Stop execution because of an error in guest code
+    fn stop_trap(&mut self, trap: Trap, bp: usize) -> ExitReason
+    {
+        self.trap_backtrace = self.symbolic_backtrace(trap.pc);
+        if self.minidumps_enabled {
+            self.minidump = Some(Box::new(self.capture_minidump(trap, bp)));
+        }
+
+        self.clear_threads();
+        self.stack.clear();
+        self.frames.clear();
+        self.active_trap = None;
+        ExitReason::Trap(trap)
+    }
+
+    /// Call the trap handler for an error in guest code, returning the pc
+    /// and base pointer to continue at. Without a handler, or when the
+    /// error is in the handler itself, execution stops.
+    fn raise_trap(&mut self, kind: TrapKind, pc: usize, bp: usize) -> Result<(usize, usize), ExitReason>
+    {
+        let trap = Trap { kind, pc };
+
+        let handler_pc = match self.trap_handler {
+            Some(handler_pc) if self.active_trap.is_none() => handler_pc,
+            _ => return Err(self.stop_trap(trap, bp)),
+        };
+
+        let (code, addr) = kind.handler_args();
+        self.push(code);
+        self.push(pc as u64);
+        self.push(addr);
+
+        self.frames.push(StackFrame {
+            prev_bp: bp,
+            ret_addr: TRAP_HANDLER_RET,
+            fn_pc: handler_pc,
+            argc: 3,
+            num_locals: None,
+        });
+        self.active_trap = Some(trap);
+
+        Ok((handler_pc, self.stack.len()))
+    }
+
+    /// Read a value at an address which doesn't need to be aligned
+    fn load_unaligned<T: Copy>(&self, addr: usize) -> T
+    {
+        if addr.checked_add(size_of::<T>()).is_none_or(|end| end > self.heap.len()) {
+            panic!("attempting to access memory slice past end of heap");
+        }
+
+        unsafe { std::ptr::read_unaligned(self.heap.data.as_ptr().add(addr) as *const T) }
+    }
+
+    /// Write a value at an address which doesn't need to be aligned
+    fn store_unaligned<T: Copy>(&mut self, addr: usize, val: T)
+    {
+        if addr.checked_add(size_of::<T>()).is_none_or(|end| end > self.heap.len()) {
+            panic!("attempting to access memory slice past end of heap");
+        }
+
+        unsafe { std::ptr::write_unaligned(self.heap.data.as_mut_ptr().add(addr) as *mut T, val) }
+    }
+
+    /// Get a bounds-checked view of the heap for use by syscalls
+    pub fn mem_view(&mut self) -> MemView<'_>
+    {
+        MemView { data: &mut self.heap.data }
+    }
+
+    /// Check that the arguments of a call are on the stack,
+    /// above the local variables of the caller
+    fn check_call_args(&self, bp: usize, num_args: usize)
+    {
+        let num_locals = self.frames[self.frames.len() - 1].num_locals.unwrap_or(0);
+        let num_vals = self.stack.len().saturating_sub(bp + num_locals);
+        if num_args > num_vals {
+            panic!("call with {} arguments but only {} values on the stack", num_args, num_vals);
+        }
+    }
+
+    /// Call a function at a given address
+    pub fn call(&mut self, callee_pc: u64, args: &[Value]) -> ExitReason
+    {
+        assert!(self.stack.len() == 0);
+        assert!(self.frames.len() == 0);
+        assert!(self.suspended_at.is_none(), "call while execution is suspended");
+
+        // Push a new stack frame
+        self.frames.push(StackFrame {
+            prev_bp: usize::MAX,
+            ret_addr: usize::MAX,
+            fn_pc: callee_pc as usize,
+            argc: args.len(),
+            num_locals: None,
+        });
+
+        // Push the arguments on the stack
+        for arg in args {
+            self.stack.push(*arg);
+        }
+
+        // The base pointer will point at the first local
+        let bp = self.stack.len();
+        let pc = callee_pc as usize;
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count_call(pc);
+        }
+
+        self.run(pc, bp)
+    }
+
+    /// Save the heap, stack, call frames and the position where execution
+    /// was suspended, if it was, in a compact binary format
+    pub fn save_snapshot(&self) -> Vec<u8>
+    {
+        assert!(
+            self.suspended_at.is_some() || self.frames.is_empty(),
+            "snapshot while execution is running"
+        );
+        assert!(!self.threads.has_others(), "snapshot of a program with several threads");
+
+        self.snapshot().encode()
+    }
+
+    /// Get the execution state which save_snapshot() saves
+    pub fn snapshot(&self) -> Snapshot
+    {
+        Snapshot {
+            code_checksum: crc32(0, self.code.as_slice()),
+            suspended_at: self.suspended_at,
+            heap: self.heap.data.clone(),
+            stack: self.stack.iter().map(|val| val.as_u64()).collect(),
+            frames: self.frames.iter().map(|frame| FrameData {
+                prev_bp: frame.prev_bp,
+                ret_addr: frame.ret_addr,
+                fn_pc: frame.fn_pc,
+                argc: frame.argc,
+                num_locals: frame.num_locals,
+            }).collect(),
+        }
+    }
+
+    /// Restore the state saved by save_snapshot(). The same program must
+    /// already be loaded. If execution was suspended, it can be continued
+    /// with resume(). On error, the state of the VM is left unchanged.
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), String>
+    {
+        assert!(self.frames.is_empty(), "snapshot loaded while execution is running");
+
+        let snapshot = Snapshot::decode(data)?;
+
+        if snapshot.code_checksum != crc32(0, self.code.as_slice()) {
+            return Err("snapshot was saved from a different program".to_string());
+        }
+
+        let valid_pc = |pc: usize| pc < self.code.len();
+        let valid_bp = |bp: usize| bp <= snapshot.stack.len();
+
+        // The top-level frame has no previous base pointer
+        let frames_valid = snapshot.frames.iter().all(|frame|
+            (valid_bp(frame.prev_bp) || frame.prev_bp == usize::MAX) && valid_pc(frame.fn_pc)
+        );
+
+        let state_valid = match snapshot.suspended_at {
+            Some((pc, bp)) => !snapshot.frames.is_empty() && valid_pc(pc) && valid_bp(bp),
+            None => snapshot.frames.is_empty() && snapshot.stack.is_empty(),
+        };
+
+        if !frames_valid || !state_valid || snapshot.heap.len() % 8 != 0 {
+            return Err("invalid execution state in snapshot".to_string());
+        }
+
+        self.heap.data = snapshot.heap;
+        if let Some(asan) = &mut self.asan {
+            asan.resize(self.heap.len());
+        }
+        if let Some(page_prot) = &mut self.page_prot {
+            page_prot.resize(self.heap.len());
+        }
+
+        self.stack = snapshot.stack.into_iter().map(Value::from).collect();
+        self.frames = snapshot.frames.into_iter().map(|frame| StackFrame {
+            prev_bp: frame.prev_bp,
+            ret_addr: frame.ret_addr,
+            fn_pc: frame.fn_pc,
+            argc: frame.argc,
+            num_locals: frame.num_locals,
+        }).collect();
+        self.suspended_at = snapshot.suspended_at;
+
+        Ok(())
+    }
+
+    /// Call a function and run it to completion, continuing after yields.
+    /// This is what the host uses to run the event callbacks of programs.
+    /// A trap is printed and turned into an exit with TRAP_EXIT_CODE.
+    pub fn call_sync(&mut self, callee_pc: u64, args: &[Value]) -> ExitReason
+    {
+        let result = self.call(callee_pc, args);
+        self.run_to_completion(result)
+    }
+
+    /// Continue running after yields until the top-level function
+    /// returns or exits, and turn traps into exits
+    fn run_to_completion(&mut self, mut result: ExitReason) -> ExitReason
+    {
+        loop
+        {
+            match result
+            {
+                ExitReason::Yield | ExitReason::Suspended => result = self.resume(),
+                ExitReason::Breakpoint(pc) => panic!("execution stopped at breakpoint, pc {}", pc),
+                ExitReason::SyscallPending(idx) => panic!("syscall {} can't be deferred here", idx),
+                ExitReason::Trap(trap) => {
+                    eprintln!("execution error, {}", trap);
+                    eprint!("guest backtrace:\n{}", self.trap_backtrace);
+                    return ExitReason::Exit(Value::from(TRAP_EXIT_CODE));
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    /// Continue running after execution was suspended
+    /// because the instruction budget ran out, the program
+    /// yielded, or it stopped at a breakpoint
+    pub fn resume(&mut self) -> ExitReason
+    {
+        let (pc, bp) = self.suspended_at.take().expect("resume without suspended execution");
+        self.run(pc, bp)
+    }
+
+    /// Complete a deferred syscall with its return value and continue running
+    pub fn resume_with(&mut self, ret_val: Value) -> ExitReason
+    {
+        assert!(self.suspended_at.is_some(), "resume without suspended execution");
+        self.push(ret_val);
+        self.resume()
+    }
+
+    /// Stop execution once the current syscall returns, and
+    /// give control back to the host with ExitReason::Yield
+    pub fn request_yield(&mut self)
+    {
+        self.yield_requested = true;
+    }
+
+    /// Called by a syscall to let the host complete it later. The value
+    /// the syscall returns is discarded, and execution stops with
+    /// ExitReason::SyscallPending until the host calls resume_with().
+    /// Syscalls without a return value are continued with resume().
+    pub fn defer_syscall(&mut self)
+    {
+        self.syscall_deferred = true;
+    }
+
+    /// Id of the running guest thread, 0 for the main thread
+    pub fn thread_id(&self) -> u64
+    {
+        self.threads.cur_id
+    }
+
+    /// Check if there are guest threads other than the running one
+    pub fn has_other_threads(&self) -> bool
+    {
+        self.threads.has_others()
+    }
+
+    /// Start a guest thread which calls a function with one
+    /// argument and uses a given TLS block, and get its id
+    pub fn spawn_thread(&mut self, fn_pc: usize, arg: Value, tls_base: usize) -> u64
+    {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count_call(fn_pc);
+        }
+
+        self.threads.spawn(ThreadContext {
+            id: 0,
+            stack: vec![arg],
+            frames: vec![StackFrame {
+                prev_bp: usize::MAX,
+                ret_addr: usize::MAX,
+                fn_pc,
+                argc: 1,
+                num_locals: None,
+            }],
+            pc: fn_pc,
+            bp: 1,
+            tls_base,
+            wait_for: None,
+        })
+    }
+
+    /// Called by thread_join to get the return value of a thread. If the
+    /// thread hasn't finished, None is returned, and the running thread
+    /// waits for it once the syscall returns.
+    pub fn join_thread(&mut self, id: u64) -> Option<Value>
+    {
+        if let Some(ret_val) = self.threads.take_result(id) {
+            return Some(ret_val);
+        }
+
+        if id == 0 || id == self.threads.cur_id || !self.threads.is_alive(id) {
+            panic!("thread_join: invalid thread id {}", id);
+        }
+
+        self.wait_requested = Some(WaitFor::Thread(id));
+        None
+    }
+
+    pub fn create_mutex(&mut self) -> u64
+    {
+        self.threads.create_mutex()
+    }
+
+    pub fn create_cond(&mut self) -> u64
+    {
+        self.threads.create_cond()
+    }
+
+    /// Called by mutex_lock. If another thread holds the mutex, the
+    /// running thread waits for it once the syscall returns.
+    pub fn lock_mutex(&mut self, id: u64)
+    {
+        if !self.threads.is_mutex(id) {
+            panic!("mutex_lock: invalid mutex id {}", id);
+        }
+
+        if self.threads.mutex_owner(id) == Some(self.threads.cur_id) {
+            panic!("mutex_lock: thread {} already holds mutex {}", self.threads.cur_id, id);
+        }
+
+        if !self.threads.lock_mutex(id) {
+            self.wait_requested = Some(WaitFor::Mutex(id));
+        }
+    }
+
+    /// Called by mutex_unlock, the mutex must be held by the running thread
+    pub fn unlock_mutex(&mut self, id: u64)
+    {
+        if !self.threads.is_mutex(id) || self.threads.mutex_owner(id) != Some(self.threads.cur_id) {
+            panic!("mutex_unlock: thread {} doesn't hold mutex {}", self.threads.cur_id, id);
+        }
+
+        self.threads.unlock_mutex(id);
+    }
+
+    /// Called by cond_wait, the running thread unlocks the mutex and
+    /// waits on the condition variable once the syscall returns
+    pub fn wait_cond(&mut self, cond: u64, mutex: u64)
+    {
+        if !self.threads.is_cond(cond) {
+            panic!("cond_wait: invalid condition variable id {}", cond);
+        }
+
+        if !self.threads.is_mutex(mutex) || self.threads.mutex_owner(mutex) != Some(self.threads.cur_id) {
+            panic!("cond_wait: thread {} doesn't hold mutex {}", self.threads.cur_id, mutex);
+        }
+
+        self.threads.unlock_mutex(mutex);
+        self.threads.wait_cond(cond, mutex);
+        self.wait_requested = Some(WaitFor::Signal { cond, mutex });
+    }
+
+    /// Called by cond_signal
+    pub fn signal_cond(&mut self, cond: u64)
+    {
+        if !self.threads.is_cond(cond) {
+            panic!("cond_signal: invalid condition variable id {}", cond);
+        }
+
+        self.threads.signal_cond(cond);
+    }
+
+    /// Called by pipe_create, once the handle of the pipe is allocated
+    pub fn create_pipe(&mut self, pipe: u64, buf: usize, capacity: usize)
+    {
+        self.threads.create_pipe(pipe, buf, capacity);
+    }
+
+    /// Called by pipe_close, the threads waiting on the pipe can run again
+    pub fn close_pipe(&mut self, pipe: u64)
+    {
+        self.threads.remove_pipe(pipe);
+    }
+
+    /// Called by pipe_write. If the pipe doesn't have room for all the
+    /// bytes, the running thread waits for it once the syscall returns,
+    /// and gets the number of bytes written when it runs again.
+    pub fn write_pipe(&mut self, pipe: u64, src: usize, num_bytes: usize) -> Value
+    {
+        match self.threads.pipe_capacity(pipe) {
+            Some(capacity) if num_bytes <= capacity => {}
+            Some(_) => return sys_err(EINVAL),
+            None => return sys_err(EBADF),
+        }
+
+        // Check the buffer now, so that a bad pointer faults in the caller
+        self.mem_view().slice::<u8>(src, num_bytes);
+
+        match self.threads.write_pipe(pipe, num_bytes) {
+            Some(ranges) => {
+                self.copy_to_pipe(src, ranges);
+                Value::from(num_bytes)
+            }
+            None => {
+                self.wait_requested = Some(WaitFor::PipeWrite { pipe, src, num_bytes });
+                // The number of bytes written is pushed when the thread runs again
+                Value::from(0)
+            }
+        }
+    }
+
+    /// Called by pipe_read. If the pipe is empty, the running thread
+    /// waits for data once the syscall returns, and gets the number of
+    /// bytes read when it runs again.
+    pub fn read_pipe(&mut self, pipe: u64, dst: usize, num_bytes: usize) -> Value
+    {
+        if self.threads.pipe_capacity(pipe).is_none() {
+            return sys_err(EBADF);
+        }
+
+        // Check the buffer now, so that a bad pointer faults in the caller
+        self.mem_view().slice::<u8>(dst, num_bytes);
+
+        if num_bytes == 0 {
+            return Value::from(0);
+        }
+
+        match self.threads.read_pipe(pipe, num_bytes) {
+            Some(ranges) => Value::from(self.copy_from_pipe(dst, ranges)),
+            None => {
+                self.wait_requested = Some(WaitFor::PipeRead { pipe, dst, num_bytes });
+                // The number of bytes read is pushed when the thread runs again
+                Value::from(0)
+            }
+        }
+    }
+
+    /// Copy bytes into the ranges of a pipe buffer reserved for a write
+    fn copy_to_pipe(&mut self, src: usize, ranges: [(usize, usize); 2])
+    {
+        let mut src = src;
+        for (addr, len) in ranges {
+            let data = self.mem_view().slice::<u8>(src, len).to_vec();
+            self.mem_view().write_bytes(addr, &data);
+            src += len;
+        }
+    }
+
+    /// Copy bytes out of the ranges of a pipe buffer taken by a read,
+    /// and get the number of bytes read
+    fn copy_from_pipe(&mut self, dst: usize, ranges: [(usize, usize); 2]) -> usize
+    {
+        let mut dst = dst;
+        for (addr, len) in ranges {
+            let data = self.mem_view().slice::<u8>(addr, len).to_vec();
+            self.mem_view().write_bytes(dst, &data);
+            dst += len;
+        }
+        ranges[0].1 + ranges[1].1
+    }
+
+    /// Check if threads other than the main thread can run
+    /// while the main thread is idle in the host
+    pub fn has_runnable_threads(&self) -> bool
+    {
+        self.threads.has_runnable()
+    }
+
+    /// Give the time the main thread is idle in the event loop of the
+    /// host to the other threads, which each run for a time slice.
+    /// Returns the exit value if a thread exits.
+    pub fn run_threads(&mut self) -> Option<Value>
+    {
+        assert!(self.frames.is_empty(), "run_threads while execution is running");
+
+        if !self.threads.has_runnable() {
+            return None;
+        }
+
+        // The main thread gets control back from the queue with no frames
+        self.save_thread(0, 0, None);
+        let (pc, bp) = self.next_thread().unwrap();
+
+        let result = self.run(pc, bp);
+        match self.run_to_completion(result) {
+            ExitReason::Exit(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Put the running thread in the queue of the scheduler
+    fn save_thread(&mut self, pc: usize, bp: usize, wait_for: Option<WaitFor>)
+    {
+        self.threads.save(ThreadContext {
+            id: self.threads.cur_id,
+            stack: std::mem::take(&mut self.stack),
+            frames: std::mem::take(&mut self.frames),
+            pc,
+            bp,
+            tls_base: self.sys_state.thread_state.tls_base,
+            wait_for,
+        });
+    }
+
+    /// Switch to the next thread which can run, and get the pc and base
+    /// pointer to continue at. Returns None when that is the main thread
+    /// waiting for the host in run_threads().
+    fn next_thread(&mut self) -> Option<(usize, usize)>
+    {
+        // Callers check that a thread can run
+        let ctx = self.threads.next().unwrap();
+        self.stack = ctx.stack;
+        self.frames = ctx.frames;
+        self.sys_state.thread_state.tls_base = ctx.tls_base;
+
+        // Complete the pipe read or write the thread was waiting on. The
+        // scheduler only runs the thread once the pipe has room or data,
+        // so it can't find it full or empty, but it may have been closed.
+        match ctx.wait_for {
+            Some(WaitFor::PipeWrite { pipe, src, num_bytes }) => {
+                let result = match self.threads.write_pipe(pipe, num_bytes) {
+                    Some(ranges) => {
+                        self.copy_to_pipe(src, ranges);
+                        Value::from(num_bytes)
+                    }
+                    None => sys_err(EBADF),
+                };
+                self.stack.push(result);
+            }
+            Some(WaitFor::PipeRead { pipe, dst, num_bytes }) => {
+                let result = match self.threads.read_pipe(pipe, num_bytes) {
+                    Some(ranges) => Value::from(self.copy_from_pipe(dst, ranges)),
+                    None => sys_err(EBADF),
+                };
+                self.stack.push(result);
+            }
+            _ => {}
+        }
+
+        if self.frames.is_empty() {
+            return None;
+        }
+
+        Some((ctx.pc, ctx.bp))
+    }
+
+    /// Stop the running thread, other than the main thread, once its
+    /// function has returned, and free its TLS block
+    fn finish_thread(&mut self, ret_val: Value)
+    {
+        self.threads.finish(ret_val);
+
+        if !self.threads.has_runnable() {
+            panic!("deadlock, thread {} finished and all other threads are waiting", self.threads.cur_id);
+        }
+
+        let tls_base = self.sys_state.thread_state.tls_base;
+        if tls_base != 0 {
+            mem_free(self, Value::from(tls_base));
+        }
+
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    /// Stop all the threads but the main thread, when execution stops
+    fn clear_threads(&mut self)
+    {
+        if self.threads.cur_id != 0 {
+            let tls_base = self.sys_state.thread_state.tls_base;
+            if tls_base != 0 {
+                mem_free(self, Value::from(tls_base));
+            }
+        }
+
+        for ctx in self.threads.clear() {
+            if ctx.id == 0 {
+                self.sys_state.thread_state.tls_base = ctx.tls_base;
+            } else if ctx.tls_base != 0 {
+                mem_free(self, Value::from(ctx.tls_base));
+            }
+        }
+    }
+
+    /// Run code until the top-level function returns or exits, and
+    /// print the backtrace of the guest if the VM panics
+    fn run(&mut self, pc: usize, bp: usize) -> ExitReason
+    {
+        match catch_unwind(AssertUnwindSafe(|| self.run_loop(pc, bp))) {
+            Ok(exit_reason) => exit_reason,
+            Err(payload) => {
+                eprint!("guest backtrace:\n{}", self.symbolic_backtrace(self.cur_pc));
+                resume_unwind(payload)
+            }
+        }
+    }
+
+    fn run_loop(&mut self, mut pc: usize, mut bp: usize) -> ExitReason
+    {
+        // Continue in the trap handler, at the start of a new block, or stop
+        macro_rules! trap {
+            ($kind:expr, $op_pc:expr) => {
+                match self.raise_trap($kind, $op_pc, bp) {
+                    Ok((handler_pc, handler_bp)) => {
+                        pc = handler_pc;
+                        bp = handler_bp;
+                        break;
+                    }
+                    Err(exit_reason) => return exit_reason,
+                }
+            }
+        }
+
+        // Compiled code doesn't count instructions, loop
+        // iterations or check memory accesses against shadow memory
+        let use_jit =
+            self.jit.is_some() &&
+            self.profiler.is_none() &&
+            self.coverage.is_none() &&
+            self.insn_budget.is_none() &&
+            !self.asan.as_ref().is_some_and(|asan| asan.checks_enabled()) &&
+            !cfg!(feature = "count_insns");
+
+        // For each basic block to execute
+        loop
+        {
+            // If the host asked us to stop (e.g. ctrl-C), exit cleanly
+            if stop_requested() {
+                self.clear_threads();
+                self.stack.clear();
+                self.frames.clear();
+                return ExitReason::Exit(Value::from(SIGINT_EXIT_CODE));
+            }
+
+            // Switch to the next thread once the running
+            // one has used up its time slice
+            if self.threads.has_others() {
+                if self.threads.slice_left == 0 {
+                    self.save_thread(pc, bp, None);
+                    match self.next_thread() {
+                        Some((next_pc, next_bp)) => (pc, bp) = (next_pc, next_bp),
+                        None => return ExitReason::Return(Value::from(0)),
+                    }
+                }
+
+                self.threads.slice_left -= 1;
+            }
+
+            // Blocks are entered at the start of a function, after a call
+            // or at a branch target, which are only unchecked when reached
+            // through a computed address
+            if self.verified_code.as_ref().is_some_and(|checked| !checked.get(pc).copied().unwrap_or(false)) {
+                match self.raise_trap(TrapKind::UnverifiedCode, pc, bp) {
+                    Ok((handler_pc, handler_bp)) => {
+                        pc = handler_pc;
+                        bp = handler_bp;
+                        continue;
+                    }
+                    Err(exit_reason) => return exit_reason,
+                }
+            }
+
+            let block_idx = self.block_cache.get(&self.code, pc);
+
+            // Compiled code doesn't check page protections, which can
+            // be set while running, and isn't used with several threads,
+            // which must switch at the same points as in the interpreter
+            if use_jit && self.page_prot.is_none() && !self.threads.has_others() {
+                if let Some(next_pc) = self.run_native(block_idx, pc, bp) {
+                    pc = next_pc;
+                    continue;
+                }
+            }
+
+            // For each instruction in the block. Only the last instruction
+            // of a block can branch, so pc always points past the
+            // instruction being executed
+            for idx in self.block_cache.op_range(block_idx)
+            {
+                let uop = self.block_cache.op(idx);
+
+                #[cfg(feature = "count_insns")]
+                {
+                    self.insn_count += 1;
+                }
+
+                // Suspend before this instruction if the budget ran out,
+                // pc is still the address of the instruction here
+                if let Some(budget) = &mut self.insn_budget {
+                    if *budget == 0 {
+                        self.suspended_at = Some((pc, bp));
+                        return ExitReason::Suspended;
+                    }
+                    *budget -= 1;
+                }
+
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.count(pc);
+                }
+
+                // Address of the instruction, for reporting traps
+                let op_pc = pc;
+                self.cur_pc = pc;
+                pc = uop.next_pc;
+
+                match uop.op
+                {
+                    Op::panic => panic!("execution error, encountered panic opcode"),
+
+                    Op::nop => continue,
+
+                    Op::breakpoint => {
+                        self.suspended_at = Some((pc, bp));
+                        return ExitReason::Breakpoint(pc - 1);
+                    }
+
+                    Op::trap => {
+                        let loc_ptr = self.pop().as_usize();
+                        let loc = self.mem_view().str(loc_ptr).to_string();
+                        panic!("execution error, trap at {}, pc {}", loc, pc - 1);
+                    }
+
+                    Op::assert_stack => {
+                        if cfg!(debug_assertions) {
+                            let depth = self.stack.len() - bp;
+                            if depth != uop.imm as usize {
+                                panic!(
+                                    "stack assertion failed, expected {} values on the stack, found {}, pc {}",
+                                    uop.imm, depth, op_pc
+                                );
+                            }
+                        }
+                    }
+
+                    Op::pop => {
+                        self.pop();
+                    }
 
-                Op::pop => {
-                    self.pop();
-                }
+                    Op::getn => {
+                        let n = uop.idx as usize;
+                        let val = self.stack[self.stack.len() - (1 + n)];
+                        self.push(val);
+                    }
 
-                Op::getn => {
-                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.stack[self.stack.len() - (1 + n)];
-                    self.push(val);
-                }
+                    Op::setn => {
+                        let n = uop.idx as usize;
+                        let val = self.pop();
+                        let len = self.stack.len();
+                        self.stack[len - (1 + n)] = val;
+                    }
 
-                Op::setn => {
-                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.pop();
-                    let len = self.stack.len();
-                    self.stack[len - (1 + n)] = val;
-                }
+                    Op::dup => {
+                        let val = self.pop();
+                        self.push(val);
+                        self.push(val);
+                    }
 
-                Op::dup => {
-                    let val = self.pop();
-                    self.push(val);
-                    self.push(val);
-                }
+                    Op::swap => {
+                        let a = self.pop();
+                        let b = self.pop();
+                        self.push(a);
+                        self.push(b);
+                    }
 
-                Op::swap => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(a);
-                    self.push(b);
-                }
+                    Op::get_arg => {
+                        let idx = uop.idx as usize;
 
-                Op::get_arg => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                        let argc = self.frames[self.frames.len() - 1].argc;
+                        if idx >= argc {
+                            panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                        }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                        // Last argument is at bp - 1 (if there are arguments)
+                        let stack_idx = (bp - argc) + idx;
+                        self.push(self.stack[stack_idx]);
                     }
 
-                    // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    self.push(self.stack[stack_idx]);
-                }
+                    Op::get_var_arg => {
+                        let idx = self.pop().as_usize();
 
-                Op::get_var_arg => {
-                    let idx = self.pop().as_usize();
+                        let argc = self.frames[self.frames.len() - 1].argc;
+                        if idx >= argc {
+                            panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                        }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                        // Last argument is at bp - 1 (if there are arguments)
+                        let stack_idx = (bp - argc) + idx;
+                        self.push(self.stack[stack_idx]);
                     }
 
-                    // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    self.push(self.stack[stack_idx]);
-                }
+                    Op::set_arg => {
+                        let idx = uop.idx as usize;
 
-                Op::set_arg => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                        let argc = self.frames[self.frames.len() - 1].argc;
+                        if idx >= argc {
+                            panic!("invalid index in set_arg, idx={}, argc={}", idx, argc);
+                        }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in set_arg, idx={}, argc={}", idx, argc);
+                        // Last argument is at bp - 1 (if there are arguments)
+                        let stack_idx = (bp - argc) + idx;
+                        let val = self.pop();
+                        self.stack[stack_idx] = val;
                     }
 
-                    // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    let val = self.pop();
-                    self.stack[stack_idx] = val;
-                }
+                    Op::get_local => {
+                        let idx = uop.idx as usize;
+
+                        if let Some(num_locals) = self.frames[self.frames.len() - 1].num_locals {
+                            if idx >= num_locals {
+                                panic!("invalid index in get_local, idx={}, num_locals={}", idx, num_locals);
+                            }
+                        }
 
-                Op::get_local => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                        if bp + idx >= self.stack.len() {
+                            panic!("invalid index {} in get_local", idx);
+                        }
 
-                    if bp + idx >= self.stack.len() {
-                        panic!("invalid index {} in get_local", idx);
+                        self.push(self.stack[bp + idx]);
                     }
 
-                    self.push(self.stack[bp + idx]);
-                }
+                    Op::set_local => {
+                        let idx = uop.idx as usize;
+                        let val = self.pop();
+
+                        if let Some(num_locals) = self.frames[self.frames.len() - 1].num_locals {
+                            if idx >= num_locals {
+                                panic!("invalid index in set_local, idx={}, num_locals={}", idx, num_locals);
+                            }
+                        }
 
-                Op::set_local => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.pop();
+                        if bp + idx >= self.stack.len() {
+                            panic!("invalid index in set_local");
+                        }
 
-                    if bp + idx >= self.stack.len() {
-                        panic!("invalid index in set_local");
+                        self.stack[bp + idx] = val;
                     }
 
-                    self.stack[bp + idx] = val;
-                }
+                    Op::enter => {
+                        let n = uop.idx as usize;
+                        let frame = self.frames.last_mut().unwrap();
 
-                Op::push_0 => {
-                    self.push(0);
-                }
-                Op::push_1 => {
-                    self.push(1);
-                }
-                Op::push_2 => {
-                    self.push(2);
-                }
+                        if frame.num_locals.is_some() || self.stack.len() != bp {
+                            panic!("enter must be the first instruction of a function, pc {}", pc - 2);
+                        }
 
-                Op::push_0n => {
-                    let n = self.code.read_pc::<u8>(&mut pc);
-                    self.stack.resize(self.stack.len() + n as usize, Value::from(0));
-                }
+                        frame.num_locals = Some(n);
+                        self.stack.resize(bp + n, Value::from(0));
+                    }
 
-                Op::push_i8 => {
-                    let val = self.code.read_pc::<i8>(&mut pc);
-                    self.push(val);
-                }
+                    Op::push_0 => {
+                        self.push(0);
+                    }
+                    Op::push_1 => {
+                        self.push(1);
+                    }
+                    Op::push_2 => {
+                        self.push(2);
+                    }
 
-                Op::push_u32 => {
-                    let val = self.code.read_pc::<u32>(&mut pc);
-                    self.push(val);
-                }
+                    Op::push_0n => {
+                        let n = uop.idx;
+                        self.stack.resize(self.stack.len() + n as usize, Value::from(0));
+                    }
 
-                Op::push_u64 => {
-                    let val = self.code.read_pc::<u64>(&mut pc);
-                    self.push(val);
-                }
+                    Op::push_i8 => {
+                        let val = uop.imm;
+                        self.push(val);
+                    }
 
-                Op::and_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() & v1.as_u32());
-                }
+                    Op::push_u32 => {
+                        let val = uop.imm as u32;
+                        self.push(val);
+                    }
 
-                Op::or_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() | v1.as_u32());
-                }
+                    Op::push_u64 => {
+                        let val = uop.imm;
+                        self.push(val);
+                    }
 
-                Op::xor_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() ^ v1.as_u32());
-                }
+                    Op::and_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() & v1.as_u32());
+                    }
 
-                Op::not_u32 => {
-                    let v0 = self.pop();
-                    self.push(!v0.as_u32());
-                }
+                    Op::or_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() | v1.as_u32());
+                    }
 
-                Op::lshift_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_shl(v1.as_u32())
-                    );
-                }
+                    Op::xor_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() ^ v1.as_u32());
+                    }
 
-                Op::rshift_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_shr(v1.as_u32())
-                    );
-                }
+                    Op::not_u32 => {
+                        let v0 = self.pop();
+                        self.push(!v0.as_u32());
+                    }
 
-                Op::rshift_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i32().wrapping_shr(v1.as_u32())
-                    );
-                }
+                    Op::lshift_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u32().wrapping_shl(v1.as_u32())
+                        );
+                    }
 
-                Op::add_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_add(v1.as_u32())
-                    );
-                }
+                    Op::rshift_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u32().wrapping_shr(v1.as_u32())
+                        );
+                    }
 
-                Op::sub_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_sub(v1.as_u32())
-                    );
-                }
+                    Op::rshift_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_i32().wrapping_shr(v1.as_u32())
+                        );
+                    }
 
-                Op::mul_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_mul(v1.as_u32())
-                    );
-                }
+                    Op::add_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u32().wrapping_add(v1.as_u32())
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32() / v1.as_u32()
-                    );
-                }
+                    Op::sub_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u32().wrapping_sub(v1.as_u32())
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32() % v1.as_u32()
-                    );
-                }
+                    Op::mul_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u32().wrapping_mul(v1.as_u32())
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i32() / v1.as_i32()
-                    );
-                }
+                    Op::div_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_u32() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_u32() / v1.as_u32()
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i32() % v1.as_i32()
-                    );
-                }
+                    Op::mod_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_u32() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_u32() % v1.as_u32()
+                        );
+                    }
 
-                Op::eq_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() == v1.as_u32());
-                }
+                    Op::div_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_i32() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_i32() / v1.as_i32()
+                        );
+                    }
 
-                Op::ne_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() != v1.as_u32());
-                }
+                    Op::mod_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_i32() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_i32() % v1.as_i32()
+                        );
+                    }
 
-                Op::lt_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() < v1.as_u32());
-                }
+                    Op::eq_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() == v1.as_u32());
+                    }
 
-                Op::le_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() <= v1.as_u32());
-                }
+                    Op::ne_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() != v1.as_u32());
+                    }
 
-                Op::gt_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() > v1.as_u32());
-                }
+                    Op::lt_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() < v1.as_u32());
+                    }
 
-                Op::ge_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() >= v1.as_u32());
-                }
+                    Op::le_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() <= v1.as_u32());
+                    }
 
-                Op::lt_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() < v1.as_i32());
-                }
+                    Op::gt_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() > v1.as_u32());
+                    }
 
-                Op::le_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() <= v1.as_i32());
-                }
+                    Op::ge_u32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u32() >= v1.as_u32());
+                    }
 
-                Op::gt_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() > v1.as_i32());
-                }
+                    Op::lt_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i32() < v1.as_i32());
+                    }
 
-                Op::ge_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() >= v1.as_i32());
-                }
+                    Op::le_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i32() <= v1.as_i32());
+                    }
 
-                Op::and_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() & v1.as_u64());
-                }
+                    Op::gt_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i32() > v1.as_i32());
+                    }
 
-                Op::or_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() | v1.as_u64());
-                }
+                    Op::ge_i32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i32() >= v1.as_i32());
+                    }
 
-                Op::xor_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() ^ v1.as_u64());
-                }
+                    Op::and_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() & v1.as_u64());
+                    }
 
-                Op::not_u64 => {
-                    let v0 = self.pop();
-                    self.push(!v0.as_u64());
-                }
+                    Op::or_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() | v1.as_u64());
+                    }
 
-                Op::lshift_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_shl(v1.as_u32())
-                    );
-                }
+                    Op::xor_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() ^ v1.as_u64());
+                    }
 
-                Op::rshift_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_shr(v1.as_u32())
-                    );
-                }
+                    Op::not_u64 => {
+                        let v0 = self.pop();
+                        self.push(!v0.as_u64());
+                    }
 
-                Op::rshift_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i64().wrapping_shr(v1.as_u32())
-                    );
-                }
+                    Op::lshift_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u64().wrapping_shl(v1.as_u32())
+                        );
+                    }
 
-                Op::add_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_add(v1.as_u64())
-                    );
-                }
+                    Op::rshift_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u64().wrapping_shr(v1.as_u32())
+                        );
+                    }
 
-                Op::sub_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_sub(v1.as_u64())
-                    );
-                }
+                    Op::rshift_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_i64().wrapping_shr(v1.as_u32())
+                        );
+                    }
 
-                Op::mul_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_mul(v1.as_u64())
-                    );
-                }
+                    Op::add_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u64().wrapping_add(v1.as_u64())
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64() / v1.as_u64()
-                    );
-                }
+                    Op::sub_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u64().wrapping_sub(v1.as_u64())
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64() % v1.as_u64()
-                    );
-                }
+                    Op::mul_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(
+                            v0.as_u64().wrapping_mul(v1.as_u64())
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i64() / v1.as_i64()
-                    );
-                }
+                    Op::div_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_u64() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_u64() / v1.as_u64()
+                        );
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i64() % v1.as_i64()
-                    );
-                }
+                    Op::mod_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_u64() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_u64() % v1.as_u64()
+                        );
+                    }
 
-                Op::eq_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() == v1.as_u64());
-                }
+                    Op::div_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_i64() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_i64() / v1.as_i64()
+                        );
+                    }
 
-                Op::ne_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() != v1.as_u64());
-                }
+                    Op::mod_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        if v1.as_i64() == 0 {
+                            trap!(TrapKind::DivByZero, op_pc);
+                        }
+                        self.push(
+                            v0.as_i64() % v1.as_i64()
+                        );
+                    }
 
-                Op::lt_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() < v1.as_u64());
-                }
+                    Op::eq_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() == v1.as_u64());
+                    }
 
-                Op::le_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() <= v1.as_u64());
-                }
+                    Op::ne_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() != v1.as_u64());
+                    }
 
-                Op::gt_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() > v1.as_u64());
-                }
+                    Op::lt_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() < v1.as_u64());
+                    }
 
-                Op::ge_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() >= v1.as_u64());
-                }
+                    Op::le_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() <= v1.as_u64());
+                    }
 
-                Op::lt_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() < v1.as_i64());
-                }
+                    Op::gt_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() > v1.as_u64());
+                    }
 
-                Op::le_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() <= v1.as_i64());
-                }
+                    Op::ge_u64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_u64() >= v1.as_u64());
+                    }
 
-                Op::gt_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() > v1.as_i64());
-                }
+                    Op::lt_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i64() < v1.as_i64());
+                    }
 
-                Op::ge_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() >= v1.as_i64());
-                }
+                    Op::le_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i64() <= v1.as_i64());
+                    }
 
-                Op::sx_i8_i32 => {
-                    let v = self.pop();
-                    self.push(v.as_i8() as i32);
-                }
+                    Op::gt_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i64() > v1.as_i64());
+                    }
 
-                Op::sx_i8_i64 => {
-                    let v = self.pop();
-                    self.push(v.as_i8() as i64);
-                }
+                    Op::ge_i64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_i64() >= v1.as_i64());
+                    }
 
-                Op::sx_i16_i32 => {
-                    let v = self.pop();
-                    self.push(v.as_i16() as i32);
-                }
+                    Op::sx_i8_i32 => {
+                        let v = self.pop();
+                        self.push(v.as_i8() as i32);
+                    }
 
-                Op::sx_i16_i64 => {
-                    let v = self.pop();
-                    self.push(v.as_i16() as i64);
-                }
+                    Op::sx_i8_i64 => {
+                        let v = self.pop();
+                        self.push(v.as_i8() as i64);
+                    }
 
-                Op::sx_i32_i64 => {
-                    let v = self.pop();
-                    self.push(v.as_i32() as i64);
-                }
+                    Op::sx_i16_i32 => {
+                        let v = self.pop();
+                        self.push(v.as_i16() as i32);
+                    }
 
-                Op::trunc_u8 => {
-                    let v = self.pop();
-                    self.push(v.as_u8());
-                }
+                    Op::sx_i16_i64 => {
+                        let v = self.pop();
+                        self.push(v.as_i16() as i64);
+                    }
 
-                Op::trunc_u16 => {
-                    let v = self.pop();
-                    self.push(v.as_u16());
-                }
+                    Op::sx_i32_i64 => {
+                        let v = self.pop();
+                        self.push(v.as_i32() as i64);
+                    }
 
-                Op::trunc_u32 => {
-                    let v = self.pop();
-                    self.push(v.as_u32());
-                }
+                    Op::trunc_u8 => {
+                        let v = self.pop();
+                        self.push(v.as_u8());
+                    }
 
-                Op::add_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() + v1.as_f32());
-                }
+                    Op::trunc_u16 => {
+                        let v = self.pop();
+                        self.push(v.as_u16());
+                    }
 
-                Op::sub_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() - v1.as_f32());
-                }
+                    Op::trunc_u32 => {
+                        let v = self.pop();
+                        self.push(v.as_u32());
+                    }
 
-                Op::mul_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() * v1.as_f32());
-                }
+                    Op::add_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f32(v0.as_f32() + v1.as_f32());
+                    }
 
-                // Should return NaN for invalid inputs
-                Op::div_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() / v1.as_f32());
-                }
+                    Op::sub_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f32(v0.as_f32() - v1.as_f32());
+                    }
 
-                Op::sin_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.sin());
-                }
+                    Op::mul_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f32(v0.as_f32() * v1.as_f32());
+                    }
 
-                Op::cos_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.cos());
-                }
+                    // Should return NaN for invalid inputs
+                    Op::div_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f32(v0.as_f32() / v1.as_f32());
+                    }
 
-                // Should return NaN for invalid inputs
-                Op::tan_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.tan());
-                }
+                    Op::sin_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.sin());
+                    }
 
-                // Should return NaN for invalid inputs
-                Op::asin_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.asin());
-                }
+                    Op::cos_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.cos());
+                    }
 
-                // Should return NaN for invalid inputs
-                Op::acos_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.acos());
-                }
+                    // Should return NaN for invalid inputs
+                    Op::tan_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.tan());
+                    }
 
-                Op::atan_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.atan());
-                }
+                    // Should return NaN for invalid inputs
+                    Op::asin_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.asin());
+                    }
 
-                // Should return NaN for invalid inputs
-                Op::pow_f32 => {
-                    let v1 = self.pop().as_f32();
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.powf(v1));
-                }
+                    // Should return NaN for invalid inputs
+                    Op::acos_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.acos());
+                    }
 
-                // Should return NaN for invalid inputs
-                Op::sqrt_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.sqrt());
-                }
+                    Op::atan_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.atan());
+                    }
 
-                Op::eq_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() == v1.as_f32());
-                }
+                    // Should return NaN for invalid inputs
+                    Op::pow_f32 => {
+                        let v1 = self.pop().as_f32();
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.powf(v1));
+                    }
 
-                Op::ne_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() != v1.as_f32());
-                }
+                    // Should return NaN for invalid inputs
+                    Op::sqrt_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.sqrt());
+                    }
+
+                    Op::floor_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.floor());
+                    }
+
+                    Op::ceil_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.ceil());
+                    }
+
+                    Op::round_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push_f32(v0.round_ties_even());
+                    }
+
+                    Op::eq_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f32() == v1.as_f32());
+                    }
+
+                    Op::ne_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f32() != v1.as_f32());
+                    }
+
+                    Op::lt_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f32() < v1.as_f32());
+                    }
+
+                    Op::le_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f32() <= v1.as_f32());
+                    }
+
+                    Op::gt_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f32() > v1.as_f32());
+                    }
+
+                    Op::ge_f32 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f32() >= v1.as_f32());
+                    }
+
+                    Op::is_nan_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push(v0.is_nan());
+                    }
+
+                    Op::is_inf_f32 => {
+                        let v0 = self.pop().as_f32();
+                        self.push(v0.is_infinite());
+                    }
+
+                    // Follows Rust semantics:
+                    // - Round ties to even
+                    // - Never panics
+                    Op::i32_to_f32 => {
+                        let v = self.pop();
+                        self.push(v.as_i32() as f32);
+                    }
+
+                    // Follows Rust semantics:
+                    // - Round ties to even
+                    // - Never panics
+                    Op::i64_to_f32 => {
+                        let v = self.pop();
+                        self.push(v.as_i64() as f32);
+                    }
+
+                    // Follows Rust semantics:
+                    // - Rounds towards zero (truncates)
+                    // - Saturates to min/max int values
+                    // - NaN converts to zero
+                    // - Never panics
+                    Op::f32_to_i32 => {
+                        let v = self.pop();
+                        self.push(v.as_f32() as i32);
+                    }
+
+                    Op::add_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f64(v0.as_f64() + v1.as_f64());
+                    }
+
+                    Op::sub_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f64(v0.as_f64() - v1.as_f64());
+                    }
+
+                    Op::mul_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f64(v0.as_f64() * v1.as_f64());
+                    }
+
+                    Op::div_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push_f64(v0.as_f64() / v1.as_f64());
+                    }
+
+                    Op::sqrt_f64 => {
+                        let v0 = self.pop().as_f64();
+                        self.push_f64(v0.sqrt());
+                    }
+
+                    Op::floor_f64 => {
+                        let v0 = self.pop().as_f64();
+                        self.push_f64(v0.floor());
+                    }
+
+                    Op::ceil_f64 => {
+                        let v0 = self.pop().as_f64();
+                        self.push_f64(v0.ceil());
+                    }
+
+                    Op::round_f64 => {
+                        let v0 = self.pop().as_f64();
+                        self.push_f64(v0.round_ties_even());
+                    }
+
+                    Op::eq_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f64() == v1.as_f64());
+                    }
+
+                    Op::ne_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f64() != v1.as_f64());
+                    }
+
+                    Op::lt_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f64() < v1.as_f64());
+                    }
+
+                    Op::le_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f64() <= v1.as_f64());
+                    }
+
+                    Op::gt_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f64() > v1.as_f64());
+                    }
+
+                    Op::ge_f64 => {
+                        let v1 = self.pop();
+                        let v0 = self.pop();
+                        self.push(v0.as_f64() >= v1.as_f64());
+                    }
+
+                    Op::is_nan_f64 => {
+                        let v0 = self.pop().as_f64();
+                        self.push(v0.is_nan());
+                    }
+
+                    Op::is_inf_f64 => {
+                        let v0 = self.pop().as_f64();
+                        self.push(v0.is_infinite());
+                    }
+
+                    // Follows Rust semantics:
+                    // - Round ties to even
+                    // - Never panics
+                    Op::i64_to_f64 => {
+                        let v = self.pop();
+                        self.push(v.as_i64() as f64);
+                    }
+
+                    // Follows Rust semantics:
+                    // - Rounds towards zero (truncates)
+                    // - Saturates to min/max int values
+                    // - NaN converts to zero
+                    // - Never panics
+                    Op::f64_to_i64 => {
+                        let v = self.pop();
+                        self.push(v.as_f64() as i64);
+                    }
+
+                    // Exact for all values
+                    Op::f32_to_f64 => {
+                        let v = self.pop();
+                        self.push_f64(v.as_f32() as f64);
+                    }
+
+                    // Rounds ties to even, overflows to infinity
+                    Op::f64_to_f32 => {
+                        let v = self.pop();
+                        self.push_f32(v.as_f64() as f32);
+                    }
+
+                    Op::load_u8 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 1, 1, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 1, pc - 1, false);
+                        let val: u8 = unsafe { *heap_ptr };
+                        self.push(val);
+                    }
 
-                Op::lt_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() < v1.as_f32());
-                }
+                    Op::load_u16 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 2, 2, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 2, pc - 1, false);
+                        let val: u16 = unsafe { *heap_ptr };
+                        self.push(val);
+                    }
 
-                Op::le_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() <= v1.as_f32());
-                }
+                    Op::load_u32 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 4, 4, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 4, pc - 1, false);
+                        let val: u32 = unsafe { *heap_ptr };
+                        self.push(val);
+                    }
 
-                Op::gt_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() > v1.as_f32());
-                }
+                    Op::load_u64 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 8, 8, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 8, pc - 1, false);
+                        let val: u64 = unsafe { *heap_ptr };
+                        self.push(val);
+                    }
 
-                Op::ge_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() >= v1.as_f32());
-                }
+                    Op::store_u8 => {
+                        let val = self.pop().as_u8();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 1, 1, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 1, pc - 1, true);
+                        unsafe { *heap_ptr = val; }
+                    }
 
-                // Follows Rust semantics:
-                // - Round ties to even
-                // - Never panics
-                Op::i32_to_f32 => {
-                    let v = self.pop();
-                    self.push(v.as_i32() as f32);
-                }
+                    Op::store_u16 => {
+                        let val = self.pop().as_u16();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 2, 2, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 2, pc - 1, true);
+                        unsafe { *heap_ptr = val; }
+                    }
 
-                // Follows Rust semantics:
-                // - Round ties to even
-                // - Never panics
-                Op::i64_to_f32 => {
-                    let v = self.pop();
-                    self.push(v.as_i64() as f32);
-                }
+                    Op::store_u32 => {
+                        let val = self.pop().as_u32();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 4, 4, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 4, pc - 1, true);
+                        unsafe { *heap_ptr = val; }
+                    }
 
-                // Follows Rust semantics:
-                // - Rounds towards zero (truncates)
-                // - Saturates to min/max int values
-                // - NaN converts to zero
-                // - Never panics
-                Op::f32_to_i32 => {
-                    let v = self.pop();
-                    self.push(v.as_f32() as i32);
-                }
+                    Op::store_u64 => {
+                        let val = self.pop().as_u64();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 8, 8, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        let heap_ptr = self.get_heap_ptr(addr, 1);
+                        self.asan_check(addr, 8, pc - 1, true);
+                        unsafe { *heap_ptr = val; }
+                    }
 
-                Op::load_u8 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    let val: u8 = unsafe { *heap_ptr };
-                    self.push(val);
-                }
+                    Op::load_unaligned_u16 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 2, 1, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        self.asan_check(addr, 2, pc - 1, false);
+                        let val: u16 = self.load_unaligned(addr);
+                        self.push(val);
+                    }
 
-                Op::load_u16 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    let val: u16 = unsafe { *heap_ptr };
-                    self.push(val);
-                }
+                    Op::load_unaligned_u32 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 4, 1, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        self.asan_check(addr, 4, pc - 1, false);
+                        let val: u32 = self.load_unaligned(addr);
+                        self.push(val);
+                    }
 
-                Op::load_u32 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    let val: u32 = unsafe { *heap_ptr };
-                    self.push(val);
-                }
+                    Op::load_unaligned_u64 => {
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 8, 1, false) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        self.asan_check(addr, 8, pc - 1, false);
+                        let val: u64 = self.load_unaligned(addr);
+                        self.push(val);
+                    }
 
-                Op::load_u64 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    let val: u64 = unsafe { *heap_ptr };
-                    self.push(val);
-                }
+                    Op::store_unaligned_u16 => {
+                        let val = self.pop().as_u16();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 2, 1, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        self.asan_check(addr, 2, pc - 1, true);
+                        self.store_unaligned(addr, val);
+                    }
 
-                Op::store_u8 => {
-                    let val = self.pop().as_u8();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    unsafe { *heap_ptr = val; }
-                }
+                    Op::store_unaligned_u32 => {
+                        let val = self.pop().as_u32();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 4, 1, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        self.asan_check(addr, 4, pc - 1, true);
+                        self.store_unaligned(addr, val);
+                    }
 
-                Op::store_u16 => {
-                    let val = self.pop().as_u16();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    unsafe { *heap_ptr = val; }
-                }
+                    Op::store_unaligned_u64 => {
+                        let val = self.pop().as_u64();
+                        let addr = self.pop().as_usize();
+                        if self.checked_memory {
+                            if let Some(kind) = self.check_access(addr, 8, 1, true) {
+                                trap!(kind, op_pc);
+                            }
+                        }
+                        self.asan_check(addr, 8, pc - 1, true);
+                        self.store_unaligned(addr, val);
+                    }
 
-                Op::store_u32 => {
-                    let val = self.pop().as_u32();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    unsafe { *heap_ptr = val; }
-                }
+                    Op::jmp => {
+                        self.profile_jump(pc, uop.imm as usize);
+                        pc = uop.imm as usize;
+                    }
 
-                Op::store_u64 => {
-                    let val = self.pop().as_u64();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr, 1);
-                    unsafe { *heap_ptr = val; }
-                }
+                    Op::jz => {
+                        let v0 = self.pop();
 
-                Op::jmp => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    pc = ((pc as isize) + offset) as usize;
-                }
+                        if v0.as_i64() == 0 {
+                            self.profile_jump(pc, uop.imm as usize);
+                            pc = uop.imm as usize;
+                        }
+                    }
 
-                Op::jz => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    let v0 = self.pop();
+                    Op::jnz => {
+                        let v0 = self.pop();
 
-                    if v0.as_i64() == 0 {
-                        pc = ((pc as isize) + offset) as usize;
+                        if v0.as_i64() != 0 {
+                            self.profile_jump(pc, uop.imm as usize);
+                            pc = uop.imm as usize;
+                        }
                     }
-                }
-
-                Op::jnz => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    let v0 = self.pop();
 
-                    if v0.as_i64() != 0 {
-                        pc = ((pc as isize) + offset) as usize;
+                    Op::jmp_table => {
+                        let idx = self.pop().as_u64();
+                        let target = jmp_table_target(&self.code, &uop, idx);
+                        self.profile_jump(pc, target);
+                        pc = target;
                     }
-                }
 
-                // call <num_args:u8> <offset:i32> (arg0, arg1, ..., argN)
-                Op::call => {
-                    // Offset of the function to call
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
+                    // call <num_args:u8> <offset:i32> (arg0, arg1, ..., argN)
+                    Op::call => {
+                        // Argument count
+                        let num_args = uop.idx as usize;
+                        self.check_call_args(bp, num_args);
+
+                        if self.frames.len() >= MAX_CALL_DEPTH {
+                            trap!(TrapKind::StackOverflow, op_pc);
+                        }
 
-                    // Argument count
-                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
-                    assert!(num_args <= self.stack.len() - bp);
+                        self.frames.push(StackFrame {
+                            prev_bp: bp,
+                            ret_addr: pc,
+                            fn_pc: uop.imm as usize,
+                            argc: num_args,
+                            num_locals: None,
+                        });
 
-                    self.frames.push(StackFrame {
-                        prev_bp: bp,
-                        ret_addr: pc,
-                        argc: num_args,
-                    });
+                        // The base pointer will point at the first local
+                        bp = self.stack.len();
+                        pc = uop.imm as usize;
 
-                    // The base pointer will point at the first local
-                    bp = self.stack.len();
-                    pc = ((pc as isize) + offset) as usize;
-                }
+                        if let Some(profiler) = &mut self.profiler {
+                            profiler.count_call(pc);
+                        }
+                    }
 
-                // call <num_args:u8> (arg0, arg1, ..., argN, f_ptr)
-                Op::call_fp => {
-                    // Absolute address of the function to call
-                    let fp = self.pop();
+                    // call <num_args:u8> (arg0, arg1, ..., argN, f_ptr)
+                    Op::call_fp => {
+                        // Absolute address of the function to call
+                        let fp = self.pop();
 
-                    // Argument count
-                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
-                    assert!(num_args <= self.stack.len() - bp);
+                        // Argument count
+                        let num_args = uop.idx as usize;
+                        self.check_call_args(bp, num_args);
 
-                    self.frames.push(StackFrame {
-                        prev_bp: bp,
-                        ret_addr: pc,
-                        argc: num_args,
-                    });
+                        if self.frames.len() >= MAX_CALL_DEPTH {
+                            trap!(TrapKind::StackOverflow, op_pc);
+                        }
 
-                    // The base pointer will point at the first local
-                    bp = self.stack.len();
-                    pc = fp.as_usize();
-                }
+                        self.frames.push(StackFrame {
+                            prev_bp: bp,
+                            ret_addr: pc,
+                            fn_pc: fp.as_usize(),
+                            argc: num_args,
+                            num_locals: None,
+                        });
 
-                Op::syscall => {
-                    let syscall_idx = self.code.read_pc::<u16>(&mut pc);
-                    let syscall_fn = self.sys_state.get_syscall(syscall_idx);
+                        // The base pointer will point at the first local
+                        bp = self.stack.len();
+                        pc = fp.as_usize();
 
-                    match syscall_fn
-                    {
-                        SysCallFn::Fn0_0(fun) => {
-                            fun(self)
+                        if let Some(profiler) = &mut self.profiler {
+                            profiler.count_call(pc);
                         }
+                    }
 
-                        SysCallFn::Fn0_1(fun) => {
-                            let v = fun(self);
-                            self.push(v);
+                    Op::syscall => {
+                        let syscall_idx = uop.imm as u16;
+                        let syscall_fn = match self.sys_state.find_syscall(syscall_idx) {
+                            Some(syscall_fn) => syscall_fn,
+                            None => trap!(TrapKind::InvalidSyscall { idx: syscall_idx }, op_pc),
+                        };
+
+                        // Only read the clock when syscall stats are being collected
+                        let start_time = if self.sys_state.syscall_stats_enabled() {
+                            Some(Instant::now())
+                        } else {
+                            None
+                        };
+
+                        match syscall_fn
+                        {
+                            SysCallFn::Fn0_0(fun) => {
+                                fun(self)
+                            }
+
+                            SysCallFn::Fn0_1(fun) => {
+                                let v = fun(self);
+                                self.push(v);
+                            }
+
+                            SysCallFn::Fn1_0(fun) => {
+                                let a0 = self.pop();
+                                fun(self, a0)
+                            }
+
+                            SysCallFn::Fn1_1(fun) => {
+                                let a0 = self.pop();
+                                let v = fun(self, a0);
+                                self.push(v);
+                            }
+
+                            SysCallFn::Fn2_0(fun) => {
+                                let a1 = self.pop();
+                                let a0 = self.pop();
+                                fun(self, a0, a1)
+                            }
+
+                            SysCallFn::Fn2_1(fun) => {
+                                let a1 = self.pop();
+                                let a0 = self.pop();
+                                let v = fun(self, a0, a1);
+                                self.push(v);
+                            }
+
+                            SysCallFn::Fn3_0(fun) => {
+                                let a2 = self.pop();
+                                let a1 = self.pop();
+                                let a0 = self.pop();
+                                fun(self, a0, a1, a2)
+                            }
+
+                            SysCallFn::Fn3_1(fun) => {
+                                let a2 = self.pop();
+                                let a1 = self.pop();
+                                let a0 = self.pop();
+                                let v = fun(self, a0, a1, a2);
+                                self.push(v);
+                            }
+
+                            SysCallFn::Fn4_0(fun) => {
+                                let a3 = self.pop();
+                                let a2 = self.pop();
+                                let a1 = self.pop();
+                                let a0 = self.pop();
+                                fun(self, a0, a1, a2, a3)
+                            }
+
+                            SysCallFn::Fn4_1(fun) => {
+                                let a3 = self.pop();
+                                let a2 = self.pop();
+                                let a1 = self.pop();
+                                let a0 = self.pop();
+                                let v = fun(self, a0, a1, a2, a3);
+                                self.push(v);
+                            }
                         }
 
-                        SysCallFn::Fn1_0(fun) => {
-                            let a0 = self.pop();
-                            fun(self, a0)
+                        if let Some(start_time) = start_time {
+                            self.sys_state.record_syscall(syscall_idx, start_time.elapsed());
                         }
 
-                        SysCallFn::Fn1_1(fun) => {
-                            let a0 = self.pop();
-                            let v = fun(self, a0);
-                            self.push(v);
+                        if self.syscall_deferred {
+                            if syscall_fn.has_ret() {
+                                self.pop();
+                            }
+                            self.syscall_deferred = false;
+                            self.suspended_at = Some((pc, bp));
+                            return ExitReason::SyscallPending(syscall_idx);
                         }
 
-                        SysCallFn::Fn2_0(fun) => {
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1)
+                        if self.yield_requested {
+                            self.yield_requested = false;
+                            self.suspended_at = Some((pc, bp));
+                            return ExitReason::Yield;
                         }
 
-                        SysCallFn::Fn2_1(fun) => {
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1);
-                            self.push(v);
+                        // Wait for a thread, a mutex, a condition variable
+                        // or a pipe. A thread being joined pushes its return
+                        // value once it finishes, and a pipe read or write
+                        // pushes the number of bytes once it completes.
+                        if let Some(wait_for) = self.wait_requested.take() {
+                            if syscall_fn.has_ret() {
+                                self.pop();
+                            }
+
+                            if !self.threads.has_runnable() {
+                                panic!("deadlock, thread {} waits for {} and all other threads are waiting", self.threads.cur_id, wait_for);
+                            }
+
+                            self.save_thread(pc, bp, Some(wait_for));
+                            match self.next_thread() {
+                                Some((next_pc, next_bp)) => (pc, bp) = (next_pc, next_bp),
+                                None => return ExitReason::Return(Value::from(0)),
+                            }
+                            break;
                         }
+                    }
 
-                        SysCallFn::Fn3_0(fun) => {
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1, a2)
+                    Op::exit => {
+                        if self.stack.len() <= bp {
+                            panic!("exit with no return value on stack");
                         }
 
-                        SysCallFn::Fn3_1(fun) => {
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1, a2);
-                            self.push(v);
+                        let val = self.pop();
+                        self.clear_threads();
+                        self.stack.clear();
+                        self.frames.clear();
+                        return ExitReason::Exit(val);
+                    }
+
+                    Op::ret => {
+                        // The return value must be above the local variables
+                        let num_locals = self.frames[self.frames.len() - 1].num_locals.unwrap_or(0);
+                        if self.stack.len() <= bp + num_locals {
+                            panic!("ret with no return value on stack");
                         }
 
-                        SysCallFn::Fn4_0(fun) => {
-                            let a3 = self.pop();
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1, a2, a3)
+                        let ret_val = self.pop();
+
+                        // A thread other than the main thread
+                        // finished, continue with the next one
+                        if self.frames.len() == 1 && self.threads.cur_id != 0 {
+                            self.finish_thread(ret_val);
+                            match self.next_thread() {
+                                Some((next_pc, next_bp)) => (pc, bp) = (next_pc, next_bp),
+                                None => return ExitReason::Return(Value::from(0)),
+                            }
+                            break;
                         }
 
-                        SysCallFn::Fn4_1(fun) => {
-                            let a3 = self.pop();
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1, a2, a3);
-                            self.push(v);
+                        // If this is a top-level return
+                        if self.frames.len() == 1 {
+                            self.stack.clear();
+                            self.frames.clear();
+                            return ExitReason::Return(ret_val);
                         }
-                    }
-                }
 
-                Op::exit => {
-                    if self.stack.len() <= bp {
-                        panic!("exit with no return value on stack");
-                    }
+                        assert!(self.frames.len() > 0);
+                        let top_frame = self.frames.pop().unwrap();
 
-                    let val = self.pop();
-                    self.stack.clear();
-                    self.frames.clear();
-                    return ExitReason::Exit(val);
-                }
+                        // Execution can't continue after an error
+                        if top_frame.ret_addr == TRAP_HANDLER_RET {
+                            let trap = self.active_trap.unwrap();
+                            return self.stop_trap(trap, top_frame.prev_bp);
+                        }
 
-                Op::ret => {
-                    if self.stack.len() <= bp {
-                        panic!("ret with no return value on stack");
-                    }
+                        // Pop all local variables and arguments
+                        // We pop arguments in the callee so we can support tail calls
+                        assert!(self.stack.len() >= bp - top_frame.argc);
+                        self.stack.truncate(bp - top_frame.argc);
 
-                    let ret_val = self.pop();
+                        pc = top_frame.ret_addr;
+                        bp = top_frame.prev_bp;
 
-                    // If this is a top-level return
-                    if self.frames.len() == 1 {
-                        self.stack.clear();
-                        self.frames.clear();
-                        return ExitReason::Return(ret_val);
+                        self.push(ret_val);
                     }
 
-                    assert!(self.frames.len() > 0);
-                    let top_frame = self.frames.pop().unwrap();
-
-                    // Pop all local variables and arguments
-                    // We pop arguments in the callee so we can support tail calls
-                    assert!(self.stack.len() >= bp - top_frame.argc);
-                    self.stack.truncate(bp - top_frame.argc);
-
-                    pc = top_frame.ret_addr;
-                    bp = top_frame.prev_bp;
-
-                    self.push(ret_val);
+                    _ => panic!("unknown opcode {:?}", uop.op),
                 }
-
-                _ => panic!("unknown opcode {:?}", op),
             }
         }
     }
@@ -1596,6 +3678,7 @@ mod tests
         {
             ExitReason::Exit(value) => value,
             ExitReason::Return(value) => value,
+            _ => panic!("execution was suspended"),
         }
     }
 
@@ -1613,7 +3696,7 @@ mod tests
 
         // Keep track of how many short opcodes we have so far
         dbg!(Op::exit as usize);
-        assert!(Op::exit as usize <= 113);
+        assert!(Op::exit as usize <= 148);
     }
 
     #[test]
@@ -1659,6 +3742,69 @@ mod tests
     fn test_floats()
     {
         eval_i64("push_f32 1.5; push_f32 2.5; add_f32; push_f32 4.0; eq_u64; exit;", 1);
+
+        // Rounding
+        eval_i64("push_f32 -1.5; floor_f32; push_f32 -2.0; eq_f32; exit;", 1);
+        eval_i64("push_f32 1.25; ceil_f32; push_f32 2.0; eq_f32; exit;", 1);
+        eval_i64("push_f32 2.5; round_f32; push_f32 2.0; eq_f32; exit;", 1);
+        eval_i64("push_f32 3.5; round_f32; push_f32 4.0; eq_f32; exit;", 1);
+
+        // Classification
+        eval_i64("push_f32 0.0; push_f32 0.0; div_f32; is_nan_f32; exit;", 1);
+        eval_i64("push_f32 1.0; push_f32 0.0; div_f32; is_nan_f32; exit;", 0);
+        eval_i64("push_f32 1.0; push_f32 0.0; div_f32; is_inf_f32; exit;", 1);
+        eval_i64("push_f32 1.0; is_inf_f32; exit;", 0);
+    }
+
+    #[test]
+    fn test_floats_f64()
+    {
+        eval_i64("push_f64 1.5; push_f64 2.25; add_f64; push_f64 3.75; eq_f64; exit;", 1);
+        eval_i64("push_f64 1.0; push_f64 3.0; div_f64; push_f64 0.3333333333333333; eq_f64; exit;", 1);
+        eval_i64("push_f64 2.0; sqrt_f64; push_f64 1.4142135623730951; eq_f64; exit;", 1);
+        eval_i64("push_f64 -1.5; push_f64 1.0; lt_f64; exit;", 1);
+        eval_i64("push_f64 -2.5; round_f64; f64_to_i64; exit;", -2);
+        eval_i64("push_f64 -2.5; floor_f64; f64_to_i64; exit;", -3);
+
+        // Conversions
+        eval_i64("push -7; i64_to_f64; push_f64 -7.0; eq_f64; exit;", 1);
+        eval_i64("push_f64 1e300; f64_to_i64; push 9223372036854775807; eq_u64; exit;", 1);
+        eval_i64("push_f32 0.1; f32_to_f64; f64_to_f32; push_f32 0.1; eq_f32; exit;", 1);
+        eval_i64("push_f64 1e300; f64_to_f32; is_inf_f32; exit;", 1);
+
+        // Data directive
+        eval_i64(".data; X: .f64 2.5; .code; push X; load_u64; push_f64 2.5; eq_f64; exit;", 1);
+
+        // Classification
+        eval_i64("push_f64 0.0; push_f64 0.0; div_f64; is_nan_f64; exit;", 1);
+        eval_i64("push_f64 -1.0; push_f64 0.0; div_f64; is_inf_f64; exit;", 1);
+    }
+
+    #[test]
+    fn test_canonical_nans()
+    {
+        let mut vm = Assembler::new().parse_str("
+            push_f32 0.0; push_f32 0.0; div_f32;
+            push_f32 -1.0; sqrt_f32;
+            push_f32 1.0; add_f32;
+            exit;
+        ").unwrap();
+        vm.enable_deterministic_floats();
+
+        match vm.call(0, &[]) {
+            ExitReason::Exit(val) => assert_eq!(val.as_u32(), 0x7fc0_0000),
+            _ => panic!()
+        }
+
+        let mut vm = Assembler::new().parse_str("
+            push_f64 0.0; push_f64 0.0; div_f64; push_f64 1.0; mul_f64; exit;
+        ").unwrap();
+        vm.enable_deterministic_floats();
+
+        match vm.call(0, &[]) {
+            ExitReason::Exit(val) => assert_eq!(val.as_u64(), 0x7ff8_0000_0000_0000),
+            _ => panic!()
+        }
     }
 
     #[test]
@@ -1668,6 +3814,32 @@ mod tests
         eval_i64("push_i8 0; LOOP: push_i8 1; add_u64; dup; push_i8 10; eq_u64; jz LOOP; exit;", 10);
     }
 
+    #[test]
+    fn test_jmp_table()
+    {
+        let src = "
+            push {}; jmp_table [A, B, C], D;
+            A: push 10; exit;
+            B: push 11; exit;
+            C: push 12; exit;
+            D: push 13; exit;
+        ";
+        eval_i64(&src.replace("{}", "0"), 10);
+        eval_i64(&src.replace("{}", "2"), 12);
+        eval_i64(&src.replace("{}", "3"), 13);
+        eval_i64(&src.replace("{}", "-1"), 13);
+
+        // Dispatch loop with forward and backward targets
+        eval_i64("
+            push 0; push 0;
+            LOOP: dup; jmp_table [INC, INC, DBL], DONE;
+            INC: swap; push 1; add_u64; swap; push 1; add_u64; jmp LOOP;
+            DBL: swap; push 2; mul_u64; swap; push 1; add_u64; jmp LOOP;
+            DONE: pop; exit;
+        ", 4);
+        eval_i64("push 0; jmp_table [], E; E: push 5; exit;", 5);
+    }
+
     #[test]
     fn test_load_store()
     {
@@ -1714,10 +3886,206 @@ mod tests
     }
 
     #[test]
-    #[should_panic]
+    fn test_audio_mix()
+    {
+        let src = ".data; A: .i16 1000; .i16 -30000; B: .i16 500; .i16 -30000; .code; \
+            push A; push B; push 2; push_f32 2.0; syscall audio_mix;";
+
+        // Mixed values, the second one saturates
+        eval_i64(&format!("{} push A; load_u16; sx_i16_i64; exit;", src), 2000);
+        eval_i64(&format!("{} push 2; load_u16; sx_i16_i64; exit;", src), -32768);
+    }
+
+    #[test]
+    fn test_audio_resample()
+    {
+        // Upsampling [0, 100] to 3 samples interpolates the middle one
+        let src = ".data; A: .i16 0; .i16 100; B: .zero 6; .code; \
+            push B; push 3; push A; push 2; syscall audio_resample;";
+
+        eval_i64(&format!("{} push B; push 2; add_u64; load_u16; exit;", src), 50);
+        eval_i64(&format!("{} push B; push 4; add_u64; load_u16; exit;", src), 100);
+    }
+
+    #[test]
+    fn test_proc_spawn_denied()
+    {
+        // Spawning processes is not allowed without --allow-exec
+        eval_i64(".data; C: .stringz \"true\"; B: .zero 8; .code; push C; push B; push 8; push 0; syscall proc_spawn; exit;", 0);
+    }
+
+    #[test]
+    fn test_str_utf8()
+    {
+        let src = ".data; S: .stringz \"straße\"; D: .zero 16; .code;";
+
+        eval_i64(&format!("{} push S; push 7; syscall str_validate_utf8; exit;", src), 1);
+        eval_i64(&format!("{} push S; push 5; syscall str_validate_utf8; exit;", src), 0);
+        eval_i64(&format!("{} push S; push 7; syscall str_char_count; exit;", src), 6);
+        eval_i64(&format!("{} push S; push 5; syscall str_char_count; exit;", src), 5);
+
+        // The uppercase form of ß is SS
+        eval_i64(&format!("{} push D; push 16; push S; push 7; syscall str_to_upper; exit;", src), 7);
+        eval_i64(&format!("{} push D; push 16; push S; push 7; syscall str_to_upper; pop; push D; push 6; add_u64; load_u8; exit;", src), 'E' as i64);
+        eval_i64(&format!("{} push D; push 6; push S; push 7; syscall str_to_upper; exit;", src), -ENOSPC);
+        eval_i64(&format!("{} push D; push 16; push S; push 5; syscall str_to_lower; exit;", src), -EINVAL);
+    }
+
+    #[test]
+    fn test_atexit()
+    {
+        // The callbacks should run in reverse order, so the last value stored is 3
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(
+            ".data; .zero 8; .code; push CB3; syscall proc_atexit; push CB5; syscall proc_atexit; push 0; exit; \
+            CB3: push 0; push 3; store_u64; push 0; ret; \
+            CB5: push 0; push 5; store_u64; push 0; ret;"
+        ).unwrap();
+
+        vm.call(0, &[]);
+        crate::sys::proc::run_atexit_cbs(&mut vm);
+
+        let val: u64 = unsafe { *vm.get_heap_ptr(0, 1) };
+        assert_eq!(val, 3);
+    }
+
+    #[test]
     fn test_div_zero()
     {
-        eval_src("push 8; push 0; div_u64; exit;");
+        let trap = |src| match Assembler::new().parse_str(src).unwrap().call(0, &[]) {
+            ExitReason::Trap(trap) => trap,
+            _ => panic!("expected a trap"),
+        };
+
+        assert_eq!(trap("push 8; push 0; div_u64; exit;"), Trap { kind: TrapKind::DivByZero, pc: 3 });
+        assert_eq!(trap("push 8; push 0; mod_i32; exit;").kind, TrapKind::DivByZero);
+
+        // Only the low 32 bits are used by 32-bit ops
+        assert_eq!(trap("push 8; push 0x100000000; div_u32; exit;").kind, TrapKind::DivByZero);
+    }
+
+    #[test]
+    fn test_trap_handler()
+    {
+        // The handler gets the trap code, pc and address, and exits
+        let src = "
+            .data; .zero 16; .code;
+            push HANDLER; syscall vm_set_trap_handler;
+            push 2; call LOAD, 1;
+            push 0; exit;
+            LOAD: get_arg 0; load_u64; ret;
+            HANDLER: get_arg 0; push 100; mul_u64; get_arg 2; add_u64; exit;
+        ";
+        assert_eq!(Assembler::new().parse_str(src).unwrap().call(0, &[]), ExitReason::Exit(Value::from(302)));
+
+        // The stack is still there when the handler runs
+        let src = "
+            push HANDLER; syscall vm_set_trap_handler;
+            push 7; call FN, 0; exit;
+            FN: push 1; push 0; div_u64; ret;
+            HANDLER: get_arg 0; push 100; mul_u64; getn 4; add_u64; exit;
+        ";
+        assert_eq!(Assembler::new().parse_str(src).unwrap().call(0, &[]), ExitReason::Exit(Value::from(107)));
+
+        // If the handler returns, the original trap is reported
+        let src = "
+            push HANDLER; syscall vm_set_trap_handler;
+            push 1; push 0; div_u64; exit;
+            HANDLER: push 0; ret;
+        ";
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Trap(Trap { kind: TrapKind::DivByZero, .. })));
+        assert_eq!(vm.stack_size(), 0);
+
+        // A trap in the handler stops execution
+        let src = "
+            push HANDLER; syscall vm_set_trap_handler;
+            push 1; push 0; div_u64; exit;
+            HANDLER: push 1; push 0; mod_u64; ret;
+        ";
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Trap(Trap { kind: TrapKind::DivByZero, .. })));
+
+        // The handler runs again on the next call
+        assert!(matches!(vm.call(0, &[]), ExitReason::Trap(Trap { kind: TrapKind::DivByZero, .. })));
+    }
+
+    #[test]
+    fn test_trap_backtrace()
+    {
+        let mut vm = Assembler::new().parse_str("
+            call MAIN, 0; exit;
+            MAIN: push 1; call HELPER, 1; ret;
+            HELPER: get_arg 0; push 0; div_u64; ret;
+        ").unwrap();
+
+        assert!(matches!(vm.call(0, &[]), ExitReason::Trap(_)));
+        assert_eq!(vm.trap_backtrace(), "  #0 pc 18 (HELPER+3)\n  #1 pc 14 (MAIN+7)\n  #2 pc 6\n");
+        assert!(vm.take_minidump().is_none());
+    }
+
+    #[test]
+    fn test_minidump()
+    {
+        let mut vm = Assembler::new().parse_str("
+            .data;
+            BUF: .fill 16, 7;
+            .code;
+            push 5; call LOAD, 0; exit;
+            LOAD: push 1; push BUF; push 3; add_u64; load_u32; ret;
+        ").unwrap();
+        vm.set_checked_memory(true);
+        vm.enable_minidumps();
+
+        assert!(matches!(vm.call(0, &[]), ExitReason::Trap(_)));
+        let dump = vm.take_minidump().unwrap();
+        assert_eq!(dump.trap, "unaligned access of 4 bytes at address 0x3, pc 18");
+        assert_eq!(dump.insn, "load_u32");
+        assert_eq!((dump.pc, dump.bp), (18, 1));
+        assert_eq!(dump.stack, [5, 1]);
+        assert_eq!(dump.frames.len(), 2);
+        assert_eq!(dump.frames[0].fn_name.as_deref(), Some("LOAD"));
+        assert_eq!(dump.frames[1].pc, 8);
+        assert_eq!(dump.code[dump.pc - dump.code_start], Op::load_u32 as u8);
+        assert_eq!((dump.heap_start, &dump.heap[..4]), (0, [7, 7, 7, 7].as_slice()));
+        assert!(vm.take_minidump().is_none());
+    }
+
+    #[test]
+    fn test_restricted()
+    {
+        use crate::verify::OpRestrictions;
+
+        let mut restrictions = OpRestrictions::default();
+        restrictions.deny_name("float").unwrap();
+
+        // H is never referred to, so the verifier doesn't check it,
+        // but calling G+2 reaches it
+        let src = "push G; push 2; add_u64; call_fp 0; exit; G: push 0; ret; H: push 1; ret;";
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert_eq!(vm.call(0, &[]), ExitReason::Exit(Value::from(1)));
+
+        let mut vm = Assembler::new().restricted(restrictions.clone()).parse_str(src).unwrap();
+        assert!(vm.is_restricted());
+        assert_eq!(vm.call(0, &[]), ExitReason::Trap(Trap { kind: TrapKind::UnverifiedCode, pc: 12 }));
+        assert_eq!(vm.call(10, &[]), ExitReason::Return(Value::from(0)));
+
+        let err = Assembler::new().restricted(restrictions).parse_str("push 0; i64_to_f64; exit;").err().unwrap();
+        assert!(err.to_string().contains("i64_to_f64 is not allowed"), "{}", err);
+    }
+
+    #[test]
+    fn test_stack_overflow()
+    {
+        let mut vm = Assembler::new().parse_str("call FN, 0; exit; FN: call FN, 0; ret;").unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Trap(Trap { kind: TrapKind::StackOverflow, .. })));
+    }
+
+    #[test]
+    fn test_invalid_syscall()
+    {
+        let mut vm = Assembler::new().parse_str("syscall 1000; push 0; exit;").unwrap();
+        assert_eq!(vm.call(0, &[]), ExitReason::Trap(Trap { kind: TrapKind::InvalidSyscall { idx: 1000 }, pc: 0 }));
     }
 
     #[test]
@@ -1734,6 +4102,290 @@ mod tests
         eval_src("call FN, 0; exit; FN: get_arg 0; push 0; ret;");
     }
 
+    #[test]
+    fn test_unaligned()
+    {
+        eval_i64(".data; .zero 16; .code; push 1; push 0x1234; store_unaligned_u16; push 1; load_unaligned_u16; exit;", 0x1234);
+        eval_i64(".data; .zero 16; .code; push 3; push 0x12345678; store_unaligned_u32; push 3; load_unaligned_u32; exit;", 0x12345678);
+        eval_i64(".data; .zero 16; .code; push 5; push -7; store_unaligned_u64; push 5; load_unaligned_u64; exit;", -7);
+
+        // Little-endian, so the low byte is stored first
+        eval_i64(".data; .zero 16; .code; push 1; push 0x1234; store_unaligned_u16; push 1; load_u8; exit;", 0x34);
+    }
+
+    #[test]
+    #[should_panic(expected = "unaligned address 0x3")]
+    fn test_unaligned_trap()
+    {
+        eval_src(".data; .zero 16; .code; push 3; load_u32; exit;");
+    }
+
+    #[test]
+    #[should_panic(expected = "past end of heap")]
+    fn test_unaligned_past_end()
+    {
+        eval_src(".data; .zero 16; .code; syscall vm_heap_size; push 1; sub_u64; load_unaligned_u16; exit;");
+    }
+
+    #[test]
+    #[should_panic(expected = "past end of heap")]
+    fn test_load_addr_overflow()
+    {
+        eval_src("push -8; load_u64; exit;");
+    }
+
+    #[test]
+    fn test_checked_memory()
+    {
+        let mut vm = Assembler::new().parse_str("
+            .data;
+            .zero 16;
+            .code;
+            get_arg 0;
+            load_u32;
+            ret;
+        ").unwrap();
+        vm.set_checked_memory(true);
+
+        let heap_size = vm.heap_size();
+        let trap = |kind, pc| ExitReason::Trap(Trap { kind, pc });
+        assert_eq!(vm.call(0, &[Value::from(4)]), ExitReason::Return(Value::from(0)));
+        assert_eq!(vm.call(0, &[Value::from(2)]), trap(TrapKind::Unaligned { addr: 2, size: 4 }, 2));
+        assert_eq!(vm.call(0, &[Value::from(heap_size)]), trap(TrapKind::OutOfBounds { addr: heap_size, size: 4 }, 2));
+        assert_eq!(vm.call(0, &[Value::from(-4_i64)]), trap(TrapKind::OutOfBounds { addr: usize::MAX - 3, size: 4 }, 2));
+
+        // The VM can be used again after a trap
+        assert_eq!(vm.stack_size(), 0);
+        assert_eq!(vm.call(0, &[Value::from(8)]), ExitReason::Return(Value::from(0)));
+
+        let mut vm = Assembler::new().parse_str(".data; .zero 16; .code; push 3; push 7; store_unaligned_u64; push 3; load_unaligned_u64; exit;").unwrap();
+        vm.set_checked_memory(true);
+        assert_eq!(vm.call(0, &[]), ExitReason::Exit(Value::from(7)));
+    }
+
+    #[test]
+    fn test_trap_exit_code()
+    {
+        let mut vm = Assembler::new().parse_str(".data; .zero 16; .code; push 1; push 0; store_u16; push 0; exit;").unwrap();
+        vm.set_checked_memory(true);
+        assert_eq!(vm.call_sync(0, &[]), ExitReason::Exit(Value::from(TRAP_EXIT_CODE)));
+    }
+
+    #[test]
+    fn test_enter()
+    {
+        eval_i64("push 3; call FN, 1; exit; FN: enter 2; get_arg 0; set_local 1; get_local 1; ret;", 3);
+        eval_i64("push 1; push 2; call FN, 2; exit; FN: enter 0; get_arg 0; get_arg 1; add_u64; ret;", 3);
+
+        // Functions without enter don't have checked frames
+        eval_i64("call FN, 0; exit; FN: push 5; get_local 0; ret;", 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid index in get_local, idx=1, num_locals=1")]
+    fn test_enter_get_local()
+    {
+        eval_src("call FN, 0; exit; FN: enter 1; push 5; get_local 1; ret;");
+    }
+
+    #[test]
+    #[should_panic(expected = "ret with no return value on stack")]
+    fn test_enter_ret_locals()
+    {
+        eval_src("call FN, 0; exit; FN: enter 2; pop; ret;");
+    }
+
+    #[test]
+    #[should_panic(expected = "call with 1 arguments but only 0 values on the stack")]
+    fn test_enter_call_args()
+    {
+        eval_src("call FN, 0; exit; FN: enter 1; call FN, 1; ret;");
+    }
+
+    #[test]
+    #[should_panic(expected = "enter must be the first instruction")]
+    fn test_enter_not_first()
+    {
+        eval_src("call FN, 0; exit; FN: push 0; enter 1; ret;");
+    }
+
+    #[test]
+    fn test_assert_stack()
+    {
+        // The depth counts the locals, but not the arguments of the function
+        eval_i64(".assert_stack 0; push 1; push 2; .assert_stack 2; call FN, 2; .assert_stack 1; exit; FN: enter 1; .assert_stack 1; get_arg 1; .assert_stack 2; ret;", 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "stack assertion failed, expected 1 values on the stack, found 2, pc 2")]
+    fn test_assert_stack_fails()
+    {
+        eval_src("push 1; push 2; .assert_stack 1; exit;");
+    }
+
+    #[test]
+    #[should_panic(expected = "trap at foo.c@3, pc 5")]
+    fn test_trap()
+    {
+        eval_src(".data; LOC: .stringz \"foo.c@3\"; .code; push LOC; trap; exit;");
+    }
+
+    #[test]
+    fn test_breakpoint()
+    {
+        let mut vm = Assembler::new().parse_str("nop; breakpoint; push 5; exit;").unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Breakpoint(1)));
+        assert!(vm.is_suspended());
+        assert!(matches!(vm.resume(), ExitReason::Exit(val) if val.as_u64() == 5));
+    }
+
+    #[test]
+    #[should_panic(expected = "breakpoint, pc 1")]
+    fn test_breakpoint_sync()
+    {
+        let mut vm = Assembler::new().parse_str("nop; breakpoint; push 0; exit;").unwrap();
+        vm.call_sync(0, &[]);
+    }
+
+    #[test]
+    fn test_yield()
+    {
+        let src = "push 1; syscall vm_yield; push 2; syscall vm_yield; add_u64; exit;";
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Yield));
+        assert!(matches!(vm.resume(), ExitReason::Yield));
+        assert!(matches!(vm.resume(), ExitReason::Exit(val) if val.as_u64() == 3));
+
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert!(matches!(vm.call_sync(0, &[]), ExitReason::Exit(val) if val.as_u64() == 3));
+    }
+
+    #[test]
+    fn test_threads()
+    {
+        // Two threads append their id to a log, one byte per iteration
+        let src = "
+            .data;
+            COUNT: .u64 0;
+            LOG: .zero 6000;
+            .code;
+            push WORKER; push 0; syscall thread_spawn; pop;
+            push WORKER; push 0; syscall thread_spawn; pop;
+            push 1; syscall thread_join;
+            push 2; syscall thread_join; add_u64;
+            exit;
+
+            WORKER:
+            push 0;
+            LOOP:
+            push LOG; push COUNT; load_u64; add_u64; syscall thread_id; store_u8;
+            push COUNT; push COUNT; load_u64; push 1; add_u64; store_u64;
+            push 1; add_u64; dup; push 3000; lt_u64; jnz LOOP;
+            syscall thread_id; ret;
+        ";
+
+        let mut logs = Vec::new();
+        for jit in [false, true] {
+            let mut vm = Assembler::new().parse_str(src).unwrap();
+            if jit {
+                vm.enable_jit();
+            }
+
+            assert_eq!(vm.call_sync(0, &[]), ExitReason::Exit(Value::from(3)));
+            assert!(!vm.has_other_threads());
+            logs.push(vm.mem_view().slice::<u8>(8, 6000).to_vec());
+        }
+
+        // The threads take turns, in the same order with the JIT
+        let log = &logs[0];
+        assert!(log.iter().all(|&id| id == 1 || id == 2));
+        assert_eq!(log.iter().filter(|&&id| id == 1).count(), 3000);
+        assert!(log.windows(2).filter(|ids| ids[0] != ids[1]).count() > 2);
+        assert_eq!(logs[0], logs[1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock")]
+    fn test_thread_deadlock()
+    {
+        // Threads 1 and 2 wait for each other
+        let mut vm = Assembler::new().parse_str("
+            push JOIN; push 2; syscall thread_spawn; pop;
+            push JOIN; push 1; syscall thread_spawn; pop;
+            push 1; syscall thread_join; exit;
+            JOIN: get_arg 0; syscall thread_join; ret;
+        ").unwrap();
+        vm.call_sync(0, &[]);
+    }
+
+    #[test]
+    fn test_syscall_pending()
+    {
+        fn deferred(vm: &mut VM) -> Value
+        {
+            vm.defer_syscall();
+            Value::from(0)
+        }
+
+        let mut vm = Assembler::new().parse_str("push 1; syscall vm_heap_size; add_u64; exit;").unwrap();
+        vm.sys_state.reg_syscall(crate::sys::constants::VM_HEAP_SIZE, SysCallFn::Fn0_1(deferred));
+
+        match vm.call(0, &[]) {
+            ExitReason::SyscallPending(idx) => assert_eq!(idx, crate::sys::constants::VM_HEAP_SIZE),
+            _ => panic!(),
+        }
+
+        // The host provides the result of the syscall
+        assert!(matches!(vm.resume_with(Value::from(41)), ExitReason::Exit(val) if val.as_u64() == 42));
+    }
+
+    #[test]
+    fn test_insn_budget()
+    {
+        // Sum 1 to 100 with a function call, so that execution
+        // gets suspended in both the caller and the callee
+        let src = "
+            push 0; push 100;
+            LOOP:
+            dup; jz DONE;
+            swap; getn 1; call ADD, 2; swap;
+            push 1; sub_u64;
+            jmp LOOP;
+            DONE: pop; exit;
+            ADD: get_arg 0; get_arg 1; add_u64; ret;
+        ";
+
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.set_insn_budget(Some(7));
+        let mut result = vm.call(0, &[]);
+        let mut num_slices = 1;
+
+        while let ExitReason::Suspended = result {
+            assert!(vm.is_suspended());
+            assert_eq!(vm.insn_budget(), Some(0));
+            vm.set_insn_budget(Some(7));
+            result = vm.resume();
+            num_slices += 1;
+        }
+
+        match result {
+            ExitReason::Exit(val) => assert_eq!(val.as_u64(), 5050),
+            _ => panic!(),
+        }
+        assert!(num_slices > 100);
+        assert!(!vm.is_suspended());
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // With a large enough budget, the program runs to completion
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.set_insn_budget(Some(1_000_000));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(_)));
+        vm.set_insn_budget(Some(0));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Suspended));
+        vm.set_insn_budget(None);
+        assert!(matches!(vm.resume(), ExitReason::Exit(_)));
+    }
+
     #[test]
     #[should_panic]
     fn test_load_oob()
@@ -1755,4 +4407,19 @@ mod tests
     {
         eval_src(".data; A: .zero 10; B: .zero 10; .code; push A; push B; push -1; syscall memcpy;");
     }
+
+    // The address plus the length used to wrap around
+    #[test]
+    #[should_panic]
+    fn test_memcmp_wrap()
+    {
+        eval_src(".data; A: .zero 10; .code; push -4; push A; push 8; syscall memcmp; push 0; exit;");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_crc32_oob()
+    {
+        eval_src(".data; A: .zero 10; .code; push A; push 100_000_000; push 0; syscall crypto_crc32; push 0; exit;");
+    }
 }
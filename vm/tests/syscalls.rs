@@ -0,0 +1,1972 @@
+//! Integration tests for the system calls
+//!
+//! Each test runs the uvm binary on a small asm program and checks
+//! its exit code and output. Every syscall should be exercised by at
+//! least one test here, which `all_syscalls_tested` enforces.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Syscalls that can't be exercised here, and why
+const UNTESTED: [(&str, &str); 2] = [
+    ("pad_rumble", "needs a game controller"),
+    ("audio_open_output", "needs an audio device"),
+];
+
+/// Write an asm program to a temporary file
+fn write_asm(src: &str) -> PathBuf
+{
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let idx = COUNTER.fetch_add(1, Ordering::SeqCst);
+
+    let path = std::env::temp_dir().join(format!("uvm_test_{}_{}.asm", std::process::id(), idx));
+    fs::write(&path, src).unwrap();
+    path
+}
+
+/// Run an asm program with some VM options and some input on stdin
+fn run_with(src: &str, opts: &[&str], stdin: &[u8]) -> Output
+{
+    let path = write_asm(src);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .args(opts)
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+/// Run an asm program and return its exit code and stdout
+fn run(src: &str) -> (i32, String)
+{
+    let output = run_with(src, &[], &[]);
+    (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn all_syscalls_tested()
+{
+    let constants = include_str!("../src/sys/constants.rs");
+    let tests = include_str!("syscalls.rs");
+
+    for line in constants.lines() {
+        let name = match line.trim().strip_prefix("Some(SysCallDesc { name: \"") {
+            Some(rest) => rest.split('"').next().unwrap(),
+            None => continue
+        };
+
+        if UNTESTED.iter().any(|(n, _)| *n == name) {
+            continue;
+        }
+
+        assert!(
+            tests.contains(&format!("syscall {};", name)),
+            "syscall {} has no integration test", name
+        );
+    }
+}
+
+#[test]
+fn vm_memory()
+{
+    let (code, _) = run("
+        .data;
+        A: .stringz \"abcdef\";
+        B: .zero 8;
+        .align 4;
+        W: .zero 16;
+        .code;
+        push B; push A; push 7; syscall memcpy;
+        push A; push B; push 7; syscall memcmp;
+        jnz FAIL;
+        push B; push 120; push 3; syscall memset;
+        push A; push B; push 1; syscall memcmp;
+        push 0; ge_i32; jnz FAIL;
+        push W; push 0xAABBCCDD; push 4; syscall memset32;
+        push W; push 12; add_u64; load_u32; push 0xAABBCCDD; ne_u32; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+
+    let (code, out) = run("
+        push 1000000; syscall vm_resize_heap; pop;
+        syscall vm_heap_size; push 1000000; lt_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0, "{}", out);
+}
+
+#[test]
+fn vm_grow_heap()
+{
+    // The heap can't grow past --max-heap, and neither can the allocator
+    let src = "
+        .data;
+        .zero 16;
+        .code;
+        syscall vm_heap_size;
+        push 100; syscall vm_grow_heap; ne_u64; jnz FAIL;
+        syscall vm_heap_size; push 100; lt_u64; jnz FAIL;
+        push 2000000; syscall vm_grow_heap; push -12; ne_u64; jnz FAIL;
+        push 2000000; syscall vm_resize_heap; push -1; ne_u64; jnz FAIL;
+        push 2000000; syscall mem_alloc; jnz FAIL;
+        push 500000; syscall mem_alloc; jz FAIL;
+        syscall vm_heap_size; push 1048576; gt_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+
+    let output = run_with(src, &["--max-heap", "1048576"], &[]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn mem_alloc()
+{
+    // Blocks are allocated after the heap, resized blocks keep
+    // their contents, and freed blocks are merged and reused
+    let (code, out) = run("
+        .data;
+        .zero 16;
+        .code;
+        syscall vm_heap_size;
+        push 100; syscall mem_alloc;
+        dup; push 0; eq_u64; jnz FAIL;
+        dup; getn 2; le_u64; jnz FAIL;
+        dup; push 777; store_u64;
+        dup; push 50000; syscall mem_resize;
+        dup; load_u64; push 777; ne_u64; jnz FAIL;
+        dup; push 49992; add_u64; push 1; store_u64;
+        syscall mem_free;
+        push 50000; syscall mem_alloc; ne_u64; jnz FAIL;
+        push 0; syscall mem_free;
+        push -1; syscall mem_alloc; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0, "{}", out);
+}
+
+#[test]
+fn vm_asan()
+{
+    let src = "
+        .data;
+        .align 8;
+        BUF: .zero 64;
+        .code;
+        push BUF; push 8; add_u64; push 16; syscall vm_track_alloc;
+        jz UNTRACKED;
+        push BUF; push 8; add_u64; push 0; store_u64;
+        push BUF; push 24; add_u64; push 0; store_u8;
+        push 1; exit;
+        UNTRACKED:
+        push BUF; push 8; add_u64; syscall vm_track_free;
+        exit;
+    ";
+
+    // Without --asan, allocations aren't tracked
+    let output = run_with(src, &[], &[]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_with(src, &["--asan"], &[]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("heap-buffer-overflow on write of 1 bytes"), "{}", stderr);
+}
+
+#[test]
+fn vm_detect_leaks()
+{
+    let output = run_with("
+        .data;
+        .align 8;
+        BUF: .zero 64;
+        .code;
+        push BUF; push 8; add_u64; push 16; syscall vm_track_alloc; pop;
+        push BUF; push 32; add_u64; push 4; syscall vm_track_alloc; pop;
+        push BUF; push 32; add_u64; syscall vm_track_free; pop;
+        push 0; exit;
+    ", &["--detect-leaks"], &[]);
+
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("16 bytes leaked in 1 blocks"), "{}", stderr);
+}
+
+#[test]
+fn vm_heap_walk()
+{
+    // Returns the number of live blocks, and checks the entry
+    // for the first one, which the buffer only has room for
+    let src = "
+        .data;
+        .align 8;
+        BUF: .zero 64;
+        ENTRIES: .zero 24;
+        .code;
+        push BUF; push 8; add_u64; push 16; syscall vm_track_alloc; pop;
+        push BUF; push 32; add_u64; push 4; syscall vm_track_alloc; pop;
+        push BUF; push 48; add_u64; push 8; syscall vm_track_alloc; pop;
+        push BUF; push 32; add_u64; syscall vm_track_free; pop;
+        push ENTRIES; push 1; syscall vm_heap_walk;
+        dup; push -1; eq_u64; jnz DONE;
+        push ENTRIES; load_u64; push BUF; push 8; add_u64; ne_u64; jnz FAIL;
+        push ENTRIES; push 8; add_u64; load_u64; push 16; ne_u64; jnz FAIL;
+        push ENTRIES; push 16; add_u64; load_u64; jz FAIL;
+        DONE: exit;
+        FAIL: push 100; exit;
+    ";
+
+    let output = run_with(src, &["--detect-leaks"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+
+    // Without tracking, there is nothing to walk
+    let output = run_with(src, &[], &[]);
+    assert_eq!(output.status.code(), Some(255));
+}
+
+#[test]
+fn vm_backtrace()
+{
+    // The return addresses of the active calls are listed innermost
+    // first, and only as many as fit in the buffer are written
+    let (code, _) = run("
+        .data;
+        .align 8;
+        BUF: .zero 24;
+        .code;
+        call F, 0; R0: exit;
+        F: push G; call_fp 0; R1: ret;
+        G:
+        push BUF; push 1; syscall vm_backtrace; push 2; ne_u64; jnz FAIL;
+        push BUF; push 8; add_u64; load_u64; jnz FAIL;
+        push BUF; load_u64; push R1; ne_u64; jnz FAIL;
+        push BUF; push 3; syscall vm_backtrace; pop;
+        push BUF; push 8; add_u64; load_u64; push R0; ne_u64; jnz FAIL;
+        push 0; ret;
+        FAIL: push 1; ret;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn vm_yield()
+{
+    // The command-line host continues right away after a yield,
+    // including in callbacks
+    let (code, out) = run("
+        .data;
+        STR: .stringz \"before\";
+        .code;
+        push STR; syscall print_str;
+        syscall vm_yield;
+        push 1; push CB; syscall time_delay_cb;
+        push 0; ret;
+        CB:
+        call F, 0;
+        push 7; exit;
+        F: syscall vm_yield; push 0; syscall print_i64; push 0; ret;
+    ");
+    assert_eq!(code, 7);
+    assert_eq!(out, "before0");
+}
+
+#[test]
+fn vm_trap_handler()
+{
+    // The handler is called with the trap code and faulting pc,
+    // which is also the first entry of the backtrace
+    let (code, _) = run("
+        .data;
+        .align 8;
+        BUF: .zero 8;
+        .code;
+        push HANDLER; syscall vm_set_trap_handler;
+        call F, 0; exit;
+        F: push 1; push 0; FAULT: div_u64; ret;
+        HANDLER:
+        get_arg 1; push FAULT; ne_u64; jnz FAIL;
+        push BUF; push 1; syscall vm_backtrace; pop;
+        push BUF; load_u64; push FAULT; ne_u64; jnz FAIL;
+        get_arg 0; exit;
+        FAIL: push 100; exit;
+    ");
+    assert_eq!(code, 1);
+
+    // Without a handler, the error is reported and the VM exits
+    let output = run_with("push 1; push 0; div_u64; exit;", &[], &[]);
+    assert_eq!(output.status.code(), Some(134));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("division by zero, pc 2"));
+}
+
+#[test]
+fn vm_mem_protect()
+{
+    // The data region is followed by the heap region
+    let (code, _) = run("
+        .data;
+        .align 8;
+        LAYOUT: .zero 64;
+        .code;
+        push LAYOUT; syscall vm_mem_layout;
+        push LAYOUT; push 16; add_u64; load_u64; jnz FAIL;
+        push LAYOUT; push 24; add_u64; load_u64; push 64; ne_u64; jnz FAIL;
+        push LAYOUT; push 32; add_u64; load_u64; push 64; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+
+    // Writing to a read-only page traps, but reading from it doesn't
+    let (code, _) = run("
+        push 16384; syscall vm_resize_heap; pop;
+        push HANDLER; syscall vm_set_trap_handler;
+        push 100; push 4096; push 1; syscall vm_mem_protect; push -22; ne_u64; jnz FAIL;
+        push 4096; push 1; push 1; syscall vm_mem_protect; jnz FAIL;
+        push 4100; syscall vm_mem_query; push 1; ne_u64; jnz FAIL;
+        push 8192; syscall vm_mem_query; push 3; ne_u64; jnz FAIL;
+        push 4096; load_u64; pop;
+        push 4096; push 7; store_u32;
+        FAIL: push 1; exit;
+        HANDLER: get_arg 2; push 4096; ne_u64; jnz FAIL; get_arg 0; exit;
+    ");
+    assert_eq!(code, 6);
+}
+
+#[test]
+fn vm_capabilities()
+{
+    let (code, out) = run("
+        syscall sys_version; syscall print_i64; syscall print_endl;
+        push 6; syscall sys_has_syscall; jz FAIL;
+        push 60000; syscall sys_has_syscall; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+
+    let major: u32 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+    let minor: u32 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+    let patch: u32 = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap();
+    assert_eq!(out, format!("{}\n", (major << 16) | (minor << 8) | patch));
+}
+
+#[test]
+fn sys_batch()
+{
+    // Entries of print_i64, print_endl, sys_has_syscall, vm_yield and print_i64
+    let (code, out) = run("
+        .data;
+        .align 8;
+        B:
+        .u64 5; .u64 42; .u64 0; .u64 0; .u64 0; .u64 7;
+        .u64 7; .u64 0; .u64 0; .u64 0; .u64 0; .u64 7;
+        .u64 52; .u64 6; .u64 0; .u64 0; .u64 0; .u64 7;
+        .u64 66; .u64 0; .u64 0; .u64 0; .u64 0; .u64 7;
+        .u64 5; .u64 99; .u64 0; .u64 0; .u64 0; .u64 7;
+        .code;
+
+        push B; push 5; syscall sys_batch; push 3; ne_u64; jnz FAIL;
+        push B; push 40; add_u64; load_u64; jnz FAIL;
+        push B; push 136; add_u64; load_u64; push 1; ne_u64; jnz FAIL;
+        push B; push 184; add_u64; load_u64; push -38; ne_u64; jnz FAIL;
+        push B; push 232; add_u64; load_u64; push 7; ne_u64; jnz FAIL;
+
+        # Unknown syscalls also stop the batch
+        push B; push 0xFFFF; store_u64;
+        push B; push 2; syscall sys_batch; jnz FAIL;
+        push B; push 40; add_u64; load_u64; push -38; ne_u64; jnz FAIL;
+
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+    assert_eq!(out, "42\n");
+}
+
+#[test]
+fn io_print()
+{
+    let (code, out) = run("
+        .data;
+        S: .stringz \"hello\";
+        .code;
+        push S; syscall print_str; syscall print_endl;
+        push -42; syscall print_i64; syscall print_endl;
+        push_f32 1.5; syscall print_f32; syscall print_endl;
+        push_f64 -0.125; syscall print_f64; syscall print_endl;
+        push 33; syscall putchar; pop;
+        push S; push 6; syscall io_write; push 6; ne_u64; jnz FAIL;
+        push S; push 0; syscall io_write; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+    assert_eq!(out, "hello\n-42\n1.5\n-0.125\n!hello\0");
+}
+
+#[test]
+fn io_getchar()
+{
+    // Echo stdin until end of file, which getchar reports as -1
+    let output = run_with("
+        LOOP:
+        syscall getchar;
+        dup; push -1; eq_u64; jnz DONE;
+        syscall putchar; pop;
+        jmp LOOP;
+        DONE:
+        exit;
+    ", &[], b"abc");
+    // The exit code of -1 is truncated to 8 bits by the OS
+    assert_eq!(output.status.code(), Some(255));
+    assert_eq!(output.stdout, b"abc");
+}
+
+#[test]
+fn io_read_line()
+{
+    // Lines longer than the buffer are read in parts
+    let src = "
+        .data;
+        SEP: .stringz \"|\";
+        BUF: .zero 8;
+        .code;
+        LOOP:
+        push BUF; push 8; syscall read_line;
+        jz DONE;
+        push BUF; syscall print_str;
+        push SEP; syscall print_str;
+        jmp LOOP;
+        DONE:
+        push BUF; push 0; syscall read_line; push -22; ne_u64;
+        exit;
+    ";
+
+    let output = run_with(src, &["--stdio"], b"ab\nlonger line\n\nlast");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"ab\n|longer |line\n|\n|last|");
+}
+
+#[test]
+fn console_output()
+{
+    // Stdout isn't a terminal here, so the escape sequences
+    // are written as-is and there is no terminal size
+    let (code, out) = run("
+        syscall console_clear;
+        push 2; push 4; syscall console_move_cursor;
+        push 9; push 255; push 5; syscall console_set_style;
+        push 0; syscall console_show_cursor;
+        syscall console_size; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+    assert_eq!(out, "\x1b[2J\x1b[H\x1b[3;5H\x1b[0;91;49;1;7m\x1b[?25l");
+}
+
+#[test]
+fn console_read_key()
+{
+    // Print the keys read, with escape sequences for the arrow
+    // keys decoded, until there is no more input
+    let output = run_with("
+        push 1; syscall console_set_raw; jnz FAIL;
+        LOOP:
+        push 1; syscall console_read_key;
+        dup; push -1; eq_u64; jnz DONE;
+        syscall print_i64; push 32; syscall putchar; pop;
+        jmp LOOP;
+        DONE:
+        push 0; syscall console_read_key; push -1; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ", &[], b"a\x1b[A\x1b[1;5D\x1b[5~\r\x7f");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "97 16003 16001 10 8 ");
+}
+
+#[test]
+fn proc_atexit()
+{
+    let (code, out) = run("
+        .data;
+        S: .stringz \"cleanup\";
+        .code;
+        push CB; syscall proc_atexit;
+        push 7; exit;
+        CB: push S; syscall print_str; push 0; ret;
+    ");
+    assert_eq!(code, 7);
+    assert_eq!(out, "cleanup");
+}
+
+#[test]
+fn proc_args()
+{
+    let path = write_asm("
+        syscall proc_argc; syscall print_i64; syscall print_endl;
+        syscall proc_argv; push 8; add_u64; load_u64; syscall print_str; syscall print_endl;
+        syscall proc_argv; push 16; add_u64; load_u64; syscall print_str; syscall print_endl;
+
+        # The array is terminated by a null pointer, and only copied once
+        syscall proc_argv; push 24; add_u64; load_u64; jnz FAIL;
+        syscall proc_argv; syscall proc_argv; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .arg(&path)
+        .args(["-v", "hello world"])
+        .output()
+        .unwrap();
+
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n-v\nhello world\n");
+}
+
+#[test]
+fn proc_spawn()
+{
+    let src = "
+        .data;
+        CMD: .stringz \"echo spawned; exit 3\";
+        BUF: .zero 32;
+        .code;
+        push CMD; push BUF; push 32; push ON_EXIT; syscall proc_spawn;
+        jz DENIED;
+        push 0; ret;
+        DENIED: push 100; exit;
+        ON_EXIT:
+        push BUF; syscall print_str;
+        get_arg 2; syscall print_i64;
+        get_arg 1; exit;
+    ";
+
+    // Denied without --allow-exec
+    let output = run_with(src, &[], &[]);
+    assert_eq!(output.status.code(), Some(100));
+
+    let output = run_with(src, &["--allow-exec"], &[]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"spawned\n8");
+}
+
+#[test]
+fn module_load()
+{
+    let module_path = write_asm("
+        .import BASE;
+        .import twice;
+        .export mod_add;
+        .data;
+        .align 8;
+        Z: .u64 0;
+        .code;
+        push Z; push 3; store_u64;
+        push BASE; load_u64; call twice, 1; ret;
+        mod_add: get_arg 0; get_arg 1; add_u64; push Z; load_u64; add_u64; ret;
+    ");
+
+    let (code, out) = run(&format!("
+        .export BASE;
+        .export twice;
+        .data;
+        PATH: .stringz \"{}\";
+        BAD: .stringz \"/nonexistent.asm\";
+        NAME: .stringz \"mod_add\";
+        .align 8;
+        BASE: .u64 5;
+        .code;
+        push BAD; syscall module_load; jnz FAIL;
+        push PATH; syscall module_load;
+        dup; jz FAIL;
+        call_fp 0; push 10; ne_u64; jnz FAIL;
+        push 20; push 22; push NAME; syscall module_symbol; call_fp 2;
+        exit;
+        FAIL: push 100; exit;
+        twice: get_arg 0; push 2; mul_u64; ret;
+    ", module_path.display()));
+
+    fs::remove_file(&module_path).unwrap();
+    assert_eq!(code, 45, "{}", out);
+}
+
+#[test]
+fn module_load_signed()
+{
+    let uvm = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_uvm")).args(args).output().unwrap();
+
+    let key_name = std::env::temp_dir().join(format!("uvm_test_{}_signer", std::process::id()));
+    let key_name = key_name.to_str().unwrap();
+    assert_eq!(uvm(&["keygen", key_name]).status.code(), Some(0));
+    let key_file = format!("{}.key", key_name);
+    let pub_file = format!("{}.pub", key_name);
+
+    let module_path = write_asm(".export mod_val; mod_val: push 7; ret;");
+    let module_path = module_path.to_str().unwrap();
+    let prog_path = write_asm(&format!("
+        .data;
+        PATH: .stringz \"{}\";
+        .code;
+        push PATH; syscall module_load; jz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ", module_path));
+    let prog_path = prog_path.to_str().unwrap();
+    assert_eq!(uvm(&["sign", &key_file, prog_path]).status.code(), Some(0));
+
+    // The module must be signed by a trusted key as well
+    let output = uvm(&["--require-signed", "--trusted-key", &pub_file, prog_path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("is not signed"));
+
+    assert_eq!(uvm(&["sign", &key_file, module_path]).status.code(), Some(0));
+    let output = uvm(&["--require-signed", "--trusted-key", &pub_file, prog_path]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Modifying the module invalidates its signature
+    fs::write(module_path, ".export mod_val; mod_val: push 8; ret;").unwrap();
+    let output = uvm(&["--require-signed", "--trusted-key", &pub_file, prog_path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("invalid signature"));
+
+    for path in [module_path, prog_path] {
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.sig", path)).unwrap();
+    }
+    fs::remove_file(&key_file).unwrap();
+    fs::remove_file(&pub_file).unwrap();
+}
+
+/// Assemble a program into a binary image with uvm build
+fn build_image(src: &str) -> PathBuf
+{
+    let src_path = write_asm(src);
+    let image_path = src_path.with_extension("uvm");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .arg("build")
+        .arg(&src_path)
+        .arg(&image_path)
+        .output()
+        .unwrap();
+
+    fs::remove_file(&src_path).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stdout));
+    image_path
+}
+
+#[test]
+fn test_runner()
+{
+    let dir = std::env::temp_dir().join(format!("uvm_test_runner_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a_pass.asm"), "push 0; exit;").unwrap();
+    fs::write(dir.join("b_fail.asm"), ".data; MSG: .stringz \"oops\"; .code; push MSG; syscall print_str; push 3; exit;").unwrap();
+    fs::write(dir.join("notes.txt"), "not a test").unwrap();
+
+    // VM options given before the command apply to the tests
+    fs::write(dir.join("c_oob.asm"), "push 1000000000; load_u8; push 0; exit;").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .arg("--checked-memory")
+        .arg("test")
+        .arg(&dir)
+        .output()
+        .unwrap();
+    let out = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1), "{}", out);
+    assert!(out.starts_with("running 3 tests\na_pass.asm: ok\nb_fail.asm: FAILED (exit code 3)\n"), "{}", out);
+    assert!(out.contains("c_oob.asm: FAILED (exit code 134)"), "{}", out);
+    assert!(out.contains("---- output of b_fail.asm ----\noops"), "{}", out);
+    assert!(out.ends_with("1 passed, 2 failed\n"), "{}", out);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn minidump()
+{
+    let src = "
+        call MAIN, 0; exit;
+        MAIN: push 7; push 0; call HELPER, 1; ret;
+        HELPER: get_arg 0; push 0; div_u64; ret;
+    ";
+    let dump_path = std::env::temp_dir().join(format!("uvm_test_{}.dump", std::process::id()));
+
+    let output = run_with(src, &["--dump-on-trap", dump_path.to_str().unwrap()], &[]);
+    assert_eq!(output.status.code(), Some(134));
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .arg("debug")
+        .arg("--open-dump")
+        .arg(&dump_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"stack\nquit\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    let out = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(0), "{}", out);
+    assert!(out.contains("trap: division by zero, pc 20\ninstruction: div_u64\n"), "{}", out);
+    assert!(out.contains("backtrace:\n  #0 pc 20 (HELPER+3)"), "{}", out);
+    assert!(out.ends_with("  ---- bp\n  [1] 0 (0x0)\n  [0] 7 (0x7)\n"), "{}", out);
+
+    fs::remove_file(&dump_path).unwrap();
+}
+
+#[test]
+fn deny_ops()
+{
+    let src = "syscall time_current_ms; pop; push 0; exit;";
+    assert_eq!(run_with(src, &["--deny-ops", "float"], &[]).status.code(), Some(0));
+    assert_eq!(run_with(src, &["--deny-ops", "jumps"], &[]).status.code(), Some(255));
+
+    let output = run_with(src, &["--deny-ops", "float", "--deny-ops", "syscall"], &[]);
+    assert_eq!(output.status.code(), Some(255));
+    let out = String::from_utf8(output.stdout).unwrap();
+    assert!(out.contains("syscall time_current_ms is not allowed, syscall instructions are denied"), "{}", out);
+
+    // Modules are verified against the same restrictions
+    let int_module = write_asm(".export val; val: push 7; ret;");
+    let float_module = write_asm(".export val; val: push 7; i64_to_f64; ret;");
+    let src = |module: &std::path::Path| format!("
+        .data;
+        PATH: .stringz \"{}\";
+        .code;
+        push PATH; syscall module_load; dup; jz FAIL;
+        call_fp 0; exit;
+        FAIL: push 100; exit;
+    ", module.display());
+    assert_eq!(run_with(&src(&int_module), &["--deny-ops", "float"], &[]).status.code(), Some(7));
+    let output = run_with(&src(&float_module), &["--deny-ops", "float"], &[]);
+    assert_eq!(output.status.code(), Some(100));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("i64_to_f64 is not allowed"));
+    fs::remove_file(&int_module).unwrap();
+    fs::remove_file(&float_module).unwrap();
+
+    // With heap_grow denied, the heap can't grow through sys_batch,
+    // which runs vm_resize_heap and vm_grow_heap, or through allocations
+    let src = "
+        .data;
+        .align 8;
+        B:
+        .u64 17; .u64 0x1000000; .u64 0; .u64 0; .u64 0; .u64 7;
+        .u64 87; .u64 4096; .u64 0; .u64 0; .u64 0; .u64 7;
+        .code;
+        syscall vm_heap_size;
+        push B; push 1; syscall sys_batch; push 1; ne_u64; jnz FAIL;
+        push B; push 40; add_u64; load_u64; push -1; ne_u64; jnz FAIL;
+        push B; push 48; add_u64; push 1; syscall sys_batch; push 1; ne_u64; jnz FAIL;
+        push B; push 88; add_u64; load_u64; push -12; ne_u64; jnz FAIL;
+        push 0x1000000; syscall mem_alloc; jnz FAIL;
+        syscall vm_heap_size; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+    assert_eq!(run_with(src, &["--deny-ops", "heap_grow"], &[]).status.code(), Some(0));
+    assert_eq!(run_with(src, &["--deny-ops", "float"], &[]).status.code(), Some(1));
+}
+
+#[test]
+fn vm_coverage()
+{
+    let dir = std::env::temp_dir().join(format!("uvm_coverage_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.asm"), "push 1;\njnz END;\npush 2; exit;\nEND:\npush 0; exit;\n").unwrap();
+    fs::write(dir.join("b.asm"), "push 0;\nexit;\n").unwrap();
+
+    // The reports of the tests are combined
+    let report_path = dir.join("coverage.info");
+    let output = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .arg("--coverage")
+        .arg(&report_path)
+        .arg("test")
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let report = fs::read_to_string(&report_path).unwrap();
+    let expected = format!(
+        "TN:\nSF:{}\nDA:1,1\nDA:2,1\nDA:3,0\nDA:5,1\nLF:4\nLH:3\nend_of_record\n\
+         TN:\nSF:{}\nDA:1,1\nDA:2,1\nLF:2\nLH:2\nend_of_record\n",
+        dir.join("a.asm").display(),
+        dir.join("b.asm").display(),
+    );
+    assert_eq!(report, expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn vm_diff_jit()
+{
+    // Sum the values stored in a heap buffer, in a loop hot enough to be compiled
+    let output = run_with("
+        .data; .zero 8192; .code;
+        push 0;
+        FILL:
+        dup; dup; push 8; mul_u64; swap; push 3; mul_u64; store_u64;
+        push 1; add_u64; dup; push 1000; lt_u64; jnz FILL;
+        pop;
+        push 0; push 0;
+        SUM:
+        dup; push 8; mul_u64; load_u64; getn 2; add_u64; setn 1;
+        push 1; add_u64; dup; push 1000; lt_u64; jnz SUM;
+        pop; syscall print_i64; syscall print_endl;
+        push 7; exit;
+    ", &["--diff-jit"], &[]);
+
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1498500\n");
+
+    #[cfg(target_arch = "x86_64")]
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Both runs are restricted the same way, so the heap can't
+    // grow in either of them, even through sys_batch
+    let output = run_with("
+        .data;
+        .align 8;
+        E: .u64 17; .u64 1000000; .u64 0; .u64 0; .u64 0; .u64 0;
+        .code;
+        push E; push 1; syscall sys_batch; pop;
+        push E; push 40; add_u64; load_u64; push -1; eq_u64; syscall print_i64; syscall print_endl;
+        push 0; exit;
+    ", &["--diff-jit", "--deny-ops", "heap_grow"], &[]);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+}
+
+#[test]
+fn module_load_image()
+{
+    // Both the program and the module it loads are binary images
+    let module_path = build_image("
+        .import BASE;
+        .export mod_twice;
+        .code;
+        push BASE; load_u64; push 2; mul_u64; ret;
+        mod_twice: get_arg 0; push 2; mul_u64; ret;
+    ");
+
+    let main_path = build_image(&format!("
+        .export BASE;
+        .data;
+        PATH: .stringz \"{}\";
+        NAME: .stringz \"mod_twice\";
+        .align 8;
+        BASE: .u64 5;
+        .code;
+        push PATH; syscall module_load;
+        dup; jz FAIL;
+        call_fp 0;
+        push NAME; syscall module_symbol; call_fp 1;
+        exit;
+        FAIL: push 100; exit;
+    ", module_path.display()));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_uvm")).arg(&main_path).output().unwrap();
+    fs::remove_file(&module_path).unwrap();
+    fs::remove_file(&main_path).unwrap();
+    assert_eq!(output.status.code(), Some(20));
+}
+
+#[test]
+fn tls()
+{
+    let (code, out) = run("
+        .data;
+        .u64 0;
+        INIT: .u64 7;
+        .code;
+        syscall tls_base; jnz FAIL;
+
+        # First allocation, initialized from INIT
+        push INIT; push 8; syscall tls_alloc; jnz FAIL;
+        syscall tls_base; load_u64; push 7; ne_u64; jnz FAIL;
+        syscall tls_base; push 9; store_u64;
+
+        # Growing the block preserves its contents
+        push 0; push 4; syscall tls_alloc; push 8; ne_u64; jnz FAIL;
+        syscall tls_base; push 8; add_u64; load_u32; jnz FAIL;
+        syscall tls_base; load_u64;
+        exit;
+        FAIL: push 100; exit;
+    ");
+
+    assert_eq!(code, 9, "{}", out);
+}
+
+#[test]
+fn threads()
+{
+    let (code, out) = run("
+        .data;
+        SHARED: .u64 0;
+        INIT: .u64 7;
+        .code;
+        push INIT; push 8; syscall tls_alloc; pop;
+        syscall thread_id; jnz FAIL;
+
+        push WORKER; push 5000; syscall thread_spawn; push 1; ne_u64; jnz FAIL;
+        push WORKER; push 3000; syscall thread_spawn; push 2; ne_u64; jnz FAIL;
+
+        # Each thread has its own TLS block, and the heap is shared
+        push 1; syscall thread_join; push 5007; ne_u64; jnz FAIL;
+        push 2; syscall thread_join; push 3007; ne_u64; jnz FAIL;
+        syscall tls_base; load_u64; push 7; ne_u64; jnz FAIL;
+        push SHARED; load_u64; push 8000; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 100; exit;
+
+        # Increment the TLS value and the shared counter in a loop
+        WORKER:
+        push 0;
+        LOOP:
+        syscall tls_base; syscall tls_base; load_u64; push 1; add_u64; store_u64;
+        push SHARED; push SHARED; load_u64; push 1; add_u64; store_u64;
+        push 1; add_u64; dup; get_arg 0; lt_u64; jnz LOOP;
+        pop;
+        syscall tls_base; load_u64; ret;
+    ");
+    assert_eq!(code, 0, "{}", out);
+
+    // Threads keep running in the event loop once main returns
+    let (code, out) = run("
+        push THREAD; push 0; syscall thread_spawn; pop;
+        push 0; ret;
+        THREAD: syscall thread_id; push 40; add_u64; exit;
+    ");
+    assert_eq!(code, 41, "{}", out);
+}
+
+#[test]
+fn sync()
+{
+    let (code, out) = run("
+        .data;
+        M: .u64 0;
+        C: .u64 0;
+        COUNT: .u64 0;
+        READY: .u64 0;
+        .code;
+        push M; syscall mutex_create; store_u64;
+        push C; syscall cond_create; store_u64;
+
+        # The counter is incremented with jumps between the load and the
+        # store, where the threads could switch without the mutex
+        push WORKER; push 3000; syscall thread_spawn; pop;
+        push WORKER; push 2000; syscall thread_spawn; pop;
+        push 1; syscall thread_join; pop;
+        push 2; syscall thread_join; pop;
+        push COUNT; load_u64; push 5000; ne_u64; jnz FAIL;
+
+        # Wait until the waiter thread sets READY to 2
+        push M; load_u64; syscall mutex_lock;
+        push WAITER; push 0; syscall thread_spawn; pop;
+        push READY; push 1; store_u64;
+        push C; load_u64; syscall cond_signal;
+        WAIT:
+        push C; load_u64; push M; load_u64; syscall cond_wait;
+        push READY; load_u64; push 2; ne_u64; jnz WAIT;
+        push M; load_u64; syscall mutex_unlock;
+        push 3; syscall thread_join; push 42; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 100; exit;
+
+        WORKER:
+        push 0;
+        LOOP:
+        push M; load_u64; syscall mutex_lock;
+        push COUNT; push COUNT; load_u64; jmp A; A: jmp B; B: push 1; add_u64; store_u64;
+        push M; load_u64; syscall mutex_unlock;
+        push 1; add_u64; dup; get_arg 0; lt_u64; jnz LOOP;
+        ret;
+
+        WAITER:
+        push M; load_u64; syscall mutex_lock;
+        push READY; push 2; store_u64;
+        push C; load_u64; syscall cond_signal;
+        push M; load_u64; syscall mutex_unlock;
+        push 42; ret;
+    ");
+    assert_eq!(code, 0, "{}", out);
+
+    // Waiting for a mutex held by a thread which waits is a deadlock
+    let output = run_with("
+        syscall mutex_create; syscall mutex_lock;
+        push THREAD; push 0; syscall thread_spawn; syscall thread_join;
+        exit;
+        THREAD: push 0; syscall mutex_lock; push 0; ret;
+    ", &[], &[]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("deadlock, thread 1 waits for mutex 0"), "{}", stderr);
+}
+
+#[test]
+fn pipes()
+{
+    let (code, out) = run("
+        .data;
+        P: .u64 0;
+        Q: .u64 0;
+        R: .u64 0;
+        VALS: .u64 0; .u64 1; .u64 2;
+        BUF: .u64 0;
+        SUM: .u64 0;
+        N: .u64 0;
+        S: .stringz \"hello\";
+        PBUF: .zero 12;
+        QBUF: .zero 8;
+        RBUF: .zero 8;
+        .code;
+
+        # The pipe only holds one value, so the producers wait to write
+        # and the consumer waits to read
+        push P; push PBUF; push 12; syscall pipe_create; store_u64;
+        push PRODUCER; push 1; syscall thread_spawn; pop;
+        push PRODUCER; push 2; syscall thread_spawn; pop;
+        LOOP:
+        push P; load_u64; push BUF; push 8; syscall pipe_read; push 8; ne_u64; jnz FAIL;
+        push SUM; push SUM; load_u64; push BUF; load_u64; add_u64; store_u64;
+        push N; push N; load_u64; push 1; add_u64; store_u64;
+        push N; load_u64; push 200; lt_u64; jnz LOOP;
+        push SUM; load_u64; push 300; ne_u64; jnz FAIL;
+        push 1; syscall thread_join; push 100; ne_u64; jnz FAIL;
+        push 2; syscall thread_join; push 100; ne_u64; jnz FAIL;
+
+        # Reads return the bytes available, up to the size of the buffer
+        push Q; push QBUF; push 8; syscall pipe_create; store_u64;
+        push Q; load_u64; push S; push 5; syscall pipe_write; push 5; ne_u64; jnz FAIL;
+        push Q; load_u64; push BUF; push 3; syscall pipe_read; push 3; ne_u64; jnz FAIL;
+        push Q; load_u64; push BUF; push 8; syscall pipe_read; push 2; ne_u64; jnz FAIL;
+        push BUF; load_u8; push 108; ne_u64; jnz FAIL;
+        push Q; load_u64; push BUF; push 0; syscall pipe_read; jnz FAIL;
+
+        # Bytes wrap around the end of the buffer of the pipe
+        push Q; load_u64; push S; push 5; syscall pipe_write; push 5; ne_u64; jnz FAIL;
+        push Q; load_u64; push BUF; push 8; syscall pipe_read; push 5; ne_u64; jnz FAIL;
+        push BUF; load_u8; push 104; ne_u64; jnz FAIL;
+        push BUF; push 4; add_u64; load_u8; push 111; ne_u64; jnz FAIL;
+
+        # Writes can't be larger than the pipe, and pipes can't be empty
+        push Q; load_u64; push BUF; push 9; syscall pipe_write; push -22; ne_u64; jnz FAIL;
+        push QBUF; push 0; syscall pipe_create; push -22; ne_u64; jnz FAIL;
+
+        # Closing a pipe wakes up the thread waiting to read from it
+        push R; push RBUF; push 8; syscall pipe_create; store_u64;
+        push CLOSER; push 0; syscall thread_spawn; pop;
+        push R; load_u64; push BUF; push 8; syscall pipe_read; push -9; ne_u64; jnz FAIL;
+        push R; load_u64; push S; push 5; syscall pipe_write; push -9; ne_u64; jnz FAIL;
+        push R; load_u64; syscall pipe_close; push -9; ne_u64; jnz FAIL;
+        push Q; load_u64; syscall pipe_close; jnz FAIL;
+        push Q; load_u64; push BUF; push 8; syscall pipe_read; push -9; ne_u64; jnz FAIL;
+        push P; load_u64; syscall pipe_close; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+
+        PRODUCER:
+        push 0;
+        PLOOP:
+        push P; load_u64; push VALS; get_arg 0; push 8; mul_u64; add_u64; push 8; syscall pipe_write;
+        push 8; ne_u64; jnz FAIL;
+        push 1; add_u64; dup; push 100; lt_u64; jnz PLOOP;
+        ret;
+
+        CLOSER:
+        push R; load_u64; syscall pipe_close;
+        ret;
+    ");
+    assert_eq!(code, 0, "{}", out);
+
+    // Reading from a pipe no other thread writes to is a deadlock
+    let output = run_with("
+        .data;
+        BUF: .u64 0;
+        .code;
+        push BUF; push 8; syscall pipe_create; push BUF; push 8; syscall pipe_read;
+        exit;
+    ", &[], &[]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("deadlock, thread 0 waits for pipe"), "{}", stderr);
+}
+
+#[test]
+fn time_callbacks()
+{
+    let (code, out) = run("
+        syscall time_current_ms; push 0; eq_u64; jnz FAIL;
+        push 10; push CB; syscall time_delay_cb;
+        push 0; ret;
+        FAIL: push 1; exit;
+        CB: push 5; exit;
+    ");
+    assert_eq!(code, 5, "{}", out);
+
+    // Callbacks due at the same time run in the order they were scheduled,
+    // and a repeating timer runs until it is cancelled
+    let src = "
+        .data;
+        ORDER: .u64 0;
+        TIMER: .u64 0;
+        COUNT: .u64 0;
+        .code;
+        push 0; push A; syscall time_delay_cb;
+        push 0; push B; syscall time_delay_cb;
+        push TIMER; push 5; push TICK; syscall time_interval_cb; store_u64;
+        push 100; push END; syscall time_delay_cb;
+        push 0; ret;
+        FAIL: push 1; exit;
+
+        A: push ORDER; load_u64; jnz FAIL; push ORDER; push 1; store_u64; push 0; ret;
+        B: push ORDER; load_u64; push 1; ne_u64; jnz FAIL; push 0; ret;
+
+        TICK:
+        push COUNT; push COUNT; load_u64; push 1; add_u64; store_u64;
+        push COUNT; load_u64; push 3; lt_u64; jnz DONE;
+        push TIMER; load_u64; syscall time_cancel_cb; jnz FAIL;
+        DONE: push 0; ret;
+
+        END: push COUNT; load_u64; exit;
+    ";
+    for opts in [&[][..], &["--deterministic"][..]] {
+        let output = run_with(src, opts, &[]);
+        assert_eq!(output.status.code(), Some(3), "{:?}", opts);
+    }
+}
+
+#[test]
+fn event_poll()
+{
+    // Timers with a null callback queue EVENT_TIMER (7) events with the
+    // id of the timer, which are polled from a regular callback
+    let output = run_with("
+        .data;
+        EV: .zero 40;
+        TIMER: .u64 0;
+        COUNT: .u64 0;
+        .code;
+        push EV; syscall event_poll; jnz FAIL;
+        push 5; push 0; syscall time_delay_cb;
+        push TIMER; push 10; push 0; syscall time_interval_cb; store_u64;
+        push 35; push POLL; syscall time_delay_cb;
+        push 0; ret;
+        FAIL: push 100; exit;
+
+        POLL:
+        push EV; syscall event_poll; push 1; ne_u64; jnz FAIL;
+        push EV; load_u64; push 7; ne_u64; jnz FAIL;
+        push EV; push 8; add_u64; load_u64; jnz FAIL;
+        LOOP:
+        push EV; syscall event_poll; jz DONE;
+        push EV; push 8; add_u64; load_u64; push TIMER; load_u64; ne_u64; jnz FAIL;
+        push COUNT; push COUNT; load_u64; push 1; add_u64; store_u64;
+        jmp LOOP;
+        DONE: push COUNT; load_u64; exit;
+    ", &["--deterministic"], &[]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn trace_spans()
+{
+    let src = "
+        .data;
+        OUTER: .stringz \"outer\";
+        INNER: .stringz \"in \\\"quotes\\\"\";
+        .code;
+        syscall trace_end;
+        push OUTER; syscall trace_begin;
+        push INNER; syscall trace_begin;
+        syscall trace_end;
+        push INNER; syscall trace_begin;
+        push 0; exit;
+    ";
+
+    // Without --trace-out, the spans are ignored
+    let output = run_with(src, &[], &[]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let trace_path = std::env::temp_dir().join(format!("uvm_test_{}.trace.json", std::process::id()));
+    let output = run_with(src, &["--trace-out", trace_path.to_str().unwrap()], &[]);
+    assert_eq!(output.status.code(), Some(0));
+
+    // The unmatched trace_end is dropped, and the spans still open are ended on exit
+    let trace = fs::read_to_string(&trace_path).unwrap();
+    fs::remove_file(&trace_path).unwrap();
+    assert!(trace.starts_with("{\"traceEvents\":["));
+    assert_eq!(trace.matches("\"ph\":\"B\"").count(), 3);
+    assert_eq!(trace.matches("\"ph\":\"E\"").count(), 3);
+    assert!(trace.contains("{\"name\":\"outer\",\"ph\":\"B\""));
+    assert!(trace.contains("\"name\":\"in \\\"quotes\\\"\""));
+}
+
+#[test]
+fn time_virtual_clock()
+{
+    // The clock starts at 0 and jumps to each callback in --deterministic
+    // mode, so waiting for an hour takes no time
+    let start = std::time::Instant::now();
+    let output = run_with("
+        syscall time_current_ms; jnz FAIL;
+        push 3600000; push CB; syscall time_delay_cb;
+        push 0; ret;
+        FAIL: push 2; exit;
+        CB: syscall time_current_ms; push 3600000; eq_u64; exit;
+    ", &["--deterministic"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(start.elapsed() < Duration::from_secs(60));
+}
+
+#[test]
+fn audio_helpers()
+{
+    let (code, _) = run("
+        .data;
+        A: .i16 30000; .i16 -100;
+        B: .i16 10000; .i16 300;
+        R: .zero 6;
+        .code;
+        push A; push B; push 2; push_f32 1.0; syscall audio_mix;
+        push A; load_u16; push 32767; ne_u64; jnz FAIL;
+        push A; push 2; add_u64; load_u16; push 200; ne_u64; jnz FAIL;
+        push R; push 3; push B; push 2; syscall audio_resample;
+        push R; push 4; add_u64; load_u16; push 300; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn audio_synth()
+{
+    let (code, _) = run("
+        .data;
+        .align 8;
+        OSC: .u32 4; .f32 1.0; .f32 0.5; .f32 0.0;
+        ENV: .u32 2; .u32 2; .f32 0.5; .u32 2; .u64 0; .u64 6;
+        FILTER: .zero 36;
+        W: .zero 8;
+        E: .i16 1000; .i16 1000; .i16 1000; .i16 1000; .i16 1000; .i16 1000; .i16 1000; .i16 1000;
+        .code;
+        push W; push 4; push 1; push OSC; syscall audio_osc; jnz FAIL;
+        push W; push 4; add_u64; load_u16; push 49153; ne_u64; jnz FAIL;
+        push W; push 4; push 9; push OSC; syscall audio_osc; push -22; ne_u64; jnz FAIL;
+        push E; push 8; push ENV; syscall audio_envelope;
+        push E; push 6; add_u64; load_u16; push 750; ne_u64; jnz FAIL;
+        push E; push 14; add_u64; load_u16; push 250; ne_u64; jnz FAIL;
+        push ENV; push 16; add_u64; load_u64; push 8; ne_u64; jnz FAIL;
+        push FILTER; push 0; push_f32 0.25; push_f32 0.7; syscall audio_biquad_init; jnz FAIL;
+        push FILTER; push 7; push_f32 0.25; push_f32 0.7; syscall audio_biquad_init; push -22; ne_u64; jnz FAIL;
+        push E; push 8; push FILTER; syscall audio_biquad;
+        push E; load_u16; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn graphics_sprites()
+{
+    let (code, _) = run("
+        .data;
+        .align 8;
+        DST: .addr64 DST_PX; .u32 4; .u32 2;
+        SHEET: .addr64 SHEET_PX; .u32 2; .u32 2;
+        MAP: .addr64 SHEET; .addr64 TILES; .u32 2; .u32 1; .u32 1; .u32 1;
+        SPR: .u32 1; .u32 0; .u32 0; .u32 0; .u32 2; .u32 2; .u32 1;
+        DST_PX: .zero 32;
+        SHEET_PX: .u32 0xFF000001; .u32 0x00000002; .u32 0xFF000003; .u32 0xFF000004;
+        TILES: .u16 3; .u16 0xFFFF;
+        .code;
+
+        # Draw the sheet at x=1, flipped horizontally
+        push DST; push SHEET; push SPR; push 1; syscall blit_sprites;
+        push DST_PX; push 4; add_u64; load_u32; jnz FAIL;
+        push DST_PX; push 8; add_u64; load_u32; push 0xFF000001; ne_u64; jnz FAIL;
+        push DST_PX; push 20; add_u64; load_u32; push 0xFF000004; ne_u64; jnz FAIL;
+        push DST_PX; push 24; add_u64; load_u32; push 0xFF000003; ne_u64; jnz FAIL;
+
+        # Draw tile 3 at x=1, the second tile is empty
+        push DST; push MAP; push -1; push 0; syscall draw_tilemap;
+        push DST_PX; push 4; add_u64; load_u32; push 0xFF000004; ne_u64; jnz FAIL;
+        push DST_PX; push 8; add_u64; load_u32; push 0xFF000001; ne_u64; jnz FAIL;
+
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn graphics_surfaces()
+{
+    let (code, _) = run("
+        .data;
+        .align 8;
+        A: .addr64 A_PX; .u32 3; .u32 1;
+        B: .addr64 B_PX; .u32 2; .u32 1;
+        A_PX: .u32 0xFF000001; .u32 0xFF000002; .u32 0xFF000003;
+        B_PX: .u32 0; .u32 0xFF000009;
+        .code;
+        push A; push 0; syscall surface_create;
+        push B; push 1; syscall surface_create;
+
+        # Transparent pixels of B are skipped
+        get_local 0; get_local 1; push 1; push 0; syscall surface_blit; jnz FAIL;
+        push A_PX; push 4; add_u64; load_u32; push 0xFF000002; ne_u64; jnz FAIL;
+        push A_PX; push 8; add_u64; load_u32; push 0xFF000009; ne_u64; jnz FAIL;
+
+        # A is opaque, and can be blitted onto itself
+        get_local 0; get_local 0; push -1; push 0; syscall surface_blit; jnz FAIL;
+        push A_PX; load_u32; push 0xFF000002; ne_u64; jnz FAIL;
+        push A_PX; push 4; add_u64; load_u32; push 0xFF000009; ne_u64; jnz FAIL;
+
+        # Blits entirely outside of the destination draw nothing
+        get_local 0; get_local 0; push 10; push 0; syscall surface_blit; jnz FAIL;
+        get_local 0; get_local 0; push -5; push 0; syscall surface_blit; jnz FAIL;
+        get_local 0; get_local 1; push 0; push -1; syscall surface_blit; jnz FAIL;
+        get_local 0; get_local 1; push 3; push 1; syscall surface_blit; jnz FAIL;
+        push A_PX; load_u32; push 0xFF000002; ne_u64; jnz FAIL;
+        push A_PX; push 4; add_u64; load_u32; push 0xFF000009; ne_u64; jnz FAIL;
+        push A_PX; push 8; add_u64; load_u32; push 0xFF000009; ne_u64; jnz FAIL;
+
+        # Invalid surfaces are reported as -EBADF
+        get_local 1; syscall surface_destroy; jnz FAIL;
+        get_local 1; syscall surface_destroy; push -9; ne_u64; jnz FAIL;
+        get_local 0; get_local 1; push 0; push 0; syscall surface_blit; push -9; ne_u64; jnz FAIL;
+        get_local 0; syscall net_close; push -9; ne_u64; jnz FAIL;
+
+        # A new surface gets a new handle, the old one stays invalid
+        push B; push 1; syscall surface_create; get_local 1; ne_u64; jz FAIL;
+        get_local 1; syscall surface_destroy; push -9; ne_u64; jnz FAIL;
+
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn invalid_handles()
+{
+    // Windows and timers are handles, and syscalls given a handle which
+    // isn't a window or a running timer return -EBADF (-9), without
+    // needing a display. An invalid pixel format is -EINVAL (-22).
+    let (code, _) = run("
+        .data;
+        .align 8;
+        BITMAP: .addr64 PX; .u32 1; .u32 1;
+        PX: .zero 4;
+        .align 8;
+        STATS: .zero 32;
+        BUF: .zero 16;
+        TITLE: .stringz \"test\";
+        .code;
+        push BITMAP; push 0; syscall surface_create;
+        push 1000; push TICK; syscall time_interval_cb;
+
+        # Unknown pixel formats are rejected before a window is opened
+        push 64; push 64; push TITLE; push 0xFF; syscall window_create; push -22; ne_u64; jnz FAIL;
+
+        push 12345; push PX; syscall window_draw_frame; push -9; ne_u64; jnz FAIL;
+        get_local 0; push PX; syscall window_draw_frame; push -9; ne_u64; jnz FAIL;
+        get_local 1; get_local 0; syscall window_draw_surface; push -9; ne_u64; jnz FAIL;
+        get_local 0; push STATS; syscall window_frame_stats; push -9; ne_u64; jnz FAIL;
+        push 0; push TICK; syscall window_on_mousemove; push -9; ne_u64; jnz FAIL;
+        push 0; push TICK; syscall window_on_mousedown; push -9; ne_u64; jnz FAIL;
+        push 0; push TICK; syscall window_on_mouseup; push -9; ne_u64; jnz FAIL;
+        get_local 1; push TICK; syscall window_on_keydown; push -9; ne_u64; jnz FAIL;
+        get_local 1; push TICK; syscall window_on_keyup; push -9; ne_u64; jnz FAIL;
+        get_local 1; push TICK; syscall window_on_textinput; push -9; ne_u64; jnz FAIL;
+        get_local 1; push BUF; push 16; push TICK; syscall window_on_dropfile; push -1; ne_u64; jnz FAIL;
+
+        # A timer can only be cancelled once, and surfaces aren't timers
+        push 0; syscall time_cancel_cb; push -9; ne_u64; jnz FAIL;
+        get_local 0; syscall time_cancel_cb; push -9; ne_u64; jnz FAIL;
+        get_local 1; syscall time_cancel_cb; jnz FAIL;
+        get_local 1; syscall time_cancel_cb; push -9; ne_u64; jnz FAIL;
+
+        push 0; exit;
+        FAIL: push 1; exit;
+        TICK: push 0; ret;
+    ");
+    assert_eq!(code, 0);
+
+    // Dropped file paths are denied (-EPERM) unless --allow-dropfile is given
+    let output = run_with("
+        .data;
+        BUF: .zero 16;
+        .code;
+        push 0; push BUF; push 16; push 0; syscall window_on_dropfile; push -9; ne_u64; exit;
+    ", &["--allow-dropfile"], &[]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn graphics_colors()
+{
+    let (code, _) = run("
+        .data;
+        .align 8;
+        BMP: .addr64 PX; .u32 2; .u32 1;
+        RECT: .u32 1; .u32 0; .u32 5; .u32 5;
+        PX: .u32 0xFF0000FF; .u32 0xFF0000FF;
+        SRC: .u32 0x80FF0000; .u32 0x00FF0000;
+        HSV: .f32 120.0; .f32 1.0; .f32 1.0;
+        RGB: .u32 0;
+        LUT: .zero 256;
+        .code;
+        push PX; push SRC; push 2; syscall blend_over;
+        push PX; load_u32; push 0xFF80007F; ne_u64; jnz FAIL;
+        push PX; push 4; add_u64; load_u32; push 0xFF0000FF; ne_u64; jnz FAIL;
+
+        push RGB; push HSV; push 1; syscall hsv_to_rgb;
+        push RGB; load_u32; push 0xFF00FF00; ne_u64; jnz FAIL;
+
+        # The rectangle only covers the second pixel
+        push BMP; push RECT; push LUT; syscall apply_lut;
+        push PX; load_u32; push 0xFF80007F; ne_u64; jnz FAIL;
+        push PX; push 4; add_u64; load_u32; push 0xFF000000; ne_u64; jnz FAIL;
+        push BMP; push 0; push LUT; syscall apply_lut;
+        push PX; load_u32; push 0xFF000000; ne_u64; jnz FAIL;
+
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn net_echo()
+{
+    // Find a free port for the guest to listen on
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let src = format!("
+        .data;
+        ADDR: .stringz \"127.0.0.1:{port}\";
+        CLIENT: .zero 64;
+        BUF: .zero 64;
+        .align 8;
+        NB: .u64 0;
+        .code;
+        push ADDR; push ON_CONN; syscall net_listen; pop;
+        push 0; ret;
+        ON_CONN:
+        get_arg 0; push CLIENT; push 64; push ON_DATA; syscall net_accept; pop;
+        push 0; ret;
+        ON_DATA:
+        push NB; get_arg 0; push BUF; push 64; syscall net_read; store_u64;
+        get_arg 0; push BUF; push NB; load_u64; syscall net_write; pop;
+        get_arg 0; syscall net_close;
+        push 0; exit;
+    ");
+
+    let path = write_asm(&src);
+    let child = Command::new(env!("CARGO_BIN_EXE_uvm"))
+        .args(["--net-allow", &format!("127.0.0.1:{port}")])
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Wait for the guest to start listening
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(s) = TcpStream::connect(("127.0.0.1", port)) {
+            stream = Some(s);
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let mut stream = stream.expect("could not connect to guest");
+
+    stream.write_all(b"ping").unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, b"ping");
+
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn net_tcp_connect()
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // The server answers the first message and closes the connection
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").unwrap();
+    });
+
+    // The socket can't be written to until the connect callback is called,
+    // and the data callback is called with 0 bytes when the server closes the connection
+    let src = format!("
+        .data;
+        ADDR: .stringz \"127.0.0.1:{port}\";
+        MSG: .stringz \"ping\";
+        BUF: .zero 64;
+        .code;
+        push ADDR; push ON_CONNECT; push ON_DATA; syscall net_tcp_connect;
+        push MSG; push 4; syscall net_write; push -107; ne_u64; jnz FAIL;
+        push 0; ret;
+        ON_CONNECT:
+        get_arg 1; jnz FAIL;
+        get_arg 0; push MSG; push 4; syscall net_write; push 4; ne_u64; jnz FAIL;
+        push 0; ret;
+        ON_DATA:
+        get_arg 1; jz CLOSED;
+        get_arg 0; push BUF; push 64; syscall net_read; pop;
+        push 0; ret;
+        CLOSED:
+        push BUF; syscall print_str;
+        get_arg 0; syscall net_close; pop;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+
+    let output = run_with(&src, &["--net-allow", &format!("127.0.0.1:{port}")], &[]);
+    server.join().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"pong");
+
+    // A failed connection is reported to the connect callback with -ECONNREFUSED (-111),
+    // and the socket is closed
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let src = format!("
+        .data;
+        ADDR: .stringz \"127.0.0.1:{port}\";
+        .code;
+        push ADDR; push ON_CONNECT; push 0; syscall net_tcp_connect; pop;
+        push 0; ret;
+        ON_CONNECT:
+        get_arg 1; push -111; ne_u64; jnz FAIL;
+        get_arg 0; syscall net_close; push -9; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+
+    let output = run_with(&src, &["--net-allow", &format!("127.0.0.1:{port}")], &[]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn ws_connect()
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // The server refuses the WebSocket upgrade
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        assert!(request.starts_with(b"GET /chat HTTP/1.1\r\n"));
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").unwrap();
+    });
+
+    // The guest keeps running during the handshake, and messages can't be sent
+    // until it is done. A failed handshake is reported to the connect callback
+    // with -EIO (-5), and the socket is closed. Invalid URLs are -EINVAL (-22).
+    let src = format!("
+        .data;
+        URL: .stringz \"ws://127.0.0.1:{port}/chat\";
+        BAD_URL: .stringz \"wss://127.0.0.1:{port}/chat\";
+        MSG: .stringz \"hi\";
+        .code;
+        push BAD_URL; push ON_CONNECT; push 0; syscall ws_connect; push -22; ne_u64; jnz FAIL;
+        push URL; push ON_CONNECT; push 0; syscall ws_connect;
+        dup; push MSG; push 2; push 1; syscall ws_send; push -107; ne_u64; jnz FAIL;
+        push 0; ret;
+        ON_CONNECT:
+        get_arg 1; push -5; ne_u64; jnz FAIL;
+        get_arg 0; syscall net_close; push -9; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+
+    let output = run_with(&src, &["--net-allow", &format!("127.0.0.1:{port}")], &[]);
+    server.join().unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn net_fetch()
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        assert!(request.starts_with(b"GET /data?x=1 HTTP/1.1\r\n"));
+        stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n").unwrap();
+    });
+
+    // The callback gets the request id, the status and the length of the body
+    let src = format!("
+        .data;
+        URL: .stringz \"http://127.0.0.1:{port}/data?x=1\";
+        BUF: .zero 64;
+        .code;
+        push URL; push ON_DONE; syscall net_fetch; pop;
+        push 0; ret;
+        ON_DONE:
+        get_arg 1; push 200; ne_u64; jnz FAIL;
+        get_arg 2; push 5; ne_u64; jnz FAIL;
+        get_arg 0; push BUF; push 64; syscall net_read; pop;
+        push BUF; syscall print_str;
+        get_arg 0; syscall net_close; pop;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+
+    let output = run_with(&src, &["--net-allow", &format!("127.0.0.1:{port}")], &[]);
+    server.join().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"hello");
+}
+
+#[test]
+fn net_policy()
+{
+    // Denied addresses are reported as -EACCES (-13)
+    let src = "
+        .data;
+        ADDR: .stringz \"127.0.0.1:1\";
+        URL: .stringz \"ws://127.0.0.1:1/\";
+        HTTP_URL: .stringz \"http://127.0.0.1:1/\";
+        .code;
+        push ADDR; push 0; syscall net_listen; push -13; ne_u64; jnz FAIL;
+        push URL; push 0; push 0; syscall ws_connect; push -13; ne_u64; jnz FAIL;
+        push ADDR; push 0; push 0; syscall net_tcp_connect; push -13; ne_u64; jnz FAIL;
+        push HTTP_URL; push 0; syscall net_fetch; push -13; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+
+    let output = run_with(src, &["--net-deny-all"], &[]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn net_errors()
+{
+    // Invalid socket ids are reported as -EBADF (-9),
+    // and invalid arguments as -EINVAL (-22)
+    let (code, _) = run("
+        .data;
+        URL: .stringz \"http://example.com/\";
+        FTP_URL: .stringz \"ftp://example.com/\";
+        BUF: .zero 8;
+        .code;
+        push 12345; push BUF; push 8; syscall net_read; push -9; ne_u64; jnz FAIL;
+        push 12345; push BUF; push 8; syscall net_write; push -9; ne_u64; jnz FAIL;
+        push 12345; push BUF; push 8; push 0; syscall net_accept; push -9; ne_u64; jnz FAIL;
+        push 12345; syscall net_close; push -9; ne_u64; jnz FAIL;
+        push 12345; push BUF; push 8; push 1; syscall ws_send; push -9; ne_u64; jnz FAIL;
+        push 12345; push BUF; push 8; push 7; syscall ws_send; push -22; ne_u64; jnz FAIL;
+        push URL; push 0; push 0; syscall ws_connect; push -22; ne_u64; jnz FAIL;
+        push FTP_URL; push 0; syscall net_fetch; push -22; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn quotas()
+{
+    // Writing more than the quota allows fails with -EDQUOT (-122),
+    // and opening too many sockets fails with -EMFILE (-24)
+    let src = "
+        .data;
+        ADDR: .stringz \"127.0.0.1:0\";
+        S: .stringz \"dropped\";
+        .code;
+        push S; push 3; syscall io_write; push -122; ne_u64; jnz FAIL;
+        push 104; syscall putchar; pop;
+        push 105; syscall putchar; pop;
+        push 33; syscall putchar; push -122; ne_u64; jnz FAIL;
+        push S; syscall print_str;
+        push ADDR; push 0; syscall net_listen; syscall net_close; pop;
+        push ADDR; push 0; syscall net_listen; pop;
+        push ADDR; push 0; syscall net_listen; push -24; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+
+    let output = run_with(src, &["--quota", "bytes-written=2", "--quota", "net-conns=1"], &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"hi");
+}
+
+#[test]
+fn fs_files()
+{
+    let dir = std::env::temp_dir().join(format!("uvm_test_fs_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("in.txt"), "input").unwrap();
+
+    // Files outside of the mapped directories can't be opened (-ENOENT),
+    // and files opened for reading can't be written to (-EBADF).
+    // Seeking before the start of a file fails with -EINVAL.
+    let src = "
+        .data;
+        OUT: .stringz \"/data/out.txt\";
+        IN: .stringz \"/data/in.txt\";
+        ESCAPE: .stringz \"/data/../in.txt\";
+        HELLO: .stringz \"hello \";
+        BUF: .zero 16;
+        .code;
+        push OUT; push 14; syscall fs_open; dup; push 0; lt_i64; jnz FAIL;
+        dup; push HELLO; push 6; syscall fs_write; push 6; ne_u64; jnz FAIL;
+        dup; push 0; push 0; syscall fs_seek; jnz FAIL;
+        dup; push ESCAPE; push 1; syscall fs_write; pop;
+        dup; push -1; push 2; syscall fs_seek; push 5; ne_u64; jnz FAIL;
+        dup; push 2; push 1; syscall fs_seek; push 7; ne_u64; jnz FAIL;
+        dup; push HELLO; push 1; syscall fs_write; pop;
+        dup; push -9; push 1; syscall fs_seek; push -22; ne_u64; jnz FAIL;
+        dup; push 0; push 3; syscall fs_seek; push -22; ne_u64; jnz FAIL;
+        syscall fs_close; jnz FAIL;
+        push 1000; push 0; push 0; syscall fs_seek; push -9; ne_u64; jnz FAIL;
+        push IN; push 1; syscall fs_open;
+        dup; push BUF; push 16; syscall fs_read; push 5; ne_u64; jnz FAIL;
+        dup; push BUF; push 16; syscall fs_read; jnz FAIL;
+        dup; push HELLO; push 1; syscall fs_write; push -9; ne_u64; jnz FAIL;
+        syscall fs_close; pop;
+        push BUF; syscall print_str;
+        push ESCAPE; push 1; syscall fs_open; push -2; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+
+    let map = format!("/data={}", dir.display());
+    let output = run_with(src, &["--map", &map], &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"input");
+    assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), "/ello \0h");
+
+    // Denying a permission removes the syscalls which require it
+    let src = "
+        push 80; syscall sys_has_syscall; jz FAIL;
+        push 81; syscall sys_has_syscall; jnz FAIL;
+        push 100; syscall sys_has_syscall; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+    assert_eq!(run_with(src, &["--map", &map, "--deny", "fs_io"], &[]).status.code(), Some(0));
+    assert_eq!(run_with(src, &["--deny", "fs_everything"], &[]).status.code(), Some(255));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fs_dirs()
+{
+    let dir = std::env::temp_dir().join(format!("uvm_test_dirs_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("in.txt"), "input").unwrap();
+
+    // Creating a directory twice fails with -EEXIST (-17), removing
+    // a directory which isn't empty fails with -ENOTEMPTY (-39),
+    // and mount points can't be removed (-EBUSY)
+    let src = "
+        .data;
+        DATA: .stringz \"/data\";
+        SUB: .stringz \"/data/sub\";
+        FILE: .stringz \"/data/sub/f.txt\";
+        .align 8;
+        STAT: .zero 16;
+        BUF: .zero 64;
+        .code;
+        push SUB; syscall fs_mkdir; jnz FAIL;
+        push SUB; syscall fs_mkdir; push -17; ne_u64; jnz FAIL;
+        push FILE; push 6; syscall fs_open; dup; push SUB; push 3; syscall fs_write; pop; syscall fs_close; pop;
+
+        push FILE; push STAT; syscall fs_stat; jnz FAIL;
+        push STAT; load_u64; push 1; ne_u64; jnz FAIL;
+        push STAT; push 8; add_u64; load_u64; push 3; ne_u64; jnz FAIL;
+        push SUB; push STAT; syscall fs_stat; jnz FAIL;
+        push STAT; load_u64; push 2; ne_u64; jnz FAIL;
+
+        push DATA; push BUF; push 2; syscall fs_list_dir; push 11; ne_u64; jnz FAIL;
+        push BUF; load_u8; jnz FAIL;
+        push DATA; push BUF; push 64; syscall fs_list_dir; push 11; ne_u64; jnz FAIL;
+        push BUF; syscall print_str; push BUF; push 7; add_u64; syscall print_str;
+        push FILE; push BUF; push 64; syscall fs_list_dir; push -20; ne_u64; jnz FAIL;
+
+        push SUB; syscall fs_remove; push -39; ne_u64; jnz FAIL;
+        push FILE; syscall fs_remove; jnz FAIL;
+        push SUB; syscall fs_remove; jnz FAIL;
+        push DATA; syscall fs_remove; push -16; ne_u64; jnz FAIL;
+        push SUB; push STAT; syscall fs_stat; push -2; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+
+    let map = format!("/data={}", dir.display());
+    let output = run_with(src, &["--map", &map], &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"in.txtsub");
+    assert!(!dir.join("sub").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fs_archive()
+{
+    // Produced by Python's zipfile module, with "a.txt" compressed
+    let zip: [u8; 119] = [
+        0x50, 0x4b, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,
+        0x21, 0x00, 0x10, 0xb4, 0xa3, 0x0d, 0x0b, 0x00, 0x00, 0x00, 0x30, 0x00,
+        0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x61, 0x2e, 0x74, 0x78, 0x74, 0x4b,
+        0x2c, 0x2e, 0x4e, 0x2d, 0x51, 0x48, 0x24, 0x9a, 0x04, 0x00, 0x50, 0x4b,
+        0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,
+        0x21, 0x00, 0x10, 0xb4, 0xa3, 0x0d, 0x0b, 0x00, 0x00, 0x00, 0x30, 0x00,
+        0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x61, 0x2e, 0x74, 0x78,
+        0x74, 0x50, 0x4b, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01,
+        0x00, 0x33, 0x00, 0x00, 0x00, 0x2e, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let path = std::env::temp_dir().join(format!("uvm_test_archive_{}.zip", std::process::id()));
+    fs::write(&path, zip).unwrap();
+
+    // Files in the archive can't be opened for writing (-EROFS)
+    let src = "
+        .data;
+        FILE: .stringz \"/assets/a.txt\";
+        MISSING: .stringz \"/assets/b.txt\";
+        BUF: .zero 64;
+        .code;
+        push FILE; push 1; syscall fs_open; dup; push 0; lt_i64; jnz FAIL;
+        dup; push BUF; push 64; syscall fs_read; push 48; ne_u64; jnz FAIL;
+        syscall fs_close; jnz FAIL;
+        push BUF; syscall print_str;
+        push FILE; push 3; syscall fs_open; push -30; ne_u64; jnz FAIL;
+        push MISSING; push 1; syscall fs_open; push -2; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ";
+
+    let mount = format!("{}:/assets", path.display());
+    let output = run_with(src, &["--mount", &mount], &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, "asset ".repeat(8).as_bytes());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn compress_round_trip()
+{
+    let (code, out) = run("
+        .data;
+        S: .stringz \"abcabcabcabcabcabcabcabcabcabcabcabc\";
+        C: .zero 64;
+        D: .zero 64;
+        .align 8;
+        N: .u64 0;
+        .code;
+        push N; push C; push 64; push S; push 37; syscall compress; store_u64;
+        push N; load_u64; push 37; ge_i64; jnz FAIL;
+        push D; push 64; push C; push N; load_u64; syscall decompress;
+        push 37; ne_u64; jnz FAIL;
+        push S; push D; push 37; syscall memcmp; jnz FAIL;
+        push C; push 4; push S; push 37; syscall compress; push -28; ne_u64; jnz FAIL;
+        push D; push 36; push C; push N; load_u64; syscall decompress; push -28; ne_u64; jnz FAIL;
+        push D; push 64; push S; push 37; syscall decompress; push -22; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0, "{}", out);
+}
+
+#[test]
+fn crypto_hashes()
+{
+    let (code, _) = run("
+        .data;
+        S: .stringz \"123456789\";
+        .align 4;
+        H: .zero 32;
+        .code;
+        push S; push 9; push 0; syscall crypto_crc32;
+        push 0xCBF43926; ne_u32; jnz FAIL;
+        push S; push 0; push H; syscall crypto_sha256;
+        push H; load_u32; push 0x42C4B0E3; ne_u32; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn str_utf8()
+{
+    let (code, out) = run("
+        .data;
+        S: .stringz \"héllo\";
+        D: .zero 16;
+        .code;
+        push S; push 6; syscall str_validate_utf8; jz FAIL;
+        push S; push 6; syscall str_char_count; syscall print_i64;
+        push D; push 16; push S; push 6; syscall str_to_upper; pop;
+        push D; syscall print_str;
+        push D; push 16; push D; push 6; syscall str_to_lower; pop;
+        push D; syscall print_str;
+        push D; push 5; push S; push 6; syscall str_to_upper; push -28; ne_u64; jnz FAIL;
+        push D; push 16; push S; push 2; syscall str_to_lower; push -22; ne_u64; jnz FAIL;
+        push 0; exit;
+        FAIL: push 1; exit;
+    ");
+    assert_eq!(code, 0);
+    assert_eq!(out, "5HÉLLOhéllo");
+}